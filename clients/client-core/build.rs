@@ -0,0 +1,48 @@
+/*
+ * Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+use sqlx::migrate::Migrator;
+use sqlx::{Connection, Executor, SqliteConnection};
+use std::env;
+use std::path::Path;
+
+// each store keeps its own migrations, but the queries of all of them are checked against
+// the same example database
+const MIGRATION_DIRECTORIES: [&str; 2] = [
+    "migrations/reply_key_storage",
+    "migrations/outbound_journal",
+];
+
+#[tokio::main]
+async fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let database_path = format!("{}/client-core-example.sqlite", out_dir);
+    // the schema is always created from scratch
+    let _ = std::fs::remove_file(&database_path);
+
+    let mut conn = SqliteConnection::connect(&*format!("sqlite://{}?mode=rwc", database_path))
+        .await
+        .expect("Failed to create SQLx database connection");
+
+    for directory in MIGRATION_DIRECTORIES {
+        println!("cargo:rerun-if-changed={}", directory);
+        let migrator = Migrator::new(Path::new(directory))
+            .await
+            .expect("Failed to resolve SQLx migrations");
+        for migration in migrator.iter() {
+            conn.execute(&*migration.sql)
+                .await
+                .expect("Failed to perform SQLx migrations");
+        }
+    }
+
+    #[cfg(target_family = "unix")]
+    println!("cargo:rustc-env=DATABASE_URL=sqlite://{}", &database_path);
+
+    #[cfg(target_family = "windows")]
+    // for some strange reason we need to add a leading `/` to the windows path even though it's
+    // not a valid windows path... but hey, it works...
+    println!("cargo:rustc-env=DATABASE_URL=sqlite:///{}", &database_path);
+}
@@ -0,0 +1,13 @@
+/*
+ * Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+CREATE TABLE outbound_fragment
+(
+    fragment_id         BLOB    NOT NULL PRIMARY KEY,
+    recipient           BLOB    NOT NULL,
+    fragment            BLOB    NOT NULL,
+    message_id          INTEGER NOT NULL,
+    created_at          INTEGER NOT NULL
+);
@@ -0,0 +1,13 @@
+/*
+ * Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+CREATE TABLE reply_key
+(
+    key_digest          BLOB    NOT NULL PRIMARY KEY,
+    reply_key           BLOB    NOT NULL,
+    created_at          INTEGER NOT NULL
+);
+
+CREATE INDEX reply_key_created_at ON reply_key (created_at);
@@ -0,0 +1,278 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Period over which the bandwidth consumption is averaged to determine the current burn rate.
+const BURN_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct BandwidthStatisticsInner {
+    started: Instant,
+    remaining: AtomicI64,
+    consumed: AtomicU64,
+    credentials_redeemed: AtomicU64,
+
+    // bandwidth consumed by the packets sent within the last `BURN_RATE_WINDOW`
+    recent_consumption: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+/// Keeps track of the bandwidth available at the gateway and the rate at which
+/// the client is consuming it.
+// note that clone here is fine as upon cloning the same underlying counters will be used
+#[derive(Debug, Clone)]
+pub struct BandwidthStatistics {
+    inner: Arc<BandwidthStatisticsInner>,
+}
+
+impl Default for BandwidthStatistics {
+    fn default() -> Self {
+        BandwidthStatistics::new()
+    }
+}
+
+impl BandwidthStatistics {
+    pub fn new() -> Self {
+        BandwidthStatistics {
+            inner: Arc::new(BandwidthStatisticsInner {
+                started: Instant::now(),
+                remaining: AtomicI64::new(0),
+                consumed: AtomicU64::new(0),
+                credentials_redeemed: AtomicU64::new(0),
+                recent_consumption: Mutex::new(VecDeque::new()),
+            }),
+        }
+    }
+
+    fn prune_recent_consumption(recent: &mut VecDeque<(Instant, u64)>, now: Instant) {
+        while let Some((timestamp, _)) = recent.front() {
+            if now.duration_since(*timestamp) > BURN_RATE_WINDOW {
+                recent.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub(crate) fn set_remaining(&self, remaining: i64) {
+        self.inner.remaining.store(remaining, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_consumption(&self, bandwidth: i64, remaining: i64) {
+        let bandwidth = bandwidth.max(0) as u64;
+        self.inner.consumed.fetch_add(bandwidth, Ordering::Relaxed);
+        self.set_remaining(remaining);
+
+        let now = Instant::now();
+        let mut recent = self.inner.recent_consumption.lock().unwrap();
+        recent.push_back((now, bandwidth));
+        Self::prune_recent_consumption(&mut recent, now);
+    }
+
+    // credentials are only ever redeemed at runtime with coconut
+    #[cfg_attr(not(feature = "coconut"), allow(dead_code))]
+    pub(crate) fn increment_credentials_redeemed(&self) {
+        self.inner
+            .credentials_redeemed
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    // bytes per second consumed over the last `BURN_RATE_WINDOW`
+    // (or since the start if the client has been running for a shorter time)
+    fn burn_rate(&self) -> f64 {
+        let now = Instant::now();
+        let mut recent = self.inner.recent_consumption.lock().unwrap();
+        Self::prune_recent_consumption(&mut recent, now);
+
+        let window = now
+            .duration_since(self.inner.started)
+            .min(BURN_RATE_WINDOW)
+            .as_secs_f64();
+        if window == 0.0 {
+            return 0.0;
+        }
+
+        let recently_consumed: u64 = recent.iter().map(|(_, bandwidth)| bandwidth).sum();
+        recently_consumed as f64 / window
+    }
+
+    /// Returns the current balance and consumption of the bandwidth.
+    pub fn snapshot(&self) -> BandwidthStatisticsSnapshot {
+        let remaining = self.inner.remaining.load(Ordering::Relaxed);
+        let burn_rate = self.burn_rate();
+        let estimated_time_remaining = if burn_rate > 0.0 {
+            Some(Duration::from_secs_f64(remaining.max(0) as f64 / burn_rate))
+        } else {
+            None
+        };
+
+        BandwidthStatisticsSnapshot {
+            remaining,
+            consumed: self.inner.consumed.load(Ordering::Relaxed),
+            burn_rate,
+            estimated_time_remaining,
+            credentials_redeemed: self.inner.credentials_redeemed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time values of the [`BandwidthStatistics`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BandwidthStatisticsSnapshot {
+    /// Bandwidth, in bytes, still available at the gateway.
+    pub remaining: i64,
+
+    /// Total bandwidth, in bytes, consumed by all packets sent to the gateway.
+    pub consumed: u64,
+
+    /// Average number of bytes consumed per second over the last minute.
+    pub burn_rate: f64,
+
+    /// How long the remaining bandwidth is going to last at the current burn rate.
+    /// It is unknown if the client hasn't sent anything recently.
+    pub estimated_time_remaining: Option<Duration>,
+
+    /// Number of stored credentials redeemed for more bandwidth since the client has started.
+    pub credentials_redeemed: u64,
+}
+
+/// Decides when the client should redeem another stored credential for more bandwidth.
+#[derive(Debug)]
+pub(crate) struct RedemptionPolicy {
+    /// Once sending the packets would bring the remaining bandwidth below this many bytes,
+    /// another credential should be redeemed.
+    threshold: i64,
+
+    /// Minimum delay between subsequent attempts of redeeming a credential, if the previous one failed.
+    retry_interval: Duration,
+
+    /// Time of the latest failed attempt of redeeming a credential, if any.
+    last_failure: Option<Instant>,
+
+    /// Indicates whether a credential is currently being redeemed.
+    in_progress: bool,
+}
+
+impl RedemptionPolicy {
+    pub(crate) fn new(threshold: i64, retry_interval: Duration) -> Self {
+        RedemptionPolicy {
+            threshold,
+            retry_interval,
+            last_failure: None,
+            in_progress: false,
+        }
+    }
+
+    pub(crate) fn should_redeem(&self, remaining: i64, required: i64, now: Instant) -> bool {
+        if self.in_progress || remaining - required >= self.threshold {
+            return false;
+        }
+
+        match self.last_failure {
+            Some(last_failure) => now.duration_since(last_failure) >= self.retry_interval,
+            None => true,
+        }
+    }
+
+    pub(crate) fn on_started(&mut self) {
+        self.in_progress = true;
+    }
+
+    pub(crate) fn on_finished(&mut self, succeeded: bool, now: Instant) {
+        self.in_progress = false;
+        self.last_failure = if succeeded { None } else { Some(now) };
+    }
+
+    /// Allows redeeming a credential straight away, for example after switching to a different gateway.
+    pub(crate) fn reset_failures(&mut self) {
+        self.last_failure = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consumption_is_accumulated() {
+        let statistics = BandwidthStatistics::new();
+        statistics.set_remaining(1000);
+        statistics.record_consumption(100, 900);
+        statistics.record_consumption(200, 700);
+        // negative estimates are never counted as consumption
+        statistics.record_consumption(-50, 700);
+        statistics.increment_credentials_redeemed();
+
+        let snapshot = statistics.snapshot();
+        assert_eq!(snapshot.remaining, 700);
+        assert_eq!(snapshot.consumed, 300);
+        assert_eq!(snapshot.credentials_redeemed, 1);
+    }
+
+    #[test]
+    fn remaining_time_is_unknown_without_recent_consumption() {
+        let statistics = BandwidthStatistics::new();
+        statistics.set_remaining(1000);
+
+        let snapshot = statistics.snapshot();
+        assert_eq!(snapshot.burn_rate, 0.0);
+        assert_eq!(snapshot.estimated_time_remaining, None);
+    }
+
+    #[test]
+    fn old_consumption_is_pruned() {
+        let now = Instant::now();
+        let mut recent = VecDeque::new();
+        if let Some(long_ago) = now.checked_sub(BURN_RATE_WINDOW * 2) {
+            recent.push_back((long_ago, 100));
+        }
+        recent.push_back((now, 200));
+
+        BandwidthStatistics::prune_recent_consumption(&mut recent, now);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].1, 200);
+    }
+
+    #[test]
+    fn credential_is_redeemed_only_below_threshold() {
+        let policy = RedemptionPolicy::new(1000, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(!policy.should_redeem(5000, 1000, now));
+        assert!(!policy.should_redeem(2000, 1000, now));
+        assert!(policy.should_redeem(1999, 1000, now));
+        assert!(policy.should_redeem(-100, 0, now));
+    }
+
+    #[test]
+    fn only_one_credential_is_redeemed_at_a_time() {
+        let mut policy = RedemptionPolicy::new(1000, Duration::from_secs(60));
+        let now = Instant::now();
+
+        policy.on_started();
+        assert!(!policy.should_redeem(0, 1000, now));
+
+        policy.on_finished(true, now);
+        assert!(policy.should_redeem(0, 1000, now));
+    }
+
+    #[test]
+    fn failed_redemptions_are_retried_after_interval() {
+        let retry_interval = Duration::from_secs(60);
+        let mut policy = RedemptionPolicy::new(1000, retry_interval);
+        let failed_at = Instant::now();
+
+        policy.on_started();
+        policy.on_finished(false, failed_at);
+        assert!(!policy.should_redeem(0, 1000, failed_at));
+        assert!(!policy.should_redeem(0, 1000, failed_at + retry_interval / 2));
+        assert!(policy.should_redeem(0, 1000, failed_at + retry_interval));
+
+        policy.on_finished(false, failed_at);
+        policy.reset_failures();
+        assert!(policy.should_redeem(0, 1000, failed_at));
+    }
+}
@@ -0,0 +1,473 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::client::bandwidth_control::BandwidthStatistics;
+use crate::client::cover_traffic_stream::LoopCoverTrafficStream;
+use crate::client::delivery_receipts::{DeliveryReceiptReceiver, DeliveryReceiptSender};
+use crate::client::gateway_failover::{
+    self_address_channel, GatewayConfigPersister, GatewayConnector, GatewayFailover,
+    SelfAddressReceiver, SelfAddressSender,
+};
+use crate::client::inbound_messages::InputMessageSender;
+use crate::client::key_manager::KeyManager;
+use crate::client::key_rotation::{KeyRotator, RunningClientLock};
+use crate::client::mix_traffic::{BatchMixMessageSender, InboxRequester, MixTrafficController};
+use crate::client::outbound_journal::OutboundJournal;
+use crate::client::real_messages_control::{self, RealMessagesController, RetransmissionPolicy};
+use crate::client::received_buffer::{
+    ReceivedBufferRequestSender, ReceivedMessagesBufferController,
+};
+use crate::client::reply_key_storage::{ReplyKeyStorage, ReplyKeyStorageController};
+use crate::client::route_selection::{setup_route_selector, NodeMetricsRefresher};
+use crate::client::topology_control::{
+    TopologyAccessor, TopologyRefreshRequester, TopologyRefresher, TopologyRefresherConfig,
+};
+use crate::client::traffic_control::{PacketStatistics, TrafficRates, TrafficRatesControl};
+use crate::config::persistence::key_pathfinder::ClientKeyPathfinder;
+use crate::config::Config;
+use crate::error::ClientCoreError;
+use config::NymConfig;
+use futures::channel::mpsc;
+use gateway_client::GatewayClient;
+use log::*;
+use nymsphinx::addressing::clients::Recipient;
+use nymsphinx::addressing::nodes::NodeIdentity;
+use std::sync::Arc;
+use topology::route_selection::{PolicyRouteSelector, RouteSelector};
+
+/// Channels and handles of a started [`BaseClient`] through which it's driven by whatever
+/// is exposing it, be it a websocket, a socks5 proxy or the library [`MixnetClient`].
+///
+/// [`MixnetClient`]: crate::client::mixnet_client::MixnetClient
+pub struct StartedClient {
+    /// Channel used for transforming 'raw' messages into sphinx packets and sending them
+    /// through the mix network.
+    pub input_sender: InputMessageSender,
+
+    /// Channel used for announcing a receiver of the reconstructed messages received from
+    /// the mix network, or routing the replies.
+    pub received_buffer_request_sender: ReceivedBufferRequestSender,
+
+    /// Channel onto which the delivery receipts of the messages replayed from the outbound
+    /// journal are pushed. Its sender can be attached to other messages as well.
+    pub receipt_sender: DeliveryReceiptSender,
+    pub receipt_receiver: DeliveryReceiptReceiver,
+
+    /// Current view of the network topology.
+    pub topology_accessor: TopologyAccessor,
+
+    /// Channel used for requesting an immediate refresh of the network topology.
+    pub topology_refresh_requester: TopologyRefreshRequester,
+
+    /// Channel used for retrieving the messages stored at the gateway.
+    pub inbox_requester: InboxRequester,
+
+    /// Marks the keys as being in use, so that they would not be rotated from under the client
+    /// while it's running. It has to be kept for as long as the client is running.
+    pub running_lock: RunningClientLock,
+}
+
+/// All of the components making up a running client wired together. It's shared by the native
+/// and socks5 clients as well as the library [`MixnetClient`], which only differ in how they
+/// expose it.
+///
+/// [`MixnetClient`]: crate::client::mixnet_client::MixnetClient
+pub struct BaseClient {
+    /// KeyManager object containing smart pointers to all relevant keys used by the client.
+    key_manager: KeyManager,
+
+    /// Handle used for adjusting the rates of the real and cover traffic streams at runtime.
+    traffic_rates: TrafficRatesControl,
+
+    /// Counters of the real and cover packets sent by the client.
+    packet_statistics: PacketStatistics,
+
+    /// Balance and consumption of the bandwidth available at the gateway.
+    bandwidth_statistics: BandwidthStatistics,
+
+    /// Current address of the client. It changes after switching to a backup gateway
+    /// or rotating the encryption key.
+    self_address: SelfAddressReceiver,
+
+    /// Channel used for publishing the new address after switching to a backup gateway
+    /// or rotating the encryption key.
+    self_address_sender: SelfAddressSender,
+
+    /// Custom way of choosing the mix nodes for the routes of all sent packets. If not set,
+    /// the route selection policy and node exclusions from the config are used.
+    custom_route_selector: Option<Arc<dyn RouteSelector>>,
+
+    /// Used for persisting the gateway the client has switched to, so that it would
+    /// still be used after a restart.
+    gateway_config_persister: Option<GatewayConfigPersister>,
+}
+
+impl BaseClient {
+    /// Creates new instance of a [`BaseClient`] using the provided configuration and keys.
+    /// The client does not do anything until it's started.
+    pub fn new<T: NymConfig>(
+        config: &Config<T>,
+        key_manager: KeyManager,
+    ) -> Result<Self, ClientCoreError> {
+        // TODO: below only works under assumption that gateway address == gateway id
+        // (which currently is true)
+        let gateway_identity = NodeIdentity::from_base58_string(config.get_gateway_id())
+            .map_err(|_| ClientCoreError::MalformedGatewayIdentity)?;
+        let initial_address = Recipient::new(
+            *key_manager.identity_keypair().public_key(),
+            *key_manager.encryption_keypair().public_key(),
+            gateway_identity,
+        );
+        let (self_address_sender, self_address) = self_address_channel(initial_address);
+
+        let traffic_rates = TrafficRatesControl::new(TrafficRates::new(
+            config.get_loop_cover_traffic_average_delay(),
+            config.get_message_sending_average_delay(),
+        ));
+
+        Ok(BaseClient {
+            key_manager,
+            traffic_rates,
+            packet_statistics: PacketStatistics::new(),
+            bandwidth_statistics: BandwidthStatistics::new(),
+            self_address,
+            self_address_sender,
+            custom_route_selector: None,
+            gateway_config_persister: None,
+        })
+    }
+
+    /// Specifies a custom way of choosing the mix nodes for the routes of all sent packets.
+    #[must_use]
+    pub fn with_route_selector(mut self, route_selector: Arc<dyn RouteSelector>) -> Self {
+        self.custom_route_selector = Some(route_selector);
+        self
+    }
+
+    /// Makes the client persist the gateway it has switched to after its primary one has
+    /// stopped responding.
+    #[must_use]
+    pub fn with_gateway_config_persister(mut self, persister: GatewayConfigPersister) -> Self {
+        self.gateway_config_persister = Some(persister);
+        self
+    }
+
+    /// Returns the current address of this client. It changes whenever the client switches to
+    /// a backup gateway or rotates its encryption key, see [`Self::address_updates`].
+    pub fn address(&self) -> Recipient {
+        *self.self_address.borrow()
+    }
+
+    /// Returns the channel on which the new address of this client is announced whenever
+    /// it changes, that is whenever the client switches to one of its backup gateways or
+    /// rotates its encryption key (see the `key_rotation_interval` config option).
+    pub fn address_updates(&self) -> SelfAddressReceiver {
+        self.self_address.clone()
+    }
+
+    /// Returns the handle allowing to adjust the rates at which the real and cover packets
+    /// are sent.
+    pub fn traffic_rates(&self) -> &TrafficRatesControl {
+        &self.traffic_rates
+    }
+
+    /// Returns the counters of the real and cover packets sent by the client.
+    pub fn packet_statistics(&self) -> &PacketStatistics {
+        &self.packet_statistics
+    }
+
+    /// Returns the balance and consumption of the bandwidth available at the gateway.
+    pub fn bandwidth_statistics(&self) -> &BandwidthStatistics {
+        &self.bandwidth_statistics
+    }
+
+    async fn start_gateway_client<T: NymConfig>(
+        &self,
+        config: &Config<T>,
+        gateway_connector: &GatewayConnector,
+    ) -> Result<GatewayClient, ClientCoreError> {
+        gateway_connector
+            .connect(
+                config.get_gateway_endpoint(),
+                self.key_manager.gateway_shared_key(),
+            )
+            .await
+    }
+
+    // future responsible for periodically polling directory server and updating
+    // the current global view of topology
+    async fn start_topology_refresher<T: NymConfig>(
+        &self,
+        config: &Config<T>,
+        topology_accessor: TopologyAccessor,
+    ) -> Result<TopologyRefreshRequester, ClientCoreError> {
+        let topology_refresher_config = TopologyRefresherConfig::new(
+            config.get_validator_api_endpoints(),
+            config.get_topology_refresh_rate(),
+            env!("CARGO_PKG_VERSION").to_string(),
+        )
+        .with_topology_file(config.get_topology_file())
+        .with_pinned_topology(config.get_pin_topology())
+        .with_topology_dump_file(config.get_topology_dump_file());
+        let mut topology_refresher =
+            TopologyRefresher::new(topology_refresher_config, topology_accessor);
+        // before returning, block entire runtime to refresh the current network view so that any
+        // components depending on topology would see a non-empty view
+        info!("Obtaining initial network topology");
+        topology_refresher.refresh().await;
+
+        if !topology_refresher.is_topology_routable().await {
+            return Err(ClientCoreError::InsufficientNetworkTopology);
+        }
+
+        info!("Starting topology refresher...");
+        let refresh_requester = topology_refresher.refresh_requester();
+        topology_refresher.start();
+        Ok(refresh_requester)
+    }
+
+    // future responsible for periodically obtaining the uptime, latency and location of mixnodes,
+    // if they are required for choosing the routes
+    async fn start_node_metrics_refresher<T: NymConfig>(
+        &self,
+        config: &Config<T>,
+        route_selector: Arc<PolicyRouteSelector>,
+        topology_accessor: TopologyAccessor,
+    ) -> Result<(), ClientCoreError> {
+        let mut node_metrics_refresher = NodeMetricsRefresher::new(
+            config.get_validator_api_endpoints(),
+            topology_accessor,
+            route_selector,
+            config.get_node_metrics_refresh_rate(),
+        );
+        if !node_metrics_refresher.is_required() {
+            // the nodes might still be excluded by their identities or owners
+            return node_metrics_refresher.check_eligible_nodes().await;
+        }
+
+        info!("Obtaining initial mix node metrics");
+        node_metrics_refresher.refresh().await;
+        // there's no point in starting if none of the packets could be sent
+        node_metrics_refresher.check_eligible_nodes().await?;
+
+        info!("Starting node metrics refresher...");
+        node_metrics_refresher.start();
+        Ok(())
+    }
+
+    // future constantly pumping loop cover traffic at some specified average rate
+    // the pumped traffic goes to the MixTrafficController
+    fn start_cover_traffic_stream<T: NymConfig>(
+        &self,
+        config: &Config<T>,
+        topology_accessor: TopologyAccessor,
+        mix_tx: BatchMixMessageSender,
+        route_selector: Arc<dyn RouteSelector>,
+    ) {
+        info!("Starting loop cover traffic stream...");
+        LoopCoverTrafficStream::new(
+            self.key_manager.ack_keys(),
+            config.get_average_ack_delay(),
+            config.get_average_packet_delay(),
+            self.traffic_rates.subscribe(),
+            self.packet_statistics.clone(),
+            mix_tx,
+            self.self_address.clone(),
+            topology_accessor,
+        )
+        .with_route_selector(route_selector)
+        .start();
+    }
+
+    fn start_key_rotator<T: NymConfig>(&self, config: &Config<T>) {
+        if let Some(rotation_interval) = config.get_key_rotation_interval() {
+            info!("Starting key rotator...");
+            KeyRotator::new(
+                self.key_manager.clone(),
+                ClientKeyPathfinder::new_from_config(config),
+                rotation_interval,
+                config.get_key_rotation_grace_period(),
+                Arc::clone(&self.self_address_sender),
+            )
+            .start();
+        }
+    }
+
+    /// Starts all of the background tasks required for sending and receiving messages through
+    /// the mix network. The provided configuration has to be the same one the client was
+    /// created with.
+    pub async fn start<T: NymConfig>(
+        &mut self,
+        config: &Config<T>,
+    ) -> Result<StartedClient, ClientCoreError> {
+        let pathfinder = ClientKeyPathfinder::new_from_config(config);
+        // two clients must never run on the same keys, nor can they be rotated from under us
+        let running_lock = RunningClientLock::acquire(&pathfinder)?;
+
+        // channels for inter-component communication
+        // TODO: make the channels be internally created by the relevant components
+        // rather than creating them here, so say for example the buffer controller would create the request channels
+        // and would allow anyone to clone the sender channel
+
+        // sphinx_message_sender is the transmitter for any component generating sphinx packets that are to be sent to the mixnet
+        // they are used by cover traffic stream and real traffic stream
+        // sphinx_message_receiver is the receiver used by MixTrafficController that sends the actual traffic
+        let (sphinx_message_sender, sphinx_message_receiver) = mpsc::unbounded();
+
+        // unwrapped_sphinx_sender is the transmitter of mixnet messages received from the gateway
+        // unwrapped_sphinx_receiver is the receiver for said messages - used by ReceivedMessagesBuffer
+        let (mixnet_messages_sender, mixnet_messages_receiver) = mpsc::unbounded();
+
+        // used for announcing connection or disconnection of a channel for pushing re-assembled messages to
+        let (received_buffer_request_sender, received_buffer_request_receiver) = mpsc::unbounded();
+
+        // channels responsible for controlling real messages
+        let (input_sender, input_receiver) = mpsc::unbounded();
+
+        // channels responsible for controlling ack messages
+        let (ack_sender, ack_receiver) = mpsc::unbounded();
+        let shared_topology_accessor = TopologyAccessor::new();
+
+        let reply_key_storage = ReplyKeyStorage::load(
+            config.get_reply_encryption_key_store_path(),
+            config.get_reply_key_ttl(),
+        )
+        .await?;
+
+        // the original requesters of the messages replayed from the journal are gone,
+        // so their receipts are delivered onto a channel of their own
+        let (receipt_sender, receipt_receiver) = mpsc::unbounded();
+        let outbound_journal = if config.get_outbound_journal_enabled() {
+            Some(
+                OutboundJournal::load(
+                    config.get_outbound_journal_path(),
+                    config.get_outbound_journal_entry_ttl(),
+                )
+                .await?
+                .with_receipt_sender(receipt_sender.clone()),
+            )
+        } else {
+            None
+        };
+
+        // the components are started in very specific order. Unless you know what you are doing,
+        // do not change that.
+        info!("Starting reply key storage controller...");
+        ReplyKeyStorageController::new(
+            reply_key_storage.clone(),
+            config.get_reply_key_storage_flush_interval(),
+            config.get_reply_key_storage_gc_interval(),
+        )
+        .start();
+
+        let topology_refresh_requester = self
+            .start_topology_refresher(config, shared_topology_accessor.clone())
+            .await?;
+        let route_selector: Arc<dyn RouteSelector> = match &self.custom_route_selector {
+            Some(route_selector) => Arc::clone(route_selector),
+            None => {
+                let route_selector = setup_route_selector(config);
+                self.start_node_metrics_refresher(
+                    config,
+                    Arc::clone(&route_selector),
+                    shared_topology_accessor.clone(),
+                )
+                .await?;
+                route_selector
+            }
+        };
+
+        // buffer controlling all messages fetched from provider
+        // required so that other components would be able to use them (say the websocket)
+        info!("Starting received messages buffer controller...");
+        ReceivedMessagesBufferController::new(
+            self.key_manager.encryption_keys(),
+            received_buffer_request_receiver,
+            mixnet_messages_receiver,
+            reply_key_storage.clone(),
+        )
+        .start();
+
+        let gateway_connector = GatewayConnector::new(
+            config,
+            self.key_manager.identity_keypair(),
+            mixnet_messages_sender,
+            ack_sender,
+        );
+        let gateway_client = self
+            .start_gateway_client(config, &gateway_connector)
+            .await?;
+
+        // controller for sending sphinx packets to mixnet (either real traffic or cover traffic)
+        // as it owns the gateway_client, any other requests for the gateway have to go through it
+        info!("Starting mix traffic controller...");
+        let mut mix_traffic_controller = MixTrafficController::new(
+            sphinx_message_receiver,
+            gateway_client,
+            self.bandwidth_statistics.clone(),
+            config.get_bandwidth_redemption_threshold(),
+        );
+        if let Some(mut failover) = GatewayFailover::from_config(
+            config,
+            &self.key_manager,
+            gateway_connector,
+            Arc::clone(&self.self_address_sender),
+        ) {
+            if let Some(persister) = self.gateway_config_persister.take() {
+                failover = failover.with_persistence(pathfinder, persister);
+            }
+            mix_traffic_controller = mix_traffic_controller.with_failover(failover);
+        }
+        let inbox_requester = mix_traffic_controller.inbox_requester();
+        mix_traffic_controller.start();
+
+        self.start_key_rotator(config);
+
+        info!("Starting real traffic stream...");
+        let controller_config = real_messages_control::Config::new(
+            self.key_manager.ack_keys(),
+            config.get_ack_wait_multiplier(),
+            config.get_ack_wait_addition(),
+            RetransmissionPolicy::new(
+                config.get_maximum_retransmissions(),
+                config.get_retransmission_backoff_multiplier(),
+                config.get_maximum_retransmission_backoff(),
+                config.get_message_delivery_deadline(),
+            ),
+            config.get_average_ack_delay(),
+            config.get_average_packet_delay(),
+            self.self_address.clone(),
+        )
+        .with_route_selector(route_selector.clone());
+        RealMessagesController::new(
+            controller_config,
+            ack_receiver,
+            input_receiver,
+            sphinx_message_sender.clone(),
+            shared_topology_accessor.clone(),
+            reply_key_storage,
+            outbound_journal,
+            self.traffic_rates.subscribe(),
+            self.packet_statistics.clone(),
+        )
+        .start();
+
+        self.start_cover_traffic_stream(
+            config,
+            shared_topology_accessor.clone(),
+            sphinx_message_sender,
+            route_selector,
+        );
+
+        Ok(StartedClient {
+            input_sender,
+            received_buffer_request_sender,
+            receipt_sender,
+            receipt_receiver,
+            topology_accessor: shared_topology_accessor,
+            topology_refresh_requester,
+            inbox_requester,
+            running_lock,
+        })
+    }
+}
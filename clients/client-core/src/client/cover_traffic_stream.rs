@@ -1,13 +1,15 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::client::gateway_failover::SelfAddressReceiver;
+use crate::client::key_rotation::RotatableKey;
 use crate::client::mix_traffic::BatchMixMessageSender;
 use crate::client::topology_control::TopologyAccessor;
+use crate::client::traffic_control::{PacketStatistics, TrafficRatesReceiver};
 use futures::task::{Context, Poll};
 use futures::{Future, Stream, StreamExt};
 use log::*;
 use nymsphinx::acknowledgements::AckKey;
-use nymsphinx::addressing::clients::Recipient;
 use nymsphinx::cover::generate_loop_cover_packet;
 use nymsphinx::utils::sample_poisson_duration;
 use rand::{rngs::OsRng, CryptoRng, Rng};
@@ -15,13 +17,14 @@ use std::pin::Pin;
 use std::sync::Arc;
 use tokio::task::JoinHandle;
 use tokio::time;
+use topology::route_selection::{RouteSelector, UniformRouteSelector};
 
 pub struct LoopCoverTrafficStream<R>
 where
     R: CryptoRng + Rng,
 {
     /// Key used to encrypt and decrypt content of an ACK packet.
-    ack_key: Arc<AckKey>,
+    ack_key: RotatableKey<AckKey>,
 
     /// Average delay an acknowledgement packet is going to get delay at a single mixnode.
     average_ack_delay: time::Duration,
@@ -32,6 +35,12 @@ where
     /// Average delay between sending subsequent cover packets.
     average_cover_message_sending_delay: time::Duration,
 
+    /// Channel used for receiving updates to the rate at which cover packets are sent.
+    rates_receiver: TrafficRatesReceiver,
+
+    /// Counters of sent packets shared with the rest of the client.
+    packet_statistics: PacketStatistics,
+
     /// Internal state, determined by `average_message_sending_delay`,
     /// used to keep track of when a next packet should be sent out.
     next_delay: Pin<Box<time::Sleep>>,
@@ -40,14 +49,17 @@ where
     /// out to the network without any further delays.
     mix_tx: BatchMixMessageSender,
 
-    /// Represents full address of this client.
-    our_full_destination: Recipient,
+    /// Represents current full address of this client.
+    our_full_destination: SelfAddressReceiver,
 
     /// Instance of a cryptographically secure random number generator.
     rng: R,
 
     /// Accessor to the common instance of network topology.
     topology_access: TopologyAccessor,
+
+    /// Chooses the mix nodes used for the routes of the cover packets.
+    route_selector: Arc<dyn RouteSelector>,
 }
 
 impl<R> Stream for LoopCoverTrafficStream<R>
@@ -85,62 +97,82 @@ where
 // generic `R`
 impl LoopCoverTrafficStream<OsRng> {
     pub fn new(
-        ack_key: Arc<AckKey>,
+        ack_key: RotatableKey<AckKey>,
         average_ack_delay: time::Duration,
         average_packet_delay: time::Duration,
-        average_cover_message_sending_delay: time::Duration,
+        rates_receiver: TrafficRatesReceiver,
+        packet_statistics: PacketStatistics,
         mix_tx: BatchMixMessageSender,
-        our_full_destination: Recipient,
+        our_full_destination: SelfAddressReceiver,
         topology_access: TopologyAccessor,
     ) -> Self {
         let rng = OsRng;
+        let average_cover_message_sending_delay =
+            rates_receiver.borrow().loop_cover_traffic_average_delay;
 
         LoopCoverTrafficStream {
             ack_key,
             average_ack_delay,
             average_packet_delay,
             average_cover_message_sending_delay,
+            rates_receiver,
+            packet_statistics,
             next_delay: Box::pin(time::sleep(Default::default())),
             mix_tx,
             our_full_destination,
             rng,
             topology_access,
+            route_selector: Arc::new(UniformRouteSelector),
         }
     }
 
+    /// Makes the stream choose the mix nodes by something other than uniformly at random.
+    #[must_use]
+    pub fn with_route_selector(mut self, route_selector: Arc<dyn RouteSelector>) -> Self {
+        self.route_selector = route_selector;
+        self
+    }
+
     async fn on_new_message(&mut self) {
         trace!("next cover message!");
 
         // TODO for way down the line: in very rare cases (during topology update) we might have
         // to wait a really tiny bit before actually obtaining the permit hence messing with our
         // poisson delay, but is it really a problem?
+        let our_full_destination = *self.our_full_destination.borrow();
         let topology_permit = self.topology_access.get_read_permit().await;
         // the ack is sent back to ourselves (and then ignored)
-        let topology_ref_option = topology_permit.try_get_valid_topology_ref(
-            &self.our_full_destination,
-            Some(&self.our_full_destination),
-        );
+        let topology_ref_option = topology_permit
+            .try_get_valid_topology_ref(&our_full_destination, Some(&our_full_destination));
         if topology_ref_option.is_none() {
             warn!("No valid topology detected - won't send any loop cover message this time");
             return;
         }
         let topology_ref = topology_ref_option.unwrap();
 
-        let cover_message = generate_loop_cover_packet(
+        // this can only fail if the route selector has excluded all nodes on some layer
+        let cover_message = match generate_loop_cover_packet(
             &mut self.rng,
             topology_ref,
-            &*self.ack_key,
-            &self.our_full_destination,
+            &*self.ack_key.current(),
+            &our_full_destination,
             self.average_ack_delay,
             self.average_packet_delay,
-        )
-        .expect("Somehow failed to generate a loop cover message with a valid topology");
+            &*self.route_selector,
+        ) {
+            Ok(cover_message) => cover_message,
+            Err(err) => {
+                warn!("Failed to generate a loop cover message - {:?}", err);
+                return;
+            }
+        };
 
         // if this one fails, there's no retrying because it means that either:
         // - we run out of memory
         // - the receiver channel is closed
         // in either case there's no recovery and we can only panic
         self.mix_tx.unbounded_send(vec![cover_message]).unwrap();
+        self.packet_statistics.increment_loop_cover_packets_sent();
 
         // TODO: I'm not entirely sure whether this is really required, because I'm not 100%
         // sure how `yield_now()` works - whether it just notifies the scheduler or whether it
@@ -152,6 +184,22 @@ impl LoopCoverTrafficStream<OsRng> {
         tokio::task::yield_now().await;
     }
 
+    fn on_rates_change(&mut self) {
+        let new_delay = self
+            .rates_receiver
+            .borrow()
+            .loop_cover_traffic_average_delay;
+        debug!(
+            "Changing the average delay between loop cover messages from {:?} to {:?}",
+            self.average_cover_message_sending_delay, new_delay
+        );
+        self.average_cover_message_sending_delay = new_delay;
+
+        // don't wait for the message scheduled with the old rate
+        let next = time::Instant::now() + sample_poisson_duration(&mut self.rng, new_delay);
+        self.next_delay.as_mut().reset(next);
+    }
+
     async fn run(&mut self) {
         // we should set initial delay only when we actually start the stream
         self.next_delay = Box::pin(time::sleep(sample_poisson_duration(
@@ -159,8 +207,17 @@ impl LoopCoverTrafficStream<OsRng> {
             self.average_cover_message_sending_delay,
         )));
 
-        while self.next().await.is_some() {
-            self.on_new_message().await;
+        let mut rates_receiver = self.rates_receiver.clone();
+        loop {
+            tokio::select! {
+                next = self.next() => {
+                    if next.is_none() {
+                        break;
+                    }
+                    self.on_new_message().await;
+                }
+                Ok(_) = rates_receiver.changed() => self.on_rates_change(),
+            }
         }
     }
 
@@ -0,0 +1,115 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use futures::channel::mpsc;
+use nymsphinx::chunking::fragment::FragmentIdentifier;
+use rand::{rngs::OsRng, RngCore};
+use std::fmt::{self, Display, Formatter};
+
+pub type DeliveryReceiptSender = mpsc::UnboundedSender<DeliveryReceipt>;
+pub type DeliveryReceiptReceiver = mpsc::UnboundedReceiver<DeliveryReceipt>;
+
+/// Identifier assigned to every fresh message sent into the mix network, used for
+/// correlating it with its eventual `DeliveryReceipt`.
+pub type MessageId = u64;
+
+pub(crate) fn new_message_id() -> MessageId {
+    OsRng.next_u64()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// Acknowledgements were received for all fragments of the message.
+    Delivered,
+
+    /// At least one fragment of the message was not acknowledged even after it had been
+    /// retransmitted the specified number of times and the whole message got abandoned.
+    GaveUp {
+        retransmissions: u32,
+        /// All fragments of the message that did not get acknowledged.
+        failed_fragments: Vec<FragmentIdentifier>,
+    },
+
+    /// The message was not fully acknowledged before its delivery deadline
+    /// and got abandoned.
+    DeadlineExceeded {
+        /// All fragments of the message that did not get acknowledged.
+        failed_fragments: Vec<FragmentIdentifier>,
+    },
+
+    /// The message could not be turned into sphinx packets, for example because the network
+    /// topology was invalid, so none of it was sent.
+    NotSent,
+}
+
+/// Final outcome of sending a particular message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryReceipt {
+    pub message_id: MessageId,
+    pub status: DeliveryStatus,
+}
+
+impl DeliveryReceipt {
+    pub fn new_delivered(message_id: MessageId) -> Self {
+        DeliveryReceipt {
+            message_id,
+            status: DeliveryStatus::Delivered,
+        }
+    }
+
+    pub fn new_gave_up(
+        message_id: MessageId,
+        retransmissions: u32,
+        failed_fragments: Vec<FragmentIdentifier>,
+    ) -> Self {
+        DeliveryReceipt {
+            message_id,
+            status: DeliveryStatus::GaveUp {
+                retransmissions,
+                failed_fragments,
+            },
+        }
+    }
+
+    pub fn new_deadline_exceeded(
+        message_id: MessageId,
+        failed_fragments: Vec<FragmentIdentifier>,
+    ) -> Self {
+        DeliveryReceipt {
+            message_id,
+            status: DeliveryStatus::DeadlineExceeded { failed_fragments },
+        }
+    }
+
+    pub fn new_not_sent(message_id: MessageId) -> Self {
+        DeliveryReceipt {
+            message_id,
+            status: DeliveryStatus::NotSent,
+        }
+    }
+}
+
+impl Display for DeliveryReceipt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.status {
+            DeliveryStatus::Delivered => write!(f, "message {} was delivered", self.message_id),
+            DeliveryStatus::GaveUp {
+                retransmissions,
+                failed_fragments,
+            } => write!(
+                f,
+                "gave up on message {} after {} retransmissions ({} fragments were lost)",
+                self.message_id,
+                retransmissions,
+                failed_fragments.len()
+            ),
+            DeliveryStatus::DeadlineExceeded { failed_fragments } => write!(
+                f,
+                "message {} was not delivered before its deadline ({} fragments were lost)",
+                self.message_id,
+                failed_fragments.len()
+            ),
+            DeliveryStatus::NotSent => write!(f, "message {} could not be sent", self.message_id),
+        }
+    }
+}
@@ -0,0 +1,529 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::client::key_manager::KeyManager;
+use crate::config::persistence::key_pathfinder::ClientKeyPathfinder;
+use crate::config::{Config, GatewayEndpoint};
+use crate::error::ClientCoreError;
+use config::NymConfig;
+use crypto::asymmetric::identity;
+use gateway_client::bandwidth::BandwidthController;
+use gateway_client::{AcknowledgementSender, GatewayClient, MixnetMessageSender};
+use gateway_requests::registration::handshake::SharedKeys;
+use log::*;
+use nymsphinx::addressing::clients::Recipient;
+use nymsphinx::addressing::nodes::NodeIdentity;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+#[cfg(feature = "coconut")]
+use url::Url;
+
+/// Channel on which the current address of this client is published. It changes whenever
+/// the client switches to a different gateway or rotates its encryption key.
+pub type SelfAddressReceiver = watch::Receiver<Recipient>;
+// the sender is shared between the gateway failover and the key rotator
+pub type SelfAddressSender = Arc<watch::Sender<Recipient>>;
+
+/// Creates the channel used for publishing the current address of this client.
+pub fn self_address_channel(
+    initial_address: Recipient,
+) -> (SelfAddressSender, SelfAddressReceiver) {
+    let (sender, receiver) = watch::channel(initial_address);
+    (Arc::new(sender), receiver)
+}
+
+/// Everything required for establishing an authenticated connection with any gateway
+/// the client has registered with.
+#[derive(Clone)]
+pub struct GatewayConnector {
+    local_identity: Arc<identity::KeyPair>,
+    mixnet_message_sender: MixnetMessageSender,
+    ack_sender: AcknowledgementSender,
+    response_timeout: Duration,
+    disabled_credentials_mode: bool,
+    pull_mode: bool,
+    database_path: PathBuf,
+
+    #[cfg(feature = "coconut")]
+    validator_api_endpoints: Vec<Url>,
+
+    #[cfg(not(feature = "coconut"))]
+    eth_endpoint: String,
+
+    #[cfg(not(feature = "coconut"))]
+    eth_private_key: String,
+}
+
+impl GatewayConnector {
+    pub fn new<T: NymConfig>(
+        config: &Config<T>,
+        local_identity: Arc<identity::KeyPair>,
+        mixnet_message_sender: MixnetMessageSender,
+        ack_sender: AcknowledgementSender,
+    ) -> Self {
+        GatewayConnector {
+            local_identity,
+            mixnet_message_sender,
+            ack_sender,
+            response_timeout: config.get_gateway_response_timeout(),
+            disabled_credentials_mode: config.get_disabled_credentials_mode(),
+            pull_mode: config.get_gateway_pull_mode(),
+            database_path: config.get_database_path(),
+            #[cfg(feature = "coconut")]
+            validator_api_endpoints: config.get_validator_api_endpoints(),
+            #[cfg(not(feature = "coconut"))]
+            eth_endpoint: config.get_eth_endpoint(),
+            #[cfg(not(feature = "coconut"))]
+            eth_private_key: config.get_eth_private_key(),
+        }
+    }
+
+    /// Connects and authenticates with the specified gateway, after which it starts listening
+    /// for any messages and acknowledgements sent by it.
+    pub async fn connect(
+        &self,
+        gateway: &GatewayEndpoint,
+        shared_key: Arc<SharedKeys>,
+    ) -> Result<GatewayClient, ClientCoreError> {
+        let gateway_identity = identity::PublicKey::from_base58_string(&gateway.gateway_id)
+            .map_err(|_| ClientCoreError::MalformedGatewayIdentity)?;
+
+        #[cfg(feature = "coconut")]
+        let bandwidth_controller = BandwidthController::new(
+            credential_storage::initialise_storage(self.database_path.clone()).await,
+            self.validator_api_endpoints.clone(),
+        );
+        #[cfg(not(feature = "coconut"))]
+        let bandwidth_controller = BandwidthController::new(
+            credential_storage::initialise_storage(self.database_path.clone()).await,
+            self.eth_endpoint.clone(),
+            self.eth_private_key.clone(),
+        )?;
+
+        let mut gateway_client = GatewayClient::new(
+            gateway.gateway_listener.clone(),
+            Arc::clone(&self.local_identity),
+            gateway_identity,
+            gateway.gateway_owner.clone(),
+            Some(shared_key),
+            self.mixnet_message_sender.clone(),
+            self.ack_sender.clone(),
+            self.response_timeout,
+            Some(bandwidth_controller),
+        );
+
+        if self.disabled_credentials_mode {
+            gateway_client.set_disabled_credentials_mode(true)
+        }
+        gateway_client.set_pull_mode(self.pull_mode);
+        gateway_client.authenticate_and_start().await?;
+
+        Ok(gateway_client)
+    }
+}
+
+/// Gateway the client has registered with alongside the key it shares with it.
+#[derive(Clone)]
+pub struct RegisteredGateway {
+    endpoint: GatewayEndpoint,
+    shared_key: Arc<SharedKeys>,
+}
+
+impl RegisteredGateway {
+    pub fn new(endpoint: GatewayEndpoint, shared_key: Arc<SharedKeys>) -> Self {
+        RegisteredGateway {
+            endpoint,
+            shared_key,
+        }
+    }
+
+    pub fn endpoint(&self) -> &GatewayEndpoint {
+        &self.endpoint
+    }
+}
+
+/// Callback persisting the gateways in the order established by the failover, i.e. the gateway
+/// the client has switched to, followed by all of the backup gateways.
+pub type GatewayConfigPersister =
+    Box<dyn FnMut(&GatewayEndpoint, &[GatewayEndpoint]) -> io::Result<()> + Send>;
+
+/// Creates a [`GatewayConfigPersister`] updating the gateways in the saved config file
+/// of the client with the specified id.
+// the config is reloaded rather than kept around so that any overrides provided
+// via the command line arguments would not end up being persisted
+pub fn config_file_persister<T, F>(id: String, base_config: F) -> GatewayConfigPersister
+where
+    T: NymConfig,
+    F: Fn(&mut T) -> &mut Config<T> + Send + 'static,
+{
+    Box::new(move |current, backups| {
+        let mut config = T::load_from_file(Some(&id))?;
+        let base = base_config(&mut config);
+        base.with_gateway_endpoint(current.clone());
+        base.with_backup_gateway_endpoints(backups.to_vec());
+        config.save_to_file(None)
+    })
+}
+
+/// The order in which the client is going to use the gateways it has registered with.
+struct GatewayRotation {
+    /// Gateway the client is currently connected to.
+    current: RegisteredGateway,
+
+    /// Gateways the client is going to try to switch to, in order.
+    backups: VecDeque<RegisteredGateway>,
+}
+
+impl GatewayRotation {
+    fn new(current: RegisteredGateway, backups: Vec<RegisteredGateway>) -> Self {
+        GatewayRotation {
+            current,
+            backups: backups.into(),
+        }
+    }
+
+    /// Attempts to connect to each of the backups, in order, until one of them succeeds.
+    /// The gateway that was switched from becomes the last backup so that the client could
+    /// eventually come back to it, while any gateway that has failed is moved behind it.
+    async fn switch_to_backup<C, F, Fut>(&mut self, mut connect: F) -> Option<C>
+    where
+        F: FnMut(RegisteredGateway) -> Fut,
+        Fut: Future<Output = Result<C, ClientCoreError>>,
+    {
+        for _ in 0..self.backups.len() {
+            let candidate = self.backups.pop_front()?;
+            info!(
+                "Attempting to fail over from gateway {} to {}",
+                self.current.endpoint.gateway_id, candidate.endpoint.gateway_id
+            );
+
+            match connect(candidate.clone()).await {
+                Ok(connection) => {
+                    let previous = std::mem::replace(&mut self.current, candidate);
+                    self.backups.push_back(previous);
+                    return Some(connection);
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to connect to the backup gateway {} - {}",
+                        candidate.endpoint.gateway_id, err
+                    );
+                    self.backups.push_back(candidate);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn backup_endpoints(&self) -> Vec<GatewayEndpoint> {
+        self.backups
+            .iter()
+            .map(|backup| backup.endpoint.clone())
+            .collect()
+    }
+}
+
+/// Keeps track of the gateway currently used by the client and of all the backup gateways
+/// it could switch to once the current one stops responding.
+pub struct GatewayFailover {
+    connector: GatewayConnector,
+
+    /// Gateways the client has registered with, in the order they're going to be used.
+    rotation: GatewayRotation,
+
+    /// Channel on which the address of the client is published after switching the gateway.
+    address_sender: SelfAddressSender,
+
+    /// The interval at which the connection to the current gateway is checked.
+    health_check_interval: Duration,
+
+    /// Number of consecutive failures after which the gateway is considered dead.
+    failure_threshold: usize,
+
+    /// If specified, the gateway the client has switched to is persisted alongside its shared key
+    /// so that the client would keep on using it after being restarted.
+    persistence: Option<(ClientKeyPathfinder, GatewayConfigPersister)>,
+}
+
+impl GatewayFailover {
+    pub fn new(
+        connector: GatewayConnector,
+        current: RegisteredGateway,
+        backups: Vec<RegisteredGateway>,
+        address_sender: SelfAddressSender,
+        health_check_interval: Duration,
+        failure_threshold: u32,
+    ) -> Self {
+        GatewayFailover {
+            connector,
+            rotation: GatewayRotation::new(current, backups),
+            address_sender,
+            health_check_interval,
+            failure_threshold: failure_threshold.max(1) as usize,
+            persistence: None,
+        }
+    }
+
+    /// Creates the failover using all of the backup gateways specified in the config for which
+    /// the shared keys are available. Returns `None` if there are no usable backup gateways.
+    pub fn from_config<T: NymConfig>(
+        config: &Config<T>,
+        key_manager: &KeyManager,
+        connector: GatewayConnector,
+        address_sender: SelfAddressSender,
+    ) -> Option<Self> {
+        let backups = config
+            .get_backup_gateway_endpoints()
+            .iter()
+            .filter_map(|endpoint| {
+                key_manager
+                    .backup_gateway_shared_key(&endpoint.gateway_id)
+                    .map(|shared_key| RegisteredGateway::new(endpoint.clone(), shared_key))
+            })
+            .collect::<Vec<_>>();
+
+        if backups.is_empty() {
+            return None;
+        }
+
+        info!("{} backup gateway(s) available for failover", backups.len());
+        let current = RegisteredGateway::new(
+            config.get_gateway_endpoint().clone(),
+            key_manager.gateway_shared_key(),
+        );
+
+        Some(GatewayFailover::new(
+            connector,
+            current,
+            backups,
+            address_sender,
+            config.get_gateway_health_check_interval(),
+            config.get_gateway_failover_threshold(),
+        ))
+    }
+
+    /// Makes the failover persist the gateway it has switched to, so that the client would
+    /// not go back to the dead gateway after being restarted.
+    #[must_use]
+    pub fn with_persistence(
+        mut self,
+        key_pathfinder: ClientKeyPathfinder,
+        config_persister: GatewayConfigPersister,
+    ) -> Self {
+        self.persistence = Some((key_pathfinder, config_persister));
+        self
+    }
+
+    pub(crate) fn health_check_interval(&self) -> Duration {
+        self.health_check_interval
+    }
+
+    pub(crate) fn failure_threshold(&self) -> usize {
+        self.failure_threshold
+    }
+
+    pub(crate) fn has_backups(&self) -> bool {
+        !self.rotation.backups.is_empty()
+    }
+
+    /// Attempts to connect to each of the backup gateways, in order, until one of them succeeds.
+    /// Upon success, the new address of this client is published and the new gateway is persisted.
+    pub(crate) async fn fail_over(&mut self) -> Option<GatewayClient> {
+        let connector = &self.connector;
+        let gateway_client = self
+            .rotation
+            .switch_to_backup(|candidate| async move {
+                connector
+                    .connect(&candidate.endpoint, candidate.shared_key)
+                    .await
+            })
+            .await?;
+
+        self.publish_new_address(gateway_client.gateway_identity());
+        self.persist_current_gateway();
+        Some(gateway_client)
+    }
+
+    fn persist_current_gateway(&mut self) {
+        let (key_pathfinder, config_persister) = match self.persistence.as_mut() {
+            Some(persistence) => persistence,
+            None => return,
+        };
+
+        // the keys are stored first so that the config would never point to a gateway
+        // the client does not have the shared key of
+        if let Err(err) = store_shared_keys(&self.rotation, key_pathfinder) {
+            warn!(
+                "Failed to store the keys shared with the gateways - {}. The client will go back to its previous gateway after restarting",
+                err
+            );
+            return;
+        }
+
+        let backups = self.rotation.backup_endpoints();
+        if let Err(err) = config_persister(&self.rotation.current.endpoint, &backups) {
+            warn!(
+                "Failed to save gateway {} in the config - {}. The client will go back to its previous gateway after restarting",
+                self.rotation.current.endpoint.gateway_id, err
+            )
+        }
+    }
+
+    fn publish_new_address(&self, gateway_identity: NodeIdentity) {
+        let previous_address = *self.address_sender.borrow();
+        let new_address = Recipient::new(
+            *previous_address.identity(),
+            *previous_address.encryption_key(),
+            gateway_identity,
+        );
+
+        info!("The new address of this client is: {}", new_address);
+        if self.address_sender.send(new_address).is_err() {
+            // this can only happen if the entire client is being shut down
+            warn!("Nobody is listening for the address changes of this client");
+        }
+    }
+}
+
+fn store_shared_keys(
+    rotation: &GatewayRotation,
+    key_pathfinder: &ClientKeyPathfinder,
+) -> io::Result<()> {
+    for backup in &rotation.backups {
+        pemstore::store_key(
+            backup.shared_key.as_ref(),
+            &key_pathfinder.backup_gateway_shared_key(&backup.endpoint.gateway_id),
+        )?;
+    }
+    pemstore::store_key(
+        rotation.current.shared_key.as_ref(),
+        key_pathfinder.gateway_shared_key(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gateway_requests::generic_array::typenum::Unsigned;
+    use gateway_requests::registration::handshake::SharedKeySize;
+
+    fn gateway(id: u8) -> RegisteredGateway {
+        let endpoint = GatewayEndpoint {
+            gateway_id: format!("gateway{}", id),
+            gateway_owner: format!("owner{}", id),
+            gateway_listener: format!("ws://127.0.0.{}:9000", id),
+        };
+        let shared_key = SharedKeys::try_from_bytes(&vec![id; SharedKeySize::to_usize()]).unwrap();
+        RegisteredGateway::new(endpoint, Arc::new(shared_key))
+    }
+
+    fn ids(rotation: &GatewayRotation) -> (String, Vec<String>) {
+        (
+            rotation.current.endpoint.gateway_id.clone(),
+            rotation
+                .backup_endpoints()
+                .into_iter()
+                .map(|endpoint| endpoint.gateway_id)
+                .collect(),
+        )
+    }
+
+    // pretends only the gateways with the specified ids are alive
+    async fn connect_to_alive(
+        alive: &[&str],
+        candidate: RegisteredGateway,
+    ) -> Result<String, ClientCoreError> {
+        if alive.contains(&candidate.endpoint.gateway_id.as_str()) {
+            Ok(candidate.endpoint.gateway_id)
+        } else {
+            Err(ClientCoreError::MalformedGatewayIdentity)
+        }
+    }
+
+    #[tokio::test]
+    async fn switches_to_first_backup_and_keeps_previous_as_last() {
+        let mut rotation = GatewayRotation::new(gateway(1), vec![gateway(2), gateway(3)]);
+
+        let connected = rotation
+            .switch_to_backup(|candidate| connect_to_alive(&["gateway2", "gateway3"], candidate))
+            .await;
+
+        assert_eq!(connected.unwrap(), "gateway2");
+        assert_eq!(
+            ids(&rotation),
+            (
+                "gateway2".to_string(),
+                vec!["gateway3".to_string(), "gateway1".to_string()]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_dead_backups() {
+        let mut rotation =
+            GatewayRotation::new(gateway(1), vec![gateway(2), gateway(3), gateway(4)]);
+
+        let connected = rotation
+            .switch_to_backup(|candidate| connect_to_alive(&["gateway3"], candidate))
+            .await;
+
+        assert_eq!(connected.unwrap(), "gateway3");
+        // the gateway that failed is tried again only after all the others
+        assert_eq!(
+            ids(&rotation),
+            (
+                "gateway3".to_string(),
+                vec![
+                    "gateway4".to_string(),
+                    "gateway2".to_string(),
+                    "gateway1".to_string()
+                ]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_current_gateway_if_all_backups_are_dead() {
+        let mut rotation = GatewayRotation::new(gateway(1), vec![gateway(2), gateway(3)]);
+
+        let mut attempts = 0;
+        let connected = rotation
+            .switch_to_backup(|candidate| {
+                attempts += 1;
+                connect_to_alive(&[], candidate)
+            })
+            .await;
+
+        assert!(connected.is_none());
+        // every backup is attempted exactly once
+        assert_eq!(attempts, 2);
+        assert_eq!(
+            ids(&rotation),
+            (
+                "gateway1".to_string(),
+                vec!["gateway2".to_string(), "gateway3".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn shared_keys_are_stored_for_chosen_gateways() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_pathfinder = ClientKeyPathfinder::new_in_directory(dir.path().to_path_buf());
+        let rotation = GatewayRotation::new(gateway(2), vec![gateway(1)]);
+
+        store_shared_keys(&rotation, &key_pathfinder).unwrap();
+
+        let current: SharedKeys = pemstore::load_key(key_pathfinder.gateway_shared_key()).unwrap();
+        let previous: SharedKeys =
+            pemstore::load_key(&key_pathfinder.backup_gateway_shared_key("gateway1")).unwrap();
+        assert_eq!(current.to_bytes(), gateway(2).shared_key.to_bytes());
+        assert_eq!(previous.to_bytes(), gateway(1).shared_key.to_bytes());
+    }
+}
@@ -1,6 +1,8 @@
-use futures::channel::mpsc;
+use crate::client::delivery_receipts::{new_message_id, DeliveryReceiptSender, MessageId};
+use futures::channel::{mpsc, oneshot};
 use nymsphinx::addressing::clients::Recipient;
-use nymsphinx::anonymous_replies::ReplySurb;
+use nymsphinx::anonymous_replies::{encryption_key::EncryptionKeyDigest, ReplySurb};
+use nymsphinx::headers::MessageHeaders;
 
 pub type InputMessageSender = mpsc::UnboundedSender<InputMessage>;
 pub type InputMessageReceiver = mpsc::UnboundedReceiver<InputMessage>;
@@ -10,7 +12,19 @@ pub enum InputMessage {
     Fresh {
         recipient: Recipient,
         data: Vec<u8>,
-        with_reply_surb: bool,
+        /// Number of reply SURBs to attach to the message so that the recipient could
+        /// send back (possibly multi-packet) anonymous replies.
+        num_reply_surbs: u8,
+        /// Identifier of this message that is going to be included in its `DeliveryReceipt`.
+        message_id: MessageId,
+        /// If specified, channel onto which the `DeliveryReceipt` is going to be pushed once
+        /// the message is either fully acknowledged or abandoned.
+        receipt_sender: Option<DeliveryReceiptSender>,
+        /// Metadata attached to the message, encrypted alongside it for the recipient.
+        headers: Option<MessageHeaders>,
+        /// If specified, channel onto which the digests of the keys of all attached reply SURBs
+        /// are sent once the message is prepared, so that the replies could be matched with it.
+        reply_key_digests_sender: Option<oneshot::Sender<Vec<EncryptionKeyDigest>>>,
     },
     Reply {
         reply_surb: ReplySurb,
@@ -19,15 +33,77 @@ pub enum InputMessage {
 }
 
 impl InputMessage {
-    pub fn new_fresh(recipient: Recipient, data: Vec<u8>, with_reply_surb: bool) -> Self {
-        InputMessage::Fresh {
+    pub fn new_fresh(recipient: Recipient, data: Vec<u8>, num_reply_surbs: u8) -> Self {
+        Self::new_fresh_with_id(recipient, data, num_reply_surbs).0
+    }
+
+    /// Creates a fresh message alongside the id assigned to it, which is going to be used
+    /// in its `DeliveryReceipt`.
+    pub fn new_fresh_with_id(
+        recipient: Recipient,
+        data: Vec<u8>,
+        num_reply_surbs: u8,
+    ) -> (Self, MessageId) {
+        let message_id = new_message_id();
+        let message = InputMessage::Fresh {
             recipient,
             data,
-            with_reply_surb,
-        }
+            num_reply_surbs,
+            message_id,
+            receipt_sender: None,
+            headers: None,
+            reply_key_digests_sender: None,
+        };
+        (message, message_id)
     }
 
     pub fn new_reply(reply_surb: ReplySurb, data: Vec<u8>) -> Self {
         InputMessage::Reply { reply_surb, data }
     }
+
+    /// Requests a `DeliveryReceipt` to be sent on the provided channel once the fate of this
+    /// message is known. It has no effect on replies as they are not being acknowledged.
+    #[must_use]
+    pub fn with_receipt_sender(mut self, sender: DeliveryReceiptSender) -> Self {
+        if let InputMessage::Fresh { receipt_sender, .. } = &mut self {
+            *receipt_sender = Some(sender)
+        }
+        self
+    }
+
+    /// Attaches the provided headers to the message. It has no effect on replies
+    /// as there's no space for them in a reply SURB.
+    #[must_use]
+    pub fn with_headers(mut self, message_headers: MessageHeaders) -> Self {
+        if let InputMessage::Fresh { headers, .. } = &mut self {
+            *headers = Some(message_headers)
+        }
+        self
+    }
+
+    /// Requests the digests of the keys of the attached reply SURBs to be sent on the provided
+    /// channel once the message is prepared. It has no effect on replies as they can't carry
+    /// any reply SURBs.
+    #[must_use]
+    pub fn with_reply_key_digests_sender(
+        mut self,
+        sender: oneshot::Sender<Vec<EncryptionKeyDigest>>,
+    ) -> Self {
+        if let InputMessage::Fresh {
+            reply_key_digests_sender,
+            ..
+        } = &mut self
+        {
+            *reply_key_digests_sender = Some(sender)
+        }
+        self
+    }
+
+    /// Returns the identifier of this message, if it's a fresh one.
+    pub fn message_id(&self) -> Option<MessageId> {
+        match self {
+            InputMessage::Fresh { message_id, .. } => Some(*message_id),
+            InputMessage::Reply { .. } => None,
+        }
+    }
 }
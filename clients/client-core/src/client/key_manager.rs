@@ -1,34 +1,40 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::client::key_rotation::{KeyRotationState, RotatableKey};
 use crate::config::persistence::key_pathfinder::ClientKeyPathfinder;
 use crypto::asymmetric::{encryption, identity};
 use gateway_requests::registration::handshake::SharedKeys;
 use log::*;
 use nymsphinx::acknowledgements::AckKey;
 use rand::{CryptoRng, RngCore};
+use std::collections::HashMap;
 use std::io;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-// Note: to support key rotation in the future, all keys will require adding an extra smart pointer,
-// most likely an AtomicCell, or if it doesn't work as I think it does, a Mutex. Although I think
-// AtomicCell includes a Mutex implicitly if the underlying type does not work atomically.
-// And I guess there will need to be some mechanism for a grace period when you can still
-// use the old key after new one was issued.
+// Note: the encryption and ack keys can be rotated while the client is running, so they're wrapped
+// in a `RotatableKey` that keeps the replaced key around for the duration of the grace period.
+// The identity key is never rotated as it determines the identity of the client.
 
 // Remember that Arc<T> has Deref implementation for T
+// note that clone here is fine as upon cloning the same underlying rotatable keys will be used
+#[derive(Clone)]
 pub struct KeyManager {
     /// identity key associated with the client instance.
     identity_keypair: Arc<identity::KeyPair>,
 
     /// encryption key associated with the client instance.
-    encryption_keypair: Arc<encryption::KeyPair>,
+    encryption_keypair: RotatableKey<encryption::KeyPair>,
 
     /// shared key derived with the gateway during "registration handshake"
     gateway_shared_key: Option<Arc<SharedKeys>>,
 
+    /// shared keys derived with the backup gateways, indexed by their identities
+    backup_gateway_shared_keys: HashMap<String, Arc<SharedKeys>>,
+
     /// key used for producing and processing acknowledgement packets.
-    ack_key: Arc<AckKey>,
+    ack_key: RotatableKey<AckKey>,
 }
 
 // The expected flow of a KeyManager "lifetime" is as follows:
@@ -37,7 +43,7 @@ pub struct KeyManager {
    2. after gateway registration is completed [in init] ::insert_gateway_shared_key() is called
    3. ::store_keys() is called before init finishes execution.
    4. ::load_keys() is called at the beginning of each subsequent client-run
-   5. [not implemented] ::rotate_keys() is called periodically during client-run I presume?
+   5. ::rotate_keys() is called periodically during client-run if key rotation is enabled
 */
 
 impl KeyManager {
@@ -51,9 +57,10 @@ impl KeyManager {
     {
         KeyManager {
             identity_keypair: Arc::new(identity::KeyPair::new(rng)),
-            encryption_keypair: Arc::new(encryption::KeyPair::new(rng)),
+            encryption_keypair: RotatableKey::new(encryption::KeyPair::new(rng)),
             gateway_shared_key: None,
-            ack_key: Arc::new(AckKey::new(rng)),
+            backup_gateway_shared_keys: HashMap::new(),
+            ack_key: RotatableKey::new(AckKey::new(rng)),
         }
     }
 
@@ -65,7 +72,18 @@ impl KeyManager {
         self.gateway_shared_key = Some(gateway_shared_key)
     }
 
+    /// After shared key with a backup gateway is derived, puts its ownership to this instance of a [`KeyManager`].
+    pub fn insert_backup_gateway_shared_key(
+        &mut self,
+        gateway_id: String,
+        gateway_shared_key: Arc<SharedKeys>,
+    ) {
+        self.backup_gateway_shared_keys
+            .insert(gateway_id, gateway_shared_key);
+    }
+
     /// Loads previously stored keys from the disk.
+    /// Missing keys of backup gateways are not treated as an error - the gateways are skipped instead.
     pub fn load_keys(client_pathfinder: &ClientKeyPathfinder) -> io::Result<Self> {
         let identity_keypair: identity::KeyPair =
             pemstore::load_keypair(&pemstore::KeyPairPath::new(
@@ -83,14 +101,115 @@ impl KeyManager {
 
         let ack_key: AckKey = pemstore::load_key(client_pathfinder.ack_key())?;
 
-        // TODO: ack key is never stored so it is generated now. But perhaps it should be stored
-        // after all for consistency sake?
-        Ok(KeyManager {
+        let mut backup_gateway_shared_keys = HashMap::new();
+        for gateway_id in client_pathfinder.backup_gateway_ids() {
+            match pemstore::load_key::<SharedKeys>(
+                &client_pathfinder.backup_gateway_shared_key(gateway_id),
+            ) {
+                Ok(shared_key) => {
+                    backup_gateway_shared_keys.insert(gateway_id.clone(), Arc::new(shared_key));
+                }
+                Err(err) => warn!(
+                    "Failed to load the key shared with the backup gateway {} - {}. It will not be used.",
+                    gateway_id, err
+                ),
+            }
+        }
+
+        let key_manager = KeyManager {
             identity_keypair: Arc::new(identity_keypair),
-            encryption_keypair: Arc::new(encryption_keypair),
+            encryption_keypair: RotatableKey::new(encryption_keypair),
             gateway_shared_key: Some(Arc::new(gateway_shared_key)),
-            ack_key: Arc::new(ack_key),
-        })
+            backup_gateway_shared_keys,
+            ack_key: RotatableKey::new(ack_key),
+        };
+        key_manager.load_previous_keys(client_pathfinder);
+
+        Ok(key_manager)
+    }
+
+    /// Loads the keys replaced during the latest rotation, if their grace period has not expired yet.
+    /// Any failure is not treated as an error as the previous keys are not essential for running the client.
+    fn load_previous_keys(&self, client_pathfinder: &ClientKeyPathfinder) {
+        let rotation_state = match KeyRotationState::load(client_pathfinder.key_rotation_state()) {
+            Ok(state) => state,
+            // the keys have never been rotated
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return,
+            Err(err) => {
+                warn!("Failed to load the key rotation state - {}", err);
+                return;
+            }
+        };
+
+        let valid_until = rotation_state.previous_keys_valid_until();
+        if valid_until <= SystemTime::now() {
+            debug!("The grace period of the previous keys has already expired");
+            return;
+        }
+
+        let previous_encryption_keypair: io::Result<encryption::KeyPair> =
+            pemstore::load_keypair(&pemstore::KeyPairPath::new(
+                client_pathfinder.previous_private_encryption_key(),
+                client_pathfinder.previous_public_encryption_key(),
+            ));
+        match previous_encryption_keypair {
+            Ok(keypair) => self.encryption_keypair.set_previous(keypair, valid_until),
+            Err(err) => warn!("Failed to load the previous encryption key - {}", err),
+        }
+
+        match pemstore::load_key::<AckKey>(&client_pathfinder.previous_ack_key()) {
+            Ok(ack_key) => self.ack_key.set_previous(ack_key, valid_until),
+            Err(err) => warn!("Failed to load the previous ack key - {}", err),
+        }
+    }
+
+    /// Generates new encryption and ack keys. The replaced keys remain valid for processing
+    /// received packets for the duration of the grace period.
+    /// The new keys are persisted before being put in use so that the client would not lose them upon restart.
+    pub fn rotate_keys<R>(
+        &self,
+        rng: &mut R,
+        grace_period: Duration,
+        client_pathfinder: &ClientKeyPathfinder,
+    ) -> io::Result<()>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let new_encryption_keypair = encryption::KeyPair::new(rng);
+        let new_ack_key = AckKey::new(rng);
+
+        let now = SystemTime::now();
+        let previous_valid_until = now + grace_period;
+
+        pemstore::store_keypair(
+            self.encryption_keypair.current().as_ref(),
+            &pemstore::KeyPairPath::new(
+                client_pathfinder.previous_private_encryption_key(),
+                client_pathfinder.previous_public_encryption_key(),
+            ),
+        )?;
+        pemstore::store_key(
+            self.ack_key.current().as_ref(),
+            &client_pathfinder.previous_ack_key(),
+        )?;
+
+        pemstore::store_keypair(
+            &new_encryption_keypair,
+            &pemstore::KeyPairPath::new(
+                client_pathfinder.private_encryption_key().to_owned(),
+                client_pathfinder.public_encryption_key().to_owned(),
+            ),
+        )?;
+        pemstore::store_key(&new_ack_key, client_pathfinder.ack_key())?;
+
+        KeyRotationState::new(now, previous_valid_until)
+            .save(client_pathfinder.key_rotation_state())?;
+
+        self.encryption_keypair
+            .rotate(new_encryption_keypair, previous_valid_until);
+        self.ack_key.rotate(new_ack_key, previous_valid_until);
+
+        Ok(())
     }
 
     // this is actually **NOT** dead code
@@ -109,14 +228,14 @@ impl KeyManager {
             ),
         )?;
         pemstore::store_keypair(
-            self.encryption_keypair.as_ref(),
+            self.encryption_keypair.current().as_ref(),
             &pemstore::KeyPairPath::new(
                 client_pathfinder.private_encryption_key().to_owned(),
                 client_pathfinder.public_encryption_key().to_owned(),
             ),
         )?;
 
-        pemstore::store_key(self.ack_key.as_ref(), client_pathfinder.ack_key())?;
+        pemstore::store_key(self.ack_key.current().as_ref(), client_pathfinder.ack_key())?;
 
         match self.gateway_shared_key.as_ref() {
             None => warn!("No gateway shared key available to store!"),
@@ -125,6 +244,13 @@ impl KeyManager {
             }
         }
 
+        for (gateway_id, gate_key) in &self.backup_gateway_shared_keys {
+            pemstore::store_key(
+                gate_key.as_ref(),
+                &client_pathfinder.backup_gateway_shared_key(gateway_id),
+            )?
+        }
+
         Ok(())
     }
 
@@ -133,9 +259,15 @@ impl KeyManager {
         Arc::clone(&self.identity_keypair)
     }
 
-    /// Gets an atomically reference counted pointer to [`encryption::KeyPair`].
+    /// Gets an atomically reference counted pointer to the current [`encryption::KeyPair`].
     pub fn encryption_keypair(&self) -> Arc<encryption::KeyPair> {
-        Arc::clone(&self.encryption_keypair)
+        self.encryption_keypair.current()
+    }
+
+    /// Gets a handle to the rotatable [`encryption::KeyPair`], which also gives access to the previous key
+    /// during its grace period.
+    pub fn encryption_keys(&self) -> RotatableKey<encryption::KeyPair> {
+        self.encryption_keypair.clone()
     }
 
     /// Gets an atomically reference counted pointer to [`SharedKey`].
@@ -149,8 +281,20 @@ impl KeyManager {
         )
     }
 
-    /// Gets an atomically reference counted pointer to [`AckKey`].
+    /// Gets an atomically reference counted pointer to [`SharedKey`] derived with the specified
+    /// backup gateway, if available.
+    pub fn backup_gateway_shared_key(&self, gateway_id: &str) -> Option<Arc<SharedKeys>> {
+        self.backup_gateway_shared_keys.get(gateway_id).cloned()
+    }
+
+    /// Gets an atomically reference counted pointer to the current [`AckKey`].
     pub fn ack_key(&self) -> Arc<AckKey> {
-        Arc::clone(&self.ack_key)
+        self.ack_key.current()
+    }
+
+    /// Gets a handle to the rotatable [`AckKey`], which also gives access to the previous key
+    /// during its grace period.
+    pub fn ack_keys(&self) -> RotatableKey<AckKey> {
+        self.ack_key.clone()
     }
 }
@@ -0,0 +1,616 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::client::gateway_failover::SelfAddressSender;
+use crate::client::key_manager::KeyManager;
+use crate::config::persistence::key_pathfinder::ClientKeyPathfinder;
+use crate::config::Config;
+use crate::error::ClientCoreError;
+use config::NymConfig;
+use log::*;
+use nymsphinx::addressing::clients::Recipient;
+use nymsphinx::addressing::nodes::NodeIdentity;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+
+/// Delay before retrying the rotation if the previous attempt has failed,
+/// for example because the keys could not have been written to the disk.
+const KEY_ROTATION_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Time after which an empty lock is no longer considered to be in the middle of being acquired,
+/// but to be left behind by a client that crashed before writing its process id into it.
+const EMPTY_LOCK_RECLAIM_DELAY: Duration = Duration::from_secs(5);
+
+struct PreviousKey<K> {
+    key: Arc<K>,
+    valid_until: SystemTime,
+}
+
+struct RotatableKeyInner<K> {
+    current: Arc<K>,
+    previous: Option<PreviousKey<K>>,
+}
+
+/// Key that can be replaced while the client is running. The replaced key is still available
+/// for processing received packets until the end of its grace period.
+pub struct RotatableKey<K> {
+    inner: Arc<RwLock<RotatableKeyInner<K>>>,
+}
+
+// clone is implemented manually as the derived one would require `K: Clone`,
+// upon cloning the same underlying keys will be used
+impl<K> Clone for RotatableKey<K> {
+    fn clone(&self) -> Self {
+        RotatableKey {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<K> RotatableKey<K> {
+    pub fn new(key: K) -> Self {
+        RotatableKey {
+            inner: Arc::new(RwLock::new(RotatableKeyInner {
+                current: Arc::new(key),
+                previous: None,
+            })),
+        }
+    }
+
+    /// Gets the key that should be used for anything that is about to be sent out.
+    pub fn current(&self) -> Arc<K> {
+        Arc::clone(&self.inner.read().unwrap().current)
+    }
+
+    /// Gets the key that was replaced during the latest rotation, if its grace period
+    /// has not expired yet.
+    pub fn previous(&self) -> Option<Arc<K>> {
+        self.inner
+            .read()
+            .unwrap()
+            .previous
+            .as_ref()
+            .filter(|previous| previous.valid_until > SystemTime::now())
+            .map(|previous| Arc::clone(&previous.key))
+    }
+
+    /// Gets all keys that are still valid for processing received packets, starting with
+    /// the current one.
+    pub fn valid_keys(&self) -> Vec<Arc<K>> {
+        std::iter::once(self.current())
+            .chain(self.previous())
+            .collect()
+    }
+
+    pub(crate) fn set_previous(&self, key: K, valid_until: SystemTime) {
+        self.inner.write().unwrap().previous = Some(PreviousKey {
+            key: Arc::new(key),
+            valid_until,
+        })
+    }
+
+    /// Replaces the current key with the provided one. The replaced key remains valid until
+    /// the specified time, while the key replaced in any earlier rotation is dropped.
+    pub(crate) fn rotate(&self, new_key: K, previous_valid_until: SystemTime) {
+        let mut inner = self.inner.write().unwrap();
+        let previous = std::mem::replace(&mut inner.current, Arc::new(new_key));
+        inner.previous = Some(PreviousKey {
+            key: previous,
+            valid_until: previous_valid_until,
+        });
+    }
+}
+
+fn to_unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default()
+}
+
+fn from_unix_timestamp(timestamp: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(timestamp)
+}
+
+/// Information about the latest key rotation, persisted next to the keys themselves.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct KeyRotationState {
+    /// Unix timestamp of the latest rotation.
+    rotated_at: u64,
+
+    /// Unix timestamp until which the keys replaced during the latest rotation remain valid.
+    previous_keys_valid_until: u64,
+}
+
+impl KeyRotationState {
+    pub(crate) fn new(rotated_at: SystemTime, previous_keys_valid_until: SystemTime) -> Self {
+        KeyRotationState {
+            rotated_at: to_unix_timestamp(rotated_at),
+            previous_keys_valid_until: to_unix_timestamp(previous_keys_valid_until),
+        }
+    }
+
+    pub(crate) fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        serde_json::from_str(&content)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub(crate) fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        // serialization of a struct with only integer fields can't fail
+        let content = serde_json::to_string_pretty(self).unwrap();
+        fs::write(path, content)
+    }
+
+    pub(crate) fn rotated_at(&self) -> SystemTime {
+        from_unix_timestamp(self.rotated_at)
+    }
+
+    pub(crate) fn previous_keys_valid_until(&self) -> SystemTime {
+        from_unix_timestamp(self.previous_keys_valid_until)
+    }
+}
+
+/// Returns the id of the process holding the lock at the provided path, as long as it's still
+/// running. Locks left behind by clients that have crashed are treated as free.
+fn running_lock_owner(path: &Path) -> Option<u32> {
+    let pid: u32 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    is_process_running(pid).then(|| pid)
+}
+
+#[cfg(target_family = "unix")]
+fn is_process_running(pid: u32) -> bool {
+    // 0 would refer to our own process group
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => pid,
+        _ => return false,
+    };
+    // sending no signal at all only checks whether the process exists. If we're not allowed
+    // to signal it, it exists as well, it just belongs to somebody else
+    // SAFETY: the null signal doesn't affect the target process in any way
+    unsafe { libc::kill(pid, 0) == 0 }
+    || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// there's no cheap way of checking it elsewhere, so the lock of a crashed client
+// has to be removed by hand
+#[cfg(not(target_family = "unix"))]
+fn is_process_running(_pid: u32) -> bool {
+    true
+}
+
+/// The lock is created empty and the id of its process is written into it right after,
+/// so a fresh empty lock is most likely being acquired by another client at this very moment.
+/// However, if it stays empty for a while, its client must have crashed in between.
+fn is_lock_being_acquired(path: &Path, now: SystemTime) -> bool {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return false,
+    };
+    if metadata.len() != 0 {
+        return false;
+    }
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| now.duration_since(modified).ok())
+        .map(|age| age < EMPTY_LOCK_RECLAIM_DELAY)
+        // if we can't tell its age, better safe than sorry
+        .unwrap_or(true)
+}
+
+/// Marks the keys of a client as being used by its running instance, so that they would not get
+/// rotated from under it, for example by the `rotate-keys` command. The mark, containing the id
+/// of the process, is removed once the lock is dropped.
+pub struct RunningClientLock {
+    path: PathBuf,
+}
+
+impl RunningClientLock {
+    /// Marks the keys as being in use by the current process. Fails if they're already in use
+    /// by another running client, while a lock left behind by a client that has crashed
+    /// is reclaimed.
+    pub fn acquire(pathfinder: &ClientKeyPathfinder) -> Result<Self, ClientCoreError> {
+        let path = pathfinder.running_client_lock();
+        match Self::create(&path) {
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            result => return result.map_err(Into::into),
+        }
+
+        if is_lock_being_acquired(&path, SystemTime::now()) || running_lock_owner(&path).is_some() {
+            return Err(ClientCoreError::ClientRunning(path));
+        }
+
+        warn!(
+            "Removing the lock {} left behind by a client that is no longer running",
+            path.display()
+        );
+        if let Err(err) = fs::remove_file(&path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err.into());
+            }
+        }
+
+        // if another client has reclaimed the lock in the meantime, we fail here
+        Self::create(&path).map_err(|err| match err.kind() {
+            io::ErrorKind::AlreadyExists => ClientCoreError::ClientRunning(path.clone()),
+            _ => err.into(),
+        })
+    }
+
+    // atomically creates the lock, failing if it already exists
+    fn create(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        // from now on the lock is ours, so make sure it gets removed even if the write fails
+        let lock = RunningClientLock {
+            path: path.to_path_buf(),
+        };
+        file.write_all(std::process::id().to_string().as_bytes())?;
+        Ok(lock)
+    }
+}
+
+impl Drop for RunningClientLock {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!(
+                "Failed to remove the lock file {} - {}",
+                self.path.display(),
+                err
+            )
+        }
+    }
+}
+
+/// Replaces the stored encryption and ack keys of a client that is not currently running.
+/// The previous keys remain valid for the configured grace period. Returns the new address
+/// of the client.
+pub fn rotate_stored_keys<T: NymConfig>(config: &Config<T>) -> Result<Recipient, ClientCoreError> {
+    let pathfinder = ClientKeyPathfinder::new_from_config(config);
+    // holding the lock for the duration of the rotation makes sure the client
+    // can't be started while its keys are being replaced
+    let _lock = RunningClientLock::acquire(&pathfinder)?;
+
+    let gateway = NodeIdentity::from_base58_string(config.get_gateway_id())
+        .map_err(|_| ClientCoreError::MalformedGatewayIdentity)?;
+    let key_manager = KeyManager::load_keys(&pathfinder)?;
+    key_manager.rotate_keys(
+        &mut OsRng,
+        config.get_key_rotation_grace_period(),
+        &pathfinder,
+    )?;
+
+    Ok(Recipient::new(
+        *key_manager.identity_keypair().public_key(),
+        *key_manager.encryption_keypair().public_key(),
+        // TODO: below only works under assumption that gateway address == gateway id
+        // (which currently is true)
+        gateway,
+    ))
+}
+
+/// Body of the `rotate-keys` command of the client binaries. If a new rotation interval or grace
+/// period is provided, only the schedule is saved, so that the keys would never be replaced from
+/// under a running client; it is going to rotate them itself once restarted. Otherwise the stored
+/// keys of the client are rotated straight away.
+pub fn execute_rotate_keys<C, F>(
+    id: &str,
+    base_config: F,
+    interval: Option<Duration>,
+    grace_period: Option<Duration>,
+) where
+    C: NymConfig,
+    F: Fn(&mut C) -> &mut Config<C>,
+{
+    let mut config = match C::load_from_file(Some(id)) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            error!("Failed to load config for {}. Are you sure you have run `init` before? (Error was: {})", id, err);
+            return;
+        }
+    };
+
+    if interval.is_some() || grace_period.is_some() {
+        if let Some(interval) = interval {
+            base_config(&mut config).set_key_rotation_interval(interval);
+        }
+        if let Some(grace_period) = grace_period {
+            base_config(&mut config).set_key_rotation_grace_period(grace_period);
+        }
+        config
+            .save_to_file(None)
+            .expect("Failed to save the config file");
+        println!("Saved the key rotation schedule to the configuration file. It is going to take effect once the client is (re)started");
+        return;
+    }
+
+    let base_config = base_config(&mut config);
+    match rotate_stored_keys(base_config) {
+        Ok(address) => {
+            println!(
+                "The keys were rotated. The previous keys will remain valid for {:?}",
+                base_config.get_key_rotation_grace_period()
+            );
+            println!("The new address of this client is: {}", address);
+        }
+        Err(err) => {
+            eprintln!("Failed to rotate the keys - {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Periodically replaces the encryption and ack keys of the client and announces
+/// the resulting new address.
+pub struct KeyRotator {
+    key_manager: KeyManager,
+    pathfinder: ClientKeyPathfinder,
+    rotation_interval: Duration,
+    grace_period: Duration,
+    address_sender: SelfAddressSender,
+}
+
+impl KeyRotator {
+    pub fn new(
+        key_manager: KeyManager,
+        pathfinder: ClientKeyPathfinder,
+        rotation_interval: Duration,
+        grace_period: Duration,
+        address_sender: SelfAddressSender,
+    ) -> Self {
+        KeyRotator {
+            key_manager,
+            pathfinder,
+            rotation_interval,
+            grace_period,
+            address_sender,
+        }
+    }
+
+    // if the keys were never rotated, they are as old as the files they're stored in
+    fn last_rotation(&self) -> SystemTime {
+        match KeyRotationState::load(self.pathfinder.key_rotation_state()) {
+            Ok(state) => state.rotated_at(),
+            Err(_) => fs::metadata(self.pathfinder.private_encryption_key())
+                .and_then(|metadata| metadata.modified())
+                .unwrap_or_else(|_| SystemTime::now()),
+        }
+    }
+
+    fn time_until_next_rotation(&self) -> Duration {
+        let next_rotation = self.last_rotation() + self.rotation_interval;
+        next_rotation
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
+
+    fn publish_new_address(&self) {
+        let previous_address = *self.address_sender.borrow();
+        let new_address = Recipient::new(
+            *previous_address.identity(),
+            *self.key_manager.encryption_keypair().public_key(),
+            *previous_address.gateway(),
+        );
+
+        info!("The new address of this client is: {}", new_address);
+        if self.address_sender.send(new_address).is_err() {
+            // this can only happen if the entire client is being shut down
+            warn!("Nobody is listening for the address changes of this client");
+        }
+    }
+
+    /// Replaces the keys and announces the new address. Upon failure, the current keys
+    /// are left intact.
+    pub fn rotate(&self) -> io::Result<()> {
+        info!("Rotating the encryption and ack keys");
+        self.key_manager
+            .rotate_keys(&mut OsRng, self.grace_period, &self.pathfinder)?;
+        self.publish_new_address();
+        Ok(())
+    }
+
+    pub fn start(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(self.time_until_next_rotation()).await;
+                if let Err(err) = self.rotate() {
+                    error!(
+                        "Failed to rotate the keys - {}. Going to retry in {:?}",
+                        err, KEY_ROTATION_RETRY_DELAY
+                    );
+                    tokio::time::sleep(KEY_ROTATION_RETRY_DELAY).await;
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gateway_requests::generic_array::typenum::Unsigned;
+    use gateway_requests::registration::handshake::{SharedKeySize, SharedKeys};
+
+    #[test]
+    fn rotation_keeps_previous_key_until_its_grace_period_ends() {
+        let key = RotatableKey::new(1u32);
+        assert_eq!(*key.current(), 1);
+        assert!(key.previous().is_none());
+        assert_eq!(key.valid_keys().len(), 1);
+
+        key.rotate(2, SystemTime::now() + Duration::from_secs(3600));
+        assert_eq!(*key.current(), 2);
+        assert_eq!(key.previous().map(|previous| *previous), Some(1));
+        let valid_keys: Vec<_> = key.valid_keys().iter().map(|key| **key).collect();
+        assert_eq!(valid_keys, vec![2, 1]);
+
+        // the grace period has already expired
+        key.rotate(3, SystemTime::now() - Duration::from_secs(1));
+        assert_eq!(*key.current(), 3);
+        assert!(key.previous().is_none());
+        assert_eq!(key.valid_keys().len(), 1);
+    }
+
+    #[test]
+    fn only_the_latest_replaced_key_is_kept() {
+        let key = RotatableKey::new(1u32);
+        let valid_until = SystemTime::now() + Duration::from_secs(3600);
+
+        key.rotate(2, valid_until);
+        key.rotate(3, valid_until);
+        assert_eq!(*key.current(), 3);
+        assert_eq!(key.previous().map(|previous| *previous), Some(2));
+    }
+
+    #[test]
+    fn clones_share_the_same_keys() {
+        let key = RotatableKey::new(1u32);
+        let cloned = key.clone();
+
+        key.rotate(2, SystemTime::now() + Duration::from_secs(3600));
+        assert_eq!(*cloned.current(), 2);
+        assert_eq!(cloned.previous().map(|previous| *previous), Some(1));
+
+        cloned.set_previous(42, SystemTime::now() + Duration::from_secs(3600));
+        assert_eq!(key.previous().map(|previous| *previous), Some(42));
+    }
+
+    #[test]
+    fn rotated_keys_are_restored_after_reloading() {
+        let dir = tempfile::tempdir().unwrap();
+        let pathfinder = ClientKeyPathfinder::new_in_directory(dir.path().to_path_buf());
+
+        let mut key_manager = KeyManager::new(&mut OsRng);
+        let shared_key = SharedKeys::try_from_bytes(&vec![1; SharedKeySize::to_usize()]).unwrap();
+        key_manager.insert_gateway_shared_key(Arc::new(shared_key));
+        key_manager.store_keys(&pathfinder).unwrap();
+
+        let original_encryption_key = *key_manager.encryption_keypair().public_key();
+        key_manager
+            .rotate_keys(&mut OsRng, Duration::from_secs(3600), &pathfinder)
+            .unwrap();
+        let rotated_encryption_key = *key_manager.encryption_keypair().public_key();
+        assert_ne!(original_encryption_key, rotated_encryption_key);
+
+        let reloaded = KeyManager::load_keys(&pathfinder).unwrap();
+        assert_eq!(
+            *reloaded.encryption_keypair().public_key(),
+            rotated_encryption_key
+        );
+        let valid_keys: Vec<_> = reloaded
+            .encryption_keys()
+            .valid_keys()
+            .iter()
+            .map(|keypair| *keypair.public_key())
+            .collect();
+        assert_eq!(
+            valid_keys,
+            vec![rotated_encryption_key, original_encryption_key]
+        );
+    }
+
+    #[test]
+    fn running_client_lock_is_removed_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let pathfinder = ClientKeyPathfinder::new_in_directory(dir.path().to_path_buf());
+
+        let lock = RunningClientLock::acquire(&pathfinder).unwrap();
+        assert!(pathfinder.running_client_lock().exists());
+
+        drop(lock);
+        assert!(!pathfinder.running_client_lock().exists());
+    }
+
+    #[test]
+    fn running_client_lock_is_exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let pathfinder = ClientKeyPathfinder::new_in_directory(dir.path().to_path_buf());
+
+        let lock = RunningClientLock::acquire(&pathfinder).unwrap();
+        assert_eq!(
+            running_lock_owner(&pathfinder.running_client_lock()),
+            Some(std::process::id())
+        );
+        assert!(RunningClientLock::acquire(&pathfinder).is_err());
+
+        drop(lock);
+        assert!(RunningClientLock::acquire(&pathfinder).is_ok());
+    }
+
+    #[test]
+    fn running_client_lock_is_acquired_by_a_single_client_at_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let pathfinder = ClientKeyPathfinder::new_in_directory(dir.path().to_path_buf());
+
+        let barrier = Arc::new(std::sync::Barrier::new(8));
+        let attempts = (0..8)
+            .map(|_| {
+                let pathfinder = pathfinder.clone();
+                let barrier = Arc::clone(&barrier);
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    RunningClientLock::acquire(&pathfinder)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let results = attempts
+            .into_iter()
+            .map(|attempt| attempt.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(results
+            .iter()
+            .filter_map(|result| result.as_ref().err())
+            .all(|err| matches!(err, ClientCoreError::ClientRunning(_))));
+    }
+
+    #[test]
+    fn empty_lock_is_only_reclaimed_once_it_gets_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let pathfinder = ClientKeyPathfinder::new_in_directory(dir.path().to_path_buf());
+        let lock_path = pathfinder.running_client_lock();
+
+        fs::write(&lock_path, "").unwrap();
+        assert!(is_lock_being_acquired(&lock_path, SystemTime::now()));
+        assert!(matches!(
+            RunningClientLock::acquire(&pathfinder),
+            Err(ClientCoreError::ClientRunning(_))
+        ));
+
+        let later = SystemTime::now() + EMPTY_LOCK_RECLAIM_DELAY;
+        assert!(!is_lock_being_acquired(&lock_path, later));
+
+        // locks with the process id are never considered to be in the middle of being acquired
+        fs::write(&lock_path, std::process::id().to_string()).unwrap();
+        assert!(!is_lock_being_acquired(&lock_path, SystemTime::now()));
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn lock_of_a_dead_process_is_free() {
+        let dir = tempfile::tempdir().unwrap();
+        let pathfinder = ClientKeyPathfinder::new_in_directory(dir.path().to_path_buf());
+
+        // a process that has already exited
+        let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+            .arg("--list")
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        child.wait().unwrap();
+        fs::write(pathfinder.running_client_lock(), child.id().to_string()).unwrap();
+        assert!(running_lock_owner(&pathfinder.running_client_lock()).is_none());
+
+        // as well as the lock that doesn't contain a valid process id
+        fs::write(pathfinder.running_client_lock(), "foomp").unwrap();
+        assert!(running_lock_owner(&pathfinder.running_client_lock()).is_none());
+
+        let _lock = RunningClientLock::acquire(&pathfinder).unwrap();
+    }
+}
@@ -1,18 +1,61 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
-use futures::channel::mpsc;
+use crate::client::bandwidth_control::{BandwidthStatistics, RedemptionPolicy};
+use crate::client::gateway_failover::GatewayFailover;
+use futures::channel::{mpsc, oneshot};
 use futures::StreamExt;
+use gateway_client::error::GatewayClientError;
 use gateway_client::GatewayClient;
 use log::*;
 use nymsphinx::forwarding::packet::MixPacket;
+use std::time::Instant;
 use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
 
 pub type BatchMixMessageSender = mpsc::UnboundedSender<Vec<MixPacket>>;
 pub type BatchMixMessageReceiver = mpsc::UnboundedReceiver<Vec<MixPacket>>;
 
+pub type InboxRequester = mpsc::UnboundedSender<InboxRequest>;
+type InboxRequestReceiver = mpsc::UnboundedReceiver<InboxRequest>;
+type InboxPageResponder = oneshot::Sender<Result<(Vec<i64>, Option<i64>), GatewayClientError>>;
+
+/// Result of preparing a stored credential for redemption, which is done in the background
+/// as it might take a while.
+#[cfg(feature = "coconut")]
+type PreparedCredential = Result<gateway_client::bandwidth::Credential, GatewayClientError>;
+// credentials are only redeemed automatically if they were obtained beforehand,
+// i.e. with coconut, so there's never anything to prepare otherwise
+#[cfg(not(feature = "coconut"))]
+type PreparedCredential = std::convert::Infallible;
+
+/// Request concerning the messages the gateway has stored for the client while it was offline.
+/// Mostly useful if the client is connected to the gateway in the pull mode.
+pub enum InboxRequest {
+    /// Gets the number of stored messages.
+    Count(oneshot::Sender<Result<u64, GatewayClientError>>),
+
+    /// Makes the gateway push the next page of the stored messages. Responds with the ids of the
+    /// pushed messages alongside the id to start the next page after, if there are any more left.
+    Fetch {
+        start_after: Option<i64>,
+        page_size: u32,
+        remove: bool,
+        responder: InboxPageResponder,
+    },
+
+    /// Removes the specified stored messages. Responds with the number of the removed messages.
+    Delete {
+        ids: Vec<i64>,
+        responder: oneshot::Sender<Result<u64, GatewayClientError>>,
+    },
+}
+
 const MAX_FAILURE_COUNT: usize = 100;
 
+/// Minimum delay between subsequent attempts of redeeming a credential, if the previous one failed.
+const BANDWIDTH_REDEMPTION_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
 pub struct MixTrafficController {
     // TODO: most likely to be replaced by some higher level construct as
     // later on gateway_client will need to be accessible by other entities
@@ -22,23 +65,214 @@ pub struct MixTrafficController {
     // TODO: this is temporary work-around.
     // in long run `gateway_client` will be moved away from `MixTrafficController` anyway.
     consecutive_gateway_failure_count: usize,
+
+    /// If specified, the gateway is periodically health checked and replaced with one of the
+    /// backup gateways once it's deemed dead.
+    failover: Option<GatewayFailover>,
+
+    /// Balance and consumption of the bandwidth available at the gateway.
+    bandwidth_statistics: BandwidthStatistics,
+
+    /// Decides when the next stored credential should be redeemed for more bandwidth.
+    redemption_policy: RedemptionPolicy,
+
+    // we're holding a sender ourselves so that the channel would never get closed
+    #[cfg_attr(not(feature = "coconut"), allow(dead_code))]
+    prepared_credential_sender: mpsc::UnboundedSender<PreparedCredential>,
+    prepared_credential_receiver: mpsc::UnboundedReceiver<PreparedCredential>,
+
+    inbox_request_sender: InboxRequester,
+    inbox_request_receiver: InboxRequestReceiver,
 }
 
 impl MixTrafficController {
     pub fn new(
         mix_rx: BatchMixMessageReceiver,
         gateway_client: GatewayClient,
+        bandwidth_statistics: BandwidthStatistics,
+        bandwidth_redemption_threshold: i64,
     ) -> MixTrafficController {
+        bandwidth_statistics.set_remaining(gateway_client.remaining_bandwidth());
+        let (inbox_request_sender, inbox_request_receiver) = mpsc::unbounded();
+        let (prepared_credential_sender, prepared_credential_receiver) = mpsc::unbounded();
+
         MixTrafficController {
             gateway_client,
             mix_rx,
             consecutive_gateway_failure_count: 0,
+            failover: None,
+            bandwidth_statistics,
+            redemption_policy: RedemptionPolicy::new(
+                bandwidth_redemption_threshold,
+                BANDWIDTH_REDEMPTION_RETRY_INTERVAL,
+            ),
+            prepared_credential_sender,
+            prepared_credential_receiver,
+            inbox_request_sender,
+            inbox_request_receiver,
         }
     }
 
+    /// Returns the channel for making requests concerning the messages stored at the gateway.
+    pub fn inbox_requester(&self) -> InboxRequester {
+        self.inbox_request_sender.clone()
+    }
+
+    /// Allows the controller to switch to one of the backup gateways if the current one
+    /// stops responding.
+    #[must_use]
+    pub fn with_failover(mut self, failover: GatewayFailover) -> Self {
+        self.failover = Some(failover);
+        self
+    }
+
+    async fn on_gateway_failure(&mut self) {
+        self.consecutive_gateway_failure_count += 1;
+
+        if let Some(failover) = self.failover.as_mut() {
+            if self.consecutive_gateway_failure_count >= failover.failure_threshold()
+                && failover.has_backups()
+            {
+                warn!(
+                    "The gateway has failed {} times in a row - attempting to switch to a backup gateway",
+                    self.consecutive_gateway_failure_count
+                );
+                if let Some(new_gateway_client) = failover.fail_over().await {
+                    let mut previous_client =
+                        std::mem::replace(&mut self.gateway_client, new_gateway_client);
+                    // the old gateway is most likely dead, so don't wait for it for too long
+                    let close_timeout = failover.health_check_interval();
+                    if time::timeout(close_timeout, previous_client.close_connection())
+                        .await
+                        .is_err()
+                    {
+                        debug!("Timed out while closing connection to the previous gateway");
+                    }
+                    self.consecutive_gateway_failure_count = 0;
+                    self.redemption_policy.reset_failures();
+                    self.bandwidth_statistics
+                        .set_remaining(self.gateway_client.remaining_bandwidth());
+                    return;
+                }
+                error!("Failed to switch to any of the backup gateways");
+            }
+        }
+
+        if self.consecutive_gateway_failure_count == MAX_FAILURE_COUNT {
+            // todo: in the future this should initiate a 'graceful' shutdown or try
+            // to reconnect?
+            panic!("failed to send sphinx packet to the gateway {} times in a row - assuming the gateway is dead. Can't do anything about it yet :(", MAX_FAILURE_COUNT)
+        }
+    }
+
+    async fn check_gateway_health(&mut self) {
+        trace!("Checking health of the gateway connection");
+        match self.gateway_client.send_ping_message().await {
+            Ok(_) => self.consecutive_gateway_failure_count = 0,
+            Err(err) => {
+                warn!("The gateway has failed the health check - {}", err);
+                self.on_gateway_failure().await
+            }
+        }
+    }
+
+    // starts redeeming the next stored credential if sending the packets would bring
+    // the remaining bandwidth below the threshold, so that we would not run out of it mid-way
+    fn ensure_sufficient_bandwidth(&mut self, required_bandwidth: i64) {
+        let remaining = self.gateway_client.remaining_bandwidth();
+        if !self
+            .redemption_policy
+            .should_redeem(remaining, required_bandwidth, Instant::now())
+        {
+            return;
+        }
+
+        self.redemption_policy.on_started();
+        self.prepare_credential(remaining);
+    }
+
+    #[cfg(feature = "coconut")]
+    fn prepare_credential(&mut self, remaining: i64) {
+        let bandwidth_controller = match self.gateway_client.bandwidth_controller() {
+            Some(bandwidth_controller) => bandwidth_controller.clone(),
+            None => {
+                self.redemption_policy.on_finished(false, Instant::now());
+                return;
+            }
+        };
+
+        warn!(
+            "The remaining bandwidth is running low ({} bytes left) - going to redeem the next stored credential",
+            remaining
+        );
+        // preparing the credential involves querying the validators, so it's done in the background
+        // in order not to hold up sending the packets in the meantime
+        let prepared_credential_sender = self.prepared_credential_sender.clone();
+        tokio::spawn(async move {
+            let credential = bandwidth_controller.prepare_coconut_credential().await;
+            // the controller might have been shut down in the meantime
+            let _ = prepared_credential_sender.unbounded_send(credential);
+        });
+    }
+
+    #[cfg(not(feature = "coconut"))]
+    fn prepare_credential(&mut self, remaining: i64) {
+        // claiming bandwidth with tokens burns them, which must never happen without an explicit
+        // consent of the user, so it's only ever done when the client is being started
+        warn!(
+            "The remaining bandwidth is running low ({} bytes left). Restart the client in order to claim more",
+            remaining
+        );
+        // don't keep on repeating the warning for every packet
+        self.redemption_policy.on_finished(false, Instant::now());
+    }
+
+    #[cfg(feature = "coconut")]
+    async fn on_prepared_credential(&mut self, credential: PreparedCredential) {
+        let result = match credential {
+            Ok(credential) => {
+                self.gateway_client
+                    .redeem_coconut_credential(credential)
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(_) => {
+                self.redemption_policy.on_finished(true, Instant::now());
+                self.bandwidth_statistics.increment_credentials_redeemed();
+                self.bandwidth_statistics
+                    .set_remaining(self.gateway_client.remaining_bandwidth());
+                info!(
+                    "Redeemed a credential - there are now {} bytes of bandwidth available",
+                    self.gateway_client.remaining_bandwidth()
+                );
+            }
+            Err(err) => {
+                self.redemption_policy.on_finished(false, Instant::now());
+                error!(
+                    "Failed to redeem a credential for more bandwidth - {}. Sending packets is going to fail once the remaining {} bytes are used up",
+                    err,
+                    self.gateway_client.remaining_bandwidth()
+                );
+            }
+        }
+    }
+
+    #[cfg(not(feature = "coconut"))]
+    async fn on_prepared_credential(&mut self, credential: PreparedCredential) {
+        match credential {}
+    }
+
     async fn on_messages(&mut self, mut mix_packets: Vec<MixPacket>) {
         debug_assert!(!mix_packets.is_empty());
 
+        let required_bandwidth = self
+            .gateway_client
+            .estimate_required_bandwidth(&mix_packets);
+        self.ensure_sufficient_bandwidth(required_bandwidth);
+
         let result = if mix_packets.len() == 1 {
             let mix_packet = mix_packets.pop().unwrap();
             self.gateway_client.send_mix_packet(mix_packet).await
@@ -51,23 +285,69 @@ impl MixTrafficController {
         match result {
             Err(e) => {
                 error!("Failed to send sphinx packet(s) to the gateway! - {:?}", e);
-                self.consecutive_gateway_failure_count += 1;
-                if self.consecutive_gateway_failure_count == MAX_FAILURE_COUNT {
-                    // todo: in the future this should initiate a 'graceful' shutdown or try
-                    // to reconnect?
-                    panic!("failed to send sphinx packet to the gateway {} times in a row - assuming the gateway is dead. Can't do anything about it yet :(", MAX_FAILURE_COUNT)
-                }
+                self.on_gateway_failure().await
             }
             Ok(_) => {
                 trace!("We *might* have managed to forward sphinx packet(s) to the gateway!");
                 self.consecutive_gateway_failure_count = 0;
+                self.bandwidth_statistics.record_consumption(
+                    required_bandwidth,
+                    self.gateway_client.remaining_bandwidth(),
+                );
+            }
+        }
+    }
+
+    async fn on_inbox_request(&mut self, request: InboxRequest) {
+        // if the requester has gone away in the meantime, there's nobody to tell about the result
+        match request {
+            InboxRequest::Count(responder) => {
+                let result = self.gateway_client.count_stored_messages().await;
+                let _ = responder.send(result);
+            }
+            InboxRequest::Fetch {
+                start_after,
+                page_size,
+                remove,
+                responder,
+            } => {
+                let result = self
+                    .gateway_client
+                    .fetch_stored_messages(start_after, page_size, remove)
+                    .await;
+                let _ = responder.send(result);
+            }
+            InboxRequest::Delete { ids, responder } => {
+                let result = self.gateway_client.delete_stored_messages(ids).await;
+                let _ = responder.send(result);
             }
         }
     }
 
     pub async fn run(&mut self) {
-        while let Some(mix_packets) = self.mix_rx.next().await {
-            self.on_messages(mix_packets).await;
+        let mut health_check = self
+            .failover
+            .as_ref()
+            .map(|failover| time::interval(failover.health_check_interval()));
+        if let Some(health_check) = health_check.as_mut() {
+            // the first tick completes immediately and we've only just connected to the gateway
+            health_check.tick().await;
+        }
+
+        loop {
+            tokio::select! {
+                mix_packets = self.mix_rx.next() => match mix_packets {
+                    Some(mix_packets) => self.on_messages(mix_packets).await,
+                    None => break,
+                },
+                // we're holding a sender ourselves so the channel can't ever get closed
+                request = self.inbox_request_receiver.next() => self.on_inbox_request(request.unwrap()).await,
+                // same here, we're holding a sender ourselves
+                credential = self.prepared_credential_receiver.next() => self.on_prepared_credential(credential.unwrap()).await,
+                _ = async { health_check.as_mut().unwrap().tick().await }, if health_check.is_some() => {
+                    self.check_gateway_health().await
+                }
+            }
         }
     }
 
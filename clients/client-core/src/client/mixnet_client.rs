@@ -0,0 +1,515 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Library interface for running a mixnet client in-process.
+//!
+//! Rather than talking to a separate `nym-client` process over a websocket, applications can link
+//! `client-core` directly and use [`MixnetClientBuilder`] to obtain a fully connected [`MixnetClient`]:
+//!
+//! ```no_run
+//! # use client_core::client::mixnet_client::MixnetClientBuilder;
+//! # use client_core::config::Config;
+//! # use futures::StreamExt;
+//! # async fn example<T: config::NymConfig>(config: Config<T>) {
+//! let mut client = MixnetClientBuilder::new(config).build().await.unwrap();
+//! let our_address = client.address();
+//!
+//! client.send(our_address, b"hello".to_vec()).await.unwrap();
+//! let received = client.next().await.unwrap();
+//! # }
+//! ```
+
+use crate::client::bandwidth_control::{BandwidthStatistics, BandwidthStatisticsSnapshot};
+use crate::client::base_client::BaseClient;
+use crate::client::delivery_receipts::{DeliveryReceiptReceiver, DeliveryReceiptSender, MessageId};
+use crate::client::gateway_failover::SelfAddressReceiver;
+use crate::client::inbound_messages::{InputMessage, InputMessageSender};
+use crate::client::key_manager::KeyManager;
+use crate::client::key_rotation::RunningClientLock;
+use crate::client::received_buffer::{
+    ReceivedBufferMessage, ReceivedBufferRequestSender, ReconstructedMessagesReceiver,
+    ReplyKeyDigestsReceiver, ReplyKeyDigestsSender, ReplyRouteSender,
+};
+use crate::client::traffic_control::{
+    PacketStatistics, PacketStatisticsSnapshot, TrafficRatesControl,
+};
+use crate::config::persistence::key_pathfinder::ClientKeyPathfinder;
+use crate::config::{Config, GatewayEndpoint};
+use crate::error::ClientCoreError;
+use crate::init;
+use config::NymConfig;
+use futures::channel::mpsc;
+use futures::task::{Context, Poll};
+use futures::Stream;
+use log::*;
+use nymsphinx::addressing::clients::Recipient;
+use nymsphinx::anonymous_replies::ReplySurb;
+use nymsphinx::headers::MAX_REPLY_SURBS;
+use nymsphinx::receiver::ReconstructedMessage;
+use rand::rngs::OsRng;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::Arc;
+use topology::route_selection::RouteSelector;
+
+/// Builder responsible for initialising (if required) and connecting a [`MixnetClient`].
+pub struct MixnetClientBuilder<T> {
+    config: Config<T>,
+    chosen_gateway_id: Option<String>,
+    force_register_gateway: bool,
+    backup_gateways: usize,
+    route_selector: Option<Arc<dyn RouteSelector>>,
+    key_manager: Option<KeyManager>,
+}
+
+impl<T> MixnetClientBuilder<T>
+where
+    T: NymConfig,
+{
+    /// Creates new instance of a [`MixnetClientBuilder`] using the provided client configuration.
+    /// The key files and storage paths specified in the configuration are going to be used for
+    /// loading (or, if they do not exist, storing) the client keys.
+    pub fn new(config: Config<T>) -> Self {
+        MixnetClientBuilder {
+            config,
+            chosen_gateway_id: None,
+            force_register_gateway: false,
+            backup_gateways: 0,
+            route_selector: None,
+            key_manager: None,
+        }
+    }
+
+    /// Specifies the gateway the client should register with if it has not been initialised before.
+    /// If omitted, a random gateway is going to be chosen from the available topology.
+    #[must_use]
+    pub fn with_gateway<S: Into<String>>(mut self, gateway_id: S) -> Self {
+        self.chosen_gateway_id = Some(gateway_id.into());
+        self
+    }
+
+    /// Forces generation of new keys and registration with a gateway even if the client
+    /// has already been initialised before.
+    #[must_use]
+    pub fn with_forced_gateway_registration(mut self, force_register_gateway: bool) -> Self {
+        self.force_register_gateway = force_register_gateway;
+        self
+    }
+
+    /// Specifies the number of additional gateways the client should register with during
+    /// the initialisation. If the primary gateway stops responding, the client is going to
+    /// switch to one of them, changing its address in the process.
+    #[must_use]
+    pub fn with_backup_gateways(mut self, count: usize) -> Self {
+        self.backup_gateways = count;
+        self
+    }
+
+    /// Specifies a custom way of choosing the mix nodes for the routes of all sent packets.
+    /// If omitted, the route selection policy and node exclusions from the config are used.
+    #[must_use]
+    pub fn with_route_selector(mut self, route_selector: Arc<dyn RouteSelector>) -> Self {
+        self.route_selector = Some(route_selector);
+        self
+    }
+
+    fn is_initialised(&self) -> bool {
+        let pathfinder = ClientKeyPathfinder::new_from_config(&self.config);
+
+        !self.config.get_gateway_id().is_empty()
+            && pathfinder.private_identity_key().exists()
+            && pathfinder.private_encryption_key().exists()
+            && pathfinder.gateway_shared_key().exists()
+            && pathfinder.ack_key().exists()
+    }
+
+    /// Equivalent of running `init` on any of the client binaries. If the client has not been
+    /// initialised before (or the registration was forced), it generates new set of keys,
+    /// registers with a gateway and stores the keys on the disk. The chosen gateway details are
+    /// written to the underlying [`Config`], so you might want to persist it afterwards.
+    pub async fn initialise(&mut self) -> Result<(), ClientCoreError> {
+        if self.key_manager.is_some() || (self.is_initialised() && !self.force_register_gateway) {
+            return Ok(());
+        }
+
+        info!("Initialising new mixnet client keys");
+        let gateway_details = init::query_gateway_details(
+            self.config.get_validator_api_endpoints(),
+            self.chosen_gateway_id.as_deref(),
+        )
+        .await;
+        debug!("Registering with gateway {}", gateway_details);
+
+        let mut key_manager = KeyManager::new(&mut OsRng);
+        let shared_keys =
+            init::register_with_gateway(&gateway_details, key_manager.identity_keypair()).await?;
+        key_manager.insert_gateway_shared_key(shared_keys);
+
+        let gateway_endpoint: GatewayEndpoint = gateway_details.into();
+        let backup_gateway_endpoints = if self.backup_gateways > 0 {
+            let backup_gateways = init::query_backup_gateway_details(
+                self.config.get_validator_api_endpoints(),
+                &[gateway_endpoint.gateway_id.clone()],
+                self.backup_gateways,
+            )
+            .await;
+            init::register_with_backup_gateways(&mut key_manager, backup_gateways).await
+        } else {
+            Vec::new()
+        };
+
+        let pathfinder = ClientKeyPathfinder::new_from_config(&self.config);
+        key_manager.store_keys(&pathfinder)?;
+
+        self.config.with_gateway_endpoint(gateway_endpoint);
+        self.config
+            .with_backup_gateway_endpoints(backup_gateway_endpoints);
+        self.key_manager = Some(key_manager);
+        Ok(())
+    }
+
+    /// Initialises the client if it has not been initialised before, establishes connection
+    /// with its gateway and starts all of the background tasks required for sending and receiving
+    /// messages through the mix network.
+    pub async fn build(mut self) -> Result<MixnetClient, ClientCoreError> {
+        self.initialise().await?;
+
+        let key_manager = match self.key_manager.take() {
+            Some(key_manager) => key_manager,
+            None => {
+                let pathfinder = ClientKeyPathfinder::new_from_config(&self.config);
+                KeyManager::load_keys(&pathfinder)?
+            }
+        };
+
+        let mut base_client = BaseClient::new(&self.config, key_manager)?;
+        if let Some(route_selector) = self.route_selector {
+            base_client = base_client.with_route_selector(route_selector);
+        }
+
+        info!("Starting mixnet client");
+        let started = base_client.start(&self.config).await?;
+        let running_lock = Arc::new(started.running_lock);
+
+        // announce ourselves to the buffer so that it would start sending reconstructed messages to us
+        let (reconstructed_sender, reconstructed_receiver) = mpsc::unbounded();
+        started
+            .received_buffer_request_sender
+            .unbounded_send(ReceivedBufferMessage::ReceiverAnnounce(
+                reconstructed_sender,
+            ))
+            .map_err(|_| ClientCoreError::ClientShutdown)?;
+
+        info!("Client startup finished!");
+        info!("The address of this client is: {}", base_client.address());
+
+        Ok(MixnetClient {
+            sender: MixnetClientSender {
+                address: base_client.address_updates(),
+                input_sender: started.input_sender,
+                receipt_sender: started.receipt_sender,
+                received_buffer_request_sender: started.received_buffer_request_sender,
+                _running_lock: running_lock.clone(),
+            },
+            receipt_receiver: Some(started.receipt_receiver),
+            traffic_rates: base_client.traffic_rates().clone(),
+            packet_statistics: base_client.packet_statistics().clone(),
+            bandwidth_statistics: base_client.bandwidth_statistics().clone(),
+            receiver: MixnetClientReceiver {
+                reconstructed_receiver,
+                buffered: VecDeque::new(),
+                _running_lock: running_lock,
+            },
+        })
+    }
+
+    /// Returns the configuration of this client. It is updated with the gateway details during
+    /// the initialisation.
+    pub fn config(&self) -> &Config<T> {
+        &self.config
+    }
+}
+
+// the number of reply SURBs shares its byte with the flag indicating whether the message has headers
+fn check_num_reply_surbs(num_reply_surbs: u8) -> Result<(), ClientCoreError> {
+    if num_reply_surbs > MAX_REPLY_SURBS {
+        return Err(ClientCoreError::TooManyReplySurbs {
+            requested: num_reply_surbs,
+            max: MAX_REPLY_SURBS,
+        });
+    }
+    Ok(())
+}
+
+/// Handle to a running mixnet client. It allows sending messages to other clients and implements
+/// [`Stream`] over all [`ReconstructedMessage`]s received from the mix network.
+pub struct MixnetClient {
+    sender: MixnetClientSender,
+    receiver: MixnetClientReceiver,
+    receipt_receiver: Option<DeliveryReceiptReceiver>,
+    traffic_rates: TrafficRatesControl,
+    packet_statistics: PacketStatistics,
+    bandwidth_statistics: BandwidthStatistics,
+}
+
+impl MixnetClient {
+    /// Returns the current address of this client. It changes whenever the client switches to
+    /// a backup gateway or rotates its encryption key, see [`Self::address_updates`].
+    pub fn address(&self) -> Recipient {
+        self.sender.address()
+    }
+
+    /// Returns the channel on which the new address of this client is announced whenever
+    /// it changes, that is whenever the client switches to one of its backup gateways or
+    /// rotates its encryption key (see the `key_rotation_interval` config option).
+    pub fn address_updates(&self) -> SelfAddressReceiver {
+        self.sender.address_updates()
+    }
+
+    /// Sends the provided message to the specified recipient.
+    pub async fn send(
+        &self,
+        recipient: Recipient,
+        message: Vec<u8>,
+    ) -> Result<MessageId, ClientCoreError> {
+        self.sender.send(recipient, message).await
+    }
+
+    /// Sends the provided message to the specified recipient attaching a reply SURB so that
+    /// they could anonymously respond to us.
+    pub async fn send_with_reply_surb(
+        &self,
+        recipient: Recipient,
+        message: Vec<u8>,
+    ) -> Result<MessageId, ClientCoreError> {
+        self.sender.send_with_reply_surb(recipient, message).await
+    }
+
+    /// Sends the provided message to the specified recipient attaching `num_reply_surbs`
+    /// reply SURBs so that they could send back a (possibly multi-packet) anonymous response.
+    /// A single message can carry at most [`MAX_REPLY_SURBS`] reply SURBs.
+    pub async fn send_with_reply_surbs(
+        &self,
+        recipient: Recipient,
+        message: Vec<u8>,
+        num_reply_surbs: u8,
+    ) -> Result<MessageId, ClientCoreError> {
+        self.sender
+            .send_with_reply_surbs(recipient, message, num_reply_surbs)
+            .await
+    }
+
+    /// Uses the provided reply SURB to anonymously respond to whoever has sent it.
+    pub async fn send_reply(
+        &self,
+        reply_surb: ReplySurb,
+        message: Vec<u8>,
+    ) -> Result<(), ClientCoreError> {
+        self.sender.send_reply(reply_surb, message).await
+    }
+
+    /// Takes the channel onto which a [`DeliveryReceipt`] is pushed for every sent message once
+    /// all of its fragments got acknowledged or the client gave up on retransmitting them.
+    /// Receipts are queued until the channel is taken, which can only happen once.
+    ///
+    /// [`DeliveryReceipt`]: crate::client::delivery_receipts::DeliveryReceipt
+    pub fn take_delivery_receipts(&mut self) -> Option<DeliveryReceiptReceiver> {
+        self.receipt_receiver.take()
+    }
+
+    /// Returns the handle allowing to adjust the rates at which the real and cover packets
+    /// are sent, for example to switch into a low power mode.
+    pub fn traffic_rates(&self) -> &TrafficRatesControl {
+        &self.traffic_rates
+    }
+
+    /// Returns the numbers of real and cover packets sent so far.
+    pub fn packet_statistics(&self) -> PacketStatisticsSnapshot {
+        self.packet_statistics.snapshot()
+    }
+
+    /// Returns the bandwidth remaining at the gateway and the rate at which it's being consumed.
+    pub fn bandwidth_statistics(&self) -> BandwidthStatisticsSnapshot {
+        self.bandwidth_statistics.snapshot()
+    }
+
+    /// Splits the client into independent sending and receiving halves.
+    pub fn split(self) -> (MixnetClientSender, MixnetClientReceiver) {
+        (self.sender, self.receiver)
+    }
+}
+
+impl Stream for MixnetClient {
+    type Item = ReconstructedMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+/// Sending half of a [`MixnetClient`]. It can be freely cloned and shared between tasks.
+#[derive(Clone)]
+pub struct MixnetClientSender {
+    address: SelfAddressReceiver,
+    input_sender: InputMessageSender,
+    receipt_sender: DeliveryReceiptSender,
+    received_buffer_request_sender: ReceivedBufferRequestSender,
+
+    /// Prevents the keys from being rotated from under the client, for example by the
+    /// `rotate-keys` command, until both halves of the client are dropped.
+    _running_lock: Arc<RunningClientLock>,
+}
+
+impl MixnetClientSender {
+    /// Returns the current address of this client. It changes whenever the client switches to
+    /// a backup gateway or rotates its encryption key, see [`Self::address_updates`].
+    pub fn address(&self) -> Recipient {
+        *self.address.borrow()
+    }
+
+    /// Returns the channel on which the new address of this client is announced whenever
+    /// it changes, that is whenever the client switches to one of its backup gateways or
+    /// rotates its encryption key (see the `key_rotation_interval` config option).
+    pub fn address_updates(&self) -> SelfAddressReceiver {
+        self.address.clone()
+    }
+
+    fn send_input_message(&self, input_message: InputMessage) -> Result<(), ClientCoreError> {
+        // the ack control is responsible for chunking, etc.
+        self.input_sender
+            .unbounded_send(input_message)
+            .map_err(|_| ClientCoreError::ClientShutdown)
+    }
+
+    fn send_fresh_message(
+        &self,
+        recipient: Recipient,
+        message: Vec<u8>,
+        num_reply_surbs: u8,
+    ) -> Result<MessageId, ClientCoreError> {
+        check_num_reply_surbs(num_reply_surbs)?;
+        let (input_message, message_id) =
+            InputMessage::new_fresh_with_id(recipient, message, num_reply_surbs);
+        self.send_input_message(input_message.with_receipt_sender(self.receipt_sender.clone()))?;
+        Ok(message_id)
+    }
+
+    /// Sends the provided message to the specified recipient.
+    pub async fn send(
+        &self,
+        recipient: Recipient,
+        message: Vec<u8>,
+    ) -> Result<MessageId, ClientCoreError> {
+        self.send_fresh_message(recipient, message, 0)
+    }
+
+    /// Sends the provided message to the specified recipient attaching a reply SURB so that
+    /// they could anonymously respond to us.
+    pub async fn send_with_reply_surb(
+        &self,
+        recipient: Recipient,
+        message: Vec<u8>,
+    ) -> Result<MessageId, ClientCoreError> {
+        self.send_fresh_message(recipient, message, 1)
+    }
+
+    /// Sends the provided message to the specified recipient attaching `num_reply_surbs`
+    /// reply SURBs so that they could send back a (possibly multi-packet) anonymous response.
+    /// A single message can carry at most [`MAX_REPLY_SURBS`] reply SURBs.
+    pub async fn send_with_reply_surbs(
+        &self,
+        recipient: Recipient,
+        message: Vec<u8>,
+        num_reply_surbs: u8,
+    ) -> Result<MessageId, ClientCoreError> {
+        self.send_fresh_message(recipient, message, num_reply_surbs)
+    }
+
+    /// Uses the provided reply SURB to anonymously respond to whoever has sent it.
+    pub async fn send_reply(
+        &self,
+        reply_surb: ReplySurb,
+        message: Vec<u8>,
+    ) -> Result<(), ClientCoreError> {
+        self.send_input_message(InputMessage::new_reply(reply_surb, message))
+    }
+
+    /// Sends the provided message attaching `num_reply_surbs` reply SURBs, the key digests
+    /// of which are going to be sent on the provided channel once the message is prepared.
+    pub(crate) fn send_with_reply_key_digests(
+        &self,
+        recipient: Recipient,
+        message: Vec<u8>,
+        num_reply_surbs: u8,
+        reply_key_digests_sender: ReplyKeyDigestsSender,
+    ) -> Result<(), ClientCoreError> {
+        check_num_reply_surbs(num_reply_surbs)?;
+        let input_message = InputMessage::new_fresh(recipient, message, num_reply_surbs)
+            .with_reply_key_digests_sender(reply_key_digests_sender);
+        self.send_input_message(input_message)
+    }
+
+    /// Makes the replies sent using the reply SURBs with the key digests received on the provided
+    /// channel get pushed onto the route channel rather than being received normally.
+    /// The route has to be registered before the corresponding message is sent.
+    pub(crate) fn route_replies(
+        &self,
+        key_digests_receiver: ReplyKeyDigestsReceiver,
+        route_sender: ReplyRouteSender,
+    ) -> Result<(), ClientCoreError> {
+        self.received_buffer_request_sender
+            .unbounded_send(ReceivedBufferMessage::ReplyRoute(
+                key_digests_receiver,
+                route_sender,
+            ))
+            .map_err(|_| ClientCoreError::ClientShutdown)
+    }
+}
+
+/// Receiving half of a [`MixnetClient`] yielding all [`ReconstructedMessage`]s
+/// received from the mix network.
+pub struct MixnetClientReceiver {
+    reconstructed_receiver: ReconstructedMessagesReceiver,
+
+    /// Messages are pushed to us in batches, so we might have to hold onto some of them
+    /// until they're polled.
+    buffered: VecDeque<ReconstructedMessage>,
+
+    _running_lock: Arc<RunningClientLock>,
+}
+
+impl Stream for MixnetClientReceiver {
+    type Item = ReconstructedMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(message) = self.buffered.pop_front() {
+                return Poll::Ready(Some(message));
+            }
+
+            match Pin::new(&mut self.reconstructed_receiver).poll_next(cx) {
+                Poll::Ready(Some(messages)) => self.buffered.extend(messages),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn number_of_reply_surbs_is_limited_by_the_headers_flag() {
+        assert!(check_num_reply_surbs(0).is_ok());
+        assert!(check_num_reply_surbs(MAX_REPLY_SURBS).is_ok());
+        assert!(matches!(
+            check_num_reply_surbs(MAX_REPLY_SURBS + 1),
+            Err(ClientCoreError::TooManyReplySurbs {
+                requested: 128,
+                max: 127
+            })
+        ));
+    }
+}
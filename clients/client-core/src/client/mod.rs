@@ -1,8 +1,29 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod bandwidth_control;
+pub mod base_client;
 pub mod cover_traffic_stream;
+pub mod delivery_receipts;
+pub mod gateway_failover;
 pub mod inbound_messages;
 pub mod key_manager;
+pub mod key_rotation;
 pub mod mix_traffic;
+pub mod mixnet_client;
+pub mod outbound_journal;
 pub mod real_messages_control;
 pub mod received_buffer;
 pub mod reply_key_storage;
+pub mod route_selection;
+pub mod rpc;
 pub mod topology_control;
+pub mod traffic_control;
+
+/// Current unix timestamp in seconds, as persisted by the client's storages. If the system clock
+/// is set to before the unix epoch, 0 is returned instead.
+pub(crate) fn current_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs() as i64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,401 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::client::current_timestamp;
+use crate::client::delivery_receipts::{DeliveryReceiptSender, MessageId};
+use log::*;
+use nymsphinx::addressing::clients::Recipient;
+use nymsphinx::chunking::fragment::{Fragment, FragmentIdentifier};
+use sqlx::ConnectOptions;
+use std::convert::TryInto;
+use std::path::Path;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum OutboundJournalError {
+    #[error("Database experienced an internal error - {0}")]
+    InternalDatabaseError(#[from] sqlx::Error),
+
+    #[error("Failed to perform database migration - {0}")]
+    MigrationError(#[from] sqlx::migrate::MigrateError),
+
+    #[error("Failed to restrict access to the journal file - {0}")]
+    PermissionsError(#[from] std::io::Error),
+}
+
+/// Fragment recorded in the journal that has not been acknowledged yet.
+#[derive(Debug)]
+pub struct JournaledFragment {
+    pub recipient: Recipient,
+    pub fragment: Fragment,
+
+    /// Identifier of the message the fragment is part of.
+    pub message_id: MessageId,
+}
+
+/// On-disk record of all message fragments that were sent out, but for which we have not yet
+/// received an acknowledgement.
+///
+/// Upon client restart all of the recorded fragments are sent out again so that messages that
+/// were still in transit when the client went down would not get lost. Fragments are only kept
+/// for the configured time-to-live, after which they are not resent anymore.
+///
+/// Replies are never recorded, as each reply SURB can only be used once.
+///
+/// Note that the fragments are stored unencrypted, so the journal file is only made
+/// accessible by its owner.
+// note that clone here is fine as upon cloning the same underlying pool will be used
+#[derive(Debug, Clone)]
+pub struct OutboundJournal {
+    connection_pool: sqlx::SqlitePool,
+    receipt_sender: Option<DeliveryReceiptSender>,
+    entry_ttl: Duration,
+}
+
+impl OutboundJournal {
+    /// Loads the outbound journal located at the specified path, creating it if it doesn't exist,
+    /// and purges all of the fragments that have outlived the specified time-to-live.
+    pub async fn load<P: AsRef<Path>>(
+        path: P,
+        entry_ttl: Duration,
+    ) -> Result<Self, OutboundJournalError> {
+        debug!(
+            "Attempting to connect to database {:?}",
+            path.as_ref().as_os_str()
+        );
+
+        if let Err(err) = create_private_file(path.as_ref()) {
+            error!("Failed to restrict access to the outbound journal: {}", err);
+            return Err(err.into());
+        }
+
+        let mut opts = sqlx::sqlite::SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+
+        opts.disable_statement_logging();
+
+        let connection_pool = match sqlx::SqlitePool::connect_with(opts).await {
+            Ok(db) => db,
+            Err(err) => {
+                error!("Failed to connect to SQLx database: {}", err);
+                return Err(err.into());
+            }
+        };
+
+        if let Err(err) = sqlx::migrate!("./migrations/outbound_journal")
+            .run(&connection_pool)
+            .await
+        {
+            error!("Failed to perform migration on the SQLx database: {}", err);
+            return Err(err.into());
+        }
+
+        let journal = OutboundJournal {
+            connection_pool,
+            receipt_sender: None,
+            entry_ttl,
+        };
+
+        let removed = journal.remove_expired_fragments().await?;
+        if removed > 0 {
+            info!(
+                "Removed {} expired fragments from the outbound journal - they are not going to be resent",
+                removed
+            );
+        }
+
+        Ok(journal)
+    }
+
+    /// Specifies where the delivery receipts of the messages replayed after restart
+    /// should be sent to, as their original requesters are long gone.
+    #[must_use]
+    pub fn with_receipt_sender(mut self, receipt_sender: DeliveryReceiptSender) -> Self {
+        self.receipt_sender = Some(receipt_sender);
+        self
+    }
+
+    pub(crate) fn receipt_sender(&self) -> Option<DeliveryReceiptSender> {
+        self.receipt_sender.clone()
+    }
+
+    /// Records all fragments of a message that is about to be sent to the specified recipient.
+    pub async fn insert_fragments(
+        &self,
+        recipient: &Recipient,
+        fragments: &[Fragment],
+        message_id: MessageId,
+    ) -> Result<(), OutboundJournalError> {
+        let recipient_bytes = recipient.to_bytes().to_vec();
+        let created_at = current_timestamp();
+        // sqlite has no notion of unsigned integers
+        let message_id = message_id as i64;
+
+        let mut tx = self.connection_pool.begin().await?;
+        for fragment in fragments {
+            let fragment_id = fragment.fragment_identifier().to_bytes().to_vec();
+            let fragment_bytes = fragment.clone().into_bytes();
+            sqlx::query!(
+                "INSERT INTO outbound_fragment(fragment_id, recipient, fragment, created_at, message_id) VALUES (?, ?, ?, ?, ?)",
+                fragment_id,
+                recipient_bytes,
+                fragment_bytes,
+                created_at,
+                message_id
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Removes the fragment with the specified identifier, for example once it got acknowledged.
+    pub async fn remove_fragment(
+        &self,
+        fragment_id: FragmentIdentifier,
+    ) -> Result<(), OutboundJournalError> {
+        // replies are never recorded
+        if fragment_id.is_reply() {
+            return Ok(());
+        }
+
+        let fragment_id = fragment_id.to_bytes().to_vec();
+        sqlx::query!(
+            "DELETE FROM outbound_fragment WHERE fragment_id = ?",
+            fragment_id
+        )
+        .execute(&self.connection_pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes all fragments that have outlived the configured time-to-live and returns
+    /// the number of fragments purged from the disk.
+    pub async fn remove_expired_fragments(&self) -> Result<u64, OutboundJournalError> {
+        let cutoff = current_timestamp().saturating_sub(self.entry_ttl.as_secs() as i64);
+        let removed = sqlx::query!("DELETE FROM outbound_fragment WHERE created_at < ?", cutoff)
+            .execute(&self.connection_pool)
+            .await?
+            .rows_affected();
+
+        Ok(removed)
+    }
+
+    /// Retrieves all recorded fragments alongside their intended recipients,
+    /// in the order they were originally sent out.
+    pub async fn unacked_fragments(&self) -> Result<Vec<JournaledFragment>, OutboundJournalError> {
+        let records = sqlx::query!(
+            "SELECT fragment_id, recipient, fragment, message_id FROM outbound_fragment ORDER BY created_at"
+        )
+        .fetch_all(&self.connection_pool)
+        .await?;
+
+        let mut fragments = Vec::with_capacity(records.len());
+        for record in records {
+            let recipient = record
+                .recipient
+                .as_slice()
+                .try_into()
+                .ok()
+                .and_then(|recipient_bytes| Recipient::try_from_bytes(recipient_bytes).ok());
+            let fragment = Fragment::try_from_bytes(&record.fragment).ok();
+
+            match (recipient, fragment) {
+                (Some(recipient), Some(fragment)) => fragments.push(JournaledFragment {
+                    recipient,
+                    fragment,
+                    message_id: record.message_id as MessageId,
+                }),
+                _ => {
+                    // there's no point in keeping it around if we can't ever resend it
+                    warn!(
+                        "Found a malformed fragment in the outbound journal - it will be removed"
+                    );
+                    sqlx::query!(
+                        "DELETE FROM outbound_fragment WHERE fragment_id = ?",
+                        record.fragment_id
+                    )
+                    .execute(&self.connection_pool)
+                    .await?;
+                }
+            }
+        }
+
+        Ok(fragments)
+    }
+}
+
+// the journal contains the plaintext of the messages, so nobody else should be able to read it
+fn create_private_file(path: &Path) -> std::io::Result<()> {
+    // note: this is only supported on unix (on different systems, like Windows, it will just
+    // be ignored)
+    #[cfg(target_family = "unix")]
+    {
+        use std::fs;
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)?;
+        // the file might have been created before with more relaxed permissions
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    #[cfg(not(target_family = "unix"))]
+    let _ = path;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::asymmetric::{encryption, identity};
+    use nymsphinx::chunking::split_into_sets;
+    use rand::rngs::OsRng;
+    use tempfile::tempdir;
+
+    const TEST_TTL: Duration = Duration::from_secs(60 * 60);
+
+    fn test_recipient() -> Recipient {
+        let mut rng = OsRng;
+        Recipient::new(
+            *identity::KeyPair::new(&mut rng).public_key(),
+            *encryption::KeyPair::new(&mut rng).public_key(),
+            *identity::KeyPair::new(&mut rng).public_key(),
+        )
+    }
+
+    fn test_fragments(message_len: usize) -> Vec<Fragment> {
+        split_into_sets(&mut OsRng, &vec![42; message_len], 1000)
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn fragments_survive_restart_with_their_message_ids() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("journal.db");
+        let recipient = test_recipient();
+        let first_message = test_fragments(2500);
+        let second_message = test_fragments(500);
+
+        {
+            let journal = OutboundJournal::load(&path, TEST_TTL).await.unwrap();
+            journal
+                .insert_fragments(&recipient, &first_message, 1)
+                .await
+                .unwrap();
+            journal
+                .insert_fragments(&recipient, &second_message, u64::MAX)
+                .await
+                .unwrap();
+        }
+
+        let journal = OutboundJournal::load(&path, TEST_TTL).await.unwrap();
+        let unacked = journal.unacked_fragments().await.unwrap();
+        assert_eq!(unacked.len(), first_message.len() + second_message.len());
+        for journaled in &unacked {
+            assert_eq!(
+                journaled.recipient.to_bytes().to_vec(),
+                recipient.to_bytes().to_vec()
+            );
+            if first_message.contains(&journaled.fragment) {
+                assert_eq!(journaled.message_id, 1);
+            } else {
+                assert!(second_message.contains(&journaled.fragment));
+                assert_eq!(journaled.message_id, u64::MAX);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn acknowledged_fragments_are_not_replayed() {
+        let dir = tempdir().unwrap();
+        let journal = OutboundJournal::load(dir.path().join("journal.db"), TEST_TTL)
+            .await
+            .unwrap();
+        let fragments = test_fragments(2500);
+        journal
+            .insert_fragments(&test_recipient(), &fragments, 1)
+            .await
+            .unwrap();
+
+        journal
+            .remove_fragment(fragments[0].fragment_identifier())
+            .await
+            .unwrap();
+
+        let unacked = journal.unacked_fragments().await.unwrap();
+        assert_eq!(unacked.len(), fragments.len() - 1);
+        assert!(unacked
+            .iter()
+            .all(|journaled| journaled.fragment != fragments[0]));
+    }
+
+    #[tokio::test]
+    async fn expired_fragments_are_not_replayed() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("journal.db");
+        let recipient = test_recipient();
+        let fresh_message = test_fragments(500);
+        let expired_message = test_fragments(500);
+
+        {
+            let journal = OutboundJournal::load(&path, TEST_TTL).await.unwrap();
+            journal
+                .insert_fragments(&recipient, &fresh_message, 1)
+                .await
+                .unwrap();
+            journal
+                .insert_fragments(&recipient, &expired_message, 2)
+                .await
+                .unwrap();
+            sqlx::query("UPDATE outbound_fragment SET created_at = ? WHERE message_id = 2")
+                .bind(current_timestamp() - TEST_TTL.as_secs() as i64 - 1)
+                .execute(&journal.connection_pool)
+                .await
+                .unwrap();
+        }
+
+        let journal = OutboundJournal::load(&path, TEST_TTL).await.unwrap();
+        let unacked = journal.unacked_fragments().await.unwrap();
+        assert_eq!(unacked.len(), fresh_message.len());
+        assert!(unacked.iter().all(|journaled| journaled.message_id == 1));
+    }
+
+    #[tokio::test]
+    async fn acknowledgements_of_replies_are_ignored() {
+        let dir = tempdir().unwrap();
+        let journal = OutboundJournal::load(dir.path().join("journal.db"), TEST_TTL)
+            .await
+            .unwrap();
+
+        journal
+            .remove_fragment(FragmentIdentifier::new_reply(&mut OsRng))
+            .await
+            .unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn journal_is_only_accessible_by_its_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("journal.db");
+        std::fs::write(&path, []).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        OutboundJournal::load(&path, TEST_TTL).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -2,6 +2,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::action_controller::{Action, ActionSender};
+use crate::client::key_rotation::RotatableKey;
+use crate::client::outbound_journal::OutboundJournal;
 use futures::StreamExt;
 use gateway_client::AcknowledgementReceiver;
 use log::*;
@@ -9,55 +11,81 @@ use nymsphinx::{
     acknowledgements::{identifier::recover_identifier, AckKey},
     chunking::fragment::{FragmentIdentifier, COVER_FRAG_ID},
 };
-use std::sync::Arc;
 
 /// Module responsible for listening for any data resembling acknowledgements from the network
 /// and firing actions to remove them from the 'Pending' state.
 pub(super) struct AcknowledgementListener {
-    ack_key: Arc<AckKey>,
+    ack_key: RotatableKey<AckKey>,
     ack_receiver: AcknowledgementReceiver,
     action_sender: ActionSender,
+    outbound_journal: Option<OutboundJournal>,
 }
 
 impl AcknowledgementListener {
     pub(super) fn new(
-        ack_key: Arc<AckKey>,
+        ack_key: RotatableKey<AckKey>,
         ack_receiver: AcknowledgementReceiver,
         action_sender: ActionSender,
+        outbound_journal: Option<OutboundJournal>,
     ) -> Self {
         AcknowledgementListener {
             ack_key,
             ack_receiver,
             action_sender,
+            outbound_journal,
         }
     }
 
     async fn on_ack(&mut self, ack_content: Vec<u8>) {
         debug!("Received an ack");
-        let frag_id = match recover_identifier(&self.ack_key, &ack_content)
-            .map(FragmentIdentifier::try_from_bytes)
-        {
-            Some(Ok(frag_id)) => frag_id,
-            _ => {
-                warn!("Received invalid ACK!"); // should we do anything else about that?
-                return;
-            }
-        };
+        // the ack might have been created with the key replaced during the latest rotation.
+        // The identifiers are not authenticated, so every key that produces a valid one is
+        // treated as a candidate - removing an identifier that isn't pending is a no-op.
+        let frag_ids: Vec<_> = self
+            .ack_key
+            .valid_keys()
+            .iter()
+            .filter_map(|ack_key| recover_identifier(ack_key, &ack_content))
+            .filter_map(|frag_id_bytes| FragmentIdentifier::try_from_bytes(frag_id_bytes).ok())
+            .collect();
+
+        if frag_ids.is_empty() {
+            warn!("Received invalid ACK!"); // should we do anything else about that?
+            return;
+        }
 
-        // if we received an ack for cover message or a reply there will be nothing to remove,
+        for frag_id in frag_ids {
+            self.on_fragment_ack(frag_id).await
+        }
+    }
+
+    async fn on_fragment_ack(&mut self, frag_id: FragmentIdentifier) {
+        // if we received an ack for cover message there will be nothing to remove,
         // because nothing was inserted in the first place
         if frag_id == COVER_FRAG_ID {
             trace!("Received an ack for a cover message - no need to do anything");
             return;
-        } else if frag_id.is_reply() {
+        }
+
+        trace!("Received {} from the mix network", frag_id);
+
+        if let Some(outbound_journal) = &self.outbound_journal {
+            if let Err(err) = outbound_journal.remove_fragment(frag_id).await {
+                // worst case scenario the fragment is going to be resent after restart
+                warn!(
+                    "Failed to remove {} from the outbound journal - {}",
+                    frag_id, err
+                );
+            }
+        }
+
+        if frag_id.is_reply() {
             info!("Received an ack for a reply message - no need to do anything! (don't know what to do!)");
             // TODO: probably there will need to be some extra procedure here, something to notify
             // user that his reply reached the recipient (since we got an ack)
             return;
         }
 
-        trace!("Received {} from the mix network", frag_id);
-
         self.action_sender
             .unbounded_send(Action::new_remove(frag_id))
             .unwrap();
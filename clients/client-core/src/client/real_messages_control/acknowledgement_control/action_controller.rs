@@ -2,21 +2,76 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::PendingAcknowledgement;
+use crate::client::delivery_receipts::{DeliveryReceipt, DeliveryReceiptSender, MessageId};
+use crate::client::outbound_journal::OutboundJournal;
 use crate::client::real_messages_control::acknowledgement_control::RetransmissionRequestSender;
+use crate::client::real_messages_control::RetransmissionPolicy;
+use crate::client::traffic_control::PacketStatistics;
 use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use futures::StreamExt;
 use log::*;
 use nonexhaustive_delayqueue::{Expired, NonExhaustiveDelayQueue, QueueKey};
+use nymsphinx::addressing::nodes::NymNodeRoutingAddress;
 use nymsphinx::chunking::fragment::FragmentIdentifier;
 use nymsphinx::Delay as SphinxDelay;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub(crate) type ActionSender = UnboundedSender<Action>;
 
-// The actual data being sent off as well as potential key to the delay queue
-type PendingAckEntry = (Arc<PendingAcknowledgement>, Option<QueueKey>);
+// The actual data being sent off, potential key to the delay queue and number of times
+// it has already been retransmitted
+struct PendingAckEntry {
+    pending_ack: Arc<PendingAcknowledgement>,
+    queue_key: Option<QueueKey>,
+    retransmissions: u32,
+}
+
+impl PendingAckEntry {
+    fn new(pending_ack: PendingAcknowledgement) -> Self {
+        PendingAckEntry {
+            pending_ack: Arc::new(pending_ack),
+            queue_key: None,
+            retransmissions: 0,
+        }
+    }
+}
+
+// All fragments of a particular message that are still waiting for their acks alongside
+// the channel to notify once the fate of the message is known.
+struct PendingMessage {
+    fragments: HashSet<FragmentIdentifier>,
+    receipt_sender: Option<DeliveryReceiptSender>,
+    deadline: Option<Instant>,
+}
+
+impl PendingMessage {
+    fn new(receipt_sender: Option<DeliveryReceiptSender>, deadline: Option<Instant>) -> Self {
+        PendingMessage {
+            fragments: HashSet::new(),
+            receipt_sender,
+            deadline,
+        }
+    }
+
+    fn is_past_deadline(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        }
+    }
+
+    fn send_receipt(&self, receipt: DeliveryReceipt) {
+        if let Some(receipt_sender) = &self.receipt_sender {
+            // the receiver might have legitimately gone away in the meantime,
+            // for example if the websocket connection got closed
+            if receipt_sender.unbounded_send(receipt).is_err() {
+                debug!("Could not send delivery receipt - the receiver is gone");
+            }
+        }
+    }
+}
 
 // we can either:
 // - have a completely new set of packets we just sent and need to create entries for
@@ -24,9 +79,10 @@ type PendingAckEntry = (Arc<PendingAcknowledgement>, Option<QueueKey>);
 // - start a retransmission timer for sending the packet into the network (on either first try or retransmission)
 // - update the internal sphinx delay of an expired packet
 pub(crate) enum Action {
-    /// Inserts new `PendingAcknowledgement`s into the 'shared' state.
+    /// Inserts new `PendingAcknowledgement`s into the 'shared' state alongside optional channel
+    /// for the delivery receipt of the message they are part of.
     /// Initiated by `InputMessageListener`
-    InsertPending(Vec<PendingAcknowledgement>),
+    InsertPending(Vec<PendingAcknowledgement>, Option<DeliveryReceiptSender>),
 
     /// Removes given `PendingAcknowledgement` from the 'shared' state. Also cancels the retransmission timer.
     /// Initiated by `AcknowledgementListener`
@@ -38,14 +94,18 @@ pub(crate) enum Action {
     /// Can also be initiated by `RetransmissionRequestListener` in the rare cases of invalid Topology.
     StartTimer(FragmentIdentifier),
 
-    /// Updates the expected delay of given `PendingAcknowledgement` with the new provided `SphinxDelay`.
+    /// Updates the expected delay and the route of given `PendingAcknowledgement` with the new provided
+    /// `SphinxDelay` and the new route the retransmitted packet is going to take.
     /// Initiated by `RetransmissionRequestListener`
-    UpdateDelay(FragmentIdentifier, SphinxDelay),
+    UpdateDelay(FragmentIdentifier, SphinxDelay, Vec<NymNodeRoutingAddress>),
 }
 
 impl Action {
-    pub(crate) fn new_insert(pending_acks: Vec<PendingAcknowledgement>) -> Self {
-        Action::InsertPending(pending_acks)
+    pub(crate) fn new_insert(
+        pending_acks: Vec<PendingAcknowledgement>,
+        receipt_sender: Option<DeliveryReceiptSender>,
+    ) -> Self {
+        Action::InsertPending(pending_acks, receipt_sender)
     }
 
     pub(crate) fn new_remove(frag_id: FragmentIdentifier) -> Self {
@@ -56,8 +116,12 @@ impl Action {
         Action::StartTimer(frag_id)
     }
 
-    pub(crate) fn new_update_delay(frag_id: FragmentIdentifier, delay: SphinxDelay) -> Self {
-        Action::UpdateDelay(frag_id, delay)
+    pub(crate) fn new_update_delay(
+        frag_id: FragmentIdentifier,
+        delay: SphinxDelay,
+        route: Vec<NymNodeRoutingAddress>,
+    ) -> Self {
+        Action::UpdateDelay(frag_id, delay, route)
     }
 }
 
@@ -68,17 +132,31 @@ pub(super) struct Config {
 
     /// Given ack timeout in the form a * BASE_DELAY + b, it specifies the multiplier `a`
     ack_wait_multiplier: f64,
+
+    /// Determines when lost `Fragment`s are retransmitted and when we give up on them.
+    retransmission_policy: RetransmissionPolicy,
 }
 
 impl Config {
-    pub(super) fn new(ack_wait_addition: Duration, ack_wait_multiplier: f64) -> Self {
+    pub(super) fn new(
+        ack_wait_addition: Duration,
+        ack_wait_multiplier: f64,
+        retransmission_policy: RetransmissionPolicy,
+    ) -> Self {
         Config {
             ack_wait_addition,
             ack_wait_multiplier,
+            retransmission_policy,
         }
     }
 }
 
+// reason for abandoning all remaining fragments of a message
+enum AbandonReason {
+    RetransmissionLimit(u32),
+    DeadlineExceeded,
+}
+
 pub(super) struct ActionController {
     /// Configurable parameters of the `ActionController`
     config: Config,
@@ -87,6 +165,10 @@ pub(super) struct ActionController {
     /// key to its `AckDelayQueue` entry if it was started.
     pending_acks_data: HashMap<FragmentIdentifier, PendingAckEntry>,
 
+    /// Contains a map between `MessageId` and identifiers of all its fragments that are still
+    /// waiting to get acknowledged.
+    pending_messages: HashMap<MessageId, PendingMessage>,
+
     // This structure ensures that we will EITHER handle expired timer or a received action and NEVER both
     // at the same time hence getting rid of one possible race condition that we suffered from in the
     // previous version.
@@ -99,34 +181,64 @@ pub(super) struct ActionController {
 
     /// Channel for notifying `RetransmissionRequestListener` about expired acknowledgements.
     retransmission_sender: RetransmissionRequestSender,
+
+    /// Journal from which fragments of abandoned messages have to be removed so that they
+    /// would not get resent upon restart.
+    outbound_journal: Option<OutboundJournal>,
+
+    /// Statistics onto which the numbers of pending acks and messages are published.
+    packet_statistics: PacketStatistics,
 }
 
 impl ActionController {
     pub(super) fn new(
         config: Config,
         retransmission_sender: RetransmissionRequestSender,
+        outbound_journal: Option<OutboundJournal>,
+        packet_statistics: PacketStatistics,
     ) -> (Self, ActionSender) {
         let (sender, receiver) = mpsc::unbounded();
         (
             ActionController {
                 config,
                 pending_acks_data: HashMap::new(),
+                pending_messages: HashMap::new(),
                 pending_acks_timers: NonExhaustiveDelayQueue::new(),
                 incoming_actions: receiver,
                 retransmission_sender,
+                outbound_journal,
+                packet_statistics,
             },
             sender,
         )
     }
 
-    fn handle_insert(&mut self, pending_acks: Vec<PendingAcknowledgement>) {
+    fn handle_insert(
+        &mut self,
+        pending_acks: Vec<PendingAcknowledgement>,
+        receipt_sender: Option<DeliveryReceiptSender>,
+    ) {
         for pending_ack in pending_acks {
             let frag_id = pending_ack.message_chunk.fragment_identifier();
             trace!("{} is inserted", frag_id);
 
+            if let Some(message_id) = pending_ack.message_id {
+                let deadline = self
+                    .config
+                    .retransmission_policy
+                    .message_deadline
+                    .map(|deadline| Instant::now() + deadline);
+
+                self.pending_messages
+                    .entry(message_id)
+                    .or_insert_with(|| PendingMessage::new(receipt_sender.clone(), deadline))
+                    .fragments
+                    .insert(frag_id);
+            }
+
             if self
                 .pending_acks_data
-                .insert(frag_id, (Arc::new(pending_ack), None))
+                .insert(frag_id, PendingAckEntry::new(pending_ack))
                 .is_some()
             {
                 panic!("Tried to insert duplicate pending ack")
@@ -137,18 +249,22 @@ impl ActionController {
     fn handle_start_timer(&mut self, frag_id: FragmentIdentifier) {
         trace!("{} is starting its timer", frag_id);
 
-        if let Some((pending_ack_data, queue_key)) = self.pending_acks_data.get_mut(&frag_id) {
-            if queue_key.is_some() {
+        if let Some(entry) = self.pending_acks_data.get_mut(&frag_id) {
+            if entry.queue_key.is_some() {
                 // this branch should be IMPOSSIBLE under ANY condition. It would imply starting
                 // timer TWICE for the SAME PendingAcknowledgement
                 panic!("Tried to start an already started ack timer!")
             }
-            let timeout = (pending_ack_data.delay.clone() * self.config.ack_wait_multiplier)
+            let base_timeout = (entry.pending_ack.delay.clone() * self.config.ack_wait_multiplier)
                 .to_duration()
                 + self.config.ack_wait_addition;
+            let timeout = self
+                .config
+                .retransmission_policy
+                .ack_timeout(base_timeout, entry.retransmissions);
 
             let new_queue_key = self.pending_acks_timers.insert(frag_id, timeout);
-            *queue_key = Some(new_queue_key)
+            entry.queue_key = Some(new_queue_key)
         } else {
             debug!(
                 "Tried to START TIMER on pending ack that is already gone! - {}",
@@ -167,8 +283,12 @@ impl ActionController {
                     frag_id
                 );
             }
-            Some((_, queue_key)) => {
-                if let Some(queue_key) = queue_key {
+            Some(entry) => {
+                if let Some(message_id) = entry.pending_ack.message_id {
+                    self.on_fragment_acknowledged(message_id, frag_id)
+                }
+
+                if let Some(queue_key) = entry.queue_key {
                     // there are no possible checks here, we must GUARANTEE that we NEVER try
                     // to remove an entry that doesn't exist (and we MUST GUARANTEE that
                     // we do not have a stale key)
@@ -186,20 +306,110 @@ impl ActionController {
         }
     }
 
+    fn on_fragment_acknowledged(&mut self, message_id: MessageId, frag_id: FragmentIdentifier) {
+        let is_complete = match self.pending_messages.get_mut(&message_id) {
+            Some(pending_message) => {
+                pending_message.fragments.remove(&frag_id);
+                pending_message.fragments.is_empty()
+            }
+            None => false,
+        };
+
+        if is_complete {
+            if let Some(pending_message) = self.pending_messages.remove(&message_id) {
+                debug!("All fragments of message {} got acknowledged", message_id);
+                pending_message.send_receipt(DeliveryReceipt::new_delivered(message_id))
+            }
+        }
+    }
+
+    // removes all remaining fragments of the message that exceeded its retransmission limit
+    // or its deadline
+    async fn abandon_message(
+        &mut self,
+        message_id: Option<MessageId>,
+        frag_id: FragmentIdentifier,
+        reason: AbandonReason,
+    ) {
+        let (abandoned_fragments, pending_message) =
+            match message_id.and_then(|id| self.pending_messages.remove(&id)) {
+                Some(pending_message) => (
+                    pending_message.fragments.iter().copied().collect(),
+                    Some(pending_message),
+                ),
+                None => (vec![frag_id], None),
+            };
+
+        for abandoned_id in &abandoned_fragments {
+            if let Some(entry) = self.pending_acks_data.remove(abandoned_id) {
+                if let Some(queue_key) = entry.queue_key {
+                    self.pending_acks_timers.remove(&queue_key);
+                }
+            }
+
+            if let Some(outbound_journal) = &self.outbound_journal {
+                if let Err(err) = outbound_journal.remove_fragment(*abandoned_id).await {
+                    error!(
+                        "Failed to remove abandoned fragment from the outbound journal - {}",
+                        err
+                    );
+                }
+            }
+        }
+
+        let (message_id, pending_message) = match (message_id, pending_message) {
+            (Some(message_id), Some(pending_message)) => (message_id, pending_message),
+            _ => {
+                match reason {
+                    AbandonReason::RetransmissionLimit(retransmissions) => warn!(
+                        "Giving up on {} after {} retransmissions",
+                        frag_id, retransmissions
+                    ),
+                    // a fragment without a known message can't have a deadline
+                    AbandonReason::DeadlineExceeded => warn!("Giving up on {}", frag_id),
+                }
+                return;
+            }
+        };
+
+        let receipt = match reason {
+            AbandonReason::RetransmissionLimit(retransmissions) => {
+                warn!(
+                    "Giving up on message {} after {} retransmissions of {}",
+                    message_id, retransmissions, frag_id
+                );
+                DeliveryReceipt::new_gave_up(message_id, retransmissions, abandoned_fragments)
+            }
+            AbandonReason::DeadlineExceeded => {
+                warn!(
+                    "Giving up on message {} as it was not delivered before its deadline",
+                    message_id
+                );
+                DeliveryReceipt::new_deadline_exceeded(message_id, abandoned_fragments)
+            }
+        };
+        pending_message.send_receipt(receipt)
+    }
+
     // initiated basically as a first step of retransmission. At first data has its delay updated
     // (as new sphinx packet was created with new expected delivery time)
-    fn handle_update_delay(&mut self, frag_id: FragmentIdentifier, delay: SphinxDelay) {
+    fn handle_update_delay(
+        &mut self,
+        frag_id: FragmentIdentifier,
+        delay: SphinxDelay,
+        route: Vec<NymNodeRoutingAddress>,
+    ) {
         trace!("{} is updating its delay", frag_id);
         // TODO: is it possible to solve this without either locking or temporarily removing the value?
-        if let Some((pending_ack_data, queue_key)) = self.pending_acks_data.remove(&frag_id) {
+        if let Some(mut entry) = self.pending_acks_data.remove(&frag_id) {
             // this Action is triggered by `RetransmissionRequestListener` which held the other potential
             // reference to this Arc. HOWEVER, before the Action was pushed onto the queue, the reference
             // was dropped hence this unwrap is safe.
-            let mut inner_data = Arc::try_unwrap(pending_ack_data).unwrap();
-            inner_data.update_delay(delay);
+            let mut inner_data = Arc::try_unwrap(entry.pending_ack).unwrap();
+            inner_data.update_delay_and_route(delay, route);
+            entry.pending_ack = Arc::new(inner_data);
 
-            self.pending_acks_data
-                .insert(frag_id, (Arc::new(inner_data), queue_key));
+            self.pending_acks_data.insert(frag_id, entry);
         } else {
             debug!(
                 "Tried to UPDATE TIMER on pending ack that is already gone! - {}",
@@ -209,7 +419,7 @@ impl ActionController {
     }
 
     // note: when the entry expires it's automatically removed from pending_acks_timers
-    fn handle_expired_ack_timer(&mut self, expired_ack: Expired<FragmentIdentifier>) {
+    async fn handle_expired_ack_timer(&mut self, expired_ack: Expired<FragmentIdentifier>) {
         // I'm honestly not sure how to handle it, because getting it means other things in our
         // system are already misbehaving. If we ever see this panic, then I guess we should worry
         // about it. Perhaps just reschedule it at later point?
@@ -217,18 +427,44 @@ impl ActionController {
 
         trace!("{} has expired", frag_id);
 
-        if let Some((pending_ack_data, queue_key)) = self.pending_acks_data.get_mut(&frag_id) {
-            if queue_key.is_none() {
+        if let Some(entry) = self.pending_acks_data.get_mut(&frag_id) {
+            if entry.queue_key.is_none() {
                 // this branch should be IMPOSSIBLE under ANY condition. It would imply the timeout
                 // happened before it even started.
                 panic!("Ack expired before it was even scheduled!")
             }
-            *queue_key = None;
+            entry.queue_key = None;
+
+            let message_id = entry.pending_ack.message_id;
+            let retransmissions = entry.retransmissions;
+            let maximum_retransmissions = self.config.retransmission_policy.maximum_retransmissions;
+
+            let past_deadline = message_id
+                .and_then(|message_id| self.pending_messages.get(&message_id))
+                .map(|pending_message| pending_message.is_past_deadline())
+                .unwrap_or_default();
+            if past_deadline {
+                self.abandon_message(message_id, frag_id, AbandonReason::DeadlineExceeded)
+                    .await;
+                return;
+            }
+
+            if maximum_retransmissions != 0 && retransmissions >= maximum_retransmissions {
+                self.abandon_message(
+                    message_id,
+                    frag_id,
+                    AbandonReason::RetransmissionLimit(retransmissions),
+                )
+                .await;
+                return;
+            }
+            entry.retransmissions += 1;
+
             // downgrading an arc and then upgrading vs cloning is difference of 30ns vs 15ns
             // so it's literally a NO difference while it might prevent us from unnecessarily
             // resending data (in maybe 1 in 1 million cases, but it's something)
             self.retransmission_sender
-                .unbounded_send(Arc::downgrade(pending_ack_data))
+                .unbounded_send(Arc::downgrade(&entry.pending_ack))
                 .unwrap()
         } else {
             // this shouldn't cause any issues but shouldn't have happened to begin with!
@@ -238,13 +474,24 @@ impl ActionController {
 
     fn process_action(&mut self, action: Action) {
         match action {
-            Action::InsertPending(pending_acks) => self.handle_insert(pending_acks),
+            Action::InsertPending(pending_acks, receipt_sender) => {
+                self.handle_insert(pending_acks, receipt_sender)
+            }
             Action::RemovePending(frag_id) => self.handle_remove(frag_id),
             Action::StartTimer(frag_id) => self.handle_start_timer(frag_id),
-            Action::UpdateDelay(frag_id, delay) => self.handle_update_delay(frag_id, delay),
+            Action::UpdateDelay(frag_id, delay, route) => {
+                self.handle_update_delay(frag_id, delay, route)
+            }
         }
     }
 
+    fn publish_pending_counts(&self) {
+        self.packet_statistics.set_pending_acks(
+            self.pending_acks_data.len() as u64,
+            self.pending_messages.len() as u64,
+        )
+    }
+
     pub(super) async fn run(&mut self) {
         loop {
             // at some point there will be a global shutdown signal here as the third option
@@ -252,8 +499,213 @@ impl ActionController {
                 // we NEVER expect for ANY sender to get dropped so unwrap here is fine
                 action = self.incoming_actions.next() => self.process_action(action.unwrap()),
                 // pending ack queue Stream CANNOT return a `None` so unwrap here is fine
-                expired_ack = self.pending_acks_timers.next() => self.handle_expired_ack_timer(expired_ack.unwrap())
+                expired_ack = self.pending_acks_timers.next() => self.handle_expired_ack_timer(expired_ack.unwrap()).await
             }
+            self.publish_pending_counts();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::delivery_receipts::DeliveryStatus;
+    use crypto::asymmetric::{encryption, identity};
+    use nymsphinx::addressing::clients::Recipient;
+    use nymsphinx::chunking::split_into_sets;
+    use rand::rngs::OsRng;
+    use std::sync::Weak;
+
+    fn test_controller(
+        retransmission_policy: RetransmissionPolicy,
+    ) -> (
+        ActionController,
+        UnboundedReceiver<Weak<PendingAcknowledgement>>,
+    ) {
+        let config = Config::new(Duration::from_millis(1), 1.0, retransmission_policy);
+        let (retransmission_sender, retransmission_receiver) = mpsc::unbounded();
+        let (controller, _) =
+            ActionController::new(config, retransmission_sender, None, PacketStatistics::new());
+        (controller, retransmission_receiver)
+    }
+
+    fn test_message(message_id: MessageId) -> Vec<PendingAcknowledgement> {
+        let mut rng = OsRng;
+        let recipient = Recipient::new(
+            *identity::KeyPair::new(&mut rng).public_key(),
+            *encryption::KeyPair::new(&mut rng).public_key(),
+            *identity::KeyPair::new(&mut rng).public_key(),
+        );
+
+        split_into_sets(&mut rng, &[42; 2500], 1000)
+            .into_iter()
+            .flatten()
+            .map(|fragment| {
+                PendingAcknowledgement::new(
+                    fragment,
+                    SphinxDelay::new_from_nanos(0),
+                    recipient,
+                    Vec::new(),
+                    Some(message_id),
+                )
+            })
+            .collect()
+    }
+
+    fn fragment_ids(pending_acks: &[PendingAcknowledgement]) -> Vec<FragmentIdentifier> {
+        pending_acks
+            .iter()
+            .map(|pending_ack| pending_ack.message_chunk.fragment_identifier())
+            .collect()
+    }
+
+    // starts the ack timer of the fragment and waits for it to expire
+    async fn expire(controller: &mut ActionController, frag_id: FragmentIdentifier) {
+        controller.handle_start_timer(frag_id);
+        let expired = controller.pending_acks_timers.next().await.unwrap();
+        assert_eq!(*expired.get_ref(), frag_id);
+        controller.handle_expired_ack_timer(expired).await;
+    }
+
+    #[tokio::test]
+    async fn delivered_receipt_is_sent_once_all_fragments_are_acknowledged() {
+        let (mut controller, _retransmissions) =
+            test_controller(RetransmissionPolicy::new(0, 1.0, Duration::ZERO, None));
+        let (receipt_sender, mut receipt_receiver) = mpsc::unbounded();
+
+        let message = test_message(42);
+        let frag_ids = fragment_ids(&message);
+        assert!(frag_ids.len() > 1);
+        controller.handle_insert(message, Some(receipt_sender));
+
+        for frag_id in &frag_ids {
+            assert!(receipt_receiver.try_next().is_err());
+            controller.handle_remove(*frag_id);
         }
+
+        assert_eq!(
+            receipt_receiver.try_next().unwrap().unwrap(),
+            DeliveryReceipt::new_delivered(42)
+        );
+        assert!(controller.pending_messages.is_empty());
+        assert!(controller.pending_acks_data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn message_is_abandoned_after_maximum_retransmissions() {
+        let (mut controller, mut retransmissions) =
+            test_controller(RetransmissionPolicy::new(2, 1.0, Duration::ZERO, None));
+        let (receipt_sender, mut receipt_receiver) = mpsc::unbounded();
+
+        let message = test_message(42);
+        let frag_ids = fragment_ids(&message);
+        controller.handle_insert(message, Some(receipt_sender));
+
+        // one of the fragments got through
+        controller.handle_remove(frag_ids[0]);
+        let lost_fragment = frag_ids[1];
+
+        for _ in 0..2 {
+            expire(&mut controller, lost_fragment).await;
+            assert!(retransmissions.try_next().unwrap().is_some());
+            assert!(receipt_receiver.try_next().is_err());
+        }
+
+        expire(&mut controller, lost_fragment).await;
+        assert!(retransmissions.try_next().is_err());
+
+        let receipt = receipt_receiver.try_next().unwrap().unwrap();
+        assert_eq!(receipt.message_id, 42);
+        match receipt.status {
+            DeliveryStatus::GaveUp {
+                retransmissions,
+                failed_fragments,
+            } => {
+                assert_eq!(retransmissions, 2);
+                assert_eq!(failed_fragments.len(), frag_ids.len() - 1);
+                assert!(!failed_fragments.contains(&frag_ids[0]));
+            }
+            status => panic!("unexpected delivery status {:?}", status),
+        }
+
+        // all remaining fragments of the message are gone
+        assert!(controller.pending_messages.is_empty());
+        assert!(controller.pending_acks_data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fragments_are_retransmitted_indefinitely_without_a_limit() {
+        let (mut controller, mut retransmissions) =
+            test_controller(RetransmissionPolicy::new(0, 1.0, Duration::ZERO, None));
+        let (receipt_sender, mut receipt_receiver) = mpsc::unbounded();
+
+        let message = test_message(42);
+        let frag_ids = fragment_ids(&message);
+        controller.handle_insert(message, Some(receipt_sender));
+
+        for _ in 0..20 {
+            expire(&mut controller, frag_ids[0]).await;
+            assert!(retransmissions.try_next().unwrap().is_some());
+        }
+
+        assert!(receipt_receiver.try_next().is_err());
+        assert_eq!(
+            controller.pending_acks_data[&frag_ids[0]].retransmissions,
+            20
+        );
+    }
+
+    #[tokio::test]
+    async fn message_is_abandoned_after_its_deadline() {
+        let (mut controller, mut retransmissions) = test_controller(RetransmissionPolicy::new(
+            0,
+            1.0,
+            Duration::ZERO,
+            Some(Duration::from_millis(50)),
+        ));
+        let (receipt_sender, mut receipt_receiver) = mpsc::unbounded();
+
+        let message = test_message(42);
+        let frag_ids = fragment_ids(&message);
+        controller.handle_insert(message, Some(receipt_sender));
+
+        // before the deadline the lost fragment is simply retransmitted
+        expire(&mut controller, frag_ids[0]).await;
+        assert!(retransmissions.try_next().unwrap().is_some());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        expire(&mut controller, frag_ids[0]).await;
+        assert!(retransmissions.try_next().is_err());
+
+        let receipt = receipt_receiver.try_next().unwrap().unwrap();
+        assert_eq!(receipt.message_id, 42);
+        match receipt.status {
+            DeliveryStatus::DeadlineExceeded { failed_fragments } => {
+                assert_eq!(failed_fragments.len(), frag_ids.len())
+            }
+            status => panic!("unexpected delivery status {:?}", status),
+        }
+        assert!(controller.pending_messages.is_empty());
+        assert!(controller.pending_acks_data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fragments_without_message_are_abandoned_on_their_own() {
+        let (mut controller, _retransmissions) =
+            test_controller(RetransmissionPolicy::new(1, 1.0, Duration::ZERO, None));
+
+        let mut message = test_message(42);
+        for pending_ack in &mut message {
+            pending_ack.message_id = None;
+        }
+        let frag_ids = fragment_ids(&message);
+        controller.handle_insert(message, None);
+
+        expire(&mut controller, frag_ids[0]).await;
+        expire(&mut controller, frag_ids[0]).await;
+
+        assert!(!controller.pending_acks_data.contains_key(&frag_ids[0]));
+        assert_eq!(controller.pending_acks_data.len(), frag_ids.len() - 1);
+        assert!(controller.pending_messages.is_empty());
     }
 }
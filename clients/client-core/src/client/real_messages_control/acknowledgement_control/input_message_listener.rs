@@ -3,19 +3,27 @@
 
 use super::action_controller::{Action, ActionSender};
 use super::PendingAcknowledgement;
+use crate::client::delivery_receipts::{DeliveryReceipt, DeliveryReceiptSender, MessageId};
+use crate::client::gateway_failover::SelfAddressReceiver;
+use crate::client::key_rotation::RotatableKey;
+use crate::client::outbound_journal::OutboundJournal;
 use crate::client::reply_key_storage::ReplyKeyStorage;
+use crate::client::traffic_control::PacketStatistics;
 use crate::client::{
     inbound_messages::{InputMessage, InputMessageReceiver},
     real_messages_control::real_traffic_stream::{BatchRealMessageSender, RealMessage},
     topology_control::TopologyAccessor,
 };
+use futures::channel::oneshot;
 use futures::StreamExt;
 use log::*;
-use nymsphinx::anonymous_replies::ReplySurb;
-use nymsphinx::preparer::MessagePreparer;
+use nymsphinx::anonymous_replies::{encryption_key::EncryptionKeyDigest, ReplySurb};
+use nymsphinx::chunking::fragment::Fragment;
+use nymsphinx::headers::MessageHeaders;
+use nymsphinx::preparer::{MessagePreparer, PreparationError};
 use nymsphinx::{acknowledgements::AckKey, addressing::clients::Recipient};
 use rand::{CryptoRng, Rng};
-use std::sync::Arc;
+use topology::{NymTopology, NymTopologyError};
 
 /// Module responsible for dealing with the received messages: splitting them, creating acknowledgements,
 /// putting everything into sphinx packets, etc.
@@ -24,14 +32,16 @@ pub(super) struct InputMessageListener<R>
 where
     R: CryptoRng + Rng,
 {
-    ack_key: Arc<AckKey>,
-    ack_recipient: Recipient,
+    ack_key: RotatableKey<AckKey>,
+    ack_recipient: SelfAddressReceiver,
     input_receiver: InputMessageReceiver,
     message_preparer: MessagePreparer<R>,
     action_sender: ActionSender,
     real_message_sender: BatchRealMessageSender,
     topology_access: TopologyAccessor,
     reply_key_storage: ReplyKeyStorage,
+    outbound_journal: Option<OutboundJournal>,
+    packet_statistics: PacketStatistics,
 }
 
 impl<R> InputMessageListener<R>
@@ -42,14 +52,16 @@ where
     // some considerable refactoring
     #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
-        ack_key: Arc<AckKey>,
-        ack_recipient: Recipient,
+        ack_key: RotatableKey<AckKey>,
+        ack_recipient: SelfAddressReceiver,
         input_receiver: InputMessageReceiver,
         message_preparer: MessagePreparer<R>,
         action_sender: ActionSender,
         real_message_sender: BatchRealMessageSender,
         topology_access: TopologyAccessor,
         reply_key_storage: ReplyKeyStorage,
+        outbound_journal: Option<OutboundJournal>,
+        packet_statistics: PacketStatistics,
     ) -> Self {
         InputMessageListener {
             ack_key,
@@ -60,13 +72,31 @@ where
             real_message_sender,
             topology_access,
             reply_key_storage,
+            outbound_journal,
+            packet_statistics,
         }
     }
 
+    fn send_real_messages(&self, real_messages: Vec<RealMessage>) {
+        self.packet_statistics
+            .increment_real_packets_queued(real_messages.len() as u64);
+        self.real_message_sender
+            .unbounded_send(real_messages)
+            .unwrap();
+    }
+
+    // the address might have changed if we switched to a different gateway
+    fn current_ack_recipient(&mut self) -> Recipient {
+        let ack_recipient = *self.ack_recipient.borrow();
+        self.message_preparer.set_sender_address(ack_recipient);
+        ack_recipient
+    }
+
     // we require topology for replies to generate surb_acks
     async fn handle_reply(&mut self, reply_surb: ReplySurb, data: Vec<u8>) -> Option<RealMessage> {
+        let ack_recipient = self.current_ack_recipient();
         let topology_permit = self.topology_access.get_read_permit().await;
-        let topology = match topology_permit.try_get_valid_topology_ref(&self.ack_recipient, None) {
+        let topology = match topology_permit.try_get_valid_topology_ref(&ack_recipient, None) {
             Some(topology_ref) => topology_ref,
             None => {
                 warn!("Could not process the message - the network topology is invalid");
@@ -74,9 +104,11 @@ where
             }
         };
 
+        // note that the replies are not recorded in the outbound journal, as each reply surb can
+        // only ever be used once and resending the reply after a restart would have to reuse it
         match self
             .message_preparer
-            .prepare_reply_for_use(data, reply_surb, topology, &self.ack_key)
+            .prepare_reply_for_use(data, reply_surb, topology, &self.ack_key.current())
             .await
         {
             Ok((mix_packet, reply_id)) => {
@@ -94,47 +126,158 @@ where
         }
     }
 
+    // every fresh message gets a receipt, even if it never made it into the mix network
+    fn send_not_sent_receipt(message_id: MessageId, receipt_sender: Option<DeliveryReceiptSender>) {
+        if let Some(receipt_sender) = receipt_sender {
+            if receipt_sender
+                .unbounded_send(DeliveryReceipt::new_not_sent(message_id))
+                .is_err()
+            {
+                debug!("Could not send delivery receipt - the receiver is gone");
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn handle_fresh_message(
         &mut self,
         recipient: Recipient,
         content: Vec<u8>,
-        with_reply_surb: bool,
+        num_reply_surbs: u8,
+        message_id: MessageId,
+        receipt_sender: Option<DeliveryReceiptSender>,
+        headers: Option<MessageHeaders>,
+        reply_key_digests_sender: Option<oneshot::Sender<Vec<EncryptionKeyDigest>>>,
     ) -> Option<Vec<RealMessage>> {
-        let topology_permit = self.topology_access.get_read_permit().await;
-        let topology = match topology_permit
-            .try_get_valid_topology_ref(&self.ack_recipient, Some(&recipient))
-        {
-            Some(topology_ref) => topology_ref,
-            None => {
-                warn!("Could not process the message - the network topology is invalid");
+        let ack_recipient = self.current_ack_recipient();
+        // the permit is obtained from a clone of the accessor so that we could still
+        // mutably borrow self while holding it
+        let topology_access = self.topology_access.clone();
+        let topology_permit = topology_access.get_read_permit().await;
+        let topology =
+            match topology_permit.try_get_valid_topology_ref(&ack_recipient, Some(&recipient)) {
+                Some(topology_ref) => topology_ref,
+                None => {
+                    warn!("Could not process the message - the network topology is invalid");
+                    Self::send_not_sent_receipt(message_id, receipt_sender);
+                    return None;
+                }
+            };
+
+        // split the message, attach requested reply surbs and headers
+        let (split_message, reply_keys) = match self
+            .message_preparer
+            .prepare_and_split_message_with_headers(
+                content,
+                num_reply_surbs,
+                headers.as_ref(),
+                topology,
+            ) {
+            Ok(prepared) => prepared,
+            Err(PreparationError::TopologyError(err)) => {
+                warn!(
+                    "Could not process the message - the network topology was invalid after all - {:?}",
+                    err
+                );
+                Self::send_not_sent_receipt(message_id, receipt_sender);
+                return None;
+            }
+            Err(err) => {
+                warn!("Could not process the message - {:?}", err);
+                Self::send_not_sent_receipt(message_id, receipt_sender);
                 return None;
             }
         };
 
-        // split the message, attach optional reply surb
-        let (split_message, reply_key) = self
-            .message_preparer
-            .prepare_and_split_message(content, with_reply_surb, topology)
-            .expect("somehow the topology was invalid after all!");
+        if let Some(reply_key_digests_sender) = reply_key_digests_sender {
+            let digests = reply_keys
+                .iter()
+                .map(|reply_key| reply_key.compute_digest())
+                .collect();
+            // the requester might have given up on the message in the meantime
+            let _ = reply_key_digests_sender.send(digests);
+        }
 
-        if let Some(reply_key) = reply_key {
-            self.reply_key_storage
+        for reply_key in reply_keys {
+            if let Err(err) = self
+                .reply_key_storage
                 .insert_encryption_key(reply_key)
-                .expect("Failed to insert surb reply key to the store!")
+                .await
+            {
+                // the message can still be sent, it's just that we won't be able to
+                // decrypt the reply sent using this particular surb
+                error!("Failed to insert surb reply key to the store - {}", err);
+            }
+        }
+
+        // the fragments are journaled only once we know they can actually be sent
+        let journal_entry = self
+            .outbound_journal
+            .as_ref()
+            .map(|_| split_message.clone());
+
+        // the receipt sender is only consumed once the pending acks are created
+        let not_sent_receipt_sender = receipt_sender.clone();
+        let real_messages = match self
+            .prepare_fragments_for_sending(
+                recipient,
+                split_message,
+                Some(message_id),
+                receipt_sender,
+                topology,
+            )
+            .await
+        {
+            Ok(real_messages) => real_messages,
+            Err(err) => {
+                // for example if all of the nodes on some layer are excluded from routing
+                warn!("Could not find a route for the message - {:?}", err);
+                Self::send_not_sent_receipt(message_id, not_sent_receipt_sender);
+                return None;
+            }
+        };
+
+        if let (Some(outbound_journal), Some(split_message)) =
+            (&self.outbound_journal, journal_entry)
+        {
+            if let Err(err) = outbound_journal
+                .insert_fragments(&recipient, &split_message, message_id)
+                .await
+            {
+                // we can still send the message, it just won't survive a restart
+                error!("Failed to record message in the outbound journal - {}", err);
+            }
         }
 
+        Some(real_messages)
+    }
+
+    /// Puts the provided fragments into sphinx packets and creates pending acks for them.
+    /// If a route could not be chosen for any of the fragments, none of them are going to be sent.
+    async fn prepare_fragments_for_sending(
+        &mut self,
+        recipient: Recipient,
+        fragments: Vec<Fragment>,
+        message_id: Option<MessageId>,
+        receipt_sender: Option<DeliveryReceiptSender>,
+        topology: &NymTopology,
+    ) -> Result<Vec<RealMessage>, NymTopologyError> {
         // encrypt chunks, put them inside sphinx packets and generate acks
-        let mut pending_acks = Vec::with_capacity(split_message.len());
-        let mut real_messages = Vec::with_capacity(split_message.len());
-        for message_chunk in split_message {
+        let mut pending_acks = Vec::with_capacity(fragments.len());
+        let mut real_messages = Vec::with_capacity(fragments.len());
+        for message_chunk in fragments {
             // we need to clone it because we need to keep it in memory in case we had to retransmit
             // it. And then we'd need to recreate entire ACK again.
             let chunk_clone = message_chunk.clone();
             let prepared_fragment = self
                 .message_preparer
-                .prepare_chunk_for_sending(chunk_clone, topology, &self.ack_key, &recipient)
-                .await
-                .unwrap();
+                .prepare_chunk_for_sending(
+                    chunk_clone,
+                    topology,
+                    &self.ack_key.current(),
+                    &recipient,
+                )
+                .await?;
 
             real_messages.push(RealMessage::new(
                 prepared_fragment.mix_packet,
@@ -145,15 +288,108 @@ where
                 message_chunk,
                 prepared_fragment.total_delay,
                 recipient,
+                prepared_fragment.route,
+                message_id,
             ));
         }
 
         // tells the controller to put this into the hashmap
         self.action_sender
-            .unbounded_send(Action::new_insert(pending_acks))
+            .unbounded_send(Action::new_insert(pending_acks, receipt_sender))
             .unwrap();
 
-        Some(real_messages)
+        Ok(real_messages)
+    }
+
+    /// Sends again all fragments recorded in the outbound journal that were not
+    /// acknowledged before the client was last shut down.
+    async fn replay_outbound_journal(&mut self) {
+        let outbound_journal = match &self.outbound_journal {
+            Some(outbound_journal) => outbound_journal.clone(),
+            None => return,
+        };
+
+        let real_messages = self.replay_journaled_fragments(&outbound_journal).await;
+        if !real_messages.is_empty() {
+            self.send_real_messages(real_messages);
+        }
+    }
+
+    async fn replay_journaled_fragments(
+        &mut self,
+        outbound_journal: &OutboundJournal,
+    ) -> Vec<RealMessage> {
+        let unacked_fragments = match outbound_journal.unacked_fragments().await {
+            Ok(unacked_fragments) => unacked_fragments,
+            Err(err) => {
+                error!("Failed to read the outbound journal - {}", err);
+                return Vec::new();
+            }
+        };
+
+        if unacked_fragments.is_empty() {
+            return Vec::new();
+        }
+        info!(
+            "Resending {} unacknowledged fragments from the outbound journal",
+            unacked_fragments.len()
+        );
+
+        // put the remaining fragments of each message back together so that a single receipt
+        // would be produced for all of them
+        let mut messages: Vec<(MessageId, Recipient, Vec<Fragment>)> = Vec::new();
+        for journaled in unacked_fragments {
+            let existing = messages
+                .iter_mut()
+                .find(|(id, _, _)| *id == journaled.message_id);
+            match existing {
+                Some((_, _, fragments)) => fragments.push(journaled.fragment),
+                None => messages.push((
+                    journaled.message_id,
+                    journaled.recipient,
+                    vec![journaled.fragment],
+                )),
+            }
+        }
+
+        let ack_recipient = self.current_ack_recipient();
+        let topology_access = self.topology_access.clone();
+        let topology_permit = topology_access.get_read_permit().await;
+
+        let mut real_messages = Vec::new();
+        for (message_id, recipient, fragments) in messages {
+            let topology = match topology_permit
+                .try_get_valid_topology_ref(&ack_recipient, Some(&recipient))
+            {
+                Some(topology_ref) => topology_ref,
+                None => {
+                    // it stays in the journal so it will be tried again next time
+                    warn!("Could not resend journaled fragments - the network topology is invalid");
+                    continue;
+                }
+            };
+
+            // the original requester is gone, so the receipt goes wherever the journal says
+            let receipt_sender = outbound_journal.receipt_sender();
+            match self
+                .prepare_fragments_for_sending(
+                    recipient,
+                    fragments,
+                    Some(message_id),
+                    receipt_sender,
+                    topology,
+                )
+                .await
+            {
+                Ok(prepared) => real_messages.extend(prepared),
+                // it stays in the journal so it will be tried again next time
+                Err(err) => warn!(
+                    "Could not find a route for the journaled fragments - {:?}",
+                    err
+                ),
+            }
+        }
+        real_messages
     }
 
     async fn on_input_message(&mut self, msg: InputMessage) {
@@ -161,10 +397,22 @@ where
             InputMessage::Fresh {
                 recipient,
                 data,
-                with_reply_surb,
+                num_reply_surbs,
+                message_id,
+                receipt_sender,
+                headers,
+                reply_key_digests_sender,
             } => {
-                self.handle_fresh_message(recipient, data, with_reply_surb)
-                    .await
+                self.handle_fresh_message(
+                    recipient,
+                    data,
+                    num_reply_surbs,
+                    message_id,
+                    receipt_sender,
+                    headers,
+                    reply_key_digests_sender,
+                )
+                .await
             }
             InputMessage::Reply { reply_surb, data } => self
                 .handle_reply(reply_surb, data)
@@ -175,14 +423,13 @@ where
         // there's no point in trying to send nothing
         if let Some(real_messages) = real_messages {
             // tells real message sender (with the poisson timer) to send this to the mix network
-            self.real_message_sender
-                .unbounded_send(real_messages)
-                .unwrap();
+            self.send_real_messages(real_messages);
         }
     }
 
     pub(super) async fn run(&mut self) {
         debug!("Started InputMessageListener");
+        self.replay_outbound_journal().await;
         while let Some(input_msg) = self.input_receiver.next().await {
             self.on_input_message(input_msg).await;
         }
@@ -8,14 +8,20 @@ use self::{
     sent_notification_listener::SentNotificationListener,
 };
 use super::real_traffic_stream::BatchRealMessageSender;
+use super::RetransmissionPolicy;
+use crate::client::delivery_receipts::MessageId;
+use crate::client::gateway_failover::SelfAddressReceiver;
+use crate::client::key_rotation::RotatableKey;
+use crate::client::outbound_journal::OutboundJournal;
 use crate::client::reply_key_storage::ReplyKeyStorage;
+use crate::client::traffic_control::PacketStatistics;
 use crate::client::{inbound_messages::InputMessageReceiver, topology_control::TopologyAccessor};
 use futures::channel::mpsc;
 use gateway_client::AcknowledgementReceiver;
 use log::*;
 use nymsphinx::{
     acknowledgements::AckKey,
-    addressing::clients::Recipient,
+    addressing::{clients::Recipient, nodes::NymNodeRoutingAddress},
     chunking::fragment::{Fragment, FragmentIdentifier},
     preparer::MessagePreparer,
     Delay as SphinxDelay,
@@ -26,6 +32,7 @@ use std::{
     time::Duration,
 };
 use tokio::task::JoinHandle;
+use topology::route_selection::RouteSelector;
 
 mod acknowledgement_listener;
 mod action_controller;
@@ -53,20 +60,39 @@ pub(crate) struct PendingAcknowledgement {
     message_chunk: Fragment,
     delay: SphinxDelay,
     recipient: Recipient,
+    /// Route taken by the latest transmission of the `Fragment` so that any retransmission
+    /// could go through a different one.
+    route: Vec<NymNodeRoutingAddress>,
+    /// Identifier of the message this `Fragment` is part of. It is not known for fragments
+    /// replayed from the outbound journal recorded by older versions of the client.
+    message_id: Option<MessageId>,
 }
 
 impl PendingAcknowledgement {
     /// Creates new instance of `PendingAcknowledgement` using the provided data.
-    fn new(message_chunk: Fragment, delay: SphinxDelay, recipient: Recipient) -> Self {
+    fn new(
+        message_chunk: Fragment,
+        delay: SphinxDelay,
+        recipient: Recipient,
+        route: Vec<NymNodeRoutingAddress>,
+        message_id: Option<MessageId>,
+    ) -> Self {
         PendingAcknowledgement {
             message_chunk,
             delay,
             recipient,
+            route,
+            message_id,
         }
     }
 
-    fn update_delay(&mut self, new_delay: SphinxDelay) {
+    fn update_delay_and_route(
+        &mut self,
+        new_delay: SphinxDelay,
+        new_route: Vec<NymNodeRoutingAddress>,
+    ) {
         self.delay = new_delay;
+        self.route = new_route;
     }
 }
 
@@ -114,25 +140,35 @@ pub(super) struct Config {
     /// Given ack timeout in the form a * BASE_DELAY + b, it specifies the multiplier `a`
     ack_wait_multiplier: f64,
 
+    /// Determines when lost `Fragment`s are retransmitted and when we give up on them.
+    retransmission_policy: RetransmissionPolicy,
+
     /// Average delay an acknowledgement packet is going to get delayed at a single mixnode.
     average_ack_delay: Duration,
 
     /// Average delay a data packet is going to get delayed at a single mixnode.
     average_packet_delay: Duration,
+
+    /// Chooses the mix nodes used for the routes of all sent packets.
+    route_selector: Arc<dyn RouteSelector>,
 }
 
 impl Config {
     pub(super) fn new(
         ack_wait_addition: Duration,
         ack_wait_multiplier: f64,
+        retransmission_policy: RetransmissionPolicy,
         average_ack_delay: Duration,
         average_packet_delay: Duration,
+        route_selector: Arc<dyn RouteSelector>,
     ) -> Self {
         Config {
             ack_wait_addition,
             ack_wait_multiplier,
+            retransmission_policy,
             average_ack_delay,
             average_packet_delay,
+            route_selector,
         }
     }
 }
@@ -152,57 +188,72 @@ impl<R> AcknowledgementController<R>
 where
     R: 'static + CryptoRng + Rng + Clone + Send,
 {
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
         config: Config,
         rng: R,
         topology_access: TopologyAccessor,
-        ack_key: Arc<AckKey>,
-        ack_recipient: Recipient,
+        ack_key: RotatableKey<AckKey>,
+        ack_recipient: SelfAddressReceiver,
         reply_key_storage: ReplyKeyStorage,
+        outbound_journal: Option<OutboundJournal>,
+        packet_statistics: PacketStatistics,
         connectors: AcknowledgementControllerConnectors,
     ) -> Self {
         let (retransmission_tx, retransmission_rx) = mpsc::unbounded();
 
-        let action_config =
-            action_controller::Config::new(config.ack_wait_addition, config.ack_wait_multiplier);
-        let (action_controller, action_sender) =
-            ActionController::new(action_config, retransmission_tx);
+        let action_config = action_controller::Config::new(
+            config.ack_wait_addition,
+            config.ack_wait_multiplier,
+            config.retransmission_policy,
+        );
+        let (action_controller, action_sender) = ActionController::new(
+            action_config,
+            retransmission_tx,
+            outbound_journal.clone(),
+            packet_statistics.clone(),
+        );
 
         let message_preparer = MessagePreparer::new(
             rng,
-            ack_recipient,
+            *ack_recipient.borrow(),
             config.average_packet_delay,
             config.average_ack_delay,
-        );
+        )
+        .with_route_selector(config.route_selector);
 
         // will listen for any acks coming from the network
         let acknowledgement_listener = AcknowledgementListener::new(
-            Arc::clone(&ack_key),
+            ack_key.clone(),
             connectors.ack_receiver,
             action_sender.clone(),
+            outbound_journal.clone(),
         );
 
         // will listen for any new messages from the client
         let input_message_listener = InputMessageListener::new(
-            Arc::clone(&ack_key),
-            ack_recipient,
+            ack_key.clone(),
+            ack_recipient.clone(),
             connectors.input_receiver,
             message_preparer.clone(),
             action_sender.clone(),
             connectors.real_message_sender.clone(),
             topology_access.clone(),
             reply_key_storage,
+            outbound_journal,
+            packet_statistics.clone(),
         );
 
         // will listen for any ack timeouts and trigger retransmission
         let retransmission_request_listener = RetransmissionRequestListener::new(
-            Arc::clone(&ack_key),
+            ack_key.clone(),
             ack_recipient,
             message_preparer,
             action_sender.clone(),
             connectors.real_message_sender,
             retransmission_rx,
             topology_access,
+            packet_statistics,
         );
 
         // will listen for events indicating the packet was sent through the network so that
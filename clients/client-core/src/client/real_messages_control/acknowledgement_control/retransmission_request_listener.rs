@@ -4,14 +4,17 @@
 use super::action_controller::{Action, ActionSender};
 use super::PendingAcknowledgement;
 use super::RetransmissionRequestReceiver;
+use crate::client::gateway_failover::SelfAddressReceiver;
+use crate::client::key_rotation::RotatableKey;
+use crate::client::traffic_control::PacketStatistics;
 use crate::client::{
     real_messages_control::real_traffic_stream::{BatchRealMessageSender, RealMessage},
     topology_control::TopologyAccessor,
 };
 use futures::StreamExt;
 use log::*;
+use nymsphinx::acknowledgements::AckKey;
 use nymsphinx::preparer::MessagePreparer;
-use nymsphinx::{acknowledgements::AckKey, addressing::clients::Recipient};
 use rand::{CryptoRng, Rng};
 use std::sync::{Arc, Weak};
 
@@ -20,27 +23,30 @@ pub(super) struct RetransmissionRequestListener<R>
 where
     R: CryptoRng + Rng,
 {
-    ack_key: Arc<AckKey>,
-    ack_recipient: Recipient,
+    ack_key: RotatableKey<AckKey>,
+    ack_recipient: SelfAddressReceiver,
     message_preparer: MessagePreparer<R>,
     action_sender: ActionSender,
     real_message_sender: BatchRealMessageSender,
     request_receiver: RetransmissionRequestReceiver,
     topology_access: TopologyAccessor,
+    packet_statistics: PacketStatistics,
 }
 
 impl<R> RetransmissionRequestListener<R>
 where
     R: CryptoRng + Rng,
 {
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
-        ack_key: Arc<AckKey>,
-        ack_recipient: Recipient,
+        ack_key: RotatableKey<AckKey>,
+        ack_recipient: SelfAddressReceiver,
         message_preparer: MessagePreparer<R>,
         action_sender: ActionSender,
         real_message_sender: BatchRealMessageSender,
         request_receiver: RetransmissionRequestReceiver,
         topology_access: TopologyAccessor,
+        packet_statistics: PacketStatistics,
     ) -> Self {
         RetransmissionRequestListener {
             ack_key,
@@ -50,6 +56,7 @@ where
             real_message_sender,
             request_receiver,
             topology_access,
+            packet_statistics,
         }
     }
 
@@ -65,9 +72,13 @@ where
         let chunk_clone = timed_out_ack.message_chunk.clone();
         let frag_id = chunk_clone.fragment_identifier();
 
+        // the address might have changed if we switched to a different gateway
+        let ack_recipient = *self.ack_recipient.borrow();
+        self.message_preparer.set_sender_address(ack_recipient);
+
         let topology_permit = self.topology_access.get_read_permit().await;
         let topology_ref = match topology_permit
-            .try_get_valid_topology_ref(&self.ack_recipient, Some(packet_recipient))
+            .try_get_valid_topology_ref(&ack_recipient, Some(packet_recipient))
         {
             Some(topology_ref) => topology_ref,
             None => {
@@ -80,11 +91,33 @@ where
             }
         };
 
-        let prepared_fragment = self
+        // try to go through a different route in case some node on the previous one is faulty
+        let prepared_fragment = match self
             .message_preparer
-            .prepare_chunk_for_sending(chunk_clone, topology_ref, &self.ack_key, packet_recipient)
+            .prepare_chunk_for_resending(
+                chunk_clone,
+                topology_ref,
+                &self.ack_key.current(),
+                packet_recipient,
+                &timed_out_ack.route,
+            )
             .await
-            .unwrap();
+        {
+            Ok(prepared_fragment) => prepared_fragment,
+            Err(err) => {
+                // for example if all of the nodes on some layer are excluded from routing.
+                // Just like with the invalid topology, try again later - the retransmission
+                // policy decides when we give up on it
+                warn!(
+                    "Could not find a route for the retransmitted packet - {:?}",
+                    err
+                );
+                self.action_sender
+                    .unbounded_send(Action::new_start_timer(frag_id))
+                    .unwrap();
+                return;
+            }
+        };
 
         // if we have the ONLY strong reference to the ack data, it means it was removed from the
         // pending acks
@@ -99,6 +132,7 @@ where
         drop(timed_out_ack);
 
         let new_delay = prepared_fragment.total_delay;
+        let new_route = prepared_fragment.route;
 
         // We know this update will be reflected by the `StartTimer` Action performed when this
         // message is sent through the mix network.
@@ -108,10 +142,11 @@ where
         // with the additional poisson delay.
         // And since Actions are executed in order `UpdateTimer` will HAVE TO be executed before `StartTimer`
         self.action_sender
-            .unbounded_send(Action::new_update_delay(frag_id, new_delay))
+            .unbounded_send(Action::new_update_delay(frag_id, new_delay, new_route))
             .unwrap();
 
         // send to `OutQueueControl` to eventually send to the mix network
+        self.packet_statistics.increment_real_packets_queued(1);
         self.real_message_sender
             .unbounded_send(vec![RealMessage::new(
                 prepared_fragment.mix_packet,
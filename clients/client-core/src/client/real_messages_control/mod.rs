@@ -8,8 +8,12 @@
 use self::{
     acknowledgement_control::AcknowledgementController, real_traffic_stream::OutQueueControl,
 };
+use crate::client::gateway_failover::SelfAddressReceiver;
+use crate::client::key_rotation::RotatableKey;
+use crate::client::outbound_journal::OutboundJournal;
 use crate::client::real_messages_control::acknowledgement_control::AcknowledgementControllerConnectors;
 use crate::client::reply_key_storage::ReplyKeyStorage;
+use crate::client::traffic_control::{PacketStatistics, TrafficRatesReceiver};
 use crate::client::{
     inbound_messages::InputMessageReceiver, mix_traffic::BatchMixMessageSender,
     topology_control::TopologyAccessor,
@@ -18,19 +22,75 @@ use futures::channel::mpsc;
 use gateway_client::AcknowledgementReceiver;
 use log::*;
 use nymsphinx::acknowledgements::AckKey;
-use nymsphinx::addressing::clients::Recipient;
 use rand::{rngs::OsRng, CryptoRng, Rng};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::task::JoinHandle;
+use topology::route_selection::{RouteSelector, UniformRouteSelector};
 
 mod acknowledgement_control;
 mod real_traffic_stream;
 
+/// Determines how persistently we try to get all fragments of a message acknowledged.
+#[derive(Debug, Clone, Copy)]
+pub struct RetransmissionPolicy {
+    /// Maximum number of times a single fragment is retransmitted before the whole message
+    /// is abandoned. 0 means there is no limit.
+    pub maximum_retransmissions: u32,
+
+    /// Each subsequent retransmission of a fragment waits this many times longer for its
+    /// acknowledgement than the previous attempt.
+    pub backoff_multiplier: f64,
+
+    /// Upper bound on the time spent waiting for an acknowledgement resulting from the backoff.
+    pub maximum_backoff: Duration,
+
+    /// If specified, messages not fully acknowledged within this time since they were sent
+    /// are abandoned.
+    pub message_deadline: Option<Duration>,
+}
+
+impl RetransmissionPolicy {
+    pub fn new(
+        maximum_retransmissions: u32,
+        backoff_multiplier: f64,
+        maximum_backoff: Duration,
+        message_deadline: Option<Duration>,
+    ) -> Self {
+        RetransmissionPolicy {
+            maximum_retransmissions,
+            backoff_multiplier,
+            maximum_backoff,
+            message_deadline,
+        }
+    }
+
+    /// Determines how long to wait for an acknowledgement of a fragment that has already been
+    /// retransmitted the specified number of times. Each retransmission waits `backoff_multiplier`
+    /// times longer than the previous one, but never longer than `maximum_backoff`
+    /// (unless the base timeout already exceeds it).
+    pub(crate) fn ack_timeout(&self, base_timeout: Duration, retransmissions: u32) -> Duration {
+        if retransmissions == 0 || self.backoff_multiplier <= 1.0 {
+            return base_timeout;
+        }
+
+        let backoff_factor = self
+            .backoff_multiplier
+            .powi(retransmissions.min(i32::MAX as u32) as i32);
+        let maximum_timeout = base_timeout.max(self.maximum_backoff);
+        let timeout_secs = base_timeout.as_secs_f64() * backoff_factor;
+        if !timeout_secs.is_finite() || timeout_secs >= maximum_timeout.as_secs_f64() {
+            maximum_timeout
+        } else {
+            Duration::from_secs_f64(timeout_secs)
+        }
+    }
+}
+
 // TODO: ack_key and self_recipient shouldn't really be part of this config
 pub struct Config {
     /// Key used to decrypt contents of received SURBAcks
-    ack_key: Arc<AckKey>,
+    ack_key: RotatableKey<AckKey>,
 
     /// Given ack timeout in the form a * BASE_DELAY + b, it specifies the additive part `b`
     ack_wait_addition: Duration,
@@ -38,39 +98,50 @@ pub struct Config {
     /// Given ack timeout in the form a * BASE_DELAY + b, it specifies the multiplier `a`
     ack_wait_multiplier: f64,
 
-    /// Address of `this` client.
-    self_recipient: Recipient,
+    /// Determines when lost fragments are retransmitted and when we give up on them.
+    retransmission_policy: RetransmissionPolicy,
 
-    /// Average delay between sending subsequent packets from this client.
-    average_message_sending_delay: Duration,
+    /// Current address of `this` client.
+    self_recipient: SelfAddressReceiver,
 
     /// Average delay a data packet is going to get delayed at a single mixnode.
     average_packet_delay_duration: Duration,
 
     /// Average delay an acknowledgement packet is going to get delayed at a single mixnode.
     average_ack_delay_duration: Duration,
+
+    /// Chooses the mix nodes used for the routes of all sent packets.
+    route_selector: Arc<dyn RouteSelector>,
 }
 
 impl Config {
     pub fn new(
-        ack_key: Arc<AckKey>,
+        ack_key: RotatableKey<AckKey>,
         ack_wait_multiplier: f64,
         ack_wait_addition: Duration,
+        retransmission_policy: RetransmissionPolicy,
         average_ack_delay_duration: Duration,
-        average_message_sending_delay: Duration,
         average_packet_delay_duration: Duration,
-        self_recipient: Recipient,
+        self_recipient: SelfAddressReceiver,
     ) -> Self {
         Config {
             ack_key,
             ack_wait_addition,
             ack_wait_multiplier,
+            retransmission_policy,
             self_recipient,
-            average_message_sending_delay,
             average_packet_delay_duration,
             average_ack_delay_duration,
+            route_selector: Arc::new(UniformRouteSelector),
         }
     }
+
+    /// Makes the controller choose the mix nodes by something other than uniformly at random.
+    #[must_use]
+    pub fn with_route_selector(mut self, route_selector: Arc<dyn RouteSelector>) -> Self {
+        self.route_selector = route_selector;
+        self
+    }
 }
 
 pub struct RealMessagesController<R>
@@ -84,6 +155,7 @@ where
 // obviously when we finally make shared rng that is on 'higher' level, this should become
 // generic `R`
 impl RealMessagesController<OsRng> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: Config,
         ack_receiver: AcknowledgementReceiver,
@@ -91,6 +163,9 @@ impl RealMessagesController<OsRng> {
         mix_sender: BatchMixMessageSender,
         topology_access: TopologyAccessor,
         reply_key_storage: ReplyKeyStorage,
+        outbound_journal: Option<OutboundJournal>,
+        traffic_rates: TrafficRatesReceiver,
+        packet_statistics: PacketStatistics,
     ) -> Self {
         let rng = OsRng;
 
@@ -107,32 +182,38 @@ impl RealMessagesController<OsRng> {
         let ack_control_config = acknowledgement_control::Config::new(
             config.ack_wait_addition,
             config.ack_wait_multiplier,
+            config.retransmission_policy,
             config.average_ack_delay_duration,
             config.average_packet_delay_duration,
+            Arc::clone(&config.route_selector),
         );
 
         let ack_control = AcknowledgementController::new(
             ack_control_config,
             rng,
             topology_access.clone(),
-            Arc::clone(&config.ack_key),
-            config.self_recipient,
+            config.ack_key.clone(),
+            config.self_recipient.clone(),
             reply_key_storage,
+            outbound_journal,
+            packet_statistics.clone(),
             ack_controller_connectors,
         );
 
         let out_queue_config = real_traffic_stream::Config::new(
             config.average_ack_delay_duration,
             config.average_packet_delay_duration,
-            config.average_message_sending_delay,
+            config.route_selector,
         );
 
         let out_queue_control = OutQueueControl::new(
             out_queue_config,
-            Arc::clone(&config.ack_key),
+            config.ack_key.clone(),
             sent_notifier_tx,
             mix_sender,
             real_message_receiver,
+            traffic_rates,
+            packet_statistics,
             rng,
             config.self_recipient,
             topology_access,
@@ -176,3 +257,48 @@ impl RealMessagesController<OsRng> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+    #[test]
+    fn first_transmission_uses_the_base_timeout() {
+        let policy = RetransmissionPolicy::new(0, 2.0, Duration::from_secs(60), None);
+        assert_eq!(policy.ack_timeout(BASE_TIMEOUT, 0), BASE_TIMEOUT);
+    }
+
+    #[test]
+    fn retransmissions_back_off_exponentially() {
+        let policy = RetransmissionPolicy::new(0, 2.0, Duration::from_secs(60), None);
+        assert_eq!(policy.ack_timeout(BASE_TIMEOUT, 1), Duration::from_secs(4));
+        assert_eq!(policy.ack_timeout(BASE_TIMEOUT, 2), Duration::from_secs(8));
+        assert_eq!(policy.ack_timeout(BASE_TIMEOUT, 4), Duration::from_secs(32));
+    }
+
+    #[test]
+    fn backoff_is_capped_at_its_maximum() {
+        let policy = RetransmissionPolicy::new(0, 2.0, Duration::from_secs(60), None);
+        assert_eq!(policy.ack_timeout(BASE_TIMEOUT, 5), Duration::from_secs(60));
+        assert_eq!(
+            policy.ack_timeout(BASE_TIMEOUT, u32::MAX),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn base_timeout_is_used_if_it_exceeds_the_maximum_backoff() {
+        let policy = RetransmissionPolicy::new(0, 2.0, Duration::from_secs(1), None);
+        assert_eq!(policy.ack_timeout(BASE_TIMEOUT, 3), BASE_TIMEOUT);
+    }
+
+    #[test]
+    fn backoff_can_be_disabled() {
+        for multiplier in [1.0, 0.5] {
+            let policy = RetransmissionPolicy::new(0, multiplier, Duration::from_secs(60), None);
+            assert_eq!(policy.ack_timeout(BASE_TIMEOUT, 10), BASE_TIMEOUT);
+        }
+    }
+}
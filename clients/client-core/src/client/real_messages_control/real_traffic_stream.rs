@@ -1,15 +1,17 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::client::gateway_failover::SelfAddressReceiver;
+use crate::client::key_rotation::RotatableKey;
 use crate::client::mix_traffic::BatchMixMessageSender;
 use crate::client::real_messages_control::acknowledgement_control::SentPacketNotificationSender;
 use crate::client::topology_control::TopologyAccessor;
+use crate::client::traffic_control::{PacketStatistics, TrafficRatesReceiver};
 use futures::channel::mpsc;
 use futures::task::{Context, Poll};
 use futures::{Future, Stream, StreamExt};
 use log::*;
 use nymsphinx::acknowledgements::AckKey;
-use nymsphinx::addressing::clients::Recipient;
 use nymsphinx::chunking::fragment::FragmentIdentifier;
 use nymsphinx::cover::generate_loop_cover_packet;
 use nymsphinx::forwarding::packet::MixPacket;
@@ -20,6 +22,7 @@ use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::time;
+use topology::route_selection::RouteSelector;
 
 /// Configurable parameters of the `OutQueueControl`
 pub(crate) struct Config {
@@ -29,20 +32,20 @@ pub(crate) struct Config {
     /// Average delay a data packet is going to get delay at a single mixnode.
     average_packet_delay: Duration,
 
-    /// Average delay between sending subsequent packets.
-    average_message_sending_delay: Duration,
+    /// Chooses the mix nodes used for the routes of the cover packets.
+    route_selector: Arc<dyn RouteSelector>,
 }
 
 impl Config {
     pub(crate) fn new(
         average_ack_delay: Duration,
         average_packet_delay: Duration,
-        average_message_sending_delay: Duration,
+        route_selector: Arc<dyn RouteSelector>,
     ) -> Self {
         Config {
             average_ack_delay,
             average_packet_delay,
-            average_message_sending_delay,
+            route_selector,
         }
     }
 }
@@ -55,11 +58,20 @@ where
     config: Config,
 
     /// Key used to encrypt and decrypt content of an ACK packet.
-    ack_key: Arc<AckKey>,
+    ack_key: RotatableKey<AckKey>,
 
     /// Channel used for notifying of a real packet being sent out. Used to start up retransmission timer.
     sent_notifier: SentPacketNotificationSender,
 
+    /// Average delay between sending subsequent packets.
+    average_message_sending_delay: Duration,
+
+    /// Channel used for receiving updates to the rate at which packets are sent.
+    rates_receiver: TrafficRatesReceiver,
+
+    /// Counters of sent packets shared with the rest of the client.
+    packet_statistics: PacketStatistics,
+
     /// Internal state, determined by `average_message_sending_delay`,
     /// used to keep track of when a next packet should be sent out.
     next_delay: Pin<Box<time::Sleep>>,
@@ -72,8 +84,8 @@ where
     /// before being sent out into the network.
     real_receiver: BatchRealMessageReceiver,
 
-    /// Represents full address of this client.
-    our_full_destination: Recipient,
+    /// Represents current full address of this client.
+    our_full_destination: SelfAddressReceiver,
 
     /// Instance of a cryptographically secure random number generator.
     rng: R,
@@ -123,7 +135,7 @@ where
 
         // we know it's time to send a message, so let's prepare delay for the next one
         // Get the `now` by looking at the current `delay` deadline
-        let avg_delay = self.config.average_message_sending_delay;
+        let avg_delay = self.average_message_sending_delay;
         let now = self.next_delay.deadline();
         let next_poisson_delay = sample_poisson_duration(&mut self.rng, avg_delay);
 
@@ -167,18 +179,25 @@ where
     #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         config: Config,
-        ack_key: Arc<AckKey>,
+        ack_key: RotatableKey<AckKey>,
         sent_notifier: SentPacketNotificationSender,
         mix_tx: BatchMixMessageSender,
         real_receiver: BatchRealMessageReceiver,
+        rates_receiver: TrafficRatesReceiver,
+        packet_statistics: PacketStatistics,
         rng: R,
-        our_full_destination: Recipient,
+        our_full_destination: SelfAddressReceiver,
         topology_access: TopologyAccessor,
     ) -> Self {
+        let average_message_sending_delay = rates_receiver.borrow().message_sending_average_delay;
+
         OutQueueControl {
             config,
             ack_key,
             sent_notifier,
+            average_message_sending_delay,
+            rates_receiver,
+            packet_statistics,
             next_delay: Box::pin(time::sleep(Default::default())),
             mix_tx,
             real_receiver,
@@ -205,12 +224,11 @@ where
                 // TODO for way down the line: in very rare cases (during topology update) we might have
                 // to wait a really tiny bit before actually obtaining the permit hence messing with our
                 // poisson delay, but is it really a problem?
+                let our_full_destination = *self.our_full_destination.borrow();
                 let topology_permit = self.topology_access.get_read_permit().await;
                 // the ack is sent back to ourselves (and then ignored)
-                let topology_ref_option = topology_permit.try_get_valid_topology_ref(
-                    &self.our_full_destination,
-                    Some(&self.our_full_destination),
-                );
+                let topology_ref_option = topology_permit
+                    .try_get_valid_topology_ref(&our_full_destination, Some(&our_full_destination));
                 if topology_ref_option.is_none() {
                     warn!(
                         "No valid topology detected - won't send any loop cover message this time"
@@ -219,18 +237,30 @@ where
                 }
                 let topology_ref = topology_ref_option.unwrap();
 
-                generate_loop_cover_packet(
+                // this can only fail if the route selector has excluded all nodes on some layer
+                let cover_packet = match generate_loop_cover_packet(
                     &mut self.rng,
                     topology_ref,
-                    &*self.ack_key,
-                    &self.our_full_destination,
+                    &*self.ack_key.current(),
+                    &our_full_destination,
                     self.config.average_ack_delay,
                     self.config.average_packet_delay,
-                )
-                .expect("Somehow failed to generate a loop cover message with a valid topology")
+                    &*self.config.route_selector,
+                ) {
+                    Ok(cover_packet) => cover_packet,
+                    Err(err) => {
+                        warn!("Failed to generate a loop cover message - {:?}", err);
+                        return;
+                    }
+                };
+
+                self.packet_statistics
+                    .increment_out_queue_cover_packets_sent();
+                cover_packet
             }
             StreamMessage::Real(real_message) => {
                 self.sent_notify(real_message.fragment_id);
+                self.packet_statistics.increment_real_packets_sent();
                 real_message.mix_packet
             }
         };
@@ -249,16 +279,36 @@ where
         tokio::task::yield_now().await;
     }
 
+    fn on_rates_change(&mut self) {
+        let new_delay = self.rates_receiver.borrow().message_sending_average_delay;
+        debug!(
+            "Changing the average delay between sent messages from {:?} to {:?}",
+            self.average_message_sending_delay, new_delay
+        );
+        self.average_message_sending_delay = new_delay;
+
+        // don't wait for the message scheduled with the old rate
+        let next = time::Instant::now() + sample_poisson_duration(&mut self.rng, new_delay);
+        self.next_delay.as_mut().reset(next);
+    }
+
     // Send messages at certain rate and if no real traffic is available, send cover message.
     async fn run_normal_out_queue(&mut self) {
         // we should set initial delay only when we actually start the stream
         self.next_delay = Box::pin(time::sleep(sample_poisson_duration(
             &mut self.rng,
-            self.config.average_message_sending_delay,
+            self.average_message_sending_delay,
         )));
 
-        while let Some(next_message) = self.next().await {
-            self.on_message(next_message).await;
+        let mut rates_receiver = self.rates_receiver.clone();
+        loop {
+            tokio::select! {
+                next_message = self.next() => match next_message {
+                    Some(next_message) => self.on_message(next_message).await,
+                    None => break,
+                },
+                Ok(_) = rates_receiver.changed() => self.on_rates_change(),
+            }
         }
     }
 
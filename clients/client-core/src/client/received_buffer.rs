@@ -1,19 +1,21 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::client::key_rotation::RotatableKey;
 use crate::client::reply_key_storage::ReplyKeyStorage;
 use crypto::asymmetric::encryption;
 use crypto::symmetric::stream_cipher;
 use crypto::Digest;
-use futures::channel::mpsc;
+use futures::channel::{mpsc, oneshot};
 use futures::lock::Mutex;
 use futures::StreamExt;
 use gateway_client::MixnetMessageReceiver;
 use log::*;
 use nymsphinx::anonymous_replies::{encryption_key::EncryptionKeyDigest, SurbEncryptionKey};
+use nymsphinx::chunking::fragment::Fragment;
 use nymsphinx::params::{ReplySurbEncryptionAlgorithm, ReplySurbKeyDigestAlgorithm};
 use nymsphinx::receiver::{MessageReceiver, MessageRecoveryError, ReconstructedMessage};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use tokio::task::JoinHandle;
 
@@ -26,9 +28,18 @@ pub type ReceivedBufferRequestReceiver = mpsc::UnboundedReceiver<ReceivedBufferM
 pub type ReconstructedMessagesSender = mpsc::UnboundedSender<Vec<ReconstructedMessage>>;
 pub type ReconstructedMessagesReceiver = mpsc::UnboundedReceiver<Vec<ReconstructedMessage>>;
 
+// Channel onto which the decrypted replies sent using particular reply SURBs are pushed
+pub type ReplyRouteSender = mpsc::UnboundedSender<Vec<u8>>;
+pub type ReplyRouteReceiver = mpsc::UnboundedReceiver<Vec<u8>>;
+
+// Channel onto which the key digests of the reply SURBs attached to a message are pushed
+// once the message is prepared
+pub type ReplyKeyDigestsSender = oneshot::Sender<Vec<EncryptionKeyDigest>>;
+pub type ReplyKeyDigestsReceiver = oneshot::Receiver<Vec<EncryptionKeyDigest>>;
+
 struct ReceivedMessagesBufferInner {
     messages: Vec<ReconstructedMessage>,
-    local_encryption_keypair: Arc<encryption::KeyPair>,
+    local_encryption_keypair: RotatableKey<encryption::KeyPair>,
 
     // TODO: looking how it 'looks' here, perhaps `MessageReceiver` should be renamed to something
     // else instead.
@@ -39,32 +50,107 @@ struct ReceivedMessagesBufferInner {
     // but perhaps it should be changed to include timestamps of when the message was reconstructed
     // and every now and then remove ids older than X
     recently_reconstructed: HashSet<i32>,
+
+    /// Replies sent using the reply SURBs with those key digests are pushed onto the associated
+    /// channels rather than being treated like any other received message.
+    reply_routes: HashMap<EncryptionKeyDigest, ReplyRouteSender>,
+
+    /// Routes registered for messages that might not have been prepared yet,
+    /// i.e. the key digests of their reply SURBs are not known yet.
+    pending_reply_routes: Vec<(ReplyKeyDigestsReceiver, ReplyRouteSender)>,
 }
 
 impl ReceivedMessagesBufferInner {
-    fn process_received_fragment(&mut self, raw_fragment: Vec<u8>) -> Option<ReconstructedMessage> {
-        let fragment_data = match self
-            .message_receiver
-            .recover_plaintext(self.local_encryption_keypair.private_key(), raw_fragment)
-        {
-            Err(e) => {
-                warn!("failed to recover fragment data: {:?}. The whole underlying message might be corrupted and unrecoverable!", e);
-                return None;
+    // The digests are always sent before the message itself, so by the time any reply
+    // could have arrived, its route is going to be resolved.
+    fn resolve_pending_reply_routes(&mut self) {
+        // get rid of the routes whose receivers have gone away, for example because they
+        // stopped waiting for the replies that were never going to arrive
+        self.reply_routes
+            .retain(|_, route_sender| !route_sender.is_closed());
+
+        let mut still_pending = Vec::new();
+        for (mut digests_receiver, route_sender) in self.pending_reply_routes.drain(..) {
+            if route_sender.is_closed() {
+                continue;
             }
-            Ok(frag_data) => frag_data,
-        };
+            match digests_receiver.try_recv() {
+                Ok(Some(key_digests)) => {
+                    for key_digest in key_digests {
+                        self.reply_routes.insert(key_digest, route_sender.clone());
+                    }
+                }
+                Ok(None) => still_pending.push((digests_receiver, route_sender)),
+                // the message could not be prepared, so dropping the route lets
+                // the receiver know there's nothing to wait for
+                Err(_) => debug!("The message with the reply route was never sent"),
+            }
+        }
+        self.pending_reply_routes = still_pending;
+    }
 
-        if nymsphinx::cover::is_cover(&fragment_data) {
-            trace!("The message was a loop cover message! Skipping it");
-            return None;
+    // The fragment might have been encrypted for the key replaced during the latest rotation.
+    // Since the plaintext is not authenticated, the key is only considered to be the correct one
+    // if it produced either a cover message or a well-formed fragment. If multiple keys produced
+    // a well-formed fragment, the one belonging to a message we've already received some fragments
+    // of is preferred, otherwise the keys are tried starting with the current one.
+    // Returns `None` for cover messages.
+    fn recover_fragment(
+        &self,
+        raw_fragment: Vec<u8>,
+    ) -> Result<Option<Fragment>, MessageRecoveryError> {
+        let mut first_error = None;
+        let mut first_fragment = None;
+        for key in self.local_encryption_keypair.valid_keys() {
+            let recovered = self
+                .message_receiver
+                .recover_plaintext(key.private_key(), raw_fragment.clone())
+                .and_then(|data| {
+                    if nymsphinx::cover::is_cover(&data) {
+                        Ok(None)
+                    } else {
+                        self.message_receiver.recover_fragment(&data).map(Some)
+                    }
+                });
+
+            match recovered {
+                Ok(None) => return Ok(None),
+                Ok(Some(fragment)) if self.is_known_set(fragment.id()) => {
+                    return Ok(Some(fragment))
+                }
+                Ok(Some(fragment)) => {
+                    first_fragment.get_or_insert(fragment);
+                }
+                Err(err) => {
+                    first_error.get_or_insert(err);
+                }
+            }
         }
 
-        let fragment = match self.message_receiver.recover_fragment(&fragment_data) {
+        match (first_fragment, first_error) {
+            (Some(fragment), _) => Ok(Some(fragment)),
+            (None, Some(err)) => Err(err),
+            // there's always at least the current key
+            (None, None) => unreachable!("there were no keys to recover the fragment with"),
+        }
+    }
+
+    fn is_known_set(&self, set_id: i32) -> bool {
+        self.message_receiver.is_set_being_reconstructed(set_id)
+            || self.recently_reconstructed.contains(&set_id)
+    }
+
+    fn process_received_fragment(&mut self, raw_fragment: Vec<u8>) -> Option<ReconstructedMessage> {
+        let fragment = match self.recover_fragment(raw_fragment) {
             Err(e) => {
-                warn!("failed to recover fragment from raw data: {:?}. The whole underlying message might be corrupted and unrecoverable!", e);
+                warn!("failed to recover fragment: {:?}. The whole underlying message might be corrupted and unrecoverable!", e);
+                return None;
+            }
+            Ok(None) => {
+                trace!("The message was a loop cover message! Skipping it");
                 return None;
             }
-            Ok(frag) => frag,
+            Ok(Some(frag)) => frag,
         };
 
         if self.recently_reconstructed.contains(&fragment.id()) {
@@ -111,14 +197,14 @@ impl ReceivedMessagesBufferInner {
 struct ReceivedMessagesBuffer {
     inner: Arc<Mutex<ReceivedMessagesBufferInner>>,
 
-    /// Storage containing keys to all [`ReplySURB`]s ever sent out that we did not receive back.
+    /// Storage containing keys to all non-expired [`ReplySURB`]s sent out that we did not receive back.
     // There's no need to put it behind a Mutex since it's already properly concurrent
     reply_key_storage: ReplyKeyStorage,
 }
 
 impl ReceivedMessagesBuffer {
     fn new(
-        local_encryption_keypair: Arc<encryption::KeyPair>,
+        local_encryption_keypair: RotatableKey<encryption::KeyPair>,
         reply_key_storage: ReplyKeyStorage,
     ) -> Self {
         ReceivedMessagesBuffer {
@@ -128,6 +214,8 @@ impl ReceivedMessagesBuffer {
                 message_receiver: MessageReceiver::new(),
                 message_sender: None,
                 recently_reconstructed: HashSet::new(),
+                reply_routes: HashMap::new(),
+                pending_reply_routes: Vec::new(),
             })),
             reply_key_storage,
         }
@@ -171,6 +259,18 @@ impl ReceivedMessagesBuffer {
         guard.message_sender = Some(sender);
     }
 
+    async fn add_reply_route(
+        &mut self,
+        key_digests_receiver: ReplyKeyDigestsReceiver,
+        route_sender: ReplyRouteSender,
+    ) {
+        let mut guard = self.inner.lock().await;
+        guard
+            .pending_reply_routes
+            .push((key_digests_receiver, route_sender));
+        guard.resolve_pending_reply_routes();
+    }
+
     async fn add_reconstructed_messages(&mut self, msgs: Vec<ReconstructedMessage>) {
         debug!("Adding {:?} new messages to the buffer!", msgs.len());
         trace!("Adding new messages to the buffer! {:?}", msgs);
@@ -195,7 +295,8 @@ impl ReceivedMessagesBuffer {
             // TODO: perhaps having to say it doesn't have a surb an indication the type should be changed?
             Some(ReconstructedMessage {
                 message: reply_msg,
-                reply_surb: None,
+                reply_surbs: Vec::new(),
+                headers: None,
             })
         }
     }
@@ -208,6 +309,7 @@ impl ReceivedMessagesBuffer {
 
         let mut completed_messages = Vec::new();
         let mut inner_guard = self.inner.lock().await;
+        inner_guard.resolve_pending_reply_routes();
 
         let reply_surb_digest_size = ReplySurbKeyDigestAlgorithm::output_size();
 
@@ -224,16 +326,39 @@ impl ReceivedMessagesBuffer {
 
             // TODO: this might be a bottleneck - since the keys are stored on disk we, presumably,
             // are doing a disk operation every single received fragment
-            if let Some(reply_encryption_key) = self
+            let reply_encryption_key = match self
                 .reply_key_storage
                 .get_and_remove_encryption_key(possible_key_digest)
-                .expect("storage operation failed!")
+                .await
             {
+                Ok(reply_encryption_key) => reply_encryption_key,
+                Err(err) => {
+                    // treat it as a 'normal' message instead
+                    error!("Failed to query the reply key storage - {}", err);
+                    None
+                }
+            };
+
+            if let Some(reply_encryption_key) = reply_encryption_key {
                 if let Some(completed_message) = Self::process_received_reply(
                     &msg[reply_surb_digest_size..],
                     reply_encryption_key,
                 ) {
-                    completed_messages.push(completed_message)
+                    match inner_guard.reply_routes.remove(&possible_key_digest) {
+                        Some(route_sender) => {
+                            if let Err(err) = route_sender.unbounded_send(completed_message.message)
+                            {
+                                // nobody is waiting for it anymore, so treat it as a normal message
+                                debug!("The reply receiver has gone away - passing the reply on");
+                                completed_messages.push(ReconstructedMessage {
+                                    message: err.into_inner(),
+                                    reply_surbs: Vec::new(),
+                                    headers: None,
+                                })
+                            }
+                        }
+                        None => completed_messages.push(completed_message),
+                    }
                 }
             } else {
                 // otherwise - it's a 'normal' message
@@ -272,6 +397,10 @@ pub enum ReceivedBufferMessage {
 
     // Explicit signal that Receiver connection will no longer accept messages
     ReceiverDisconnect,
+
+    // Replies sent using the reply SURBs with the key digests sent on the provided oneshot channel
+    // should be sent to the provided channel rather than being treated like any other received message
+    ReplyRoute(ReplyKeyDigestsReceiver, ReplyRouteSender),
 }
 
 struct RequestReceiver {
@@ -300,6 +429,11 @@ impl RequestReceiver {
                     ReceivedBufferMessage::ReceiverDisconnect => {
                         self.received_buffer.disconnect_sender().await
                     }
+                    ReceivedBufferMessage::ReplyRoute(key_digests_receiver, route_sender) => {
+                        self.received_buffer
+                            .add_reply_route(key_digests_receiver, route_sender)
+                            .await
+                    }
                 }
             }
         })
@@ -337,7 +471,7 @@ pub struct ReceivedMessagesBufferController {
 
 impl ReceivedMessagesBufferController {
     pub fn new(
-        local_encryption_keypair: Arc<encryption::KeyPair>,
+        local_encryption_keypair: RotatableKey<encryption::KeyPair>,
         query_receiver: ReceivedBufferRequestReceiver,
         mixnet_packet_receiver: MixnetMessageReceiver,
         reply_key_storage: ReplyKeyStorage,
@@ -1,94 +1,698 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::client::current_timestamp;
 use crypto::generic_array::typenum::Unsigned;
 use log::*;
 use nymsphinx::anonymous_replies::{
     encryption_key::EncryptionKeyDigest, SurbEncryptionKey, SurbEncryptionKeySize,
 };
-use std::path::Path;
+use sqlx::ConnectOptions;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 
-#[derive(Debug)]
+/// Maximum number of pending changes kept in memory before they are written to the disk,
+/// regardless of the configured flush interval.
+const MAX_PENDING_CHANGES: usize = 256;
+
+/// Suffix appended to the path of the old sled-based store while its keys are being imported.
+const LEGACY_STORE_SUFFIX: &str = ".sled-legacy";
+
+#[derive(Debug, Error)]
 pub enum ReplyKeyStorageError {
-    DbReadError(sled::Error),
-    DbWriteError(sled::Error),
-    DbOpenError(sled::Error),
+    #[error("Database experienced an internal error - {0}")]
+    InternalDatabaseError(#[from] sqlx::Error),
+
+    #[error("Failed to perform database migration - {0}")]
+    MigrationError(#[from] sqlx::migrate::MigrateError),
+
+    #[error("Failed to read the legacy sled reply key store - {0}")]
+    LegacyStoreError(#[from] sled::Error),
+
+    #[error("Failed to move the legacy reply key store - {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("Reply key with the same digest already exists in the store")]
+    DuplicateKey,
+
+    #[error("Stored reply key is malformed")]
+    MalformedKey,
+}
+
+#[derive(Debug, Clone)]
+struct StoredReplyKey {
+    key: SurbEncryptionKey,
+
+    /// Unix timestamp (in seconds) of when the key was inserted into the store.
+    created_at: i64,
+}
+
+/// Changes that have not yet been written to the underlying database.
+#[derive(Debug, Default, Clone)]
+struct PendingChanges {
+    inserted: HashMap<EncryptionKeyDigest, StoredReplyKey>,
+    removed: HashSet<EncryptionKeyDigest>,
+}
+
+impl PendingChanges {
+    fn len(&self) -> usize {
+        self.inserted.len() + self.removed.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inserted.is_empty() && self.removed.is_empty()
+    }
 }
 
-/// Permanent storage for keys in all sent [`ReplySURB`]
+fn legacy_store_path(path: &Path) -> PathBuf {
+    let mut legacy_path = OsString::from(path.as_os_str());
+    legacy_path.push(LEGACY_STORE_SUFFIX);
+    legacy_path.into()
+}
+
+/// Persistent storage for keys in all sent [`ReplySURB`]
 ///
 /// Each sent out [`ReplySURB`] has a new key associated with it that is going to be used for
 /// payload encryption. In order to -decrypt whatever reply we receive, we need to know which
 /// key to use for that purpose. We do it based on received `H(t)` which has to be included
 /// with each reply.
-/// Moreover, there is no restriction when the [`ReplySURB`] might get used so we need to
-/// have a permanent storage for all the keys that we might ever see in the future.
+///
+/// Keys are only kept for the configured time-to-live, after which any reply using them
+/// is going to be treated as an ordinary message. Insertions and removals are buffered
+/// in memory and written to the disk in batches, either periodically by the
+/// [`ReplyKeyStorageController`] or once enough of them have accumulated.
+// note that clone here is fine as upon cloning the same underlying pool will be used
 #[derive(Debug, Clone)]
 pub struct ReplyKeyStorage {
-    db: sled::Db,
+    connection_pool: sqlx::SqlitePool,
+    pending: Arc<std::sync::Mutex<PendingChanges>>,
+    flush_lock: Arc<Mutex<()>>,
+    key_ttl: Duration,
 }
 
 impl ReplyKeyStorage {
-    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ReplyKeyStorageError> {
-        let db = match sled::open(path) {
-            Err(e) => return Err(ReplyKeyStorageError::DbOpenError(e)),
+    /// Loads the reply key storage located at the specified path.
+    ///
+    /// If the path points to a reply key store created by an older version of the client,
+    /// all of its keys are imported and the old store is removed afterwards.
+    pub async fn load<P: AsRef<Path>>(
+        path: P,
+        key_ttl: Duration,
+    ) -> Result<Self, ReplyKeyStorageError> {
+        let path = path.as_ref();
+        let legacy_path = legacy_store_path(path);
+
+        // the old sled-based store used a directory at the very same path
+        if path.is_dir() {
+            info!(
+                "Found legacy reply key store at {:?}. It is going to be migrated",
+                path
+            );
+            std::fs::rename(path, &legacy_path)?;
+        }
+
+        debug!("Attempting to connect to database {:?}", path.as_os_str());
+
+        let mut opts = sqlx::sqlite::SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+
+        opts.disable_statement_logging();
+
+        let connection_pool = match sqlx::SqlitePool::connect_with(opts).await {
             Ok(db) => db,
+            Err(err) => {
+                error!("Failed to connect to SQLx database: {}", err);
+                return Err(err.into());
+            }
+        };
+
+        if let Err(err) = sqlx::migrate!("./migrations/reply_key_storage")
+            .run(&connection_pool)
+            .await
+        {
+            error!("Failed to perform migration on the SQLx database: {}", err);
+            return Err(err.into());
+        }
+
+        let storage = ReplyKeyStorage {
+            connection_pool,
+            pending: Arc::new(std::sync::Mutex::new(Default::default())),
+            flush_lock: Arc::new(Mutex::new(())),
+            key_ttl,
         };
 
-        Ok(ReplyKeyStorage { db })
+        // this will also pick up an import that got interrupted half-way through
+        if legacy_path.is_dir() {
+            storage.import_legacy_store(&legacy_path).await?;
+            std::fs::remove_dir_all(&legacy_path)?;
+        }
+
+        Ok(storage)
     }
 
-    fn read_encryption_key(&self, raw_key: sled::IVec) -> SurbEncryptionKey {
-        let key_bytes_ref = raw_key.as_ref();
-        // if this fails it means we have some database corruption and we
-        // absolutely can't continue
+    async fn import_legacy_store(&self, legacy_path: &Path) -> Result<(), ReplyKeyStorageError> {
+        let legacy_keys = {
+            let legacy_db = sled::open(legacy_path)?;
+            let mut keys = Vec::with_capacity(legacy_db.len());
+            for entry in legacy_db.iter() {
+                let (digest, key) = entry?;
+                if key.len() != SurbEncryptionKeySize::USIZE {
+                    warn!("Skipping malformed reply key found in the legacy store");
+                    continue;
+                }
+                keys.push((digest.to_vec(), key.to_vec()));
+            }
+            keys
+        };
+
+        // we have no idea when the keys were actually created, so treat them as brand new
+        // so that they would only get purged after a full ttl
+        let created_at = current_timestamp();
 
-        if key_bytes_ref.len() != SurbEncryptionKeySize::USIZE {
-            error!("REPLY KEY STORAGE DATA CORRUPTION - ENCRYPTION KEY HAS INVALID LENGTH");
-            panic!("REPLY KEY STORAGE DATA CORRUPTION - ENCRYPTION KEY HAS INVALID LENGTH");
+        let mut tx = self.connection_pool.begin().await?;
+        for (digest, key) in &legacy_keys {
+            sqlx::query!(
+                "INSERT OR IGNORE INTO reply_key(key_digest, reply_key, created_at) VALUES (?, ?, ?)",
+                digest,
+                key,
+                created_at
+            )
+            .execute(&mut tx)
+            .await?;
         }
+        tx.commit().await?;
+
+        info!(
+            "Imported {} reply keys from the legacy store",
+            legacy_keys.len()
+        );
+        Ok(())
+    }
+
+    fn is_expired(&self, stored_key: &StoredReplyKey, now: i64) -> bool {
+        now.saturating_sub(stored_key.created_at) > self.key_ttl.as_secs() as i64
+    }
+
+    async fn is_key_stored(
+        &self,
+        key_digest: &EncryptionKeyDigest,
+    ) -> Result<bool, ReplyKeyStorageError> {
+        let digest_bytes = key_digest.to_vec();
+        let stored = sqlx::query!(
+            "SELECT key_digest FROM reply_key WHERE key_digest = ?",
+            digest_bytes
+        )
+        .fetch_optional(&self.connection_pool)
+        .await?;
+
+        Ok(stored.is_some())
+    }
 
-        // this can only fail if the bytes have invalid length but we already asserted it
-        SurbEncryptionKey::try_from_bytes(key_bytes_ref).unwrap()
+    /// Checks whether the key is either pending to be written or already stored on the disk.
+    /// Note that the database is queried without holding the lock on the pending changes.
+    async fn is_duplicate(
+        &self,
+        digest: &EncryptionKeyDigest,
+    ) -> Result<bool, ReplyKeyStorageError> {
+        {
+            let pending = self
+                .pending
+                .lock()
+                .expect("reply key storage lock got poisoned");
+            if pending.inserted.contains_key(digest) {
+                return Ok(true);
+            }
+            if pending.removed.contains(digest) {
+                return Ok(false);
+            }
+        }
+        self.is_key_stored(digest).await
     }
 
     // TOOD: perhaps we could also store some part of original message here too?
-    pub fn insert_encryption_key(
-        &mut self,
+    pub async fn insert_encryption_key(
+        &self,
         encryption_key: SurbEncryptionKey,
     ) -> Result<(), ReplyKeyStorageError> {
         let digest = encryption_key.compute_digest();
+        if self.is_duplicate(&digest).await? {
+            return Err(ReplyKeyStorageError::DuplicateKey);
+        }
 
-        let insertion_result = match self.db.insert(digest, encryption_key.to_bytes()) {
-            Err(e) => Err(ReplyKeyStorageError::DbWriteError(e)),
-            Ok(existing_key) => {
-                if existing_key.is_some() {
-                    panic!("HASH COLLISION DETECTED")
-                };
-                Ok(())
+        let should_flush = {
+            let mut pending = self
+                .pending
+                .lock()
+                .expect("reply key storage lock got poisoned");
+            // somebody might have inserted the very same key while we were querying the database
+            if pending.inserted.contains_key(&digest) {
+                return Err(ReplyKeyStorageError::DuplicateKey);
             }
+            pending.inserted.insert(
+                digest,
+                StoredReplyKey {
+                    key: encryption_key,
+                    created_at: current_timestamp(),
+                },
+            );
+            pending.len() >= MAX_PENDING_CHANGES
         };
 
-        // TODO: perhaps we could implement some batching mechanism to avoid frequent flushes?
-        self.db.flush().unwrap();
-        insertion_result
+        if should_flush {
+            self.flush_if_oversized().await;
+        }
+        Ok(())
     }
 
     // Once we use key once, we do not expect to use it again
-    pub fn get_and_remove_encryption_key(
+    pub async fn get_and_remove_encryption_key(
         &self,
         key_digest: EncryptionKeyDigest,
     ) -> Result<Option<SurbEncryptionKey>, ReplyKeyStorageError> {
-        let removal_result = match self.db.remove(key_digest) {
-            Err(e) => Err(ReplyKeyStorageError::DbReadError(e)),
-            Ok(existing_key) => {
-                Ok(existing_key.map(|existing_key| self.read_encryption_key(existing_key)))
+        let now = current_timestamp();
+
+        {
+            let mut pending = self
+                .pending
+                .lock()
+                .expect("reply key storage lock got poisoned");
+
+            // the key might have not even been written to the disk yet
+            if let Some(stored_key) = pending.inserted.remove(&key_digest) {
+                // but it could also be in the middle of getting written,
+                // so make sure it's going to get removed from the disk
+                pending.removed.insert(key_digest);
+                if self.is_expired(&stored_key, now) {
+                    debug!("Received reply to an already expired reply key");
+                    return Ok(None);
+                }
+                return Ok(Some(stored_key.key));
+            }
+
+            if pending.removed.contains(&key_digest) {
+                return Ok(None);
+            }
+        }
+
+        let digest_bytes = key_digest.to_vec();
+        let stored = sqlx::query!(
+            "SELECT reply_key, created_at FROM reply_key WHERE key_digest = ?",
+            digest_bytes
+        )
+        .fetch_optional(&self.connection_pool)
+        .await?;
+
+        let stored = match stored {
+            Some(stored) => stored,
+            None => return Ok(None),
+        };
+
+        let should_flush = {
+            let mut pending = self
+                .pending
+                .lock()
+                .expect("reply key storage lock got poisoned");
+            // make sure the same key is not handed out twice if it was requested concurrently
+            if !pending.removed.insert(key_digest) {
+                return Ok(None);
             }
+            pending.len() >= MAX_PENDING_CHANGES
         };
 
-        // TODO: not sure how to feel about flushing it every single time here...
-        // same with insertion
-        self.db.flush().unwrap();
-        removal_result
+        if should_flush {
+            self.flush_if_oversized().await;
+        }
+
+        let stored_key = StoredReplyKey {
+            key: SurbEncryptionKey::try_from_bytes(&stored.reply_key)
+                .map_err(|_| ReplyKeyStorageError::MalformedKey)?,
+            created_at: stored.created_at,
+        };
+
+        if self.is_expired(&stored_key, now) {
+            debug!("Received reply to an already expired reply key");
+            return Ok(None);
+        }
+        Ok(Some(stored_key.key))
+    }
+
+    // the failure is not propagated to the caller as its own change has been recorded either way
+    // and is going to be written with the next successful flush
+    async fn flush_if_oversized(&self) {
+        if let Err(err) = self.flush().await {
+            warn!(
+                "Failed to flush the reply key storage - {}. The changes are going to be retried later",
+                err
+            );
+        }
+    }
+
+    async fn write_changes(&self, changes: &PendingChanges) -> Result<(), ReplyKeyStorageError> {
+        let mut tx = self.connection_pool.begin().await?;
+        for digest in &changes.removed {
+            let digest_bytes = digest.to_vec();
+            sqlx::query!("DELETE FROM reply_key WHERE key_digest = ?", digest_bytes)
+                .execute(&mut tx)
+                .await?;
+        }
+        for (digest, stored_key) in &changes.inserted {
+            let digest_bytes = digest.to_vec();
+            let key_bytes = stored_key.key.to_bytes();
+            sqlx::query!(
+                "INSERT OR IGNORE INTO reply_key(key_digest, reply_key, created_at) VALUES (?, ?, ?)",
+                digest_bytes,
+                key_bytes,
+                stored_key.created_at
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Writes all pending changes to the disk.
+    ///
+    /// The changes are only cleared from memory once they are safely on the disk,
+    /// otherwise they will be retried with the next flush.
+    pub async fn flush(&self) -> Result<(), ReplyKeyStorageError> {
+        // only a single flush can be in progress, but it does not block any other operation
+        let _flush_guard = self.flush_lock.lock().await;
+
+        let snapshot = {
+            let pending = self
+                .pending
+                .lock()
+                .expect("reply key storage lock got poisoned");
+            if pending.is_empty() {
+                return Ok(());
+            }
+            pending.clone()
+        };
+
+        self.write_changes(&snapshot).await?;
+
+        // anything that has changed in the meantime is going to be written with the next flush
+        let mut pending = self
+            .pending
+            .lock()
+            .expect("reply key storage lock got poisoned");
+        for digest in snapshot.inserted.keys() {
+            pending.inserted.remove(digest);
+        }
+        for digest in &snapshot.removed {
+            pending.removed.remove(digest);
+        }
+        Ok(())
+    }
+
+    /// Removes all keys that have outlived the configured time-to-live and returns
+    /// the number of keys purged from the disk.
+    pub async fn remove_expired_keys(&self) -> Result<u64, ReplyKeyStorageError> {
+        let now = current_timestamp();
+        let cutoff = now.saturating_sub(self.key_ttl.as_secs() as i64);
+
+        self.pending
+            .lock()
+            .expect("reply key storage lock got poisoned")
+            .inserted
+            .retain(|_, stored_key| stored_key.created_at >= cutoff);
+
+        let removed = sqlx::query!("DELETE FROM reply_key WHERE created_at < ?", cutoff)
+            .execute(&self.connection_pool)
+            .await?
+            .rows_affected();
+
+        Ok(removed)
+    }
+}
+
+/// Periodically writes pending changes of the [`ReplyKeyStorage`] to the disk
+/// and purges all of its expired keys.
+pub struct ReplyKeyStorageController {
+    reply_key_storage: ReplyKeyStorage,
+    flush_interval: Duration,
+    garbage_collection_interval: Duration,
+}
+
+impl ReplyKeyStorageController {
+    pub fn new(
+        reply_key_storage: ReplyKeyStorage,
+        flush_interval: Duration,
+        garbage_collection_interval: Duration,
+    ) -> Self {
+        ReplyKeyStorageController {
+            reply_key_storage,
+            flush_interval,
+            garbage_collection_interval,
+        }
+    }
+
+    async fn run(&mut self) {
+        let mut flush_interval = tokio::time::interval(self.flush_interval);
+        let mut garbage_collection_interval =
+            tokio::time::interval(self.garbage_collection_interval);
+
+        loop {
+            tokio::select! {
+                _ = flush_interval.tick() => {
+                    if let Err(err) = self.reply_key_storage.flush().await {
+                        error!("Failed to flush the reply key storage - {}", err);
+                    }
+                }
+                _ = garbage_collection_interval.tick() => {
+                    match self.reply_key_storage.remove_expired_keys().await {
+                        Ok(removed) if removed > 0 => debug!("Removed {} expired reply keys", removed),
+                        Ok(_) => (),
+                        Err(err) => error!("Failed to remove expired reply keys - {}", err),
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn start(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            self.run().await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use tempfile::tempdir;
+
+    const TEST_TTL: Duration = Duration::from_secs(60);
+
+    async fn stored_keys(storage: &ReplyKeyStorage) -> i64 {
+        sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM reply_key")
+            .fetch_one(&storage.connection_pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn keys_can_be_retrieved_exactly_once() {
+        let dir = tempdir().unwrap();
+        let storage = ReplyKeyStorage::load(dir.path().join("keys.db"), TEST_TTL)
+            .await
+            .unwrap();
+
+        let pending_key = SurbEncryptionKey::new(&mut OsRng);
+        let flushed_key = SurbEncryptionKey::new(&mut OsRng);
+        storage
+            .insert_encryption_key(flushed_key.clone())
+            .await
+            .unwrap();
+        storage.flush().await.unwrap();
+        storage
+            .insert_encryption_key(pending_key.clone())
+            .await
+            .unwrap();
+
+        for key in [pending_key, flushed_key] {
+            let digest = key.compute_digest();
+            let retrieved = storage
+                .get_and_remove_encryption_key(digest)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(retrieved.to_bytes(), key.to_bytes());
+            assert!(storage
+                .get_and_remove_encryption_key(digest)
+                .await
+                .unwrap()
+                .is_none());
+        }
+
+        storage.flush().await.unwrap();
+        assert_eq!(stored_keys(&storage).await, 0);
+    }
+
+    #[tokio::test]
+    async fn duplicate_keys_are_rejected() {
+        let dir = tempdir().unwrap();
+        let storage = ReplyKeyStorage::load(dir.path().join("keys.db"), TEST_TTL)
+            .await
+            .unwrap();
+
+        let key = SurbEncryptionKey::new(&mut OsRng);
+        storage.insert_encryption_key(key.clone()).await.unwrap();
+        assert!(matches!(
+            storage.insert_encryption_key(key.clone()).await,
+            Err(ReplyKeyStorageError::DuplicateKey)
+        ));
+
+        // and it's still a duplicate once it got written to the disk
+        storage.flush().await.unwrap();
+        assert!(matches!(
+            storage.insert_encryption_key(key).await,
+            Err(ReplyKeyStorageError::DuplicateKey)
+        ));
+    }
+
+    #[tokio::test]
+    async fn changes_are_only_written_in_batches() {
+        let dir = tempdir().unwrap();
+        let storage = ReplyKeyStorage::load(dir.path().join("keys.db"), TEST_TTL)
+            .await
+            .unwrap();
+
+        for _ in 0..MAX_PENDING_CHANGES - 1 {
+            storage
+                .insert_encryption_key(SurbEncryptionKey::new(&mut OsRng))
+                .await
+                .unwrap();
+        }
+        assert_eq!(stored_keys(&storage).await, 0);
+
+        // reaching the limit forces the batch onto the disk
+        storage
+            .insert_encryption_key(SurbEncryptionKey::new(&mut OsRng))
+            .await
+            .unwrap();
+        assert_eq!(stored_keys(&storage).await, MAX_PENDING_CHANGES as i64);
+        assert!(storage.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_flush_keeps_the_pending_changes() {
+        let dir = tempdir().unwrap();
+        let storage = ReplyKeyStorage::load(dir.path().join("keys.db"), TEST_TTL)
+            .await
+            .unwrap();
+
+        let key = SurbEncryptionKey::new(&mut OsRng);
+        storage.insert_encryption_key(key.clone()).await.unwrap();
+
+        sqlx::query("ALTER TABLE reply_key RENAME TO reply_key_tmp")
+            .execute(&storage.connection_pool)
+            .await
+            .unwrap();
+        assert!(storage.flush().await.is_err());
+        assert_eq!(storage.pending.lock().unwrap().len(), 1);
+
+        sqlx::query("ALTER TABLE reply_key_tmp RENAME TO reply_key")
+            .execute(&storage.connection_pool)
+            .await
+            .unwrap();
+        storage.flush().await.unwrap();
+        assert_eq!(stored_keys(&storage).await, 1);
+        assert!(storage.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_keys_are_not_returned_and_get_purged() {
+        let dir = tempdir().unwrap();
+        let storage = ReplyKeyStorage::load(dir.path().join("keys.db"), TEST_TTL)
+            .await
+            .unwrap();
+
+        let expired_timestamp = current_timestamp() - TEST_TTL.as_secs() as i64 - 1;
+        let expire = |storage: &ReplyKeyStorage, key: &SurbEncryptionKey| {
+            storage
+                .pending
+                .lock()
+                .unwrap()
+                .inserted
+                .get_mut(&key.compute_digest())
+                .unwrap()
+                .created_at = expired_timestamp;
+        };
+
+        let flushed_key = SurbEncryptionKey::new(&mut OsRng);
+        let fresh_key = SurbEncryptionKey::new(&mut OsRng);
+        storage
+            .insert_encryption_key(flushed_key.clone())
+            .await
+            .unwrap();
+        storage
+            .insert_encryption_key(fresh_key.clone())
+            .await
+            .unwrap();
+        expire(&storage, &flushed_key);
+        storage.flush().await.unwrap();
+
+        let pending_key = SurbEncryptionKey::new(&mut OsRng);
+        storage
+            .insert_encryption_key(pending_key.clone())
+            .await
+            .unwrap();
+        expire(&storage, &pending_key);
+        assert!(storage
+            .get_and_remove_encryption_key(pending_key.compute_digest())
+            .await
+            .unwrap()
+            .is_none());
+
+        assert_eq!(storage.remove_expired_keys().await.unwrap(), 1);
+        assert!(storage
+            .get_and_remove_encryption_key(flushed_key.compute_digest())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(storage
+            .get_and_remove_encryption_key(fresh_key.compute_digest())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn keys_are_imported_from_the_legacy_store() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("reply-key-store");
+
+        let key = SurbEncryptionKey::new(&mut OsRng);
+        {
+            let legacy_db = sled::open(&path).unwrap();
+            legacy_db
+                .insert(key.compute_digest(), key.to_bytes())
+                .unwrap();
+            legacy_db.insert(vec![1, 2, 3], vec![4, 5, 6]).unwrap();
+            legacy_db.flush().unwrap();
+        }
+
+        let storage = ReplyKeyStorage::load(&path, TEST_TTL).await.unwrap();
+        assert!(path.is_file());
+        assert!(!legacy_store_path(&path).exists());
+
+        // the malformed entry got skipped
+        assert_eq!(stored_keys(&storage).await, 1);
+        let retrieved = storage
+            .get_and_remove_encryption_key(key.compute_digest())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(retrieved.to_bytes(), key.to_bytes());
     }
 }
@@ -0,0 +1,307 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::client::topology_control::TopologyAccessor;
+use crate::config::Config;
+use crate::error::ClientCoreError;
+use config::defaults::DEFAULT_HTTP_API_LISTENING_PORT;
+use config::NymConfig;
+use futures::future::join_all;
+use log::*;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use topology::route_selection::{
+    NodeExclusions, NodeMetrics, PolicyRouteSelector, RouteSelectionPolicy,
+};
+use topology::{mix, NymTopology};
+use url::Url;
+
+/// Maximum number of mix nodes whose verloc measurements are queried during a single refresh.
+/// Since every node measures the latency to all other nodes, a small sample is enough.
+const MAX_VERLOC_REPORTERS: usize = 10;
+
+/// How long we're willing to wait for a response from the HTTP API of any mix node.
+const MIXNODE_API_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+struct VerlocResponse {
+    results: Vec<VerlocNodeResult>,
+}
+
+#[derive(Deserialize)]
+struct VerlocNodeResult {
+    identity: String,
+    latest_measurement: Option<VerlocMeasurement>,
+}
+
+#[derive(Deserialize)]
+struct VerlocMeasurement {
+    #[serde(with = "humantime_serde")]
+    mean: Duration,
+}
+
+#[derive(Deserialize)]
+struct NodeDescriptionResponse {
+    location: String,
+}
+
+/// Creates the route selector as specified by the route selection policy and the node exclusions
+/// in the client configuration.
+pub fn setup_route_selector<T: NymConfig>(config: &Config<T>) -> Arc<PolicyRouteSelector> {
+    let exclusions = NodeExclusions::new(
+        config.get_excluded_mixnode_identities(),
+        config.get_excluded_mixnode_owners(),
+        config.get_excluded_mixnode_countries(),
+    );
+
+    Arc::new(PolicyRouteSelector::new(
+        config.get_route_selection_policy(),
+        exclusions,
+    ))
+}
+
+fn check_eligible_nodes(
+    route_selector: &PolicyRouteSelector,
+    topology: &NymTopology,
+) -> Result<(), ClientCoreError> {
+    let layers = route_selector.layers_without_eligible_nodes(topology);
+    if layers.is_empty() {
+        Ok(())
+    } else {
+        Err(ClientCoreError::NoEligibleMixnodes(layers))
+    }
+}
+
+/// Periodically obtains the uptime, latency and location of all mix nodes in the current
+/// topology, as required by the [`PolicyRouteSelector`], and updates the selector with them.
+pub struct NodeMetricsRefresher {
+    validator_client: validator_client::ApiClient,
+    validator_api_urls: Vec<Url>,
+    currently_used_api: usize,
+
+    http_client: reqwest::Client,
+    topology_accessor: TopologyAccessor,
+    route_selector: Arc<PolicyRouteSelector>,
+    refresh_rate: Duration,
+}
+
+impl NodeMetricsRefresher {
+    pub fn new(
+        mut validator_api_urls: Vec<Url>,
+        topology_accessor: TopologyAccessor,
+        route_selector: Arc<PolicyRouteSelector>,
+        refresh_rate: Duration,
+    ) -> Self {
+        validator_api_urls.shuffle(&mut thread_rng());
+
+        let http_client = reqwest::Client::builder()
+            .timeout(MIXNODE_API_TIMEOUT)
+            .build()
+            .expect("failed to construct the http client");
+
+        NodeMetricsRefresher {
+            validator_client: validator_client::ApiClient::new(validator_api_urls[0].clone()),
+            validator_api_urls,
+            currently_used_api: 0,
+            http_client,
+            topology_accessor,
+            route_selector,
+            refresh_rate,
+        }
+    }
+
+    /// Indicates whether the route selector requires any metrics at all. If not, there's
+    /// no point in starting the refresher.
+    pub fn is_required(&self) -> bool {
+        self.route_selector.policy() == RouteSelectionPolicy::Uptime
+            || self.route_selector.policy() == RouteSelectionPolicy::Latency
+            || self.route_selector.exclusions().requires_locations()
+    }
+
+    fn use_next_validator_api(&mut self) {
+        if self.validator_api_urls.len() == 1 {
+            return;
+        }
+
+        self.currently_used_api = (self.currently_used_api + 1) % self.validator_api_urls.len();
+        self.validator_client
+            .change_validator_api(self.validator_api_urls[self.currently_used_api].clone())
+    }
+
+    fn mixnode_api_url(node: &mix::Node, endpoint: &str) -> String {
+        format!(
+            "http://{}:{}/{}",
+            node.host, DEFAULT_HTTP_API_LISTENING_PORT, endpoint
+        )
+    }
+
+    async fn get_uptimes(&mut self) -> HashMap<String, u8> {
+        match self.validator_client.get_mixnode_avg_uptimes().await {
+            Ok(uptimes) => uptimes
+                .into_iter()
+                .map(|uptime| (uptime.identity, uptime.avg_uptime))
+                .collect(),
+            Err(err) => {
+                warn!("failed to obtain the uptimes of the mix nodes - {}", err);
+                self.use_next_validator_api();
+                HashMap::new()
+            }
+        }
+    }
+
+    async fn get_verloc_results(&self, node: &mix::Node) -> Option<VerlocResponse> {
+        let response = self
+            .http_client
+            .get(Self::mixnode_api_url(node, "verloc"))
+            .send()
+            .await
+            .ok()?;
+        response.json().await.ok()
+    }
+
+    // the latency of each node is the average of all of the measurements of it
+    // performed by the sampled nodes
+    async fn get_latencies(&self, topology: &NymTopology) -> HashMap<String, Duration> {
+        let reporters = topology
+            .mixes_as_vec()
+            .choose_multiple(&mut thread_rng(), MAX_VERLOC_REPORTERS)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut measurements: HashMap<String, Vec<Duration>> = HashMap::new();
+        for reporter in reporters {
+            let verloc = match self.get_verloc_results(&reporter).await {
+                Some(verloc) => verloc,
+                None => {
+                    debug!(
+                        "failed to obtain verloc measurements from {}",
+                        reporter.identity_key.to_base58_string()
+                    );
+                    continue;
+                }
+            };
+
+            for result in verloc.results {
+                if let Some(measurement) = result.latest_measurement {
+                    measurements
+                        .entry(result.identity)
+                        .or_default()
+                        .push(measurement.mean);
+                }
+            }
+        }
+
+        measurements
+            .into_iter()
+            .map(|(identity, latencies)| {
+                let total: Duration = latencies.iter().sum();
+                (identity, total / latencies.len() as u32)
+            })
+            .collect()
+    }
+
+    async fn get_location(&self, node: &mix::Node) -> Option<String> {
+        let response = self
+            .http_client
+            .get(Self::mixnode_api_url(node, "description"))
+            .send()
+            .await
+            .ok()?;
+        let description: NodeDescriptionResponse = response.json().await.ok()?;
+        Some(description.location)
+    }
+
+    // unlike the verloc measurements, the location has to be obtained from every single node,
+    // so query all of them at once
+    async fn get_locations(&self, topology: &NymTopology) -> HashMap<String, String> {
+        let mixes = topology.mixes_as_vec();
+        let locations = join_all(mixes.iter().map(|node| self.get_location(node))).await;
+
+        mixes
+            .iter()
+            .zip(locations.into_iter())
+            .filter_map(|(node, location)| {
+                let identity = node.identity_key.to_base58_string();
+                if location.is_none() {
+                    debug!("failed to obtain the location of {}", identity);
+                }
+                location.map(|location| (identity, location))
+            })
+            .collect()
+    }
+
+    /// Makes sure that a route can be chosen through the current network topology,
+    /// i.e. that none of its layers consists solely of excluded nodes.
+    pub async fn check_eligible_nodes(&self) -> Result<(), ClientCoreError> {
+        match self.topology_accessor.current_topology().await {
+            Some(topology) => check_eligible_nodes(&self.route_selector, &topology),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn refresh(&mut self) {
+        trace!("Refreshing the mix node metrics");
+        let topology = match self.topology_accessor.current_topology().await {
+            Some(topology) => topology,
+            None => {
+                warn!(
+                    "there is no network topology available - can't refresh the mix node metrics"
+                );
+                return;
+            }
+        };
+
+        let uptimes = if self.route_selector.policy() == RouteSelectionPolicy::Uptime {
+            self.get_uptimes().await
+        } else {
+            HashMap::new()
+        };
+
+        let latencies = if self.route_selector.policy() == RouteSelectionPolicy::Latency {
+            self.get_latencies(&topology).await
+        } else {
+            HashMap::new()
+        };
+
+        let locations = if self.route_selector.exclusions().requires_locations() {
+            self.get_locations(&topology).await
+        } else {
+            HashMap::new()
+        };
+
+        let metrics = topology
+            .mixes_as_vec()
+            .into_iter()
+            .map(|node| {
+                let identity = node.identity_key.to_base58_string();
+                let metrics = NodeMetrics {
+                    uptime: uptimes.get(&identity).copied(),
+                    latency: latencies.get(&identity).copied(),
+                    location: locations.get(&identity).cloned(),
+                };
+                (identity, metrics)
+            })
+            .collect();
+
+        self.route_selector.update_metrics(metrics);
+
+        // we keep on running, as things might get better after the next refresh
+        if let Err(err) = check_eligible_nodes(&self.route_selector, &topology) {
+            error!("{} - no packets can be sent until that changes", err);
+        }
+    }
+
+    pub fn start(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(self.refresh_rate).await;
+                self.refresh().await;
+            }
+        })
+    }
+}
@@ -0,0 +1,315 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Anonymous request/response on top of the reply SURBs.
+//!
+//! [`RpcClient::request`] sends the request alongside enough reply SURBs for the whole response
+//! and waits for the replies sent using exactly those SURBs, while [`serve`] lets a service answer
+//! every received request without knowing who has sent it:
+//!
+//! ```no_run
+//! # use client_core::client::mixnet_client::MixnetClient;
+//! # use client_core::client::rpc::{self, RpcClient};
+//! # use nymsphinx::addressing::clients::Recipient;
+//! # async fn example(client: MixnetClient, service: MixnetClient, service_address: Recipient) {
+//! tokio::spawn(rpc::serve(service, |request: Vec<u8>| async move { request }));
+//!
+//! let (sender, _receiver) = client.split();
+//! let rpc_client = RpcClient::new(sender).with_max_response_size(10_000);
+//! let response = rpc_client.request(service_address, b"hello".to_vec()).await.unwrap();
+//! # }
+//! ```
+
+use crate::client::mixnet_client::{MixnetClient, MixnetClientSender};
+use crate::error::ClientCoreError;
+use futures::channel::{mpsc, oneshot};
+use futures::{Future, StreamExt};
+use log::*;
+use nymsphinx::addressing::clients::Recipient;
+use nymsphinx::anonymous_replies::ReplySurb;
+use nymsphinx::headers::MAX_REPLY_SURBS;
+use nymsphinx::params::PacketSize;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Every reply is prefixed with the total number of replies making up the response
+/// followed by the index of this particular one.
+const REPLY_HEADER_LEN: usize = 2;
+
+/// Total number of replies indicating the service has failed to respond and the reply
+/// contains the reason instead.
+const ERROR_REPLY_MARKER: u8 = 0;
+
+const DEFAULT_NUM_REPLY_SURBS: u8 = 4;
+const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum RpcError {
+    #[error("Client error: {0}")]
+    ClientError(#[from] ClientCoreError),
+
+    #[error("The request could not be sent")]
+    RequestNotSent,
+
+    #[error("Did not receive the full response within {0:?}")]
+    Timeout(Duration),
+
+    #[error("Received a malformed response reply")]
+    MalformedResponse,
+
+    #[error("The service has failed to respond: {0}")]
+    ServiceError(String),
+}
+
+/// Maximum number of bytes of the response that fit in a single reply.
+fn reply_capacity() -> usize {
+    ReplySurb::max_msg_len(PacketSize::default()) - REPLY_HEADER_LEN
+}
+
+/// Sends requests to services and waits for their anonymous responses.
+#[derive(Clone)]
+pub struct RpcClient {
+    sender: MixnetClientSender,
+    num_reply_surbs: u8,
+    timeout: Duration,
+}
+
+impl RpcClient {
+    pub fn new(sender: MixnetClientSender) -> Self {
+        RpcClient {
+            sender,
+            num_reply_surbs: DEFAULT_NUM_REPLY_SURBS,
+            timeout: DEFAULT_RPC_TIMEOUT,
+        }
+    }
+
+    /// Attaches enough reply SURBs to every request for a response of up to the specified number
+    /// of bytes. Note that a single request can carry at most [`MAX_REPLY_SURBS`] reply SURBs.
+    #[must_use]
+    pub fn with_max_response_size(mut self, max_response_size: usize) -> Self {
+        let num_reply_surbs =
+            max_response_size.saturating_add(reply_capacity() - 1) / reply_capacity();
+        self.num_reply_surbs = num_reply_surbs.clamp(1, MAX_REPLY_SURBS as usize) as u8;
+        self
+    }
+
+    /// Specifies for how long to wait for the full response to any request.
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sends the request to the specified service and waits for its response.
+    pub async fn request(
+        &self,
+        recipient: Recipient,
+        request: Vec<u8>,
+    ) -> Result<Vec<u8>, RpcError> {
+        // the route has to be in place before any of the replies could possibly arrive,
+        // so it's registered before the digests of the reply SURBs are even known
+        let (reply_key_digests_sender, reply_key_digests_receiver) = oneshot::channel();
+        let (route_sender, route_receiver) = mpsc::unbounded();
+        self.sender
+            .route_replies(reply_key_digests_receiver, route_sender)?;
+
+        self.sender.send_with_reply_key_digests(
+            recipient,
+            request,
+            self.num_reply_surbs,
+            reply_key_digests_sender,
+        )?;
+
+        // note that the message is prepared asynchronously, so the timeout also covers that part
+        tokio::time::timeout(self.timeout, Self::collect_response(route_receiver))
+            .await
+            .map_err(|_| RpcError::Timeout(self.timeout))?
+    }
+
+    // the replies might arrive in any order, so put them back together based on their indices
+    async fn collect_response(
+        mut route_receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+    ) -> Result<Vec<u8>, RpcError> {
+        let mut parts: Vec<Option<Vec<u8>>> = Vec::new();
+        while let Some(reply) = route_receiver.next().await {
+            if reply.len() < REPLY_HEADER_LEN {
+                return Err(RpcError::MalformedResponse);
+            }
+            let (total, index) = (reply[0], reply[1] as usize);
+            let data = reply[REPLY_HEADER_LEN..].to_vec();
+
+            if total == ERROR_REPLY_MARKER {
+                return Err(RpcError::ServiceError(
+                    String::from_utf8_lossy(&data).into_owned(),
+                ));
+            }
+            if parts.is_empty() {
+                parts.resize(total as usize, None);
+            }
+            if parts.len() != total as usize || index >= parts.len() {
+                return Err(RpcError::MalformedResponse);
+            }
+            parts[index] = Some(data);
+
+            if parts.iter().all(Option::is_some) {
+                return Ok(parts.into_iter().flatten().flatten().collect());
+            }
+        }
+
+        // the route is dropped if the request could not be prepared
+        if parts.is_empty() {
+            Err(RpcError::RequestNotSent)
+        } else {
+            Err(ClientCoreError::ClientShutdown.into())
+        }
+    }
+}
+
+fn make_reply(total: u8, index: u8, data: &[u8]) -> Vec<u8> {
+    [total, index].iter().chain(data.iter()).cloned().collect()
+}
+
+// splits the response into replies fitting into the specified number of reply SURBs
+fn make_replies(response: &[u8], num_reply_surbs: usize) -> Vec<Vec<u8>> {
+    // even an empty response has to be sent so that the requester would stop waiting
+    if response.is_empty() {
+        return vec![make_reply(1, 0, &[])];
+    }
+
+    let parts = response.chunks(reply_capacity()).collect::<Vec<_>>();
+    if parts.len() > num_reply_surbs {
+        let reason = format!(
+            "the response requires {} reply SURBs while only {} were provided",
+            parts.len(),
+            num_reply_surbs
+        );
+        warn!("Failed to respond to the request - {}", reason);
+        return vec![make_reply(ERROR_REPLY_MARKER, 0, reason.as_bytes())];
+    }
+
+    parts
+        .iter()
+        .enumerate()
+        .map(|(index, part)| make_reply(parts.len() as u8, index as u8, part))
+        .collect()
+}
+
+async fn respond(sender: &MixnetClientSender, reply_surbs: Vec<ReplySurb>, response: Vec<u8>) {
+    let replies = make_replies(&response, reply_surbs.len());
+
+    // any unused reply SURBs are simply discarded
+    for (reply_surb, reply) in reply_surbs.into_iter().zip(replies) {
+        if let Err(err) = sender.send_reply(reply_surb, reply).await {
+            warn!("Failed to send the response - {}", err);
+            return;
+        }
+    }
+}
+
+/// Answers every request received by the provided client with the response produced by
+/// the handler, until the client is shut down. The requests are handled concurrently.
+/// Messages without any reply SURBs attached can't be answered, so they're ignored.
+pub async fn serve<H, F>(client: MixnetClient, handler: H)
+where
+    H: Fn(Vec<u8>) -> F,
+    F: Future<Output = Vec<u8>>,
+{
+    let (sender, receiver) = client.split();
+    receiver
+        .for_each_concurrent(None, |request| {
+            let sender = &sender;
+            let handler = &handler;
+            async move {
+                if request.reply_surbs.is_empty() {
+                    debug!("Received a message without any reply SURBs - it can't be answered");
+                    return;
+                }
+                let response = handler(request.message).await;
+                respond(sender, request.reply_surbs, response).await
+            }
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route_with(replies: Vec<Vec<u8>>) -> mpsc::UnboundedReceiver<Vec<u8>> {
+        let (route_sender, route_receiver) = mpsc::unbounded();
+        for reply in replies {
+            route_sender.unbounded_send(reply).unwrap();
+        }
+        route_receiver
+    }
+
+    #[test]
+    fn reply_is_prefixed_with_its_position() {
+        assert_eq!(make_reply(3, 1, b"foo"), vec![3, 1, b'f', b'o', b'o']);
+        assert_eq!(make_reply(1, 0, &[]), vec![1, 0]);
+    }
+
+    #[test]
+    fn response_is_split_into_replies_fitting_into_reply_surbs() {
+        let response = vec![42; reply_capacity() * 2 + 1];
+        let replies = make_replies(&response, 3);
+
+        assert_eq!(replies.len(), 3);
+        for (index, reply) in replies.iter().enumerate() {
+            assert_eq!(reply[..REPLY_HEADER_LEN], [3, index as u8]);
+        }
+        assert_eq!(replies[2].len(), REPLY_HEADER_LEN + 1);
+    }
+
+    #[test]
+    fn empty_response_is_still_replied_to() {
+        assert_eq!(make_replies(&[], 1), vec![vec![1, 0]]);
+    }
+
+    #[test]
+    fn too_large_response_is_replaced_with_an_error() {
+        let response = vec![42; reply_capacity() + 1];
+        let replies = make_replies(&response, 1);
+
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0][..REPLY_HEADER_LEN], [ERROR_REPLY_MARKER, 0]);
+    }
+
+    #[tokio::test]
+    async fn response_is_put_back_together_regardless_of_reply_order() {
+        let response: Vec<u8> = (0..reply_capacity() * 3).map(|i| i as u8).collect();
+        let mut replies = make_replies(&response, 3);
+        replies.reverse();
+
+        let collected = RpcClient::collect_response(route_with(replies)).await;
+        assert_eq!(collected.unwrap(), response);
+    }
+
+    #[tokio::test]
+    async fn service_failure_is_reported() {
+        let replies = make_replies(&vec![42; reply_capacity() + 1], 1);
+
+        let collected = RpcClient::collect_response(route_with(replies)).await;
+        assert!(matches!(collected, Err(RpcError::ServiceError(_))));
+    }
+
+    #[tokio::test]
+    async fn malformed_replies_are_rejected() {
+        let collected = RpcClient::collect_response(route_with(vec![vec![1]])).await;
+        assert!(matches!(collected, Err(RpcError::MalformedResponse)));
+
+        let collected =
+            RpcClient::collect_response(route_with(vec![make_reply(2, 2, b"foo")])).await;
+        assert!(matches!(collected, Err(RpcError::MalformedResponse)));
+
+        let replies = vec![make_reply(2, 0, b"foo"), make_reply(3, 1, b"bar")];
+        let collected = RpcClient::collect_response(route_with(replies)).await;
+        assert!(matches!(collected, Err(RpcError::MalformedResponse)));
+    }
+
+    #[tokio::test]
+    async fn dropped_route_means_the_request_was_not_sent() {
+        let collected = RpcClient::collect_response(route_with(Vec::new())).await;
+        assert!(matches!(collected, Err(RpcError::RequestNotSent)));
+    }
+}
@@ -1,20 +1,62 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
+use futures::channel::{mpsc, oneshot};
+use futures::StreamExt;
 use log::*;
 use nymsphinx::addressing::clients::Recipient;
 use nymsphinx::params::DEFAULT_NUM_MIX_HOPS;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time;
 use std::time::Duration;
+use thiserror::Error;
 use tokio::sync::{RwLock, RwLockReadGuard};
 use tokio::task::JoinHandle;
+use topology::serializable::{SerializableNymTopology, SerializableTopologyError};
 use topology::{nym_topology_from_bonds, NymTopology};
 use url::Url;
 
+#[derive(Debug, Error)]
+pub enum TopologyFileError {
+    #[error("I/O error: {0}")]
+    IoError(#[from] io::Error),
+
+    #[error("The topology file is not a valid JSON - {0}")]
+    MalformedJson(#[from] serde_json::Error),
+
+    #[error("The topology file contains malformed nodes - {0}")]
+    MalformedTopology(#[from] SerializableTopologyError),
+
+    #[error("There is no network topology available")]
+    NoTopology,
+}
+
+/// Loads the network topology from the specified JSON file.
+pub fn load_topology_file<P: AsRef<Path>>(path: P) -> Result<NymTopology, TopologyFileError> {
+    let file = fs::File::open(path)?;
+    let serializable: SerializableNymTopology = serde_json::from_reader(io::BufReader::new(file))?;
+    Ok(NymTopology::try_from(serializable)?)
+}
+
+/// Writes the provided network topology to the specified JSON file, so that it could be
+/// loaded with [`load_topology_file`] later on.
+pub fn save_topology_file<P: AsRef<Path>>(
+    path: P,
+    topology: &NymTopology,
+) -> Result<(), TopologyFileError> {
+    let serializable = SerializableNymTopology::from(topology);
+    let file = fs::File::create(path)?;
+    serde_json::to_writer_pretty(io::BufWriter::new(file), &serializable)?;
+    Ok(())
+}
+
 // I'm extremely curious why compiler NEVER complained about lack of Debug here before
 #[derive(Debug)]
 pub struct TopologyAccessorInner(Option<NymTopology>);
@@ -110,6 +152,19 @@ impl TopologyAccessor {
         self.inner.write().await.update(new_topology);
     }
 
+    /// Returns a copy of the network topology currently used by the client.
+    pub async fn current_topology(&self) -> Option<NymTopology> {
+        self.inner.read().await.0.clone()
+    }
+
+    /// Writes the network topology currently used by the client to the specified JSON file.
+    pub async fn dump_topology<P: AsRef<Path>>(&self, path: P) -> Result<(), TopologyFileError> {
+        match &self.inner.read().await.0 {
+            None => Err(TopologyFileError::NoTopology),
+            Some(topology) => save_topology_file(path, topology),
+        }
+    }
+
     // only used by the client at startup to get a slightly more reasonable error message
     // (currently displays as unused because health checker is disabled due to required changes)
     pub async fn is_routable(&self) -> bool {
@@ -130,6 +185,9 @@ pub struct TopologyRefresherConfig {
     validator_api_urls: Vec<Url>,
     refresh_rate: time::Duration,
     client_version: String,
+    topology_file: Option<PathBuf>,
+    pin_topology: bool,
+    topology_dump_file: Option<PathBuf>,
 }
 
 impl TopologyRefresherConfig {
@@ -142,10 +200,41 @@ impl TopologyRefresherConfig {
             validator_api_urls,
             refresh_rate,
             client_version,
+            topology_file: None,
+            pin_topology: false,
+            topology_dump_file: None,
         }
     }
+
+    /// Makes the refresher load the topology from the specified JSON file rather than
+    /// obtaining it from the validator API.
+    #[must_use]
+    pub fn with_topology_file(mut self, topology_file: Option<PathBuf>) -> Self {
+        self.topology_file = topology_file;
+        self
+    }
+
+    /// Makes the refresher keep on using the initially obtained topology without ever refreshing it.
+    #[must_use]
+    pub fn with_pinned_topology(mut self, pin_topology: bool) -> Self {
+        self.pin_topology = pin_topology;
+        self
+    }
+
+    /// Makes the refresher write the currently used topology to the specified JSON file
+    /// after every refresh.
+    #[must_use]
+    pub fn with_topology_dump_file(mut self, topology_dump_file: Option<PathBuf>) -> Self {
+        self.topology_dump_file = topology_dump_file;
+        self
+    }
 }
 
+/// Channel used for requesting an immediate refresh of the network topology. Once the refresh
+/// is done, whether the new topology is routable is sent back on the provided channel.
+pub type TopologyRefreshRequester = mpsc::UnboundedSender<oneshot::Sender<bool>>;
+type TopologyRefreshRequestReceiver = mpsc::UnboundedReceiver<oneshot::Sender<bool>>;
+
 pub struct TopologyRefresher {
     validator_client: validator_client::ApiClient,
     client_version: String,
@@ -154,13 +243,21 @@ pub struct TopologyRefresher {
     topology_accessor: TopologyAccessor,
     refresh_rate: Duration,
 
+    topology_file: Option<PathBuf>,
+    pin_topology: bool,
+    topology_dump_file: Option<PathBuf>,
+
     currently_used_api: usize,
     was_latest_valid: bool,
+
+    refresh_request_sender: TopologyRefreshRequester,
+    refresh_request_receiver: TopologyRefreshRequestReceiver,
 }
 
 impl TopologyRefresher {
     pub fn new(mut cfg: TopologyRefresherConfig, topology_accessor: TopologyAccessor) -> Self {
         cfg.validator_api_urls.shuffle(&mut thread_rng());
+        let (refresh_request_sender, refresh_request_receiver) = mpsc::unbounded();
 
         TopologyRefresher {
             validator_client: validator_client::ApiClient::new(cfg.validator_api_urls[0].clone()),
@@ -168,8 +265,13 @@ impl TopologyRefresher {
             validator_api_urls: cfg.validator_api_urls,
             topology_accessor,
             refresh_rate: cfg.refresh_rate,
+            topology_file: cfg.topology_file,
+            pin_topology: cfg.pin_topology,
+            topology_dump_file: cfg.topology_dump_file,
             currently_used_api: 0,
             was_latest_valid: true,
+            refresh_request_sender,
+            refresh_request_receiver,
         }
     }
 
@@ -276,13 +378,46 @@ impl TopologyRefresher {
         }
     }
 
-    pub async fn refresh(&mut self) {
-        trace!("Refreshing the topology");
-        let new_topology = self.get_current_compatible_topology().await;
+    // unlike the topology obtained from the validator API, the one from the file is used as it is,
+    // as presumably whoever created it knew what they were doing
+    fn get_topology_from_file(&self, topology_file: &Path) -> Option<NymTopology> {
+        match load_topology_file(topology_file) {
+            Ok(topology) => Some(topology),
+            Err(err) => {
+                error!(
+                    "failed to load the network topology from {:?} - {}",
+                    topology_file, err
+                );
+                None
+            }
+        }
+    }
 
-        if new_topology.is_none() {
-            self.use_next_validator_api();
+    async fn dump_current_topology(&self, topology_dump_file: &Path) {
+        if let Err(err) = self
+            .topology_accessor
+            .dump_topology(topology_dump_file)
+            .await
+        {
+            warn!(
+                "failed to write the network topology to {:?} - {}",
+                topology_dump_file, err
+            );
         }
+    }
+
+    pub async fn refresh(&mut self) {
+        trace!("Refreshing the topology");
+        let new_topology = match self.topology_file.clone() {
+            Some(topology_file) => self.get_topology_from_file(&topology_file),
+            None => {
+                let new_topology = self.get_current_compatible_topology().await;
+                if new_topology.is_none() {
+                    self.use_next_validator_api();
+                }
+                new_topology
+            }
+        };
 
         if new_topology.is_none() && self.was_latest_valid {
             // if we failed to grab this topology, but the one before it was alright, let's assume
@@ -294,20 +429,51 @@ impl TopologyRefresher {
             self.was_latest_valid = true;
         }
 
+        let is_valid = new_topology.is_some();
         self.topology_accessor
             .update_global_topology(new_topology)
             .await;
+
+        if is_valid {
+            if let Some(topology_dump_file) = &self.topology_dump_file {
+                self.dump_current_topology(topology_dump_file).await;
+            }
+        }
     }
 
     pub async fn is_topology_routable(&self) -> bool {
         self.topology_accessor.is_routable().await
     }
 
+    /// Returns the channel allowing to refresh the topology without waiting for the next
+    /// scheduled refresh. Note that the requests are dropped if the topology is pinned.
+    pub fn refresh_requester(&self) -> TopologyRefreshRequester {
+        self.refresh_request_sender.clone()
+    }
+
+    async fn on_refresh_request(&mut self, responder: oneshot::Sender<bool>) {
+        debug!("Refreshing the topology on request");
+        self.refresh().await;
+        let is_routable = self.is_topology_routable().await;
+        // the requester might have given up on waiting in the meantime
+        let _ = responder.send(is_routable);
+    }
+
     pub fn start(mut self) -> JoinHandle<()> {
         tokio::spawn(async move {
+            if self.pin_topology {
+                info!("The network topology is pinned - it is not going to be refreshed");
+                return;
+            }
+
             loop {
-                tokio::time::sleep(self.refresh_rate).await;
-                self.refresh().await;
+                tokio::select! {
+                    _ = tokio::time::sleep(self.refresh_rate) => self.refresh().await,
+                    // we're holding a sender ourselves so the channel can't have been closed
+                    responder = self.refresh_request_receiver.next() => {
+                        self.on_refresh_request(responder.unwrap()).await
+                    }
+                }
             }
         })
     }
@@ -0,0 +1,364 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Multiplier applied to the configured delays between sent packets in the [`TrafficMode::LowPower`].
+const LOW_POWER_DELAY_MULTIPLIER: f64 = 10.0;
+
+/// Multiplier applied to the configured delays between sent packets in the [`TrafficMode::HighAnonymity`].
+const HIGH_ANONYMITY_DELAY_MULTIPLIER: f64 = 0.5;
+
+pub type TrafficRatesReceiver = watch::Receiver<TrafficRates>;
+
+/// Parameters of the Poisson distributions determining how often the client sends packets
+/// into the mix network.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrafficRates {
+    /// Average delay between subsequent packets of the loop cover traffic stream.
+    pub loop_cover_traffic_average_delay: Duration,
+
+    /// Average delay between subsequent packets of the real traffic stream. If no real packets
+    /// are available, a loop cover packet is sent instead in order to preserve the rate.
+    pub message_sending_average_delay: Duration,
+}
+
+impl TrafficRates {
+    pub fn new(
+        loop_cover_traffic_average_delay: Duration,
+        message_sending_average_delay: Duration,
+    ) -> Self {
+        TrafficRates {
+            loop_cover_traffic_average_delay,
+            message_sending_average_delay,
+        }
+    }
+
+    /// Returns new rates with both of the average delays multiplied by the specified factor.
+    #[must_use]
+    pub fn scaled(&self, factor: f64) -> Self {
+        TrafficRates {
+            loop_cover_traffic_average_delay: self.loop_cover_traffic_average_delay.mul_f64(factor),
+            message_sending_average_delay: self.message_sending_average_delay.mul_f64(factor),
+        }
+    }
+}
+
+/// Predefined sending rates, derived from the configured ones, trading the battery and bandwidth
+/// usage for the anonymity provided by the cover traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficMode {
+    /// Packets are sent at the configured rates.
+    Normal,
+
+    /// Packets are sent considerably less often than configured, saving battery and bandwidth
+    /// at the cost of weaker unobservability and higher latency.
+    LowPower,
+
+    /// Packets are sent more often than configured, making it harder to tell real traffic
+    /// apart from the cover traffic.
+    HighAnonymity,
+}
+
+impl TrafficMode {
+    fn delay_multiplier(&self) -> f64 {
+        match self {
+            TrafficMode::Normal => 1.0,
+            TrafficMode::LowPower => LOW_POWER_DELAY_MULTIPLIER,
+            TrafficMode::HighAnonymity => HIGH_ANONYMITY_DELAY_MULTIPLIER,
+        }
+    }
+}
+
+/// Handle allowing adjusting the rates of the loop cover traffic stream and the real traffic
+/// stream of a running client, without having to restart it.
+#[derive(Debug, Clone)]
+pub struct TrafficRatesControl {
+    /// Rates specified in the client configuration used as the base of the [`TrafficMode`]s.
+    base_rates: TrafficRates,
+
+    sender: Arc<watch::Sender<TrafficRates>>,
+
+    // we're keeping one receiver around so that the channel would never get closed
+    receiver: TrafficRatesReceiver,
+}
+
+impl TrafficRatesControl {
+    pub fn new(base_rates: TrafficRates) -> Self {
+        let (sender, receiver) = watch::channel(base_rates);
+
+        TrafficRatesControl {
+            base_rates,
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    /// Creates a new channel on which all subsequent changes to the rates are going to be announced.
+    pub fn subscribe(&self) -> TrafficRatesReceiver {
+        self.receiver.clone()
+    }
+
+    /// Returns the rates the client is currently using.
+    pub fn current_rates(&self) -> TrafficRates {
+        *self.receiver.borrow()
+    }
+
+    /// Returns the rates specified in the client configuration.
+    pub fn base_rates(&self) -> TrafficRates {
+        self.base_rates
+    }
+
+    /// Changes the rates of both of the traffic streams. The change takes effect immediately,
+    /// i.e. the streams do not wait for the next packet scheduled with the old rates.
+    pub fn set_rates(&self, rates: TrafficRates) {
+        // we're holding a receiver ourselves, so the channel can't be closed
+        let _ = self.sender.send(rates);
+    }
+
+    /// Changes the rates of both of the traffic streams to the ones derived from
+    /// the configured rates for the specified mode.
+    pub fn set_mode(&self, mode: TrafficMode) {
+        self.set_rates(self.base_rates.scaled(mode.delay_multiplier()))
+    }
+}
+
+#[derive(Debug)]
+struct PacketStatisticsInner {
+    started: Instant,
+    real_packets_queued: AtomicU64,
+    real_packets_sent: AtomicU64,
+    out_queue_cover_packets_sent: AtomicU64,
+    loop_cover_packets_sent: AtomicU64,
+    pending_acks: AtomicU64,
+    pending_messages: AtomicU64,
+}
+
+/// Counters of all packets the client has sent into the mix network, broken down by the stream
+/// they originated from.
+// note that clone here is fine as upon cloning the same underlying counters will be used
+#[derive(Debug, Clone)]
+pub struct PacketStatistics {
+    inner: Arc<PacketStatisticsInner>,
+}
+
+impl Default for PacketStatistics {
+    fn default() -> Self {
+        PacketStatistics::new()
+    }
+}
+
+impl PacketStatistics {
+    pub fn new() -> Self {
+        PacketStatistics {
+            inner: Arc::new(PacketStatisticsInner {
+                started: Instant::now(),
+                real_packets_queued: AtomicU64::new(0),
+                real_packets_sent: AtomicU64::new(0),
+                out_queue_cover_packets_sent: AtomicU64::new(0),
+                loop_cover_packets_sent: AtomicU64::new(0),
+                pending_acks: AtomicU64::new(0),
+                pending_messages: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub(crate) fn increment_real_packets_queued(&self, count: u64) {
+        self.inner
+            .real_packets_queued
+            .fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increment_real_packets_sent(&self) {
+        self.inner.real_packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increment_out_queue_cover_packets_sent(&self) {
+        self.inner
+            .out_queue_cover_packets_sent
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increment_loop_cover_packets_sent(&self) {
+        self.inner
+            .loop_cover_packets_sent
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_pending_acks(&self, pending_acks: u64, pending_messages: u64) {
+        self.inner
+            .pending_acks
+            .store(pending_acks, Ordering::Relaxed);
+        self.inner
+            .pending_messages
+            .store(pending_messages, Ordering::Relaxed);
+    }
+
+    /// Number of real packets that were handed to the real traffic stream but are yet to be sent.
+    /// It can be used as a measure of back-pressure when pushing a lot of data into the client.
+    pub fn pending_real_packets(&self) -> u64 {
+        // load the sent counter first so that we'd never observe more sent than queued packets
+        let sent = self.inner.real_packets_sent.load(Ordering::Relaxed);
+        let queued = self.inner.real_packets_queued.load(Ordering::Relaxed);
+        queued.saturating_sub(sent)
+    }
+
+    /// Returns the current values of all the counters.
+    pub fn snapshot(&self) -> PacketStatisticsSnapshot {
+        PacketStatisticsSnapshot {
+            elapsed: self.inner.started.elapsed(),
+            real_packets_queued: self.inner.real_packets_queued.load(Ordering::Relaxed),
+            real_packets_sent: self.inner.real_packets_sent.load(Ordering::Relaxed),
+            out_queue_cover_packets_sent: self
+                .inner
+                .out_queue_cover_packets_sent
+                .load(Ordering::Relaxed),
+            loop_cover_packets_sent: self.inner.loop_cover_packets_sent.load(Ordering::Relaxed),
+            pending_acks: self.inner.pending_acks.load(Ordering::Relaxed),
+            pending_messages: self.inner.pending_messages.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time values of the [`PacketStatistics`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketStatisticsSnapshot {
+    /// Time elapsed since the client has started counting the packets.
+    pub elapsed: Duration,
+
+    /// Number of packets with real data (including retransmissions) handed to the real traffic stream.
+    pub real_packets_queued: u64,
+
+    /// Number of packets with real data (including retransmissions) sent by the real traffic stream.
+    pub real_packets_sent: u64,
+
+    /// Number of cover packets sent by the real traffic stream in place of the real packets.
+    pub out_queue_cover_packets_sent: u64,
+
+    /// Number of cover packets sent by the loop cover traffic stream.
+    pub loop_cover_packets_sent: u64,
+
+    /// Number of sent packets that are still waiting to be acknowledged by their recipients.
+    pub pending_acks: u64,
+
+    /// Number of sent messages that are yet to be either fully acknowledged or abandoned.
+    pub pending_messages: u64,
+}
+
+impl PacketStatisticsSnapshot {
+    /// Number of real packets waiting in the out queue at the time of the snapshot.
+    pub fn pending_real_packets(&self) -> u64 {
+        self.real_packets_queued
+            .saturating_sub(self.real_packets_sent)
+    }
+
+    /// Total number of cover packets sent by both of the traffic streams.
+    pub fn cover_packets_sent(&self) -> u64 {
+        self.out_queue_cover_packets_sent + self.loop_cover_packets_sent
+    }
+
+    /// Average number of real packets sent per second.
+    pub fn real_packets_rate(&self) -> f64 {
+        self.per_second(self.real_packets_sent)
+    }
+
+    /// Average number of cover packets sent per second by both of the traffic streams.
+    pub fn cover_packets_rate(&self) -> f64 {
+        self.per_second(self.cover_packets_sent())
+    }
+
+    fn per_second(&self, count: u64) -> f64 {
+        let elapsed = self.elapsed.as_secs_f64();
+        if elapsed == 0.0 {
+            0.0
+        } else {
+            count as f64 / elapsed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base_rates() -> TrafficRates {
+        TrafficRates::new(Duration::from_millis(200), Duration::from_millis(20))
+    }
+
+    #[test]
+    fn scaling_applies_to_both_delays() {
+        let scaled = base_rates().scaled(2.5);
+        assert_eq!(
+            scaled.loop_cover_traffic_average_delay,
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            scaled.message_sending_average_delay,
+            Duration::from_millis(50)
+        );
+    }
+
+    #[test]
+    fn set_rates_is_visible_to_subscribers() {
+        let control = TrafficRatesControl::new(base_rates());
+        let mut receiver = control.subscribe();
+        assert_eq!(control.current_rates(), base_rates());
+
+        let new_rates = TrafficRates::new(Duration::from_secs(1), Duration::from_millis(100));
+        control.set_rates(new_rates);
+
+        assert!(receiver.has_changed().unwrap());
+        assert_eq!(*receiver.borrow_and_update(), new_rates);
+        assert_eq!(control.current_rates(), new_rates);
+        // base rates are unaffected by explicitly set rates
+        assert_eq!(control.base_rates(), base_rates());
+    }
+
+    #[test]
+    fn modes_are_derived_from_base_rates() {
+        let control = TrafficRatesControl::new(base_rates());
+
+        control.set_mode(TrafficMode::LowPower);
+        assert_eq!(
+            control.current_rates(),
+            base_rates().scaled(LOW_POWER_DELAY_MULTIPLIER)
+        );
+
+        // switching modes doesn't compound the multipliers
+        control.set_mode(TrafficMode::HighAnonymity);
+        assert_eq!(
+            control.current_rates(),
+            base_rates().scaled(HIGH_ANONYMITY_DELAY_MULTIPLIER)
+        );
+
+        control.set_rates(TrafficRates::new(
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        ));
+        control.set_mode(TrafficMode::Normal);
+        assert_eq!(control.current_rates(), base_rates());
+    }
+
+    #[test]
+    fn packet_statistics_track_pending_real_packets() {
+        let statistics = PacketStatistics::new();
+        statistics.increment_real_packets_queued(3);
+        statistics.increment_real_packets_sent();
+        statistics.increment_out_queue_cover_packets_sent();
+        statistics.increment_loop_cover_packets_sent();
+        statistics.increment_loop_cover_packets_sent();
+        statistics.set_pending_acks(4, 1);
+
+        assert_eq!(statistics.pending_real_packets(), 2);
+
+        let snapshot = statistics.snapshot();
+        assert_eq!(snapshot.pending_real_packets(), 2);
+        assert_eq!(snapshot.real_packets_sent, 1);
+        assert_eq!(snapshot.cover_packets_sent(), 3);
+        assert_eq!(snapshot.pending_acks, 4);
+        assert_eq!(snapshot.pending_messages, 1);
+    }
+}
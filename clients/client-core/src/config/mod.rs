@@ -7,6 +7,7 @@ use serde::{Deserialize, Serialize};
 use std::marker::PhantomData;
 use std::path::PathBuf;
 use std::time::Duration;
+use topology::route_selection::RouteSelectionPolicy;
 use url::Url;
 
 pub mod persistence;
@@ -17,15 +18,26 @@ pub const MISSING_VALUE: &str = "MISSING VALUE";
 const DEFAULT_ACK_WAIT_MULTIPLIER: f64 = 1.5;
 
 const DEFAULT_ACK_WAIT_ADDITION: Duration = Duration::from_millis(1_500);
+// 0 means the packets are retransmitted until they are acknowledged, as it has always been
+const DEFAULT_MAXIMUM_RETRANSMISSIONS: u32 = 0;
+const DEFAULT_RETRANSMISSION_BACKOFF_MULTIPLIER: f64 = 1.5;
+const DEFAULT_MAXIMUM_RETRANSMISSION_BACKOFF: Duration = Duration::from_secs(60);
 const DEFAULT_LOOP_COVER_STREAM_AVERAGE_DELAY: Duration = Duration::from_millis(200);
 const DEFAULT_MESSAGE_STREAM_AVERAGE_DELAY: Duration = Duration::from_millis(20);
 const DEFAULT_AVERAGE_PACKET_DELAY: Duration = Duration::from_millis(50);
 const DEFAULT_TOPOLOGY_REFRESH_RATE: Duration = Duration::from_secs(5 * 60); // every 5min
+const DEFAULT_NODE_METRICS_REFRESH_RATE: Duration = Duration::from_secs(15 * 60); // every 15min
 const DEFAULT_TOPOLOGY_RESOLUTION_TIMEOUT: Duration = Duration::from_millis(5_000);
 // Set this to a high value for now, so that we don't risk sporadic timeouts that might cause
 // bought bandwidth tokens to not have time to be spent; Once we remove the gateway from the
 // bandwidth bridging protocol, we can come back to a smaller timeout value
 const DEFAULT_GATEWAY_RESPONSE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_REPLY_KEY_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60); // 1 week
+const DEFAULT_REPLY_KEY_STORAGE_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_REPLY_KEY_STORAGE_GC_INTERVAL: Duration = Duration::from_secs(10 * 60); // every 10min
+const DEFAULT_GATEWAY_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_GATEWAY_FAILOVER_THRESHOLD: u32 = 3;
+const DEFAULT_KEY_ROTATION_GRACE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60); // 1 day
 
 pub fn missing_string_value() -> String {
     MISSING_VALUE.to_string()
@@ -107,6 +119,11 @@ impl<T: NymConfig> Config<T> {
             self.client.database_path = self::Client::<T>::default_database_path(&id);
         }
 
+        if self.client.outbound_journal_path.as_os_str().is_empty() {
+            self.client.outbound_journal_path =
+                self::Client::<T>::default_outbound_journal_path(&id);
+        }
+
         self.client.id = id;
     }
 
@@ -122,6 +139,10 @@ impl<T: NymConfig> Config<T> {
         self.client.gateway_endpoint.gateway_id = id.into();
     }
 
+    pub fn with_backup_gateway_endpoints(&mut self, gateway_endpoints: Vec<GatewayEndpoint>) {
+        self.client.backup_gateway_endpoints = gateway_endpoints;
+    }
+
     #[cfg(not(feature = "coconut"))]
     pub fn with_eth_private_key<S: Into<String>>(&mut self, eth_private_key: S) {
         self.client.eth_private_key = eth_private_key.into();
@@ -142,6 +163,42 @@ impl<T: NymConfig> Config<T> {
         self.debug.message_sending_average_delay = Duration::from_millis(4); // 250 "real" messages / s
     }
 
+    pub fn set_topology_file(&mut self, topology_file: PathBuf) {
+        self.debug.topology_file = Some(topology_file);
+    }
+
+    pub fn set_pinned_topology(&mut self, pin_topology: bool) {
+        self.debug.pin_topology = pin_topology;
+    }
+
+    pub fn set_topology_dump_file(&mut self, topology_dump_file: PathBuf) {
+        self.debug.topology_dump_file = Some(topology_dump_file);
+    }
+
+    pub fn set_route_selection_policy(&mut self, policy: RouteSelectionPolicy) {
+        self.debug.route_selection_policy = policy;
+    }
+
+    pub fn set_excluded_mixnode_identities(&mut self, identities: Vec<String>) {
+        self.debug.excluded_mixnode_identities = identities;
+    }
+
+    pub fn set_excluded_mixnode_owners(&mut self, owners: Vec<String>) {
+        self.debug.excluded_mixnode_owners = owners;
+    }
+
+    pub fn set_excluded_mixnode_countries(&mut self, countries: Vec<String>) {
+        self.debug.excluded_mixnode_countries = countries;
+    }
+
+    pub fn set_key_rotation_interval(&mut self, interval: Duration) {
+        self.debug.key_rotation_interval = interval;
+    }
+
+    pub fn set_key_rotation_grace_period(&mut self, grace_period: Duration) {
+        self.debug.key_rotation_grace_period = grace_period;
+    }
+
     pub fn set_custom_version(&mut self, version: &str) {
         self.client.version = version.to_string();
     }
@@ -206,10 +263,39 @@ impl<T: NymConfig> Config<T> {
         &self.client.gateway_endpoint
     }
 
+    pub fn get_backup_gateway_endpoints(&self) -> &[GatewayEndpoint] {
+        &self.client.backup_gateway_endpoints
+    }
+
     pub fn get_database_path(&self) -> PathBuf {
         self.client.database_path.clone()
     }
 
+    pub fn get_outbound_journal_enabled(&self) -> bool {
+        self.client.enable_outbound_journal
+    }
+
+    pub fn get_gateway_pull_mode(&self) -> bool {
+        self.client.gateway_pull_mode
+    }
+
+    /// How long the fragments recorded in the outbound journal are resent after restarts.
+    /// Past the message delivery deadline they would be abandoned anyway, and without it,
+    /// past the reply key ttl the replies to any attached reply SURBs could not be decrypted.
+    pub fn get_outbound_journal_entry_ttl(&self) -> Duration {
+        self.get_message_delivery_deadline()
+            .unwrap_or_else(|| self.get_reply_key_ttl())
+    }
+
+    pub fn get_outbound_journal_path(&self) -> PathBuf {
+        // configs created before the journal was introduced will not have the path set
+        if self.client.outbound_journal_path.as_os_str().is_empty() {
+            self::Client::<T>::default_outbound_journal_path(&self.client.id)
+        } else {
+            self.client.outbound_journal_path.clone()
+        }
+    }
+
     #[cfg(not(feature = "coconut"))]
     pub fn get_eth_endpoint(&self) -> String {
         self.client.eth_endpoint.clone()
@@ -237,6 +323,26 @@ impl<T: NymConfig> Config<T> {
         self.debug.ack_wait_addition
     }
 
+    pub fn get_maximum_retransmissions(&self) -> u32 {
+        self.debug.maximum_retransmissions
+    }
+
+    pub fn get_retransmission_backoff_multiplier(&self) -> f64 {
+        self.debug.retransmission_backoff_multiplier
+    }
+
+    pub fn get_maximum_retransmission_backoff(&self) -> Duration {
+        self.debug.maximum_retransmission_backoff
+    }
+
+    pub fn get_message_delivery_deadline(&self) -> Option<Duration> {
+        if self.debug.message_delivery_deadline.is_zero() {
+            None
+        } else {
+            Some(self.debug.message_delivery_deadline)
+        }
+    }
+
     pub fn get_loop_cover_traffic_average_delay(&self) -> Duration {
         self.debug.loop_cover_traffic_average_delay
     }
@@ -257,6 +363,74 @@ impl<T: NymConfig> Config<T> {
         self.debug.topology_resolution_timeout
     }
 
+    pub fn get_topology_file(&self) -> Option<PathBuf> {
+        self.debug.topology_file.clone()
+    }
+
+    pub fn get_pin_topology(&self) -> bool {
+        self.debug.pin_topology
+    }
+
+    pub fn get_topology_dump_file(&self) -> Option<PathBuf> {
+        self.debug.topology_dump_file.clone()
+    }
+
+    pub fn get_route_selection_policy(&self) -> RouteSelectionPolicy {
+        self.debug.route_selection_policy
+    }
+
+    pub fn get_excluded_mixnode_identities(&self) -> Vec<String> {
+        self.debug.excluded_mixnode_identities.clone()
+    }
+
+    pub fn get_excluded_mixnode_owners(&self) -> Vec<String> {
+        self.debug.excluded_mixnode_owners.clone()
+    }
+
+    pub fn get_excluded_mixnode_countries(&self) -> Vec<String> {
+        self.debug.excluded_mixnode_countries.clone()
+    }
+
+    pub fn get_node_metrics_refresh_rate(&self) -> Duration {
+        self.debug.node_metrics_refresh_rate
+    }
+
+    pub fn get_reply_key_ttl(&self) -> Duration {
+        self.debug.reply_key_ttl
+    }
+
+    pub fn get_reply_key_storage_flush_interval(&self) -> Duration {
+        self.debug.reply_key_storage_flush_interval
+    }
+
+    pub fn get_reply_key_storage_gc_interval(&self) -> Duration {
+        self.debug.reply_key_storage_gc_interval
+    }
+
+    pub fn get_gateway_health_check_interval(&self) -> Duration {
+        self.debug.gateway_health_check_interval
+    }
+
+    pub fn get_gateway_failover_threshold(&self) -> u32 {
+        self.debug.gateway_failover_threshold
+    }
+
+    pub fn get_bandwidth_redemption_threshold(&self) -> i64 {
+        self.debug.bandwidth_redemption_threshold
+    }
+
+    pub fn get_key_rotation_interval(&self) -> Option<Duration> {
+        if self.debug.key_rotation_interval.is_zero() {
+            None
+        } else {
+            Some(self.debug.key_rotation_interval)
+        }
+    }
+
+    pub fn get_key_rotation_grace_period(&self) -> Duration {
+        self.debug.key_rotation_grace_period
+    }
+
     pub fn get_version(&self) -> &str {
         &self.client.version
     }
@@ -340,9 +514,32 @@ pub struct Client<T> {
     /// Information regarding how the client should send data to gateway.
     gateway_endpoint: GatewayEndpoint,
 
+    /// Gateways the client has also registered with, that it is going to switch to, in order,
+    /// if the primary gateway stops responding.
+    #[serde(default)]
+    backup_gateway_endpoints: Vec<GatewayEndpoint>,
+
     /// Path to the database containing bandwidth credentials of this client.
     database_path: PathBuf,
 
+    /// Indicates whether fragments of sent messages should be recorded on disk until they are
+    /// acknowledged, so that they could be resent if the client gets restarted in the meantime.
+    /// The fragments are only resent until the message delivery deadline, or the reply key ttl
+    /// if there's no deadline, and replies sent using reply SURBs are never recorded.
+    /// Note that the fragments are stored unencrypted, so anyone able to read the journal file
+    /// can read the messages that are still in transit. The file is only accessible by its owner.
+    #[serde(default)]
+    enable_outbound_journal: bool,
+
+    /// Path to the database containing fragments of sent messages that were not yet acknowledged.
+    #[serde(default)]
+    outbound_journal_path: PathBuf,
+
+    /// Indicates whether the messages stored by the gateway while the client was offline should only
+    /// be retrieved on demand, rather than all of them being pushed as soon as the client connects.
+    #[serde(default)]
+    gateway_pull_mode: bool,
+
     /// Ethereum private key.
     #[cfg(not(feature = "coconut"))]
     eth_private_key: String,
@@ -375,7 +572,11 @@ impl<T: NymConfig> Default for Client<T> {
             ack_key_file: Default::default(),
             reply_encryption_key_store_path: Default::default(),
             gateway_endpoint: Default::default(),
+            backup_gateway_endpoints: Vec::new(),
             database_path: Default::default(),
+            enable_outbound_journal: false,
+            outbound_journal_path: Default::default(),
+            gateway_pull_mode: false,
             #[cfg(not(feature = "coconut"))]
             eth_private_key: "".to_string(),
             #[cfg(not(feature = "coconut"))]
@@ -417,6 +618,10 @@ impl<T: NymConfig> Client<T> {
     fn default_database_path(id: &str) -> PathBuf {
         T::default_data_directory(Some(id)).join("db.sqlite")
     }
+
+    fn default_outbound_journal_path(id: &str) -> PathBuf {
+        T::default_data_directory(Some(id)).join("outbound_journal.sqlite")
+    }
 }
 
 #[derive(Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
@@ -451,6 +656,25 @@ pub struct Debug {
     #[serde(with = "humantime_serde")]
     ack_wait_addition: Duration,
 
+    /// Maximum number of times a single data packet is retransmitted before the entire message
+    /// it is part of is abandoned and the sender is notified about it.
+    /// Setting it to 0 makes the client retransmit indefinitely.
+    maximum_retransmissions: u32,
+
+    /// Every subsequent retransmission of the same data packet waits this many times longer
+    /// for its acknowledgement than the previous attempt. Setting it to 1 disables the backoff.
+    retransmission_backoff_multiplier: f64,
+
+    /// Upper bound on the time spent waiting for an acknowledgement of a retransmitted packet
+    /// resulting from the backoff.
+    #[serde(with = "humantime_serde")]
+    maximum_retransmission_backoff: Duration,
+
+    /// Messages that were not fully acknowledged within this time since they were sent are
+    /// abandoned and the sender is notified about it. Setting it to 0 disables the deadline.
+    #[serde(with = "humantime_serde")]
+    message_delivery_deadline: Duration,
+
     /// The parameter of Poisson distribution determining how long, on average,
     /// it is going to take for another loop cover traffic message to be sent.
     #[serde(with = "humantime_serde")]
@@ -478,6 +702,76 @@ pub struct Debug {
     /// did not reach its destination.
     #[serde(with = "humantime_serde")]
     topology_resolution_timeout: Duration,
+
+    /// If specified, the network topology is loaded from this JSON file rather than
+    /// obtained from the validator API.
+    topology_file: Option<PathBuf>,
+
+    /// Indicates whether the initially obtained network topology should keep on being used
+    /// for the entire lifetime of the client, i.e. it is never refreshed.
+    pin_topology: bool,
+
+    /// If specified, the network topology currently used by the client is written to this
+    /// JSON file after every refresh. The file can later be used as the `topology_file`.
+    topology_dump_file: Option<PathBuf>,
+
+    /// Determines how the mix nodes used for the routes of the sent packets are chosen:
+    /// `uniform`ly at random, or weighted by their `stake`, their `uptime` as reported by
+    /// the validator API or the inverse of their `latency` as measured by the verloc protocol.
+    route_selection_policy: RouteSelectionPolicy,
+
+    /// Identity keys of the mix nodes that are never going to be used for any route.
+    excluded_mixnode_identities: Vec<String>,
+
+    /// Addresses of the operators whose mix nodes are never going to be used for any route.
+    excluded_mixnode_owners: Vec<String>,
+
+    /// Countries whose mix nodes are never going to be used for any route, as announced by
+    /// the node operators. Nodes with an unknown location are also excluded if this is non-empty.
+    excluded_mixnode_countries: Vec<String>,
+
+    /// The uniform delay every which the uptime, latency and location of the mix nodes are
+    /// queried, if required by the route selection policy or the exclusions.
+    #[serde(with = "humantime_serde")]
+    node_metrics_refresh_rate: Duration,
+
+    /// How long the keys of sent reply-SURBs are kept around. Any reply received after
+    /// this period is not going to be decryptable.
+    #[serde(with = "humantime_serde")]
+    reply_key_ttl: Duration,
+
+    /// The interval at which newly created and used reply keys are written to the disk.
+    #[serde(with = "humantime_serde")]
+    reply_key_storage_flush_interval: Duration,
+
+    /// The interval at which expired reply keys are purged from the storage.
+    #[serde(with = "humantime_serde")]
+    reply_key_storage_gc_interval: Duration,
+
+    /// The interval at which the connection to the gateway is checked, if any backup gateways
+    /// are available.
+    #[serde(with = "humantime_serde")]
+    gateway_health_check_interval: Duration,
+
+    /// Number of consecutive failed health checks or failed attempts of sending packets
+    /// to the gateway after which the client is going to switch to a backup gateway.
+    gateway_failover_threshold: u32,
+
+    /// Once the bandwidth remaining at the gateway drops below this many bytes, a low balance
+    /// warning is emitted and, with coconut credentials, the next stored credential is redeemed
+    /// to top it up.
+    bandwidth_redemption_threshold: i64,
+
+    /// The interval at which the encryption and ack keys of the client are replaced with new ones,
+    /// changing its address (but not its identity). Setting it to 0 disables the rotation.
+    #[serde(with = "humantime_serde")]
+    key_rotation_interval: Duration,
+
+    /// How long the keys replaced during a rotation are still used for decrypting received
+    /// messages and acknowledgements, so that packets already sent to the old address
+    /// would not get lost.
+    #[serde(with = "humantime_serde")]
+    key_rotation_grace_period: Duration,
 }
 
 impl Default for Debug {
@@ -487,11 +781,31 @@ impl Default for Debug {
             average_ack_delay: DEFAULT_AVERAGE_PACKET_DELAY,
             ack_wait_multiplier: DEFAULT_ACK_WAIT_MULTIPLIER,
             ack_wait_addition: DEFAULT_ACK_WAIT_ADDITION,
+            maximum_retransmissions: DEFAULT_MAXIMUM_RETRANSMISSIONS,
+            retransmission_backoff_multiplier: DEFAULT_RETRANSMISSION_BACKOFF_MULTIPLIER,
+            maximum_retransmission_backoff: DEFAULT_MAXIMUM_RETRANSMISSION_BACKOFF,
+            message_delivery_deadline: Duration::ZERO,
             loop_cover_traffic_average_delay: DEFAULT_LOOP_COVER_STREAM_AVERAGE_DELAY,
             message_sending_average_delay: DEFAULT_MESSAGE_STREAM_AVERAGE_DELAY,
             gateway_response_timeout: DEFAULT_GATEWAY_RESPONSE_TIMEOUT,
             topology_refresh_rate: DEFAULT_TOPOLOGY_REFRESH_RATE,
             topology_resolution_timeout: DEFAULT_TOPOLOGY_RESOLUTION_TIMEOUT,
+            topology_file: None,
+            pin_topology: false,
+            topology_dump_file: None,
+            route_selection_policy: RouteSelectionPolicy::Uniform,
+            excluded_mixnode_identities: Vec::new(),
+            excluded_mixnode_owners: Vec::new(),
+            excluded_mixnode_countries: Vec::new(),
+            node_metrics_refresh_rate: DEFAULT_NODE_METRICS_REFRESH_RATE,
+            reply_key_ttl: DEFAULT_REPLY_KEY_TTL,
+            reply_key_storage_flush_interval: DEFAULT_REPLY_KEY_STORAGE_FLUSH_INTERVAL,
+            reply_key_storage_gc_interval: DEFAULT_REPLY_KEY_STORAGE_GC_INTERVAL,
+            gateway_health_check_interval: DEFAULT_GATEWAY_HEALTH_CHECK_INTERVAL,
+            gateway_failover_threshold: DEFAULT_GATEWAY_FAILOVER_THRESHOLD,
+            bandwidth_redemption_threshold: REMAINING_BANDWIDTH_THRESHOLD,
+            key_rotation_interval: Duration::ZERO,
+            key_rotation_grace_period: DEFAULT_KEY_ROTATION_GRACE_PERIOD,
         }
     }
 }
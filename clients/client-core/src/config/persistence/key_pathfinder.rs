@@ -5,13 +5,14 @@ use crate::config::Config;
 use config::NymConfig;
 use std::path::{Path, PathBuf};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ClientKeyPathfinder {
     identity_private_key: PathBuf,
     identity_public_key: PathBuf,
     encryption_private_key: PathBuf,
     encryption_public_key: PathBuf,
     gateway_shared_key: PathBuf,
+    backup_gateway_ids: Vec<String>,
     ack_key: PathBuf,
 }
 
@@ -19,12 +20,18 @@ impl ClientKeyPathfinder {
     pub fn new(id: String) -> Self {
         let os_config_dir = dirs::config_dir().expect("no config directory known for this OS"); // grabs the OS default config dir
         let config_dir = os_config_dir.join("nym").join("clients").join(id);
+        ClientKeyPathfinder::new_in_directory(config_dir)
+    }
+
+    /// Creates the pathfinder with all of the keys stored directly in the specified directory.
+    pub fn new_in_directory(config_dir: PathBuf) -> Self {
         ClientKeyPathfinder {
             identity_private_key: config_dir.join("private_identity.pem"),
             identity_public_key: config_dir.join("public_identity.pem"),
             encryption_private_key: config_dir.join("public_encryption.pem"),
             encryption_public_key: config_dir.join("private_encryption.pem"),
             gateway_shared_key: config_dir.join("gateway_shared.pem"),
+            backup_gateway_ids: Vec::new(),
             ack_key: config_dir.join("ack_key.pem"),
         }
     }
@@ -36,6 +43,11 @@ impl ClientKeyPathfinder {
             encryption_private_key: config.get_private_encryption_key_file(),
             encryption_public_key: config.get_public_encryption_key_file(),
             gateway_shared_key: config.get_gateway_shared_key_file(),
+            backup_gateway_ids: config
+                .get_backup_gateway_endpoints()
+                .iter()
+                .map(|endpoint| endpoint.gateway_id.clone())
+                .collect(),
             ack_key: config.get_ack_key_file(),
         }
     }
@@ -60,7 +72,50 @@ impl ClientKeyPathfinder {
         &self.gateway_shared_key
     }
 
+    /// Identities of all backup gateways specified in the config the client should have
+    /// the shared keys for.
+    pub fn backup_gateway_ids(&self) -> &[String] {
+        &self.backup_gateway_ids
+    }
+
+    /// Keys shared with the backup gateways are stored next to the key of the primary gateway.
+    pub fn backup_gateway_shared_key(&self, gateway_id: &str) -> PathBuf {
+        self.gateway_shared_key
+            .with_file_name(format!("gateway_shared_{}.pem", gateway_id))
+    }
+
     pub fn ack_key(&self) -> &Path {
         &self.ack_key
     }
+
+    // keys replaced during the latest rotation are stored next to the current ones
+    fn previous_key(current_key: &Path) -> PathBuf {
+        let file_name = current_key
+            .file_name()
+            .map(|file_name| file_name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        current_key.with_file_name(format!("previous_{}", file_name))
+    }
+
+    pub fn previous_private_encryption_key(&self) -> PathBuf {
+        Self::previous_key(&self.encryption_private_key)
+    }
+
+    pub fn previous_public_encryption_key(&self) -> PathBuf {
+        Self::previous_key(&self.encryption_public_key)
+    }
+
+    pub fn previous_ack_key(&self) -> PathBuf {
+        Self::previous_key(&self.ack_key)
+    }
+
+    /// File recording when the keys were last rotated and until when the previous ones are valid.
+    pub fn key_rotation_state(&self) -> PathBuf {
+        self.ack_key.with_file_name("key_rotation.json")
+    }
+
+    /// File indicating the keys are currently in use by a running client.
+    pub fn running_client_lock(&self) -> PathBuf {
+        self.ack_key.with_file_name("client.lock")
+    }
 }
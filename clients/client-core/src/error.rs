@@ -0,0 +1,49 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::client::outbound_journal::OutboundJournalError;
+use crate::client::reply_key_storage::ReplyKeyStorageError;
+use gateway_client::error::GatewayClientError;
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+use topology::MixLayer;
+
+#[derive(Error, Debug)]
+pub enum ClientCoreError {
+    #[error("I/O error: {0}")]
+    IoError(#[from] io::Error),
+
+    #[error("Gateway client error: {0}")]
+    GatewayClientError(#[from] GatewayClientError),
+
+    #[error("Failed to establish connection with the gateway: {0}")]
+    GatewayConnectionError(GatewayClientError),
+
+    #[error("Failed to register with the gateway: {0}")]
+    GatewayRegistrationError(GatewayClientError),
+
+    #[error("Reply key storage error: {0}")]
+    ReplyKeyStorageError(#[from] ReplyKeyStorageError),
+
+    #[error("Outbound journal error: {0}")]
+    OutboundJournalError(#[from] OutboundJournalError),
+
+    #[error("The identity of the gateway is unknown or malformed")]
+    MalformedGatewayIdentity,
+
+    #[error("The current network topology seem to be insufficient to route any packets through")]
+    InsufficientNetworkTopology,
+
+    #[error("All of the mix nodes on the layers {0:?} are excluded from routing. Relax the node exclusions, keeping in mind that nodes with unknown locations are excluded whenever any countries are")]
+    NoEligibleMixnodes(Vec<MixLayer>),
+
+    #[error("Too many reply SURBs requested. Requested: {requested} and maximum is {max}")]
+    TooManyReplySurbs { requested: u8, max: u8 },
+
+    #[error("The mixnet client has already been shut down")]
+    ClientShutdown,
+
+    #[error("The client is running, as its process holds the lock {0}. Stop the client first")]
+    ClientRunning(PathBuf),
+}
@@ -19,13 +19,11 @@ use url::Url;
 
 use crate::{
     client::key_manager::KeyManager,
-    config::{persistence::key_pathfinder::ClientKeyPathfinder, Config},
+    config::{persistence::key_pathfinder::ClientKeyPathfinder, Config, GatewayEndpoint},
+    error::ClientCoreError,
 };
 
-pub async fn query_gateway_details(
-    validator_servers: Vec<Url>,
-    chosen_gateway_id: Option<&str>,
-) -> gateway::Node {
+async fn query_compatible_gateways(validator_servers: Vec<Url>) -> Vec<gateway::Node> {
     let validator_api = validator_servers
         .choose(&mut thread_rng())
         .expect("The list of validator apis is empty");
@@ -38,7 +36,14 @@ pub async fn query_gateway_details(
         .filter_map(|gateway| gateway.try_into().ok())
         .collect::<Vec<gateway::Node>>();
 
-    let filtered_gateways = valid_gateways.filter_by_version(env!("CARGO_PKG_VERSION"));
+    valid_gateways.filter_by_version(env!("CARGO_PKG_VERSION"))
+}
+
+pub async fn query_gateway_details(
+    validator_servers: Vec<Url>,
+    chosen_gateway_id: Option<&str>,
+) -> gateway::Node {
+    let filtered_gateways = query_compatible_gateways(validator_servers).await;
 
     // if we have chosen particular gateway - use it, otherwise choose a random one.
     // (remember that in active topology all gateways have at least 100 reputation so should
@@ -57,28 +62,100 @@ pub async fn query_gateway_details(
     }
 }
 
+/// Chooses up to `count` random gateways, other than the excluded ones, that the client could
+/// fail over to if its primary gateway stops responding.
+pub async fn query_backup_gateway_details(
+    validator_servers: Vec<Url>,
+    excluded_gateway_ids: &[String],
+    count: usize,
+) -> Vec<gateway::Node> {
+    let candidates = query_compatible_gateways(validator_servers)
+        .await
+        .into_iter()
+        .filter(|gateway| !excluded_gateway_ids.contains(&gateway.identity_key.to_base58_string()))
+        .collect::<Vec<_>>();
+
+    if candidates.len() < count {
+        log::warn!(
+            "Only {} out of {} requested backup gateways are available",
+            candidates.len(),
+            count
+        );
+    }
+
+    candidates
+        .choose_multiple(&mut thread_rng(), count)
+        .cloned()
+        .collect()
+}
+
 pub async fn register_with_gateway_and_store_keys<T>(
     gateway_details: gateway::Node,
     config: &Config<T>,
-) where
+) -> Result<(), ClientCoreError>
+where
     T: NymConfig,
 {
     let mut rng = OsRng;
     let mut key_manager = KeyManager::new(&mut rng);
 
-    let shared_keys = register_with_gateway(&gateway_details, key_manager.identity_keypair()).await;
+    let shared_keys =
+        register_with_gateway(&gateway_details, key_manager.identity_keypair()).await?;
     key_manager.insert_gateway_shared_key(shared_keys);
 
     let pathfinder = ClientKeyPathfinder::new_from_config(config);
-    key_manager
-        .store_keys(&pathfinder)
-        .expect("Failed to generated keys");
+    key_manager.store_keys(&pathfinder)?;
+    Ok(())
+}
+
+/// Registers with all of the provided backup gateways, putting the derived shared keys into the
+/// [`KeyManager`]. Gateways that could not be registered with are skipped.
+pub(crate) async fn register_with_backup_gateways(
+    key_manager: &mut KeyManager,
+    backup_gateways: Vec<gateway::Node>,
+) -> Vec<GatewayEndpoint> {
+    let mut registered = Vec::with_capacity(backup_gateways.len());
+    for gateway in backup_gateways {
+        match register_with_gateway(&gateway, key_manager.identity_keypair()).await {
+            Ok(shared_keys) => {
+                let endpoint = GatewayEndpoint::from(gateway);
+                key_manager
+                    .insert_backup_gateway_shared_key(endpoint.gateway_id.clone(), shared_keys);
+                registered.push(endpoint);
+            }
+            Err(err) => log::warn!(
+                "Failed to register with the backup gateway {} - {}",
+                gateway.identity_key.to_base58_string(),
+                err
+            ),
+        }
+    }
+    registered
 }
 
-async fn register_with_gateway(
+/// Registers the already initialised client with all of the provided backup gateways and stores
+/// the derived shared keys alongside the other keys. It returns the endpoints of the gateways
+/// that got successfully registered with, which should be put into the client config.
+pub async fn register_with_backup_gateways_and_store_keys<T>(
+    backup_gateways: Vec<gateway::Node>,
+    config: &Config<T>,
+) -> Result<Vec<GatewayEndpoint>, ClientCoreError>
+where
+    T: NymConfig,
+{
+    let pathfinder = ClientKeyPathfinder::new_from_config(config);
+    let mut key_manager = KeyManager::load_keys(&pathfinder)?;
+
+    let registered = register_with_backup_gateways(&mut key_manager, backup_gateways).await;
+    key_manager.store_keys(&pathfinder)?;
+
+    Ok(registered)
+}
+
+pub(crate) async fn register_with_gateway(
     gateway: &gateway::Node,
     our_identity: Arc<identity::KeyPair>,
-) -> Arc<SharedKeys> {
+) -> Result<Arc<SharedKeys>, ClientCoreError> {
     let timeout = Duration::from_millis(1500);
     let mut gateway_client = GatewayClient::new_init(
         gateway.clients_address(),
@@ -90,11 +167,12 @@ async fn register_with_gateway(
     gateway_client
         .establish_connection()
         .await
-        .expect("failed to establish connection with the gateway!");
-    gateway_client
+        .map_err(ClientCoreError::GatewayConnectionError)?;
+    let shared_keys = gateway_client
         .perform_initial_authentication()
         .await
-        .expect("failed to register with the gateway!")
+        .map_err(ClientCoreError::GatewayRegistrationError)?;
+    Ok(shared_keys)
 }
 
 pub fn show_address<T>(config: &Config<T>)
@@ -21,7 +21,7 @@ async fn send_message_and_get_response(
 }
 
 async fn get_self_address(ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> Recipient {
-    let self_address_request = ClientRequest::SelfAddress.serialize();
+    let self_address_request = ClientRequest::SelfAddress.serialize().unwrap();
     let response = send_message_and_get_response(ws_stream, self_address_request).await;
 
     match response {
@@ -42,11 +42,14 @@ async fn send_file_with_reply() {
     let send_request = ClientRequest::Send {
         recipient,
         message: read_data,
-        with_reply_surb: true,
+        num_reply_surbs: 1,
+        with_receipt: false,
+        headers: None,
     };
 
     println!("sending content of 'dummy_file' over the mix network...");
-    let response = send_message_and_get_response(&mut ws_stream, send_request.serialize()).await;
+    let response =
+        send_message_and_get_response(&mut ws_stream, send_request.serialize().unwrap()).await;
 
     let received = match response {
         ServerResponse::Received(received) => received,
@@ -59,14 +62,15 @@ async fn send_file_with_reply() {
     let reply_message = b"hello from reply SURB! - thanks for sending me the file!".to_vec();
     let reply_request = ClientRequest::Reply {
         message: reply_message.clone(),
-        reply_surb: received.reply_surb.unwrap(),
+        reply_surb: received.reply_surbs.into_iter().next().unwrap(),
     };
 
     println!(
         "sending {:?} (using reply SURB!) over the mix network...",
         String::from_utf8(reply_message).unwrap()
     );
-    let response = send_message_and_get_response(&mut ws_stream, reply_request.serialize()).await;
+    let response =
+        send_message_and_get_response(&mut ws_stream, reply_request.serialize().unwrap()).await;
     let received = match response {
         ServerResponse::Received(received) => received,
         _ => panic!("received an unexpected response!"),
@@ -90,11 +94,14 @@ async fn send_file_without_reply() {
     let send_request = ClientRequest::Send {
         recipient,
         message: read_data,
-        with_reply_surb: false,
+        num_reply_surbs: 0,
+        with_receipt: false,
+        headers: None,
     };
 
     println!("sending content of 'dummy_file' over the mix network...");
-    let response = send_message_and_get_response(&mut ws_stream, send_request.serialize()).await;
+    let response =
+        send_message_and_get_response(&mut ws_stream, send_request.serialize().unwrap()).await;
 
     let received = match response {
         ServerResponse::Received(received) => received,
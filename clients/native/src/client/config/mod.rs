@@ -7,10 +7,14 @@ pub use client_core::config::MISSING_VALUE;
 use config::defaults::DEFAULT_WEBSOCKET_LISTENING_PORT;
 use config::NymConfig;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
 
 mod template;
 
+// unless explicitly configured otherwise, only listen on local connections
+const DEFAULT_WEBSOCKET_LISTENING_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
 #[derive(Debug, Deserialize, PartialEq, Eq, Serialize, Clone, Copy)]
 #[serde(deny_unknown_fields)]
 pub enum SocketType {
@@ -83,6 +87,31 @@ impl Config {
         self
     }
 
+    pub fn with_listening_address(mut self, listening_address: IpAddr) -> Self {
+        self.socket.listening_address = listening_address;
+        self
+    }
+
+    pub fn with_auth_token<S: Into<String>>(mut self, auth_token: S) -> Self {
+        self.socket.auth_token = auth_token.into();
+        self
+    }
+
+    pub fn with_tls_certificate_file(mut self, tls_certificate_file: PathBuf) -> Self {
+        self.socket.tls_certificate_file = tls_certificate_file;
+        self
+    }
+
+    pub fn with_tls_private_key_file(mut self, tls_private_key_file: PathBuf) -> Self {
+        self.socket.tls_private_key_file = tls_private_key_file;
+        self
+    }
+
+    pub fn with_tls_client_ca_file(mut self, tls_client_ca_file: PathBuf) -> Self {
+        self.socket.tls_client_ca_file = tls_client_ca_file;
+        self
+    }
+
     // getters
     pub fn get_config_file_save_location(&self) -> PathBuf {
         self.config_directory().join(Self::config_file_name())
@@ -103,13 +132,66 @@ impl Config {
     pub fn get_listening_port(&self) -> u16 {
         self.socket.listening_port
     }
+
+    pub fn get_listening_address(&self) -> IpAddr {
+        self.socket.listening_address
+    }
+
+    /// Bearer token each websocket connection has to present during the handshake.
+    /// `None` if the connections are not authenticated.
+    pub fn get_auth_token(&self) -> Option<String> {
+        if self.socket.auth_token.is_empty() {
+            None
+        } else {
+            Some(self.socket.auth_token.clone())
+        }
+    }
+
+    fn non_empty_path(path: &Path) -> Option<PathBuf> {
+        if path.as_os_str().is_empty() {
+            None
+        } else {
+            Some(path.to_path_buf())
+        }
+    }
+
+    /// Certificate chain presented by the websocket listener. `None` if TLS is disabled.
+    pub fn get_tls_certificate_file(&self) -> Option<PathBuf> {
+        Self::non_empty_path(&self.socket.tls_certificate_file)
+    }
+
+    pub fn get_tls_private_key_file(&self) -> Option<PathBuf> {
+        Self::non_empty_path(&self.socket.tls_private_key_file)
+    }
+
+    /// Certificate of the authority whose signature is required on the certificates of the
+    /// websocket clients. `None` if the clients are not required to present any certificate.
+    pub fn get_tls_client_ca_file(&self) -> Option<PathBuf> {
+        Self::non_empty_path(&self.socket.tls_client_ca_file)
+    }
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq, Serialize)]
-#[serde(deny_unknown_fields)]
+#[serde(default, deny_unknown_fields)]
 pub struct Socket {
     socket_type: SocketType,
     listening_port: u16,
+
+    /// Address on which the websocket is listening.
+    listening_address: IpAddr,
+
+    /// If not empty, bearer token required in the `Authorization` header of the websocket handshake.
+    auth_token: String,
+
+    /// If not empty, path to the PEM encoded certificate chain used for accepting TLS connections.
+    tls_certificate_file: PathBuf,
+
+    /// Path to the PEM encoded private key corresponding to the TLS certificate.
+    tls_private_key_file: PathBuf,
+
+    /// If not empty, path to the PEM encoded certificate of the authority that has to have signed
+    /// the certificates presented by the clients.
+    tls_client_ca_file: PathBuf,
 }
 
 impl Default for Socket {
@@ -117,6 +199,11 @@ impl Default for Socket {
         Socket {
             socket_type: SocketType::WebSocket,
             listening_port: DEFAULT_WEBSOCKET_LISTENING_PORT,
+            listening_address: DEFAULT_WEBSOCKET_LISTENING_ADDRESS,
+            auth_token: String::new(),
+            tls_certificate_file: Default::default(),
+            tls_private_key_file: Default::default(),
+            tls_client_ca_file: Default::default(),
         }
     }
 }
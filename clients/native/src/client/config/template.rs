@@ -49,6 +49,21 @@ reply_encryption_key_store_path = '{{ client.reply_encryption_key_store_path }}'
 # Path to the database containing bandwidth credentials
 database_path = '{{ client.database_path }}'
 
+# Indicates whether fragments of sent messages should be recorded on disk until they are
+# acknowledged, so that they could be resent if the client gets restarted in the meantime.
+# The fragments are only resent until the message delivery deadline, or the reply key ttl
+# if there's no deadline, and replies sent using reply SURBs are never recorded.
+# Note that the fragments are stored unencrypted, so anyone able to read the journal file
+# can read the messages that are still in transit. The file is only accessible by its owner.
+enable_outbound_journal = {{ client.enable_outbound_journal }}
+
+# Path to the database containing fragments of sent messages that were not yet acknowledged.
+outbound_journal_path = '{{ client.outbound_journal_path }}'
+
+# Indicates whether the messages stored by the gateway while the client was offline should
+# only be retrieved on demand, rather than all of them being pushed as soon as the client connects.
+gateway_pull_mode = {{ client.gateway_pull_mode }}
+
 # Ethereum private key.
 eth_private_key = '{{ client.eth_private_key }}'
 
@@ -80,6 +95,15 @@ gateway_owner = '{{ client.gateway_endpoint.gateway_owner }}'
 # Address of the gateway listener to which all client requests should be sent.
 gateway_listener = '{{ client.gateway_endpoint.gateway_listener }}'
 
+# Gateways the client has also registered with, that it is going to switch to, in order,
+# if the primary gateway stops responding.
+{{#each client.backup_gateway_endpoints }}
+[[client.backup_gateway_endpoints]]
+gateway_id = '{{ this.gateway_id }}'
+gateway_owner = '{{ this.gateway_owner }}'
+gateway_listener = '{{ this.gateway_listener }}'
+{{/each}}
+
 
 
 ##### socket config options #####
@@ -93,6 +117,23 @@ socket_type = '{{ socket.socket_type }}'
 # will be listening for incoming requests
 listening_port = {{ socket.listening_port }}
 
+# the address on which the websocket is listening. Unless the client is meant to be reached
+# from other machines (or containers), it should be left as the loopback address
+listening_address = '{{ socket.listening_address }}'
+
+# if not empty, every websocket connection has to present this value as a bearer token
+# (i.e. 'Authorization: Bearer <token>' header) during the handshake
+auth_token = '{{ socket.auth_token }}'
+
+# if not empty, the websocket is only going to accept TLS connections using the following
+# PEM encoded certificate chain and private key
+tls_certificate_file = '{{ socket.tls_certificate_file }}'
+tls_private_key_file = '{{ socket.tls_private_key_file }}'
+
+# if not empty, the TLS clients have to present a certificate signed by the authority
+# whose PEM encoded certificate is stored in the following file
+tls_client_ca_file = '{{ socket.tls_client_ca_file }}'
+
 
 ##### logging configuration options #####
 
@@ -112,5 +153,13 @@ average_ack_delay = '{{ debug.average_ack_delay }}'
 loop_cover_traffic_average_delay = '{{ debug.loop_cover_traffic_average_delay }}'
 message_sending_average_delay = '{{ debug.message_sending_average_delay }}'
 
+# How often the encryption and ack keys are rotated while the client is running.
+# '0s' disables the rotation.
+key_rotation_interval = '{{ debug.key_rotation_interval }}'
+
+# For how long the keys replaced during the rotation are still used for processing
+# received messages and acks.
+key_rotation_grace_period = '{{ debug.key_rotation_grace_period }}'
+
 "#
 }
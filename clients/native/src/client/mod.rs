@@ -1,37 +1,28 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
-use client_core::client::cover_traffic_stream::LoopCoverTrafficStream;
-use client_core::client::inbound_messages::{
-    InputMessage, InputMessageReceiver, InputMessageSender,
-};
+use client_core::client::bandwidth_control::BandwidthStatisticsSnapshot;
+use client_core::client::base_client::BaseClient;
+use client_core::client::delivery_receipts::{DeliveryReceiptReceiver, DeliveryStatus};
+use client_core::client::gateway_failover::{config_file_persister, SelfAddressReceiver};
+use client_core::client::inbound_messages::{InputMessage, InputMessageSender};
 use client_core::client::key_manager::KeyManager;
-use client_core::client::mix_traffic::{
-    BatchMixMessageReceiver, BatchMixMessageSender, MixTrafficController,
-};
-use client_core::client::real_messages_control;
-use client_core::client::real_messages_control::RealMessagesController;
+use client_core::client::key_rotation::RunningClientLock;
+use client_core::client::mix_traffic::InboxRequester;
 use client_core::client::received_buffer::{
-    ReceivedBufferMessage, ReceivedBufferRequestReceiver, ReceivedBufferRequestSender,
-    ReceivedMessagesBufferController, ReconstructedMessagesReceiver,
-};
-use client_core::client::reply_key_storage::ReplyKeyStorage;
-use client_core::client::topology_control::{
-    TopologyAccessor, TopologyRefresher, TopologyRefresherConfig,
+    ReceivedBufferMessage, ReceivedBufferRequestSender, ReconstructedMessagesReceiver,
 };
+use client_core::client::topology_control::{TopologyAccessor, TopologyRefreshRequester};
+use client_core::client::traffic_control::{PacketStatisticsSnapshot, TrafficRatesControl};
 use client_core::config::persistence::key_pathfinder::ClientKeyPathfinder;
-use crypto::asymmetric::identity;
+use client_core::error::ClientCoreError;
 use futures::channel::mpsc;
-use gateway_client::bandwidth::BandwidthController;
-use gateway_client::{
-    AcknowledgementReceiver, AcknowledgementSender, GatewayClient, MixnetMessageReceiver,
-    MixnetMessageSender,
-};
 use log::*;
 use nymsphinx::addressing::clients::Recipient;
-use nymsphinx::addressing::nodes::NodeIdentity;
 use nymsphinx::anonymous_replies::ReplySurb;
+use nymsphinx::headers::MAX_REPLY_SURBS;
 use nymsphinx::receiver::ReconstructedMessage;
+use std::net::SocketAddr;
 
 use crate::client::config::{Config, SocketType};
 use crate::websocket;
@@ -43,8 +34,8 @@ pub struct NymClient {
     /// key filepaths, etc.
     config: Config,
 
-    /// KeyManager object containing smart pointers to all relevant keys used by the client.
-    key_manager: KeyManager,
+    /// All of the components of the client that are shared with the socks5 client.
+    base_client: BaseClient,
 
     /// Channel used for transforming 'raw' messages into sphinx packets and sending them
     /// through the mix network.
@@ -54,226 +45,171 @@ pub struct NymClient {
     /// Channel used for obtaining reconstructed messages received from the mix network.
     /// It is only available if the client started with the websocket listener disabled.
     receive_tx: Option<ReconstructedMessagesReceiver>,
+
+    /// Marks the keys as being in use, so that they would not be rotated from under the client
+    /// while it's running.
+    _running_lock: Option<RunningClientLock>,
 }
 
 impl NymClient {
     pub fn new(config: Config) -> Self {
         let pathfinder = ClientKeyPathfinder::new_from_config(config.get_base());
         let key_manager = KeyManager::load_keys(&pathfinder).expect("failed to load stored keys");
+        let base_client = BaseClient::new(config.get_base(), key_manager)
+            .expect("The identity of the gateway is unknown - did you run `nym-client` init?")
+            .with_gateway_config_persister(config_file_persister(
+                config.get_base().get_id(),
+                Config::get_base_mut,
+            ));
 
         NymClient {
             config,
-            key_manager,
+            base_client,
             input_tx: None,
             receive_tx: None,
+            _running_lock: None,
         }
     }
 
     pub fn as_mix_recipient(&self) -> Recipient {
-        Recipient::new(
-            *self.key_manager.identity_keypair().public_key(),
-            *self.key_manager.encryption_keypair().public_key(),
-            // TODO: below only works under assumption that gateway address == gateway id
-            // (which currently is true)
-            NodeIdentity::from_base58_string(self.config.get_base().get_gateway_id()).unwrap(),
-        )
+        self.base_client.address()
     }
 
-    // future constantly pumping loop cover traffic at some specified average rate
-    // the pumped traffic goes to the MixTrafficController
-    fn start_cover_traffic_stream(
-        &self,
-        topology_accessor: TopologyAccessor,
-        mix_tx: BatchMixMessageSender,
-    ) {
-        info!("Starting loop cover traffic stream...");
-
-        LoopCoverTrafficStream::new(
-            self.key_manager.ack_key(),
-            self.config.get_base().get_average_ack_delay(),
-            self.config.get_base().get_average_packet_delay(),
-            self.config
-                .get_base()
-                .get_loop_cover_traffic_average_delay(),
-            mix_tx,
-            self.as_mix_recipient(),
-            topology_accessor,
-        )
-        .start();
+    /// Returns the channel on which the new address of this client is announced whenever
+    /// it changes, that is whenever the client switches to one of its backup gateways or
+    /// rotates its encryption key (see the `key_rotation_interval` config option).
+    pub fn address_updates(&self) -> SelfAddressReceiver {
+        self.base_client.address_updates()
     }
 
-    fn start_real_traffic_controller(
-        &self,
-        topology_accessor: TopologyAccessor,
-        reply_key_storage: ReplyKeyStorage,
-        ack_receiver: AcknowledgementReceiver,
-        input_receiver: InputMessageReceiver,
-        mix_sender: BatchMixMessageSender,
-    ) {
-        let controller_config = real_messages_control::Config::new(
-            self.key_manager.ack_key(),
-            self.config.get_base().get_ack_wait_multiplier(),
-            self.config.get_base().get_ack_wait_addition(),
-            self.config.get_base().get_average_ack_delay(),
-            self.config.get_base().get_message_sending_average_delay(),
-            self.config.get_base().get_average_packet_delay(),
-            self.as_mix_recipient(),
-        );
-
-        info!("Starting real traffic stream...");
+    // the messages replayed from the outbound journal after a restart no longer have any websocket
+    // client waiting for their delivery receipts, so the receipts are only logged
+    fn start_replayed_receipts_logger(&self, mut receipt_receiver: DeliveryReceiptReceiver) {
+        use futures::StreamExt;
 
-        RealMessagesController::new(
-            controller_config,
-            ack_receiver,
-            input_receiver,
-            mix_sender,
-            topology_accessor,
-            reply_key_storage,
-        )
-        .start();
+        tokio::spawn(async move {
+            while let Some(receipt) = receipt_receiver.next().await {
+                match receipt.status {
+                    DeliveryStatus::Delivered => info!("Replayed {}", receipt),
+                    _ => warn!("Replayed {}", receipt),
+                }
+            }
+        });
     }
 
-    // buffer controlling all messages fetched from provider
-    // required so that other components would be able to use them (say the websocket)
-    fn start_received_messages_buffer_controller(
+    fn start_websocket_listener(
         &self,
-        query_receiver: ReceivedBufferRequestReceiver,
-        mixnet_receiver: MixnetMessageReceiver,
-        reply_key_storage: ReplyKeyStorage,
+        buffer_requester: ReceivedBufferRequestSender,
+        msg_input: InputMessageSender,
+        topology_accessor: TopologyAccessor,
+        topology_refresh_requester: TopologyRefreshRequester,
+        inbox_requester: InboxRequester,
     ) {
-        info!("Starting received messages buffer controller...");
-        ReceivedMessagesBufferController::new(
-            self.key_manager.encryption_keypair(),
-            query_receiver,
-            mixnet_receiver,
-            reply_key_storage,
-        )
-        .start()
-    }
+        info!("Starting websocket listener...");
 
-    async fn start_gateway_client(
-        &mut self,
-        mixnet_message_sender: MixnetMessageSender,
-        ack_sender: AcknowledgementSender,
-    ) -> GatewayClient {
-        let gateway_id = self.config.get_base().get_gateway_id();
-        if gateway_id.is_empty() {
-            panic!("The identity of the gateway is unknown - did you run `nym-client` init?")
-        }
-        let gateway_owner = self.config.get_base().get_gateway_owner();
-        if gateway_owner.is_empty() {
-            panic!("The owner of the gateway is unknown - did you run `nym-client` init?")
-        }
-        let gateway_address = self.config.get_base().get_gateway_listener();
-        if gateway_address.is_empty() {
-            panic!("The address of the gateway is unknown - did you run `nym-client` init?")
+        let (received_messages_fanout, subscription_requester) =
+            websocket::ReceivedMessagesFanout::new(buffer_requester);
+        received_messages_fanout.start();
+
+        let gateway_endpoints = std::iter::once(self.config.get_base().get_gateway_endpoint())
+            .chain(self.config.get_base().get_backup_gateway_endpoints())
+            .cloned()
+            .collect();
+        let client_state = websocket::ClientState {
+            bandwidth_statistics: self.base_client.bandwidth_statistics().clone(),
+            packet_statistics: self.base_client.packet_statistics().clone(),
+            traffic_rates: self.base_client.traffic_rates().clone(),
+            topology_accessor,
+            topology_refresh_requester,
+            inbox_requester,
+            gateway_endpoints,
+        };
+
+        let mut websocket_handler = websocket::Handler::new(
+            msg_input,
+            subscription_requester,
+            self.base_client.address_updates(),
+            client_state,
+        );
+        if let Some(auth_token) = self.config.get_auth_token() {
+            websocket_handler = websocket_handler.with_auth_token(auth_token);
         }
 
-        let gateway_identity = identity::PublicKey::from_base58_string(gateway_id)
-            .expect("provided gateway id is invalid!");
-
-        #[cfg(feature = "coconut")]
-        let bandwidth_controller = BandwidthController::new(
-            credential_storage::initialise_storage(self.config.get_base().get_database_path())
-                .await,
-            self.config.get_base().get_validator_api_endpoints(),
-        );
-        #[cfg(not(feature = "coconut"))]
-        let bandwidth_controller = BandwidthController::new(
-            credential_storage::initialise_storage(self.config.get_base().get_database_path())
-                .await,
-            self.config.get_base().get_eth_endpoint(),
-            self.config.get_base().get_eth_private_key(),
-        )
-        .expect("Could not create bandwidth controller");
-
-        let mut gateway_client = GatewayClient::new(
-            gateway_address,
-            self.key_manager.identity_keypair(),
-            gateway_identity,
-            gateway_owner,
-            Some(self.key_manager.gateway_shared_key()),
-            mixnet_message_sender,
-            ack_sender,
-            self.config.get_base().get_gateway_response_timeout(),
-            Some(bandwidth_controller),
+        let address = SocketAddr::new(
+            self.config.get_listening_address(),
+            self.config.get_listening_port(),
         );
-
-        if self.config.get_base().get_disabled_credentials_mode() {
-            gateway_client.set_disabled_credentials_mode(true)
+        let mut listener = websocket::Listener::new(address);
+
+        match (
+            self.config.get_tls_certificate_file(),
+            self.config.get_tls_private_key_file(),
+        ) {
+            (Some(certificate_file), Some(private_key_file)) => {
+                let tls_acceptor = websocket::tls::load_tls_acceptor(
+                    &certificate_file,
+                    &private_key_file,
+                    self.config.get_tls_client_ca_file().as_deref(),
+                )
+                .expect("failed to load the TLS certificate of the websocket");
+                listener = listener.with_tls(tls_acceptor);
+            }
+            (None, None) => {
+                if self.config.get_tls_client_ca_file().is_some() {
+                    panic!("client certificates can't be verified without the websocket using TLS")
+                }
+            }
+            _ => panic!("both the TLS certificate and its private key have to be provided"),
         }
-        gateway_client
-            .authenticate_and_start()
-            .await
-            .expect("could not authenticate and start up the gateway connection");
-
-        gateway_client
-    }
 
-    // future responsible for periodically polling directory server and updating
-    // the current global view of topology
-    async fn start_topology_refresher(&mut self, topology_accessor: TopologyAccessor) {
-        let topology_refresher_config = TopologyRefresherConfig::new(
-            self.config.get_base().get_validator_api_endpoints(),
-            self.config.get_base().get_topology_refresh_rate(),
-            env!("CARGO_PKG_VERSION").to_string(),
-        );
-        let mut topology_refresher =
-            TopologyRefresher::new(topology_refresher_config, topology_accessor);
-        // before returning, block entire runtime to refresh the current network view so that any
-        // components depending on topology would see a non-empty view
-        info!("Obtaining initial network topology");
-        topology_refresher.refresh().await;
-
-        // TODO: a slightly more graceful termination here
-        if !topology_refresher.is_topology_routable().await {
-            panic!(
-                "The current network topology seem to be insufficient to route any packets through\
-                - check if enough nodes and a gateway are online"
-            );
+        let is_authenticated = self.config.get_auth_token().is_some()
+            || self.config.get_tls_client_ca_file().is_some();
+        if !address.ip().is_loopback() && !is_authenticated {
+            warn!("The websocket is reachable from other machines, yet the connections are not authenticated! Consider setting an auth token");
         }
 
-        info!("Starting topology refresher...");
-        topology_refresher.start();
+        listener.start(websocket_handler);
     }
 
-    // controller for sending sphinx packets to mixnet (either real traffic or cover traffic)
-    // TODO: if we want to send control messages to gateway_client, this CAN'T take the ownership
-    // over it. Perhaps GatewayClient needs to be thread-shareable or have some channel for
-    // requests?
-    fn start_mix_traffic_controller(
-        &mut self,
-        mix_rx: BatchMixMessageReceiver,
-        gateway_client: GatewayClient,
-    ) {
-        info!("Starting mix traffic controller...");
-        MixTrafficController::new(mix_rx, gateway_client).start();
+    /// Returns the handle allowing to adjust the rates at which the real and cover packets
+    /// are sent, for example to switch into a low power mode.
+    pub fn traffic_rates(&self) -> &TrafficRatesControl {
+        self.base_client.traffic_rates()
     }
 
-    fn start_websocket_listener(
-        &self,
-        buffer_requester: ReceivedBufferRequestSender,
-        msg_input: InputMessageSender,
-    ) {
-        info!("Starting websocket listener...");
-
-        let websocket_handler =
-            websocket::Handler::new(msg_input, buffer_requester, self.as_mix_recipient());
+    /// Returns the numbers of real and cover packets sent so far.
+    pub fn packet_statistics(&self) -> PacketStatisticsSnapshot {
+        self.base_client.packet_statistics().snapshot()
+    }
 
-        websocket::Listener::new(self.config.get_listening_port()).start(websocket_handler);
+    /// Returns the bandwidth remaining at the gateway and the rate at which it's being consumed.
+    pub fn bandwidth_statistics(&self) -> BandwidthStatisticsSnapshot {
+        self.base_client.bandwidth_statistics().snapshot()
     }
 
     /// EXPERIMENTAL DIRECT RUST API
     /// It's untested and there are absolutely no guarantees about it (but seems to have worked
     /// well enough in local tests)
-    pub fn send_message(&mut self, recipient: Recipient, message: Vec<u8>, with_reply_surb: bool) {
-        let input_msg = InputMessage::new_fresh(recipient, message, with_reply_surb);
+    pub fn send_message(
+        &mut self,
+        recipient: Recipient,
+        message: Vec<u8>,
+        num_reply_surbs: u8,
+    ) -> Result<(), ClientCoreError> {
+        if num_reply_surbs > MAX_REPLY_SURBS {
+            return Err(ClientCoreError::TooManyReplySurbs {
+                requested: num_reply_surbs,
+                max: MAX_REPLY_SURBS,
+            });
+        }
+        let input_msg = InputMessage::new_fresh(recipient, message, num_reply_surbs);
 
         self.input_tx
             .as_ref()
             .expect("start method was not called before!")
             .unbounded_send(input_msg)
             .unwrap();
+        Ok(())
     }
 
     /// EXPERIMENTAL DIRECT RUST API
@@ -308,7 +244,10 @@ impl NymClient {
 
     /// blocking version of `start` method. Will run forever (or until SIGINT is sent)
     pub async fn run_forever(&mut self) {
-        self.start().await;
+        if let Err(err) = self.start().await {
+            error!("Failed to start the client - {}", err);
+            return;
+        }
         if let Err(e) = tokio::signal::ctrl_c().await {
             error!(
                 "There was an error while capturing SIGINT - {:?}. We will terminate regardless",
@@ -321,83 +260,40 @@ impl NymClient {
         );
     }
 
-    pub async fn start(&mut self) {
+    pub async fn start(&mut self) -> Result<(), ClientCoreError> {
         info!("Starting nym client");
-        // channels for inter-component communication
-        // TODO: make the channels be internally created by the relevant components
-        // rather than creating them here, so say for example the buffer controller would create the request channels
-        // and would allow anyone to clone the sender channel
-
-        // sphinx_message_sender is the transmitter for any component generating sphinx packets that are to be sent to the mixnet
-        // they are used by cover traffic stream and real traffic stream
-        // sphinx_message_receiver is the receiver used by MixTrafficController that sends the actual traffic
-        let (sphinx_message_sender, sphinx_message_receiver) = mpsc::unbounded();
-
-        // unwrapped_sphinx_sender is the transmitter of mixnet messages received from the gateway
-        // unwrapped_sphinx_receiver is the receiver for said messages - used by ReceivedMessagesBuffer
-        let (mixnet_messages_sender, mixnet_messages_receiver) = mpsc::unbounded();
-
-        // used for announcing connection or disconnection of a channel for pushing re-assembled messages to
-        let (received_buffer_request_sender, received_buffer_request_receiver) = mpsc::unbounded();
-
-        // channels responsible for controlling real messages
-        let (input_sender, input_receiver) = mpsc::unbounded::<InputMessage>();
-
-        // channels responsible for controlling ack messages
-        let (ack_sender, ack_receiver) = mpsc::unbounded();
-        let shared_topology_accessor = TopologyAccessor::new();
-
-        let reply_key_storage =
-            ReplyKeyStorage::load(self.config.get_base().get_reply_encryption_key_store_path())
-                .expect("Failed to load reply key storage!");
-
-        // the components are started in very specific order. Unless you know what you are doing,
-        // do not change that.
-        self.start_topology_refresher(shared_topology_accessor.clone())
-            .await;
-        self.start_received_messages_buffer_controller(
-            received_buffer_request_receiver,
-            mixnet_messages_receiver,
-            reply_key_storage.clone(),
-        );
-
-        let gateway_client = self
-            .start_gateway_client(mixnet_messages_sender, ack_sender)
-            .await;
-
-        self.start_mix_traffic_controller(sphinx_message_receiver, gateway_client);
-        self.start_real_traffic_controller(
-            shared_topology_accessor.clone(),
-            reply_key_storage,
-            ack_receiver,
-            input_receiver,
-            sphinx_message_sender.clone(),
-        );
-
-        self.start_cover_traffic_stream(shared_topology_accessor, sphinx_message_sender);
+        let started = self.base_client.start(self.config.get_base()).await?;
+        self._running_lock = Some(started.running_lock);
+        self.start_replayed_receipts_logger(started.receipt_receiver);
 
         match self.config.get_socket_type() {
-            SocketType::WebSocket => {
-                self.start_websocket_listener(received_buffer_request_sender, input_sender)
-            }
+            SocketType::WebSocket => self.start_websocket_listener(
+                started.received_buffer_request_sender,
+                started.input_sender,
+                started.topology_accessor,
+                started.topology_refresh_requester,
+                started.inbox_requester,
+            ),
             SocketType::None => {
                 // if we did not start the socket, it means we're running (supposedly) in the native mode
                 // and hence we should announce 'ourselves' to the buffer
                 let (reconstructed_sender, reconstructed_receiver) = mpsc::unbounded();
 
                 // tell the buffer to start sending stuff to us
-                received_buffer_request_sender
+                started
+                    .received_buffer_request_sender
                     .unbounded_send(ReceivedBufferMessage::ReceiverAnnounce(
                         reconstructed_sender,
                     ))
                     .expect("the buffer request failed!");
 
                 self.receive_tx = Some(reconstructed_receiver);
-                self.input_tx = Some(input_sender);
+                self.input_tx = Some(started.input_sender);
             }
         }
 
         info!("Client startup finished!");
         info!("The address of this client is: {}", self.as_mix_recipient());
+        Ok(())
     }
 }
@@ -4,6 +4,7 @@
 use clap::{App, Arg, ArgMatches};
 use client_core::config::GatewayEndpoint;
 use config::NymConfig;
+use std::process;
 
 use crate::client::config::Config;
 use crate::commands::override_config;
@@ -33,6 +34,11 @@ pub fn command_args<'a, 'b>() -> clap::App<'a, 'b> {
             .help("Force register gateway. WARNING: this will overwrite any existing keys for the given id, potentially causing loss of access.")
             .takes_value(false)
         )
+        .arg(Arg::with_name("backup-gateways")
+            .long("backup-gateways")
+            .help("Number of additional gateways to register with. If the primary gateway stops responding, the client is going to switch to one of them, changing its address.")
+            .takes_value(true)
+        )
         .arg(Arg::with_name("validators")
                  .long("validators")
                  .help("Comma separated list of rest endpoints of the validators")
@@ -48,6 +54,33 @@ pub fn command_args<'a, 'b>() -> clap::App<'a, 'b> {
             .help("Port for the socket (if applicable) to listen on in all subsequent runs")
             .takes_value(true)
         )
+        .arg(Arg::with_name("host")
+            .long("host")
+            .help("Address on which the socket (if applicable) should listen. Defaults to the loopback address")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("auth-token")
+            .long("auth-token")
+            .help("Bearer token every websocket connection has to present during the handshake")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .help("Path to the PEM encoded certificate chain used for accepting TLS websocket connections")
+            .takes_value(true)
+            .requires("tls-key")
+        )
+        .arg(Arg::with_name("tls-key")
+            .long("tls-key")
+            .help("Path to the PEM encoded private key of the TLS certificate")
+            .takes_value(true)
+            .requires("tls-cert")
+        )
+        .arg(Arg::with_name("tls-client-ca")
+            .long("tls-client-ca")
+            .help("Path to the PEM encoded certificate of the authority that has to have signed the certificates of the websocket clients")
+            .takes_value(true)
+        )
         .arg(Arg::with_name("fastmode")
             .long("fastmode")
             .hidden(true) // this will prevent this flag from being displayed in `--help`
@@ -115,6 +148,17 @@ pub async fn execute(matches: ArgMatches<'static>) {
     let gateway = setup_gateway(id, register_gateway, user_chosen_gateway_id, &config).await;
     config.get_base_mut().with_gateway_endpoint(gateway);
 
+    let backup_gateways_count = matches.value_of("backup-gateways").map(|count| {
+        count
+            .parse()
+            .expect("the number of backup gateways must be a number")
+    });
+    let backup_gateways =
+        setup_backup_gateways(id, register_gateway, backup_gateways_count, &config).await;
+    config
+        .get_base_mut()
+        .with_backup_gateway_endpoints(backup_gateways);
+
     let config_save_location = config.get_config_file_save_location();
     config
         .save_to_file(None)
@@ -122,6 +166,9 @@ pub async fn execute(matches: ArgMatches<'static>) {
 
     println!("Saved configuration file to {:?}", config_save_location);
     println!("Using gateway: {}", config.get_base().get_gateway_id());
+    for backup_gateway in config.get_base().get_backup_gateway_endpoints() {
+        println!("Using backup gateway: {}", backup_gateway.gateway_id);
+    }
     log::debug!("Gateway id: {}", config.get_base().get_gateway_id());
     log::debug!("Gateway owner: {}", config.get_base().get_gateway_owner());
     log::debug!(
@@ -152,8 +199,15 @@ async fn setup_gateway(
 
         // Registering with gateway by setting up and writing shared keys to disk
         log::trace!("Registering gateway");
-        client_core::init::register_with_gateway_and_store_keys(gateway.clone(), config.get_base())
-            .await;
+        if let Err(err) = client_core::init::register_with_gateway_and_store_keys(
+            gateway.clone(),
+            config.get_base(),
+        )
+        .await
+        {
+            eprintln!("Failed to register with the gateway - {}", err);
+            process::exit(1);
+        }
         println!("Saved all generated keys");
 
         gateway.into()
@@ -185,3 +239,48 @@ async fn setup_gateway(
         }
     }
 }
+
+async fn setup_backup_gateways(
+    id: &str,
+    registered_new_keys: bool,
+    requested_count: Option<usize>,
+    config: &Config,
+) -> Vec<GatewayEndpoint> {
+    match requested_count {
+        Some(count) if count > 0 => {
+            println!("Configuring {} backup gateway(s)", count);
+            let backup_gateways = client_core::init::query_backup_gateway_details(
+                config.get_base().get_validator_api_endpoints(),
+                &[config.get_base().get_gateway_id()],
+                count,
+            )
+            .await;
+
+            let registered = client_core::init::register_with_backup_gateways_and_store_keys(
+                backup_gateways,
+                config.get_base(),
+            )
+            .await
+            .unwrap_or_else(|err| {
+                eprintln!("Failed to register with the backup gateways - {}", err);
+                process::exit(1)
+            });
+            println!(
+                "Saved keys shared with {} backup gateway(s)",
+                registered.len()
+            );
+            registered
+        }
+        Some(_) => Vec::new(),
+        // keys shared with the backup gateways are only valid for the identity they were derived for
+        None if registered_new_keys => Vec::new(),
+        None => Config::load_from_file(Some(id))
+            .map(|existing_config| {
+                existing_config
+                    .get_base()
+                    .get_backup_gateway_endpoints()
+                    .to_vec()
+            })
+            .unwrap_or_default(),
+    }
+}
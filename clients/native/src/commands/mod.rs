@@ -18,6 +18,7 @@ pub(crate) const DEFAULT_ETH_PRIVATE_KEY: &str =
     "0000000000000000000000000000000000000000000000000000000000000001";
 
 pub(crate) mod init;
+pub(crate) mod rotate_keys;
 pub(crate) mod run;
 pub(crate) mod upgrade;
 
@@ -32,6 +33,13 @@ fn parse_validators(raw: &str) -> Vec<Url> {
         .collect()
 }
 
+fn parse_list(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
 pub(crate) fn override_config(mut config: Config, matches: &ArgMatches<'_>) -> Config {
     if let Some(raw_validators) = matches.value_of("validators") {
         config
@@ -51,6 +59,70 @@ pub(crate) fn override_config(mut config: Config, matches: &ArgMatches<'_>) -> C
         config = config.with_port(port.unwrap());
     }
 
+    if let Some(host) = matches.value_of("host") {
+        let listening_address = host
+            .parse()
+            .expect("the provided listening address is invalid");
+        config = config.with_listening_address(listening_address);
+    }
+
+    if let Some(auth_token) = matches.value_of("auth-token") {
+        config = config.with_auth_token(auth_token);
+    }
+
+    if let Some(tls_certificate_file) = matches.value_of("tls-cert") {
+        config = config.with_tls_certificate_file(tls_certificate_file.into());
+    }
+
+    if let Some(tls_private_key_file) = matches.value_of("tls-key") {
+        config = config.with_tls_private_key_file(tls_private_key_file.into());
+    }
+
+    if let Some(tls_client_ca_file) = matches.value_of("tls-client-ca") {
+        config = config.with_tls_client_ca_file(tls_client_ca_file.into());
+    }
+
+    if let Some(topology_file) = matches.value_of("topology-file") {
+        config
+            .get_base_mut()
+            .set_topology_file(topology_file.into());
+    }
+
+    if matches.is_present("pin-topology") {
+        config.get_base_mut().set_pinned_topology(true);
+    }
+
+    if let Some(topology_dump_file) = matches.value_of("dump-topology") {
+        config
+            .get_base_mut()
+            .set_topology_dump_file(topology_dump_file.into());
+    }
+
+    if let Some(route_selection_policy) = matches.value_of("route-selection") {
+        let policy = route_selection_policy
+            .parse()
+            .expect("the provided route selection policy is invalid");
+        config.get_base_mut().set_route_selection_policy(policy);
+    }
+
+    if let Some(excluded_mixnodes) = matches.value_of("exclude-mixnodes") {
+        config
+            .get_base_mut()
+            .set_excluded_mixnode_identities(parse_list(excluded_mixnodes));
+    }
+
+    if let Some(excluded_owners) = matches.value_of("exclude-owners") {
+        config
+            .get_base_mut()
+            .set_excluded_mixnode_owners(parse_list(excluded_owners));
+    }
+
+    if let Some(excluded_countries) = matches.value_of("exclude-countries") {
+        config
+            .get_base_mut()
+            .set_excluded_mixnode_countries(parse_list(excluded_countries));
+    }
+
     #[cfg(not(feature = "coconut"))]
     if let Some(eth_endpoint) = matches.value_of(ETH_ENDPOINT_ARG_NAME) {
         config.get_base_mut().with_eth_endpoint(eth_endpoint);
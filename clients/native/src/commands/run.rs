@@ -43,6 +43,68 @@ pub fn command_args<'a, 'b>() -> clap::App<'a, 'b> {
             .long("port")
             .help("Port for the socket (if applicable) to listen on")
             .takes_value(true)
+        )
+        .arg(Arg::with_name("host")
+            .long("host")
+            .help("Address on which the socket (if applicable) should listen. Defaults to the loopback address")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("auth-token")
+            .long("auth-token")
+            .help("Bearer token every websocket connection has to present during the handshake")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .help("Path to the PEM encoded certificate chain used for accepting TLS websocket connections")
+            .takes_value(true)
+            .requires("tls-key")
+        )
+        .arg(Arg::with_name("tls-key")
+            .long("tls-key")
+            .help("Path to the PEM encoded private key of the TLS certificate")
+            .takes_value(true)
+            .requires("tls-cert")
+        )
+        .arg(Arg::with_name("tls-client-ca")
+            .long("tls-client-ca")
+            .help("Path to the PEM encoded certificate of the authority that has to have signed the certificates of the websocket clients")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("topology-file")
+            .long("topology-file")
+            .help("Path to a JSON file containing the network topology to use instead of the one obtained from the validator API")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("pin-topology")
+            .long("pin-topology")
+            .help("Keep on using the initially obtained network topology without ever refreshing it")
+        )
+        .arg(Arg::with_name("dump-topology")
+            .long("dump-topology")
+            .help("Path to a JSON file to which the currently used network topology is going to be written after every refresh")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("route-selection")
+            .long("route-selection")
+            .help("How the mixnodes used for the packet routes are chosen")
+            .possible_values(&["uniform", "stake", "uptime", "latency"])
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("exclude-mixnodes")
+            .long("exclude-mixnodes")
+            .help("Comma separated list of identity keys of mixnodes that should never be used for any route")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("exclude-owners")
+            .long("exclude-owners")
+            .help("Comma separated list of addresses of operators whose mixnodes should never be used for any route")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("exclude-countries")
+            .long("exclude-countries")
+            .help("Comma separated list of countries (full names, matched case-insensitively against the announced locations) whose mixnodes should never be used for any route. Mixnodes with an unknown location are excluded as well")
+            .takes_value(true)
         );
     #[cfg(feature = "eth")]
     #[cfg(not(feature = "coconut"))]
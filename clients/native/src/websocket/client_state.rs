@@ -0,0 +1,34 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use client_core::client::{
+    bandwidth_control::BandwidthStatistics,
+    mix_traffic::InboxRequester,
+    topology_control::{TopologyAccessor, TopologyRefreshRequester},
+    traffic_control::{PacketStatistics, TrafficRatesControl},
+};
+use client_core::config::GatewayEndpoint;
+
+/// Handles to the internal state of the client exposed to the websocket connections
+/// through the control requests.
+#[derive(Clone)]
+pub(crate) struct ClientState {
+    pub(crate) bandwidth_statistics: BandwidthStatistics,
+    pub(crate) packet_statistics: PacketStatistics,
+    pub(crate) traffic_rates: TrafficRatesControl,
+    pub(crate) topology_accessor: TopologyAccessor,
+    pub(crate) topology_refresh_requester: TopologyRefreshRequester,
+    pub(crate) inbox_requester: InboxRequester,
+
+    /// The primary gateway followed by the backup ones, in the order they would be switched to.
+    pub(crate) gateway_endpoints: Vec<GatewayEndpoint>,
+}
+
+impl ClientState {
+    /// Finds the endpoint of the gateway with the specified identity.
+    pub(crate) fn gateway_endpoint(&self, gateway_id: &str) -> Option<&GatewayEndpoint> {
+        self.gateway_endpoints
+            .iter()
+            .find(|endpoint| endpoint.gateway_id == gateway_id)
+    }
+}
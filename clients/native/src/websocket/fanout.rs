@@ -0,0 +1,250 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use super::reassembly::StreamReassembler;
+use client_core::client::received_buffer::{
+    ReceivedBufferMessage, ReceivedBufferRequestSender, ReconstructedMessagesReceiver,
+    ReconstructedMessagesSender,
+};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use log::*;
+use nymsphinx::receiver::ReconstructedMessage;
+use std::collections::HashMap;
+use tokio::task::JoinHandle;
+
+/// Identifier of a single websocket connection subscribed to the received messages.
+pub(crate) type SubscriberId = u64;
+
+pub(crate) type SubscriptionRequestSender = mpsc::UnboundedSender<SubscriptionRequest>;
+type SubscriptionRequestReceiver = mpsc::UnboundedReceiver<SubscriptionRequest>;
+
+pub(crate) enum SubscriptionRequest {
+    /// Start pushing all received messages to the provided channel.
+    Subscribe(SubscriberId, ReconstructedMessagesSender),
+
+    /// Stop pushing messages to the specified subscriber, for example because its connection was closed.
+    Unsubscribe(SubscriberId),
+}
+
+/// Shares the messages received from the mix network between all connected websocket clients.
+/// Every subscriber gets its own copy of each message, however the reply SURBs attached to them
+/// are only handed to the longest connected subscriber, as each of them can only be used once.
+///
+/// The fanout only registers itself with the received messages buffer while there's at least
+/// a single subscriber, so that, as before, the messages are buffered while nobody is connected.
+pub(crate) struct ReceivedMessagesFanout {
+    buffer_requester: ReceivedBufferRequestSender,
+    subscription_receiver: SubscriptionRequestReceiver,
+    subscribers: HashMap<SubscriberId, ReconstructedMessagesSender>,
+
+    messages_sender: ReconstructedMessagesSender,
+    messages_receiver: ReconstructedMessagesReceiver,
+
+    // messages that were already on their way from the buffer when the last subscriber disconnected
+    pending: Vec<ReconstructedMessage>,
+
+    // puts the chunks of incoming streams back in order before they're handed to the subscribers
+    reassembler: StreamReassembler,
+}
+
+impl ReceivedMessagesFanout {
+    pub(crate) fn new(
+        buffer_requester: ReceivedBufferRequestSender,
+    ) -> (Self, SubscriptionRequestSender) {
+        let (subscription_sender, subscription_receiver) = mpsc::unbounded();
+        let (messages_sender, messages_receiver) = mpsc::unbounded();
+
+        (
+            ReceivedMessagesFanout {
+                buffer_requester,
+                subscription_receiver,
+                subscribers: HashMap::new(),
+                messages_sender,
+                messages_receiver,
+                pending: Vec::new(),
+                reassembler: StreamReassembler::default(),
+            },
+            subscription_sender,
+        )
+    }
+
+    fn subscribe(&mut self, id: SubscriberId, sender: ReconstructedMessagesSender) {
+        if !self.pending.is_empty() {
+            let pending = std::mem::take(&mut self.pending);
+            if let Err(err) = sender.unbounded_send(pending) {
+                // the connection must have been closed straight away
+                self.pending = err.into_inner();
+                return;
+            }
+        }
+
+        self.subscribers.insert(id, sender);
+        debug!(
+            "Websocket connection {} has subscribed to the received messages. There are {} subscribers now",
+            id,
+            self.subscribers.len()
+        );
+
+        if self.subscribers.len() == 1 {
+            // tell the buffer to start sending stuff to us
+            self.buffer_requester
+                .unbounded_send(ReceivedBufferMessage::ReceiverAnnounce(
+                    self.messages_sender.clone(),
+                ))
+                .expect("the buffer request failed!");
+        }
+    }
+
+    fn unsubscribe(&mut self, id: SubscriberId) {
+        if self.subscribers.remove(&id).is_none() {
+            return;
+        }
+        debug!(
+            "Websocket connection {} has unsubscribed from the received messages. There are {} subscribers left",
+            id,
+            self.subscribers.len()
+        );
+
+        if self.subscribers.is_empty() {
+            // nobody is listening anymore - let the buffer hold on to any new messages
+            self.buffer_requester
+                .unbounded_send(ReceivedBufferMessage::ReceiverDisconnect)
+                .expect("the buffer request failed!");
+        }
+    }
+
+    fn handle_subscription_request(&mut self, request: SubscriptionRequest) {
+        match request {
+            SubscriptionRequest::Subscribe(id, sender) => self.subscribe(id, sender),
+            SubscriptionRequest::Unsubscribe(id) => self.unsubscribe(id),
+        }
+    }
+
+    fn fan_out(&mut self, messages: Vec<ReconstructedMessage>) {
+        let messages = self.reassembler.reassemble(messages);
+        if messages.is_empty() {
+            return;
+        }
+
+        if self.subscribers.is_empty() {
+            self.pending.extend(messages);
+            return;
+        }
+
+        // the reply SURBs are single-use, so if two subscribers replied using the same one,
+        // the replies would be linkable and the second one would not even be decryptable
+        let without_surbs: Vec<_> = messages
+            .iter()
+            .map(|message| ReconstructedMessage {
+                message: message.message.clone(),
+                reply_surbs: Vec::new(),
+                headers: message.headers.clone(),
+            })
+            .collect();
+
+        // the ids are assigned in order, so the lowest one belongs to the longest connected subscriber
+        let mut subscriber_ids: Vec<_> = self.subscribers.keys().copied().collect();
+        subscriber_ids.sort_unstable();
+
+        let mut with_surbs = Some(messages);
+        let mut closed = Vec::new();
+        for id in subscriber_ids {
+            let sender = &self.subscribers[&id];
+            match with_surbs.take() {
+                Some(messages) => {
+                    if let Err(err) = sender.unbounded_send(messages) {
+                        // give the surbs to the next subscriber instead
+                        with_surbs = Some(err.into_inner());
+                        closed.push(id);
+                    }
+                }
+                None => {
+                    if sender.unbounded_send(without_surbs.clone()).is_err() {
+                        closed.push(id);
+                    }
+                }
+            }
+        }
+
+        // the connection handler is going to unsubscribe on its own,
+        // but there's no point in sending anything more to it in the meantime
+        for id in closed {
+            warn!(
+                "Websocket connection {} is no longer receiving messages",
+                id
+            );
+            self.unsubscribe(id)
+        }
+    }
+
+    async fn run(&mut self) {
+        loop {
+            tokio::select! {
+                request = self.subscription_receiver.next() => match request {
+                    Some(request) => self.handle_subscription_request(request),
+                    // the websocket listener is gone
+                    None => break,
+                },
+                // we're holding a sender ourselves so the channel can't have been closed
+                messages = self.messages_receiver.next() => self.fan_out(messages.unwrap()),
+            }
+        }
+    }
+
+    pub(crate) fn start(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move { self.run().await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nymsphinx::anonymous_replies::ReplySurb;
+
+    fn message_with_surb() -> ReconstructedMessage {
+        let reply_surb_string = "CjfVbHbfAjbC3W1BvNHGXmM8KNAnDNYGaHMLqVDxRYeo352csAihstup9bvqXam4dTWgfHak6KYwL9STaxWJ47E8XFZbSEvs7hEsfCkxr6K9WJuSBPK84GDDEvad8ZAuMCoaXsAd5S2Lj9a5eYyzG4SL1jHzhSMni55LyJwumxo1ZTGZNXggxw1RREosvyzNrW9Rsi3owyPqLCwXpiei2tHZty8w8midVvg8vDa7ZEJD842CLv8D4ohynSG7gDpqTrhkRaqYAuz7dzqNbMXLJRM7v823Jn16fA1L7YQxmcaUdUigyRSgTdb4i9ebiLGSyJ1iDe6Acz613PQZh6Ua3bZ2zVKq3dSycpDm9ngarRK4zJrAaUxRkdih8YzW3BY4nL9eqkfKA4N1TWCLaRU7zpSaf8yMEwrAZReU3d5zLV8c5KBfa2w8R5anhQeBojduZEGEad8kkHuKU52Zg93FeWHvH1qgZaEJMHH4nN7gKXz9mvWDhYwyF4vt3Uy2NhCHC3N5pL1gMme27YcoPcTEia1fxKZtnt6rtEozzTrAgCJGswigkFbkafiV5QaJwLKTUxtzhkZ57eEuLPte9UvJHzhhXUQ2CV7R2BUkJjYZy3Zsx6YYvdYWiAFFkWUwNEGA4QpShUHciBfsQVHQ7pN41YcyYUhbywQDFnTVgEmdUZ1XCBi3gyK5U3tDQmFzP1u9m3mWrUA8qB9mRDE7ptNDm5c3c1458L6uXLUth7sdMaa1Was5LCmCdmNDtvNpCDAEt1in6q6mrZFR85aCSU9b1baNGwZoCqPpPvydkVe63gXWoi8ebvdyxARrqACFrSB3ZdY3uJBw8CTMNkKK6MvcefMkSVVsbLd36TQAtYSCqrpiMc5dQuKcEu5QfciwvWYXYx8WFNAgKwP2mv49KCTvfozNDUCbjzDwSx92Zv5zjG8HbFpB13bY9UZGeyTPvv7gGxCzjGjJGbW6FRAheRQaaje5fUgCNM95Tv7wBmAMRHHFgWafeK1sdFH7dtCX9u898HucGTaboSKLsVh8J78gbbkHErwjMh7y9YRkceq5TTYS5da4kHnyNKYWSbxgZrmFg44XGKoeYcqoHB3XTZrdsf7F5fFeNwnihkmADvhAcaxXUmVqq4rQFZH84a1iC3WBWXYcqiZH2L7ujGWV7mMDT4HBEerDYjc8rNY4xGTPfivCrBCJW1i14aqW8xRdsdgTM88eTksvC3WPJLJ7iMzfKXeL7fMW1Ek6QGyQtLBW98vEESpdcDg6DeZ5rMz6VqjTGGqcCaFGfHoqtfxMDaBAEsyQ8h7XDX6dg1wq9wH6j4Tw7Tj1MEv1b8uj5NJkozZdzVdYA2QyE2Dp8vuurQG6uVdTDNww2d88RBQ8sVgjxN8gR45y4woJLhFAaNTAtrY6wDTxyXST13ni6oyqdYxjFVk9Am4v3DzH7Y2K8iRVSHfTk4FRbPULyaeK6wt2anvMJH1XdvVRgc14h67MnBxMgMD1UFk8AErN7CDj26fppe3c5G6KozJe4cSqQUGbBjVzBnrHCruqrfZBn5hNZHTV37bQiomqhRQXohxhuKEnNrGbAe1xNvJr9X";
+        ReconstructedMessage {
+            message: b"foomp".to_vec(),
+            reply_surbs: vec![ReplySurb::from_base58_string(reply_surb_string).unwrap()],
+            headers: None,
+        }
+    }
+
+    #[test]
+    fn reply_surbs_are_only_given_to_a_single_subscriber() {
+        let (buffer_requester, _buffer_receiver) = mpsc::unbounded();
+        let (mut fanout, _) = ReceivedMessagesFanout::new(buffer_requester);
+
+        let (first_sender, mut first_receiver) = mpsc::unbounded();
+        let (second_sender, mut second_receiver) = mpsc::unbounded();
+        let (third_sender, mut third_receiver) = mpsc::unbounded();
+        fanout.subscribe(1, second_sender);
+        fanout.subscribe(0, first_sender);
+        fanout.subscribe(2, third_sender);
+
+        fanout.fan_out(vec![message_with_surb()]);
+
+        let received = first_receiver.try_next().unwrap().unwrap();
+        assert_eq!(received[0].message, b"foomp".to_vec());
+        assert_eq!(received[0].reply_surbs.len(), 1);
+        for receiver in [&mut second_receiver, &mut third_receiver] {
+            let received = receiver.try_next().unwrap().unwrap();
+            assert_eq!(received[0].message, b"foomp".to_vec());
+            assert!(received[0].reply_surbs.is_empty());
+        }
+
+        // if the subscriber holding the surbs is gone, they're given to the next one
+        drop(first_receiver);
+        fanout.fan_out(vec![message_with_surb()]);
+        assert_eq!(
+            second_receiver.try_next().unwrap().unwrap()[0]
+                .reply_surbs
+                .len(),
+            1
+        );
+        assert!(third_receiver.try_next().unwrap().unwrap()[0]
+            .reply_surbs
+            .is_empty());
+    }
+}
@@ -1,25 +1,55 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
+use super::client_state::ClientState;
+use super::fanout::{SubscriberId, SubscriptionRequest, SubscriptionRequestSender};
 use client_core::client::{
-    inbound_messages::{InputMessage, InputMessageSender},
-    received_buffer::{
-        ReceivedBufferMessage, ReceivedBufferRequestSender, ReconstructedMessagesReceiver,
+    delivery_receipts::{
+        DeliveryReceipt, DeliveryReceiptReceiver, DeliveryReceiptSender, DeliveryStatus,
     },
+    gateway_failover::SelfAddressReceiver,
+    inbound_messages::{InputMessage, InputMessageSender},
+    mix_traffic::InboxRequest,
+    received_buffer::ReconstructedMessagesReceiver,
+    traffic_control::{self, TrafficRates},
 };
-use futures::channel::mpsc;
+use futures::channel::{mpsc, oneshot};
 use futures::{SinkExt, StreamExt};
+use gateway_client::error::GatewayClientError;
 use log::*;
 use nymsphinx::addressing::clients::Recipient;
 use nymsphinx::anonymous_replies::ReplySurb;
+use nymsphinx::headers::{MessageHeaders, StreamFrame, MAX_REPLY_SURBS};
 use nymsphinx::receiver::ReconstructedMessage;
-use tokio::net::TcpStream;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_tungstenite::{
-    accept_async,
-    tungstenite::{protocol::Message as WsMessage, Error as WsError},
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{header::AUTHORIZATION, StatusCode},
+        protocol::Message as WsMessage,
+        Error as WsError,
+    },
     WebSocketStream,
 };
-use websocket_requests::{requests::ClientRequest, responses::ServerResponse};
+use websocket_requests::{
+    error::{Error, ErrorKind},
+    requests::{ClientRequest, TrafficMode},
+    responses::ServerResponse,
+};
+
+/// Underlying stream of a websocket connection, either a plain TCP or a TLS one.
+pub(crate) trait ConnectionStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> ConnectionStream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// Number of real packets waiting to be sent above which new stream chunks are rejected
+/// until the client catches up.
+const MAX_PENDING_STREAM_PACKETS: u64 = 1000;
 
 enum ReceivedResponseType {
     Binary,
@@ -32,12 +62,42 @@ impl Default for ReceivedResponseType {
     }
 }
 
+// data sent in chunks, without ever being held in its entirety by the client
+struct OutboundStream {
+    recipient: Recipient,
+    num_reply_surbs: u8,
+    // identifies the stream to the recipient. Unlike the id known to the websocket client,
+    // it must not collide with the streams of any other sender
+    wire_id: u64,
+    next_sequence: u64,
+    chunks: u64,
+    bytes: u64,
+}
+
+impl OutboundStream {
+    fn next_frame(&mut self, end: bool) -> StreamFrame {
+        let frame = StreamFrame {
+            stream_id: self.wire_id,
+            sequence: self.next_sequence,
+            end,
+        };
+        self.next_sequence += 1;
+        frame
+    }
+}
+
 pub(crate) struct Handler {
     msg_input: InputMessageSender,
-    buffer_requester: ReceivedBufferRequestSender,
-    self_full_address: Recipient,
-    socket: Option<WebSocketStream<TcpStream>>,
+    subscription_requester: SubscriptionRequestSender,
+    self_full_address: SelfAddressReceiver,
+    client_state: ClientState,
+    auth_token: Option<String>,
+    connection_id: Option<SubscriberId>,
+    socket: Option<WebSocketStream<Box<dyn ConnectionStream>>>,
     received_response_type: ReceivedResponseType,
+    receipt_sender: Option<DeliveryReceiptSender>,
+    open_streams: HashMap<u64, OutboundStream>,
+    next_stream_id: u64,
 }
 
 // clone is used to use handler on a new connection, which initially is `None`
@@ -45,34 +105,95 @@ impl Clone for Handler {
     fn clone(&self) -> Self {
         Handler {
             msg_input: self.msg_input.clone(),
-            buffer_requester: self.buffer_requester.clone(),
-            self_full_address: self.self_full_address,
+            subscription_requester: self.subscription_requester.clone(),
+            self_full_address: self.self_full_address.clone(),
+            client_state: self.client_state.clone(),
+            auth_token: self.auth_token.clone(),
+            connection_id: None,
             socket: None,
             received_response_type: Default::default(),
+            receipt_sender: None,
+            open_streams: HashMap::new(),
+            next_stream_id: 0,
         }
     }
 }
 
 impl Drop for Handler {
     fn drop(&mut self) {
-        self.buffer_requester
-            .unbounded_send(ReceivedBufferMessage::ReceiverDisconnect)
-            .expect("the buffer request failed!")
+        // only handlers of actual connections have subscribed to the received messages
+        if let Some(connection_id) = self.connection_id {
+            // the fanout might have already been stopped if the whole client is shutting down
+            let _ = self
+                .subscription_requester
+                .unbounded_send(SubscriptionRequest::Unsubscribe(connection_id));
+        }
     }
 }
 
 impl Handler {
     pub(crate) fn new(
         msg_input: InputMessageSender,
-        buffer_requester: ReceivedBufferRequestSender,
-        self_full_address: Recipient,
+        subscription_requester: SubscriptionRequestSender,
+        self_full_address: SelfAddressReceiver,
+        client_state: ClientState,
     ) -> Self {
         Handler {
             msg_input,
-            buffer_requester,
+            subscription_requester,
             self_full_address,
+            client_state,
+            auth_token: None,
+            connection_id: None,
             socket: None,
             received_response_type: Default::default(),
+            receipt_sender: None,
+            open_streams: HashMap::new(),
+            next_stream_id: 0,
+        }
+    }
+
+    /// Requires every connection to present the specified bearer token during the websocket handshake.
+    #[must_use]
+    pub(crate) fn with_auth_token(mut self, auth_token: String) -> Self {
+        self.auth_token = Some(auth_token);
+        self
+    }
+
+    // compares the tokens in constant time, so that the expected one couldn't be guessed byte by byte
+    fn is_valid_token(expected: &str, presented: &str) -> bool {
+        expected.len() == presented.len()
+            && expected
+                .bytes()
+                .zip(presented.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+
+    fn authenticate_handshake(
+        auth_token: Option<&str>,
+        request: &Request,
+    ) -> Result<(), ErrorResponse> {
+        let expected = match auth_token {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+
+        let presented = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        match presented {
+            Some(presented) if Self::is_valid_token(expected, presented) => Ok(()),
+            _ => {
+                warn!("Rejected websocket connection with missing or invalid bearer token");
+                let mut response =
+                    ErrorResponse::new(Some("missing or invalid bearer token".to_string()));
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+                Err(response)
+            }
         }
     }
 
@@ -80,13 +201,36 @@ impl Handler {
         &mut self,
         recipient: Recipient,
         message: Vec<u8>,
-        with_reply_surb: bool,
+        num_reply_surbs: u8,
+        with_receipt: bool,
+        headers: Option<MessageHeaders>,
     ) -> Option<ServerResponse> {
+        if num_reply_surbs > MAX_REPLY_SURBS {
+            return Some(ServerResponse::new_error(format!(
+                "too many reply SURBs requested. Requested: {} and maximum is {}",
+                num_reply_surbs, MAX_REPLY_SURBS
+            )));
+        }
+
         // the ack control is now responsible for chunking, etc.
-        let input_msg = InputMessage::new_fresh(recipient, message, with_reply_surb);
+        let (mut input_msg, message_id) =
+            InputMessage::new_fresh_with_id(recipient, message, num_reply_surbs);
+
+        if with_receipt {
+            // the sender is always set once the websocket connection is established
+            let receipt_sender = self.receipt_sender.clone().unwrap();
+            input_msg = input_msg.with_receipt_sender(receipt_sender);
+        }
+        if let Some(headers) = headers {
+            input_msg = input_msg.with_headers(headers);
+        }
         self.msg_input.unbounded_send(input_msg).unwrap();
 
-        None
+        if with_receipt {
+            Some(ServerResponse::MessageSent { message_id })
+        } else {
+            None
+        }
     }
 
     fn handle_reply(&mut self, reply_surb: ReplySurb, message: Vec<u8>) -> Option<ServerResponse> {
@@ -101,25 +245,338 @@ impl Handler {
     }
 
     fn handle_self_address(&self) -> ServerResponse {
-        ServerResponse::SelfAddress(self.self_full_address)
+        ServerResponse::SelfAddress(*self.self_full_address.borrow())
+    }
+
+    fn handle_bandwidth(&self) -> ServerResponse {
+        let statistics = self.client_state.bandwidth_statistics.snapshot();
+        ServerResponse::Bandwidth {
+            remaining: statistics.remaining,
+            consumed: statistics.consumed,
+            burn_rate: statistics.burn_rate,
+        }
+    }
+
+    fn handle_open_stream(&mut self, recipient: Recipient, num_reply_surbs: u8) -> ServerResponse {
+        if num_reply_surbs > MAX_REPLY_SURBS {
+            return ServerResponse::new_error(format!(
+                "too many reply SURBs requested. Requested: {} and maximum is {}",
+                num_reply_surbs, MAX_REPLY_SURBS
+            ));
+        }
+
+        let stream_id = self.next_stream_id;
+        self.next_stream_id += 1;
+
+        debug!("Opened stream {} to {}", stream_id, recipient);
+        self.open_streams.insert(
+            stream_id,
+            OutboundStream {
+                recipient,
+                num_reply_surbs,
+                wire_id: OsRng.next_u64(),
+                next_sequence: 0,
+                chunks: 0,
+                bytes: 0,
+            },
+        );
+
+        ServerResponse::StreamOpened { stream_id }
+    }
+
+    fn handle_stream_chunk(&mut self, stream_id: u64, data: Vec<u8>) -> ServerResponse {
+        let stream = match self.open_streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return ServerResponse::new_error(format!("stream {} is not open", stream_id)),
+        };
+
+        let pending_packets = self.client_state.packet_statistics.pending_real_packets();
+        if pending_packets > MAX_PENDING_STREAM_PACKETS {
+            // the chunk is not consumed, so the websocket client can simply retry it later
+            return ServerResponse::Error(Error::new(
+                ErrorKind::Overloaded,
+                format!(
+                    "there are {} packets waiting to be sent. Retry the chunk once the client has caught up",
+                    pending_packets
+                ),
+            ));
+        }
+
+        stream.chunks += 1;
+        stream.bytes += data.len() as u64;
+
+        // every chunk goes through the ack control on its own, so the client never has to
+        // hold more than a single chunk of the stream in memory. The reply SURBs are only
+        // attached to the final frame, rather than being repeated for every chunk
+        let frame = stream.next_frame(false);
+        let input_msg =
+            InputMessage::new_fresh(stream.recipient, data, 0).with_headers(MessageHeaders {
+                stream: Some(frame),
+                ..Default::default()
+            });
+        self.msg_input.unbounded_send(input_msg).unwrap();
+
+        // note that the chunk is split into packets asynchronously, so the value might not
+        // include it just yet
+        ServerResponse::StreamChunkAccepted {
+            stream_id,
+            pending_packets,
+        }
+    }
+
+    fn handle_close_stream(&mut self, stream_id: u64) -> ServerResponse {
+        match self.open_streams.remove(&stream_id) {
+            Some(mut stream) => {
+                // let the recipient know there's nothing more to wait for
+                let frame = stream.next_frame(true);
+                let input_msg =
+                    InputMessage::new_fresh(stream.recipient, Vec::new(), stream.num_reply_surbs)
+                        .with_headers(MessageHeaders {
+                            stream: Some(frame),
+                            ..Default::default()
+                        });
+                self.msg_input.unbounded_send(input_msg).unwrap();
+
+                debug!(
+                    "Closed stream {} after sending {} chunks ({} bytes)",
+                    stream_id, stream.chunks, stream.bytes
+                );
+                ServerResponse::StreamClosed {
+                    stream_id,
+                    chunks: stream.chunks,
+                    bytes: stream.bytes,
+                }
+            }
+            None => ServerResponse::new_error(format!("stream {} is not open", stream_id)),
+        }
+    }
+
+    fn handle_gateway_info(&self) -> ServerResponse {
+        let gateway_id = self.self_full_address.borrow().gateway().to_base58_string();
+        match self.client_state.gateway_endpoint(&gateway_id) {
+            Some(endpoint) => ServerResponse::GatewayInfo {
+                gateway_id: endpoint.gateway_id.clone(),
+                gateway_owner: endpoint.gateway_owner.clone(),
+                gateway_listener: endpoint.gateway_listener.clone(),
+            },
+            None => ServerResponse::new_error(format!(
+                "gateway {} is not present in the client configuration",
+                gateway_id
+            )),
+        }
+    }
+
+    async fn handle_topology(&mut self) -> ServerResponse {
+        let topology = match self.client_state.topology_accessor.current_topology().await {
+            Some(topology) => topology,
+            None => return ServerResponse::new_error("the network topology is not available yet"),
+        };
+
+        let mut layers: Vec<_> = topology.mixes().iter().collect();
+        layers.sort_by_key(|(layer, _)| **layer);
+        let mixnodes = layers
+            .into_iter()
+            .flat_map(|(layer, nodes)| {
+                nodes
+                    .iter()
+                    .map(move |node| (*layer, node.identity_key.to_base58_string()))
+            })
+            .collect();
+        let gateways = topology
+            .gateways()
+            .iter()
+            .map(|gateway| gateway.identity_key.to_base58_string())
+            .collect();
+
+        ServerResponse::Topology { mixnodes, gateways }
     }
 
-    fn handle_request(&mut self, request: ClientRequest) -> Option<ServerResponse> {
+    fn handle_pending_acks(&self) -> ServerResponse {
+        let statistics = self.client_state.packet_statistics.snapshot();
+        ServerResponse::PendingAcks {
+            pending_acks: statistics.pending_acks,
+            pending_messages: statistics.pending_messages,
+            pending_packets: statistics.pending_real_packets(),
+        }
+    }
+
+    fn handle_cover_traffic(&self) -> ServerResponse {
+        let rates = self.client_state.traffic_rates.current_rates();
+        let statistics = self.client_state.packet_statistics.snapshot();
+        ServerResponse::CoverTraffic {
+            loop_cover_traffic_average_delay_ms: rates.loop_cover_traffic_average_delay.as_millis()
+                as u64,
+            message_sending_average_delay_ms: rates.message_sending_average_delay.as_millis()
+                as u64,
+            loop_cover_packets_sent: statistics.loop_cover_packets_sent,
+            out_queue_cover_packets_sent: statistics.out_queue_cover_packets_sent,
+            real_packets_sent: statistics.real_packets_sent,
+        }
+    }
+
+    fn handle_set_traffic_rates(
+        &self,
+        loop_cover_traffic_average_delay_ms: u64,
+        message_sending_average_delay_ms: u64,
+    ) -> ServerResponse {
+        if loop_cover_traffic_average_delay_ms == 0 || message_sending_average_delay_ms == 0 {
+            return ServerResponse::new_error(
+                "the average delays between packets must be non-zero",
+            );
+        }
+
+        self.client_state.traffic_rates.set_rates(TrafficRates::new(
+            Duration::from_millis(loop_cover_traffic_average_delay_ms),
+            Duration::from_millis(message_sending_average_delay_ms),
+        ));
+        self.handle_cover_traffic()
+    }
+
+    fn handle_set_traffic_mode(&self, mode: TrafficMode) -> ServerResponse {
+        let mode = match mode {
+            TrafficMode::Normal => traffic_control::TrafficMode::Normal,
+            TrafficMode::LowPower => traffic_control::TrafficMode::LowPower,
+            TrafficMode::HighAnonymity => traffic_control::TrafficMode::HighAnonymity,
+        };
+        self.client_state.traffic_rates.set_mode(mode);
+        self.handle_cover_traffic()
+    }
+
+    async fn handle_refresh_topology(&mut self) -> ServerResponse {
+        let (responder, routable) = oneshot::channel();
+        if self
+            .client_state
+            .topology_refresh_requester
+            .unbounded_send(responder)
+            .is_err()
+        {
+            return ServerResponse::new_error(
+                "the network topology is pinned and can't be refreshed",
+            );
+        }
+
+        match routable.await {
+            Ok(routable) => ServerResponse::TopologyRefreshed { routable },
+            Err(_) => {
+                ServerResponse::new_error("the network topology is no longer being refreshed")
+            }
+        }
+    }
+
+    // forwards the request to the mix traffic controller, which owns the gateway connection,
+    // and waits for the result
+    async fn forward_inbox_request<T>(
+        &mut self,
+        make_request: impl FnOnce(oneshot::Sender<Result<T, GatewayClientError>>) -> InboxRequest,
+        into_response: impl FnOnce(T) -> ServerResponse,
+    ) -> ServerResponse {
+        let (responder, result) = oneshot::channel();
+        if self
+            .client_state
+            .inbox_requester
+            .unbounded_send(make_request(responder))
+            .is_err()
+        {
+            return ServerResponse::new_error("the gateway connection is no longer being handled");
+        }
+
+        match result.await {
+            Ok(Ok(value)) => into_response(value),
+            Ok(Err(err)) => {
+                ServerResponse::new_error(format!("the gateway request has failed - {}", err))
+            }
+            Err(_) => {
+                ServerResponse::new_error("the gateway connection is no longer being handled")
+            }
+        }
+    }
+
+    async fn handle_inbox_count(&mut self) -> ServerResponse {
+        self.forward_inbox_request(InboxRequest::Count, |count| ServerResponse::InboxCount {
+            count,
+        })
+        .await
+    }
+
+    async fn handle_fetch_inbox(
+        &mut self,
+        start_after: Option<i64>,
+        page_size: u32,
+        remove: bool,
+    ) -> ServerResponse {
+        self.forward_inbox_request(
+            |responder| InboxRequest::Fetch {
+                start_after,
+                page_size,
+                remove,
+                responder,
+            },
+            |(ids, next_start_after)| ServerResponse::InboxPage {
+                ids,
+                next_start_after,
+            },
+        )
+        .await
+    }
+
+    async fn handle_delete_inbox(&mut self, ids: Vec<i64>) -> ServerResponse {
+        self.forward_inbox_request(
+            |responder| InboxRequest::Delete { ids, responder },
+            |removed| ServerResponse::InboxDeleted { removed },
+        )
+        .await
+    }
+
+    async fn handle_request(&mut self, request: ClientRequest) -> Option<ServerResponse> {
         match request {
             ClientRequest::Send {
                 recipient,
                 message,
-                with_reply_surb,
-            } => self.handle_send(recipient, message, with_reply_surb),
+                num_reply_surbs,
+                with_receipt,
+                headers,
+            } => self.handle_send(recipient, message, num_reply_surbs, with_receipt, headers),
             ClientRequest::Reply {
                 message,
                 reply_surb,
             } => self.handle_reply(reply_surb, message),
             ClientRequest::SelfAddress => Some(self.handle_self_address()),
+            ClientRequest::Bandwidth => Some(self.handle_bandwidth()),
+            ClientRequest::OpenStream {
+                recipient,
+                num_reply_surbs,
+            } => Some(self.handle_open_stream(recipient, num_reply_surbs)),
+            ClientRequest::StreamChunk { stream_id, data } => {
+                Some(self.handle_stream_chunk(stream_id, data))
+            }
+            ClientRequest::CloseStream { stream_id } => Some(self.handle_close_stream(stream_id)),
+            ClientRequest::GatewayInfo => Some(self.handle_gateway_info()),
+            ClientRequest::Topology => Some(self.handle_topology().await),
+            ClientRequest::PendingAcks => Some(self.handle_pending_acks()),
+            ClientRequest::CoverTraffic => Some(self.handle_cover_traffic()),
+            ClientRequest::SetTrafficRates {
+                loop_cover_traffic_average_delay_ms,
+                message_sending_average_delay_ms,
+            } => Some(self.handle_set_traffic_rates(
+                loop_cover_traffic_average_delay_ms,
+                message_sending_average_delay_ms,
+            )),
+            ClientRequest::SetTrafficMode { mode } => Some(self.handle_set_traffic_mode(mode)),
+            ClientRequest::RefreshTopology => Some(self.handle_refresh_topology().await),
+            ClientRequest::InboxCount => Some(self.handle_inbox_count().await),
+            ClientRequest::FetchInbox {
+                start_after,
+                page_size,
+                remove,
+            } => Some(
+                self.handle_fetch_inbox(start_after, page_size, remove)
+                    .await,
+            ),
+            ClientRequest::DeleteInbox { ids } => Some(self.handle_delete_inbox(ids).await),
         }
     }
 
-    fn handle_text_message(&mut self, msg: String) -> Option<WsMessage> {
+    async fn handle_text_message(&mut self, msg: String) -> Option<WsMessage> {
         debug!("Handling text message request");
         trace!("Content: {:?}", msg);
 
@@ -128,13 +585,13 @@ impl Handler {
 
         let response = match client_request {
             Err(err) => Some(ServerResponse::Error(err)),
-            Ok(req) => self.handle_request(req),
+            Ok(req) => self.handle_request(req).await,
         };
 
         response.map(|resp| WsMessage::text(resp.into_text()))
     }
 
-    fn handle_binary_message(&mut self, msg: Vec<u8>) -> Option<WsMessage> {
+    async fn handle_binary_message(&mut self, msg: Vec<u8>) -> Option<WsMessage> {
         debug!("Handling binary message request");
 
         self.received_response_type = ReceivedResponseType::Binary;
@@ -142,19 +599,19 @@ impl Handler {
 
         let response = match client_request {
             Err(err) => Some(ServerResponse::Error(err)),
-            Ok(req) => self.handle_request(req),
+            Ok(req) => self.handle_request(req).await,
         };
 
         response.map(|resp| WsMessage::Binary(resp.into_binary()))
     }
 
-    fn handle_ws_request(&mut self, raw_request: WsMessage) -> Option<WsMessage> {
+    async fn handle_ws_request(&mut self, raw_request: WsMessage) -> Option<WsMessage> {
         // apparently tungstenite auto-handles ping/pong/close messages so for now let's ignore
         // them and let's test that claim. If that's not the case, just copy code from
         // old version of this file.
         match raw_request {
-            WsMessage::Text(text_message) => self.handle_text_message(text_message),
-            WsMessage::Binary(binary_message) => self.handle_binary_message(binary_message),
+            WsMessage::Text(text_message) => self.handle_text_message(text_message).await,
+            WsMessage::Binary(binary_message) => self.handle_binary_message(binary_message).await,
             _ => None,
         }
     }
@@ -207,6 +664,59 @@ impl Handler {
             .await
     }
 
+    async fn push_websocket_delivery_receipt(
+        &mut self,
+        receipt: DeliveryReceipt,
+    ) -> Result<(), WsError> {
+        let response = match receipt.status {
+            DeliveryStatus::Delivered => ServerResponse::Delivered {
+                message_id: receipt.message_id,
+            },
+            DeliveryStatus::GaveUp {
+                retransmissions,
+                failed_fragments,
+            } => ServerResponse::DeliveryFailed {
+                message_id: receipt.message_id,
+                retransmissions,
+                failed_fragments: failed_fragments.len() as u32,
+            },
+            DeliveryStatus::DeadlineExceeded { failed_fragments } => {
+                ServerResponse::DeliveryExpired {
+                    message_id: receipt.message_id,
+                    failed_fragments: failed_fragments.len() as u32,
+                }
+            }
+            // nothing was sent, so there was nothing to retransmit either
+            DeliveryStatus::NotSent => ServerResponse::DeliveryFailed {
+                message_id: receipt.message_id,
+                retransmissions: 0,
+                failed_fragments: 0,
+            },
+        };
+
+        self.push_websocket_unsolicited_response(response).await
+    }
+
+    // unlike other responses, our address can change if we switch to a backup gateway or rotate
+    // the encryption key, so the client is notified about it without having to ask
+    async fn push_websocket_self_address(&mut self) -> Result<(), WsError> {
+        let response = self.handle_self_address();
+        self.push_websocket_unsolicited_response(response).await
+    }
+
+    async fn push_websocket_unsolicited_response(
+        &mut self,
+        response: ServerResponse,
+    ) -> Result<(), WsError> {
+        // same assumption as with the received plaintexts - respond in the kind
+        // the client used in its latest request
+        let ws_message = match self.received_response_type {
+            ReceivedResponseType::Binary => WsMessage::Binary(response.into_binary()),
+            ReceivedResponseType::Text => WsMessage::Text(response.into_text()),
+        };
+        self.send_websocket_response(ws_message).await
+    }
+
     async fn send_websocket_response(&mut self, msg: WsMessage) -> Result<(), WsError> {
         match self.socket {
             // TODO: more closely investigate difference between `Sink::send` and `Sink::send_all`
@@ -224,7 +734,12 @@ impl Handler {
         }
     }
 
-    async fn listen_for_requests(&mut self, mut msg_receiver: ReconstructedMessagesReceiver) {
+    async fn listen_for_requests(
+        &mut self,
+        mut msg_receiver: ReconstructedMessagesReceiver,
+        mut receipt_receiver: DeliveryReceiptReceiver,
+    ) {
+        let mut address_updates = self.self_full_address.clone();
         loop {
             tokio::select! {
                 // we can either get a client request from the websocket
@@ -244,7 +759,7 @@ impl Handler {
                         break;
                     }
 
-                    if let Some(response) = self.handle_ws_request(socket_msg) {
+                    if let Some(response) = self.handle_ws_request(socket_msg).await {
                         if let Err(err) = self.send_websocket_response(response).await {
                             warn!(
                                 "Failed to send message over websocket: {}. Assuming the connection is dead.",
@@ -264,13 +779,38 @@ impl Handler {
                         break;
                     }
                 }
+                // or a delivery receipt of a message the client has sent before
+                receipt = receipt_receiver.next() => {
+                    // we're holding a sender ourselves so the channel can't have been closed
+                    let receipt = receipt.unwrap();
+                    if let Err(e) = self.push_websocket_delivery_receipt(receipt).await {
+                        warn!("failed to send delivery receipt back to the client - {:?}, assuming the connection is dead", e);
+                        break;
+                    }
+                }
+                // or our address has changed after a gateway failover or a key rotation
+                Ok(_) = address_updates.changed() => {
+                    if let Err(e) = self.push_websocket_self_address().await {
+                        warn!("failed to send the new address back to the client - {:?}, assuming the connection is dead", e);
+                        break;
+                    }
+                }
             }
         }
     }
 
     // consume self to make sure `drop` is called after this is done
-    pub(crate) async fn handle_connection(mut self, socket: TcpStream) {
-        let ws_stream = match accept_async(socket).await {
+    pub(crate) async fn handle_connection(
+        mut self,
+        socket: Box<dyn ConnectionStream>,
+        connection_id: SubscriberId,
+    ) {
+        let auth_token = self.auth_token.clone();
+        let authentication_callback = move |request: &Request, response: Response| {
+            Self::authenticate_handshake(auth_token.as_deref(), request).map(|_| response)
+        };
+
+        let ws_stream = match accept_hdr_async(socket, authentication_callback).await {
             Ok(ws_stream) => ws_stream,
             Err(err) => {
                 warn!("error while performing the websocket handshake - {:?}", err);
@@ -281,13 +821,20 @@ impl Handler {
 
         let (reconstructed_sender, reconstructed_receiver) = mpsc::unbounded();
 
-        // tell the buffer to start sending stuff to us
-        self.buffer_requester
-            .unbounded_send(ReceivedBufferMessage::ReceiverAnnounce(
+        // tell the fanout to start sending copies of all received messages to us
+        self.subscription_requester
+            .unbounded_send(SubscriptionRequest::Subscribe(
+                connection_id,
                 reconstructed_sender,
             ))
-            .expect("the buffer request failed!");
+            .expect("the subscription request failed!");
+        self.connection_id = Some(connection_id);
+
+        // receipts of messages sent over this particular connection are going to be pushed to us
+        let (receipt_sender, receipt_receiver) = mpsc::unbounded();
+        self.receipt_sender = Some(receipt_sender);
 
-        self.listen_for_requests(reconstructed_receiver).await;
+        self.listen_for_requests(reconstructed_receiver, receipt_receiver)
+            .await;
     }
 }
@@ -1,34 +1,51 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
-use super::handler::Handler;
+use super::fanout::SubscriberId;
+use super::handler::{ConnectionStream, Handler};
 use log::*;
-use std::{net::SocketAddr, process, sync::Arc};
-use tokio::io::AsyncWriteExt;
-use tokio::{sync::Notify, task::JoinHandle};
-
-enum State {
-    Connected,
-    AwaitingConnection,
-}
-
-impl State {
-    fn is_connected(&self) -> bool {
-        matches!(self, State::Connected)
-    }
-}
+use std::{net::SocketAddr, process};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
 
 pub(crate) struct Listener {
     address: SocketAddr,
-    state: State,
+    tls_acceptor: Option<TlsAcceptor>,
+    next_connection_id: SubscriberId,
 }
 
 impl Listener {
-    pub(crate) fn new(port: u16) -> Self {
+    pub(crate) fn new(address: SocketAddr) -> Self {
         Listener {
-            // unless we find compelling reason not to, just listen on local only
-            address: SocketAddr::new("127.0.0.1".parse().unwrap(), port),
-            state: State::AwaitingConnection,
+            address,
+            tls_acceptor: None,
+            next_connection_id: 0,
+        }
+    }
+
+    /// Makes the listener only accept TLS connections.
+    #[must_use]
+    pub(crate) fn with_tls(mut self, tls_acceptor: TlsAcceptor) -> Self {
+        self.tls_acceptor = Some(tls_acceptor);
+        self
+    }
+
+    // the TLS handshake (if applicable) is performed in the task spawned for the connection
+    // so that a slow client wouldn't prevent others from connecting
+    async fn establish_stream(
+        tls_acceptor: Option<TlsAcceptor>,
+        socket: TcpStream,
+    ) -> Option<Box<dyn ConnectionStream>> {
+        match tls_acceptor {
+            None => Some(Box::new(socket)),
+            Some(tls_acceptor) => match tls_acceptor.accept(socket).await {
+                Ok(tls_stream) => Some(Box::new(tls_stream)),
+                Err(err) => {
+                    warn!("error while performing the TLS handshake - {}", err);
+                    None
+                }
+            },
         }
     }
 
@@ -41,53 +58,37 @@ impl Listener {
             }
         };
 
-        let notify = Arc::new(Notify::new());
-
         loop {
-            tokio::select! {
-                _ = notify.notified() => {
-                    // our connection terminated - we are open to a new one now!
-                    self.state = State::AwaitingConnection;
-                }
-                new_conn = tcp_listener.accept() => {
-                    match new_conn {
-                        Ok((mut socket, remote_addr)) => {
-                            debug!("Received connection from {:?}", remote_addr);
-                            if self.state.is_connected() {
-                                warn!("tried to duplicate!");
-                                // if we've already got a connection, don't allow another one
-                                debug!("but there was already a connection present!");
-                                // while we only ever want to accept a single connection, we don't want
-                                // to leave clients hanging (and also allow for reconnection if it somehow
-                                // was dropped)
-                                match socket.shutdown().await {
-                                    Ok(_) => trace!(
-                                        "closed the connection between attempting websocket handshake"
-                                    ),
-                                    Err(e) => warn!("failed to cleanly close the connection - {:?}", e),
-                                };
-                            } else {
-                                // even though we're spawning a new task with the handler here, we will only ever spawn a single one.
-                                // it's done so that any new connections to this listener could be rejected rather than left
-                                // hanging because the executor doesn't come back here
-                                let notify_clone = Arc::clone(&notify);
-                                let fresh_handler = handler.clone();
-                                tokio::spawn(async move {
-                                    fresh_handler.handle_connection(socket).await;
-                                    notify_clone.notify_one();
-                                });
-                                self.state = State::Connected;
-                            }
+            match tcp_listener.accept().await {
+                Ok((socket, remote_addr)) => {
+                    let connection_id = self.next_connection_id;
+                    self.next_connection_id += 1;
+                    debug!(
+                        "Received connection from {:?}. Assigned it id {}",
+                        remote_addr, connection_id
+                    );
+
+                    // every connection gets its own handler, all of them share the same
+                    // mixnet identity and receive copies of all incoming messages
+                    let fresh_handler = handler.clone();
+                    let tls_acceptor = self.tls_acceptor.clone();
+                    tokio::spawn(async move {
+                        if let Some(stream) = Self::establish_stream(tls_acceptor, socket).await {
+                            fresh_handler.handle_connection(stream, connection_id).await;
                         }
-                        Err(e) => warn!("failed to get client: {:?}", e),
-                    }
+                        debug!("Websocket connection {} has terminated", connection_id);
+                    });
                 }
+                Err(e) => warn!("failed to get client: {:?}", e),
             }
         }
     }
 
     pub(crate) fn start(mut self, handler: Handler) -> JoinHandle<()> {
         info!("Running websocket on {:?}", self.address.to_string());
+        if self.tls_acceptor.is_some() {
+            info!("The websocket only accepts TLS connections");
+        }
 
         tokio::spawn(async move { self.run(handler).await })
     }
@@ -1,8 +1,14 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
+pub(crate) use client_state::ClientState;
+pub(crate) use fanout::ReceivedMessagesFanout;
 pub(crate) use handler::Handler;
 pub(crate) use listener::Listener;
 
+pub(crate) mod client_state;
+pub(crate) mod fanout;
 pub(crate) mod handler;
 pub(crate) mod listener;
+pub(crate) mod reassembly;
+pub(crate) mod tls;
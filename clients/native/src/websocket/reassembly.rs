@@ -0,0 +1,353 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use log::*;
+use nymsphinx::headers::StreamFrame;
+use nymsphinx::receiver::ReconstructedMessage;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// Maximum number of chunks of a single stream that are held back while waiting
+/// for the ones preceding them.
+const MAX_BUFFERED_CHUNKS: usize = 256;
+
+/// Maximum total size of the chunks held back across all of the streams.
+const MAX_BUFFERED_BYTES: usize = 16 * 1024 * 1024;
+
+/// Maximum number of streams that can be incomplete at the same time.
+const MAX_INCOMPLETE_STREAMS: usize = 16;
+
+/// Time after which a stream that has not received any new chunks is given up on.
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+struct IncomingStream {
+    next_sequence: u64,
+    buffered: BTreeMap<u64, ReconstructedMessage>,
+    buffered_bytes: usize,
+    last_seen: Instant,
+}
+
+impl IncomingStream {
+    fn new(now: Instant) -> Self {
+        IncomingStream {
+            next_sequence: 0,
+            buffered: BTreeMap::new(),
+            buffered_bytes: 0,
+            last_seen: now,
+        }
+    }
+
+    fn buffer(&mut self, sequence: u64, chunk: ReconstructedMessage) {
+        self.buffered_bytes += chunk.message.len();
+        if let Some(replaced) = self.buffered.insert(sequence, chunk) {
+            self.buffered_bytes -= replaced.message.len();
+        }
+    }
+
+    // takes all the chunks that can be delivered in order
+    fn drain_ready(&mut self, ready: &mut Vec<ReconstructedMessage>) -> bool {
+        let mut ended = false;
+        while let Some(chunk) = self.buffered.remove(&self.next_sequence) {
+            self.next_sequence += 1;
+            self.buffered_bytes -= chunk.message.len();
+            ended = stream_frame(&chunk)
+                .map(|frame| frame.end)
+                .unwrap_or_default();
+            ready.push(chunk);
+            if ended {
+                break;
+            }
+        }
+        ended
+    }
+
+    // gives up on the missing chunks and delivers everything that was buffered, in order
+    fn skip_missing(&mut self, ready: &mut Vec<ReconstructedMessage>) -> bool {
+        let mut ended = false;
+        self.buffered_bytes = 0;
+        for (sequence, chunk) in std::mem::take(&mut self.buffered) {
+            self.next_sequence = sequence + 1;
+            ended = stream_frame(&chunk)
+                .map(|frame| frame.end)
+                .unwrap_or_default();
+            ready.push(chunk);
+        }
+        ended
+    }
+}
+
+fn stream_frame(message: &ReconstructedMessage) -> Option<StreamFrame> {
+    message.headers.as_ref().and_then(|headers| headers.stream)
+}
+
+/// Puts the chunks of streams sent by other clients back in order. Messages that are not part
+/// of any stream are passed through straight away.
+///
+/// Every chunk is still delivered as a separate message, with its position within the stream
+/// available in its headers, so that the entire stream never has to be held in memory.
+/// At most `MAX_BUFFERED_BYTES` are held back across all of the streams.
+#[derive(Default)]
+pub(crate) struct StreamReassembler {
+    streams: HashMap<u64, IncomingStream>,
+}
+
+impl StreamReassembler {
+    /// Returns the messages that are ready to be delivered, in order.
+    pub(crate) fn reassemble(
+        &mut self,
+        messages: Vec<ReconstructedMessage>,
+    ) -> Vec<ReconstructedMessage> {
+        self.reassemble_at(messages, Instant::now())
+    }
+
+    fn reassemble_at(
+        &mut self,
+        messages: Vec<ReconstructedMessage>,
+        now: Instant,
+    ) -> Vec<ReconstructedMessage> {
+        let mut ready = Vec::with_capacity(messages.len());
+        self.evict_idle(now, &mut ready);
+        for message in messages {
+            self.push(message, now, &mut ready)
+        }
+        ready
+    }
+
+    // gives up on the streams whose sender went quiet, delivering whatever they still buffer
+    fn evict_idle(&mut self, now: Instant, ready: &mut Vec<ReconstructedMessage>) {
+        self.streams.retain(|stream_id, stream| {
+            if now.saturating_duration_since(stream.last_seen) < STREAM_IDLE_TIMEOUT {
+                return true;
+            }
+            warn!(
+                "Stream {} has been idle for too long - delivering its {} buffered chunks and giving up on the rest",
+                stream_id,
+                stream.buffered.len()
+            );
+            stream.skip_missing(ready);
+            false
+        });
+    }
+
+    fn push(
+        &mut self,
+        message: ReconstructedMessage,
+        now: Instant,
+        ready: &mut Vec<ReconstructedMessage>,
+    ) {
+        let frame = match stream_frame(&message) {
+            Some(frame) => frame,
+            None => {
+                ready.push(message);
+                return;
+            }
+        };
+
+        if !self.streams.contains_key(&frame.stream_id)
+            && self.streams.len() >= MAX_INCOMPLETE_STREAMS
+        {
+            warn!(
+                "There are too many incomplete streams - delivering chunk {} of stream {} without reordering it",
+                frame.sequence, frame.stream_id
+            );
+            ready.push(message);
+            return;
+        }
+
+        let stream = self
+            .streams
+            .entry(frame.stream_id)
+            .or_insert_with(|| IncomingStream::new(now));
+        stream.last_seen = now;
+        if frame.sequence < stream.next_sequence {
+            // it must have been retransmitted after we gave up waiting for it
+            debug!(
+                "Received chunk {} of stream {} after the later ones were already delivered",
+                frame.sequence, frame.stream_id
+            );
+            ready.push(message);
+            return;
+        }
+        stream.buffer(frame.sequence, message);
+
+        let mut ended = stream.drain_ready(ready);
+        if !ended && stream.buffered.len() > MAX_BUFFERED_CHUNKS {
+            warn!(
+                "Stream {} is missing chunk {} for too long - delivering the later chunks without it",
+                frame.stream_id, stream.next_sequence
+            );
+            ended = stream.skip_missing(ready);
+        }
+
+        if ended {
+            self.streams.remove(&frame.stream_id);
+        }
+        self.enforce_byte_limit(ready);
+    }
+
+    fn buffered_bytes(&self) -> usize {
+        self.streams
+            .values()
+            .map(|stream| stream.buffered_bytes)
+            .sum()
+    }
+
+    // gives up on the missing chunks of the streams holding back the most data until the rest fits
+    fn enforce_byte_limit(&mut self, ready: &mut Vec<ReconstructedMessage>) {
+        while self.buffered_bytes() > MAX_BUFFERED_BYTES {
+            let (&stream_id, stream) = match self
+                .streams
+                .iter_mut()
+                .max_by_key(|(_, stream)| stream.buffered_bytes)
+            {
+                Some(largest) => largest,
+                None => return,
+            };
+            warn!(
+                "Too much data is held back - delivering the {} buffered bytes of stream {} without waiting for chunk {}",
+                stream.buffered_bytes, stream_id, stream.next_sequence
+            );
+            if stream.skip_missing(ready) {
+                self.streams.remove(&stream_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nymsphinx::headers::MessageHeaders;
+
+    fn chunk(stream_id: u64, sequence: u64, end: bool) -> ReconstructedMessage {
+        ReconstructedMessage {
+            message: sequence.to_be_bytes().to_vec(),
+            reply_surbs: Vec::new(),
+            headers: Some(MessageHeaders {
+                stream: Some(StreamFrame {
+                    stream_id,
+                    sequence,
+                    end,
+                }),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn sized_chunk(stream_id: u64, sequence: u64, size: usize) -> ReconstructedMessage {
+        ReconstructedMessage {
+            message: vec![0; size],
+            ..chunk(stream_id, sequence, false)
+        }
+    }
+
+    fn sequences(messages: &[ReconstructedMessage]) -> Vec<u64> {
+        messages
+            .iter()
+            .map(|message| stream_frame(message).unwrap().sequence)
+            .collect()
+    }
+
+    #[test]
+    fn chunks_are_delivered_in_order() {
+        let mut reassembler = StreamReassembler::default();
+
+        let ready = reassembler.reassemble(vec![chunk(1, 2, false), chunk(1, 1, false)]);
+        assert!(ready.is_empty());
+
+        let ready = reassembler.reassemble(vec![chunk(1, 0, false)]);
+        assert_eq!(sequences(&ready), vec![0, 1, 2]);
+
+        let ready = reassembler.reassemble(vec![chunk(1, 3, true)]);
+        assert_eq!(sequences(&ready), vec![3]);
+        assert!(reassembler.streams.is_empty());
+    }
+
+    #[test]
+    fn streams_are_reordered_independently() {
+        let mut reassembler = StreamReassembler::default();
+        let plain_message = ReconstructedMessage {
+            message: b"foomp".to_vec(),
+            reply_surbs: Vec::new(),
+            headers: None,
+        };
+
+        let ready = reassembler.reassemble(vec![
+            chunk(1, 1, true),
+            chunk(2, 0, false),
+            plain_message,
+            chunk(1, 0, false),
+        ]);
+        let stream_ids: Vec<_> = ready
+            .iter()
+            .map(|message| stream_frame(message).map(|frame| frame.stream_id))
+            .collect();
+        assert_eq!(stream_ids, vec![Some(2), None, Some(1), Some(1)]);
+        assert_eq!(reassembler.streams.len(), 1);
+    }
+
+    #[test]
+    fn missing_chunks_are_eventually_skipped() {
+        let mut reassembler = StreamReassembler::default();
+
+        let later_chunks = (1..=MAX_BUFFERED_CHUNKS as u64)
+            .map(|sequence| chunk(1, sequence, false))
+            .collect();
+        assert!(reassembler.reassemble(later_chunks).is_empty());
+
+        let ready = reassembler.reassemble(vec![chunk(1, MAX_BUFFERED_CHUNKS as u64 + 1, false)]);
+        assert_eq!(ready.len(), MAX_BUFFERED_CHUNKS + 1);
+
+        // the missing chunk is still delivered if it eventually arrives
+        let ready = reassembler.reassemble(vec![chunk(1, 0, false)]);
+        assert_eq!(sequences(&ready), vec![0]);
+    }
+
+    #[test]
+    fn largest_stream_is_given_up_on_when_too_much_data_is_held_back() {
+        let mut reassembler = StreamReassembler::default();
+
+        assert!(reassembler
+            .reassemble(vec![
+                sized_chunk(1, 1, MAX_BUFFERED_BYTES / 2),
+                sized_chunk(2, 1, MAX_BUFFERED_BYTES / 4),
+            ])
+            .is_empty());
+
+        let ready = reassembler.reassemble(vec![sized_chunk(2, 2, MAX_BUFFERED_BYTES / 2)]);
+        assert_eq!(sequences(&ready), vec![1, 2]);
+        assert!(ready
+            .iter()
+            .all(|message| stream_frame(message).unwrap().stream_id == 2));
+        assert_eq!(reassembler.buffered_bytes(), MAX_BUFFERED_BYTES / 2);
+
+        // the stream keeps going from where it was left off
+        let ready = reassembler.reassemble(vec![chunk(2, 3, false)]);
+        assert_eq!(sequences(&ready), vec![3]);
+    }
+
+    #[test]
+    fn idle_streams_are_evicted() {
+        let mut reassembler = StreamReassembler::default();
+        let start = Instant::now();
+
+        assert!(reassembler
+            .reassemble_at(vec![chunk(1, 2, false), chunk(2, 1, false)], start)
+            .is_empty());
+
+        // keep the second stream alive while the first one goes quiet
+        let later = start + STREAM_IDLE_TIMEOUT / 2;
+        assert!(reassembler
+            .reassemble_at(vec![chunk(2, 2, false)], later)
+            .is_empty());
+
+        let ready = reassembler.reassemble_at(Vec::new(), start + STREAM_IDLE_TIMEOUT);
+        assert_eq!(sequences(&ready), vec![2]);
+        assert_eq!(reassembler.streams.len(), 1);
+        assert!(reassembler.streams.contains_key(&2));
+
+        let ready = reassembler.reassemble_at(Vec::new(), later + STREAM_IDLE_TIMEOUT);
+        assert_eq!(sequences(&ready), vec![1, 2]);
+        assert!(reassembler.streams.is_empty());
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, NoClientAuth, PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+
+fn invalid_data<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn open_pem_file(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path).map(BufReader::new)
+}
+
+fn load_certificates(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certificates = certs(&mut open_pem_file(path)?)
+        .map_err(|_| invalid_data(format!("{:?} is not a valid PEM file", path)))?;
+    if certificates.is_empty() {
+        return Err(invalid_data(format!(
+            "{:?} does not contain any certificates",
+            path
+        )));
+    }
+    Ok(certificates)
+}
+
+fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    let invalid_pem = |_| invalid_data(format!("{:?} is not a valid PEM file", path));
+
+    // the key might be stored either in the PKCS8 or the older PKCS1 (RSA) format
+    let mut keys = pkcs8_private_keys(&mut open_pem_file(path)?).map_err(invalid_pem)?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open_pem_file(path)?).map_err(invalid_pem)?;
+    }
+
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| invalid_data(format!("{:?} does not contain any private key", path)))
+}
+
+/// Creates the acceptor for the TLS connections to the websocket. If the certificate
+/// of a certificate authority is provided, the clients are required to present
+/// a certificate signed by it.
+pub(crate) fn load_tls_acceptor(
+    certificate_file: &Path,
+    private_key_file: &Path,
+    client_ca_file: Option<&Path>,
+) -> io::Result<TlsAcceptor> {
+    let client_verifier = match client_ca_file {
+        Some(client_ca_file) => {
+            let mut client_roots = RootCertStore::empty();
+            for certificate in load_certificates(client_ca_file)? {
+                client_roots.add(&certificate).map_err(|err| {
+                    invalid_data(format!(
+                        "{:?} contains an invalid certificate - {}",
+                        client_ca_file, err
+                    ))
+                })?;
+            }
+            AllowAnyAuthenticatedClient::new(client_roots)
+        }
+        None => NoClientAuth::new(),
+    };
+
+    let mut server_config = ServerConfig::new(client_verifier);
+    server_config
+        .set_single_cert(
+            load_certificates(certificate_file)?,
+            load_private_key(private_key_file)?,
+        )
+        .map_err(|err| invalid_data(format!("invalid TLS certificate or key - {}", err)))?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
@@ -44,6 +44,9 @@ pub enum ErrorKind {
     /// The received request is malformed.
     MalformedRequest = 0x04,
 
+    /// The client has too much data waiting to be sent and the request has to be retried later.
+    Overloaded = 0x05,
+
     // that's an arbitrary division but let's keep 1-127 (hex 0x01 - 0x7F) values request-specific
     // and 128-254 (hex 0x80 - 0xFE) for responses
     /// The received response contained no data.
@@ -69,6 +72,7 @@ impl ErrorKind {
             ErrorKind::TooShortRequest => "received request did not contain enough data",
             ErrorKind::UnknownRequest => "unknown request type",
             ErrorKind::MalformedRequest => "malformed request",
+            ErrorKind::Overloaded => "too much data waiting to be sent",
 
             ErrorKind::EmptyResponse => "received response contained no data",
             ErrorKind::TooShortResponse => "received response did not contain enough data",
@@ -8,6 +8,8 @@ use crate::error::{self, ErrorKind};
 use crate::text::ClientRequestText;
 use nymsphinx::addressing::clients::Recipient;
 use nymsphinx::anonymous_replies::ReplySurb;
+use nymsphinx::headers::MessageHeaders;
+use serde::{Deserialize, Serialize};
 use std::convert::{TryFrom, TryInto};
 use std::mem::size_of;
 
@@ -20,39 +22,216 @@ pub const REPLY_REQUEST_TAG: u8 = 0x01;
 /// Value tag representing [`SelfAddress`] variant of the [`ClientRequest`]
 pub const SELF_ADDRESS_REQUEST_TAG: u8 = 0x02;
 
+/// Value tag representing [`Send`] variant of the [`ClientRequest`] for which
+/// delivery receipts were requested
+pub const SEND_WITH_RECEIPT_REQUEST_TAG: u8 = 0x03;
+
+/// Value tag representing [`Bandwidth`] variant of the [`ClientRequest`]
+pub const BANDWIDTH_REQUEST_TAG: u8 = 0x04;
+
+/// Value tag representing [`OpenStream`] variant of the [`ClientRequest`]
+pub const OPEN_STREAM_REQUEST_TAG: u8 = 0x05;
+
+/// Value tag representing [`StreamChunk`] variant of the [`ClientRequest`]
+pub const STREAM_CHUNK_REQUEST_TAG: u8 = 0x06;
+
+/// Value tag representing [`CloseStream`] variant of the [`ClientRequest`]
+pub const CLOSE_STREAM_REQUEST_TAG: u8 = 0x07;
+
+/// Value tag representing [`Send`] variant of the [`ClientRequest`] with the message headers attached
+pub const SEND_WITH_HEADERS_REQUEST_TAG: u8 = 0x08;
+
+/// Value tag representing [`GatewayInfo`] variant of the [`ClientRequest`]
+pub const GATEWAY_INFO_REQUEST_TAG: u8 = 0x09;
+
+/// Value tag representing [`Topology`] variant of the [`ClientRequest`]
+pub const TOPOLOGY_REQUEST_TAG: u8 = 0x0A;
+
+/// Value tag representing [`PendingAcks`] variant of the [`ClientRequest`]
+pub const PENDING_ACKS_REQUEST_TAG: u8 = 0x0B;
+
+/// Value tag representing [`CoverTraffic`] variant of the [`ClientRequest`]
+pub const COVER_TRAFFIC_REQUEST_TAG: u8 = 0x0C;
+
+/// Value tag representing [`RefreshTopology`] variant of the [`ClientRequest`]
+pub const REFRESH_TOPOLOGY_REQUEST_TAG: u8 = 0x0D;
+
+/// Value tag representing [`InboxCount`] variant of the [`ClientRequest`]
+pub const INBOX_COUNT_REQUEST_TAG: u8 = 0x0E;
+
+/// Value tag representing [`FetchInbox`] variant of the [`ClientRequest`]
+pub const FETCH_INBOX_REQUEST_TAG: u8 = 0x0F;
+
+/// Value tag representing [`DeleteInbox`] variant of the [`ClientRequest`]
+pub const DELETE_INBOX_REQUEST_TAG: u8 = 0x10;
+
+/// Value tag representing [`SetTrafficRates`] variant of the [`ClientRequest`]
+pub const SET_TRAFFIC_RATES_REQUEST_TAG: u8 = 0x11;
+
+/// Value tag representing [`SetTrafficMode`] variant of the [`ClientRequest`]
+pub const SET_TRAFFIC_MODE_REQUEST_TAG: u8 = 0x12;
+
+/// Predefined sending rates, derived from the rates in the client configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TrafficMode {
+    /// Packets are sent at the configured rates.
+    Normal = 0,
+    /// Packets are sent considerably less often, saving battery and bandwidth.
+    LowPower = 1,
+    /// Packets are sent more often, making the real traffic harder to tell apart.
+    HighAnonymity = 2,
+}
+
+impl TryFrom<u8> for TrafficMode {
+    type Error = error::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(TrafficMode::Normal),
+            1 => Ok(TrafficMode::LowPower),
+            2 => Ok(TrafficMode::HighAnonymity),
+            n => Err(error::Error::new(
+                ErrorKind::MalformedRequest,
+                format!("invalid traffic mode {}", n),
+            )),
+        }
+    }
+}
+
 #[allow(non_snake_case)]
 #[derive(Debug)]
 pub enum ClientRequest {
     Send {
         recipient: Recipient,
         message: Vec<u8>,
-        // Perhaps we could change it to a number to indicate how many reply_SURBs we want to include?
-        with_reply_surb: bool,
+        /// Number of reply SURBs to attach to the message. Note that `0` and `1` are
+        /// encoded identically to the old `false` and `true` reply flag.
+        num_reply_surbs: u8,
+        /// Whether the client should respond with the id assigned to the message and later
+        /// notify whether it got delivered.
+        with_receipt: bool,
+        /// Metadata sent, encrypted, alongside the message.
+        headers: Option<MessageHeaders>,
     },
     Reply {
         message: Vec<u8>,
         reply_surb: ReplySurb,
     },
     SelfAddress,
+    /// Query the bandwidth remaining at the gateway and the rate at which it's being consumed.
+    Bandwidth,
+    /// Start sending data to the recipient in multiple chunks, so that a large payload
+    /// doesn't have to be put into a single websocket frame.
+    OpenStream {
+        recipient: Recipient,
+        /// Number of reply SURBs to attach to the final message of the stream, sent once it's closed.
+        num_reply_surbs: u8,
+    },
+    /// Next part of the data sent on a previously opened stream. Every chunk is sent into
+    /// the mix network as a separate message, hence the recipient is not guaranteed to get
+    /// them in the same order.
+    StreamChunk {
+        stream_id: u64,
+        data: Vec<u8>,
+    },
+    /// Finish the stream. No more chunks are accepted for it afterwards.
+    CloseStream {
+        stream_id: u64,
+    },
+    /// Query the details of the gateway the client is currently connected to.
+    GatewayInfo,
+    /// Query the network topology the client is currently using for routing its packets.
+    Topology,
+    /// Query the number of sent packets the client is still waiting to get acknowledged.
+    PendingAcks,
+    /// Query the current rates of the cover traffic and the number of packets sent so far.
+    CoverTraffic,
+    /// Make the client refresh its view of the network topology straight away rather than
+    /// waiting for the next scheduled refresh.
+    RefreshTopology,
+    /// Query the number of messages the gateway has stored while the client was offline.
+    InboxCount,
+    /// Make the gateway push the next page of the messages it has stored while the client
+    /// was offline. They are received exactly like any other message.
+    FetchInbox {
+        /// Id of the last message of the previous page, if any.
+        start_after: Option<i64>,
+        page_size: u32,
+        /// Whether the gateway should remove the messages once they're pushed. It doesn't wait
+        /// for them to be received, so the messages lost in transit can't be fetched again.
+        /// Use [`DeleteInbox`] after receiving them instead if that's not acceptable.
+        remove: bool,
+    },
+    /// Remove the specified messages stored by the gateway.
+    DeleteInbox {
+        ids: Vec<i64>,
+    },
+    /// Change the average delays between the packets sent by the client. The client responds
+    /// the same way as to the [`CoverTraffic`] request, including the newly applied rates.
+    SetTrafficRates {
+        loop_cover_traffic_average_delay_ms: u64,
+        message_sending_average_delay_ms: u64,
+    },
+    /// Switch to the rates of one of the predefined traffic modes. The client responds
+    /// the same way as to the [`CoverTraffic`] request, including the newly applied rates.
+    SetTrafficMode {
+        mode: TrafficMode,
+    },
 }
 
 // we could have been parsing it directly TryFrom<WsMessage>, but we want to retain
 // information about whether it came from binary or text to send appropriate response back
 impl ClientRequest {
-    // SEND_REQUEST_TAG || with_surb || recipient || data_len || data
-    fn serialize_send(recipient: Recipient, data: Vec<u8>, with_reply_surb: bool) -> Vec<u8> {
+    // SEND_REQUEST_TAG || num_surbs || recipient || data_len || data
+    // or if receipt is requested:
+    // SEND_WITH_RECEIPT_REQUEST_TAG || num_surbs || recipient || data_len || data
+    // or if headers are attached:
+    // SEND_WITH_HEADERS_REQUEST_TAG || with_receipt || num_surbs || recipient || headers || data_len || data
+    fn serialize_send(
+        recipient: Recipient,
+        data: Vec<u8>,
+        num_reply_surbs: u8,
+        with_receipt: bool,
+        headers: Option<MessageHeaders>,
+    ) -> Result<Vec<u8>, error::Error> {
         let data_len_bytes = (data.len() as u64).to_be_bytes();
-        std::iter::once(SEND_REQUEST_TAG)
-            .chain(std::iter::once(with_reply_surb as u8))
+        if let Some(headers) = headers {
+            // the headers are self-describing, i.e. they're prefixed with their own length
+            let headers_bytes = headers.try_to_bytes().map_err(|err| {
+                error::Error::new(
+                    ErrorKind::MalformedRequest,
+                    format!("the headers can't be serialized: {}", err),
+                )
+            })?;
+            return Ok(std::iter::once(SEND_WITH_HEADERS_REQUEST_TAG)
+                .chain(std::iter::once(with_receipt as u8))
+                .chain(std::iter::once(num_reply_surbs))
+                .chain(recipient.to_bytes().iter().cloned())
+                .chain(headers_bytes.into_iter())
+                .chain(data_len_bytes.iter().cloned())
+                .chain(data.into_iter())
+                .collect());
+        }
+
+        let tag = if with_receipt {
+            SEND_WITH_RECEIPT_REQUEST_TAG
+        } else {
+            SEND_REQUEST_TAG
+        };
+        Ok(std::iter::once(tag)
+            .chain(std::iter::once(num_reply_surbs))
             .chain(recipient.to_bytes().iter().cloned()) // will not be length prefixed because the length is constant
             .chain(data_len_bytes.iter().cloned())
             .chain(data.into_iter())
-            .collect()
+            .collect())
     }
 
-    // SEND_REQUEST_TAG || with_reply || recipient || data_len || data
+    // SEND_REQUEST_TAG || num_surbs || recipient || data_len || data
+    // or if receipt is requested:
+    // SEND_WITH_RECEIPT_REQUEST_TAG || num_surbs || recipient || data_len || data
     fn deserialize_send(b: &[u8]) -> Result<Self, error::Error> {
-        // we need to have at least 1 (tag) + 1 (reply flag) + Recipient::LEN + sizeof<u64> bytes
+        // we need to have at least 1 (tag) + 1 (surb count) + Recipient::LEN + sizeof<u64> bytes
         if b.len() < 2 + Recipient::LEN + size_of::<u64>() {
             return Err(error::Error::new(
                 ErrorKind::TooShortRequest,
@@ -61,18 +240,10 @@ impl ClientRequest {
         }
 
         // this MUST match because it was called by 'deserialize'
-        debug_assert_eq!(b[0], SEND_REQUEST_TAG);
+        debug_assert!(b[0] == SEND_REQUEST_TAG || b[0] == SEND_WITH_RECEIPT_REQUEST_TAG);
 
-        let with_reply_surb = match b[1] {
-            0 => false,
-            1 => true,
-            n => {
-                return Err(error::Error::new(
-                    ErrorKind::MalformedRequest,
-                    format!("invalid reply surb flag {}", n),
-                ))
-            }
-        };
+        let with_receipt = b[0] == SEND_WITH_RECEIPT_REQUEST_TAG;
+        let num_reply_surbs = b[1];
 
         let mut recipient_bytes = [0u8; Recipient::LEN];
         recipient_bytes.copy_from_slice(&b[2..2 + Recipient::LEN]);
@@ -101,9 +272,73 @@ impl ClientRequest {
         }
 
         Ok(ClientRequest::Send {
-            with_reply_surb,
+            num_reply_surbs,
+            recipient,
+            message: data.to_vec(),
+            with_receipt,
+            headers: None,
+        })
+    }
+
+    // SEND_WITH_HEADERS_REQUEST_TAG || with_receipt || num_surbs || recipient || headers || data_len || data
+    fn deserialize_send_with_headers(b: &[u8]) -> Result<Self, error::Error> {
+        // we need to have at least 1 (tag) + 1 (receipt flag) + 1 (surb count) + Recipient::LEN bytes
+        if b.len() < 3 + Recipient::LEN {
+            return Err(error::Error::new(
+                ErrorKind::TooShortRequest,
+                "not enough data provided to recover 'send with headers'".to_string(),
+            ));
+        }
+
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], SEND_WITH_HEADERS_REQUEST_TAG);
+
+        let with_receipt = b[1] != 0;
+        let num_reply_surbs = b[2];
+
+        let mut recipient_bytes = [0u8; Recipient::LEN];
+        recipient_bytes.copy_from_slice(&b[3..3 + Recipient::LEN]);
+        let recipient = Recipient::try_from_bytes(recipient_bytes).map_err(|err| {
+            error::Error::new(
+                ErrorKind::MalformedRequest,
+                format!("malformed recipient: {:?}", err),
+            )
+        })?;
+
+        let i = 3 + Recipient::LEN;
+        let (headers, headers_len) = MessageHeaders::try_from_bytes(&b[i..]).map_err(|err| {
+            error::Error::new(
+                ErrorKind::MalformedRequest,
+                format!("malformed headers: {}", err),
+            )
+        })?;
+
+        let i = i + headers_len;
+        if b.len() < i + size_of::<u64>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortRequest,
+                "not enough data provided to recover data length".to_string(),
+            ));
+        }
+        let data_len = u64::from_be_bytes(b[i..i + size_of::<u64>()].try_into().unwrap());
+        let data = &b[i + size_of::<u64>()..];
+        if data.len() as u64 != data_len {
+            return Err(error::Error::new(
+                ErrorKind::MalformedRequest,
+                format!(
+                    "data len has inconsistent length. specified: {} got: {}",
+                    data_len,
+                    data.len()
+                ),
+            ));
+        }
+
+        Ok(ClientRequest::Send {
+            num_reply_surbs,
             recipient,
             message: data.to_vec(),
+            with_receipt,
+            headers: Some(headers),
         })
     }
 
@@ -202,13 +437,393 @@ impl ClientRequest {
         ClientRequest::SelfAddress
     }
 
-    pub fn serialize(self) -> Vec<u8> {
-        match self {
+    // BANDWIDTH_REQUEST_TAG
+    fn serialize_bandwidth() -> Vec<u8> {
+        std::iter::once(BANDWIDTH_REQUEST_TAG).collect()
+    }
+
+    // BANDWIDTH_REQUEST_TAG
+    fn deserialize_bandwidth(b: &[u8]) -> Self {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], BANDWIDTH_REQUEST_TAG);
+
+        ClientRequest::Bandwidth
+    }
+
+    // OPEN_STREAM_REQUEST_TAG || num_surbs || recipient
+    fn serialize_open_stream(recipient: Recipient, num_reply_surbs: u8) -> Vec<u8> {
+        std::iter::once(OPEN_STREAM_REQUEST_TAG)
+            .chain(std::iter::once(num_reply_surbs))
+            .chain(recipient.to_bytes().iter().cloned())
+            .collect()
+    }
+
+    // OPEN_STREAM_REQUEST_TAG || num_surbs || recipient
+    fn deserialize_open_stream(b: &[u8]) -> Result<Self, error::Error> {
+        if b.len() != 2 + Recipient::LEN {
+            return Err(error::Error::new(
+                ErrorKind::MalformedRequest,
+                format!(
+                    "'open stream' request has invalid length. expected: {} got: {}",
+                    2 + Recipient::LEN,
+                    b.len()
+                ),
+            ));
+        }
+
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], OPEN_STREAM_REQUEST_TAG);
+
+        let num_reply_surbs = b[1];
+
+        let mut recipient_bytes = [0u8; Recipient::LEN];
+        recipient_bytes.copy_from_slice(&b[2..]);
+        let recipient = Recipient::try_from_bytes(recipient_bytes).map_err(|err| {
+            error::Error::new(
+                ErrorKind::MalformedRequest,
+                format!("malformed recipient: {:?}", err),
+            )
+        })?;
+
+        Ok(ClientRequest::OpenStream {
+            recipient,
+            num_reply_surbs,
+        })
+    }
+
+    // STREAM_CHUNK_REQUEST_TAG || stream_id || data_len || data
+    fn serialize_stream_chunk(stream_id: u64, data: Vec<u8>) -> Vec<u8> {
+        let data_len_bytes = (data.len() as u64).to_be_bytes();
+        std::iter::once(STREAM_CHUNK_REQUEST_TAG)
+            .chain(stream_id.to_be_bytes().iter().cloned())
+            .chain(data_len_bytes.iter().cloned())
+            .chain(data.into_iter())
+            .collect()
+    }
+
+    // STREAM_CHUNK_REQUEST_TAG || stream_id || data_len || data
+    fn deserialize_stream_chunk(b: &[u8]) -> Result<Self, error::Error> {
+        // we need to have at least 1 (tag) + 2 * sizeof<u64> bytes
+        if b.len() < 1 + 2 * size_of::<u64>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortRequest,
+                "not enough data provided to recover 'stream chunk'".to_string(),
+            ));
+        }
+
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], STREAM_CHUNK_REQUEST_TAG);
+
+        let stream_id = u64::from_be_bytes(b[1..1 + size_of::<u64>()].try_into().unwrap());
+        let data_len = u64::from_be_bytes(
+            b[1 + size_of::<u64>()..1 + 2 * size_of::<u64>()]
+                .try_into()
+                .unwrap(),
+        );
+        let data = &b[1 + 2 * size_of::<u64>()..];
+        if data.len() as u64 != data_len {
+            return Err(error::Error::new(
+                ErrorKind::MalformedRequest,
+                format!(
+                    "data len has inconsistent length. specified: {} got: {}",
+                    data_len,
+                    data.len()
+                ),
+            ));
+        }
+
+        Ok(ClientRequest::StreamChunk {
+            stream_id,
+            data: data.to_vec(),
+        })
+    }
+
+    // CLOSE_STREAM_REQUEST_TAG || stream_id
+    fn serialize_close_stream(stream_id: u64) -> Vec<u8> {
+        std::iter::once(CLOSE_STREAM_REQUEST_TAG)
+            .chain(stream_id.to_be_bytes().iter().cloned())
+            .collect()
+    }
+
+    // CLOSE_STREAM_REQUEST_TAG || stream_id
+    fn deserialize_close_stream(b: &[u8]) -> Result<Self, error::Error> {
+        if b.len() != 1 + size_of::<u64>() {
+            return Err(error::Error::new(
+                ErrorKind::MalformedRequest,
+                format!(
+                    "'close stream' request has invalid length. expected: {} got: {}",
+                    1 + size_of::<u64>(),
+                    b.len()
+                ),
+            ));
+        }
+
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], CLOSE_STREAM_REQUEST_TAG);
+
+        let stream_id = u64::from_be_bytes(b[1..].try_into().unwrap());
+
+        Ok(ClientRequest::CloseStream { stream_id })
+    }
+
+    // GATEWAY_INFO_REQUEST_TAG
+    fn serialize_gateway_info() -> Vec<u8> {
+        std::iter::once(GATEWAY_INFO_REQUEST_TAG).collect()
+    }
+
+    // GATEWAY_INFO_REQUEST_TAG
+    fn deserialize_gateway_info(b: &[u8]) -> Self {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], GATEWAY_INFO_REQUEST_TAG);
+
+        ClientRequest::GatewayInfo
+    }
+
+    // TOPOLOGY_REQUEST_TAG
+    fn serialize_topology() -> Vec<u8> {
+        std::iter::once(TOPOLOGY_REQUEST_TAG).collect()
+    }
+
+    // TOPOLOGY_REQUEST_TAG
+    fn deserialize_topology(b: &[u8]) -> Self {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], TOPOLOGY_REQUEST_TAG);
+
+        ClientRequest::Topology
+    }
+
+    // PENDING_ACKS_REQUEST_TAG
+    fn serialize_pending_acks() -> Vec<u8> {
+        std::iter::once(PENDING_ACKS_REQUEST_TAG).collect()
+    }
+
+    // PENDING_ACKS_REQUEST_TAG
+    fn deserialize_pending_acks(b: &[u8]) -> Self {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], PENDING_ACKS_REQUEST_TAG);
+
+        ClientRequest::PendingAcks
+    }
+
+    // COVER_TRAFFIC_REQUEST_TAG
+    fn serialize_cover_traffic() -> Vec<u8> {
+        std::iter::once(COVER_TRAFFIC_REQUEST_TAG).collect()
+    }
+
+    // COVER_TRAFFIC_REQUEST_TAG
+    fn deserialize_cover_traffic(b: &[u8]) -> Self {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], COVER_TRAFFIC_REQUEST_TAG);
+
+        ClientRequest::CoverTraffic
+    }
+
+    // REFRESH_TOPOLOGY_REQUEST_TAG
+    fn serialize_refresh_topology() -> Vec<u8> {
+        std::iter::once(REFRESH_TOPOLOGY_REQUEST_TAG).collect()
+    }
+
+    // REFRESH_TOPOLOGY_REQUEST_TAG
+    fn deserialize_refresh_topology(b: &[u8]) -> Self {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], REFRESH_TOPOLOGY_REQUEST_TAG);
+
+        ClientRequest::RefreshTopology
+    }
+
+    // INBOX_COUNT_REQUEST_TAG
+    fn serialize_inbox_count() -> Vec<u8> {
+        std::iter::once(INBOX_COUNT_REQUEST_TAG).collect()
+    }
+
+    // INBOX_COUNT_REQUEST_TAG
+    fn deserialize_inbox_count(b: &[u8]) -> Self {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], INBOX_COUNT_REQUEST_TAG);
+
+        ClientRequest::InboxCount
+    }
+
+    // FETCH_INBOX_REQUEST_TAG || remove || page_size || [start_after]
+    fn serialize_fetch_inbox(start_after: Option<i64>, page_size: u32, remove: bool) -> Vec<u8> {
+        std::iter::once(FETCH_INBOX_REQUEST_TAG)
+            .chain(std::iter::once(remove as u8))
+            .chain(page_size.to_be_bytes().iter().cloned())
+            .chain(start_after.into_iter().flat_map(i64::to_be_bytes))
+            .collect()
+    }
+
+    // FETCH_INBOX_REQUEST_TAG || remove || page_size || [start_after]
+    fn deserialize_fetch_inbox(b: &[u8]) -> Result<Self, error::Error> {
+        // 1 (tag) + 1 (remove flag) + sizeof<u32> (page size)
+        let page_end = 2 + size_of::<u32>();
+        if b.len() != page_end && b.len() != page_end + size_of::<i64>() {
+            return Err(error::Error::new(
+                ErrorKind::MalformedRequest,
+                format!(
+                    "'fetch inbox' request has invalid length. expected: {} or {} got: {}",
+                    page_end,
+                    page_end + size_of::<i64>(),
+                    b.len()
+                ),
+            ));
+        }
+
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], FETCH_INBOX_REQUEST_TAG);
+
+        let remove = match b[1] {
+            0 => false,
+            1 => true,
+            n => {
+                return Err(error::Error::new(
+                    ErrorKind::MalformedRequest,
+                    format!("invalid remove flag {}", n),
+                ))
+            }
+        };
+        let page_size = u32::from_be_bytes(b[2..page_end].try_into().unwrap());
+        let start_after = if b.len() > page_end {
+            Some(i64::from_be_bytes(b[page_end..].try_into().unwrap()))
+        } else {
+            None
+        };
+
+        Ok(ClientRequest::FetchInbox {
+            start_after,
+            page_size,
+            remove,
+        })
+    }
+
+    // DELETE_INBOX_REQUEST_TAG || num_ids || ids
+    fn serialize_delete_inbox(ids: Vec<i64>) -> Vec<u8> {
+        std::iter::once(DELETE_INBOX_REQUEST_TAG)
+            .chain((ids.len() as u64).to_be_bytes().iter().cloned())
+            .chain(ids.into_iter().flat_map(i64::to_be_bytes))
+            .collect()
+    }
+
+    // DELETE_INBOX_REQUEST_TAG || num_ids || ids
+    fn deserialize_delete_inbox(b: &[u8]) -> Result<Self, error::Error> {
+        // we need to have at least 1 (tag) + sizeof<u64> (number of ids) bytes
+        if b.len() < 1 + size_of::<u64>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortRequest,
+                "not enough data provided to recover 'delete inbox'".to_string(),
+            ));
+        }
+
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], DELETE_INBOX_REQUEST_TAG);
+
+        let num_ids = u64::from_be_bytes(b[1..1 + size_of::<u64>()].try_into().unwrap());
+        let ids_bytes = &b[1 + size_of::<u64>()..];
+        if ids_bytes.len() as u64 != num_ids.saturating_mul(size_of::<i64>() as u64) {
+            return Err(error::Error::new(
+                ErrorKind::MalformedRequest,
+                format!(
+                    "ids have inconsistent length. specified: {} ids got: {} bytes",
+                    num_ids,
+                    ids_bytes.len()
+                ),
+            ));
+        }
+
+        let ids = ids_bytes
+            .chunks_exact(size_of::<i64>())
+            .map(|chunk| i64::from_be_bytes(chunk.try_into().unwrap()))
+            .collect();
+
+        Ok(ClientRequest::DeleteInbox { ids })
+    }
+
+    // SET_TRAFFIC_RATES_REQUEST_TAG || loop_cover_delay_ms || message_sending_delay_ms
+    fn serialize_set_traffic_rates(
+        loop_cover_traffic_average_delay_ms: u64,
+        message_sending_average_delay_ms: u64,
+    ) -> Vec<u8> {
+        std::iter::once(SET_TRAFFIC_RATES_REQUEST_TAG)
+            .chain(
+                loop_cover_traffic_average_delay_ms
+                    .to_be_bytes()
+                    .iter()
+                    .cloned(),
+            )
+            .chain(
+                message_sending_average_delay_ms
+                    .to_be_bytes()
+                    .iter()
+                    .cloned(),
+            )
+            .collect()
+    }
+
+    // SET_TRAFFIC_RATES_REQUEST_TAG || loop_cover_delay_ms || message_sending_delay_ms
+    fn deserialize_set_traffic_rates(b: &[u8]) -> Result<Self, error::Error> {
+        // 1 (tag) + 2 * sizeof<u64> (delays)
+        let expected_len = 1 + 2 * size_of::<u64>();
+        if b.len() != expected_len {
+            return Err(error::Error::new(
+                ErrorKind::MalformedRequest,
+                format!(
+                    "'set traffic rates' request has invalid length. expected: {} got: {}",
+                    expected_len,
+                    b.len()
+                ),
+            ));
+        }
+
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], SET_TRAFFIC_RATES_REQUEST_TAG);
+
+        let loop_cover_traffic_average_delay_ms =
+            u64::from_be_bytes(b[1..1 + size_of::<u64>()].try_into().unwrap());
+        let message_sending_average_delay_ms =
+            u64::from_be_bytes(b[1 + size_of::<u64>()..].try_into().unwrap());
+
+        Ok(ClientRequest::SetTrafficRates {
+            loop_cover_traffic_average_delay_ms,
+            message_sending_average_delay_ms,
+        })
+    }
+
+    // SET_TRAFFIC_MODE_REQUEST_TAG || mode
+    fn serialize_set_traffic_mode(mode: TrafficMode) -> Vec<u8> {
+        vec![SET_TRAFFIC_MODE_REQUEST_TAG, mode as u8]
+    }
+
+    // SET_TRAFFIC_MODE_REQUEST_TAG || mode
+    fn deserialize_set_traffic_mode(b: &[u8]) -> Result<Self, error::Error> {
+        if b.len() != 2 {
+            return Err(error::Error::new(
+                ErrorKind::MalformedRequest,
+                format!(
+                    "'set traffic mode' request has invalid length. expected: 2 got: {}",
+                    b.len()
+                ),
+            ));
+        }
+
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], SET_TRAFFIC_MODE_REQUEST_TAG);
+
+        Ok(ClientRequest::SetTrafficMode {
+            mode: TrafficMode::try_from(b[1])?,
+        })
+    }
+
+    /// Serializes the request into its binary representation. It fails if the attached
+    /// message headers can't be represented, for example if any of their values is too long.
+    pub fn serialize(self) -> Result<Vec<u8>, error::Error> {
+        let bytes = match self {
             ClientRequest::Send {
                 recipient,
                 message,
-                with_reply_surb,
-            } => Self::serialize_send(recipient, message, with_reply_surb),
+                num_reply_surbs,
+                with_receipt,
+                headers,
+            } => Self::serialize_send(recipient, message, num_reply_surbs, with_receipt, headers)?,
 
             ClientRequest::Reply {
                 message,
@@ -216,7 +831,51 @@ impl ClientRequest {
             } => Self::serialize_reply(message, reply_surb),
 
             ClientRequest::SelfAddress => Self::serialize_self_address(),
-        }
+
+            ClientRequest::Bandwidth => Self::serialize_bandwidth(),
+
+            ClientRequest::OpenStream {
+                recipient,
+                num_reply_surbs,
+            } => Self::serialize_open_stream(recipient, num_reply_surbs),
+
+            ClientRequest::StreamChunk { stream_id, data } => {
+                Self::serialize_stream_chunk(stream_id, data)
+            }
+
+            ClientRequest::CloseStream { stream_id } => Self::serialize_close_stream(stream_id),
+
+            ClientRequest::GatewayInfo => Self::serialize_gateway_info(),
+
+            ClientRequest::Topology => Self::serialize_topology(),
+
+            ClientRequest::PendingAcks => Self::serialize_pending_acks(),
+
+            ClientRequest::CoverTraffic => Self::serialize_cover_traffic(),
+
+            ClientRequest::RefreshTopology => Self::serialize_refresh_topology(),
+
+            ClientRequest::InboxCount => Self::serialize_inbox_count(),
+
+            ClientRequest::FetchInbox {
+                start_after,
+                page_size,
+                remove,
+            } => Self::serialize_fetch_inbox(start_after, page_size, remove),
+
+            ClientRequest::DeleteInbox { ids } => Self::serialize_delete_inbox(ids),
+
+            ClientRequest::SetTrafficRates {
+                loop_cover_traffic_average_delay_ms,
+                message_sending_average_delay_ms,
+            } => Self::serialize_set_traffic_rates(
+                loop_cover_traffic_average_delay_ms,
+                message_sending_average_delay_ms,
+            ),
+
+            ClientRequest::SetTrafficMode { mode } => Self::serialize_set_traffic_mode(mode),
+        };
+        Ok(bytes)
     }
 
     pub fn deserialize(b: &[u8]) -> Result<Self, error::Error> {
@@ -242,9 +901,24 @@ impl ClientRequest {
 
         // determine what kind of request that is and try to deserialize it
         match request_tag {
-            SEND_REQUEST_TAG => Self::deserialize_send(b),
+            SEND_REQUEST_TAG | SEND_WITH_RECEIPT_REQUEST_TAG => Self::deserialize_send(b),
+            SEND_WITH_HEADERS_REQUEST_TAG => Self::deserialize_send_with_headers(b),
             REPLY_REQUEST_TAG => Self::deserialize_reply(b),
             SELF_ADDRESS_REQUEST_TAG => Ok(Self::deserialize_self_address(b)),
+            BANDWIDTH_REQUEST_TAG => Ok(Self::deserialize_bandwidth(b)),
+            OPEN_STREAM_REQUEST_TAG => Self::deserialize_open_stream(b),
+            STREAM_CHUNK_REQUEST_TAG => Self::deserialize_stream_chunk(b),
+            CLOSE_STREAM_REQUEST_TAG => Self::deserialize_close_stream(b),
+            GATEWAY_INFO_REQUEST_TAG => Ok(Self::deserialize_gateway_info(b)),
+            TOPOLOGY_REQUEST_TAG => Ok(Self::deserialize_topology(b)),
+            PENDING_ACKS_REQUEST_TAG => Ok(Self::deserialize_pending_acks(b)),
+            COVER_TRAFFIC_REQUEST_TAG => Ok(Self::deserialize_cover_traffic(b)),
+            REFRESH_TOPOLOGY_REQUEST_TAG => Ok(Self::deserialize_refresh_topology(b)),
+            INBOX_COUNT_REQUEST_TAG => Ok(Self::deserialize_inbox_count(b)),
+            FETCH_INBOX_REQUEST_TAG => Self::deserialize_fetch_inbox(b),
+            DELETE_INBOX_REQUEST_TAG => Self::deserialize_delete_inbox(b),
+            SET_TRAFFIC_RATES_REQUEST_TAG => Self::deserialize_set_traffic_rates(b),
+            SET_TRAFFIC_MODE_REQUEST_TAG => Self::deserialize_set_traffic_mode(b),
             n => Err(error::Error::new(
                 ErrorKind::UnknownRequest,
                 format!("type {}", n),
@@ -279,20 +953,26 @@ mod tests {
         let send_request_no_surb = ClientRequest::Send {
             recipient,
             message: b"foomp".to_vec(),
-            with_reply_surb: false,
+            num_reply_surbs: 0,
+            with_receipt: false,
+            headers: None,
         };
 
-        let bytes = send_request_no_surb.serialize();
+        let bytes = send_request_no_surb.serialize().unwrap();
         let recovered = ClientRequest::deserialize(&bytes).unwrap();
         match recovered {
             ClientRequest::Send {
                 recipient,
                 message,
-                with_reply_surb,
+                num_reply_surbs,
+                with_receipt,
+                headers,
             } => {
                 assert_eq!(recipient.to_string(), recipient_string);
                 assert_eq!(message, b"foomp".to_vec());
-                assert!(!with_reply_surb)
+                assert_eq!(num_reply_surbs, 0);
+                assert!(!with_receipt);
+                assert!(headers.is_none())
             }
             _ => unreachable!(),
         }
@@ -300,23 +980,80 @@ mod tests {
         let send_request_surb = ClientRequest::Send {
             recipient,
             message: b"foomp".to_vec(),
-            with_reply_surb: true,
+            num_reply_surbs: 5,
+            with_receipt: true,
+            headers: None,
         };
 
-        let bytes = send_request_surb.serialize();
+        let bytes = send_request_surb.serialize().unwrap();
         let recovered = ClientRequest::deserialize(&bytes).unwrap();
         match recovered {
             ClientRequest::Send {
                 recipient,
                 message,
-                with_reply_surb,
+                num_reply_surbs,
+                with_receipt,
+                headers,
             } => {
                 assert_eq!(recipient.to_string(), recipient_string);
                 assert_eq!(message, b"foomp".to_vec());
-                assert!(with_reply_surb)
+                assert_eq!(num_reply_surbs, 5);
+                assert!(with_receipt);
+                assert!(headers.is_none())
             }
             _ => unreachable!(),
         }
+
+        let message_headers = MessageHeaders {
+            content_type: Some("application/json".to_string()),
+            correlation_id: Some("request-1".to_string()),
+            timestamp: Some(1234567890),
+            ..Default::default()
+        };
+        let send_request_headers = ClientRequest::Send {
+            recipient,
+            message: b"foomp".to_vec(),
+            num_reply_surbs: 2,
+            with_receipt: true,
+            headers: Some(message_headers.clone()),
+        };
+
+        let bytes = send_request_headers.serialize().unwrap();
+        let recovered = ClientRequest::deserialize(&bytes).unwrap();
+        match recovered {
+            ClientRequest::Send {
+                recipient,
+                message,
+                num_reply_surbs,
+                with_receipt,
+                headers,
+            } => {
+                assert_eq!(recipient.to_string(), recipient_string);
+                assert_eq!(message, b"foomp".to_vec());
+                assert_eq!(num_reply_surbs, 2);
+                assert!(with_receipt);
+                assert_eq!(headers, Some(message_headers))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn send_request_with_too_long_headers_is_not_serialized() {
+        let recipient = Recipient::try_from_base58_string("CytBseW6yFXUMzz4SGAKdNLGR7q3sJLLYxyBGvutNEQV.4QXYyEVc5fUDjmmi8PrHN9tdUFV4PCvSJE1278cHyvoe@4sBbL1ngf1vtNqykydQKTFh26sQCw888GpUqvPvyNB4f").unwrap();
+        let send_request = ClientRequest::Send {
+            recipient,
+            message: b"foomp".to_vec(),
+            num_reply_surbs: 0,
+            with_receipt: false,
+            headers: Some(MessageHeaders {
+                correlation_id: Some("x".repeat(u16::MAX as usize + 1)),
+                ..Default::default()
+            }),
+        };
+
+        let err = send_request.serialize().unwrap_err();
+        assert!(err.kind == ErrorKind::MalformedRequest);
     }
 
     #[test]
@@ -328,7 +1065,7 @@ mod tests {
             reply_surb,
         };
 
-        let bytes = reply_request.serialize();
+        let bytes = reply_request.serialize().unwrap();
         let recovered = ClientRequest::deserialize(&bytes).unwrap();
         match recovered {
             ClientRequest::Reply {
@@ -345,11 +1082,200 @@ mod tests {
     #[test]
     fn self_address_request_serialization_works() {
         let self_address_request = ClientRequest::SelfAddress;
-        let bytes = self_address_request.serialize();
+        let bytes = self_address_request.serialize().unwrap();
         let recovered = ClientRequest::deserialize(&bytes).unwrap();
         match recovered {
             ClientRequest::SelfAddress => (),
             _ => unreachable!(),
         }
     }
+
+    #[test]
+    fn bandwidth_request_serialization_works() {
+        let bandwidth_request = ClientRequest::Bandwidth;
+        let bytes = bandwidth_request.serialize().unwrap();
+        let recovered = ClientRequest::deserialize(&bytes).unwrap();
+        match recovered {
+            ClientRequest::Bandwidth => (),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn stream_requests_serialization_works() {
+        let recipient = Recipient::try_from_base58_string("CytBseW6yFXUMzz4SGAKdNLGR7q3sJLLYxyBGvutNEQV.4QXYyEVc5fUDjmmi8PrHN9tdUFV4PCvSJE1278cHyvoe@4sBbL1ngf1vtNqykydQKTFh26sQCw888GpUqvPvyNB4f").unwrap();
+        let recipient_string = recipient.to_string();
+
+        let open_stream_request = ClientRequest::OpenStream {
+            recipient,
+            num_reply_surbs: 3,
+        };
+        let bytes = open_stream_request.serialize().unwrap();
+        let recovered = ClientRequest::deserialize(&bytes).unwrap();
+        match recovered {
+            ClientRequest::OpenStream {
+                recipient,
+                num_reply_surbs,
+            } => {
+                assert_eq!(recipient.to_string(), recipient_string);
+                assert_eq!(num_reply_surbs, 3);
+            }
+            _ => unreachable!(),
+        }
+
+        let stream_chunk_request = ClientRequest::StreamChunk {
+            stream_id: 42,
+            data: b"foomp".to_vec(),
+        };
+        let bytes = stream_chunk_request.serialize().unwrap();
+        let recovered = ClientRequest::deserialize(&bytes).unwrap();
+        match recovered {
+            ClientRequest::StreamChunk { stream_id, data } => {
+                assert_eq!(stream_id, 42);
+                assert_eq!(data, b"foomp".to_vec());
+            }
+            _ => unreachable!(),
+        }
+
+        let close_stream_request = ClientRequest::CloseStream { stream_id: 42 };
+        let bytes = close_stream_request.serialize().unwrap();
+        let recovered = ClientRequest::deserialize(&bytes).unwrap();
+        match recovered {
+            ClientRequest::CloseStream { stream_id } => assert_eq!(stream_id, 42),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn control_requests_serialization_works() {
+        let bytes = ClientRequest::GatewayInfo.serialize().unwrap();
+        assert!(matches!(
+            ClientRequest::deserialize(&bytes).unwrap(),
+            ClientRequest::GatewayInfo
+        ));
+
+        let bytes = ClientRequest::Topology.serialize().unwrap();
+        assert!(matches!(
+            ClientRequest::deserialize(&bytes).unwrap(),
+            ClientRequest::Topology
+        ));
+
+        let bytes = ClientRequest::PendingAcks.serialize().unwrap();
+        assert!(matches!(
+            ClientRequest::deserialize(&bytes).unwrap(),
+            ClientRequest::PendingAcks
+        ));
+
+        let bytes = ClientRequest::CoverTraffic.serialize().unwrap();
+        assert!(matches!(
+            ClientRequest::deserialize(&bytes).unwrap(),
+            ClientRequest::CoverTraffic
+        ));
+
+        let bytes = ClientRequest::RefreshTopology.serialize().unwrap();
+        assert!(matches!(
+            ClientRequest::deserialize(&bytes).unwrap(),
+            ClientRequest::RefreshTopology
+        ));
+    }
+
+    #[test]
+    fn inbox_requests_serialization_works() {
+        let bytes = ClientRequest::InboxCount.serialize().unwrap();
+        assert!(matches!(
+            ClientRequest::deserialize(&bytes).unwrap(),
+            ClientRequest::InboxCount
+        ));
+
+        let bytes = ClientRequest::FetchInbox {
+            start_after: None,
+            page_size: 10,
+            remove: true,
+        }
+        .serialize()
+        .unwrap();
+        match ClientRequest::deserialize(&bytes).unwrap() {
+            ClientRequest::FetchInbox {
+                start_after,
+                page_size,
+                remove,
+            } => {
+                assert!(start_after.is_none());
+                assert_eq!(page_size, 10);
+                assert!(remove)
+            }
+            _ => unreachable!(),
+        }
+
+        let bytes = ClientRequest::FetchInbox {
+            start_after: Some(42),
+            page_size: 5,
+            remove: false,
+        }
+        .serialize()
+        .unwrap();
+        match ClientRequest::deserialize(&bytes).unwrap() {
+            ClientRequest::FetchInbox {
+                start_after,
+                page_size,
+                remove,
+            } => {
+                assert_eq!(start_after, Some(42));
+                assert_eq!(page_size, 5);
+                assert!(!remove)
+            }
+            _ => unreachable!(),
+        }
+
+        let bytes = ClientRequest::DeleteInbox {
+            ids: vec![1, 2, 42],
+        }
+        .serialize()
+        .unwrap();
+        match ClientRequest::deserialize(&bytes).unwrap() {
+            ClientRequest::DeleteInbox { ids } => assert_eq!(ids, vec![1, 2, 42]),
+            _ => unreachable!(),
+        }
+        assert!(ClientRequest::deserialize(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn traffic_requests_serialization_works() {
+        let bytes = ClientRequest::SetTrafficRates {
+            loop_cover_traffic_average_delay_ms: 200,
+            message_sending_average_delay_ms: 20,
+        }
+        .serialize()
+        .unwrap();
+        match ClientRequest::deserialize(&bytes).unwrap() {
+            ClientRequest::SetTrafficRates {
+                loop_cover_traffic_average_delay_ms,
+                message_sending_average_delay_ms,
+            } => {
+                assert_eq!(loop_cover_traffic_average_delay_ms, 200);
+                assert_eq!(message_sending_average_delay_ms, 20)
+            }
+            _ => unreachable!(),
+        }
+        assert!(ClientRequest::deserialize(&bytes[..bytes.len() - 1]).is_err());
+
+        for mode in [
+            TrafficMode::Normal,
+            TrafficMode::LowPower,
+            TrafficMode::HighAnonymity,
+        ] {
+            let bytes = ClientRequest::SetTrafficMode { mode }.serialize().unwrap();
+            match ClientRequest::deserialize(&bytes).unwrap() {
+                ClientRequest::SetTrafficMode { mode: recovered } => assert_eq!(recovered, mode),
+                _ => unreachable!(),
+            }
+        }
+        assert!(ClientRequest::deserialize(&[SET_TRAFFIC_MODE_REQUEST_TAG, 42]).is_err());
+
+        let text_request = r#"{"type":"setTrafficMode","mode":"lowPower"}"#.to_string();
+        match ClientRequest::try_from_text(text_request).unwrap() {
+            ClientRequest::SetTrafficMode { mode } => assert_eq!(mode, TrafficMode::LowPower),
+            _ => unreachable!(),
+        }
+    }
 }
@@ -4,12 +4,11 @@
 // all variable size data is always prefixed with u64 length
 // tags are u8
 
-#![allow(unknown_lints)] // due to using `clippy::branches_sharing_code` which does not exist on `stable` just yet
-
 use crate::error::{self, ErrorKind};
 use crate::text::ServerResponseText;
 use nymsphinx::addressing::clients::Recipient;
 use nymsphinx::anonymous_replies::ReplySurb;
+use nymsphinx::headers::{MessageHeaders, MAX_REPLY_SURBS};
 use nymsphinx::receiver::ReconstructedMessage;
 use std::convert::TryInto;
 use std::mem::size_of;
@@ -23,11 +22,162 @@ pub const RECEIVED_RESPONSE_TAG: u8 = 0x01;
 /// Value tag representing [`SelfAddress`] variant of the [`ServerResponse`]
 pub const SELF_ADDRESS_RESPONSE_TAG: u8 = 0x02;
 
+/// Value tag representing [`MessageSent`] variant of the [`ServerResponse`]
+pub const MESSAGE_SENT_RESPONSE_TAG: u8 = 0x03;
+
+/// Value tag representing [`Delivered`] variant of the [`ServerResponse`]
+pub const DELIVERED_RESPONSE_TAG: u8 = 0x04;
+
+/// Value tag representing [`DeliveryFailed`] variant of the [`ServerResponse`]
+pub const DELIVERY_FAILED_RESPONSE_TAG: u8 = 0x05;
+
+/// Value tag representing [`DeliveryExpired`] variant of the [`ServerResponse`]
+pub const DELIVERY_EXPIRED_RESPONSE_TAG: u8 = 0x06;
+
+/// Value tag representing [`Bandwidth`] variant of the [`ServerResponse`]
+pub const BANDWIDTH_RESPONSE_TAG: u8 = 0x07;
+
+/// Value tag representing [`StreamOpened`] variant of the [`ServerResponse`]
+pub const STREAM_OPENED_RESPONSE_TAG: u8 = 0x08;
+
+/// Value tag representing [`StreamChunkAccepted`] variant of the [`ServerResponse`]
+pub const STREAM_CHUNK_ACCEPTED_RESPONSE_TAG: u8 = 0x09;
+
+/// Value tag representing [`StreamClosed`] variant of the [`ServerResponse`]
+pub const STREAM_CLOSED_RESPONSE_TAG: u8 = 0x0A;
+
+/// Value tag representing [`Received`] variant of the [`ServerResponse`] with the message headers attached
+pub const RECEIVED_WITH_HEADERS_RESPONSE_TAG: u8 = 0x0B;
+
+/// Value tag representing [`GatewayInfo`] variant of the [`ServerResponse`]
+pub const GATEWAY_INFO_RESPONSE_TAG: u8 = 0x0C;
+
+/// Value tag representing [`Topology`] variant of the [`ServerResponse`]
+pub const TOPOLOGY_RESPONSE_TAG: u8 = 0x0D;
+
+/// Value tag representing [`PendingAcks`] variant of the [`ServerResponse`]
+pub const PENDING_ACKS_RESPONSE_TAG: u8 = 0x0E;
+
+/// Value tag representing [`CoverTraffic`] variant of the [`ServerResponse`]
+pub const COVER_TRAFFIC_RESPONSE_TAG: u8 = 0x0F;
+
+/// Value tag representing [`TopologyRefreshed`] variant of the [`ServerResponse`]
+pub const TOPOLOGY_REFRESHED_RESPONSE_TAG: u8 = 0x10;
+
+/// Value tag representing [`InboxCount`] variant of the [`ServerResponse`]
+pub const INBOX_COUNT_RESPONSE_TAG: u8 = 0x11;
+
+/// Value tag representing [`InboxPage`] variant of the [`ServerResponse`]
+pub const INBOX_PAGE_RESPONSE_TAG: u8 = 0x12;
+
+/// Value tag representing [`InboxDeleted`] variant of the [`ServerResponse`]
+pub const INBOX_DELETED_RESPONSE_TAG: u8 = 0x13;
+
 #[derive(Debug)]
 pub enum ServerResponse {
     Received(ReconstructedMessage),
     SelfAddress(Recipient),
     Error(error::Error),
+    /// Identifier assigned to the message sent with a delivery receipt requested.
+    MessageSent {
+        message_id: u64,
+    },
+    /// All fragments of the message got acknowledged by its recipient.
+    Delivered {
+        message_id: u64,
+    },
+    /// The message was abandoned after some of its fragments were retransmitted
+    /// the maximum allowed number of times. If the message could not be sent at all,
+    /// both `retransmissions` and `failed_fragments` are 0.
+    DeliveryFailed {
+        message_id: u64,
+        retransmissions: u32,
+        /// Number of fragments of the message that were never acknowledged.
+        failed_fragments: u32,
+    },
+    /// The message was abandoned as it was not fully acknowledged before its deadline.
+    DeliveryExpired {
+        message_id: u64,
+        /// Number of fragments of the message that were never acknowledged.
+        failed_fragments: u32,
+    },
+    /// Bandwidth remaining at the gateway and the rate at which it's being consumed.
+    Bandwidth {
+        /// Bandwidth, in bytes, still available at the gateway.
+        remaining: i64,
+        /// Total bandwidth, in bytes, consumed since the client has started.
+        consumed: u64,
+        /// Average number of bytes consumed per second over the last minute.
+        burn_rate: f64,
+    },
+    /// Identifier assigned to the newly opened stream that has to be used for sending its chunks.
+    StreamOpened {
+        stream_id: u64,
+    },
+    /// The chunk was split into packets and put into the out queue.
+    StreamChunkAccepted {
+        stream_id: u64,
+        /// Number of real packets in the out queue that are still waiting to be sent to
+        /// the mix network. The sender should hold off with further chunks while it keeps growing.
+        pending_packets: u64,
+    },
+    /// The stream was closed and won't accept any more chunks.
+    StreamClosed {
+        stream_id: u64,
+        /// Total number of chunks sent on the stream.
+        chunks: u64,
+        /// Total number of bytes sent on the stream.
+        bytes: u64,
+    },
+    /// Details of the gateway the client is currently connected to.
+    GatewayInfo {
+        gateway_id: String,
+        gateway_owner: String,
+        gateway_listener: String,
+    },
+    /// Identities of the nodes in the network topology currently used by the client.
+    Topology {
+        /// Layer and identity of every mixnode.
+        mixnodes: Vec<(u8, String)>,
+        gateways: Vec<String>,
+    },
+    /// Packets and messages the client is still waiting to get acknowledged.
+    PendingAcks {
+        /// Number of sent packets that are yet to be acknowledged by their recipients.
+        pending_acks: u64,
+        /// Number of sent messages that are yet to be either fully acknowledged or abandoned.
+        pending_messages: u64,
+        /// Number of real packets in the out queue that are still waiting to be sent.
+        pending_packets: u64,
+    },
+    /// Current rates of the cover traffic and the number of packets sent since the client has started.
+    CoverTraffic {
+        loop_cover_traffic_average_delay_ms: u64,
+        message_sending_average_delay_ms: u64,
+        loop_cover_packets_sent: u64,
+        out_queue_cover_packets_sent: u64,
+        real_packets_sent: u64,
+    },
+    /// The network topology got refreshed.
+    TopologyRefreshed {
+        /// Whether the new topology is sufficient to route any packets through.
+        routable: bool,
+    },
+    /// Number of messages the gateway has stored while the client was offline.
+    InboxCount {
+        count: u64,
+    },
+    /// The gateway has pushed the next page of the stored messages.
+    InboxPage {
+        /// Ids of the pushed messages.
+        ids: Vec<i64>,
+        /// Id to start the next page after, if there are any more messages stored.
+        next_start_after: Option<i64>,
+    },
+    /// The gateway has removed the stored messages.
+    InboxDeleted {
+        removed: u64,
+    },
 }
 
 impl ServerResponse {
@@ -38,37 +188,74 @@ impl ServerResponse {
         })
     }
 
-    // RECEIVED_RESPONSE_TAG || with_reply || (surb_len || surb) || msg_len || msg
-    fn serialize_received(reconstructed_message: ReconstructedMessage) -> Vec<u8> {
+    // RECEIVED_RESPONSE_TAG || num_surbs || (surb_len || surb)* || msg_len || msg
+    // or if the sender has attached headers:
+    // RECEIVED_WITH_HEADERS_RESPONSE_TAG || num_surbs || (surb_len || surb)* || headers || msg_len || msg
+    fn serialize_received(
+        reconstructed_message: ReconstructedMessage,
+    ) -> Result<Vec<u8>, error::Error> {
         let message_len_bytes = (reconstructed_message.message.len() as u64).to_be_bytes();
-        if let Some(reply_surb) = reconstructed_message.reply_surb {
-            let reply_surb_bytes = reply_surb.to_bytes();
-            let surb_len_bytes = (reply_surb_bytes.len() as u64).to_be_bytes();
-
-            // with_reply || surb_len || surb || msg_len || msg
-            std::iter::once(RECEIVED_RESPONSE_TAG)
-                .chain(std::iter::once(true as u8))
-                .chain(surb_len_bytes.iter().cloned())
-                .chain(reply_surb_bytes.iter().cloned())
-                .chain(message_len_bytes.iter().cloned())
-                .chain(reconstructed_message.message.into_iter())
-                .collect()
-        } else {
-            // without_reply || msg_len || msg
-            std::iter::once(RECEIVED_RESPONSE_TAG)
-                .chain(std::iter::once(false as u8))
-                .chain(message_len_bytes.iter().cloned())
-                .chain(reconstructed_message.message.into_iter())
-                .collect()
+        // the receiver rejects messages with more reply SURBs than that,
+        // but the count still has to fit in a single byte
+        if reconstructed_message.reply_surbs.len() > MAX_REPLY_SURBS as usize {
+            return Err(error::Error::new(
+                ErrorKind::MalformedResponse,
+                format!(
+                    "received message carries {} reply SURBs while the maximum is {}",
+                    reconstructed_message.reply_surbs.len(),
+                    MAX_REPLY_SURBS
+                ),
+            ));
         }
+        let num_surbs = reconstructed_message.reply_surbs.len() as u8;
+
+        let surbs_bytes = reconstructed_message
+            .reply_surbs
+            .iter()
+            .flat_map(|reply_surb| {
+                let reply_surb_bytes = reply_surb.to_bytes();
+                let surb_len_bytes = (reply_surb_bytes.len() as u64).to_be_bytes();
+                surb_len_bytes
+                    .iter()
+                    .cloned()
+                    .chain(reply_surb_bytes.into_iter())
+                    .collect::<Vec<_>>()
+            });
+
+        // the headers are self-describing, i.e. they're prefixed with their own length
+        let (tag, headers_bytes) = match &reconstructed_message.headers {
+            Some(headers) => {
+                let headers_bytes = headers.try_to_bytes().map_err(|err| {
+                    error::Error::new(
+                        ErrorKind::MalformedResponse,
+                        format!(
+                            "the headers of the received message can't be serialized - {}",
+                            err
+                        ),
+                    )
+                })?;
+                (RECEIVED_WITH_HEADERS_RESPONSE_TAG, headers_bytes)
+            }
+            None => (RECEIVED_RESPONSE_TAG, Vec::new()),
+        };
+
+        Ok(std::iter::once(tag)
+            .chain(std::iter::once(num_surbs))
+            .chain(surbs_bytes)
+            .chain(headers_bytes.into_iter())
+            .chain(message_len_bytes.iter().cloned())
+            .chain(reconstructed_message.message.into_iter())
+            .collect())
     }
 
-    // RECEIVED_RESPONSE_TAG || with_reply || (surb_len || surb) || msg_len || msg
+    // RECEIVED_RESPONSE_TAG || num_surbs || (surb_len || surb)* || msg_len || msg
+    // or if the sender has attached headers:
+    // RECEIVED_WITH_HEADERS_RESPONSE_TAG || num_surbs || (surb_len || surb)* || headers || msg_len || msg
     fn deserialize_received(b: &[u8]) -> Result<Self, error::Error> {
         // this MUST match because it was called by 'deserialize'
-        debug_assert_eq!(b[0], RECEIVED_RESPONSE_TAG);
+        debug_assert!(b[0] == RECEIVED_RESPONSE_TAG || b[0] == RECEIVED_WITH_HEADERS_RESPONSE_TAG);
 
-        // we must be able to read at the very least if it has a reply_surb and length of some field
+        // we must be able to read at the very least the number of reply surbs and length of some field
         if b.len() < 2 + size_of::<u64>() {
             return Err(error::Error::new(
                 ErrorKind::TooShortResponse,
@@ -76,35 +263,33 @@ impl ServerResponse {
             ));
         }
 
-        let with_reply_surb = match b[1] {
-            0 => false,
-            1 => true,
-            n => {
+        let num_surbs = b[1];
+
+        let mut reply_surbs = Vec::with_capacity(num_surbs as usize);
+        let mut i = 2;
+        for _ in 0..num_surbs {
+            // make sure we can read the surb length and the following message length
+            if b.len() < i + 2 * size_of::<u64>() {
                 return Err(error::Error::new(
                     ErrorKind::MalformedResponse,
-                    format!("invalid reply flag {}", n),
-                ))
+                    "not enough bytes to read reply_surb length!".to_string(),
+                ));
             }
-        };
 
-        // this is a false positive as even though the code is the same, it refers to different things
-        #[allow(clippy::branches_sharing_code)]
-        if with_reply_surb {
             let reply_surb_len =
-                u64::from_be_bytes(b[2..2 + size_of::<u64>()].as_ref().try_into().unwrap());
+                u64::from_be_bytes(b[i..i + size_of::<u64>()].as_ref().try_into().unwrap());
+            i += size_of::<u64>();
 
             // make sure we won't go out of bounds here
-            if reply_surb_len > (b.len() - 2 + 2 * size_of::<u64>()) as u64 {
+            if reply_surb_len > (b.len() - i - size_of::<u64>()) as u64 {
                 return Err(error::Error::new(
                     ErrorKind::MalformedResponse,
                     "not enough bytes to read reply_surb bytes!".to_string(),
                 ));
             }
 
-            let surb_bound = 2 + size_of::<u64>() + reply_surb_len as usize;
-
-            let reply_surb_bytes = &b[2 + size_of::<u64>()..surb_bound];
-            let reply_surb = match ReplySurb::from_bytes(reply_surb_bytes) {
+            let surb_bound = i + reply_surb_len as usize;
+            let reply_surb = match ReplySurb::from_bytes(&b[i..surb_bound]) {
                 Ok(reply_surb) => reply_surb,
                 Err(err) => {
                     return Err(error::Error::new(
@@ -113,49 +298,50 @@ impl ServerResponse {
                     ))
                 }
             };
+            reply_surbs.push(reply_surb);
+            i = surb_bound;
+        }
 
-            let message_len = u64::from_be_bytes(
-                b[surb_bound..surb_bound + size_of::<u64>()]
-                    .as_ref()
-                    .try_into()
-                    .unwrap(),
-            );
-            let message = &b[surb_bound + size_of::<u64>()..];
-            if message.len() as u64 != message_len {
-                return Err(error::Error::new(
-                    ErrorKind::MalformedResponse,
-                    format!(
-                        "message len has inconsistent length. specified: {} got: {}",
-                        message_len,
-                        message.len()
-                    ),
-                ));
-            }
-
-            Ok(ServerResponse::Received(ReconstructedMessage {
-                message: message.to_vec(),
-                reply_surb: Some(reply_surb),
-            }))
+        let headers = if b[0] == RECEIVED_WITH_HEADERS_RESPONSE_TAG {
+            let (headers, headers_len) =
+                MessageHeaders::try_from_bytes(&b[i..]).map_err(|err| {
+                    error::Error::new(
+                        ErrorKind::MalformedResponse,
+                        format!("malformed headers: {}", err),
+                    )
+                })?;
+            i += headers_len;
+            Some(headers)
         } else {
-            let message_len =
-                u64::from_be_bytes(b[2..2 + size_of::<u64>()].as_ref().try_into().unwrap());
-            let message = &b[2 + size_of::<u64>()..];
-            if message.len() as u64 != message_len {
-                return Err(error::Error::new(
-                    ErrorKind::MalformedResponse,
-                    format!(
-                        "message len has inconsistent length. specified: {} got: {}",
-                        message_len,
-                        message.len()
-                    ),
-                ));
-            }
+            None
+        };
 
-            Ok(ServerResponse::Received(ReconstructedMessage {
-                message: message.to_vec(),
-                reply_surb: None,
-            }))
+        if b.len() < i + size_of::<u64>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortResponse,
+                "not enough data provided to recover message length".to_string(),
+            ));
+        }
+
+        let message_len =
+            u64::from_be_bytes(b[i..i + size_of::<u64>()].as_ref().try_into().unwrap());
+        let message = &b[i + size_of::<u64>()..];
+        if message.len() as u64 != message_len {
+            return Err(error::Error::new(
+                ErrorKind::MalformedResponse,
+                format!(
+                    "message len has inconsistent length. specified: {} got: {}",
+                    message_len,
+                    message.len()
+                ),
+            ));
         }
+
+        Ok(ServerResponse::Received(ReconstructedMessage {
+            message: message.to_vec(),
+            reply_surbs,
+            headers,
+        }))
     }
 
     // SELF_ADDRESS_RESPONSE_TAG || self_address
@@ -193,6 +379,566 @@ impl ServerResponse {
         Ok(ServerResponse::SelfAddress(recipient))
     }
 
+    // MESSAGE_SENT_RESPONSE_TAG || message_id
+    // or
+    // DELIVERED_RESPONSE_TAG || message_id
+    fn serialize_message_id(tag: u8, message_id: u64) -> Vec<u8> {
+        std::iter::once(tag)
+            .chain(message_id.to_be_bytes().iter().cloned())
+            .collect()
+    }
+
+    // MESSAGE_SENT_RESPONSE_TAG || message_id
+    // or
+    // DELIVERED_RESPONSE_TAG || message_id
+    fn deserialize_message_id(b: &[u8]) -> Result<u64, error::Error> {
+        if b.len() != 1 + size_of::<u64>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortResponse,
+                "not enough data provided to recover message id".to_string(),
+            ));
+        }
+
+        Ok(u64::from_be_bytes(b[1..].as_ref().try_into().unwrap()))
+    }
+
+    // DELIVERY_FAILED_RESPONSE_TAG || message_id || retransmissions || failed_fragments
+    fn serialize_delivery_failed(
+        message_id: u64,
+        retransmissions: u32,
+        failed_fragments: u32,
+    ) -> Vec<u8> {
+        std::iter::once(DELIVERY_FAILED_RESPONSE_TAG)
+            .chain(message_id.to_be_bytes().iter().cloned())
+            .chain(retransmissions.to_be_bytes().iter().cloned())
+            .chain(failed_fragments.to_be_bytes().iter().cloned())
+            .collect()
+    }
+
+    // DELIVERY_FAILED_RESPONSE_TAG || message_id || retransmissions || failed_fragments
+    fn deserialize_delivery_failed(b: &[u8]) -> Result<Self, error::Error> {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], DELIVERY_FAILED_RESPONSE_TAG);
+
+        if b.len() != 1 + size_of::<u64>() + 2 * size_of::<u32>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortResponse,
+                "not enough data provided to recover 'delivery_failed'".to_string(),
+            ));
+        }
+
+        let i = 1 + size_of::<u64>();
+        let message_id = u64::from_be_bytes(b[1..i].as_ref().try_into().unwrap());
+        let retransmissions =
+            u32::from_be_bytes(b[i..i + size_of::<u32>()].as_ref().try_into().unwrap());
+        let failed_fragments =
+            u32::from_be_bytes(b[i + size_of::<u32>()..].as_ref().try_into().unwrap());
+
+        Ok(ServerResponse::DeliveryFailed {
+            message_id,
+            retransmissions,
+            failed_fragments,
+        })
+    }
+
+    // DELIVERY_EXPIRED_RESPONSE_TAG || message_id || failed_fragments
+    fn serialize_delivery_expired(message_id: u64, failed_fragments: u32) -> Vec<u8> {
+        std::iter::once(DELIVERY_EXPIRED_RESPONSE_TAG)
+            .chain(message_id.to_be_bytes().iter().cloned())
+            .chain(failed_fragments.to_be_bytes().iter().cloned())
+            .collect()
+    }
+
+    // DELIVERY_EXPIRED_RESPONSE_TAG || message_id || failed_fragments
+    fn deserialize_delivery_expired(b: &[u8]) -> Result<Self, error::Error> {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], DELIVERY_EXPIRED_RESPONSE_TAG);
+
+        if b.len() != 1 + size_of::<u64>() + size_of::<u32>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortResponse,
+                "not enough data provided to recover 'delivery_expired'".to_string(),
+            ));
+        }
+
+        let message_id =
+            u64::from_be_bytes(b[1..1 + size_of::<u64>()].as_ref().try_into().unwrap());
+        let failed_fragments =
+            u32::from_be_bytes(b[1 + size_of::<u64>()..].as_ref().try_into().unwrap());
+
+        Ok(ServerResponse::DeliveryExpired {
+            message_id,
+            failed_fragments,
+        })
+    }
+
+    // BANDWIDTH_RESPONSE_TAG || remaining || consumed || burn_rate
+    fn serialize_bandwidth(remaining: i64, consumed: u64, burn_rate: f64) -> Vec<u8> {
+        std::iter::once(BANDWIDTH_RESPONSE_TAG)
+            .chain(remaining.to_be_bytes().iter().cloned())
+            .chain(consumed.to_be_bytes().iter().cloned())
+            .chain(burn_rate.to_be_bytes().iter().cloned())
+            .collect()
+    }
+
+    // BANDWIDTH_RESPONSE_TAG || remaining || consumed || burn_rate
+    fn deserialize_bandwidth(b: &[u8]) -> Result<Self, error::Error> {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], BANDWIDTH_RESPONSE_TAG);
+
+        if b.len() != 1 + size_of::<i64>() + size_of::<u64>() + size_of::<f64>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortResponse,
+                "not enough data provided to recover 'bandwidth'".to_string(),
+            ));
+        }
+
+        let i = 1 + size_of::<i64>();
+        let j = i + size_of::<u64>();
+        let remaining = i64::from_be_bytes(b[1..i].as_ref().try_into().unwrap());
+        let consumed = u64::from_be_bytes(b[i..j].as_ref().try_into().unwrap());
+        let burn_rate = f64::from_be_bytes(b[j..].as_ref().try_into().unwrap());
+
+        Ok(ServerResponse::Bandwidth {
+            remaining,
+            consumed,
+            burn_rate,
+        })
+    }
+
+    // STREAM_OPENED_RESPONSE_TAG || stream_id
+    fn serialize_stream_opened(stream_id: u64) -> Vec<u8> {
+        std::iter::once(STREAM_OPENED_RESPONSE_TAG)
+            .chain(stream_id.to_be_bytes().iter().cloned())
+            .collect()
+    }
+
+    // STREAM_OPENED_RESPONSE_TAG || stream_id
+    fn deserialize_stream_opened(b: &[u8]) -> Result<Self, error::Error> {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], STREAM_OPENED_RESPONSE_TAG);
+
+        if b.len() != 1 + size_of::<u64>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortResponse,
+                "not enough data provided to recover 'stream_opened'".to_string(),
+            ));
+        }
+
+        let stream_id = u64::from_be_bytes(b[1..].as_ref().try_into().unwrap());
+
+        Ok(ServerResponse::StreamOpened { stream_id })
+    }
+
+    // STREAM_CHUNK_ACCEPTED_RESPONSE_TAG || stream_id || pending_packets
+    fn serialize_stream_chunk_accepted(stream_id: u64, pending_packets: u64) -> Vec<u8> {
+        std::iter::once(STREAM_CHUNK_ACCEPTED_RESPONSE_TAG)
+            .chain(stream_id.to_be_bytes().iter().cloned())
+            .chain(pending_packets.to_be_bytes().iter().cloned())
+            .collect()
+    }
+
+    // STREAM_CHUNK_ACCEPTED_RESPONSE_TAG || stream_id || pending_packets
+    fn deserialize_stream_chunk_accepted(b: &[u8]) -> Result<Self, error::Error> {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], STREAM_CHUNK_ACCEPTED_RESPONSE_TAG);
+
+        if b.len() != 1 + 2 * size_of::<u64>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortResponse,
+                "not enough data provided to recover 'stream_chunk_accepted'".to_string(),
+            ));
+        }
+
+        let i = 1 + size_of::<u64>();
+        let stream_id = u64::from_be_bytes(b[1..i].as_ref().try_into().unwrap());
+        let pending_packets = u64::from_be_bytes(b[i..].as_ref().try_into().unwrap());
+
+        Ok(ServerResponse::StreamChunkAccepted {
+            stream_id,
+            pending_packets,
+        })
+    }
+
+    // STREAM_CLOSED_RESPONSE_TAG || stream_id || chunks || bytes
+    fn serialize_stream_closed(stream_id: u64, chunks: u64, bytes: u64) -> Vec<u8> {
+        std::iter::once(STREAM_CLOSED_RESPONSE_TAG)
+            .chain(stream_id.to_be_bytes().iter().cloned())
+            .chain(chunks.to_be_bytes().iter().cloned())
+            .chain(bytes.to_be_bytes().iter().cloned())
+            .collect()
+    }
+
+    // STREAM_CLOSED_RESPONSE_TAG || stream_id || chunks || bytes
+    fn deserialize_stream_closed(b: &[u8]) -> Result<Self, error::Error> {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], STREAM_CLOSED_RESPONSE_TAG);
+
+        if b.len() != 1 + 3 * size_of::<u64>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortResponse,
+                "not enough data provided to recover 'stream_closed'".to_string(),
+            ));
+        }
+
+        let i = 1 + size_of::<u64>();
+        let j = i + size_of::<u64>();
+        let stream_id = u64::from_be_bytes(b[1..i].as_ref().try_into().unwrap());
+        let chunks = u64::from_be_bytes(b[i..j].as_ref().try_into().unwrap());
+        let bytes = u64::from_be_bytes(b[j..].as_ref().try_into().unwrap());
+
+        Ok(ServerResponse::StreamClosed {
+            stream_id,
+            chunks,
+            bytes,
+        })
+    }
+
+    // str_len || str
+    fn serialize_string(value: &str) -> Vec<u8> {
+        (value.len() as u64)
+            .to_be_bytes()
+            .iter()
+            .cloned()
+            .chain(value.bytes())
+            .collect()
+    }
+
+    // str_len || str
+    // returns the recovered string alongside the number of bytes it occupied
+    fn deserialize_string(b: &[u8], context: &str) -> Result<(String, usize), error::Error> {
+        if b.len() < size_of::<u64>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortResponse,
+                format!("not enough data provided to recover '{}'", context),
+            ));
+        }
+        let len = u64::from_be_bytes(b[..size_of::<u64>()].as_ref().try_into().unwrap()) as usize;
+        let value = match b.get(size_of::<u64>()..size_of::<u64>() + len) {
+            Some(value) => value,
+            None => {
+                return Err(error::Error::new(
+                    ErrorKind::TooShortResponse,
+                    format!("not enough data provided to recover '{}'", context),
+                ))
+            }
+        };
+
+        let value = String::from_utf8(value.to_vec()).map_err(|err| {
+            error::Error::new(
+                ErrorKind::MalformedResponse,
+                format!("malformed '{}': {:?}", context, err),
+            )
+        })?;
+        Ok((value, size_of::<u64>() + len))
+    }
+
+    // GATEWAY_INFO_RESPONSE_TAG || id_len || id || owner_len || owner || listener_len || listener
+    fn serialize_gateway_info(
+        gateway_id: String,
+        gateway_owner: String,
+        gateway_listener: String,
+    ) -> Vec<u8> {
+        std::iter::once(GATEWAY_INFO_RESPONSE_TAG)
+            .chain(Self::serialize_string(&gateway_id).into_iter())
+            .chain(Self::serialize_string(&gateway_owner).into_iter())
+            .chain(Self::serialize_string(&gateway_listener).into_iter())
+            .collect()
+    }
+
+    // GATEWAY_INFO_RESPONSE_TAG || id_len || id || owner_len || owner || listener_len || listener
+    fn deserialize_gateway_info(b: &[u8]) -> Result<Self, error::Error> {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], GATEWAY_INFO_RESPONSE_TAG);
+
+        let mut i = 1;
+        let (gateway_id, len) = Self::deserialize_string(&b[i..], "gateway_info")?;
+        i += len;
+        let (gateway_owner, len) = Self::deserialize_string(&b[i..], "gateway_info")?;
+        i += len;
+        let (gateway_listener, len) = Self::deserialize_string(&b[i..], "gateway_info")?;
+        i += len;
+
+        if i != b.len() {
+            return Err(error::Error::new(
+                ErrorKind::MalformedResponse,
+                "'gateway_info' has trailing data".to_string(),
+            ));
+        }
+
+        Ok(ServerResponse::GatewayInfo {
+            gateway_id,
+            gateway_owner,
+            gateway_listener,
+        })
+    }
+
+    // TOPOLOGY_RESPONSE_TAG || num_mixnodes || (layer || id_len || id)* || num_gateways || (id_len || id)*
+    fn serialize_topology(mixnodes: Vec<(u8, String)>, gateways: Vec<String>) -> Vec<u8> {
+        let mixnodes_bytes = mixnodes.iter().flat_map(|(layer, identity)| {
+            std::iter::once(*layer).chain(Self::serialize_string(identity).into_iter())
+        });
+        let gateways_bytes = gateways
+            .iter()
+            .flat_map(|identity| Self::serialize_string(identity).into_iter());
+
+        std::iter::once(TOPOLOGY_RESPONSE_TAG)
+            .chain((mixnodes.len() as u64).to_be_bytes().iter().cloned())
+            .chain(mixnodes_bytes)
+            .chain((gateways.len() as u64).to_be_bytes().iter().cloned())
+            .chain(gateways_bytes)
+            .collect()
+    }
+
+    // TOPOLOGY_RESPONSE_TAG || num_mixnodes || (layer || id_len || id)* || num_gateways || (id_len || id)*
+    fn deserialize_topology(b: &[u8]) -> Result<Self, error::Error> {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], TOPOLOGY_RESPONSE_TAG);
+
+        let too_short = || {
+            error::Error::new(
+                ErrorKind::TooShortResponse,
+                "not enough data provided to recover 'topology'".to_string(),
+            )
+        };
+
+        let mut i = 1;
+        let num_mixnodes = u64::from_be_bytes(
+            b.get(i..i + size_of::<u64>())
+                .ok_or_else(too_short)?
+                .try_into()
+                .unwrap(),
+        );
+        i += size_of::<u64>();
+
+        // don't trust the declared count for the allocation
+        let mut mixnodes = Vec::new();
+        for _ in 0..num_mixnodes {
+            let layer = *b.get(i).ok_or_else(too_short)?;
+            let (identity, len) = Self::deserialize_string(&b[i + 1..], "topology")?;
+            i += 1 + len;
+            mixnodes.push((layer, identity));
+        }
+
+        let num_gateways = u64::from_be_bytes(
+            b.get(i..i + size_of::<u64>())
+                .ok_or_else(too_short)?
+                .try_into()
+                .unwrap(),
+        );
+        i += size_of::<u64>();
+
+        let mut gateways = Vec::new();
+        for _ in 0..num_gateways {
+            let (identity, len) = Self::deserialize_string(&b[i..], "topology")?;
+            i += len;
+            gateways.push(identity);
+        }
+
+        if i != b.len() {
+            return Err(error::Error::new(
+                ErrorKind::MalformedResponse,
+                "'topology' has trailing data".to_string(),
+            ));
+        }
+
+        Ok(ServerResponse::Topology { mixnodes, gateways })
+    }
+
+    // PENDING_ACKS_RESPONSE_TAG || pending_acks || pending_messages || pending_packets
+    fn serialize_pending_acks(
+        pending_acks: u64,
+        pending_messages: u64,
+        pending_packets: u64,
+    ) -> Vec<u8> {
+        std::iter::once(PENDING_ACKS_RESPONSE_TAG)
+            .chain(pending_acks.to_be_bytes().iter().cloned())
+            .chain(pending_messages.to_be_bytes().iter().cloned())
+            .chain(pending_packets.to_be_bytes().iter().cloned())
+            .collect()
+    }
+
+    // PENDING_ACKS_RESPONSE_TAG || pending_acks || pending_messages || pending_packets
+    fn deserialize_pending_acks(b: &[u8]) -> Result<Self, error::Error> {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], PENDING_ACKS_RESPONSE_TAG);
+
+        if b.len() != 1 + 3 * size_of::<u64>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortResponse,
+                "not enough data provided to recover 'pending_acks'".to_string(),
+            ));
+        }
+
+        let i = 1 + size_of::<u64>();
+        let j = i + size_of::<u64>();
+        let pending_acks = u64::from_be_bytes(b[1..i].as_ref().try_into().unwrap());
+        let pending_messages = u64::from_be_bytes(b[i..j].as_ref().try_into().unwrap());
+        let pending_packets = u64::from_be_bytes(b[j..].as_ref().try_into().unwrap());
+
+        Ok(ServerResponse::PendingAcks {
+            pending_acks,
+            pending_messages,
+            pending_packets,
+        })
+    }
+
+    // COVER_TRAFFIC_RESPONSE_TAG || loop_cover_delay_ms || sending_delay_ms || loop_cover_sent || out_queue_cover_sent || real_sent
+    fn serialize_cover_traffic(
+        loop_cover_traffic_average_delay_ms: u64,
+        message_sending_average_delay_ms: u64,
+        loop_cover_packets_sent: u64,
+        out_queue_cover_packets_sent: u64,
+        real_packets_sent: u64,
+    ) -> Vec<u8> {
+        std::iter::once(COVER_TRAFFIC_RESPONSE_TAG)
+            .chain(
+                loop_cover_traffic_average_delay_ms
+                    .to_be_bytes()
+                    .iter()
+                    .cloned(),
+            )
+            .chain(
+                message_sending_average_delay_ms
+                    .to_be_bytes()
+                    .iter()
+                    .cloned(),
+            )
+            .chain(loop_cover_packets_sent.to_be_bytes().iter().cloned())
+            .chain(out_queue_cover_packets_sent.to_be_bytes().iter().cloned())
+            .chain(real_packets_sent.to_be_bytes().iter().cloned())
+            .collect()
+    }
+
+    // COVER_TRAFFIC_RESPONSE_TAG || loop_cover_delay_ms || sending_delay_ms || loop_cover_sent || out_queue_cover_sent || real_sent
+    fn deserialize_cover_traffic(b: &[u8]) -> Result<Self, error::Error> {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], COVER_TRAFFIC_RESPONSE_TAG);
+
+        if b.len() != 1 + 5 * size_of::<u64>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortResponse,
+                "not enough data provided to recover 'cover_traffic'".to_string(),
+            ));
+        }
+
+        let mut values = b[1..]
+            .chunks_exact(size_of::<u64>())
+            .map(|chunk| u64::from_be_bytes(chunk.try_into().unwrap()));
+
+        // the length was checked, so all the values are there
+        Ok(ServerResponse::CoverTraffic {
+            loop_cover_traffic_average_delay_ms: values.next().unwrap(),
+            message_sending_average_delay_ms: values.next().unwrap(),
+            loop_cover_packets_sent: values.next().unwrap(),
+            out_queue_cover_packets_sent: values.next().unwrap(),
+            real_packets_sent: values.next().unwrap(),
+        })
+    }
+
+    // TOPOLOGY_REFRESHED_RESPONSE_TAG || routable
+    fn serialize_topology_refreshed(routable: bool) -> Vec<u8> {
+        vec![TOPOLOGY_REFRESHED_RESPONSE_TAG, routable as u8]
+    }
+
+    // TOPOLOGY_REFRESHED_RESPONSE_TAG || routable
+    fn deserialize_topology_refreshed(b: &[u8]) -> Result<Self, error::Error> {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], TOPOLOGY_REFRESHED_RESPONSE_TAG);
+
+        if b.len() != 2 {
+            return Err(error::Error::new(
+                ErrorKind::TooShortResponse,
+                "not enough data provided to recover 'topology_refreshed'".to_string(),
+            ));
+        }
+
+        Ok(ServerResponse::TopologyRefreshed {
+            routable: b[1] != 0,
+        })
+    }
+
+    // INBOX_COUNT_RESPONSE_TAG || count
+    // INBOX_DELETED_RESPONSE_TAG || removed
+    fn serialize_inbox_value(tag: u8, value: u64) -> Vec<u8> {
+        std::iter::once(tag)
+            .chain(value.to_be_bytes().iter().cloned())
+            .collect()
+    }
+
+    // INBOX_COUNT_RESPONSE_TAG || count
+    // INBOX_DELETED_RESPONSE_TAG || removed
+    fn deserialize_inbox_value(b: &[u8]) -> Result<u64, error::Error> {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert!(b[0] == INBOX_COUNT_RESPONSE_TAG || b[0] == INBOX_DELETED_RESPONSE_TAG);
+
+        if b.len() != 1 + size_of::<u64>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortResponse,
+                "not enough data provided to recover 'inbox_count' or 'inbox_deleted'".to_string(),
+            ));
+        }
+
+        Ok(u64::from_be_bytes(b[1..].try_into().unwrap()))
+    }
+
+    // INBOX_PAGE_RESPONSE_TAG || num_ids || ids || [next_start_after]
+    fn serialize_inbox_page(ids: Vec<i64>, next_start_after: Option<i64>) -> Vec<u8> {
+        std::iter::once(INBOX_PAGE_RESPONSE_TAG)
+            .chain((ids.len() as u64).to_be_bytes().iter().cloned())
+            .chain(ids.into_iter().flat_map(i64::to_be_bytes))
+            .chain(next_start_after.into_iter().flat_map(i64::to_be_bytes))
+            .collect()
+    }
+
+    // INBOX_PAGE_RESPONSE_TAG || num_ids || ids || [next_start_after]
+    fn deserialize_inbox_page(b: &[u8]) -> Result<Self, error::Error> {
+        // this MUST match because it was called by 'deserialize'
+        debug_assert_eq!(b[0], INBOX_PAGE_RESPONSE_TAG);
+
+        if b.len() < 1 + size_of::<u64>() {
+            return Err(error::Error::new(
+                ErrorKind::TooShortResponse,
+                "not enough data provided to recover 'inbox_page'".to_string(),
+            ));
+        }
+
+        let num_ids = u64::from_be_bytes(b[1..1 + size_of::<u64>()].try_into().unwrap());
+        let ids_end = num_ids
+            .checked_mul(size_of::<i64>() as u64)
+            .and_then(|ids_len| ids_len.checked_add((1 + size_of::<u64>()) as u64))
+            .filter(|&ids_end| ids_end <= b.len() as u64)
+            .ok_or_else(|| {
+                error::Error::new(
+                    ErrorKind::TooShortResponse,
+                    "not enough data provided to recover all 'inbox_page' ids".to_string(),
+                )
+            })? as usize;
+
+        let ids = b[1 + size_of::<u64>()..ids_end]
+            .chunks_exact(size_of::<i64>())
+            .map(|chunk| i64::from_be_bytes(chunk.try_into().unwrap()))
+            .collect();
+
+        let next_start_after = match b.len() - ids_end {
+            0 => None,
+            n if n == size_of::<i64>() => {
+                Some(i64::from_be_bytes(b[ids_end..].try_into().unwrap()))
+            }
+            n => {
+                return Err(error::Error::new(
+                    ErrorKind::MalformedResponse,
+                    format!("'inbox_page' has {} unexpected trailing bytes", n),
+                ))
+            }
+        };
+
+        Ok(ServerResponse::InboxPage {
+            ids,
+            next_start_after,
+        })
+    }
+
     // ERROR_RESPONSE_TAG || err_code || msg_len || msg
     fn serialize_error(error: error::Error) -> Vec<u8> {
         let message_len_bytes = (error.message.len() as u64).to_be_bytes();
@@ -220,6 +966,7 @@ impl ServerResponse {
             _ if b[1] == (ErrorKind::TooShortRequest as u8) => ErrorKind::TooShortRequest,
             _ if b[1] == (ErrorKind::UnknownRequest as u8) => ErrorKind::UnknownRequest,
             _ if b[1] == (ErrorKind::MalformedRequest as u8) => ErrorKind::MalformedRequest,
+            _ if b[1] == (ErrorKind::Overloaded as u8) => ErrorKind::Overloaded,
 
             _ if b[1] == (ErrorKind::EmptyResponse as u8) => ErrorKind::EmptyResponse,
             _ if b[1] == (ErrorKind::TooShortResponse as u8) => ErrorKind::TooShortResponse,
@@ -266,14 +1013,86 @@ impl ServerResponse {
         )))
     }
 
-    pub fn serialize(self) -> Vec<u8> {
-        match self {
+    /// Serializes the response into its binary representation. It fails if the headers attached
+    /// to a received message can't be represented, for example if any of their values is too long.
+    pub fn serialize(self) -> Result<Vec<u8>, error::Error> {
+        let bytes = match self {
             ServerResponse::Received(reconstructed_message) => {
-                Self::serialize_received(reconstructed_message)
+                Self::serialize_received(reconstructed_message)?
             }
             ServerResponse::SelfAddress(address) => Self::serialize_self_address(address),
             ServerResponse::Error(err) => Self::serialize_error(err),
-        }
+            ServerResponse::MessageSent { message_id } => {
+                Self::serialize_message_id(MESSAGE_SENT_RESPONSE_TAG, message_id)
+            }
+            ServerResponse::Delivered { message_id } => {
+                Self::serialize_message_id(DELIVERED_RESPONSE_TAG, message_id)
+            }
+            ServerResponse::DeliveryFailed {
+                message_id,
+                retransmissions,
+                failed_fragments,
+            } => Self::serialize_delivery_failed(message_id, retransmissions, failed_fragments),
+            ServerResponse::DeliveryExpired {
+                message_id,
+                failed_fragments,
+            } => Self::serialize_delivery_expired(message_id, failed_fragments),
+            ServerResponse::Bandwidth {
+                remaining,
+                consumed,
+                burn_rate,
+            } => Self::serialize_bandwidth(remaining, consumed, burn_rate),
+            ServerResponse::StreamOpened { stream_id } => Self::serialize_stream_opened(stream_id),
+            ServerResponse::StreamChunkAccepted {
+                stream_id,
+                pending_packets,
+            } => Self::serialize_stream_chunk_accepted(stream_id, pending_packets),
+            ServerResponse::StreamClosed {
+                stream_id,
+                chunks,
+                bytes,
+            } => Self::serialize_stream_closed(stream_id, chunks, bytes),
+            ServerResponse::GatewayInfo {
+                gateway_id,
+                gateway_owner,
+                gateway_listener,
+            } => Self::serialize_gateway_info(gateway_id, gateway_owner, gateway_listener),
+            ServerResponse::Topology { mixnodes, gateways } => {
+                Self::serialize_topology(mixnodes, gateways)
+            }
+            ServerResponse::PendingAcks {
+                pending_acks,
+                pending_messages,
+                pending_packets,
+            } => Self::serialize_pending_acks(pending_acks, pending_messages, pending_packets),
+            ServerResponse::CoverTraffic {
+                loop_cover_traffic_average_delay_ms,
+                message_sending_average_delay_ms,
+                loop_cover_packets_sent,
+                out_queue_cover_packets_sent,
+                real_packets_sent,
+            } => Self::serialize_cover_traffic(
+                loop_cover_traffic_average_delay_ms,
+                message_sending_average_delay_ms,
+                loop_cover_packets_sent,
+                out_queue_cover_packets_sent,
+                real_packets_sent,
+            ),
+            ServerResponse::TopologyRefreshed { routable } => {
+                Self::serialize_topology_refreshed(routable)
+            }
+            ServerResponse::InboxCount { count } => {
+                Self::serialize_inbox_value(INBOX_COUNT_RESPONSE_TAG, count)
+            }
+            ServerResponse::InboxPage {
+                ids,
+                next_start_after,
+            } => Self::serialize_inbox_page(ids, next_start_after),
+            ServerResponse::InboxDeleted { removed } => {
+                Self::serialize_inbox_value(INBOX_DELETED_RESPONSE_TAG, removed)
+            }
+        };
+        Ok(bytes)
     }
 
     pub fn deserialize(b: &[u8]) -> Result<Self, error::Error> {
@@ -300,9 +1119,32 @@ impl ServerResponse {
 
         // determine what kind of response that is and try to deserialize it
         match response_tag {
-            RECEIVED_RESPONSE_TAG => Self::deserialize_received(b),
+            RECEIVED_RESPONSE_TAG | RECEIVED_WITH_HEADERS_RESPONSE_TAG => {
+                Self::deserialize_received(b)
+            }
             SELF_ADDRESS_RESPONSE_TAG => Self::deserialize_self_address(b),
             ERROR_RESPONSE_TAG => Self::deserialize_error(b),
+            MESSAGE_SENT_RESPONSE_TAG => Self::deserialize_message_id(b)
+                .map(|message_id| ServerResponse::MessageSent { message_id }),
+            DELIVERED_RESPONSE_TAG => Self::deserialize_message_id(b)
+                .map(|message_id| ServerResponse::Delivered { message_id }),
+            DELIVERY_FAILED_RESPONSE_TAG => Self::deserialize_delivery_failed(b),
+            DELIVERY_EXPIRED_RESPONSE_TAG => Self::deserialize_delivery_expired(b),
+            BANDWIDTH_RESPONSE_TAG => Self::deserialize_bandwidth(b),
+            STREAM_OPENED_RESPONSE_TAG => Self::deserialize_stream_opened(b),
+            STREAM_CHUNK_ACCEPTED_RESPONSE_TAG => Self::deserialize_stream_chunk_accepted(b),
+            STREAM_CLOSED_RESPONSE_TAG => Self::deserialize_stream_closed(b),
+            GATEWAY_INFO_RESPONSE_TAG => Self::deserialize_gateway_info(b),
+            TOPOLOGY_RESPONSE_TAG => Self::deserialize_topology(b),
+            PENDING_ACKS_RESPONSE_TAG => Self::deserialize_pending_acks(b),
+            COVER_TRAFFIC_RESPONSE_TAG => Self::deserialize_cover_traffic(b),
+            TOPOLOGY_REFRESHED_RESPONSE_TAG => Self::deserialize_topology_refreshed(b),
+            INBOX_COUNT_RESPONSE_TAG => {
+                Self::deserialize_inbox_value(b).map(|count| ServerResponse::InboxCount { count })
+            }
+            INBOX_PAGE_RESPONSE_TAG => Self::deserialize_inbox_page(b),
+            INBOX_DELETED_RESPONSE_TAG => Self::deserialize_inbox_value(b)
+                .map(|removed| ServerResponse::InboxDeleted { removed }),
             n => Err(error::Error::new(
                 ErrorKind::UnknownResponse,
                 format!("type {}", n),
@@ -310,8 +1152,9 @@ impl ServerResponse {
         }
     }
 
+    /// Serializes the response, replacing it with an error response if it can't be represented.
     pub fn into_binary(self) -> Vec<u8> {
-        self.serialize()
+        self.serialize().unwrap_or_else(Self::serialize_error)
     }
 
     pub fn into_text(self) -> String {
@@ -325,50 +1168,135 @@ impl ServerResponse {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use nymsphinx::headers::StreamFrame;
+
+    const REPLY_SURB: &str = "CjfVbHbfAjbC3W1BvNHGXmM8KNAnDNYGaHMLqVDxRYeo352csAihstup9bvqXam4dTWgfHak6KYwL9STaxWJ47E8XFZbSEvs7hEsfCkxr6K9WJuSBPK84GDDEvad8ZAuMCoaXsAd5S2Lj9a5eYyzG4SL1jHzhSMni55LyJwumxo1ZTGZNXggxw1RREosvyzNrW9Rsi3owyPqLCwXpiei2tHZty8w8midVvg8vDa7ZEJD842CLv8D4ohynSG7gDpqTrhkRaqYAuz7dzqNbMXLJRM7v823Jn16fA1L7YQxmcaUdUigyRSgTdb4i9ebiLGSyJ1iDe6Acz613PQZh6Ua3bZ2zVKq3dSycpDm9ngarRK4zJrAaUxRkdih8YzW3BY4nL9eqkfKA4N1TWCLaRU7zpSaf8yMEwrAZReU3d5zLV8c5KBfa2w8R5anhQeBojduZEGEad8kkHuKU52Zg93FeWHvH1qgZaEJMHH4nN7gKXz9mvWDhYwyF4vt3Uy2NhCHC3N5pL1gMme27YcoPcTEia1fxKZtnt6rtEozzTrAgCJGswigkFbkafiV5QaJwLKTUxtzhkZ57eEuLPte9UvJHzhhXUQ2CV7R2BUkJjYZy3Zsx6YYvdYWiAFFkWUwNEGA4QpShUHciBfsQVHQ7pN41YcyYUhbywQDFnTVgEmdUZ1XCBi3gyK5U3tDQmFzP1u9m3mWrUA8qB9mRDE7ptNDm5c3c1458L6uXLUth7sdMaa1Was5LCmCdmNDtvNpCDAEt1in6q6mrZFR85aCSU9b1baNGwZoCqPpPvydkVe63gXWoi8ebvdyxARrqACFrSB3ZdY3uJBw8CTMNkKK6MvcefMkSVVsbLd36TQAtYSCqrpiMc5dQuKcEu5QfciwvWYXYx8WFNAgKwP2mv49KCTvfozNDUCbjzDwSx92Zv5zjG8HbFpB13bY9UZGeyTPvv7gGxCzjGjJGbW6FRAheRQaaje5fUgCNM95Tv7wBmAMRHHFgWafeK1sdFH7dtCX9u898HucGTaboSKLsVh8J78gbbkHErwjMh7y9YRkceq5TTYS5da4kHnyNKYWSbxgZrmFg44XGKoeYcqoHB3XTZrdsf7F5fFeNwnihkmADvhAcaxXUmVqq4rQFZH84a1iC3WBWXYcqiZH2L7ujGWV7mMDT4HBEerDYjc8rNY4xGTPfivCrBCJW1i14aqW8xRdsdgTM88eTksvC3WPJLJ7iMzfKXeL7fMW1Ek6QGyQtLBW98vEESpdcDg6DeZ5rMz6VqjTGGqcCaFGfHoqtfxMDaBAEsyQ8h7XDX6dg1wq9wH6j4Tw7Tj1MEv1b8uj5NJkozZdzVdYA2QyE2Dp8vuurQG6uVdTDNww2d88RBQ8sVgjxN8gR45y4woJLhFAaNTAtrY6wDTxyXST13ni6oyqdYxjFVk9Am4v3DzH7Y2K8iRVSHfTk4FRbPULyaeK6wt2anvMJH1XdvVRgc14h67MnBxMgMD1UFk8AErN7CDj26fppe3c5G6KozJe4cSqQUGbBjVzBnrHCruqrfZBn5hNZHTV37bQiomqhRQXohxhuKEnNrGbAe1xNvJr9X";
 
     #[test]
     fn received_response_serialization_works() {
-        let reply_surb_string = "CjfVbHbfAjbC3W1BvNHGXmM8KNAnDNYGaHMLqVDxRYeo352csAihstup9bvqXam4dTWgfHak6KYwL9STaxWJ47E8XFZbSEvs7hEsfCkxr6K9WJuSBPK84GDDEvad8ZAuMCoaXsAd5S2Lj9a5eYyzG4SL1jHzhSMni55LyJwumxo1ZTGZNXggxw1RREosvyzNrW9Rsi3owyPqLCwXpiei2tHZty8w8midVvg8vDa7ZEJD842CLv8D4ohynSG7gDpqTrhkRaqYAuz7dzqNbMXLJRM7v823Jn16fA1L7YQxmcaUdUigyRSgTdb4i9ebiLGSyJ1iDe6Acz613PQZh6Ua3bZ2zVKq3dSycpDm9ngarRK4zJrAaUxRkdih8YzW3BY4nL9eqkfKA4N1TWCLaRU7zpSaf8yMEwrAZReU3d5zLV8c5KBfa2w8R5anhQeBojduZEGEad8kkHuKU52Zg93FeWHvH1qgZaEJMHH4nN7gKXz9mvWDhYwyF4vt3Uy2NhCHC3N5pL1gMme27YcoPcTEia1fxKZtnt6rtEozzTrAgCJGswigkFbkafiV5QaJwLKTUxtzhkZ57eEuLPte9UvJHzhhXUQ2CV7R2BUkJjYZy3Zsx6YYvdYWiAFFkWUwNEGA4QpShUHciBfsQVHQ7pN41YcyYUhbywQDFnTVgEmdUZ1XCBi3gyK5U3tDQmFzP1u9m3mWrUA8qB9mRDE7ptNDm5c3c1458L6uXLUth7sdMaa1Was5LCmCdmNDtvNpCDAEt1in6q6mrZFR85aCSU9b1baNGwZoCqPpPvydkVe63gXWoi8ebvdyxARrqACFrSB3ZdY3uJBw8CTMNkKK6MvcefMkSVVsbLd36TQAtYSCqrpiMc5dQuKcEu5QfciwvWYXYx8WFNAgKwP2mv49KCTvfozNDUCbjzDwSx92Zv5zjG8HbFpB13bY9UZGeyTPvv7gGxCzjGjJGbW6FRAheRQaaje5fUgCNM95Tv7wBmAMRHHFgWafeK1sdFH7dtCX9u898HucGTaboSKLsVh8J78gbbkHErwjMh7y9YRkceq5TTYS5da4kHnyNKYWSbxgZrmFg44XGKoeYcqoHB3XTZrdsf7F5fFeNwnihkmADvhAcaxXUmVqq4rQFZH84a1iC3WBWXYcqiZH2L7ujGWV7mMDT4HBEerDYjc8rNY4xGTPfivCrBCJW1i14aqW8xRdsdgTM88eTksvC3WPJLJ7iMzfKXeL7fMW1Ek6QGyQtLBW98vEESpdcDg6DeZ5rMz6VqjTGGqcCaFGfHoqtfxMDaBAEsyQ8h7XDX6dg1wq9wH6j4Tw7Tj1MEv1b8uj5NJkozZdzVdYA2QyE2Dp8vuurQG6uVdTDNww2d88RBQ8sVgjxN8gR45y4woJLhFAaNTAtrY6wDTxyXST13ni6oyqdYxjFVk9Am4v3DzH7Y2K8iRVSHfTk4FRbPULyaeK6wt2anvMJH1XdvVRgc14h67MnBxMgMD1UFk8AErN7CDj26fppe3c5G6KozJe4cSqQUGbBjVzBnrHCruqrfZBn5hNZHTV37bQiomqhRQXohxhuKEnNrGbAe1xNvJr9X";
-
         let received_with_surb = ServerResponse::Received(ReconstructedMessage {
             message: b"foomp".to_vec(),
-            reply_surb: Some(ReplySurb::from_base58_string(reply_surb_string).unwrap()),
+            reply_surbs: vec![ReplySurb::from_base58_string(REPLY_SURB).unwrap()],
+            headers: None,
         });
-        let bytes = received_with_surb.serialize();
+        let bytes = received_with_surb.serialize().unwrap();
         let recovered = ServerResponse::deserialize(&bytes).unwrap();
         match recovered {
             ServerResponse::Received(reconstructed) => {
                 assert_eq!(reconstructed.message, b"foomp".to_vec());
-                assert_eq!(
-                    reconstructed.reply_surb.unwrap().to_base58_string(),
-                    reply_surb_string
-                )
+                assert_eq!(reconstructed.reply_surbs.len(), 1);
+                assert_eq!(reconstructed.reply_surbs[0].to_base58_string(), REPLY_SURB)
+            }
+            _ => unreachable!(),
+        }
+
+        let received_with_surbs = ServerResponse::Received(ReconstructedMessage {
+            message: b"foomp".to_vec(),
+            reply_surbs: vec![
+                ReplySurb::from_base58_string(REPLY_SURB).unwrap(),
+                ReplySurb::from_base58_string(REPLY_SURB).unwrap(),
+                ReplySurb::from_base58_string(REPLY_SURB).unwrap(),
+            ],
+            headers: None,
+        });
+        let bytes = received_with_surbs.serialize().unwrap();
+        let recovered = ServerResponse::deserialize(&bytes).unwrap();
+        match recovered {
+            ServerResponse::Received(reconstructed) => {
+                assert_eq!(reconstructed.message, b"foomp".to_vec());
+                assert_eq!(reconstructed.reply_surbs.len(), 3);
+                for reply_surb in reconstructed.reply_surbs {
+                    assert_eq!(reply_surb.to_base58_string(), REPLY_SURB)
+                }
             }
             _ => unreachable!(),
         }
 
         let received_without_surb = ServerResponse::Received(ReconstructedMessage {
             message: b"foomp".to_vec(),
-            reply_surb: None,
+            reply_surbs: Vec::new(),
+            headers: None,
+        });
+        let bytes = received_without_surb.serialize().unwrap();
+        let recovered = ServerResponse::deserialize(&bytes).unwrap();
+        match recovered {
+            ServerResponse::Received(reconstructed) => {
+                assert_eq!(reconstructed.message, b"foomp".to_vec());
+                assert!(reconstructed.reply_surbs.is_empty());
+                assert!(reconstructed.headers.is_none())
+            }
+            _ => unreachable!(),
+        }
+
+        let headers = MessageHeaders {
+            content_type: Some("text/plain".to_string()),
+            correlation_id: Some("request-1".to_string()),
+            timestamp: None,
+            stream: Some(StreamFrame {
+                stream_id: 1,
+                sequence: 2,
+                end: false,
+            }),
+        };
+        let received_with_headers = ServerResponse::Received(ReconstructedMessage {
+            message: b"foomp".to_vec(),
+            reply_surbs: vec![ReplySurb::from_base58_string(REPLY_SURB).unwrap()],
+            headers: Some(headers.clone()),
         });
-        let bytes = received_without_surb.serialize();
+        let bytes = received_with_headers.serialize().unwrap();
         let recovered = ServerResponse::deserialize(&bytes).unwrap();
         match recovered {
             ServerResponse::Received(reconstructed) => {
                 assert_eq!(reconstructed.message, b"foomp".to_vec());
-                assert!(reconstructed.reply_surb.is_none())
+                assert_eq!(reconstructed.reply_surbs.len(), 1);
+                assert_eq!(reconstructed.headers, Some(headers))
             }
             _ => unreachable!(),
         }
     }
 
+    #[test]
+    fn received_response_with_unrepresentable_headers_is_replaced_with_error() {
+        let received = || {
+            ServerResponse::Received(ReconstructedMessage {
+                message: b"foomp".to_vec(),
+                reply_surbs: Vec::new(),
+                headers: Some(MessageHeaders {
+                    correlation_id: Some("x".repeat(u16::MAX as usize + 1)),
+                    ..Default::default()
+                }),
+            })
+        };
+
+        let err = received().serialize().unwrap_err();
+        assert!(err.kind == ErrorKind::MalformedResponse);
+
+        match ServerResponse::deserialize(&received().into_binary()).unwrap() {
+            ServerResponse::Error(error) => assert!(error.kind == ErrorKind::MalformedResponse),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn received_response_with_too_many_reply_surbs_is_rejected() {
+        let reply_surb = ReplySurb::from_base58_string(REPLY_SURB).unwrap();
+        let received = ServerResponse::Received(ReconstructedMessage {
+            message: b"foomp".to_vec(),
+            reply_surbs: vec![reply_surb; MAX_REPLY_SURBS as usize + 1],
+            headers: None,
+        });
+
+        let err = received.serialize().unwrap_err();
+        assert!(err.kind == ErrorKind::MalformedResponse);
+    }
+
     #[test]
     fn self_address_response_serialization_works() {
         let recipient = Recipient::try_from_base58_string("CytBseW6yFXUMzz4SGAKdNLGR7q3sJLLYxyBGvutNEQV.4QXYyEVc5fUDjmmi8PrHN9tdUFV4PCvSJE1278cHyvoe@4sBbL1ngf1vtNqykydQKTFh26sQCw888GpUqvPvyNB4f").unwrap();
         let recipient_string = recipient.to_string();
 
         let self_address_response = ServerResponse::SelfAddress(recipient);
-        let bytes = self_address_response.serialize();
+        let bytes = self_address_response.serialize().unwrap();
         let recovered = ServerResponse::deserialize(&bytes).unwrap();
         match recovered {
             ServerResponse::SelfAddress(recipient) => {
@@ -382,11 +1310,288 @@ mod tests {
     fn error_response_serialization_works() {
         let dummy_error = error::Error::new(ErrorKind::UnknownRequest, "foomp message".to_string());
         let error_response = ServerResponse::Error(dummy_error.clone());
-        let bytes = error_response.serialize();
+        let bytes = error_response.serialize().unwrap();
         let recovered = ServerResponse::deserialize(&bytes).unwrap();
         match recovered {
             ServerResponse::Error(error) => assert_eq!(error, dummy_error),
             _ => unreachable!(),
         }
     }
+
+    #[test]
+    fn delivery_responses_serialization_works() {
+        let message_sent_response = ServerResponse::MessageSent { message_id: 42 };
+        let bytes = message_sent_response.serialize().unwrap();
+        let recovered = ServerResponse::deserialize(&bytes).unwrap();
+        match recovered {
+            ServerResponse::MessageSent { message_id } => assert_eq!(message_id, 42),
+            _ => unreachable!(),
+        }
+
+        let delivered_response = ServerResponse::Delivered {
+            message_id: u64::MAX,
+        };
+        let bytes = delivered_response.serialize().unwrap();
+        let recovered = ServerResponse::deserialize(&bytes).unwrap();
+        match recovered {
+            ServerResponse::Delivered { message_id } => assert_eq!(message_id, u64::MAX),
+            _ => unreachable!(),
+        }
+
+        let delivery_failed_response = ServerResponse::DeliveryFailed {
+            message_id: 42,
+            retransmissions: 10,
+            failed_fragments: 3,
+        };
+        let bytes = delivery_failed_response.serialize().unwrap();
+        let recovered = ServerResponse::deserialize(&bytes).unwrap();
+        match recovered {
+            ServerResponse::DeliveryFailed {
+                message_id,
+                retransmissions,
+                failed_fragments,
+            } => {
+                assert_eq!(message_id, 42);
+                assert_eq!(retransmissions, 10);
+                assert_eq!(failed_fragments, 3)
+            }
+            _ => unreachable!(),
+        }
+
+        let delivery_expired_response = ServerResponse::DeliveryExpired {
+            message_id: 42,
+            failed_fragments: 3,
+        };
+        let bytes = delivery_expired_response.serialize().unwrap();
+        let recovered = ServerResponse::deserialize(&bytes).unwrap();
+        match recovered {
+            ServerResponse::DeliveryExpired {
+                message_id,
+                failed_fragments,
+            } => {
+                assert_eq!(message_id, 42);
+                assert_eq!(failed_fragments, 3)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn bandwidth_response_serialization_works() {
+        let bandwidth_response = ServerResponse::Bandwidth {
+            remaining: -42,
+            consumed: 1024 * 1024,
+            burn_rate: 1234.5,
+        };
+        let bytes = bandwidth_response.serialize().unwrap();
+        let recovered = ServerResponse::deserialize(&bytes).unwrap();
+        match recovered {
+            ServerResponse::Bandwidth {
+                remaining,
+                consumed,
+                burn_rate,
+            } => {
+                assert_eq!(remaining, -42);
+                assert_eq!(consumed, 1024 * 1024);
+                assert_eq!(burn_rate, 1234.5)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn stream_responses_serialization_works() {
+        let stream_opened_response = ServerResponse::StreamOpened { stream_id: 42 };
+        let bytes = stream_opened_response.serialize().unwrap();
+        let recovered = ServerResponse::deserialize(&bytes).unwrap();
+        match recovered {
+            ServerResponse::StreamOpened { stream_id } => assert_eq!(stream_id, 42),
+            _ => unreachable!(),
+        }
+
+        let chunk_accepted_response = ServerResponse::StreamChunkAccepted {
+            stream_id: 42,
+            pending_packets: 123,
+        };
+        let bytes = chunk_accepted_response.serialize().unwrap();
+        let recovered = ServerResponse::deserialize(&bytes).unwrap();
+        match recovered {
+            ServerResponse::StreamChunkAccepted {
+                stream_id,
+                pending_packets,
+            } => {
+                assert_eq!(stream_id, 42);
+                assert_eq!(pending_packets, 123)
+            }
+            _ => unreachable!(),
+        }
+
+        let stream_closed_response = ServerResponse::StreamClosed {
+            stream_id: 42,
+            chunks: 10,
+            bytes: 1024 * 1024,
+        };
+        let bytes = stream_closed_response.serialize().unwrap();
+        let recovered = ServerResponse::deserialize(&bytes).unwrap();
+        match recovered {
+            ServerResponse::StreamClosed {
+                stream_id,
+                chunks,
+                bytes,
+            } => {
+                assert_eq!(stream_id, 42);
+                assert_eq!(chunks, 10);
+                assert_eq!(bytes, 1024 * 1024)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn control_responses_serialization_works() {
+        let gateway_info_response = ServerResponse::GatewayInfo {
+            gateway_id: "4sBbL1ngf1vtNqykydQKTFh26sQCw888GpUqvPvyNB4f".to_string(),
+            gateway_owner: "n1foomp".to_string(),
+            gateway_listener: "ws://1.2.3.4:9000".to_string(),
+        };
+        let bytes = gateway_info_response.serialize().unwrap();
+        let recovered = ServerResponse::deserialize(&bytes).unwrap();
+        match recovered {
+            ServerResponse::GatewayInfo {
+                gateway_id,
+                gateway_owner,
+                gateway_listener,
+            } => {
+                assert_eq!(gateway_id, "4sBbL1ngf1vtNqykydQKTFh26sQCw888GpUqvPvyNB4f");
+                assert_eq!(gateway_owner, "n1foomp");
+                assert_eq!(gateway_listener, "ws://1.2.3.4:9000")
+            }
+            _ => unreachable!(),
+        }
+
+        let topology_response = ServerResponse::Topology {
+            mixnodes: vec![(1, "foo".to_string()), (3, "bar".to_string())],
+            gateways: vec!["baz".to_string()],
+        };
+        let bytes = topology_response.serialize().unwrap();
+        let recovered = ServerResponse::deserialize(&bytes).unwrap();
+        match recovered {
+            ServerResponse::Topology { mixnodes, gateways } => {
+                assert_eq!(
+                    mixnodes,
+                    vec![(1, "foo".to_string()), (3, "bar".to_string())]
+                );
+                assert_eq!(gateways, vec!["baz".to_string()])
+            }
+            _ => unreachable!(),
+        }
+        assert!(ServerResponse::deserialize(&bytes[..bytes.len() - 1]).is_err());
+
+        let pending_acks_response = ServerResponse::PendingAcks {
+            pending_acks: 42,
+            pending_messages: 3,
+            pending_packets: 10,
+        };
+        let bytes = pending_acks_response.serialize().unwrap();
+        let recovered = ServerResponse::deserialize(&bytes).unwrap();
+        match recovered {
+            ServerResponse::PendingAcks {
+                pending_acks,
+                pending_messages,
+                pending_packets,
+            } => {
+                assert_eq!(pending_acks, 42);
+                assert_eq!(pending_messages, 3);
+                assert_eq!(pending_packets, 10)
+            }
+            _ => unreachable!(),
+        }
+
+        let cover_traffic_response = ServerResponse::CoverTraffic {
+            loop_cover_traffic_average_delay_ms: 200,
+            message_sending_average_delay_ms: 20,
+            loop_cover_packets_sent: 1000,
+            out_queue_cover_packets_sent: 500,
+            real_packets_sent: 123,
+        };
+        let bytes = cover_traffic_response.serialize().unwrap();
+        let recovered = ServerResponse::deserialize(&bytes).unwrap();
+        match recovered {
+            ServerResponse::CoverTraffic {
+                loop_cover_traffic_average_delay_ms,
+                message_sending_average_delay_ms,
+                loop_cover_packets_sent,
+                out_queue_cover_packets_sent,
+                real_packets_sent,
+            } => {
+                assert_eq!(loop_cover_traffic_average_delay_ms, 200);
+                assert_eq!(message_sending_average_delay_ms, 20);
+                assert_eq!(loop_cover_packets_sent, 1000);
+                assert_eq!(out_queue_cover_packets_sent, 500);
+                assert_eq!(real_packets_sent, 123)
+            }
+            _ => unreachable!(),
+        }
+
+        let topology_refreshed_response = ServerResponse::TopologyRefreshed { routable: true };
+        let bytes = topology_refreshed_response.serialize().unwrap();
+        let recovered = ServerResponse::deserialize(&bytes).unwrap();
+        match recovered {
+            ServerResponse::TopologyRefreshed { routable } => assert!(routable),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn inbox_responses_serialization_works() {
+        let bytes = ServerResponse::InboxCount { count: 42 }
+            .serialize()
+            .unwrap();
+        match ServerResponse::deserialize(&bytes).unwrap() {
+            ServerResponse::InboxCount { count } => assert_eq!(count, 42),
+            _ => unreachable!(),
+        }
+
+        let bytes = ServerResponse::InboxDeleted { removed: 3 }
+            .serialize()
+            .unwrap();
+        match ServerResponse::deserialize(&bytes).unwrap() {
+            ServerResponse::InboxDeleted { removed } => assert_eq!(removed, 3),
+            _ => unreachable!(),
+        }
+
+        let bytes = ServerResponse::InboxPage {
+            ids: vec![1, 2, 3],
+            next_start_after: Some(3),
+        }
+        .serialize()
+        .unwrap();
+        match ServerResponse::deserialize(&bytes).unwrap() {
+            ServerResponse::InboxPage {
+                ids,
+                next_start_after,
+            } => {
+                assert_eq!(ids, vec![1, 2, 3]);
+                assert_eq!(next_start_after, Some(3))
+            }
+            _ => unreachable!(),
+        }
+
+        let bytes = ServerResponse::InboxPage {
+            ids: Vec::new(),
+            next_start_after: None,
+        }
+        .serialize()
+        .unwrap();
+        match ServerResponse::deserialize(&bytes).unwrap() {
+            ServerResponse::InboxPage {
+                ids,
+                next_start_after,
+            } => {
+                assert!(ids.is_empty());
+                assert!(next_start_after.is_none())
+            }
+            _ => unreachable!(),
+        }
+    }
 }
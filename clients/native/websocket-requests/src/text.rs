@@ -2,13 +2,88 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::error::ErrorKind;
-use crate::requests::ClientRequest;
+use crate::requests::{ClientRequest, TrafficMode};
 use crate::responses::ServerResponse;
 use nymsphinx::addressing::clients::Recipient;
 use nymsphinx::anonymous_replies::ReplySurb;
+use nymsphinx::headers::{MessageHeaders, StreamFrame};
 use serde::{Deserialize, Serialize};
 use std::convert::{TryFrom, TryInto};
 
+// local text equivalent of `StreamFrame`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct StreamFrameText {
+    stream_id: u64,
+    sequence: u64,
+    #[serde(default)]
+    end: bool,
+}
+
+impl From<StreamFrameText> for StreamFrame {
+    fn from(frame: StreamFrameText) -> Self {
+        StreamFrame {
+            stream_id: frame.stream_id,
+            sequence: frame.sequence,
+            end: frame.end,
+        }
+    }
+}
+
+impl From<StreamFrame> for StreamFrameText {
+    fn from(frame: StreamFrame) -> Self {
+        StreamFrameText {
+            stream_id: frame.stream_id,
+            sequence: frame.sequence,
+            end: frame.end,
+        }
+    }
+}
+
+// local text equivalent of `MessageHeaders`, shared by both requests and responses
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct MessageHeadersText {
+    #[serde(default)]
+    content_type: Option<String>,
+    #[serde(default)]
+    correlation_id: Option<String>,
+    /// Milliseconds since the unix epoch.
+    #[serde(default)]
+    timestamp: Option<u64>,
+    #[serde(default)]
+    stream: Option<StreamFrameText>,
+}
+
+impl From<MessageHeadersText> for MessageHeaders {
+    fn from(headers: MessageHeadersText) -> Self {
+        MessageHeaders {
+            content_type: headers.content_type,
+            correlation_id: headers.correlation_id,
+            timestamp: headers.timestamp,
+            stream: headers.stream.map(Into::into),
+        }
+    }
+}
+
+impl From<MessageHeaders> for MessageHeadersText {
+    fn from(headers: MessageHeaders) -> Self {
+        MessageHeadersText {
+            content_type: headers.content_type,
+            correlation_id: headers.correlation_id,
+            timestamp: headers.timestamp,
+            stream: headers.stream.map(Into::into),
+        }
+    }
+}
+
+// local text equivalent of a single mixnode of the `Topology` response
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct MixnodeText {
+    layer: u8,
+    identity: String,
+}
+
 // local text equivalent of `ClientRequest` for easier serialization + deserialization with serde
 // TODO: figure out if there's an easy way to avoid defining it
 
@@ -19,14 +94,66 @@ pub(super) enum ClientRequestText {
     Send {
         message: String,
         recipient: String,
+        /// Number of reply SURBs to attach to the message.
+        #[serde(default)]
+        num_reply_surbs: Option<u8>,
+        /// Legacy flag equivalent to `numReplySurbs` of 0 or 1. Ignored if `numReplySurbs` is set.
+        #[serde(default)]
         with_reply_surb: bool,
+        /// Whether the id of the message and its eventual delivery status should be reported back.
+        #[serde(default)]
+        with_receipt: bool,
+        /// Metadata sent, encrypted, alongside the message.
+        #[serde(default)]
+        headers: Option<MessageHeadersText>,
     },
     SelfAddress,
+    Bandwidth,
     #[serde(rename_all = "camelCase")]
     Reply {
         message: String,
         reply_surb: String,
     },
+    #[serde(rename_all = "camelCase")]
+    OpenStream {
+        recipient: String,
+        #[serde(default)]
+        num_reply_surbs: u8,
+    },
+    #[serde(rename_all = "camelCase")]
+    StreamChunk {
+        stream_id: u64,
+        data: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    CloseStream {
+        stream_id: u64,
+    },
+    GatewayInfo,
+    Topology,
+    PendingAcks,
+    CoverTraffic,
+    RefreshTopology,
+    InboxCount,
+    #[serde(rename_all = "camelCase")]
+    FetchInbox {
+        #[serde(default)]
+        start_after: Option<i64>,
+        page_size: u32,
+        #[serde(default)]
+        remove: bool,
+    },
+    DeleteInbox {
+        ids: Vec<i64>,
+    },
+    #[serde(rename_all = "camelCase")]
+    SetTrafficRates {
+        loop_cover_traffic_average_delay_ms: u64,
+        message_sending_average_delay_ms: u64,
+    },
+    SetTrafficMode {
+        mode: TrafficMode,
+    },
 }
 
 impl TryFrom<String> for ClientRequestText {
@@ -45,7 +172,10 @@ impl TryInto<ClientRequest> for ClientRequestText {
             ClientRequestText::Send {
                 message,
                 recipient,
+                num_reply_surbs,
                 with_reply_surb,
+                with_receipt,
+                headers,
             } => {
                 let message_bytes = message.into_bytes();
                 let recipient = Recipient::try_from_base58_string(recipient).map_err(|err| {
@@ -55,10 +185,13 @@ impl TryInto<ClientRequest> for ClientRequestText {
                 Ok(ClientRequest::Send {
                     message: message_bytes,
                     recipient,
-                    with_reply_surb,
+                    num_reply_surbs: num_reply_surbs.unwrap_or(with_reply_surb as u8),
+                    with_receipt,
+                    headers: headers.map(Into::into),
                 })
             }
             ClientRequestText::SelfAddress => Ok(ClientRequest::SelfAddress),
+            ClientRequestText::Bandwidth => Ok(ClientRequest::Bandwidth),
             ClientRequestText::Reply {
                 message,
                 reply_surb,
@@ -73,6 +206,52 @@ impl TryInto<ClientRequest> for ClientRequestText {
                     reply_surb,
                 })
             }
+            ClientRequestText::OpenStream {
+                recipient,
+                num_reply_surbs,
+            } => {
+                let recipient = Recipient::try_from_base58_string(recipient).map_err(|err| {
+                    Self::Error::new(ErrorKind::MalformedRequest, err.to_string())
+                })?;
+
+                Ok(ClientRequest::OpenStream {
+                    recipient,
+                    num_reply_surbs,
+                })
+            }
+            ClientRequestText::StreamChunk { stream_id, data } => Ok(ClientRequest::StreamChunk {
+                stream_id,
+                data: data.into_bytes(),
+            }),
+            ClientRequestText::CloseStream { stream_id } => {
+                Ok(ClientRequest::CloseStream { stream_id })
+            }
+            ClientRequestText::GatewayInfo => Ok(ClientRequest::GatewayInfo),
+            ClientRequestText::Topology => Ok(ClientRequest::Topology),
+            ClientRequestText::PendingAcks => Ok(ClientRequest::PendingAcks),
+            ClientRequestText::CoverTraffic => Ok(ClientRequest::CoverTraffic),
+            ClientRequestText::RefreshTopology => Ok(ClientRequest::RefreshTopology),
+            ClientRequestText::InboxCount => Ok(ClientRequest::InboxCount),
+            ClientRequestText::FetchInbox {
+                start_after,
+                page_size,
+                remove,
+            } => Ok(ClientRequest::FetchInbox {
+                start_after,
+                page_size,
+                remove,
+            }),
+            ClientRequestText::DeleteInbox { ids } => Ok(ClientRequest::DeleteInbox { ids }),
+            ClientRequestText::SetTrafficRates {
+                loop_cover_traffic_average_delay_ms,
+                message_sending_average_delay_ms,
+            } => Ok(ClientRequest::SetTrafficRates {
+                loop_cover_traffic_average_delay_ms,
+                message_sending_average_delay_ms,
+            }),
+            ClientRequestText::SetTrafficMode { mode } => {
+                Ok(ClientRequest::SetTrafficMode { mode })
+            }
         }
     }
 }
@@ -86,7 +265,13 @@ pub(super) enum ServerResponseText {
     #[serde(rename_all = "camelCase")]
     Received {
         message: String,
+        #[serde(default)]
+        reply_surbs: Vec<String>,
+        /// First of the `reply_surbs`, kept for compatibility with the single reply SURB API.
         reply_surb: Option<String>,
+        /// Metadata attached to the message by its sender, if any.
+        #[serde(default)]
+        headers: Option<MessageHeadersText>,
     },
     SelfAddress {
         address: String,
@@ -94,6 +279,84 @@ pub(super) enum ServerResponseText {
     Error {
         message: String,
     },
+    #[serde(rename_all = "camelCase")]
+    MessageSent {
+        message_id: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    Delivered {
+        message_id: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    DeliveryFailed {
+        message_id: u64,
+        retransmissions: u32,
+        failed_fragments: u32,
+    },
+    #[serde(rename_all = "camelCase")]
+    DeliveryExpired {
+        message_id: u64,
+        failed_fragments: u32,
+    },
+    #[serde(rename_all = "camelCase")]
+    Bandwidth {
+        remaining: i64,
+        consumed: u64,
+        burn_rate: f64,
+    },
+    #[serde(rename_all = "camelCase")]
+    StreamOpened {
+        stream_id: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    StreamChunkAccepted {
+        stream_id: u64,
+        pending_packets: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    StreamClosed {
+        stream_id: u64,
+        chunks: u64,
+        bytes: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    GatewayInfo {
+        gateway_id: String,
+        gateway_owner: String,
+        gateway_listener: String,
+    },
+    Topology {
+        mixnodes: Vec<MixnodeText>,
+        gateways: Vec<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    PendingAcks {
+        pending_acks: u64,
+        pending_messages: u64,
+        pending_packets: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    CoverTraffic {
+        loop_cover_traffic_average_delay_ms: u64,
+        message_sending_average_delay_ms: u64,
+        loop_cover_packets_sent: u64,
+        out_queue_cover_packets_sent: u64,
+        real_packets_sent: u64,
+    },
+    TopologyRefreshed {
+        routable: bool,
+    },
+    InboxCount {
+        count: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    InboxPage {
+        ids: Vec<i64>,
+        next_start_after: Option<i64>,
+    },
+    InboxDeleted {
+        removed: u64,
+    },
 }
 
 impl TryFrom<String> for ServerResponseText {
@@ -120,13 +383,19 @@ impl From<ServerResponse> for ServerResponseText {
     fn from(resp: ServerResponse) -> Self {
         match resp {
             ServerResponse::Received(reconstructed) => {
+                let reply_surbs: Vec<_> = reconstructed
+                    .reply_surbs
+                    .iter()
+                    .map(|reply_surb| reply_surb.to_base58_string())
+                    .collect();
+
                 ServerResponseText::Received {
                     // TODO: ask DH what is more appropriate, lossy utf8 conversion or returning error and then
                     // pure binary later
                     message: String::from_utf8_lossy(&reconstructed.message).into_owned(),
-                    reply_surb: reconstructed
-                        .reply_surb
-                        .map(|reply_surb| reply_surb.to_base58_string()),
+                    reply_surb: reply_surbs.first().cloned(),
+                    reply_surbs,
+                    headers: reconstructed.headers.map(Into::into),
                 }
             }
             ServerResponse::SelfAddress(recipient) => ServerResponseText::SelfAddress {
@@ -135,6 +404,108 @@ impl From<ServerResponse> for ServerResponseText {
             ServerResponse::Error(err) => ServerResponseText::Error {
                 message: err.to_string(),
             },
+            ServerResponse::MessageSent { message_id } => {
+                ServerResponseText::MessageSent { message_id }
+            }
+            ServerResponse::Delivered { message_id } => {
+                ServerResponseText::Delivered { message_id }
+            }
+            ServerResponse::DeliveryFailed {
+                message_id,
+                retransmissions,
+                failed_fragments,
+            } => ServerResponseText::DeliveryFailed {
+                message_id,
+                retransmissions,
+                failed_fragments,
+            },
+            ServerResponse::DeliveryExpired {
+                message_id,
+                failed_fragments,
+            } => ServerResponseText::DeliveryExpired {
+                message_id,
+                failed_fragments,
+            },
+            ServerResponse::Bandwidth {
+                remaining,
+                consumed,
+                burn_rate,
+            } => ServerResponseText::Bandwidth {
+                remaining,
+                consumed,
+                burn_rate,
+            },
+            ServerResponse::StreamOpened { stream_id } => {
+                ServerResponseText::StreamOpened { stream_id }
+            }
+            ServerResponse::StreamChunkAccepted {
+                stream_id,
+                pending_packets,
+            } => ServerResponseText::StreamChunkAccepted {
+                stream_id,
+                pending_packets,
+            },
+            ServerResponse::StreamClosed {
+                stream_id,
+                chunks,
+                bytes,
+            } => ServerResponseText::StreamClosed {
+                stream_id,
+                chunks,
+                bytes,
+            },
+            ServerResponse::GatewayInfo {
+                gateway_id,
+                gateway_owner,
+                gateway_listener,
+            } => ServerResponseText::GatewayInfo {
+                gateway_id,
+                gateway_owner,
+                gateway_listener,
+            },
+            ServerResponse::Topology { mixnodes, gateways } => ServerResponseText::Topology {
+                mixnodes: mixnodes
+                    .into_iter()
+                    .map(|(layer, identity)| MixnodeText { layer, identity })
+                    .collect(),
+                gateways,
+            },
+            ServerResponse::PendingAcks {
+                pending_acks,
+                pending_messages,
+                pending_packets,
+            } => ServerResponseText::PendingAcks {
+                pending_acks,
+                pending_messages,
+                pending_packets,
+            },
+            ServerResponse::CoverTraffic {
+                loop_cover_traffic_average_delay_ms,
+                message_sending_average_delay_ms,
+                loop_cover_packets_sent,
+                out_queue_cover_packets_sent,
+                real_packets_sent,
+            } => ServerResponseText::CoverTraffic {
+                loop_cover_traffic_average_delay_ms,
+                message_sending_average_delay_ms,
+                loop_cover_packets_sent,
+                out_queue_cover_packets_sent,
+                real_packets_sent,
+            },
+            ServerResponse::TopologyRefreshed { routable } => {
+                ServerResponseText::TopologyRefreshed { routable }
+            }
+            ServerResponse::InboxCount { count } => ServerResponseText::InboxCount { count },
+            ServerResponse::InboxPage {
+                ids,
+                next_start_after,
+            } => ServerResponseText::InboxPage {
+                ids,
+                next_start_after,
+            },
+            ServerResponse::InboxDeleted { removed } => {
+                ServerResponseText::InboxDeleted { removed }
+            }
         }
     }
 }
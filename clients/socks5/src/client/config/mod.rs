@@ -8,10 +8,59 @@ use config::defaults::DEFAULT_SOCKS5_LISTENING_PORT;
 use config::NymConfig;
 use nymsphinx::addressing::clients::Recipient;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 mod template;
 
+const DEFAULT_SOCKS5_LISTENING_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// How the service provider is chosen for every new connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderSelection {
+    /// The healthy providers are used in turns.
+    RoundRobin,
+
+    /// The healthy provider with the lowest measured round trip time is used.
+    Latency,
+}
+
+impl Default for ProviderSelection {
+    fn default() -> Self {
+        ProviderSelection::RoundRobin
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownProviderSelection(String);
+
+impl Display for UnknownProviderSelection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is not a valid provider selection - use either 'round_robin' or 'latency'",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownProviderSelection {}
+
+impl FromStr for ProviderSelection {
+    type Err = UnknownProviderSelection;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "round_robin" => Ok(ProviderSelection::RoundRobin),
+            "latency" => Ok(ProviderSelection::Latency),
+            _ => Err(UnknownProviderSelection(s.to_string())),
+        }
+    }
+}
+
 #[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
@@ -61,6 +110,36 @@ impl Config {
         self
     }
 
+    pub fn with_socks4a_port(mut self, port: u16) -> Self {
+        self.socks5.socks4a_listening_port = port;
+        self
+    }
+
+    pub fn with_http_port(mut self, port: u16) -> Self {
+        self.socks5.http_listening_port = port;
+        self
+    }
+
+    pub fn with_additional_provider_mix_addresses(mut self, addresses: Vec<String>) -> Self {
+        self.socks5.additional_provider_mix_addresses = addresses;
+        self
+    }
+
+    pub fn with_provider_selection(mut self, provider_selection: ProviderSelection) -> Self {
+        self.socks5.provider_selection = provider_selection;
+        self
+    }
+
+    pub fn with_listening_address(mut self, listening_address: IpAddr) -> Self {
+        self.socks5.listening_address = listening_address;
+        self
+    }
+
+    pub fn with_credentials_file(mut self, credentials_file: PathBuf) -> Self {
+        self.socks5.credentials_file = credentials_file;
+        self
+    }
+
     pub fn with_provider_mix_address(mut self, address: String) -> Self {
         self.socks5.provider_mix_address = address;
         self
@@ -76,6 +155,25 @@ impl Config {
             .expect("malformed provider address")
     }
 
+    /// Mix addresses of all the providers the requests can be sent to, starting with the primary one.
+    pub fn get_provider_mix_addresses(&self) -> Vec<Recipient> {
+        std::iter::once(self.get_provider_mix_address())
+            .chain(
+                self.socks5
+                    .additional_provider_mix_addresses
+                    .iter()
+                    .map(|address| {
+                        Recipient::try_from_base58_string(address)
+                            .expect("malformed additional provider address")
+                    }),
+            )
+            .collect()
+    }
+
+    pub fn get_provider_selection(&self) -> ProviderSelection {
+        self.socks5.provider_selection
+    }
+
     pub fn get_base(&self) -> &BaseConfig<Self> {
         &self.base
     }
@@ -87,6 +185,42 @@ impl Config {
     pub fn get_listening_port(&self) -> u16 {
         self.socks5.listening_port
     }
+
+    pub fn get_listening_address(&self) -> IpAddr {
+        self.socks5.listening_address
+    }
+
+    /// Port of the additional listener accepting SOCKS4a requests, if it's enabled.
+    pub fn get_socks4a_listening_port(&self) -> Option<u16> {
+        non_zero_port(self.socks5.socks4a_listening_port)
+    }
+
+    /// Port of the additional listener accepting HTTP CONNECT requests, if it's enabled.
+    pub fn get_http_listening_port(&self) -> Option<u16> {
+        non_zero_port(self.socks5.http_listening_port)
+    }
+
+    /// File with the usernames and passwords of the users allowed to use the proxy.
+    /// `None` if the connections are not authenticated.
+    pub fn get_credentials_file(&self) -> Option<PathBuf> {
+        non_empty_path(&self.socks5.credentials_file)
+    }
+}
+
+fn non_zero_port(port: u16) -> Option<u16> {
+    if port == 0 {
+        None
+    } else {
+        Some(port)
+    }
+}
+
+fn non_empty_path(path: &Path) -> Option<PathBuf> {
+    if path.as_os_str().is_empty() {
+        None
+    } else {
+        Some(path.to_path_buf())
+    }
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq, Serialize)]
@@ -95,15 +229,51 @@ pub struct Socks5 {
     /// The port on which the client will be listening for incoming requests
     listening_port: u16,
 
+    /// Address on which the client will be listening for incoming requests.
+    #[serde(default = "default_listening_address")]
+    listening_address: IpAddr,
+
+    /// If not 0, the port on which the client will be listening for SOCKS4a requests.
+    #[serde(default)]
+    socks4a_listening_port: u16,
+
+    /// If not 0, the port on which the client will be listening for HTTP CONNECT requests.
+    #[serde(default)]
+    http_listening_port: u16,
+
     /// The mix address of the provider to which all requests are going to be sent.
     provider_mix_address: String,
+
+    /// Mix addresses of additional providers the requests can be sent to, so that the client
+    /// could keep on working if any of them stops responding.
+    #[serde(default)]
+    additional_provider_mix_addresses: Vec<String>,
+
+    /// How the provider is chosen for every new connection.
+    #[serde(default)]
+    provider_selection: ProviderSelection,
+
+    /// If not empty, path to the file with the `username:password` pairs of the users allowed
+    /// to use the proxy, in which case the connections have to authenticate.
+    #[serde(default)]
+    credentials_file: PathBuf,
+}
+
+fn default_listening_address() -> IpAddr {
+    DEFAULT_SOCKS5_LISTENING_ADDRESS
 }
 
 impl Socks5 {
     pub fn new<S: Into<String>>(provider_mix_address: S) -> Self {
         Socks5 {
             listening_port: DEFAULT_SOCKS5_LISTENING_PORT,
+            listening_address: DEFAULT_SOCKS5_LISTENING_ADDRESS,
+            socks4a_listening_port: 0,
+            http_listening_port: 0,
             provider_mix_address: provider_mix_address.into(),
+            additional_provider_mix_addresses: Vec::new(),
+            provider_selection: Default::default(),
+            credentials_file: Default::default(),
         }
     }
 }
@@ -112,7 +282,13 @@ impl Default for Socks5 {
     fn default() -> Self {
         Socks5 {
             listening_port: DEFAULT_SOCKS5_LISTENING_PORT,
+            listening_address: DEFAULT_SOCKS5_LISTENING_ADDRESS,
+            socks4a_listening_port: 0,
+            http_listening_port: 0,
             provider_mix_address: "".into(),
+            additional_provider_mix_addresses: Vec::new(),
+            provider_selection: Default::default(),
+            credentials_file: Default::default(),
         }
     }
 }
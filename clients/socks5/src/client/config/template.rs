@@ -49,6 +49,21 @@ reply_encryption_key_store_path = '{{ client.reply_encryption_key_store_path }}'
 # Path to the database containing bandwidth credentials
 database_path = '{{ client.database_path }}'
 
+# Indicates whether fragments of sent messages should be recorded on disk until they are
+# acknowledged, so that they could be resent if the client gets restarted in the meantime.
+# The fragments are only resent until the message delivery deadline, or the reply key ttl
+# if there's no deadline, and replies sent using reply SURBs are never recorded.
+# Note that the fragments are stored unencrypted, so anyone able to read the journal file
+# can read the messages that are still in transit. The file is only accessible by its owner.
+enable_outbound_journal = {{ client.enable_outbound_journal }}
+
+# Path to the database containing fragments of sent messages that were not yet acknowledged.
+outbound_journal_path = '{{ client.outbound_journal_path }}'
+
+# Indicates whether the messages stored by the gateway while the client was offline should
+# only be retrieved on demand, rather than all of them being pushed as soon as the client connects.
+gateway_pull_mode = {{ client.gateway_pull_mode }}
+
 # Ethereum private key.
 eth_private_key = '{{ client.eth_private_key }}'
 
@@ -80,6 +95,15 @@ gateway_owner = '{{ client.gateway_endpoint.gateway_owner }}'
 # Address of the gateway listener to which all client requests should be sent.
 gateway_listener = '{{ client.gateway_endpoint.gateway_listener }}'
 
+# Gateways the client has also registered with, that it is going to switch to, in order,
+# if the primary gateway stops responding.
+{{#each client.backup_gateway_endpoints }}
+[[client.backup_gateway_endpoints]]
+gateway_id = '{{ this.gateway_id }}'
+gateway_owner = '{{ this.gateway_owner }}'
+gateway_listener = '{{ this.gateway_listener }}'
+{{/each}}
+
 
 ##### socket config options #####
 
@@ -88,9 +112,39 @@ gateway_listener = '{{ client.gateway_endpoint.gateway_listener }}'
 # The mix address of the provider to which all requests are going to be sent.
 provider_mix_address = '{{ socks5.provider_mix_address }}'
 
+# Mix addresses of additional providers the requests can be sent to. They are periodically
+# probed, so that the ones that stop responding are no longer used.
+additional_provider_mix_addresses = [
+    {{#each socks5.additional_provider_mix_addresses }}
+        '{{this}}',
+    {{/each}}
+]
+
+# How the provider is chosen for every new connection. Either 'round_robin' or 'latency'
+# (the one with the lowest measured round trip time).
+provider_selection = '{{ socks5.provider_selection }}'
+
 # The port on which the client will be listening for incoming requests
 listening_port = {{ socks5.listening_port }}
 
+# The address on which the client will be listening for incoming requests. Unless the proxy
+# is meant to be reached from other machines, it should be left as the loopback address
+listening_address = '{{ socks5.listening_address }}'
+
+# If not 0, the client will additionally be listening on this port for SOCKS4a requests,
+# for applications that don't support SOCKS5. Note that SOCKS4a has no notion of passwords,
+# so the requests are rejected if the credentials_file is set.
+socks4a_listening_port = {{ socks5.socks4a_listening_port }}
+
+# If not 0, the client will additionally be listening on this port for HTTP CONNECT requests,
+# for applications that only support HTTP proxies. If the credentials_file is set, the requests
+# have to include the 'Proxy-Authorization' header with the basic credentials.
+http_listening_port = {{ socks5.http_listening_port }}
+
+# If not empty, every connection has to authenticate with one of the usernames and passwords
+# stored in the following file, one 'username:password' pair per line
+credentials_file = '{{ socks5.credentials_file }}'
+
 
 ##### logging configuration options #####
 
@@ -110,5 +164,13 @@ average_ack_delay = '{{ debug.average_ack_delay }}'
 loop_cover_traffic_average_delay = '{{ debug.loop_cover_traffic_average_delay }}'
 message_sending_average_delay = '{{ debug.message_sending_average_delay }}'
 
+# How often the encryption and ack keys are rotated while the client is running.
+# '0s' disables the rotation.
+key_rotation_interval = '{{ debug.key_rotation_interval }}'
+
+# For how long the keys replaced during the rotation are still used for processing
+# received messages and acks.
+key_rotation_grace_period = '{{ debug.key_rotation_grace_period }}'
+
 "#
 }
@@ -1,34 +1,22 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
-use client_core::client::cover_traffic_stream::LoopCoverTrafficStream;
-use client_core::client::inbound_messages::{
-    InputMessage, InputMessageReceiver, InputMessageSender,
-};
+use client_core::client::bandwidth_control::BandwidthStatisticsSnapshot;
+use client_core::client::base_client::BaseClient;
+use client_core::client::gateway_failover::{config_file_persister, SelfAddressReceiver};
+use client_core::client::inbound_messages::InputMessageSender;
 use client_core::client::key_manager::KeyManager;
-use client_core::client::mix_traffic::{
-    BatchMixMessageReceiver, BatchMixMessageSender, MixTrafficController,
-};
-use client_core::client::real_messages_control::RealMessagesController;
-use client_core::client::received_buffer::{
-    ReceivedBufferRequestReceiver, ReceivedBufferRequestSender, ReceivedMessagesBufferController,
-};
-use client_core::client::reply_key_storage::ReplyKeyStorage;
-use client_core::client::topology_control::{
-    TopologyAccessor, TopologyRefresher, TopologyRefresherConfig,
+use client_core::client::key_rotation::RunningClientLock;
+use client_core::client::received_buffer::ReceivedBufferRequestSender;
+use client_core::client::traffic_control::{
+    PacketStatisticsSnapshot, TrafficMode, TrafficRates, TrafficRatesControl,
 };
 use client_core::config::persistence::key_pathfinder::ClientKeyPathfinder;
-use crypto::asymmetric::identity;
-use futures::channel::mpsc;
+use client_core::error::ClientCoreError;
+use futures::channel::{mpsc, oneshot};
 use futures::StreamExt;
-use gateway_client::bandwidth::BandwidthController;
-use gateway_client::{
-    AcknowledgementReceiver, AcknowledgementSender, GatewayClient, MixnetMessageReceiver,
-    MixnetMessageSender,
-};
 use log::*;
 use nymsphinx::addressing::clients::Recipient;
-use nymsphinx::addressing::nodes::NodeIdentity;
 
 use crate::client::config::Config;
 use crate::socks::{
@@ -46,6 +34,15 @@ pub type Socks5ControlMessageReceiver = mpsc::UnboundedReceiver<Socks5ControlMes
 pub enum Socks5ControlMessage {
     /// Tell the main task to stop
     Stop,
+
+    /// Switch the real and cover traffic streams to the rates of the specified mode
+    SetTrafficMode(TrafficMode),
+
+    /// Switch the real and cover traffic streams to the specified rates
+    SetTrafficRates(TrafficRates),
+
+    /// Report the bandwidth remaining at the gateway and the rate at which it's being consumed
+    GetBandwidthStatistics(oneshot::Sender<BandwidthStatisticsSnapshot>),
 }
 
 pub struct NymClient {
@@ -53,200 +50,59 @@ pub struct NymClient {
     /// key filepaths, etc.
     config: Config,
 
-    /// KeyManager object containing smart pointers to all relevant keys used by the client.
-    key_manager: KeyManager,
+    /// All of the components of the client that are shared with the native client.
+    base_client: BaseClient,
+
+    /// Marks the keys as being in use, so that they would not be rotated from under the client
+    /// while it's running.
+    _running_lock: Option<RunningClientLock>,
 }
 
 impl NymClient {
     pub fn new(config: Config) -> Self {
         let pathfinder = ClientKeyPathfinder::new_from_config(config.get_base());
         let key_manager = KeyManager::load_keys(&pathfinder).expect("failed to load stored keys");
+        let base_client = BaseClient::new(config.get_base(), key_manager)
+            .expect(
+                "The identity of the gateway is unknown - did you run `nym-socks5-client` init?",
+            )
+            .with_gateway_config_persister(config_file_persister(
+                config.get_base().get_id(),
+                Config::get_base_mut,
+            ));
 
         NymClient {
             config,
-            key_manager,
+            base_client,
+            _running_lock: None,
         }
     }
 
-    pub fn as_mix_recipient(&self) -> Recipient {
-        Recipient::new(
-            *self.key_manager.identity_keypair().public_key(),
-            *self.key_manager.encryption_keypair().public_key(),
-            // TODO: below only works under assumption that gateway address == gateway id
-            // (which currently is true)
-            NodeIdentity::from_base58_string(self.config.get_base().get_gateway_id()).unwrap(),
-        )
+    /// Returns the handle allowing to adjust the rates at which the real and cover packets
+    /// are sent, for example to switch into a low power mode.
+    pub fn traffic_rates(&self) -> &TrafficRatesControl {
+        self.base_client.traffic_rates()
     }
 
-    // future constantly pumping loop cover traffic at some specified average rate
-    // the pumped traffic goes to the MixTrafficController
-    fn start_cover_traffic_stream(
-        &self,
-        topology_accessor: TopologyAccessor,
-        mix_tx: BatchMixMessageSender,
-    ) {
-        info!("Starting loop cover traffic stream...");
-
-        LoopCoverTrafficStream::new(
-            self.key_manager.ack_key(),
-            self.config.get_base().get_average_ack_delay(),
-            self.config.get_base().get_average_packet_delay(),
-            self.config
-                .get_base()
-                .get_loop_cover_traffic_average_delay(),
-            mix_tx,
-            self.as_mix_recipient(),
-            topology_accessor,
-        )
-        .start();
-    }
-
-    fn start_real_traffic_controller(
-        &self,
-        topology_accessor: TopologyAccessor,
-        reply_key_storage: ReplyKeyStorage,
-        ack_receiver: AcknowledgementReceiver,
-        input_receiver: InputMessageReceiver,
-        mix_sender: BatchMixMessageSender,
-    ) {
-        let controller_config = client_core::client::real_messages_control::Config::new(
-            self.key_manager.ack_key(),
-            self.config.get_base().get_ack_wait_multiplier(),
-            self.config.get_base().get_ack_wait_addition(),
-            self.config.get_base().get_average_ack_delay(),
-            self.config.get_base().get_message_sending_average_delay(),
-            self.config.get_base().get_average_packet_delay(),
-            self.as_mix_recipient(),
-        );
-
-        info!("Starting real traffic stream...");
-
-        RealMessagesController::new(
-            controller_config,
-            ack_receiver,
-            input_receiver,
-            mix_sender,
-            topology_accessor,
-            reply_key_storage,
-        )
-        .start();
+    /// Returns the numbers of real and cover packets sent so far.
+    pub fn packet_statistics(&self) -> PacketStatisticsSnapshot {
+        self.base_client.packet_statistics().snapshot()
     }
 
-    // buffer controlling all messages fetched from provider
-    // required so that other components would be able to use them (say the websocket)
-    fn start_received_messages_buffer_controller(
-        &self,
-        query_receiver: ReceivedBufferRequestReceiver,
-        mixnet_receiver: MixnetMessageReceiver,
-        reply_key_storage: ReplyKeyStorage,
-    ) {
-        info!("Starting received messages buffer controller...");
-        ReceivedMessagesBufferController::new(
-            self.key_manager.encryption_keypair(),
-            query_receiver,
-            mixnet_receiver,
-            reply_key_storage,
-        )
-        .start()
+    /// Returns the bandwidth remaining at the gateway and the rate at which it's being consumed.
+    pub fn bandwidth_statistics(&self) -> BandwidthStatisticsSnapshot {
+        self.base_client.bandwidth_statistics().snapshot()
     }
 
-    async fn start_gateway_client(
-        &mut self,
-        mixnet_message_sender: MixnetMessageSender,
-        ack_sender: AcknowledgementSender,
-    ) -> GatewayClient {
-        let gateway_id = self.config.get_base().get_gateway_id();
-        if gateway_id.is_empty() {
-            panic!("The identity of the gateway is unknown - did you run `nym-client` init?")
-        }
-        let gateway_owner = self.config.get_base().get_gateway_owner();
-        if gateway_owner.is_empty() {
-            panic!("The owner of the gateway is unknown - did you run `nym-client` init?")
-        }
-        let gateway_address = self.config.get_base().get_gateway_listener();
-        if gateway_address.is_empty() {
-            panic!("The address of the gateway is unknown - did you run `nym-client` init?")
-        }
-
-        let gateway_identity = identity::PublicKey::from_base58_string(gateway_id)
-            .expect("provided gateway id is invalid!");
-
-        #[cfg(feature = "coconut")]
-        let bandwidth_controller = BandwidthController::new(
-            credential_storage::initialise_storage(self.config.get_base().get_database_path())
-                .await,
-            self.config.get_base().get_validator_api_endpoints(),
-        );
-        #[cfg(not(feature = "coconut"))]
-        let bandwidth_controller = BandwidthController::new(
-            credential_storage::initialise_storage(self.config.get_base().get_database_path())
-                .await,
-            self.config.get_base().get_eth_endpoint(),
-            self.config.get_base().get_eth_private_key(),
-        )
-        .expect("Could not create bandwidth controller");
-
-        let mut gateway_client = GatewayClient::new(
-            gateway_address,
-            self.key_manager.identity_keypair(),
-            gateway_identity,
-            gateway_owner,
-            Some(self.key_manager.gateway_shared_key()),
-            mixnet_message_sender,
-            ack_sender,
-            self.config.get_base().get_gateway_response_timeout(),
-            Some(bandwidth_controller),
-        );
-
-        if self.config.get_base().get_disabled_credentials_mode() {
-            gateway_client.set_disabled_credentials_mode(true)
-        }
-        gateway_client
-            .authenticate_and_start()
-            .await
-            .expect("could not authenticate and start up the gateway connection");
-
-        gateway_client
-    }
-
-    // future responsible for periodically polling directory server and updating
-    // the current global view of topology
-    async fn start_topology_refresher(&mut self, topology_accessor: TopologyAccessor) {
-        let topology_refresher_config = TopologyRefresherConfig::new(
-            self.config.get_base().get_validator_api_endpoints(),
-            self.config.get_base().get_topology_refresh_rate(),
-            env!("CARGO_PKG_VERSION").to_string(),
-        );
-        let mut topology_refresher =
-            TopologyRefresher::new(topology_refresher_config, topology_accessor);
-        // before returning, block entire runtime to refresh the current network view so that any
-        // components depending on topology would see a non-empty view
-        info!("Obtaining initial network topology");
-        topology_refresher.refresh().await;
-
-        // TODO: a slightly more graceful termination here
-        if !topology_refresher.is_topology_routable().await {
-            panic!(
-                "The current network topology seem to be insufficient to route any packets through\
-                - check if enough nodes and a gateway are online"
-            );
-        }
-
-        info!("Starting topology refresher...");
-        topology_refresher.start();
+    pub fn as_mix_recipient(&self) -> Recipient {
+        self.base_client.address()
     }
 
-    // controller for sending sphinx packets to mixnet (either real traffic or cover traffic)
-    // TODO: if we want to send control messages to gateway_client, this CAN'T take the ownership
-    // over it. Perhaps GatewayClient needs to be thread-shareable or have some channel for
-    // requests?
-    fn start_mix_traffic_controller(
-        &mut self,
-        mix_rx: BatchMixMessageReceiver,
-        gateway_client: GatewayClient,
-    ) {
-        info!("Starting mix traffic controller...");
-        MixTrafficController::new(mix_rx, gateway_client).start();
+    /// Returns the channel on which the new address of this client is announced whenever
+    /// it changes, that is whenever the client switches to one of its backup gateways or
+    /// rotates its encryption key (see the `key_rotation_interval` config option).
+    pub fn address_updates(&self) -> SelfAddressReceiver {
+        self.base_client.address_updates()
     }
 
     fn start_socks5_listener(
@@ -255,22 +111,52 @@ impl NymClient {
         msg_input: InputMessageSender,
     ) {
         info!("Starting socks5 listener...");
-        let auth_methods = vec![AuthenticationMethods::NoAuth as u8];
-        let allowed_users: Vec<User> = Vec::new();
+        let listening_address = self.config.get_listening_address();
+        let authenticator = match self.config.get_credentials_file() {
+            Some(credentials_file) => {
+                let allowed_users =
+                    User::load_credentials(&credentials_file).unwrap_or_else(|err| {
+                        panic!(
+                            "failed to load the socks5 credentials from {:?} - {}",
+                            credentials_file, err
+                        )
+                    });
+                info!(
+                    "Requiring username/password authentication ({} users are allowed)",
+                    allowed_users.len()
+                );
+                Authenticator::new(vec![AuthenticationMethods::UserPass as u8], allowed_users)
+            }
+            None => {
+                if !listening_address.is_loopback() {
+                    warn!(
+                        "The socks5 proxy is reachable on {} without any authentication - anyone who can reach it can use it",
+                        listening_address
+                    );
+                }
+                Authenticator::new(vec![AuthenticationMethods::NoAuth as u8], Vec::new())
+            }
+        };
 
-        let authenticator = Authenticator::new(auth_methods, allowed_users);
         let mut sphinx_socks = SphinxSocksServer::new(
+            listening_address,
             self.config.get_listening_port(),
+            self.config.get_socks4a_listening_port(),
+            self.config.get_http_listening_port(),
             authenticator,
-            self.config.get_provider_mix_address(),
-            self.as_mix_recipient(),
+            self.config.get_provider_mix_addresses(),
+            self.config.get_provider_selection(),
+            self.base_client.address_updates(),
         );
         tokio::spawn(async move { sphinx_socks.serve(msg_input, buffer_requester).await });
     }
 
     /// blocking version of `start` method. Will run forever (or until SIGINT is sent)
     pub async fn run_forever(&mut self) {
-        self.start().await;
+        if let Err(err) = self.start().await {
+            error!("Failed to start the client - {}", err);
+            return;
+        }
         if let Err(e) = tokio::signal::ctrl_c().await {
             error!(
                 "There was an error while capturing SIGINT - {:?}. We will terminate regardless",
@@ -285,75 +171,39 @@ impl NymClient {
 
     // Variant of `run_forever` that listends for remote control messages
     pub async fn run_and_listen(&mut self, mut receiver: Socks5ControlMessageReceiver) {
-        self.start().await;
-        tokio::select! {
-            message = receiver.next() =>  match message {
-                Some(Socks5ControlMessage::Stop) => {
-                    log::info!("Received: {:?}", message);
+        if let Err(err) = self.start().await {
+            error!("Failed to start the client - {}", err);
+            return;
+        }
+        while let Some(message) = receiver.next().await {
+            log::info!("Received: {:?}", message);
+            match message {
+                Socks5ControlMessage::Stop => {
                     log::info!("Shutting down");
+                    return;
+                }
+                Socks5ControlMessage::SetTrafficMode(mode) => self.traffic_rates().set_mode(mode),
+                Socks5ControlMessage::SetTrafficRates(rates) => {
+                    self.traffic_rates().set_rates(rates)
+                }
+                Socks5ControlMessage::GetBandwidthStatistics(response_sender) => {
+                    // we don't care if the requester has gone away in the meantime
+                    let _ = response_sender.send(self.bandwidth_statistics());
                 }
-                None => log::info!("none"),
             }
         }
+        log::info!("The control channel got closed")
     }
 
-    pub async fn start(&mut self) {
+    pub async fn start(&mut self) -> Result<(), ClientCoreError> {
         info!("Starting nym client");
-        // channels for inter-component communication
-        // TODO: make the channels be internally created by the relevant components
-        // rather than creating them here, so say for example the buffer controller would create the request channels
-        // and would allow anyone to clone the sender channel
-
-        // sphinx_message_sender is the transmitter for any component generating sphinx packets that are to be sent to the mixnet
-        // they are used by cover traffic stream and real traffic stream
-        // sphinx_message_receiver is the receiver used by MixTrafficController that sends the actual traffic
-        let (sphinx_message_sender, sphinx_message_receiver) = mpsc::unbounded();
-
-        // unwrapped_sphinx_sender is the transmitter of mixnet messages received from the gateway
-        // unwrapped_sphinx_receiver is the receiver for said messages - used by ReceivedMessagesBuffer
-        let (mixnet_messages_sender, mixnet_messages_receiver) = mpsc::unbounded();
-
-        // used for announcing connection or disconnection of a channel for pushing re-assembled messages to
-        let (received_buffer_request_sender, received_buffer_request_receiver) = mpsc::unbounded();
-
-        // channels responsible for controlling real messages
-        let (input_sender, input_receiver) = mpsc::unbounded::<InputMessage>();
-
-        // channels responsible for controlling ack messages
-        let (ack_sender, ack_receiver) = mpsc::unbounded();
-        let shared_topology_accessor = TopologyAccessor::new();
-
-        let reply_key_storage =
-            ReplyKeyStorage::load(self.config.get_base().get_reply_encryption_key_store_path())
-                .expect("Failed to load reply key storage!");
-
-        // the components are started in very specific order. Unless you know what you are doing,
-        // do not change that.
-        self.start_topology_refresher(shared_topology_accessor.clone())
-            .await;
-        self.start_received_messages_buffer_controller(
-            received_buffer_request_receiver,
-            mixnet_messages_receiver,
-            reply_key_storage.clone(),
-        );
-
-        let gateway_client = self
-            .start_gateway_client(mixnet_messages_sender, ack_sender)
-            .await;
-
-        self.start_mix_traffic_controller(sphinx_message_receiver, gateway_client);
-        self.start_real_traffic_controller(
-            shared_topology_accessor.clone(),
-            reply_key_storage,
-            ack_receiver,
-            input_receiver,
-            sphinx_message_sender.clone(),
-        );
+        let started = self.base_client.start(self.config.get_base()).await?;
+        self._running_lock = Some(started.running_lock);
 
-        self.start_cover_traffic_stream(shared_topology_accessor, sphinx_message_sender);
-        self.start_socks5_listener(received_buffer_request_sender, input_sender);
+        self.start_socks5_listener(started.received_buffer_request_sender, started.input_sender);
 
         info!("Client startup finished!");
         info!("The address of this client is: {}", self.as_mix_recipient());
+        Ok(())
     }
 }
@@ -0,0 +1,47 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::client::config::Config;
+use clap::{App, Arg, ArgMatches};
+use client_core::client::key_rotation::execute_rotate_keys;
+use std::time::Duration;
+
+const SECONDS_PER_HOUR: u64 = 60 * 60;
+
+pub fn command_args<'a, 'b>() -> App<'a, 'b> {
+    App::new("rotate-keys")
+        .about("Replace the encryption and ack keys of the client while keeping its identity. The previous keys remain valid for the duration of the grace period")
+        .arg(Arg::with_name("id")
+            .long("id")
+            .help("Id of the nym-mixnet-client whose keys we want to rotate")
+            .takes_value(true)
+            .required(true)
+        )
+        .arg(Arg::with_name("interval")
+            .long("interval")
+            .help("Instead of rotating the keys now, make the client rotate them automatically every specified number of hours while it is running. 0 disables the automatic rotation")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("grace-period")
+            .long("grace-period")
+            .help("Instead of rotating the keys now, set the number of hours for which the previous keys are still used for decrypting received messages and acks")
+            .takes_value(true)
+        )
+}
+
+fn parse_hours(matches: &ArgMatches<'_>, arg_name: &str) -> Option<Duration> {
+    matches.value_of(arg_name).map(|raw| {
+        let hours: u64 = raw
+            .parse()
+            .unwrap_or_else(|err| panic!("Invalid {} value provided - {:?}", arg_name, err));
+        Duration::from_secs(hours * SECONDS_PER_HOUR)
+    })
+}
+
+pub fn execute(matches: &ArgMatches<'_>) {
+    let id = matches.value_of("id").unwrap();
+    let interval = parse_hours(matches, "interval");
+    let grace_period = parse_hours(matches, "grace-period");
+
+    execute_rotate_keys(id, Config::get_base_mut, interval, grace_period)
+}
@@ -34,6 +34,17 @@ pub fn command_args<'a, 'b>() -> clap::App<'a, 'b> {
             .help("Address of the socks5 provider to send messages to.")
             .takes_value(true)
         )
+        .arg(Arg::with_name("additional-providers")
+            .long("additional-providers")
+            .help("Comma separated list of addresses of additional socks5 providers to fail over to")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("provider-selection")
+            .long("provider-selection")
+            .help("How the provider is chosen for every new connection")
+            .possible_values(&["round_robin", "latency"])
+            .takes_value(true)
+        )
         .arg(Arg::with_name("gateway")
             .long("gateway")
             .help("Id of the gateway we want to connect to. If overridden, it is user's responsibility to ensure prior registration happened")
@@ -49,6 +60,61 @@ pub fn command_args<'a, 'b>() -> clap::App<'a, 'b> {
             .long("port")
             .help("Port for the socket to listen on")
             .takes_value(true)
+        )
+        .arg(Arg::with_name("socks4a-port")
+            .long("socks4a-port")
+            .help("Port on which to additionally listen for SOCKS4a requests. 0 disables the listener")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("http-port")
+            .long("http-port")
+            .help("Port on which to additionally listen for HTTP CONNECT requests. 0 disables the listener")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("host")
+            .long("host")
+            .help("Address on which the socket should listen. Defaults to the loopback address")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("credentials-file")
+            .long("credentials-file")
+            .help("Path to the file with 'username:password' pairs of the users allowed to use the proxy")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("topology-file")
+            .long("topology-file")
+            .help("Path to a JSON file containing the network topology to use instead of the one obtained from the validator API")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("pin-topology")
+            .long("pin-topology")
+            .help("Keep on using the initially obtained network topology without ever refreshing it")
+        )
+        .arg(Arg::with_name("dump-topology")
+            .long("dump-topology")
+            .help("Path to a JSON file to which the currently used network topology is going to be written after every refresh")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("route-selection")
+            .long("route-selection")
+            .help("How the mixnodes used for the packet routes are chosen")
+            .possible_values(&["uniform", "stake", "uptime", "latency"])
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("exclude-mixnodes")
+            .long("exclude-mixnodes")
+            .help("Comma separated list of identity keys of mixnodes that should never be used for any route")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("exclude-owners")
+            .long("exclude-owners")
+            .help("Comma separated list of addresses of operators whose mixnodes should never be used for any route")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("exclude-countries")
+            .long("exclude-countries")
+            .help("Comma separated list of countries (full names, matched case-insensitively against the announced locations) whose mixnodes should never be used for any route. Mixnodes with an unknown location are excluded as well")
+            .takes_value(true)
         );
     #[cfg(feature = "eth")]
     #[cfg(not(feature = "coconut"))]
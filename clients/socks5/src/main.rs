@@ -21,6 +21,7 @@ async fn main() {
         .about("A Socks5 localhost proxy that converts incoming messages to Sphinx and sends them to a Nym address")
         .subcommand(commands::init::command_args())
         .subcommand(commands::run::command_args())
+        .subcommand(commands::rotate_keys::command_args())
         .subcommand(commands::upgrade::command_args())
         .get_matches();
 
@@ -32,6 +33,7 @@ async fn execute(matches: ArgMatches<'static>) {
         ("init", Some(m)) => commands::init::execute(m.clone()).await,
         ("run", Some(m)) => commands::run::execute(m.clone()).await,
         ("upgrade", Some(m)) => commands::upgrade::execute(m),
+        ("rotate-keys", Some(m)) => commands::rotate_keys::execute(m),
         _ => println!("{}", usage()),
     }
 }
@@ -1,3 +1,7 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+
 /// Client Authentication Methods
 pub(crate) enum AuthenticationMethods {
     /// No Authentication
@@ -16,6 +20,73 @@ pub struct User {
     pub password: String,
 }
 
+#[derive(Debug)]
+pub enum CredentialsError {
+    Io(io::Error),
+    MalformedLine(usize),
+    InvalidLength(usize),
+    NoUsers,
+}
+
+impl fmt::Display for CredentialsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CredentialsError::Io(err) => write!(f, "failed to read the credentials - {}", err),
+            CredentialsError::MalformedLine(line) => {
+                write!(f, "line {} is not a 'username:password' pair", line)
+            }
+            CredentialsError::InvalidLength(line) => write!(
+                f,
+                "username or password on line {} is not between 1 and 255 bytes long",
+                line
+            ),
+            CredentialsError::NoUsers => write!(f, "no users are defined"),
+        }
+    }
+}
+
+impl std::error::Error for CredentialsError {}
+
+impl User {
+    /// Parses the contents of a credentials file, which holds one `username:password` pair
+    /// per line. Empty lines and the ones starting with `#` are ignored.
+    pub fn parse_credentials(credentials: &str) -> Result<Vec<User>, CredentialsError> {
+        let mut users = Vec::new();
+        for (index, line) in credentials.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (username, password) = line
+                .split_once(':')
+                .ok_or(CredentialsError::MalformedLine(index + 1))?;
+
+            // the lengths are sent as single bytes during the authentication (RFC 1929)
+            let valid_length = |value: &str| (1..=u8::MAX as usize).contains(&value.len());
+            if !valid_length(username) || !valid_length(password) {
+                return Err(CredentialsError::InvalidLength(index + 1));
+            }
+
+            users.push(User {
+                username: username.to_string(),
+                password: password.to_string(),
+            });
+        }
+
+        if users.is_empty() {
+            return Err(CredentialsError::NoUsers);
+        }
+        Ok(users)
+    }
+
+    /// Loads the users from the specified credentials file.
+    pub fn load_credentials(path: &Path) -> Result<Vec<User>, CredentialsError> {
+        let credentials = std::fs::read_to_string(path).map_err(CredentialsError::Io)?;
+        Self::parse_credentials(&credentials)
+    }
+}
+
 #[derive(Clone, Debug)]
 /// Allows configuration of access methods (no auth required, username/pass, reject all)
 /// and keeps a list of users who have access if that method is enabled.
@@ -32,6 +103,13 @@ impl Authenticator {
         }
     }
 
+    /// Check if the connections have to present a username and password.
+    pub(crate) fn requires_authentication(&self) -> bool {
+        !self
+            .auth_methods
+            .contains(&(AuthenticationMethods::NoAuth as u8))
+    }
+
     /// Check if username + password pair are valid
     pub fn is_allowed(&self, user: &User) -> bool {
         if self
@@ -129,4 +207,48 @@ mod tests {
             assert!(!authenticator.is_allowed(&bad_user));
         }
     }
+
+    mod parsing_credentials {
+        use super::*;
+
+        #[test]
+        fn users_are_recovered_skipping_comments_and_empty_lines() {
+            let credentials = "# proxy users\nfoo:bar\n\n  alice:pass:word  \n";
+            let users = User::parse_credentials(credentials).unwrap();
+
+            assert_eq!(
+                users,
+                vec![
+                    User {
+                        username: "foo".to_string(),
+                        password: "bar".to_string(),
+                    },
+                    User {
+                        username: "alice".to_string(),
+                        password: "pass:word".to_string(),
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn malformed_credentials_are_rejected() {
+            assert!(matches!(
+                User::parse_credentials("foo:bar\nbaz"),
+                Err(CredentialsError::MalformedLine(2))
+            ));
+            assert!(matches!(
+                User::parse_credentials(":bar"),
+                Err(CredentialsError::InvalidLength(1))
+            ));
+            assert!(matches!(
+                User::parse_credentials(&format!("foo:{}", "a".repeat(256))),
+                Err(CredentialsError::InvalidLength(1))
+            ));
+            assert!(matches!(
+                User::parse_credentials("# nobody\n"),
+                Err(CredentialsError::NoUsers)
+            ));
+        }
+    }
 }
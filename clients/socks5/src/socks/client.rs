@@ -1,9 +1,15 @@
 #![forbid(unsafe_code)]
 
 use super::authentication::{AuthenticationMethods, Authenticator, User};
+use super::http::{self, HttpConnectRequest};
+use super::mixnet_responses::{ConnectionOutcome, PendingConnections};
+use super::providers::ProviderPool;
 use super::request::{SocksCommand, SocksRequest};
+use super::socks4::{self, Socks4Request, SOCKS4_VERSION};
 use super::types::{ResponseCode, SocksProxyError};
+use super::udp::{self, DatagramRoutes, UdpRelay};
 use super::{RESERVED, SOCKS_VERSION};
+use client_core::client::delivery_receipts::DeliveryReceiptSender;
 use client_core::client::inbound_messages::InputMessage;
 use client_core::client::inbound_messages::InputMessageSender;
 use futures::channel::mpsc;
@@ -14,15 +20,39 @@ use pin_project::pin_project;
 use proxy_helpers::connection_controller::{
     ConnectionReceiver, ControllerCommand, ControllerSender,
 };
-use proxy_helpers::proxy_runner::ProxyRunner;
+use proxy_helpers::proxy_runner::{FlowControl, ProxyRunner};
 use rand::RngCore;
-use socks5_requests::{ConnectionId, Message, RemoteAddress, Request};
+use socks5_requests::{
+    ConnectionError, ConnectionId, Message, RemoteAddress, Request, StreamControl,
+};
 use std::io;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::UdpSocket;
 use tokio::{self, net::TcpStream};
 
+/// For how long we're going to wait for the service provider to tell us whether it has managed
+/// to connect to the remote host.
+const CONNECTION_STATUS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// For how long we're going to wait for the status from a provider that has never reported one.
+/// Older providers send nothing until the remote host does, so if the application is expected
+/// to speak first, the connection is assumed to be established once this runs out.
+const UNKNOWN_PROVIDER_STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum number of providers we're going to ask to establish a single connection.
+const MAX_CONNECT_ATTEMPTS: usize = 3;
+
+/// Protocol the application is using to talk to the proxy. Every protocol has its own listener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ProxyProtocol {
+    Socks5,
+    Socks4,
+    HttpConnect,
+}
+
 #[pin_project(project = StateProject)]
 enum StreamState {
     Available(TcpStream),
@@ -51,6 +81,17 @@ impl StreamState {
         stream.unwrap()
     }
 
+    /// Returns the local address that this stream is bound to.
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            StreamState::RunningProxy => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "stream is being used to run the proxy",
+            )),
+            StreamState::Available(ref stream) => stream.local_addr(),
+        }
+    }
+
     /// Returns the remote address that this stream is connected to.
     fn peer_addr(&self) -> io::Result<SocketAddr> {
         match self {
@@ -131,12 +172,17 @@ impl AsyncWrite for StreamState {
 /// SphinxSocksServer.
 pub(crate) struct SocksClient {
     controller_sender: ControllerSender,
+    datagram_routes: DatagramRoutes,
+    pending_connections: PendingConnections,
     stream: StreamState,
+    protocol: ProxyProtocol,
     auth_nmethods: u8,
     authenticator: Authenticator,
     socks_version: u8,
     input_sender: InputMessageSender,
+    receipt_sender: DeliveryReceiptSender,
     connection_id: ConnectionId,
+    provider_pool: ProviderPool,
     service_provider: Recipient,
     self_address: Recipient,
     started_proxy: bool,
@@ -158,22 +204,31 @@ impl SocksClient {
     /// Create a new SOCKClient
     pub fn new(
         stream: TcpStream,
+        protocol: ProxyProtocol,
         authenticator: Authenticator,
         input_sender: InputMessageSender,
-        service_provider: Recipient,
+        receipt_sender: DeliveryReceiptSender,
+        provider_pool: ProviderPool,
         controller_sender: ControllerSender,
+        datagram_routes: DatagramRoutes,
+        pending_connections: PendingConnections,
         self_address: Recipient,
     ) -> Self {
         let connection_id = Self::generate_random();
         SocksClient {
             controller_sender,
+            datagram_routes,
+            pending_connections,
             connection_id,
             stream: StreamState::Available(stream),
+            protocol,
             auth_nmethods: 0,
             socks_version: 0,
             authenticator,
             input_sender,
-            service_provider,
+            receipt_sender,
+            service_provider: provider_pool.choose(&[]),
+            provider_pool,
             self_address,
             started_proxy: false,
         }
@@ -186,10 +241,111 @@ impl SocksClient {
 
     // Send an error back to the client
     pub async fn error(&mut self, r: ResponseCode) -> Result<(), SocksProxyError> {
-        self.stream.write_all(&[5, r as u8]).await?;
+        match self.protocol {
+            // the reply has to include an (unspecified) bound address
+            ProxyProtocol::Socks5 => {
+                self.stream
+                    .write_all(&[SOCKS_VERSION, r as u8, RESERVED, 1, 0, 0, 0, 0, 0, 0])
+                    .await?
+            }
+            ProxyProtocol::Socks4 => self.stream.write_all(&socks4::reply(false)).await?,
+            ProxyProtocol::HttpConnect => self.stream.write_all(http::response(r)).await?,
+        }
         Ok(())
     }
 
+    fn connection_error_response_code(err: ConnectionError) -> ResponseCode {
+        match err {
+            ConnectionError::HostNotAllowed => ResponseCode::RuleFailure,
+            ConnectionError::DnsFailure => ResponseCode::HostUnreachable,
+            ConnectionError::ConnectionRefused => ResponseCode::ConnectionRefused,
+            ConnectionError::Timeout => ResponseCode::TtlExpired,
+            ConnectionError::Other => ResponseCode::Failure,
+        }
+    }
+
+    /// Asks the service provider to connect to the remote host and waits for the outcome.
+    /// If the provider does not respond, the connection is attempted through another one,
+    /// unless the provider has never reported any connection status, in which case
+    /// the connection is optimistically assumed to be established.
+    /// Returns whether the flow control is going to be used on the established connection,
+    /// or the code that should be sent to the application if the connection has failed.
+    async fn connect_remote(
+        &mut self,
+        remote_address: RemoteAddress,
+    ) -> Result<FlowControl, ResponseCode> {
+        let max_attempts = self.provider_pool.len().min(MAX_CONNECT_ATTEMPTS);
+        let mut unresponsive_providers = Vec::new();
+
+        for attempt in 1..=max_attempts {
+            if attempt > 1 {
+                // use a fresh id so that a late response of the previous provider
+                // wouldn't get mixed with the new connection
+                self.connection_id = Self::generate_random();
+                self.service_provider = self.provider_pool.choose(&unresponsive_providers);
+            }
+
+            let reports_status = self.provider_pool.reports_status(self.service_provider);
+            let status_timeout = if reports_status {
+                CONNECTION_STATUS_TIMEOUT
+            } else {
+                UNKNOWN_PROVIDER_STATUS_TIMEOUT
+            };
+
+            let status_receiver = self.pending_connections.insert(self.connection_id);
+            self.send_connect_to_mixnet(remote_address.clone()).await;
+
+            let sent = Instant::now();
+            let status = tokio::time::timeout(status_timeout, status_receiver).await;
+            self.pending_connections.remove(self.connection_id);
+
+            match status {
+                Ok(Ok(ConnectionOutcome::DataReceived)) => {
+                    self.provider_pool
+                        .record_response(self.service_provider, sent.elapsed());
+                    return Ok(FlowControl::Disabled);
+                }
+                Ok(Ok(ConnectionOutcome::Status(status))) => {
+                    self.provider_pool
+                        .record_status_response(self.service_provider, sent.elapsed());
+                    if let Err(err) = status.result {
+                        warn!("Failed to connect to {} - {}", remote_address, err);
+                        return Err(Self::connection_error_response_code(err));
+                    }
+                    // older providers don't know anything about the flow control
+                    return Ok(if status.flow_control {
+                        FlowControl::Enabled
+                    } else {
+                        FlowControl::Disabled
+                    });
+                }
+                Ok(Err(_)) => return Err(ResponseCode::Failure),
+                // it might be an older provider waiting for the application to send something
+                Err(_) if !reports_status => {
+                    debug!(
+                        "The service provider {} has not reported the status of the connection to {} - assuming it's established",
+                        self.service_provider, remote_address
+                    );
+                    return Ok(FlowControl::Disabled);
+                }
+                Err(_) => {
+                    warn!(
+                        "The service provider {} has not connected to {} within {:?}",
+                        self.service_provider, remote_address, CONNECTION_STATUS_TIMEOUT
+                    );
+                    self.provider_pool.record_failure(self.service_provider);
+                    unresponsive_providers.push(self.service_provider);
+                    // in case the provider eventually connects, make it drop the connection
+                    self.send_message_to_mixnet(Message::StreamControl(StreamControl::Reset(
+                        self.connection_id,
+                    )));
+                }
+            }
+        }
+
+        Err(ResponseCode::TtlExpired)
+    }
+
     /// Shutdown the TcpStream to the client and end the session
     pub async fn shutdown(&mut self) -> Result<(), SocksProxyError> {
         info!("client is shutting down its TCP stream");
@@ -197,43 +353,99 @@ impl SocksClient {
         Ok(())
     }
 
-    /// Initializes the new client, checking that the correct Socks version (5)
-    /// is in use and that the client is authenticated, then runs the request.
+    /// Initializes the new client, checking that the request matches the protocol (Socks5,
+    /// Socks4a or HTTP CONNECT) of the listener and that the client is authenticated,
+    /// then runs the request.
     pub async fn run(&mut self) -> Result<(), SocksProxyError> {
-        debug!("New connection from: {}", self.stream.peer_addr()?.ip());
-        let mut header = [0u8; 2];
+        debug!(
+            "New {:?} connection from: {}",
+            self.protocol,
+            self.stream.peer_addr()?.ip()
+        );
         // Read a byte from the stream and determine the version being requested
-        self.stream.read_exact(&mut header).await?;
+        let first_byte = self.stream.read_u8().await?;
+
+        match (self.protocol, first_byte) {
+            // Valid SOCKS5
+            (ProxyProtocol::Socks5, SOCKS_VERSION) => {
+                self.socks_version = first_byte;
+                self.auth_nmethods = self.stream.read_u8().await?;
+                // Authenticate w/ client
+                self.authenticate().await?;
+                // Handle requests
+                self.handle_request().await
+            }
+            (ProxyProtocol::Socks4, SOCKS4_VERSION) => self.handle_socks4_request().await,
+            // the first byte is already part of the HTTP method
+            (ProxyProtocol::HttpConnect, _) => self.handle_http_request(first_byte).await,
+            _ => {
+                warn!(
+                    "Init: Unsupported version: SOCKS{} on the {:?} listener",
+                    first_byte, self.protocol
+                );
+                self.shutdown().await
+            }
+        }
+    }
 
-        self.socks_version = header[0];
-        self.auth_nmethods = header[1];
+    /// Handles a Socks4a client request.
+    async fn handle_socks4_request(&mut self) -> Result<(), SocksProxyError> {
+        debug!("Handling SOCKS4 CONNECT Command");
 
-        // Handle SOCKS4 requests
-        if header[0] != SOCKS_VERSION {
-            warn!("Init: Unsupported version: SOCKS{}", self.socks_version);
-            self.shutdown().await
+        // Socks4 has no notion of passwords, so it can't be used if they're required
+        if self.authenticator.requires_authentication() {
+            warn!("Rejecting a SOCKS4 request as authentication is required");
+            self.error(ResponseCode::RuleFailure).await?;
+            return self.shutdown().await;
         }
-        // Valid SOCKS5
-        else {
-            // Authenticate w/ client
-            self.authenticate().await?;
-            // Handle requests
-            self.handle_request().await
+
+        let request = Socks4Request::from_stream(&mut self.stream).await?;
+        self.proxy_connect(request.remote_address).await
+    }
+
+    /// Handles an HTTP CONNECT client request.
+    async fn handle_http_request(&mut self, first_byte: u8) -> Result<(), SocksProxyError> {
+        debug!("Handling HTTP CONNECT request");
+
+        let request = HttpConnectRequest::from_stream(first_byte, &mut self.stream).await?;
+        if self.authenticator.requires_authentication() {
+            let allowed = match &request.user {
+                Some(user) => self.authenticator.is_allowed(user),
+                None => false,
+            };
+            if !allowed {
+                debug!("Access Denied for the HTTP CONNECT request");
+                self.stream
+                    .write_all(http::AUTHENTICATION_REQUIRED_RESPONSE)
+                    .await?;
+                return self.shutdown().await;
+            }
         }
+
+        self.proxy_connect(request.remote_address).await
     }
 
-    async fn send_connect_to_mixnet(&mut self, remote_address: RemoteAddress) {
-        let req = Request::new_connect(self.connection_id, remote_address, self.self_address);
-        let msg = Message::Request(req);
+    fn send_request_to_mixnet(&self, req: Request) {
+        self.send_message_to_mixnet(Message::Request(req))
+    }
 
-        let input_message = InputMessage::new_fresh(self.service_provider, msg.into_bytes(), false);
+    fn send_message_to_mixnet(&self, msg: Message) {
+        let input_message = InputMessage::new_fresh(self.service_provider, msg.into_bytes(), 0)
+            .with_receipt_sender(self.receipt_sender.clone());
         self.input_sender.unbounded_send(input_message).unwrap();
     }
 
-    async fn run_proxy(&mut self, conn_receiver: ConnectionReceiver, remote_proxy_target: String) {
-        self.send_connect_to_mixnet(remote_proxy_target.clone())
-            .await;
+    async fn send_connect_to_mixnet(&mut self, remote_address: RemoteAddress) {
+        let req = Request::new_connect(self.connection_id, remote_address, self.self_address);
+        self.send_request_to_mixnet(req);
+    }
 
+    async fn run_proxy(
+        &mut self,
+        conn_receiver: ConnectionReceiver,
+        remote_proxy_target: String,
+        flow_control: FlowControl,
+    ) {
         let stream = self.stream.run_proxy();
         let local_stream_remote = stream
             .peer_addr()
@@ -241,8 +453,10 @@ impl SocksClient {
             .to_string();
         let connection_id = self.connection_id;
         let input_sender = self.input_sender.clone();
+        let receipt_sender = self.receipt_sender.clone();
 
         let recipient = self.service_provider;
+        let control_receipt_sender = self.receipt_sender.clone();
         let (stream, _) = ProxyRunner::new(
             stream,
             local_stream_remote,
@@ -251,17 +465,111 @@ impl SocksClient {
             input_sender,
             connection_id,
         )
-        .run(move |conn_id, read_data, socket_closed| {
-            let provider_request = Request::new_send(conn_id, read_data, socket_closed);
-            let provider_message = Message::Request(provider_request);
-            InputMessage::new_fresh(recipient, provider_message.into_bytes(), false)
-        })
+        .run(
+            flow_control,
+            move |conn_id, read_data, socket_closed| {
+                let provider_request = Request::new_send(conn_id, read_data, socket_closed);
+                let provider_message = Message::Request(provider_request);
+                InputMessage::new_fresh(recipient, provider_message.into_bytes(), 0)
+                    .with_receipt_sender(receipt_sender.clone())
+            },
+            move |control| {
+                let provider_message = Message::StreamControl(control);
+                InputMessage::new_fresh(recipient, provider_message.into_bytes(), 0)
+                    .with_receipt_sender(control_receipt_sender.clone())
+            },
+        )
         .await
         .into_inner();
         // recover stream from the proxy
         self.stream.finish_proxy(stream)
     }
 
+    /// Relays the datagrams of the application through the mixnet until it closes
+    /// the TCP connection it has requested the UDP association on.
+    async fn run_udp_association(&mut self) -> Result<(), SocksProxyError> {
+        // the application is going to send the datagrams to the same interface it has connected to
+        let socket = UdpSocket::bind(SocketAddr::new(self.stream.local_addr()?.ip(), 0)).await?;
+        let bound_address = socket.local_addr()?;
+        let client_ip = self.stream.peer_addr()?.ip();
+
+        let (datagram_sender, datagram_receiver) = mpsc::unbounded();
+        self.datagram_routes
+            .insert(self.connection_id, datagram_sender);
+        self.send_request_to_mixnet(Request::new_udp_associate(
+            self.connection_id,
+            self.self_address,
+        ));
+        self.acknowledge_udp_associate(bound_address).await?;
+
+        info!(
+            "Starting UDP association on {} (id: {})",
+            bound_address, self.connection_id
+        );
+        UdpRelay::new(
+            socket,
+            self.connection_id,
+            client_ip,
+            self.service_provider,
+            self.self_address,
+            self.input_sender.clone(),
+            self.receipt_sender.clone(),
+        )
+        .run(&mut self.stream, datagram_receiver)
+        .await;
+        info!("UDP association is finished (id: {})", self.connection_id);
+
+        self.datagram_routes.remove(self.connection_id);
+        self.send_request_to_mixnet(Request::new_udp_close(
+            self.connection_id,
+            self.self_address,
+        ));
+        Ok(())
+    }
+
+    /// Connects to the remote through the mixnet and, if successful, proxies the data
+    /// until either side closes the connection.
+    async fn proxy_connect(
+        &mut self,
+        remote_address: RemoteAddress,
+    ) -> Result<(), SocksProxyError> {
+        trace!("Connecting to: {:?}", remote_address.clone());
+        let flow_control = match self.connect_remote(remote_address.clone()).await {
+            Ok(flow_control) => flow_control,
+            Err(response_code) => {
+                self.error(response_code).await?;
+                self.shutdown().await?;
+                return Ok(());
+            }
+        };
+        self.acknowledge_connect().await?;
+
+        // setup for receiving from the mixnet
+        let (mix_sender, mix_receiver) = mpsc::unbounded();
+
+        self.started_proxy = true;
+        self.controller_sender
+            .unbounded_send(ControllerCommand::Insert(
+                self.connection_id,
+                mix_sender,
+                flow_control,
+            ))
+            .unwrap();
+
+        info!(
+            "Starting proxy for {} (id: {})",
+            remote_address.clone(),
+            self.connection_id
+        );
+        self.run_proxy(mix_receiver, remote_address.clone(), flow_control)
+            .await;
+        info!(
+            "Proxy for {} is finished (id: {})",
+            remote_address, self.connection_id
+        );
+        Ok(())
+    }
+
     /// Handles a client request.
     async fn handle_request(&mut self) -> Result<(), SocksProxyError> {
         debug!("Handling CONNECT Command");
@@ -269,39 +577,39 @@ impl SocksClient {
         let request = SocksRequest::from_stream(&mut self.stream).await?;
         let remote_address = request.to_string();
 
-        // setup for receiving from the mixnet
-        let (mix_sender, mix_receiver) = mpsc::unbounded();
-
         match request.command {
             // Use the Proxy to connect to the specified addr/port
-            SocksCommand::Connect => {
-                trace!("Connecting to: {:?}", remote_address.clone());
-                self.acknowledge_socks5().await;
-
-                self.started_proxy = true;
-                self.controller_sender
-                    .unbounded_send(ControllerCommand::Insert(self.connection_id, mix_sender))
-                    .unwrap();
-
-                info!(
-                    "Starting proxy for {} (id: {})",
-                    remote_address.clone(),
-                    self.connection_id
-                );
-                self.run_proxy(mix_receiver, remote_address.clone()).await;
-                info!(
-                    "Proxy for {} is finished (id: {})",
-                    remote_address, self.connection_id
-                );
+            SocksCommand::Connect => self.proxy_connect(remote_address).await?,
+
+            SocksCommand::UdpAssociate => {
+                trace!("Associating UDP relay for: {:?}", remote_address);
+                self.run_udp_association().await?;
             }
 
-            SocksCommand::Bind => unimplemented!(), // not handled
-            SocksCommand::UdpAssociate => unimplemented!(), // not handled
+            SocksCommand::Bind => {
+                warn!("BIND command is not supported");
+                self.error(ResponseCode::CommandNotSupported).await?;
+                self.shutdown().await?;
+            }
         };
 
         Ok(())
     }
 
+    /// Tells the application the connection has been established, using its protocol.
+    async fn acknowledge_connect(&mut self) -> Result<(), SocksProxyError> {
+        match self.protocol {
+            ProxyProtocol::Socks5 => self.acknowledge_socks5().await,
+            ProxyProtocol::Socks4 => self.stream.write_all(&socks4::reply(true)).await?,
+            ProxyProtocol::HttpConnect => {
+                self.stream
+                    .write_all(http::response(ResponseCode::Success))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
     /// Writes a Socks5 header back to the requesting client's TCP stream,
     /// basically saying "I acknowledge your request and am dealing with it".
     async fn acknowledge_socks5(&mut self) {
@@ -322,6 +630,21 @@ impl SocksClient {
             .unwrap();
     }
 
+    /// Writes a Socks5 header back to the requesting client's TCP stream, telling it
+    /// the address it should send its datagrams to.
+    async fn acknowledge_udp_associate(
+        &mut self,
+        bound_address: SocketAddr,
+    ) -> Result<(), SocksProxyError> {
+        let response: Vec<_> = [SOCKS_VERSION, ResponseCode::Success as u8, RESERVED]
+            .iter()
+            .cloned()
+            .chain(udp::encode_socks_address(&bound_address.to_string()).into_iter())
+            .collect();
+        self.stream.write_all(&response).await?;
+        Ok(())
+    }
+
     /// Authenticate the incoming request. Each request is checked for its
     /// authentication method. A user/password request will extract the
     /// username and password from the stream, then check with the Authenticator
@@ -394,6 +717,7 @@ impl SocksClient {
 
                 // Shutdown
                 self.shutdown().await?;
+                return Err(ResponseCode::Failure.into());
             }
 
             Ok(())
@@ -425,3 +749,69 @@ impl SocksClient {
         Ok(methods)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::config::ProviderSelection;
+    use crypto::asymmetric::{encryption, identity};
+    use futures::StreamExt;
+    use tokio::net::TcpListener;
+
+    fn test_recipient() -> Recipient {
+        let mut rng = rand::thread_rng();
+        Recipient::new(
+            *identity::KeyPair::new(&mut rng).public_key(),
+            *encryption::KeyPair::new(&mut rng).public_key(),
+            *identity::KeyPair::new(&mut rng).public_key(),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_through_provider_never_reporting_status_is_assumed_established() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _application = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let (input_sender, mut input_receiver) = mpsc::unbounded();
+        let (receipt_sender, _receipt_receiver) = mpsc::unbounded();
+        let (controller_sender, _controller_receiver) = mpsc::unbounded();
+        let provider = test_recipient();
+        let mut client = SocksClient::new(
+            stream,
+            ProxyProtocol::Socks5,
+            Authenticator::new(vec![AuthenticationMethods::NoAuth as u8], Vec::new()),
+            input_sender,
+            receipt_sender,
+            ProviderPool::new(vec![provider], ProviderSelection::RoundRobin),
+            controller_sender,
+            DatagramRoutes::default(),
+            PendingConnections::default(),
+            test_recipient(),
+        );
+
+        // just like the providers released before the connection status was introduced,
+        // connect and then stay silent until the remote host (or the application) says something
+        let requester = tokio::spawn(async move {
+            while let Some(InputMessage::Fresh { data, .. }) = input_receiver.next().await {
+                if let Ok(Message::Request(Request::Connect(request))) =
+                    Message::try_from_bytes(&data)
+                {
+                    return request.conn_id;
+                }
+            }
+            panic!("no connection was requested")
+        });
+
+        let started = Instant::now();
+        let flow_control = client
+            .connect_remote("nymtech.net:443".to_string())
+            .await
+            .unwrap();
+        assert_eq!(flow_control, FlowControl::Disabled);
+        assert!(started.elapsed() < CONNECTION_STATUS_TIMEOUT);
+        assert_eq!(requester.await.unwrap(), client.connection_id);
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use super::authentication::User;
+use super::types::{ResponseCode, SocksProxyError};
+use log::*;
+use socks5_requests::RemoteAddress;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Maximum size of the request head (i.e. the request line and the headers) we're willing to read.
+const MAX_REQUEST_HEAD_SIZE: usize = 8 * 1024;
+
+const HEAD_TERMINATOR: &[u8] = b"\r\n\r\n";
+
+/// An HTTP CONNECT request hitting the proxy.
+pub(crate) struct HttpConnectRequest {
+    pub remote_address: RemoteAddress,
+
+    /// Credentials from the `Proxy-Authorization` header, if any were provided.
+    pub user: Option<User>,
+}
+
+// decodes the credentials of the `Basic` authentication scheme
+fn parse_basic_credentials(value: &str) -> Option<User> {
+    let (scheme, credentials) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64::decode(credentials.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some(User {
+        username: username.to_string(),
+        password: password.to_string(),
+    })
+}
+
+impl HttpConnectRequest {
+    /// Parse an HTTP CONNECT request from a TcpStream. The first byte of the request is expected
+    /// to have already been read off the stream, so it has to be provided separately.
+    ///
+    /// The head is read byte by byte so that nothing the application might send after it would
+    /// get consumed.
+    pub async fn from_stream<R>(first_byte: u8, stream: &mut R) -> Result<Self, SocksProxyError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut head = vec![first_byte];
+        while !head.ends_with(HEAD_TERMINATOR) {
+            if head.len() == MAX_REQUEST_HEAD_SIZE {
+                warn!("HTTP request head is too long");
+                return Err(ResponseCode::Failure.into());
+            }
+            head.push(stream.read_u8().await?);
+        }
+
+        let head = String::from_utf8_lossy(&head);
+        let mut lines = head.lines();
+
+        // the request line looks like `CONNECT host:port HTTP/1.1`
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = (parts.next(), parts.next());
+        let remote_address = match (method, target) {
+            (Some("CONNECT"), Some(target)) if target.contains(':') => target.to_string(),
+            _ => {
+                warn!("Unsupported HTTP request: {}", request_line);
+                return Err(ResponseCode::CommandNotSupported.into());
+            }
+        };
+
+        let user = lines
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("proxy-authorization"))
+            .and_then(|(_, value)| parse_basic_credentials(value));
+
+        Ok(HttpConnectRequest {
+            remote_address,
+            user,
+        })
+    }
+}
+
+/// Response to the CONNECT request, telling the application whether the tunnel is established.
+pub(crate) fn response(code: ResponseCode) -> &'static [u8] {
+    match code {
+        ResponseCode::Success => b"HTTP/1.1 200 Connection established\r\n\r\n",
+        ResponseCode::RuleFailure => b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n",
+        ResponseCode::TtlExpired => b"HTTP/1.1 504 Gateway Timeout\r\nContent-Length: 0\r\n\r\n",
+        ResponseCode::NetworkUnreachable
+        | ResponseCode::HostUnreachable
+        | ResponseCode::ConnectionRefused => {
+            b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n"
+        }
+        ResponseCode::CommandNotSupported | ResponseCode::AddrTypeNotSupported => {
+            b"HTTP/1.1 501 Not Implemented\r\nContent-Length: 0\r\n\r\n"
+        }
+        ResponseCode::Failure => b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+    }
+}
+
+/// Response asking the application to provide valid credentials.
+pub(crate) const AUTHENTICATION_REQUIRED_RESPONSE: &[u8] =
+    b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+    Proxy-Authenticate: Basic realm=\"nym\"\r\n\
+    Content-Length: 0\r\n\r\n";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parses_connect_request_with_credentials() {
+        // "foo:bar" is "Zm9vOmJhcg==" in base64
+        let request = b"ONNECT nymtech.net:443 HTTP/1.1\r\n\
+            Host: nymtech.net:443\r\n\
+            Proxy-Authorization: Basic Zm9vOmJhcg==\r\n\r\n";
+        let parsed = HttpConnectRequest::from_stream(b'C', &mut &request[..])
+            .await
+            .unwrap();
+
+        assert_eq!("nymtech.net:443", parsed.remote_address);
+        assert_eq!(
+            Some(User {
+                username: "foo".to_string(),
+                password: "bar".to_string(),
+            }),
+            parsed.user
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_read_past_the_request_head() {
+        let request = b"ONNECT 1.2.3.4:22 HTTP/1.1\r\n\r\nSSH-2.0";
+        let mut stream = &request[..];
+        let parsed = HttpConnectRequest::from_stream(b'C', &mut stream)
+            .await
+            .unwrap();
+
+        assert_eq!("1.2.3.4:22", parsed.remote_address);
+        assert!(parsed.user.is_none());
+        assert_eq!(b"SSH-2.0", stream);
+    }
+
+    #[tokio::test]
+    async fn rejects_other_methods() {
+        let request = b"ET http://nymtech.net/ HTTP/1.1\r\n\r\n";
+        assert!(HttpConnectRequest::from_stream(b'G', &mut &request[..])
+            .await
+            .is_err());
+    }
+}
@@ -1,16 +1,80 @@
+use super::udp::DatagramRoutes;
 use client_core::client::received_buffer::ReconstructedMessagesReceiver;
 use client_core::client::received_buffer::{ReceivedBufferMessage, ReceivedBufferRequestSender};
-use futures::channel::mpsc;
+use futures::channel::{mpsc, oneshot};
 use futures::StreamExt;
 use log::*;
 use nymsphinx::receiver::ReconstructedMessage;
 use proxy_helpers::connection_controller::{ControllerCommand, ControllerSender};
-use socks5_requests::Response;
+use socks5_requests::{ConnectionId, ConnectionStatusResponse, Message, StreamControl};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// What the service provider has told us about a connection it was asked to establish.
+#[derive(Debug)]
+pub(crate) enum ConnectionOutcome {
+    /// The provider has explicitly reported whether it has managed to connect.
+    Status(ConnectionStatusResponse),
+
+    /// Older service providers never report the status of the connection, so receiving any data
+    /// for it is the first indication that it has been established.
+    DataReceived,
+}
+
+/// Connections waiting for the service provider to report whether it has managed
+/// to connect to the remote host.
+#[derive(Clone, Default)]
+pub(crate) struct PendingConnections {
+    inner: Arc<Mutex<HashMap<ConnectionId, oneshot::Sender<ConnectionOutcome>>>>,
+}
+
+impl PendingConnections {
+    pub(crate) fn insert(
+        &self,
+        connection_id: ConnectionId,
+    ) -> oneshot::Receiver<ConnectionOutcome> {
+        let (status_sender, status_receiver) = oneshot::channel();
+        self.inner
+            .lock()
+            .unwrap()
+            .insert(connection_id, status_sender);
+        status_receiver
+    }
+
+    pub(crate) fn remove(&self, connection_id: ConnectionId) {
+        self.inner.lock().unwrap().remove(&connection_id);
+    }
+
+    /// Passes the status on to the connection waiting for it. Returns the status back
+    /// if nothing is waiting for it anymore.
+    fn complete(&self, status: ConnectionStatusResponse) -> Option<ConnectionStatusResponse> {
+        match self.inner.lock().unwrap().remove(&status.connection_id) {
+            // the connection might have just given up on waiting
+            Some(status_sender) => {
+                let _ = status_sender.send(ConnectionOutcome::Status(status));
+                None
+            }
+            None => Some(status),
+        }
+    }
+
+    fn complete_on_data(&self, connection_id: ConnectionId) {
+        if let Some(status_sender) = self.inner.lock().unwrap().remove(&connection_id) {
+            debug!(
+                "Received data for connection {} before its status - assuming it's connected",
+                connection_id
+            );
+            let _ = status_sender.send(ConnectionOutcome::DataReceived);
+        }
+    }
+}
 
 pub(crate) struct MixnetResponseListener {
     buffer_requester: ReceivedBufferRequestSender,
     mix_response_receiver: ReconstructedMessagesReceiver,
     controller_sender: ControllerSender,
+    datagram_routes: DatagramRoutes,
+    pending_connections: PendingConnections,
 }
 
 impl Drop for MixnetResponseListener {
@@ -25,6 +89,8 @@ impl MixnetResponseListener {
     pub(crate) fn new(
         buffer_requester: ReceivedBufferRequestSender,
         controller_sender: ControllerSender,
+        datagram_routes: DatagramRoutes,
+        pending_connections: PendingConnections,
     ) -> Self {
         let (mix_response_sender, mix_response_receiver) = mpsc::unbounded();
         buffer_requester
@@ -35,21 +101,69 @@ impl MixnetResponseListener {
             buffer_requester,
             mix_response_receiver,
             controller_sender,
+            datagram_routes,
+            pending_connections,
         }
     }
 
     async fn on_message(&self, reconstructed_message: ReconstructedMessage) {
         let raw_message = reconstructed_message.message;
-        if reconstructed_message.reply_surb.is_some() {
+        if !reconstructed_message.reply_surbs.is_empty() {
             warn!("this message had a surb - we didn't do anything with it");
         }
 
-        let response = match Response::try_from_bytes(&raw_message) {
+        let response = match Message::try_from_bytes(&raw_message) {
             Err(err) => {
-                warn!("failed to parse received response - {:?}", err);
+                warn!("failed to parse received response - {}", err);
+                return;
+            }
+            Ok(Message::Response(data)) => {
+                self.pending_connections
+                    .complete_on_data(data.connection_id);
+                data
+            }
+            Ok(Message::Datagram(datagram)) => {
+                self.datagram_routes.route(datagram);
+                return;
+            }
+            Ok(Message::ConnectionStatus(status)) => {
+                if let Some(status) = self.pending_connections.complete(status) {
+                    // we might have stopped waiting for a slow provider and assumed the connection
+                    // was established, so make sure it's closed if it has failed after all.
+                    // UDP associations are never waited for, so the provider only ever reports
+                    // their failures
+                    if status.result.is_err() {
+                        debug!(
+                            "Connection {} has failed after it was assumed to be established",
+                            status.connection_id
+                        );
+                        self.controller_sender
+                            .unbounded_send(ControllerCommand::Reset(status.connection_id))
+                            .unwrap();
+                        self.datagram_routes.remove(status.connection_id);
+                    } else {
+                        debug!(
+                            "Received a status of unknown connection {}",
+                            status.connection_id
+                        );
+                    }
+                }
+                return;
+            }
+            Ok(Message::StreamControl(control)) => {
+                let command = match control {
+                    StreamControl::Credit(conn_id, credit) => {
+                        ControllerCommand::Credit(conn_id, credit)
+                    }
+                    StreamControl::Reset(conn_id) => ControllerCommand::Reset(conn_id),
+                };
+                self.controller_sender.unbounded_send(command).unwrap();
+                return;
+            }
+            Ok(Message::Request(_)) => {
+                warn!("unexpected request received from the service provider");
                 return;
             }
-            Ok(data) => data,
         };
 
         self.controller_sender
@@ -70,3 +184,48 @@ impl MixnetResponseListener {
         error!("We should never see this message");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socks5_requests::ConnectionError;
+
+    #[test]
+    fn explicit_status_is_passed_on() {
+        let pending_connections = PendingConnections::default();
+        let mut status_receiver = pending_connections.insert(42);
+
+        pending_connections.complete(ConnectionStatusResponse::new(
+            42,
+            Err(ConnectionError::ConnectionRefused),
+        ));
+        match status_receiver.try_recv().unwrap().unwrap() {
+            ConnectionOutcome::Status(status) => {
+                assert_eq!(status.result, Err(ConnectionError::ConnectionRefused))
+            }
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn first_data_implies_the_connection_succeeded() {
+        let pending_connections = PendingConnections::default();
+        let mut status_receiver = pending_connections.insert(42);
+
+        pending_connections.complete_on_data(42);
+        assert!(matches!(
+            status_receiver.try_recv().unwrap().unwrap(),
+            ConnectionOutcome::DataReceived
+        ));
+
+        // any later status is handed back as nobody is waiting for it anymore
+        let late_status = pending_connections
+            .complete(ConnectionStatusResponse::new(
+                42,
+                Err(ConnectionError::Other),
+            ))
+            .unwrap();
+        assert_eq!(late_status.result, Err(ConnectionError::Other));
+        assert!(pending_connections.inner.lock().unwrap().is_empty());
+    }
+}
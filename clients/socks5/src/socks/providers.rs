@@ -0,0 +1,456 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use super::mixnet_responses::PendingConnections;
+use crate::client::config::ProviderSelection;
+use client_core::client::gateway_failover::SelfAddressReceiver;
+use client_core::client::inbound_messages::{InputMessage, InputMessageSender};
+use futures::future::join_all;
+use log::*;
+use nymsphinx::addressing::clients::Recipient;
+use rand::RngCore;
+use socks5_requests::{Message, Request};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often all the providers are checked for being alive.
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// For how long we're going to wait for the response to the probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of consecutive probes or connections a provider has to fail to respond to
+/// before it stops being used.
+const MAX_CONSECUTIVE_FAILURES: u32 = 2;
+
+/// Weight of the newest round trip time measurement in the moving average of the latency.
+const LATENCY_SMOOTHING_FACTOR: f64 = 0.25;
+
+/// Kind of the response received from a provider, telling which parts of the protocol
+/// the provider understands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ResponseKind {
+    Data,
+    ConnectionStatus,
+    Probe,
+}
+
+#[derive(Debug)]
+struct ProviderState {
+    address: Recipient,
+    consecutive_failures: u32,
+    latency: Option<Duration>,
+
+    // older providers don't understand the probes at all, so their silence says nothing
+    // about their health until they have answered at least once
+    supports_ping: bool,
+
+    // similarly, older providers never report the status of the connections they establish,
+    // so they can't be expected to until they have done it at least once
+    reports_status: bool,
+}
+
+impl ProviderState {
+    fn has_address(&self, address: &Recipient) -> bool {
+        self.address.to_bytes() == address.to_bytes()
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.consecutive_failures < MAX_CONSECUTIVE_FAILURES
+    }
+}
+
+#[derive(Debug)]
+struct ProviderPoolInner {
+    providers: Vec<ProviderState>,
+    selection: ProviderSelection,
+    next: usize,
+}
+
+/// The service providers all the connections are spread across, alongside what we know about
+/// their health.
+#[derive(Clone, Debug)]
+pub(crate) struct ProviderPool {
+    inner: Arc<Mutex<ProviderPoolInner>>,
+}
+
+impl ProviderPool {
+    pub(crate) fn new(providers: Vec<Recipient>, selection: ProviderSelection) -> Self {
+        assert!(!providers.is_empty(), "at least one provider is required");
+        let providers = providers
+            .into_iter()
+            .map(|address| ProviderState {
+                address,
+                consecutive_failures: 0,
+                latency: None,
+                supports_ping: false,
+                reports_status: false,
+            })
+            .collect();
+
+        ProviderPool {
+            inner: Arc::new(Mutex::new(ProviderPoolInner {
+                providers,
+                selection,
+                next: 0,
+            })),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.inner.lock().unwrap().providers.len()
+    }
+
+    pub(crate) fn addresses(&self) -> Vec<Recipient> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .providers
+            .iter()
+            .map(|provider| provider.address)
+            .collect()
+    }
+
+    /// Chooses the provider for a new connection, avoiding the excluded ones unless there's
+    /// nothing else left. If none of them seems healthy, all of them are considered,
+    /// as they might have just recovered.
+    pub(crate) fn choose(&self, excluded: &[Recipient]) -> Recipient {
+        let mut inner = self.inner.lock().unwrap();
+        let is_excluded =
+            |provider: &ProviderState| excluded.iter().any(|e| provider.has_address(e));
+        let all_excluded = inner.providers.iter().all(is_excluded);
+        let is_candidate = |provider: &ProviderState| all_excluded || !is_excluded(provider);
+
+        let any_healthy = inner
+            .providers
+            .iter()
+            .any(|provider| is_candidate(provider) && provider.is_healthy());
+        let is_usable = |provider: &ProviderState| {
+            is_candidate(provider) && (!any_healthy || provider.is_healthy())
+        };
+        let num_providers = inner.providers.len();
+
+        let chosen = match inner.selection {
+            ProviderSelection::RoundRobin => {
+                let start = inner.next;
+                let chosen = (0..num_providers)
+                    .map(|offset| (start + offset) % num_providers)
+                    .find(|&index| is_usable(&inner.providers[index]))
+                    .unwrap_or(start % num_providers);
+                inner.next = chosen + 1;
+                chosen
+            }
+            // the providers without any measurements yet are tried last
+            ProviderSelection::Latency => inner
+                .providers
+                .iter()
+                .enumerate()
+                .filter(|(_, provider)| is_usable(provider))
+                .min_by_key(|(_, provider)| provider.latency.unwrap_or(Duration::MAX))
+                .map(|(index, _)| index)
+                .unwrap_or_default(),
+        };
+
+        inner.providers[chosen].address
+    }
+
+    /// Whether the provider is known to report the status of the connections it's asked
+    /// to establish.
+    pub(crate) fn reports_status(&self, address: Recipient) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .providers
+            .iter()
+            .find(|provider| provider.has_address(&address))
+            .map(|provider| provider.reports_status)
+            .unwrap_or_default()
+    }
+
+    /// Records the provider has responded after the specified time.
+    pub(crate) fn record_response(&self, address: Recipient, round_trip_time: Duration) {
+        self.update_on_response(address, round_trip_time, ResponseKind::Data)
+    }
+
+    /// Records the provider has reported the status of a connection after the specified time.
+    pub(crate) fn record_status_response(&self, address: Recipient, round_trip_time: Duration) {
+        self.update_on_response(address, round_trip_time, ResponseKind::ConnectionStatus)
+    }
+
+    /// Records the provider has answered the probe after the specified time.
+    pub(crate) fn record_probe_response(&self, address: Recipient, round_trip_time: Duration) {
+        self.update_on_response(address, round_trip_time, ResponseKind::Probe)
+    }
+
+    fn update_on_response(
+        &self,
+        address: Recipient,
+        round_trip_time: Duration,
+        kind: ResponseKind,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(provider) = inner.providers.iter_mut().find(|p| p.has_address(&address)) {
+            if kind == ResponseKind::Probe && !provider.supports_ping {
+                debug!("Provider {} supports the probes", address);
+                provider.supports_ping = true;
+            }
+            // the probes are answered with the connection status as well
+            if kind != ResponseKind::Data && !provider.reports_status {
+                debug!("Provider {} reports the status of its connections", address);
+                provider.reports_status = true;
+            }
+            if !provider.is_healthy() {
+                info!("Provider {} is responding again", address);
+            }
+            provider.consecutive_failures = 0;
+            provider.latency = Some(match provider.latency {
+                Some(latency) => {
+                    latency.mul_f64(1.0 - LATENCY_SMOOTHING_FACTOR)
+                        + round_trip_time.mul_f64(LATENCY_SMOOTHING_FACTOR)
+                }
+                None => round_trip_time,
+            });
+        }
+    }
+
+    /// Records the provider has failed to respond in time.
+    pub(crate) fn record_failure(&self, address: Recipient) {
+        self.update_on_failure(address, false)
+    }
+
+    /// Records the provider has failed to answer the probe in time. It's only held against
+    /// the providers that are known to answer the probes at all.
+    pub(crate) fn record_probe_failure(&self, address: Recipient) {
+        self.update_on_failure(address, true)
+    }
+
+    fn update_on_failure(&self, address: Recipient, probed: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(provider) = inner.providers.iter_mut().find(|p| p.has_address(&address)) {
+            if probed && !provider.supports_ping {
+                trace!("Provider {} might not support the probes", address);
+                return;
+            }
+            provider.consecutive_failures += 1;
+            if provider.consecutive_failures == MAX_CONSECUTIVE_FAILURES {
+                warn!(
+                    "Provider {} has stopped responding - it's no longer going to be used",
+                    address
+                );
+            }
+        }
+    }
+}
+
+/// Periodically pings all the providers to learn which ones are alive and how fast they respond.
+pub(crate) struct ProviderProber {
+    provider_pool: ProviderPool,
+    pending_connections: PendingConnections,
+    input_sender: InputMessageSender,
+    self_address: SelfAddressReceiver,
+}
+
+impl ProviderProber {
+    pub(crate) fn new(
+        provider_pool: ProviderPool,
+        pending_connections: PendingConnections,
+        input_sender: InputMessageSender,
+        self_address: SelfAddressReceiver,
+    ) -> Self {
+        ProviderProber {
+            provider_pool,
+            pending_connections,
+            input_sender,
+            self_address,
+        }
+    }
+
+    async fn probe(&self, provider: Recipient) {
+        // the probe is answered just like a connection request, so make sure its id never clashes
+        // with any of the actual connections
+        let probe_id = rand::rngs::OsRng.next_u64();
+        let status_receiver = self.pending_connections.insert(probe_id);
+
+        let request = Request::new_ping(probe_id, *self.self_address.borrow());
+        let input_message =
+            InputMessage::new_fresh(provider, Message::Request(request).into_bytes(), 0);
+        self.input_sender.unbounded_send(input_message).unwrap();
+
+        let sent = Instant::now();
+        match tokio::time::timeout(PROBE_TIMEOUT, status_receiver).await {
+            Ok(Ok(_)) => {
+                let round_trip_time = sent.elapsed();
+                trace!("Provider {} responded in {:?}", provider, round_trip_time);
+                self.provider_pool
+                    .record_probe_response(provider, round_trip_time)
+            }
+            _ => {
+                self.pending_connections.remove(probe_id);
+                debug!("Provider {} has not responded to the probe", provider);
+                self.provider_pool.record_probe_failure(provider)
+            }
+        }
+    }
+
+    pub(crate) async fn run(&self) {
+        let providers = self.provider_pool.addresses();
+        let mut probe_interval = tokio::time::interval(PROBE_INTERVAL);
+        loop {
+            probe_interval.tick().await;
+            join_all(providers.iter().map(|provider| self.probe(*provider))).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::asymmetric::{encryption, identity};
+
+    struct TestPool {
+        pool: ProviderPool,
+        providers: Vec<Recipient>,
+    }
+
+    impl TestPool {
+        fn new(num_providers: usize, selection: ProviderSelection) -> Self {
+            let mut rng = rand::thread_rng();
+            let providers: Vec<_> = (0..num_providers)
+                .map(|_| {
+                    Recipient::new(
+                        *identity::KeyPair::new(&mut rng).public_key(),
+                        *encryption::KeyPair::new(&mut rng).public_key(),
+                        *identity::KeyPair::new(&mut rng).public_key(),
+                    )
+                })
+                .collect();
+
+            TestPool {
+                pool: ProviderPool::new(providers.clone(), selection),
+                providers,
+            }
+        }
+
+        // returns the index of the chosen provider
+        fn choose(&self, excluded: &[usize]) -> usize {
+            let excluded: Vec<_> = excluded.iter().map(|&i| self.providers[i]).collect();
+            let chosen = self.pool.choose(&excluded);
+            self.providers
+                .iter()
+                .position(|provider| provider.to_bytes() == chosen.to_bytes())
+                .unwrap()
+        }
+
+        fn respond(&self, index: usize, round_trip_millis: u64) {
+            self.pool.record_response(
+                self.providers[index],
+                Duration::from_millis(round_trip_millis),
+            )
+        }
+
+        fn fail(&self, index: usize) {
+            for _ in 0..MAX_CONSECUTIVE_FAILURES {
+                self.pool.record_failure(self.providers[index])
+            }
+        }
+    }
+
+    #[test]
+    fn round_robin_uses_providers_in_turns() {
+        let pool = TestPool::new(3, ProviderSelection::RoundRobin);
+
+        let chosen: Vec<_> = (0..6).map(|_| pool.choose(&[])).collect();
+        assert_eq!(chosen, vec![0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn round_robin_skips_unhealthy_providers() {
+        let pool = TestPool::new(3, ProviderSelection::RoundRobin);
+        pool.fail(1);
+
+        let chosen: Vec<_> = (0..4).map(|_| pool.choose(&[])).collect();
+        assert_eq!(chosen, vec![0, 2, 0, 2]);
+    }
+
+    #[test]
+    fn latency_selection_prefers_the_fastest_provider() {
+        let pool = TestPool::new(3, ProviderSelection::Latency);
+        pool.respond(0, 300);
+        pool.respond(1, 100);
+
+        assert_eq!(pool.choose(&[]), 1);
+        // the providers without any measurements are still better than the excluded ones
+        assert_eq!(pool.choose(&[0, 1]), 2);
+
+        // the latency is averaged, so a single slow response doesn't change much
+        pool.respond(1, 500);
+        assert_eq!(pool.choose(&[]), 1);
+
+        pool.fail(1);
+        assert_eq!(pool.choose(&[]), 0);
+    }
+
+    #[test]
+    fn failed_providers_are_avoided_until_they_recover() {
+        let pool = TestPool::new(2, ProviderSelection::Latency);
+        pool.respond(0, 100);
+        pool.respond(1, 200);
+
+        pool.fail(0);
+        assert_eq!(pool.choose(&[]), 1);
+
+        // if nothing is healthy, everything is given another chance
+        pool.fail(1);
+        assert_eq!(pool.choose(&[]), 0);
+
+        pool.respond(1, 200);
+        assert_eq!(pool.choose(&[]), 1);
+    }
+
+    #[test]
+    fn excluded_providers_are_used_as_the_last_resort() {
+        let pool = TestPool::new(2, ProviderSelection::RoundRobin);
+
+        assert_eq!(pool.choose(&[0]), 1);
+        assert_eq!(pool.choose(&[0]), 1);
+        assert_eq!(pool.choose(&[0, 1]), 0);
+    }
+
+    #[test]
+    fn connection_status_support_is_learned_from_responses() {
+        let pool = TestPool::new(3, ProviderSelection::RoundRobin);
+        let reports_status = |index: usize| pool.pool.reports_status(pool.providers[index]);
+        assert!(!reports_status(0));
+
+        // the connections through older providers only ever get data
+        pool.respond(0, 100);
+        assert!(!reports_status(0));
+
+        pool.pool
+            .record_status_response(pool.providers[1], Duration::from_millis(100));
+        assert!(reports_status(1));
+
+        pool.pool
+            .record_probe_response(pool.providers[2], Duration::from_millis(100));
+        assert!(reports_status(2));
+    }
+
+    #[test]
+    fn probe_failures_only_count_for_providers_answering_probes() {
+        let pool = TestPool::new(2, ProviderSelection::Latency);
+        pool.respond(0, 100);
+        pool.respond(1, 200);
+
+        let fail_probes = |index: usize| {
+            for _ in 0..MAX_CONSECUTIVE_FAILURES {
+                pool.pool.record_probe_failure(pool.providers[index])
+            }
+        };
+
+        fail_probes(0);
+        assert_eq!(pool.choose(&[]), 0);
+
+        pool.pool
+            .record_probe_response(pool.providers[0], Duration::from_millis(100));
+        fail_probes(0);
+        assert_eq!(pool.choose(&[]), 1);
+    }
+}
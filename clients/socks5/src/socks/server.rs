@@ -1,46 +1,102 @@
 use super::authentication::Authenticator;
-use super::client::SocksClient;
+use super::client::{ProxyProtocol, SocksClient};
 use super::{
-    mixnet_responses::MixnetResponseListener,
+    mixnet_responses::{MixnetResponseListener, PendingConnections},
+    providers::{ProviderPool, ProviderProber},
     types::{ResponseCode, SocksProxyError},
+    udp::DatagramRoutes,
 };
+use crate::client::config::ProviderSelection;
+use client_core::client::gateway_failover::SelfAddressReceiver;
 use client_core::client::{
-    inbound_messages::InputMessageSender, received_buffer::ReceivedBufferRequestSender,
+    delivery_receipts::{DeliveryReceiptReceiver, DeliveryReceiptSender, DeliveryStatus},
+    inbound_messages::InputMessageSender,
+    received_buffer::ReceivedBufferRequestSender,
 };
+use futures::channel::mpsc;
+use futures::StreamExt;
 use log::*;
 use nymsphinx::addressing::clients::Recipient;
-use proxy_helpers::connection_controller::Controller;
-use std::net::SocketAddr;
+use proxy_helpers::connection_controller::{Controller, ControllerSender};
+use std::net::{IpAddr, SocketAddr};
 use tokio::net::TcpListener;
 
-/// A Socks5 server that listens for connections.
+/// Handles shared by the connections accepted on all the listeners.
+#[derive(Clone)]
+struct ConnectionContext {
+    authenticator: Authenticator,
+    input_sender: InputMessageSender,
+    receipt_sender: DeliveryReceiptSender,
+    provider_pool: ProviderPool,
+    controller_sender: ControllerSender,
+    datagram_routes: DatagramRoutes,
+    pending_connections: PendingConnections,
+    self_address: SelfAddressReceiver,
+}
+
+/// A Socks5 server that listens for connections, optionally alongside the SOCKS4a
+/// and HTTP CONNECT listeners.
 pub struct SphinxSocksServer {
     authenticator: Authenticator,
     listening_address: SocketAddr,
-    service_provider: Recipient,
-    self_address: Recipient,
+    socks4a_listening_address: Option<SocketAddr>,
+    http_listening_address: Option<SocketAddr>,
+    provider_pool: ProviderPool,
+
+    /// Current address of this client. Any new connection is going to use the most recent one
+    /// if the client switches to a backup gateway.
+    self_address: SelfAddressReceiver,
 }
 
 impl SphinxSocksServer {
     /// Create a new SphinxSocks instance
     pub(crate) fn new(
+        ip: IpAddr,
         port: u16,
+        socks4a_port: Option<u16>,
+        http_port: Option<u16>,
         authenticator: Authenticator,
-        service_provider: Recipient,
-        self_address: Recipient,
+        service_providers: Vec<Recipient>,
+        provider_selection: ProviderSelection,
+        self_address: SelfAddressReceiver,
     ) -> Self {
-        // hardcode ip as we (presumably) ONLY want to listen locally. If we change it, we can
-        // just modify the config
-        let ip = "127.0.0.1";
-        info!("Listening on {}:{}", ip, port);
+        let listening_address = SocketAddr::new(ip, port);
+        info!("Listening on {}", listening_address);
+        let socks4a_listening_address = socks4a_port.map(|port| SocketAddr::new(ip, port));
+        if let Some(address) = socks4a_listening_address {
+            info!("Listening for SOCKS4a requests on {}", address);
+        }
+        let http_listening_address = http_port.map(|port| SocketAddr::new(ip, port));
+        if let Some(address) = http_listening_address {
+            info!("Listening for HTTP CONNECT requests on {}", address);
+        }
+        let provider_pool = ProviderPool::new(service_providers, provider_selection);
         SphinxSocksServer {
             authenticator,
-            listening_address: format!("{}:{}", ip, port).parse().unwrap(),
-            service_provider,
+            listening_address,
+            socks4a_listening_address,
+            http_listening_address,
+            provider_pool,
             self_address,
         }
     }
 
+    async fn log_delivery_receipts(mut receipt_receiver: DeliveryReceiptReceiver) {
+        while let Some(receipt) = receipt_receiver.next().await {
+            match receipt.status {
+                DeliveryStatus::Delivered => {
+                    trace!("Message {} got delivered", receipt.message_id)
+                }
+                DeliveryStatus::GaveUp { .. }
+                | DeliveryStatus::DeadlineExceeded { .. }
+                | DeliveryStatus::NotSent => warn!(
+                    "Failed to send data to the service provider: {}. The proxied connection it was part of is likely to be broken",
+                    receipt
+                ),
+            }
+        }
+    }
+
     /// Set up the listener and initiate connection handling when something
     /// connects to the server.
     pub(crate) async fn serve(
@@ -49,6 +105,14 @@ impl SphinxSocksServer {
         buffer_requester: ReceivedBufferRequestSender,
     ) -> Result<(), SocksProxyError> {
         let listener = TcpListener::bind(self.listening_address).await.unwrap();
+        let socks4a_listener = match self.socks4a_listening_address {
+            Some(address) => Some(TcpListener::bind(address).await.unwrap()),
+            None => None,
+        };
+        let http_listener = match self.http_listening_address {
+            Some(address) => Some(TcpListener::bind(address).await.unwrap()),
+            None => None,
+        };
         info!("Serving Connections...");
 
         // controller for managing all active connections
@@ -57,26 +121,91 @@ impl SphinxSocksServer {
             active_streams_controller.run().await;
         });
 
+        // routes for the datagrams of all active UDP associations
+        let datagram_routes = DatagramRoutes::default();
+
+        // connections waiting for the service provider to establish them
+        let pending_connections = PendingConnections::default();
+
         // listener for mix messages
-        let mut mixnet_response_listener =
-            MixnetResponseListener::new(buffer_requester, controller_sender.clone());
+        let mut mixnet_response_listener = MixnetResponseListener::new(
+            buffer_requester,
+            controller_sender.clone(),
+            datagram_routes.clone(),
+            pending_connections.clone(),
+        );
 
         tokio::spawn(async move {
             mixnet_response_listener.run().await;
         });
 
+        // with more than one provider, keep track of which of them are still alive
+        if self.provider_pool.len() > 1 {
+            let provider_prober = ProviderProber::new(
+                self.provider_pool.clone(),
+                pending_connections.clone(),
+                input_sender.clone(),
+                self.self_address.clone(),
+            );
+            tokio::spawn(async move {
+                provider_prober.run().await;
+            });
+        }
+
+        // all messages sent to the service provider are tracked so that we'd learn
+        // if any of them could not have been delivered
+        let (receipt_sender, receipt_receiver) = mpsc::unbounded();
+        tokio::spawn(async move {
+            Self::log_delivery_receipts(receipt_receiver).await;
+        });
+
+        let context = ConnectionContext {
+            authenticator: self.authenticator.clone(),
+            input_sender,
+            receipt_sender,
+            provider_pool: self.provider_pool.clone(),
+            controller_sender,
+            datagram_routes,
+            pending_connections,
+            self_address: self.self_address.clone(),
+        };
+
+        if let Some(socks4a_listener) = socks4a_listener {
+            let context = context.clone();
+            tokio::spawn(async move {
+                Self::accept_connections(socks4a_listener, ProxyProtocol::Socks4, context).await
+            });
+        }
+        if let Some(http_listener) = http_listener {
+            let context = context.clone();
+            tokio::spawn(async move {
+                Self::accept_connections(http_listener, ProxyProtocol::HttpConnect, context).await
+            });
+        }
+        Self::accept_connections(listener, ProxyProtocol::Socks5, context).await;
+        Ok(())
+    }
+
+    async fn accept_connections(
+        listener: TcpListener,
+        protocol: ProxyProtocol,
+        context: ConnectionContext,
+    ) {
         loop {
             if let Ok((stream, _remote)) = listener.accept().await {
                 // TODO Optimize this
                 let mut client = SocksClient::new(
                     stream,
-                    self.authenticator.clone(),
-                    input_sender.clone(),
-                    self.service_provider,
-                    controller_sender.clone(),
-                    self.self_address,
+                    protocol,
+                    context.authenticator.clone(),
+                    context.input_sender.clone(),
+                    context.receipt_sender.clone(),
+                    context.provider_pool.clone(),
+                    context.controller_sender.clone(),
+                    context.datagram_routes.clone(),
+                    context.pending_connections.clone(),
+                    *context.self_address.borrow(),
                 );
-
                 tokio::spawn(async move {
                     {
                         match client.run().await {
@@ -0,0 +1,120 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use super::types::{ResponseCode, SocksProxyError};
+use log::*;
+use socks5_requests::RemoteAddress;
+use std::net::Ipv4Addr;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Version of socks4(a)
+pub(crate) const SOCKS4_VERSION: u8 = 0x04;
+
+const SOCKS4_CONNECT: u8 = 0x01;
+
+/// Maximum length of the user id and the domain name we're willing to read.
+const MAX_FIELD_LENGTH: usize = 255;
+
+const REQUEST_GRANTED: u8 = 0x5A;
+const REQUEST_REJECTED: u8 = 0x5B;
+
+/// A Socks4a CONNECT request hitting the proxy. Plain Socks4 requests, which always carry
+/// an IPv4 address, are just a special case of it.
+pub(crate) struct Socks4Request {
+    pub remote_address: RemoteAddress,
+}
+
+// reads a NUL terminated string
+async fn read_field<R>(stream: &mut R) -> Result<Vec<u8>, SocksProxyError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut field = Vec::new();
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == 0 {
+            return Ok(field);
+        }
+        if field.len() == MAX_FIELD_LENGTH {
+            warn!("Socks4 request field is too long");
+            return Err(ResponseCode::Failure.into());
+        }
+        field.push(byte);
+    }
+}
+
+impl Socks4Request {
+    /// Parse a Socks4a request from a TcpStream. The version byte is expected to have already
+    /// been read off the stream. The bytes being extracted look like this:
+    ///
+    /// +----+----+----+----+----+----+----+----+----+----+....+----+
+    /// | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+    /// +----+----+----+----+----+----+----+----+----+----+....+----+
+    ///    1    1      2              4           variable       1
+    ///
+    /// If DSTIP is `0.0.0.x` (with non-zero x), it's followed by a NUL terminated domain name.
+    pub async fn from_stream<R>(stream: &mut R) -> Result<Self, SocksProxyError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut packet = [0u8; 7];
+        stream.read_exact(&mut packet).await?;
+
+        if packet[0] != SOCKS4_CONNECT {
+            warn!("Socks4 command {} is not supported", packet[0]);
+            return Err(ResponseCode::CommandNotSupported.into());
+        }
+
+        let port = u16::from_be_bytes([packet[1], packet[2]]);
+        let ip = Ipv4Addr::new(packet[3], packet[4], packet[5], packet[6]);
+
+        // the user id is not used for anything
+        read_field(stream).await?;
+
+        let host = if ip.octets()[..3] == [0, 0, 0] && ip.octets()[3] != 0 {
+            String::from_utf8_lossy(&read_field(stream).await?).to_string()
+        } else {
+            ip.to_string()
+        };
+
+        Ok(Socks4Request {
+            remote_address: format!("{}:{}", host, port),
+        })
+    }
+}
+
+/// Reply to a Socks4(a) request. The destination port and address are ignored by the clients.
+pub(crate) fn reply(granted: bool) -> [u8; 8] {
+    let status = if granted {
+        REQUEST_GRANTED
+    } else {
+        REQUEST_REJECTED
+    };
+    [0, status, 0, 0, 0, 0, 0, 0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parses_socks4_request() {
+        let request = [SOCKS4_CONNECT, 0, 80, 1, 2, 3, 4, b'f', b'o', b'o', 0];
+        let parsed = Socks4Request::from_stream(&mut &request[..]).await.unwrap();
+        assert_eq!("1.2.3.4:80", parsed.remote_address);
+    }
+
+    #[tokio::test]
+    async fn parses_socks4a_request() {
+        let mut request = vec![SOCKS4_CONNECT, 1, 187, 0, 0, 0, 1, 0];
+        request.extend_from_slice(b"nymtech.net\0");
+        let parsed = Socks4Request::from_stream(&mut &request[..]).await.unwrap();
+        assert_eq!("nymtech.net:443", parsed.remote_address);
+    }
+
+    #[tokio::test]
+    async fn rejects_bind_requests() {
+        let request = [2, 0, 80, 1, 2, 3, 4, 0];
+        assert!(Socks4Request::from_stream(&mut &request[..]).await.is_err());
+    }
+}
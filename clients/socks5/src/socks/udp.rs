@@ -0,0 +1,271 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use super::types::AddrType;
+use super::RESERVED;
+use client_core::client::delivery_receipts::DeliveryReceiptSender;
+use client_core::client::inbound_messages::{InputMessage, InputMessageSender};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use log::*;
+use nymsphinx::addressing::clients::Recipient;
+use socks5_requests::{ConnectionId, DatagramResponse, Message, RemoteAddress, Request};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::UdpSocket;
+
+/// Maximum size of a single UDP datagram, including the SOCKS5 UDP header.
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+pub(crate) type DatagramSender = mpsc::UnboundedSender<DatagramResponse>;
+pub(crate) type DatagramReceiver = mpsc::UnboundedReceiver<DatagramResponse>;
+
+/// Routes the datagrams received from the mixnet to the UDP associations they belong to.
+#[derive(Clone, Default)]
+pub(crate) struct DatagramRoutes {
+    inner: Arc<Mutex<HashMap<ConnectionId, DatagramSender>>>,
+}
+
+impl DatagramRoutes {
+    pub(crate) fn insert(&self, connection_id: ConnectionId, sender: DatagramSender) {
+        self.inner.lock().unwrap().insert(connection_id, sender);
+    }
+
+    pub(crate) fn remove(&self, connection_id: ConnectionId) {
+        self.inner.lock().unwrap().remove(&connection_id);
+    }
+
+    pub(crate) fn route(&self, datagram: DatagramResponse) {
+        match self.inner.lock().unwrap().get(&datagram.connection_id) {
+            Some(sender) => {
+                // the association might have just been finished
+                let _ = sender.unbounded_send(datagram);
+            }
+            None => debug!(
+                "Received a datagram for unknown UDP association {}",
+                datagram.connection_id
+            ),
+        }
+    }
+}
+
+/// Recovers the destination address and the data out of a datagram sent by the application.
+/// The datagram is expected to be prefixed with the SOCKS5 UDP request header:
+///
+/// +----+------+------+----------+----------+----------+
+/// |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+/// +----+------+------+----------+----------+----------+
+/// | 2  |  1   |  1   | Variable |    2     | Variable |
+/// +----+------+------+----------+----------+----------+
+///
+/// Fragmentation is not supported, so any fragments are rejected.
+pub(crate) fn parse_udp_request(packet: &[u8]) -> Option<(RemoteAddress, &[u8])> {
+    if packet.len() < 4 || packet[2] != 0 {
+        return None;
+    }
+
+    let (host, rest) = match AddrType::from(packet[3] as usize)? {
+        AddrType::V4 => {
+            let octets: [u8; 4] = packet.get(4..8)?.try_into().ok()?;
+            (IpAddr::from(Ipv4Addr::from(octets)), &packet[8..])
+        }
+        AddrType::V6 => {
+            let octets: [u8; 16] = packet.get(4..20)?.try_into().ok()?;
+            (IpAddr::from(Ipv6Addr::from(octets)), &packet[20..])
+        }
+        AddrType::Domain => {
+            let domain_len = *packet.get(4)? as usize;
+            let domain = packet.get(5..5 + domain_len)?;
+            let rest = &packet[5 + domain_len..];
+            if rest.len() < 2 {
+                return None;
+            }
+            let port = u16::from_be_bytes([rest[0], rest[1]]);
+            let address = format!("{}:{}", String::from_utf8_lossy(domain), port);
+            return Some((address, &rest[2..]));
+        }
+    };
+
+    if rest.len() < 2 {
+        return None;
+    }
+    let port = u16::from_be_bytes([rest[0], rest[1]]);
+    Some((SocketAddr::new(host, port).to_string(), &rest[2..]))
+}
+
+/// Encodes the SOCKS5 address of the specified `host:port`, i.e. `ATYP || ADDR || PORT`.
+pub(crate) fn encode_socks_address(address: &str) -> Vec<u8> {
+    match address.parse::<SocketAddr>() {
+        Ok(SocketAddr::V4(address)) => std::iter::once(AddrType::V4 as u8)
+            .chain(address.ip().octets().iter().cloned())
+            .chain(address.port().to_be_bytes().iter().cloned())
+            .collect(),
+        Ok(SocketAddr::V6(address)) => std::iter::once(AddrType::V6 as u8)
+            .chain(address.ip().octets().iter().cloned())
+            .chain(address.port().to_be_bytes().iter().cloned())
+            .collect(),
+        Err(_) => {
+            let (domain, port) = address.rsplit_once(':').unwrap_or((address, "0"));
+            let port = port.parse::<u16>().unwrap_or_default();
+            let domain = &domain.as_bytes()[..domain.len().min(u8::MAX as usize)];
+            [AddrType::Domain as u8, domain.len() as u8]
+                .iter()
+                .chain(domain.iter())
+                .chain(port.to_be_bytes().iter())
+                .cloned()
+                .collect()
+        }
+    }
+}
+
+/// Prefixes a datagram received through the mixnet with the SOCKS5 UDP header
+/// so that it could be passed to the application.
+pub(crate) fn make_udp_response(datagram: DatagramResponse) -> Vec<u8> {
+    [RESERVED, RESERVED, 0]
+        .iter()
+        .cloned()
+        .chain(encode_socks_address(&datagram.source_address).into_iter())
+        .chain(datagram.data.into_iter())
+        .collect()
+}
+
+/// Relays the datagrams of a single UDP association between the local application and
+/// the service provider. The association lives for as long as the TCP connection
+/// it was requested on.
+pub(crate) struct UdpRelay {
+    socket: UdpSocket,
+    connection_id: ConnectionId,
+    client_ip: IpAddr,
+    service_provider: Recipient,
+    self_address: Recipient,
+    input_sender: InputMessageSender,
+    receipt_sender: DeliveryReceiptSender,
+}
+
+impl UdpRelay {
+    pub(crate) fn new(
+        socket: UdpSocket,
+        connection_id: ConnectionId,
+        client_ip: IpAddr,
+        service_provider: Recipient,
+        self_address: Recipient,
+        input_sender: InputMessageSender,
+        receipt_sender: DeliveryReceiptSender,
+    ) -> Self {
+        UdpRelay {
+            socket,
+            connection_id,
+            client_ip,
+            service_provider,
+            self_address,
+            input_sender,
+            receipt_sender,
+        }
+    }
+
+    fn send_to_mixnet(&self, remote_address: RemoteAddress, data: Vec<u8>) {
+        let request =
+            Request::new_udp_send(self.connection_id, remote_address, data, self.self_address);
+        let input_message = InputMessage::new_fresh(
+            self.service_provider,
+            Message::Request(request).into_bytes(),
+            0,
+        )
+        .with_receipt_sender(self.receipt_sender.clone());
+        self.input_sender.unbounded_send(input_message).unwrap();
+    }
+
+    pub(crate) async fn run<S>(
+        self,
+        control_stream: &mut S,
+        mut datagram_receiver: DatagramReceiver,
+    ) where
+        S: AsyncRead + Unpin,
+    {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut control_buf = [0u8; 64];
+        // the application might only tell us where it's going to send the datagrams from
+        // once it sends the first one
+        let mut client_address: Option<SocketAddr> = None;
+
+        loop {
+            tokio::select! {
+                // the application is not supposed to send anything more on the TCP connection,
+                // so we only care about it getting closed
+                read = control_stream.read(&mut control_buf) => match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => trace!("Ignoring data received on the association control stream"),
+                },
+                received = self.socket.recv_from(&mut buf) => match received {
+                    Ok((len, source)) => {
+                        if source.ip() != self.client_ip {
+                            debug!("Dropping a datagram from unexpected source {}", source);
+                            continue;
+                        }
+                        client_address = Some(source);
+                        match parse_udp_request(&buf[..len]) {
+                            Some((remote_address, data)) => {
+                                self.send_to_mixnet(remote_address, data.to_vec())
+                            }
+                            None => debug!("Dropping malformed or fragmented datagram"),
+                        }
+                    }
+                    Err(err) => debug!("Failed to receive a datagram - {}", err),
+                },
+                datagram = datagram_receiver.next() => match datagram {
+                    Some(datagram) => match client_address {
+                        Some(client_address) => {
+                            let packet = make_udp_response(datagram);
+                            if let Err(err) = self.socket.send_to(&packet, client_address).await {
+                                warn!("Failed to pass the datagram to the application - {}", err)
+                            }
+                        }
+                        None => debug!("Dropping a datagram as the application is not known yet"),
+                    },
+                    None => break,
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_udp_request_with_ipv4_address() {
+        let packet = [0, 0, 0, 1, 8, 8, 8, 8, 0, 53, 1, 2, 3];
+        let (address, data) = parse_udp_request(&packet).unwrap();
+        assert_eq!("8.8.8.8:53", address);
+        assert_eq!(&[1, 2, 3], data);
+    }
+
+    #[test]
+    fn parses_udp_request_with_domain_address() {
+        let packet = [0, 0, 0, 3, 7, 102, 111, 111, 46, 99, 111, 109, 1, 187, 42];
+        let (address, data) = parse_udp_request(&packet).unwrap();
+        assert_eq!("foo.com:443", address);
+        assert_eq!(&[42], data);
+    }
+
+    #[test]
+    fn rejects_fragmented_and_truncated_udp_requests() {
+        assert!(parse_udp_request(&[0, 0, 1, 1, 8, 8, 8, 8, 0, 53]).is_none());
+        assert!(parse_udp_request(&[0, 0, 0, 1, 8, 8, 8, 8, 0]).is_none());
+        assert!(parse_udp_request(&[0, 0, 0, 3, 7, 102, 111]).is_none());
+    }
+
+    #[test]
+    fn udp_response_header_can_be_parsed_back() {
+        for source in ["8.8.8.8:53", "[2001:db8::1]:443", "foo.com:443"] {
+            let datagram = DatagramResponse::new(42, source.to_string(), vec![1, 2, 3]);
+            let packet = make_udp_response(datagram);
+            let (address, data) = parse_udp_request(&packet).unwrap();
+            assert_eq!(source, address);
+            assert_eq!(&[1, 2, 3], data);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use fluvio_wasm_timer::Instant;
+use nymsphinx::chunking::fragment::FragmentIdentifier;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// Identifier assigned to every sent message, used for correlating it with its delivery notification.
+pub(crate) type MessageId = u64;
+
+/// How long, on top of the expected round trip time of its slowest packet, we keep waiting for
+/// the acknowledgements of a message before assuming some of its packets got lost.
+pub(crate) const ACK_WAIT_MARGIN: Duration = Duration::from_secs(30);
+
+struct PendingMessage {
+    fragments: HashSet<FragmentIdentifier>,
+    deadline: Instant,
+}
+
+/// Keeps track of fragments of sent messages that are still waiting for their acknowledgements.
+/// Since we don't do retransmissions, messages that are not fully acknowledged by their deadline
+/// are given up on.
+#[derive(Default)]
+pub(crate) struct DeliveryTracker {
+    pending_messages: HashMap<MessageId, PendingMessage>,
+    fragment_messages: HashMap<FragmentIdentifier, MessageId>,
+}
+
+impl DeliveryTracker {
+    pub(crate) fn insert_message(
+        &mut self,
+        message_id: MessageId,
+        fragments: impl IntoIterator<Item = FragmentIdentifier>,
+        deadline: Instant,
+    ) {
+        let fragments: HashSet<_> = fragments.into_iter().collect();
+        for frag_id in &fragments {
+            self.fragment_messages.insert(*frag_id, message_id);
+        }
+        self.pending_messages.insert(
+            message_id,
+            PendingMessage {
+                fragments,
+                deadline,
+            },
+        );
+    }
+
+    /// Marks the fragment as acknowledged. If it was the last unacknowledged fragment
+    /// of its message, id of that message is returned.
+    pub(crate) fn acknowledge_fragment(
+        &mut self,
+        frag_id: FragmentIdentifier,
+    ) -> Option<MessageId> {
+        let message_id = self.fragment_messages.remove(&frag_id)?;
+        let remaining = &mut self.pending_messages.get_mut(&message_id)?.fragments;
+        remaining.remove(&frag_id);
+        if remaining.is_empty() {
+            self.pending_messages.remove(&message_id);
+            Some(message_id)
+        } else {
+            None
+        }
+    }
+
+    /// Forgets about all messages whose deadline has passed, returning their ids.
+    /// Any acknowledgements of their fragments received afterwards are ignored.
+    pub(crate) fn remove_expired(&mut self, now: Instant) -> Vec<MessageId> {
+        let expired: Vec<_> = self
+            .pending_messages
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(message_id, _)| *message_id)
+            .collect();
+
+        for message_id in &expired {
+            if let Some(pending) = self.pending_messages.remove(message_id) {
+                for frag_id in pending.fragments {
+                    self.fragment_messages.remove(&frag_id);
+                }
+            }
+        }
+        expired
+    }
+}
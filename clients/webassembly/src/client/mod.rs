@@ -2,14 +2,17 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crypto::asymmetric::{encryption, identity};
+use delivery_tracker::{DeliveryTracker, MessageId, ACK_WAIT_MARGIN};
+use fluvio_wasm_timer::Instant;
 use futures::channel::mpsc;
 use gateway_client::GatewayClient;
 use nymsphinx::acknowledgements::AckKey;
 use nymsphinx::addressing::clients::Recipient;
 use nymsphinx::preparer::MessagePreparer;
 use rand::rngs::OsRng;
+use rand::RngCore;
 use received_processor::ReceivedMessagesProcessor;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use topology::{gateway, nym_topology_from_bonds, NymTopology};
 use url::Url;
@@ -17,6 +20,7 @@ use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::spawn_local;
 use wasm_utils::{console_log, console_warn};
 
+pub(crate) mod delivery_tracker;
 pub(crate) mod received_processor;
 
 const DEFAULT_AVERAGE_PACKET_DELAY: Duration = Duration::from_millis(200);
@@ -43,8 +47,13 @@ pub struct NymClient {
     topology: Option<NymTopology>,
     gateway_client: Option<GatewayClient>,
 
+    delivery_tracker: Arc<Mutex<DeliveryTracker>>,
+    last_message_id: Option<MessageId>,
+
     // callbacks
     on_message: Option<js_sys::Function>,
+    on_delivered: Option<js_sys::Function>,
+    on_delivery_failed: Option<js_sys::Function>,
     on_gateway_connect: Option<js_sys::Function>,
 }
 
@@ -70,7 +79,12 @@ impl NymClient {
             topology: None,
             gateway_client: None,
 
+            delivery_tracker: Default::default(),
+            last_message_id: None,
+
             on_message: None,
+            on_delivered: None,
+            on_delivery_failed: None,
             on_gateway_connect: None,
             disabled_credentials_mode: true,
         }
@@ -80,6 +94,19 @@ impl NymClient {
         self.on_message = Some(on_message);
     }
 
+    /// Sets the callback invoked with the id of a sent message (as a string) once all of its
+    /// packets got acknowledged by the recipient.
+    pub fn set_on_delivered(&mut self, on_delivered: js_sys::Function) {
+        self.on_delivered = Some(on_delivered);
+    }
+
+    /// Sets the callback invoked with the id of a sent message (as a string) once we give up on
+    /// its delivery. Unlike the native client, we do not retransmit lost packets, so this happens
+    /// as soon as any of its packets hasn't been acknowledged in time.
+    pub fn set_on_delivery_failed(&mut self, on_delivery_failed: js_sys::Function) {
+        self.on_delivery_failed = Some(on_delivery_failed);
+    }
+
     pub fn set_on_gateway_connect(&mut self, on_connect: js_sys::Function) {
         console_log!("setting on connect...");
         self.on_gateway_connect = Some(on_connect)
@@ -108,6 +135,12 @@ impl NymClient {
         self.self_recipient().to_string()
     }
 
+    /// Returns the id (as a string) assigned to the most recently sent message.
+    pub fn last_message_id(&self) -> Option<String> {
+        self.last_message_id
+            .map(|message_id| message_id.to_string())
+    }
+
     // Right now it's impossible to have async exported functions to take `&self` rather than self
     pub async fn initial_setup(self) -> Self {
         let disabled_credentials_mode = self.disabled_credentials_mode;
@@ -162,6 +195,7 @@ impl NymClient {
         let received_processor = ReceivedMessagesProcessor::new(
             Arc::clone(&client.encryption_keys),
             Arc::clone(&client.ack_key),
+            Arc::clone(&client.delivery_tracker),
         );
 
         client.message_preparer = Some(message_preparer);
@@ -170,6 +204,8 @@ impl NymClient {
             mixnet_messages_receiver,
             ack_receiver,
             client.on_message.take().expect("on_message was not set!"),
+            client.on_delivered.take(),
+            client.on_delivery_failed.take(),
         ));
 
         client
@@ -191,20 +227,36 @@ impl NymClient {
         let message_preparer = self.message_preparer.as_mut().unwrap();
 
         let (split_message, _reply_keys) = message_preparer
-            .prepare_and_split_message(message_bytes, false, topology)
+            .prepare_and_split_message(message_bytes, 0, topology)
             .expect("failed to split the message");
 
+        let fragments: Vec<_> = split_message
+            .iter()
+            .map(|message_chunk| message_chunk.fragment_identifier())
+            .collect();
+
         let mut mix_packets = Vec::with_capacity(split_message.len());
+        let mut longest_round_trip = Duration::ZERO;
         for message_chunk in split_message {
-            // don't bother with acks etc. for time being
+            // don't bother with retransmissions etc. for time being
             let prepared_fragment = message_preparer
                 .prepare_chunk_for_sending(message_chunk, topology, &self.ack_key, &recipient)
                 .await
                 .unwrap();
 
-            console_warn!("packet is going to have round trip time of {:?}, but we're not going to do anything for acks anyway ", prepared_fragment.total_delay);
+            console_warn!("packet is going to have round trip time of {:?}, but we're not going to retransmit it if it gets lost", prepared_fragment.total_delay);
+            longest_round_trip = longest_round_trip.max(prepared_fragment.total_delay);
             mix_packets.push(prepared_fragment.mix_packet);
         }
+
+        // if any of the packets is not acknowledged by then, it has most likely got lost
+        let message_id = OsRng.next_u64();
+        self.delivery_tracker.lock().unwrap().insert_message(
+            message_id,
+            fragments,
+            Instant::now() + longest_round_trip + ACK_WAIT_MARGIN,
+        );
+        self.last_message_id = Some(message_id);
         self.gateway_client
             .as_mut()
             .unwrap()
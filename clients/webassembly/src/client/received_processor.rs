@@ -1,7 +1,9 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
+use super::delivery_tracker::{DeliveryTracker, MessageId};
 use crypto::asymmetric::encryption;
+use fluvio_wasm_timer::{Instant, Interval};
 use futures::StreamExt;
 use gateway_client::{AcknowledgementReceiver, MixnetMessageReceiver};
 use nymsphinx::acknowledgements::identifier::recover_identifier;
@@ -10,23 +12,29 @@ use nymsphinx::chunking::fragment::{FragmentIdentifier, COVER_FRAG_ID};
 use nymsphinx::receiver::{MessageReceiver, MessageRecoveryError, ReconstructedMessage};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use wasm_bindgen::JsValue;
 use wasm_utils::{console_error, console_log, console_warn};
 
+/// How often we check for sent messages that haven't been acknowledged in time.
+const EXPIRED_MESSAGES_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
 #[derive(Serialize, Deserialize)]
 pub struct ProcessedMessage {
     pub message: String,
-    pub reply_surb: Option<String>,
+    pub reply_surbs: Vec<String>,
 }
 
 impl From<ReconstructedMessage> for ProcessedMessage {
     fn from(reconstructed: ReconstructedMessage) -> Self {
         ProcessedMessage {
             message: String::from_utf8_lossy(&reconstructed.message).into_owned(),
-            reply_surb: reconstructed
-                .reply_surb
-                .map(|reply_surb| reply_surb.to_base58_string()),
+            reply_surbs: reconstructed
+                .reply_surbs
+                .iter()
+                .map(|reply_surb| reply_surb.to_base58_string())
+                .collect(),
         }
     }
 }
@@ -34,6 +42,7 @@ impl From<ReconstructedMessage> for ProcessedMessage {
 pub(crate) struct ReceivedMessagesProcessor {
     local_encryption_keypair: Arc<encryption::KeyPair>,
     ack_key: Arc<AckKey>,
+    delivery_tracker: Arc<Mutex<DeliveryTracker>>,
     message_receiver: MessageReceiver,
 
     recently_reconstructed: HashSet<i32>,
@@ -43,10 +52,12 @@ impl ReceivedMessagesProcessor {
     pub(crate) fn new(
         local_encryption_keypair: Arc<encryption::KeyPair>,
         ack_key: Arc<AckKey>,
+        delivery_tracker: Arc<Mutex<DeliveryTracker>>,
     ) -> Self {
         ReceivedMessagesProcessor {
             local_encryption_keypair,
             ack_key,
+            delivery_tracker,
             message_receiver: MessageReceiver::new(),
             recently_reconstructed: HashSet::new(),
         }
@@ -121,34 +132,35 @@ impl ReceivedMessagesProcessor {
     }
 
     // TODO: duplicate code from acknowledgement listener...
-    fn process_received_ack(&self, ack_content: Vec<u8>) {
+    // returns id of the message if all of its fragments are now acknowledged
+    fn process_received_ack(&self, ack_content: Vec<u8>) -> Option<MessageId> {
         let frag_id = match recover_identifier(&self.ack_key, &ack_content)
             .map(FragmentIdentifier::try_from_bytes)
         {
             Some(Ok(frag_id)) => frag_id,
             _ => {
                 console_warn!("Received invalid ACK!"); // should we do anything else about that?
-                return;
+                return None;
             }
         };
 
         // if we received an ack for cover message or a reply there will be nothing to remove,
         // because nothing was inserted in the first place
         if frag_id == COVER_FRAG_ID {
-            return;
+            return None;
         } else if frag_id.is_reply() {
             console_warn!("Received an ack for a reply message - no need to do anything! (don't know what to do!)");
             // TODO: probably there will need to be some extra procedure here, something to notify
             // user that his reply reached the recipient (since we got an ack)
-            return;
+            return None;
         }
 
-        console_log!(
-            "Received an ack for fragment {:?} but can't do anything more about it just yet...",
-            frag_id
-        );
+        console_log!("Received an ack for fragment {:?}", frag_id);
 
-        // here be ack handling...
+        self.delivery_tracker
+            .lock()
+            .unwrap()
+            .acknowledge_fragment(frag_id)
     }
 
     // TODO: this needs to have a shutdown signal!
@@ -157,9 +169,12 @@ impl ReceivedMessagesProcessor {
         mixnet_messages_receiver: MixnetMessageReceiver,
         ack_receiver: AcknowledgementReceiver,
         on_message: js_sys::Function,
+        on_delivered: Option<js_sys::Function>,
+        on_delivery_failed: Option<js_sys::Function>,
     ) {
         let mut fused_mixnet_messages_receiver = mixnet_messages_receiver.fuse();
         let mut fused_ack_receiver = ack_receiver.fuse();
+        let mut expired_messages_check = Interval::new(EXPIRED_MESSAGES_CHECK_INTERVAL).fuse();
         let this = JsValue::null();
 
         loop {
@@ -174,7 +189,27 @@ impl ReceivedMessagesProcessor {
                 }
                 acks = fused_ack_receiver.next() => {
                     for ack in acks.unwrap() {
-                        self.process_received_ack(ack);
+                        if let Some(message_id) = self.process_received_ack(ack) {
+                            match on_delivered.as_ref() {
+                                Some(callback) => {
+                                    let arg1 = JsValue::from(message_id.to_string());
+                                    callback.call1(&this, &arg1).expect("on delivered failed!");
+                                }
+                                None => console_log!("Message {} got delivered - no callback specified", message_id),
+                            }
+                        }
+                    }
+                }
+                _ = expired_messages_check.next() => {
+                    let expired = self.delivery_tracker.lock().unwrap().remove_expired(Instant::now());
+                    for message_id in expired {
+                        match on_delivery_failed.as_ref() {
+                            Some(callback) => {
+                                let arg1 = JsValue::from(message_id.to_string());
+                                callback.call1(&this, &arg1).expect("on delivery failed failed!");
+                            }
+                            None => console_warn!("Gave up on delivering message {} - no callback specified", message_id),
+                        }
                     }
                 }
             }
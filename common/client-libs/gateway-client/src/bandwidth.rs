@@ -15,6 +15,9 @@ use crate::wasm_storage::StorageError;
 #[cfg(all(not(target_arch = "wasm32"), feature = "coconut"))]
 use credential_storage::error::StorageError;
 
+#[cfg(feature = "coconut")]
+pub use coconut_interface::Credential;
+
 #[cfg(feature = "coconut")]
 use {
     coconut_interface::Base58,
@@ -46,6 +46,9 @@ const DEFAULT_RECONNECTION_BACKOFF: Duration = Duration::from_secs(5);
 pub struct GatewayClient {
     authenticated: bool,
     disabled_credentials_mode: bool,
+    /// Specifies whether the messages stored by the gateway while the client was offline
+    /// have to be explicitly fetched rather than being pushed upon authentication.
+    pull_mode: bool,
     bandwidth_remaining: i64,
     gateway_address: String,
     gateway_identity: identity::PublicKey,
@@ -84,6 +87,7 @@ impl GatewayClient {
         GatewayClient {
             authenticated: false,
             disabled_credentials_mode: true,
+            pull_mode: false,
             bandwidth_remaining: 0,
             gateway_address,
             gateway_identity,
@@ -104,6 +108,13 @@ impl GatewayClient {
         self.disabled_credentials_mode = disabled_credentials_mode;
     }
 
+    /// Makes the gateway hold on to the messages it stored while the client was offline until
+    /// they're explicitly fetched, rather than pushing them all upon authentication.
+    /// Note that it has no effect on the registration of a new client.
+    pub fn set_pull_mode(&mut self, pull_mode: bool) {
+        self.pull_mode = pull_mode;
+    }
+
     // TODO: later convert into proper builder methods
     pub fn with_reconnection_on_failure(&mut self, should_reconnect_on_failure: bool) {
         self.should_reconnect_on_failure = should_reconnect_on_failure
@@ -135,6 +146,7 @@ impl GatewayClient {
         GatewayClient {
             authenticated: false,
             disabled_credentials_mode: true,
+            pull_mode: false,
             bandwidth_remaining: 0,
             gateway_address,
             gateway_identity,
@@ -290,9 +302,15 @@ impl GatewayClient {
                         Ok(msg) => msg
                     };
                     match ws_msg {
-                        Message::Binary(bin_msg) => {
-                            self.packet_router.route_received(vec![bin_msg]);
-                        }
+                        Message::Binary(bin_msg) => match self.shared_key.as_ref() {
+                            Some(shared_key) => PartiallyDelegated::route_socket_message(
+                                Message::Binary(bin_msg),
+                                &self.packet_router,
+                                shared_key,
+                            ),
+                            // we can't decrypt anything before the registration is finished
+                            None => warn!("received a binary message before deriving the shared keys - it is going to be dropped"),
+                        },
                         Message::Text(txt_msg) => {
                             break ServerResponse::try_from(txt_msg).map_err(|_| GatewayClientError::MalformedResponse);
                         }
@@ -449,8 +467,13 @@ impl GatewayClient {
             .derive_destination_address();
         let encrypted_address = EncryptedAddressBytes::new(&self_address, shared_key, &iv);
 
-        let msg =
-            ClientControlRequest::new_authenticate(self_address, encrypted_address, iv).into();
+        let msg = ClientControlRequest::new_authenticate(
+            self_address,
+            encrypted_address,
+            iv,
+            self.pull_mode,
+        )
+        .into();
 
         match self.send_websocket_message(msg).await? {
             ServerResponse::Authenticate {
@@ -540,6 +563,28 @@ impl GatewayClient {
         Ok(())
     }
 
+    /// Gets the controller used for obtaining the bandwidth credentials, so that they could be
+    /// prepared without holding on to the client.
+    #[cfg(feature = "coconut")]
+    pub fn bandwidth_controller(&self) -> Option<&BandwidthController<PersistentStorage>> {
+        self.bandwidth_controller.as_ref()
+    }
+
+    /// Redeems an already prepared coconut credential for more bandwidth.
+    #[cfg(feature = "coconut")]
+    pub async fn redeem_coconut_credential(
+        &mut self,
+        credential: Credential,
+    ) -> Result<(), GatewayClientError> {
+        if !self.authenticated {
+            return Err(GatewayClientError::NotAuthenticated);
+        }
+        if self.shared_key.is_none() {
+            return Err(GatewayClientError::NoSharedKeyAvailable);
+        }
+        self.claim_coconut_bandwidth(credential).await
+    }
+
     pub async fn claim_bandwidth(&mut self) -> Result<(), GatewayClientError> {
         if !self.authenticated {
             return Err(GatewayClientError::NotAuthenticated);
@@ -580,7 +625,72 @@ impl GatewayClient {
         return self.claim_token_bandwidth(credential).await;
     }
 
-    fn estimate_required_bandwidth(&self, packets: &[MixPacket]) -> i64 {
+    /// Gets the number of messages the gateway has stored for the client while it was offline.
+    pub async fn count_stored_messages(&mut self) -> Result<u64, GatewayClientError> {
+        if !self.authenticated {
+            return Err(GatewayClientError::NotAuthenticated);
+        }
+
+        let msg = ClientControlRequest::InboxCount.into();
+        match self.send_websocket_message(msg).await? {
+            ServerResponse::InboxCount { count } => Ok(count),
+            ServerResponse::Error { message } => Err(GatewayClientError::GatewayError(message)),
+            _ => Err(GatewayClientError::UnexpectedResponse),
+        }
+    }
+
+    /// Makes the gateway push the next page of the messages it has stored for the client. The messages
+    /// are routed exactly like the ones received while being online.
+    ///
+    /// Returns the ids of the pushed messages alongside the id to start the next page after,
+    /// if there are any more messages stored.
+    pub async fn fetch_stored_messages(
+        &mut self,
+        start_after: Option<i64>,
+        page_size: u32,
+        remove: bool,
+    ) -> Result<(Vec<i64>, Option<i64>), GatewayClientError> {
+        if !self.authenticated {
+            return Err(GatewayClientError::NotAuthenticated);
+        }
+
+        let msg = ClientControlRequest::FetchInbox {
+            start_after,
+            page_size,
+            remove,
+        }
+        .into();
+        match self.send_websocket_message(msg).await? {
+            ServerResponse::InboxPage {
+                ids,
+                next_start_after,
+            } => Ok((ids, next_start_after)),
+            ServerResponse::Error { message } => Err(GatewayClientError::GatewayError(message)),
+            _ => Err(GatewayClientError::UnexpectedResponse),
+        }
+    }
+
+    /// Removes the specified messages stored by the gateway for the client.
+    /// Returns the number of messages actually removed.
+    pub async fn delete_stored_messages(
+        &mut self,
+        ids: Vec<i64>,
+    ) -> Result<u64, GatewayClientError> {
+        if !self.authenticated {
+            return Err(GatewayClientError::NotAuthenticated);
+        }
+
+        let msg = ClientControlRequest::DeleteInbox { ids }.into();
+        match self.send_websocket_message(msg).await? {
+            ServerResponse::InboxDeleted { removed } => Ok(removed),
+            ServerResponse::Error { message } => Err(GatewayClientError::GatewayError(message)),
+            _ => Err(GatewayClientError::UnexpectedResponse),
+        }
+    }
+
+    /// Estimates the amount of bandwidth, in bytes, that is going to be consumed by sending
+    /// the provided packets to the gateway.
+    pub fn estimate_required_bandwidth(&self, packets: &[MixPacket]) -> i64 {
         packets
             .iter()
             .map(|packet| packet.sphinx_packet().len())
@@ -594,9 +704,10 @@ impl GatewayClient {
         if !self.authenticated {
             return Err(GatewayClientError::NotAuthenticated);
         }
-        if self.estimate_required_bandwidth(&packets) > self.bandwidth_remaining {
+        let required_bandwidth = self.estimate_required_bandwidth(&packets);
+        if required_bandwidth > self.bandwidth_remaining {
             return Err(GatewayClientError::NotEnoughBandwidth(
-                self.estimate_required_bandwidth(&packets),
+                required_bandwidth,
                 self.bandwidth_remaining,
             ));
         }
@@ -615,17 +726,21 @@ impl GatewayClient {
             })
             .collect();
 
-        if let Err(err) = self
+        match self
             .batch_send_websocket_messages_without_response(messages)
             .await
         {
-            if err.is_closed_connection() && self.should_reconnect_on_failure {
+            Ok(_) => {
+                // keep track of the bandwidth the gateway is going to deduct for forwarding the packets
+                self.bandwidth_remaining -= required_bandwidth;
+                Ok(())
+            }
+            // the packets are lost, but the bandwidth balance is going to be refreshed
+            // upon authenticating with the gateway again
+            Err(err) if err.is_closed_connection() && self.should_reconnect_on_failure => {
                 self.attempt_reconnection().await
-            } else {
-                Err(err)
             }
-        } else {
-            Ok(())
+            Err(err) => Err(err),
         }
     }
 
@@ -664,9 +779,10 @@ impl GatewayClient {
         if !self.authenticated {
             return Err(GatewayClientError::NotAuthenticated);
         }
-        if (mix_packet.sphinx_packet().len() as i64) > self.bandwidth_remaining {
+        let required_bandwidth = mix_packet.sphinx_packet().len() as i64;
+        if required_bandwidth > self.bandwidth_remaining {
             return Err(GatewayClientError::NotEnoughBandwidth(
-                mix_packet.sphinx_packet().len() as i64,
+                required_bandwidth,
                 self.bandwidth_remaining,
             ));
         }
@@ -680,7 +796,20 @@ impl GatewayClient {
                 .as_ref()
                 .expect("no shared key present even though we're authenticated!"),
         );
-        self.send_with_reconnection_on_failure(msg).await
+        match self.send_websocket_message_without_response(msg).await {
+            Ok(_) => {
+                // keep track of the bandwidth the gateway is going to deduct for forwarding the packet
+                self.bandwidth_remaining -= required_bandwidth;
+                Ok(())
+            }
+            // the packet is lost, but the bandwidth balance is going to be refreshed
+            // upon authenticating with the gateway again
+            Err(err) if err.is_closed_connection() && self.should_reconnect_on_failure => {
+                info!("Going to attempt a reconnection");
+                self.attempt_reconnection().await
+            }
+            Err(err) => Err(err),
+        }
     }
 
     async fn recover_socket_connection(&mut self) -> Result<(), GatewayClientError> {
@@ -42,7 +42,7 @@ pub(crate) struct PartiallyDelegated {
 }
 
 impl PartiallyDelegated {
-    fn route_socket_message(
+    pub(crate) fn route_socket_message(
         ws_msg: Message,
         packet_router: &PacketRouter,
         shared_key: &SharedKeys,
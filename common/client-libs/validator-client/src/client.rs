@@ -11,11 +11,11 @@ use validator_api_requests::coconut::{
 };
 use validator_api_requests::models::{
     CoreNodeStatusResponse, MixnodeStatusResponse, RewardEstimationResponse,
-    StakeSaturationResponse,
+    StakeSaturationResponse, UptimeResponse,
 };
 
 #[cfg(feature = "nymd-client")]
-use validator_api_requests::models::{MixNodeBondAnnotated, UptimeResponse};
+use validator_api_requests::models::MixNodeBondAnnotated;
 
 #[cfg(feature = "nymd-client")]
 use crate::nymd::{
@@ -711,6 +711,12 @@ impl ApiClient {
             .await?)
     }
 
+    pub async fn get_mixnode_avg_uptimes(
+        &self,
+    ) -> Result<Vec<UptimeResponse>, ValidatorClientError> {
+        Ok(self.validator_api.get_mixnode_avg_uptimes().await?)
+    }
+
     pub async fn blind_sign(
         &self,
         request_body: &BlindSignRequestBody,
@@ -15,6 +15,7 @@ use nymsphinx_types::{
 use rand::{CryptoRng, RngCore};
 use std::convert::TryFrom;
 use std::time;
+use topology::route_selection::{RouteSelector, UniformRouteSelector};
 use topology::{NymTopology, NymTopologyError};
 
 pub struct SurbAck {
@@ -42,8 +43,36 @@ impl SurbAck {
     where
         R: RngCore + CryptoRng,
     {
-        let route =
-            topology.random_route_to_gateway(rng, DEFAULT_NUM_MIX_HOPS, recipient.gateway())?;
+        Self::construct_with_selector(
+            rng,
+            recipient,
+            ack_key,
+            marshaled_fragment_id,
+            average_delay,
+            topology,
+            &UniformRouteSelector,
+        )
+    }
+
+    /// Same as `construct`, but with the mix nodes on the route being chosen by the provided selector.
+    pub fn construct_with_selector<R>(
+        rng: &mut R,
+        recipient: &Recipient,
+        ack_key: &AckKey,
+        marshaled_fragment_id: [u8; 5],
+        average_delay: time::Duration,
+        topology: &NymTopology,
+        route_selector: &dyn RouteSelector,
+    ) -> Result<Self, NymTopologyError>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let route = topology.route_to_gateway_with_selector(
+            route_selector,
+            rng,
+            DEFAULT_NUM_MIX_HOPS,
+            recipient.gateway(),
+        )?;
         let delays = delays::generate_from_average_duration(route.len(), average_delay);
         let destination = recipient.as_sphinx_destination();
 
@@ -14,6 +14,7 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::convert::TryFrom;
 use std::fmt::{self, Formatter};
 use std::time;
+use topology::route_selection::{RouteSelector, UniformRouteSelector};
 use topology::{NymTopology, NymTopologyError};
 
 #[derive(Debug)]
@@ -60,6 +61,15 @@ pub struct ReplySurb {
     encryption_key: SurbEncryptionKey,
 }
 
+// the underlying sphinx SURB does not implement Clone, however its serialization is lossless,
+// so a copy can be obtained by going through the bytes representation
+impl Clone for ReplySurb {
+    fn clone(&self) -> Self {
+        ReplySurb::from_bytes(&self.to_bytes())
+            .expect("failed to recover reply SURB from its own serialization")
+    }
+}
+
 // Serialize + Deserialize is not really used anymore (it was for a CBOR experiment)
 // however, if we decided we needed it again, it's already here
 impl Serialize for ReplySurb {
@@ -116,8 +126,32 @@ impl ReplySurb {
     where
         R: RngCore + CryptoRng,
     {
-        let route =
-            topology.random_route_to_gateway(rng, DEFAULT_NUM_MIX_HOPS, recipient.gateway())?;
+        Self::construct_with_selector(
+            rng,
+            recipient,
+            average_delay,
+            topology,
+            &UniformRouteSelector,
+        )
+    }
+
+    /// Same as `construct`, but with the mix nodes on the route being chosen by the provided selector.
+    pub fn construct_with_selector<R>(
+        rng: &mut R,
+        recipient: &Recipient,
+        average_delay: time::Duration,
+        topology: &NymTopology,
+        route_selector: &dyn RouteSelector,
+    ) -> Result<Self, NymTopologyError>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let route = topology.route_to_gateway_with_selector(
+            route_selector,
+            rng,
+            DEFAULT_NUM_MIX_HOPS,
+            recipient.gateway(),
+        )?;
         let delays = delays::generate_from_average_duration(route.len(), average_delay);
         let destination = recipient.as_sphinx_destination();
 
@@ -192,6 +192,12 @@ impl MessageReconstructor {
             .unwrap_or_else(|| false)
     }
 
+    /// Checks whether any `Fragment`s of the set of given `id` have been received
+    /// and the set is still waiting to be reconstructed.
+    pub fn is_set_being_reconstructed(&self, id: i32) -> bool {
+        self.reconstructed_sets.contains_key(&id)
+    }
+
     /// Check if message that was split into possibly multiple sets was received in fully using
     /// `id` of any of its sets.
     fn is_message_fully_received(&self, id: i32) -> bool {
@@ -18,6 +18,7 @@ use nymsphinx_types::{delays, Error as SphinxError};
 use rand::{CryptoRng, RngCore};
 use std::convert::TryFrom;
 use std::time;
+use topology::route_selection::RouteSelector;
 use topology::{NymTopology, NymTopologyError};
 
 pub const LOOP_COVER_MESSAGE_PAYLOAD: &[u8] = b"The cake is a lie!";
@@ -55,17 +56,19 @@ pub fn generate_loop_cover_surb_ack<R>(
     ack_key: &AckKey,
     full_address: &Recipient,
     average_ack_delay: time::Duration,
+    route_selector: &dyn RouteSelector,
 ) -> Result<SurbAck, CoverMessageError>
 where
     R: RngCore + CryptoRng,
 {
-    Ok(SurbAck::construct(
+    Ok(SurbAck::construct_with_selector(
         rng,
         full_address,
         ack_key,
         COVER_FRAG_ID.to_bytes(),
         average_ack_delay,
         topology,
+        route_selector,
     )?)
 }
 
@@ -76,14 +79,21 @@ pub fn generate_loop_cover_packet<R>(
     full_address: &Recipient,
     average_ack_delay: time::Duration,
     average_packet_delay: time::Duration,
+    route_selector: &dyn RouteSelector,
 ) -> Result<MixPacket, CoverMessageError>
 where
     R: RngCore + CryptoRng,
 {
     // we don't care about total ack delay - we will not be retransmitting it anyway
-    let (_, ack_bytes) =
-        generate_loop_cover_surb_ack(rng, topology, ack_key, full_address, average_ack_delay)?
-            .prepare_for_sending();
+    let (_, ack_bytes) = generate_loop_cover_surb_ack(
+        rng,
+        topology,
+        ack_key,
+        full_address,
+        average_ack_delay,
+        route_selector,
+    )?
+    .prepare_for_sending();
 
     // cover message can't be distinguishable from a normal traffic so we have to go through
     // all the effort of key generation, encryption, etc. Note here we are generating shared key
@@ -122,8 +132,12 @@ where
         .chain(cover_content.into_iter())
         .collect();
 
-    let route =
-        topology.random_route_to_gateway(rng, DEFAULT_NUM_MIX_HOPS, full_address.gateway())?;
+    let route = topology.route_to_gateway_with_selector(
+        route_selector,
+        rng,
+        DEFAULT_NUM_MIX_HOPS,
+        full_address.gateway(),
+    )?;
     let delays = delays::generate_from_average_duration(route.len(), average_packet_delay);
     let destination = full_address.as_sphinx_destination();
 
@@ -0,0 +1,294 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use std::convert::TryInto;
+use std::fmt::{self, Display, Formatter};
+use std::mem::size_of;
+
+/// Bit of the reply-SURB count prefix indicating the message has headers attached.
+/// As a result, a single message can carry at most [`MAX_REPLY_SURBS`] reply-SURBs.
+pub const HEADERS_PRESENT_FLAG: u8 = 0x80;
+
+/// Maximum number of reply-SURBs that can be attached to a single message.
+pub const MAX_REPLY_SURBS: u8 = !HEADERS_PRESENT_FLAG;
+
+const CONTENT_TYPE_HEADER_TAG: u8 = 0x01;
+const CORRELATION_ID_HEADER_TAG: u8 = 0x02;
+const TIMESTAMP_HEADER_TAG: u8 = 0x03;
+const STREAM_HEADER_TAG: u8 = 0x04;
+
+const STREAM_END_FLAG: u8 = 0x01;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MessageHeadersError {
+    TooShortHeaders,
+    TooLongHeaderValue(u8, usize),
+    MalformedHeaderValue(u8),
+}
+
+impl Display for MessageHeadersError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageHeadersError::TooShortHeaders => {
+                write!(f, "not enough data provided to recover the message headers")
+            }
+            MessageHeadersError::TooLongHeaderValue(tag, len) => write!(
+                f,
+                "value of header {} is {} bytes long while at most {} bytes are allowed",
+                tag,
+                len,
+                u16::MAX
+            ),
+            MessageHeadersError::MalformedHeaderValue(tag) => {
+                write!(f, "value of header {} is malformed", tag)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MessageHeadersError {}
+
+/// Position of a message within a stream of data sent in multiple messages, allowing
+/// the recipient to put the stream back together in the right order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamFrame {
+    /// Identifier of the stream, chosen at random by the sender.
+    pub stream_id: u64,
+
+    /// Position of the message within the stream, starting from 0.
+    pub sequence: u64,
+
+    /// Indicates whether this is the last message of the stream.
+    pub end: bool,
+}
+
+impl StreamFrame {
+    // stream_id || sequence || flags
+    fn to_bytes(self) -> Vec<u8> {
+        let flags = if self.end { STREAM_END_FLAG } else { 0 };
+        self.stream_id
+            .to_be_bytes()
+            .iter()
+            .cloned()
+            .chain(self.sequence.to_be_bytes().iter().cloned())
+            .chain(std::iter::once(flags))
+            .collect()
+    }
+
+    // stream_id || sequence || flags
+    fn try_from_bytes(b: &[u8]) -> Option<Self> {
+        if b.len() != 2 * size_of::<u64>() + 1 {
+            return None;
+        }
+
+        Some(StreamFrame {
+            stream_id: u64::from_be_bytes(b[..8].try_into().unwrap()),
+            sequence: u64::from_be_bytes(b[8..16].try_into().unwrap()),
+            end: b[16] & STREAM_END_FLAG != 0,
+        })
+    }
+}
+
+/// Optional metadata attached to a message by its sender. The headers are put alongside
+/// the reply-SURBs, so they are encrypted for the recipient exactly like the message itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageHeaders {
+    /// Type of the content of the message, for example a MIME type.
+    pub content_type: Option<String>,
+
+    /// Identifier allowing the sender to match the responses with the requests.
+    pub correlation_id: Option<String>,
+
+    /// Time, in milliseconds since the unix epoch, at which the sender claims to have sent the message.
+    /// It's not verified in any way.
+    pub timestamp: Option<u64>,
+
+    /// Position of the message within a stream, if it's a part of one.
+    pub stream: Option<StreamFrame>,
+}
+
+impl MessageHeaders {
+    pub fn is_empty(&self) -> bool {
+        self.content_type.is_none()
+            && self.correlation_id.is_none()
+            && self.timestamp.is_none()
+            && self.stream.is_none()
+    }
+
+    fn encode_header(tag: u8, value: &[u8]) -> Result<Vec<u8>, MessageHeadersError> {
+        let value_len = value.len();
+        if value_len > u16::MAX as usize {
+            return Err(MessageHeadersError::TooLongHeaderValue(tag, value_len));
+        }
+
+        Ok(std::iter::once(tag)
+            .chain((value_len as u16).to_be_bytes().iter().cloned())
+            .chain(value.iter().cloned())
+            .collect())
+    }
+
+    /// Serializes the headers as a sequence of `tag || value_len || value` entries, prefixed
+    /// with the total length of all of them, i.e.
+    /// `headers_len || (tag || value_len || value)*`.
+    pub fn try_to_bytes(&self) -> Result<Vec<u8>, MessageHeadersError> {
+        let mut entries = Vec::new();
+        if let Some(content_type) = &self.content_type {
+            entries.extend(Self::encode_header(
+                CONTENT_TYPE_HEADER_TAG,
+                content_type.as_bytes(),
+            )?);
+        }
+        if let Some(correlation_id) = &self.correlation_id {
+            entries.extend(Self::encode_header(
+                CORRELATION_ID_HEADER_TAG,
+                correlation_id.as_bytes(),
+            )?);
+        }
+        if let Some(timestamp) = self.timestamp {
+            entries.extend(Self::encode_header(
+                TIMESTAMP_HEADER_TAG,
+                &timestamp.to_be_bytes(),
+            )?);
+        }
+        if let Some(stream) = self.stream {
+            entries.extend(Self::encode_header(STREAM_HEADER_TAG, &stream.to_bytes())?);
+        }
+
+        Ok((entries.len() as u32)
+            .to_be_bytes()
+            .iter()
+            .cloned()
+            .chain(entries.into_iter())
+            .collect())
+    }
+
+    /// Recovers the headers from the beginning of the provided bytes.
+    /// Returns the headers alongside the number of bytes they occupied.
+    ///
+    /// Headers with unknown tags are ignored so that new ones could be introduced
+    /// without breaking older recipients.
+    pub fn try_from_bytes(b: &[u8]) -> Result<(Self, usize), MessageHeadersError> {
+        if b.len() < size_of::<u32>() {
+            return Err(MessageHeadersError::TooShortHeaders);
+        }
+        let headers_len = u32::from_be_bytes(b[..size_of::<u32>()].try_into().unwrap()) as usize;
+        let total_len = size_of::<u32>() + headers_len;
+        if b.len() < total_len {
+            return Err(MessageHeadersError::TooShortHeaders);
+        }
+
+        let mut headers = MessageHeaders::default();
+        let mut entries = &b[size_of::<u32>()..total_len];
+        while !entries.is_empty() {
+            // 1 (tag) + sizeof<u16> (value length)
+            if entries.len() < 1 + size_of::<u16>() {
+                return Err(MessageHeadersError::TooShortHeaders);
+            }
+            let tag = entries[0];
+            let value_len = u16::from_be_bytes(entries[1..3].try_into().unwrap()) as usize;
+            if entries.len() < 3 + value_len {
+                return Err(MessageHeadersError::TooShortHeaders);
+            }
+            let value = &entries[3..3 + value_len];
+            entries = &entries[3 + value_len..];
+
+            match tag {
+                CONTENT_TYPE_HEADER_TAG => {
+                    headers.content_type = Some(
+                        String::from_utf8(value.to_vec())
+                            .map_err(|_| MessageHeadersError::MalformedHeaderValue(tag))?,
+                    )
+                }
+                CORRELATION_ID_HEADER_TAG => {
+                    headers.correlation_id = Some(
+                        String::from_utf8(value.to_vec())
+                            .map_err(|_| MessageHeadersError::MalformedHeaderValue(tag))?,
+                    )
+                }
+                TIMESTAMP_HEADER_TAG => {
+                    headers.timestamp =
+                        Some(u64::from_be_bytes(value.try_into().map_err(|_| {
+                            MessageHeadersError::MalformedHeaderValue(tag)
+                        })?))
+                }
+                STREAM_HEADER_TAG => {
+                    headers.stream = Some(
+                        StreamFrame::try_from_bytes(value)
+                            .ok_or(MessageHeadersError::MalformedHeaderValue(tag))?,
+                    )
+                }
+                _ => (),
+            }
+        }
+
+        Ok((headers, total_len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_serialization_works() {
+        let headers = MessageHeaders {
+            content_type: Some("application/json".to_string()),
+            correlation_id: Some("foomp".to_string()),
+            timestamp: Some(1234567890),
+            stream: Some(StreamFrame {
+                stream_id: 42,
+                sequence: 7,
+                end: true,
+            }),
+        };
+        let mut bytes = headers.try_to_bytes().unwrap();
+        let headers_len = bytes.len();
+        bytes.extend_from_slice(b"message");
+
+        let (recovered, len) = MessageHeaders::try_from_bytes(&bytes).unwrap();
+        assert_eq!(recovered, headers);
+        assert_eq!(len, headers_len);
+
+        let partial_headers = MessageHeaders {
+            correlation_id: Some("foomp".to_string()),
+            ..Default::default()
+        };
+        let bytes = partial_headers.try_to_bytes().unwrap();
+        let (recovered, _) = MessageHeaders::try_from_bytes(&bytes).unwrap();
+        assert_eq!(recovered, partial_headers);
+    }
+
+    #[test]
+    fn unknown_headers_are_ignored() {
+        let unknown_header: Vec<_> = std::iter::once(0xFF)
+            .chain(3u16.to_be_bytes().iter().cloned())
+            .chain(b"foo".iter().cloned())
+            .collect();
+        let mut entries =
+            MessageHeaders::encode_header(CONTENT_TYPE_HEADER_TAG, b"text/plain").unwrap();
+        entries.extend(unknown_header);
+        let bytes: Vec<_> = (entries.len() as u32)
+            .to_be_bytes()
+            .iter()
+            .cloned()
+            .chain(entries.into_iter())
+            .collect();
+
+        let (recovered, _) = MessageHeaders::try_from_bytes(&bytes).unwrap();
+        assert_eq!(recovered.content_type, Some("text/plain".to_string()));
+        assert!(recovered.correlation_id.is_none());
+    }
+
+    #[test]
+    fn truncated_headers_are_rejected() {
+        let headers = MessageHeaders {
+            content_type: Some("application/json".to_string()),
+            ..Default::default()
+        };
+        let bytes = headers.try_to_bytes().unwrap();
+        assert_eq!(
+            MessageHeaders::try_from_bytes(&bytes[..bytes.len() - 1]),
+            Err(MessageHeadersError::TooShortHeaders)
+        );
+    }
+}
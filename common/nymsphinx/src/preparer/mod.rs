@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::chunking;
+use crate::headers::{MessageHeaders, MessageHeadersError, HEADERS_PRESENT_FLAG, MAX_REPLY_SURBS};
 use crypto::asymmetric::encryption;
 use crypto::shared_key::new_ephemeral_shared_key;
 use crypto::symmetric::stream_cipher;
@@ -20,10 +21,12 @@ use nymsphinx_params::{
     ReplySurbKeyDigestAlgorithm, DEFAULT_NUM_MIX_HOPS,
 };
 use nymsphinx_types::builder::SphinxPacketBuilder;
-use nymsphinx_types::{delays, Delay};
+use nymsphinx_types::{delays, Delay, Node as SphinxNode};
 use rand::{CryptoRng, Rng};
 use std::convert::TryFrom;
+use std::sync::Arc;
 use std::time::Duration;
+use topology::route_selection::{AvoidingRouteSelector, RouteSelector, UniformRouteSelector};
 use topology::{NymTopology, NymTopologyError};
 
 /// Represents fully packed and prepared [`Fragment`] that can be sent through the mix network.
@@ -36,12 +39,23 @@ pub struct PreparedFragment {
     /// address of the node to which the message should be sent, the actual 'chunk' of the message
     /// going through the mix network and also the 'mode' of the packet, i.e. VPN or Mix.
     pub mix_packet: MixPacket,
+
+    /// Addresses of all the nodes, including the egress gateway, the packet is going to traverse.
+    pub route: Vec<NymNodeRoutingAddress>,
 }
 
 #[derive(Debug)]
 pub enum PreparationError {
     TopologyError(NymTopologyError),
     TooLongReplyMessageError,
+    TooManyReplySurbs(u8),
+    MalformedHeaders(MessageHeadersError),
+}
+
+impl From<MessageHeadersError> for PreparationError {
+    fn from(err: MessageHeadersError) -> Self {
+        PreparationError::MalformedHeaders(err)
+    }
 }
 
 impl From<NymTopologyError> for PreparationError {
@@ -75,6 +89,9 @@ pub struct MessagePreparer<R: CryptoRng + Rng> {
     /// Number of mix hops each packet ('real' message, ack, reply) is expected to take.
     /// Note that it does not include gateway hops.
     num_mix_hops: u8,
+
+    /// Chooses the mix nodes used for the routes of all packets, acknowledgements and reply-SURBs.
+    route_selector: Arc<dyn RouteSelector>,
 }
 
 impl<R> MessagePreparer<R>
@@ -94,6 +111,7 @@ where
             average_packet_delay,
             average_ack_delay,
             num_mix_hops: DEFAULT_NUM_MIX_HOPS,
+            route_selector: Arc::new(UniformRouteSelector),
         }
     }
 
@@ -109,6 +127,12 @@ where
         self
     }
 
+    /// Allows choosing the mix nodes by something other than uniformly at random.
+    pub fn with_route_selector(mut self, route_selector: Arc<dyn RouteSelector>) -> Self {
+        self.route_selector = route_selector;
+        self
+    }
+
     /// Overwrites existing sender address with the provided value.
     pub fn set_sender_address(&mut self, sender_address: Recipient) {
         self.sender_address = sender_address;
@@ -148,43 +172,65 @@ where
             .collect()
     }
 
-    /// Attaches reply-SURB to the message alongside the reply key.
+    /// Attaches the requested number of reply-SURBs to the message.
     /// Results in:
     /// new_message = 0 || message
     /// OR
-    /// new_message = 1 || REPLY_KEY || REPLY_SURB || message
-    fn optionally_attach_reply_surb(
+    /// new_message = n || REPLY_SURB_1 || ... || REPLY_SURB_n || message
+    ///
+    /// Note that attaching a single reply-SURB results in exactly the same format as it
+    /// always did, i.e. `1 || REPLY_SURB || message`.
+    ///
+    /// If any headers are provided, they are put right after the reply-SURBs and
+    /// the `HEADERS_PRESENT_FLAG` bit of `n` is set:
+    /// new_message = (n | HEADERS_PRESENT_FLAG) || REPLY_SURB_1 || ... || REPLY_SURB_n || HEADERS || message
+    fn attach_reply_surbs(
         &mut self,
         message: Vec<u8>,
-        should_attach: bool,
+        num_reply_surbs: u8,
+        headers: Option<&MessageHeaders>,
         topology: &NymTopology,
-    ) -> Result<(Vec<u8>, Option<SurbEncryptionKey>), PreparationError> {
-        if should_attach {
-            let reply_surb = ReplySurb::construct(
+    ) -> Result<(Vec<u8>, Vec<SurbEncryptionKey>), PreparationError> {
+        if num_reply_surbs > MAX_REPLY_SURBS {
+            return Err(PreparationError::TooManyReplySurbs(num_reply_surbs));
+        }
+
+        let headers_bytes = match headers {
+            Some(headers) if !headers.is_empty() => Some(headers.try_to_bytes()?),
+            _ => None,
+        };
+        let surbs_prefix = if headers_bytes.is_some() {
+            num_reply_surbs | HEADERS_PRESENT_FLAG
+        } else {
+            num_reply_surbs
+        };
+
+        let mut reply_keys = Vec::with_capacity(num_reply_surbs as usize);
+        let mut surbs_bytes = Vec::new();
+
+        for _ in 0..num_reply_surbs {
+            let reply_surb = ReplySurb::construct_with_selector(
                 &mut self.rng,
                 &self.sender_address,
                 self.average_packet_delay,
                 topology,
+                &*self.route_selector,
             )?;
 
-            let reply_key = reply_surb.encryption_key();
-            // if there's a reply surb, the message takes form of `1 || REPLY_KEY || REPLY_SURB || MSG`
-            Ok((
-                std::iter::once(true as u8)
-                    .chain(reply_surb.to_bytes().iter().cloned())
-                    .chain(message.into_iter())
-                    .collect(),
-                Some(reply_key.clone()),
-            ))
-        } else {
-            // but if there's no reply surb, the message takes form of `0 || MSG`
-            Ok((
-                std::iter::once(false as u8)
-                    .chain(message.into_iter())
-                    .collect(),
-                None,
-            ))
+            reply_keys.push(reply_surb.encryption_key().clone());
+            surbs_bytes.extend_from_slice(&reply_surb.to_bytes());
         }
+
+        // the message takes form of `n || REPLY_SURB_1 || ... || REPLY_SURB_n || MSG`
+        // (which for n = 0 is simply `0 || MSG`)
+        Ok((
+            std::iter::once(surbs_prefix)
+                .chain(surbs_bytes.into_iter())
+                .chain(headers_bytes.unwrap_or_default().into_iter())
+                .chain(message.into_iter())
+                .collect(),
+            reply_keys,
+        ))
     }
 
     /// Splits the message into [`Fragment`] that are going to be put later put into sphinx packets.
@@ -219,6 +265,41 @@ where
         topology: &NymTopology,
         ack_key: &AckKey,
         packet_recipient: &Recipient,
+    ) -> Result<PreparedFragment, NymTopologyError> {
+        self.prepare_chunk(fragment, topology, ack_key, packet_recipient, &[])
+            .await
+    }
+
+    /// Equivalent of [`prepare_chunk_for_sending`] meant for retransmissions of [`Fragment`]s
+    /// whose previous transmission has been lost. It attempts to route the packet through
+    /// a different set of nodes than the one used previously.
+    ///
+    /// [`prepare_chunk_for_sending`]: Self::prepare_chunk_for_sending
+    pub async fn prepare_chunk_for_resending(
+        &mut self,
+        fragment: Fragment,
+        topology: &NymTopology,
+        ack_key: &AckKey,
+        packet_recipient: &Recipient,
+        previous_route: &[NymNodeRoutingAddress],
+    ) -> Result<PreparedFragment, NymTopologyError> {
+        self.prepare_chunk(
+            fragment,
+            topology,
+            ack_key,
+            packet_recipient,
+            previous_route,
+        )
+        .await
+    }
+
+    async fn prepare_chunk(
+        &mut self,
+        fragment: Fragment,
+        topology: &NymTopology,
+        ack_key: &AckKey,
+        packet_recipient: &Recipient,
+        avoided_route: &[NymNodeRoutingAddress],
     ) -> Result<PreparedFragment, NymTopologyError> {
         // create an ack
         let (ack_delay, surb_ack_bytes) = self
@@ -263,11 +344,7 @@ where
             .collect();
 
         // generate pseudorandom route for the packet
-        let route = topology.random_route_to_gateway(
-            &mut self.rng,
-            self.num_mix_hops,
-            packet_recipient.gateway(),
-        )?;
+        let route = self.choose_route(topology, packet_recipient, avoided_route)?;
         let destination = packet_recipient.as_sphinx_destination();
 
         // including set of delays
@@ -290,9 +367,35 @@ where
             // note that the last hop of the packet is a gateway that does not do any delays
             total_delay: delays.iter().take(delays.len() - 1).sum::<Delay>() + ack_delay,
             mix_packet: MixPacket::new(first_hop_address, sphinx_packet, Default::default()),
+            route: Self::route_addresses(&route),
         })
     }
 
+    /// Chooses pseudorandom route to the gateway of the recipient avoiding, on every layer,
+    /// the node used by the provided `avoided_route`. Note that in a small enough network
+    /// it might not always be possible, in which case the same node is used again.
+    fn choose_route(
+        &mut self,
+        topology: &NymTopology,
+        packet_recipient: &Recipient,
+        avoided_route: &[NymNodeRoutingAddress],
+    ) -> Result<Vec<SphinxNode>, NymTopologyError> {
+        let route_selector = AvoidingRouteSelector::new(&*self.route_selector, avoided_route);
+        topology.route_to_gateway_with_selector(
+            &route_selector,
+            &mut self.rng,
+            self.num_mix_hops,
+            packet_recipient.gateway(),
+        )
+    }
+
+    fn route_addresses(route: &[SphinxNode]) -> Vec<NymNodeRoutingAddress> {
+        route
+            .iter()
+            .filter_map(|node| NymNodeRoutingAddress::try_from(node.address).ok())
+            .collect()
+    }
+
     /// Construct an acknowledgement SURB for the given [`FragmentIdentifier`]
     async fn generate_surb_ack(
         &mut self,
@@ -300,31 +403,44 @@ where
         topology: &NymTopology,
         ack_key: &AckKey,
     ) -> Result<SurbAck, NymTopologyError> {
-        SurbAck::construct(
+        SurbAck::construct_with_selector(
             &mut self.rng,
             &self.sender_address,
             ack_key,
             fragment_id.to_bytes(),
             self.average_ack_delay,
             topology,
+            &*self.route_selector,
         )
     }
 
-    /// Attaches an optional reply-surb and correct padding to the underlying message
+    /// Attaches the requested number of reply-surbs and correct padding to the underlying message
     /// and splits it into [`Fragment`] that can be later packed into sphinx packets to be
     /// sent through the mix network.
     pub fn prepare_and_split_message(
         &mut self,
         message: Vec<u8>,
-        with_reply_surb: bool,
+        num_reply_surbs: u8,
+        topology: &NymTopology,
+    ) -> Result<(Vec<Fragment>, Vec<SurbEncryptionKey>), PreparationError> {
+        self.prepare_and_split_message_with_headers(message, num_reply_surbs, None, topology)
+    }
+
+    /// Same as [`Self::prepare_and_split_message`], but additionally attaches the provided
+    /// headers to the message, so that they are encrypted alongside it.
+    pub fn prepare_and_split_message_with_headers(
+        &mut self,
+        message: Vec<u8>,
+        num_reply_surbs: u8,
+        headers: Option<&MessageHeaders>,
         topology: &NymTopology,
-    ) -> Result<(Vec<Fragment>, Option<SurbEncryptionKey>), PreparationError> {
-        let (message, reply_key) =
-            self.optionally_attach_reply_surb(message, with_reply_surb, topology)?;
+    ) -> Result<(Vec<Fragment>, Vec<SurbEncryptionKey>), PreparationError> {
+        let (message, reply_keys) =
+            self.attach_reply_surbs(message, num_reply_surbs, headers, topology)?;
 
         let message = self.pad_message(message);
 
-        Ok((self.split_message(message), reply_key))
+        Ok((self.split_message(message), reply_keys))
     }
 
     // TODO: perhaps the return type could somehow be combined with [`PreparedFragment`] ?
@@ -417,6 +533,7 @@ where
             average_packet_delay: Default::default(),
             average_ack_delay: Default::default(),
             num_mix_hops: DEFAULT_NUM_MIX_HOPS,
+            route_selector: Arc::new(UniformRouteSelector),
         }
     }
 }
@@ -1,6 +1,7 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::headers::{MessageHeaders, MessageHeadersError, HEADERS_PRESENT_FLAG, MAX_REPLY_SURBS};
 use crypto::asymmetric::encryption;
 use crypto::shared_key::recompute_shared_key;
 use crypto::symmetric::stream_cipher;
@@ -10,24 +11,30 @@ use nymsphinx_chunking::reconstruction::MessageReconstructor;
 use nymsphinx_params::{PacketEncryptionAlgorithm, PacketHkdfAlgorithm, DEFAULT_NUM_MIX_HOPS};
 
 // TODO: should this live in this file?
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ReconstructedMessage {
     /// The actual plaintext message that was received.
     pub message: Vec<u8>,
 
-    /// Optional ReplySURB to allow for an anonymous reply to the sender.
-    pub reply_surb: Option<ReplySurb>,
+    /// ReplySURBs attached by the sender to allow for anonymous replies.
+    /// Empty if the sender did not request any.
+    pub reply_surbs: Vec<ReplySurb>,
+
+    /// Metadata attached to the message by the sender, if any.
+    pub headers: Option<MessageHeaders>,
 }
 
 #[derive(Debug)]
 pub enum MessageRecoveryError {
     InvalidSurbPrefixError,
     MalformedSurbError(ReplySurbError),
+    MalformedHeadersError(MessageHeadersError),
     InvalidRemoteEphemeralKey(encryption::KeyRecoveryError),
     MalformedFragmentError,
     InvalidMessagePaddingError,
     MalformedReconstructedMessage(Vec<i32>),
     TooShortMessageError,
+    TooManyReplySurbs(usize),
 }
 
 impl From<ReplySurbError> for MessageRecoveryError {
@@ -36,6 +43,12 @@ impl From<ReplySurbError> for MessageRecoveryError {
     }
 }
 
+impl From<MessageHeadersError> for MessageRecoveryError {
+    fn from(err: MessageHeadersError) -> Self {
+        MessageRecoveryError::MalformedHeadersError(err)
+    }
+}
+
 impl From<encryption::KeyRecoveryError> for MessageRecoveryError {
     fn from(err: encryption::KeyRecoveryError) -> Self {
         MessageRecoveryError::InvalidRemoteEphemeralKey(err)
@@ -64,27 +77,46 @@ impl MessageReceiver {
         self
     }
 
-    /// Parses the message to strip and optionally recover reply SURB.
-    fn recover_reply_surb_from_message(
+    /// Parses the message to strip and recover all attached reply SURBs.
+    fn recover_reply_surbs_from_message(
         &self,
         message: &mut Vec<u8>,
-    ) -> Result<Option<ReplySurb>, MessageRecoveryError> {
-        match message[0] {
-            n if n == false as u8 => {
-                message.remove(0);
-                Ok(None)
-            }
-            n if n == true as u8 => {
-                let surb_len: usize = ReplySurb::serialized_len(self.num_mix_hops);
-                // note the extra +1 (due to 0/1 message prefix)
-                let surb_bytes = &message[1..1 + surb_len];
-                let reply_surb = ReplySurb::from_bytes(surb_bytes)?;
-
-                *message = message.drain(1 + surb_len..).collect();
-                Ok(Some(reply_surb))
-            }
-            _ => Err(MessageRecoveryError::InvalidSurbPrefixError),
+    ) -> Result<Vec<ReplySurb>, MessageRecoveryError> {
+        if message.is_empty() {
+            return Err(MessageRecoveryError::TooShortMessageError);
         }
+
+        // the top bit only indicates whether the headers follow the surbs
+        let num_surbs = (message[0] & !HEADERS_PRESENT_FLAG) as usize;
+        // the flag already keeps the count within the limit, but everything handling
+        // the reconstructed message relies on it, so make sure it's never exceeded
+        if num_surbs > MAX_REPLY_SURBS as usize {
+            return Err(MessageRecoveryError::TooManyReplySurbs(num_surbs));
+        }
+        let surb_len: usize = ReplySurb::serialized_len(self.num_mix_hops);
+        // note the extra +1 (due to the surb count prefix)
+        let surbs_end = 1 + num_surbs * surb_len;
+        if message.len() < surbs_end {
+            return Err(MessageRecoveryError::TooShortMessageError);
+        }
+
+        let reply_surbs = message[1..surbs_end]
+            .chunks_exact(surb_len)
+            .map(ReplySurb::from_bytes)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        *message = message.drain(surbs_end..).collect();
+        Ok(reply_surbs)
+    }
+
+    /// Parses the message to strip and recover the headers placed right after the reply SURBs.
+    fn recover_headers_from_message(
+        &self,
+        message: &mut Vec<u8>,
+    ) -> Result<MessageHeaders, MessageRecoveryError> {
+        let (headers, headers_len) = MessageHeaders::try_from_bytes(message)?;
+        *message = message.drain(headers_len..).collect();
+        Ok(headers)
     }
 
     /// Given raw fragment data, recovers the remote ephemeral key, recomputes shared secret,
@@ -121,6 +153,11 @@ impl MessageReceiver {
             .map_err(|_| MessageRecoveryError::MalformedFragmentError)
     }
 
+    /// Checks whether some [`Fragment`]s of the set of given `id` have already been received.
+    pub fn is_set_being_reconstructed(&self, set_id: i32) -> bool {
+        self.reconstructor.is_set_being_reconstructed(set_id)
+    }
+
     /// Removes the zero padding from the message that was initially included to ensure same length
     /// sphinx payloads.
     pub fn remove_padding(message: &mut Vec<u8>) -> Result<(), MessageRecoveryError> {
@@ -139,16 +176,21 @@ impl MessageReceiver {
     /// and returned alongside all (if applicable) set ids used in the message.
     ///
     /// # Returns:
-    /// - The reconstructed message alongside optional reply SURB,
+    /// - The reconstructed message alongside any attached reply SURBs,
     /// - List of ids of all the [`Set`]s used during reconstruction to detect stale retransmissions.
     pub fn insert_new_fragment(
         &mut self,
         fragment: Fragment,
     ) -> Result<Option<(ReconstructedMessage, Vec<i32>)>, MessageRecoveryError> {
         if let Some((mut message, used_sets)) = self.reconstructor.insert_new_fragment(fragment) {
-            // Split message into plaintext and reply-SURB
-            let reply_surb = match self.recover_reply_surb_from_message(&mut message) {
-                Ok(reply_surb) => reply_surb,
+            let has_headers = message
+                .first()
+                .map(|prefix| prefix & HEADERS_PRESENT_FLAG != 0)
+                .unwrap_or_default();
+
+            // Split message into plaintext and reply-SURBs
+            let reply_surbs = match self.recover_reply_surbs_from_message(&mut message) {
+                Ok(reply_surbs) => reply_surbs,
                 Err(_) => {
                     return Err(MessageRecoveryError::MalformedReconstructedMessage(
                         used_sets,
@@ -156,6 +198,20 @@ impl MessageReceiver {
                 }
             };
 
+            // And then the headers, if the sender has attached any
+            let headers = if has_headers {
+                match self.recover_headers_from_message(&mut message) {
+                    Ok(headers) => Some(headers),
+                    Err(_) => {
+                        return Err(MessageRecoveryError::MalformedReconstructedMessage(
+                            used_sets,
+                        ));
+                    }
+                }
+            } else {
+                None
+            };
+
             // Finally, remove the zero padding from the message
             Self::remove_padding(&mut message).map_err(|_| {
                 MessageRecoveryError::MalformedReconstructedMessage(used_sets.clone())
@@ -164,7 +220,8 @@ impl MessageReceiver {
             Ok(Some((
                 ReconstructedMessage {
                     message,
-                    reply_surb,
+                    reply_surbs,
+                    headers,
                 },
                 used_sets,
             )))
@@ -287,7 +344,7 @@ mod message_receiver {
     }
 
     #[test]
-    fn correctly_splits_message_into_plaintext_and_surb() {
+    fn correctly_splits_message_into_plaintext_and_surbs() {
         let message_receiver: MessageReceiver = Default::default();
 
         // the actual 'correctness' of the underlying message doesn't matter for this test
@@ -306,20 +363,83 @@ mod message_receiver {
         let mut received_without_surb: Vec<_> =
             std::iter::once(0).chain(message.iter().cloned()).collect();
 
-        let reply_surb = message_receiver
-            .recover_reply_surb_from_message(&mut received_without_surb)
+        let reply_surbs = message_receiver
+            .recover_reply_surbs_from_message(&mut received_without_surb)
             .unwrap();
         assert_eq!(received_without_surb, message);
-        assert!(reply_surb.is_none());
+        assert!(reply_surbs.is_empty());
 
         let mut received_with_surb: Vec<_> = std::iter::once(1)
             .chain(reply_surb_bytes.iter().cloned())
             .chain(message.iter().cloned())
             .collect();
-        let reply_surb = message_receiver
-            .recover_reply_surb_from_message(&mut received_with_surb)
+        let reply_surbs = message_receiver
+            .recover_reply_surbs_from_message(&mut received_with_surb)
             .unwrap();
         assert_eq!(received_with_surb, message);
-        assert_eq!(reply_surb_bytes, reply_surb.unwrap().to_bytes());
+        assert_eq!(reply_surbs.len(), 1);
+        assert_eq!(reply_surb_bytes, reply_surbs[0].to_bytes());
+
+        let another_surb =
+            ReplySurb::construct(&mut OsRng, &dummy_recipient, average_delay, &topology).unwrap();
+        let another_surb_bytes = another_surb.to_bytes();
+
+        let mut received_with_surbs: Vec<_> = std::iter::once(2)
+            .chain(reply_surb_bytes.iter().cloned())
+            .chain(another_surb_bytes.iter().cloned())
+            .chain(message.iter().cloned())
+            .collect();
+        let reply_surbs = message_receiver
+            .recover_reply_surbs_from_message(&mut received_with_surbs)
+            .unwrap();
+        assert_eq!(received_with_surbs, message);
+        assert_eq!(reply_surbs.len(), 2);
+        assert_eq!(reply_surb_bytes, reply_surbs[0].to_bytes());
+        assert_eq!(another_surb_bytes, reply_surbs[1].to_bytes());
+
+        // claiming more surbs than there is data for must fail
+        let mut truncated: Vec<_> = std::iter::once(3)
+            .chain(reply_surb_bytes.iter().cloned())
+            .collect();
+        assert!(message_receiver
+            .recover_reply_surbs_from_message(&mut truncated)
+            .is_err());
+    }
+
+    #[test]
+    fn recovering_headers_after_reply_surbs_works() {
+        let message_receiver = MessageReceiver::new();
+        let message = vec![42; 100];
+        let dummy_recipient = Recipient::try_from_base58_string("CytBseW6yFXUMzz4SGAKdNLGR7q3sJLLYxyBGvutNEQV.4QXYyEVc5fUDjmmi8PrHN9tdUFV4PCvSJE1278cHyvoe@FioFa8nMmPpQnYi7JyojoTuwGLeyNS8BF4ChPr29zUML").unwrap();
+        let reply_surb = ReplySurb::construct(
+            &mut OsRng,
+            &dummy_recipient,
+            Duration::from_millis(500),
+            &topology_fixture(),
+        )
+        .unwrap();
+        let headers = MessageHeaders {
+            content_type: Some("text/plain".to_string()),
+            correlation_id: None,
+            timestamp: Some(1234567890),
+            stream: None,
+        };
+
+        let mut received: Vec<_> = std::iter::once(1 | HEADERS_PRESENT_FLAG)
+            .chain(reply_surb.to_bytes().iter().cloned())
+            .chain(headers.try_to_bytes().unwrap().into_iter())
+            .chain(message.iter().cloned())
+            .collect();
+
+        let reply_surbs = message_receiver
+            .recover_reply_surbs_from_message(&mut received)
+            .unwrap();
+        assert_eq!(reply_surbs.len(), 1);
+
+        let recovered_headers = message_receiver
+            .recover_headers_from_message(&mut received)
+            .unwrap();
+        assert_eq!(recovered_headers, headers);
+        assert_eq!(received, message);
     }
 }
@@ -2,36 +2,75 @@ use crate::message::OrderedMessage;
 use log::*;
 use std::collections::HashMap;
 
+/// Maximum number of messages a buffer created with `OrderedMessageBuffer::new` is going to hold.
+pub const DEFAULT_CAPACITY: u64 = 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BufferError {
+    /// The message is too far ahead of the next expected one to fit in the buffer.
+    CapacityExceeded,
+}
+
 /// Stores messages and emits them in order.
 ///
 /// Only contiguous messages with an index less than or equal to `next_index`
 /// will be returned - this avoids returning gaps while we wait for the buffer
 /// to fill up with the full sequence.
+///
+/// The buffer is bounded: it only accepts messages with an index lower than
+/// `next_index + capacity`, so a sender ignoring that limit can't make it grow indefinitely.
 #[derive(Debug)]
 pub struct OrderedMessageBuffer {
     next_index: u64,
+    capacity: u64,
     messages: HashMap<u64, OrderedMessage>,
 }
 
 impl OrderedMessageBuffer {
     pub fn new() -> OrderedMessageBuffer {
+        OrderedMessageBuffer::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: u64) -> OrderedMessageBuffer {
         OrderedMessageBuffer {
             next_index: 0,
+            capacity,
             messages: HashMap::new(),
         }
     }
 
+    /// Index of the next message that is going to be returned by `read`.
+    pub fn next_index(&self) -> u64 {
+        self.next_index
+    }
+
     /// Writes a message to the buffer. messages are sort on insertion, so
     /// that later on multiple reads for incomplete sequences don't result in
     /// useless sort work.
-    pub fn write(&mut self, message: OrderedMessage) {
+    ///
+    /// Messages that have already been read are ignored, while the ones that wouldn't fit
+    /// within the capacity of the buffer are rejected.
+    pub fn write(&mut self, message: OrderedMessage) -> Result<(), BufferError> {
         trace!(
             "Writing message index: {} length {:?} to OrderedMessageBuffer.",
             message.index,
             message.data.len()
         );
 
+        if message.index < self.next_index {
+            debug!(
+                "Message index: {} has already been read - ignoring it",
+                message.index
+            );
+            return Ok(());
+        }
+
+        if message.index - self.next_index >= self.capacity {
+            return Err(BufferError::CapacityExceeded);
+        }
+
         self.messages.insert(message.index, message);
+        Ok(())
     }
 
     /// Returns `Option<Vec<u8>>` where it's `Some(bytes)` if there is gapless
@@ -101,11 +140,11 @@ mod test_chunking_and_reassembling {
                     index: 1,
                 };
 
-                buffer.write(first_message);
+                buffer.write(first_message).unwrap();
                 let first_read = buffer.read().unwrap();
                 assert_eq!(vec![1, 2, 3, 4], first_read);
 
-                buffer.write(second_message);
+                buffer.write(second_message).unwrap();
                 let second_read = buffer.read().unwrap();
                 assert_eq!(vec![5, 6, 7, 8], second_read);
 
@@ -125,8 +164,8 @@ mod test_chunking_and_reassembling {
                     index: 1,
                 };
 
-                buffer.write(first_message);
-                buffer.write(second_message);
+                buffer.write(first_message).unwrap();
+                buffer.write(second_message).unwrap();
                 let second_read = buffer.read();
                 assert_eq!(vec![1, 2, 3, 4, 5, 6, 7, 8], second_read.unwrap());
                 assert_eq!(None, buffer.read()); // second read on fully ordered result set is empty
@@ -145,8 +184,8 @@ mod test_chunking_and_reassembling {
                     index: 1,
                 };
 
-                buffer.write(second_message);
-                buffer.write(first_message);
+                buffer.write(second_message).unwrap();
+                buffer.write(first_message).unwrap();
                 let read = buffer.read();
                 assert_eq!(vec![1, 2, 3, 4, 5, 6, 7, 8], read.unwrap());
                 assert_eq!(None, buffer.read()); // second read on fully ordered result set is empty
@@ -174,9 +213,9 @@ mod test_chunking_and_reassembling {
                     index: 3,
                 };
 
-                buffer.write(zero_message);
-                buffer.write(one_message);
-                buffer.write(three_message);
+                buffer.write(zero_message).unwrap();
+                buffer.write(one_message).unwrap();
+                buffer.write(three_message).unwrap();
                 buffer
             }
             #[test]
@@ -193,7 +232,7 @@ mod test_chunking_and_reassembling {
                     data: vec![5, 5, 5, 5],
                     index: 5,
                 };
-                buffer.write(five_message);
+                buffer.write(five_message).unwrap();
                 assert_eq!(None, buffer.read());
             }
 
@@ -206,7 +245,7 @@ mod test_chunking_and_reassembling {
                     data: vec![2, 2, 2, 2],
                     index: 2,
                 };
-                buffer.write(two_message);
+                buffer.write(two_message).unwrap();
 
                 let more_ordered_bytes = buffer.read().unwrap();
                 assert_eq!([2, 2, 2, 2, 3, 3, 3, 3].to_vec(), more_ordered_bytes);
@@ -216,7 +255,7 @@ mod test_chunking_and_reassembling {
                     data: vec![5, 5, 5, 5],
                     index: 5,
                 };
-                buffer.write(five_message);
+                buffer.write(five_message).unwrap();
 
                 assert_eq!(None, buffer.read());
 
@@ -225,7 +264,7 @@ mod test_chunking_and_reassembling {
                     data: vec![4, 4, 4, 4],
                     index: 4,
                 };
-                buffer.write(four_message);
+                buffer.write(four_message).unwrap();
 
                 assert_eq!([4, 4, 4, 4, 5, 5, 5, 5].to_vec(), buffer.read().unwrap());
 
@@ -249,11 +288,11 @@ mod test_chunking_and_reassembling {
                     index: 2,
                 };
 
-                buffer.write(zero_message);
+                buffer.write(zero_message).unwrap();
                 assert!(buffer.read().is_some()); // burn the buffer
 
-                buffer.write(two_message);
-                buffer.write(one_message);
+                buffer.write(two_message).unwrap();
+                buffer.write(one_message).unwrap();
                 assert!(buffer.read().is_some());
                 assert_eq!(buffer.next_index, 3);
             }
@@ -281,26 +320,77 @@ mod test_chunking_and_reassembling {
                     data: vec![2, 2, 2, 2],
                     index: 4,
                 };
-                buffer.write(zero_message);
+                buffer.write(zero_message).unwrap();
                 assert!(buffer.read().is_some());
                 assert_eq!(buffer.next_index, 1);
 
-                buffer.write(four_message);
+                buffer.write(four_message).unwrap();
                 assert!(buffer.read().is_none());
                 assert_eq!(buffer.next_index, 1);
 
-                buffer.write(three_message);
+                buffer.write(three_message).unwrap();
                 assert!(buffer.read().is_none());
                 assert_eq!(buffer.next_index, 1);
 
-                buffer.write(two_message);
+                buffer.write(two_message).unwrap();
                 assert!(buffer.read().is_none());
                 assert_eq!(buffer.next_index, 1);
 
-                buffer.write(one_message);
+                buffer.write(one_message).unwrap();
                 assert!(buffer.read().is_some());
                 assert_eq!(buffer.next_index, 5)
             }
         }
+
+        mod when_the_buffer_is_full {
+            use super::*;
+
+            #[test]
+            fn messages_beyond_capacity_are_rejected() {
+                let mut buffer = OrderedMessageBuffer::with_capacity(2);
+
+                let one_message = OrderedMessage {
+                    data: vec![1, 1, 1, 1],
+                    index: 1,
+                };
+                let two_message = OrderedMessage {
+                    data: vec![2, 2, 2, 2],
+                    index: 2,
+                };
+
+                buffer.write(one_message).unwrap();
+                assert_eq!(
+                    Err(BufferError::CapacityExceeded),
+                    buffer.write(two_message.clone())
+                );
+
+                // once the gap is filled, there's space for more
+                let zero_message = OrderedMessage {
+                    data: vec![0, 0, 0, 0],
+                    index: 0,
+                };
+                buffer.write(zero_message).unwrap();
+                assert_eq!([0, 0, 0, 0, 1, 1, 1, 1].to_vec(), buffer.read().unwrap());
+
+                buffer.write(two_message).unwrap();
+                assert_eq!([2, 2, 2, 2].to_vec(), buffer.read().unwrap());
+            }
+
+            #[test]
+            fn messages_that_were_already_read_are_ignored() {
+                let mut buffer = OrderedMessageBuffer::with_capacity(2);
+
+                let zero_message = OrderedMessage {
+                    data: vec![0, 0, 0, 0],
+                    index: 0,
+                };
+                buffer.write(zero_message.clone()).unwrap();
+                assert!(buffer.read().is_some());
+
+                buffer.write(zero_message).unwrap();
+                assert_eq!(None, buffer.read());
+                assert_eq!(buffer.next_index(), 1);
+            }
+        }
     }
 }
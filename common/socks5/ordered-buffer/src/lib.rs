@@ -2,7 +2,7 @@ mod buffer;
 mod message;
 mod sender;
 
-pub use buffer::OrderedMessageBuffer;
+pub use buffer::{BufferError, OrderedMessageBuffer, DEFAULT_CAPACITY};
 pub use message::MessageError;
 pub use message::OrderedMessage;
 pub use sender::OrderedMessageSender;
@@ -1,26 +1,63 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::proxy_runner::{FlowControl, SEND_WINDOW};
 use futures::channel::mpsc;
 use futures::StreamExt;
 use log::*;
-use ordered_buffer::{OrderedMessage, OrderedMessageBuffer};
+use ordered_buffer::{BufferError, OrderedMessage, OrderedMessageBuffer, DEFAULT_CAPACITY};
 use socks5_requests::ConnectionId;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
-/// A generic message produced after reading from a socket/connection. It includes data that was
-/// actually read alongside boolean indicating whether the connection got closed so that
-/// remote could act accordingly.
+/// Maximum number of messages buffered for a single connection. The remote is never supposed to
+/// have more than `SEND_WINDOW` (plus the final closing message) in flight, so anything beyond
+/// that means it ignores the flow control.
+const MAX_BUFFERED_MESSAGES: u64 = 2 * SEND_WINDOW as u64;
+
+/// Maximum number of messages buffered for a connection that is not established yet. We don't know
+/// yet whether the remote uses the flow control, so it has to be as lenient as for the ones that don't.
+const MAX_PENDING_MESSAGES: usize = DEFAULT_CAPACITY as usize;
+
+/// Maximum number of distinct connections that can have messages buffered before being established.
+const MAX_PENDING_CONNECTIONS: usize = 128;
+
+/// Time for which we remember connections that got closed, reset or lost their pending data,
+/// and keep the data received before a connection got established. It only has to cover
+/// the messages the mix network might have delayed.
+const CONNECTION_RETENTION: Duration = Duration::from_secs(5 * 60);
+
+/// How often the connections that are no longer worth remembering are forgotten.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A generic message produced after reading from a socket/connection, or a control message
+/// regarding it, that is to be handled by the local side of the proxy.
 #[derive(Debug)]
-pub struct ConnectionMessage {
-    pub payload: Vec<u8>,
-    pub socket_closed: bool,
+pub enum ConnectionMessage {
+    /// Data that was actually read alongside boolean indicating whether the connection got closed
+    /// so that remote could act accordingly. It also includes the number of ordered messages
+    /// the data was reassembled from, so that they could be credited back to the remote.
+    Data {
+        payload: Vec<u8>,
+        socket_closed: bool,
+        message_count: u32,
+    },
+
+    /// The remote has consumed some of our data and allows us to send that many more messages.
+    Credit(u32),
+
+    /// The remote has aborted the connection.
+    Reset,
+
+    /// The remote has sent more data than it was allowed to, so the connection has to be aborted.
+    BufferOverflow,
 }
 
 /// Channel responsible for sending data that was received from mix network into particular connection.
 /// Data includes the actual payload that is to be written onto the connection
 /// alongside boolean indicating whether the remote connection was closed after producing this message,
-/// so that the local connection should also shut down.
+/// so that the local connection should also shut down. It also carries the flow control credit
+/// and resets of the connection.
 pub type ConnectionSender = mpsc::UnboundedSender<ConnectionMessage>;
 
 /// Receiver part of the [`ConnectionSender`]
@@ -30,9 +67,11 @@ pub type ControllerSender = mpsc::UnboundedSender<ControllerCommand>;
 pub type ControllerReceiver = mpsc::UnboundedReceiver<ControllerCommand>;
 
 pub enum ControllerCommand {
-    Insert(ConnectionId, ConnectionSender),
+    Insert(ConnectionId, ConnectionSender, FlowControl),
     Remove(ConnectionId),
     Send(ConnectionId, Vec<u8>, bool),
+    Credit(ConnectionId, u32),
+    Reset(ConnectionId),
 }
 
 struct ActiveConnection {
@@ -42,38 +81,58 @@ struct ActiveConnection {
 }
 
 impl ActiveConnection {
-    fn write_to_buf(&mut self, payload: Vec<u8>) {
+    fn write_to_buf(&mut self, payload: Vec<u8>) -> Result<(), BufferError> {
         let ordered_message = match OrderedMessage::try_from_bytes(payload) {
             Ok(msg) => msg,
             Err(err) => {
                 error!("Malformed ordered message - {:?}", err);
-                return;
+                return Ok(());
             }
         };
-        self.ordered_buffer.write(ordered_message);
+        self.ordered_buffer.write(ordered_message)
+    }
+
+    /// Reads all contiguous data alongside the number of messages it was made of.
+    fn read_from_buf(&mut self) -> Option<(Vec<u8>, u32)> {
+        let previous_index = self.ordered_buffer.next_index();
+        let payload = self.ordered_buffer.read()?;
+        let message_count = (self.ordered_buffer.next_index() - previous_index) as u32;
+        Some((payload, message_count))
     }
 
-    fn read_from_buf(&mut self) -> Option<Vec<u8>> {
-        self.ordered_buffer.read()
+    fn send(&mut self, message: ConnectionMessage) {
+        if let Some(connection_sender) = self.connection_sender.as_mut() {
+            if let Err(err) = connection_sender.unbounded_send(message) {
+                debug!("The connection has already finished - {}", err);
+            }
+        }
     }
 }
 
+struct PendingConnection {
+    messages: Vec<(Vec<u8>, bool)>,
+    first_received: Instant,
+}
+
 /// Controller represents a way of managing multiple open connections that are used for socks5
 /// proxy.
 pub struct Controller {
     active_connections: HashMap<ConnectionId, ActiveConnection>,
     receiver: ControllerReceiver,
 
-    // TODO: this will need to be either completely removed (from code) or periodically cleaned
-    // to avoid memory issues
-    recently_closed: HashSet<ConnectionId>,
-
-    // TODO: this can potentially be abused to ddos and kill provider. Not sure at this point
-    // how to handle it more gracefully
+    // connections that got closed alongside the time they were closed at, forgotten after
+    // `CONNECTION_RETENTION`
+    recently_closed: HashMap<ConnectionId, Instant>,
 
     // buffer for messages received before connection was established due to mixnet being able to
-    // un-order messages. Note we don't ever expect to have more than 1-2 messages per connection here
-    pending_messages: HashMap<ConnectionId, Vec<(Vec<u8>, bool)>>,
+    // un-order messages. Note we don't ever expect to have more than 1-2 messages per connection here,
+    // and we won't keep more than `MAX_PENDING_MESSAGES` of them for at most `MAX_PENDING_CONNECTIONS`
+    // connections
+    pending_messages: HashMap<ConnectionId, PendingConnection>,
+
+    // connections that have lost some of the messages received before being established. They're
+    // aborted as soon as they get established, as the data we've dropped is never going to be resent.
+    overflowed_pending: HashMap<ConnectionId, Instant>,
 }
 
 impl Controller {
@@ -83,18 +142,49 @@ impl Controller {
             Controller {
                 active_connections: HashMap::new(),
                 receiver,
-                recently_closed: HashSet::new(),
+                recently_closed: HashMap::new(),
                 pending_messages: HashMap::new(),
+                overflowed_pending: HashMap::new(),
             },
             sender,
         )
     }
 
-    fn insert_connection(&mut self, conn_id: ConnectionId, connection_sender: ConnectionSender) {
+    fn insert_connection(
+        &mut self,
+        conn_id: ConnectionId,
+        connection_sender: ConnectionSender,
+        flow_control: FlowControl,
+    ) {
+        if self.recently_closed.contains_key(&conn_id) {
+            // the remote has reset the connection before it was even established
+            debug!("Connection {} has already been reset", conn_id);
+            let _ = connection_sender.unbounded_send(ConnectionMessage::Reset);
+            return;
+        }
+
+        if self.overflowed_pending.remove(&conn_id).is_some() {
+            warn!(
+                "Connection {} has lost some of its data before it was established. Aborting it",
+                conn_id
+            );
+            let _ = connection_sender.unbounded_send(ConnectionMessage::BufferOverflow);
+            // whatever else the remote sends is going to be dropped
+            self.recently_closed.insert(conn_id, Instant::now());
+            return;
+        }
+
+        // only the remote using the flow control is guaranteed not to send too far ahead. The ones
+        // that don't use it can send as fast as they want, so limiting them would just abort
+        // their connections
+        let buffer_capacity = match flow_control {
+            FlowControl::Enabled => MAX_BUFFERED_MESSAGES,
+            FlowControl::Disabled | FlowControl::Pending => u64::MAX,
+        };
         let active_connection = ActiveConnection {
             is_closed: false,
             connection_sender: Some(connection_sender),
-            ordered_buffer: OrderedMessageBuffer::new(),
+            ordered_buffer: OrderedMessageBuffer::with_capacity(buffer_capacity),
         };
         if let Some(_active_conn) = self.active_connections.insert(conn_id, active_connection) {
             error!("Received a duplicate 'Connect'!")
@@ -102,7 +192,7 @@ impl Controller {
             // check if there were any pending messages
             if let Some(pending) = self.pending_messages.remove(&conn_id) {
                 debug!("There were some pending messages for {}", conn_id);
-                for (payload, is_closed) in pending {
+                for (payload, is_closed) in pending.messages {
                     self.send_to_connection(conn_id, payload, is_closed)
                 }
             }
@@ -111,19 +201,28 @@ impl Controller {
 
     fn remove_connection(&mut self, conn_id: ConnectionId) {
         debug!("Removing {} from controller", conn_id);
-        if self.active_connections.remove(&conn_id).is_none() {
+        if self.active_connections.remove(&conn_id).is_none()
+            && !self.recently_closed.contains_key(&conn_id)
+        {
             error!(
                 "tried to remove non-existing connection with id: {:?}",
                 conn_id
             )
         }
-        self.recently_closed.insert(conn_id);
+        self.recently_closed.insert(conn_id, Instant::now());
     }
 
     fn send_to_connection(&mut self, conn_id: ConnectionId, payload: Vec<u8>, is_closed: bool) {
         if let Some(active_connection) = self.active_connections.get_mut(&conn_id) {
             if !payload.is_empty() {
-                active_connection.write_to_buf(payload);
+                if let Err(err) = active_connection.write_to_buf(payload) {
+                    warn!(
+                        "Failed to buffer data of connection {} - {:?}. Aborting it",
+                        conn_id, err
+                    );
+                    active_connection.send(ConnectionMessage::BufferOverflow);
+                    return;
+                }
             } else if !is_closed {
                 error!("Tried to write an empty message to a not-closing connection. Please let us know if you see this message");
             }
@@ -131,34 +230,48 @@ impl Controller {
             // remote socket getting closed!
             active_connection.is_closed |= is_closed;
 
-            if let Some(payload) = active_connection.read_from_buf() {
-                if let Err(err) = active_connection
-                    .connection_sender
-                    .as_mut()
-                    .unwrap()
-                    .unbounded_send(ConnectionMessage {
-                        payload,
-                        socket_closed: active_connection.is_closed,
-                    })
-                {
-                    error!("WTF IS THIS: {:?}", err);
-                }
-
-                // TODO: ABOVE UNWRAP CAUSED A CRASH IN A NORMAL USE!!!!
-                // TODO:
-                // TODO: surprisingly it only happened on socks client, never on nSP
-                // TODO:
-                // TODO:
-                // TODO:
-                // TODO:
+            if let Some((payload, message_count)) = active_connection.read_from_buf() {
+                let socket_closed = active_connection.is_closed;
+                active_connection.send(ConnectionMessage::Data {
+                    payload,
+                    socket_closed,
+                    message_count,
+                });
             }
-        } else if !self.recently_closed.contains(&conn_id) {
+        } else if self.overflowed_pending.contains_key(&conn_id) {
+            debug!(
+                "Dropping data of connection {} as it's going to be aborted",
+                conn_id
+            );
+        } else if !self.recently_closed.contains_key(&conn_id) {
             debug!("Received a 'Send' before 'Connect' - going to buffer the data");
-            let pending = self
-                .pending_messages
-                .entry(conn_id)
-                .or_insert_with(Vec::new);
-            pending.push((payload, is_closed));
+            if !self.pending_messages.contains_key(&conn_id)
+                && self.pending_messages.len() >= MAX_PENDING_CONNECTIONS
+            {
+                warn!(
+                    "Too many connections have received data before being established - connection {} is going to be aborted",
+                    conn_id
+                );
+                self.overflowed_pending.insert(conn_id, Instant::now());
+                return;
+            }
+            let pending =
+                self.pending_messages
+                    .entry(conn_id)
+                    .or_insert_with(|| PendingConnection {
+                        messages: Vec::new(),
+                        first_received: Instant::now(),
+                    });
+            if pending.messages.len() >= MAX_PENDING_MESSAGES {
+                warn!(
+                    "Too many messages were received for connection {} before it was established - it's going to be aborted",
+                    conn_id
+                );
+                self.pending_messages.remove(&conn_id);
+                self.overflowed_pending.insert(conn_id, Instant::now());
+                return;
+            }
+            pending.messages.push((payload, is_closed));
         } else if !is_closed {
             error!(
                 "Tried to write to closed connection ({} bytes were 'lost)",
@@ -169,17 +282,262 @@ impl Controller {
         }
     }
 
+    fn credit_connection(&mut self, conn_id: ConnectionId, credit: u32) {
+        match self.active_connections.get_mut(&conn_id) {
+            Some(active_connection) => active_connection.send(ConnectionMessage::Credit(credit)),
+            None => debug!("Received credit for inactive connection {}", conn_id),
+        }
+    }
+
+    fn reset_connection(&mut self, conn_id: ConnectionId) {
+        debug!("Connection {} got reset by the remote", conn_id);
+        self.pending_messages.remove(&conn_id);
+        self.overflowed_pending.remove(&conn_id);
+        match self.active_connections.get_mut(&conn_id) {
+            // the connection is going to get removed once it's done
+            Some(active_connection) => active_connection.send(ConnectionMessage::Reset),
+            // make sure that if it gets established in the future, it's aborted straight away
+            None => {
+                self.recently_closed.insert(conn_id, Instant::now());
+            }
+        }
+    }
+
+    fn remove_stale_connections(&mut self, now: Instant) {
+        let is_fresh =
+            |since: &Instant| now.saturating_duration_since(*since) < CONNECTION_RETENTION;
+        self.recently_closed
+            .retain(|_, closed_at| is_fresh(closed_at));
+        self.overflowed_pending
+            .retain(|_, overflowed_at| is_fresh(overflowed_at));
+
+        let overflowed_pending = &mut self.overflowed_pending;
+        self.pending_messages.retain(|conn_id, pending| {
+            if is_fresh(&pending.first_received) {
+                return true;
+            }
+            debug!(
+                "Connection {} was not established in time - dropping its pending data",
+                conn_id
+            );
+            // if it does get established after all, it's missing the data
+            overflowed_pending.insert(*conn_id, now);
+            false
+        });
+    }
+
+    fn handle_command(&mut self, command: ControllerCommand) {
+        match command {
+            ControllerCommand::Send(conn_id, data, is_closed) => {
+                self.send_to_connection(conn_id, data, is_closed)
+            }
+            ControllerCommand::Insert(conn_id, sender, flow_control) => {
+                self.insert_connection(conn_id, sender, flow_control)
+            }
+            ControllerCommand::Remove(conn_id) => self.remove_connection(conn_id),
+            ControllerCommand::Credit(conn_id, credit) => self.credit_connection(conn_id, credit),
+            ControllerCommand::Reset(conn_id) => self.reset_connection(conn_id),
+        }
+    }
+
     pub async fn run(&mut self) {
-        while let Some(command) = self.receiver.next().await {
-            match command {
-                ControllerCommand::Send(conn_id, data, is_closed) => {
-                    self.send_to_connection(conn_id, data, is_closed)
-                }
-                ControllerCommand::Insert(conn_id, sender) => {
-                    self.insert_connection(conn_id, sender)
-                }
-                ControllerCommand::Remove(conn_id) => self.remove_connection(conn_id),
+        let mut cleanup_interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            tokio::select! {
+                command = self.receiver.next() => match command {
+                    Some(command) => self.handle_command(command),
+                    None => break,
+                },
+                _ = cleanup_interval.tick() => self.remove_stale_connections(Instant::now()),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ordered_bytes(index: u64) -> Vec<u8> {
+        OrderedMessage {
+            data: vec![1, 2, 3],
+            index,
+        }
+        .into_bytes()
+    }
+
+    fn insert(
+        controller: &mut Controller,
+        conn_id: ConnectionId,
+        flow_control: FlowControl,
+    ) -> ConnectionReceiver {
+        let (sender, receiver) = mpsc::unbounded();
+        controller.insert_connection(conn_id, sender, flow_control);
+        receiver
+    }
+
+    #[test]
+    fn data_is_delivered_in_order_with_its_message_count() {
+        let (mut controller, _) = Controller::new();
+        let mut receiver = insert(&mut controller, 42, FlowControl::Enabled);
+
+        controller.send_to_connection(42, ordered_bytes(1), false);
+        assert!(receiver.try_next().is_err());
+        controller.send_to_connection(42, ordered_bytes(0), false);
+
+        match receiver.try_next().unwrap().unwrap() {
+            ConnectionMessage::Data {
+                payload,
+                socket_closed,
+                message_count,
+            } => {
+                assert_eq!(payload, vec![1, 2, 3, 1, 2, 3]);
+                assert!(!socket_closed);
+                assert_eq!(message_count, 2);
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn data_beyond_the_window_aborts_flow_controlled_connection() {
+        let (mut controller, _) = Controller::new();
+        let mut receiver = insert(&mut controller, 42, FlowControl::Enabled);
+
+        controller.send_to_connection(42, ordered_bytes(MAX_BUFFERED_MESSAGES), false);
+        assert!(matches!(
+            receiver.try_next().unwrap().unwrap(),
+            ConnectionMessage::BufferOverflow
+        ));
+    }
+
+    #[test]
+    fn connection_without_flow_control_can_buffer_more() {
+        let (mut controller, _) = Controller::new();
+        let mut receiver = insert(&mut controller, 42, FlowControl::Disabled);
+
+        controller.send_to_connection(42, ordered_bytes(MAX_BUFFERED_MESSAGES), false);
+        controller.send_to_connection(42, ordered_bytes(10 * DEFAULT_CAPACITY), false);
+        assert!(receiver.try_next().is_err());
+    }
+
+    #[test]
+    fn pending_overflow_aborts_connection_once_established() {
+        let (mut controller, _) = Controller::new();
+        for index in 0..=MAX_PENDING_MESSAGES as u64 {
+            controller.send_to_connection(42, ordered_bytes(index), false);
+        }
+        assert!(controller.pending_messages.is_empty());
+
+        // anything sent afterwards is dropped rather than buffered again
+        controller.send_to_connection(42, ordered_bytes(0), false);
+        assert!(controller.pending_messages.is_empty());
+
+        let mut receiver = insert(&mut controller, 42, FlowControl::Pending);
+        assert!(matches!(
+            receiver.try_next().unwrap().unwrap(),
+            ConnectionMessage::BufferOverflow
+        ));
+        // the connection is never made active, so nothing else is going to be sent to it
+        assert!(matches!(receiver.try_next(), Ok(None)));
+        assert!(!controller.active_connections.contains_key(&42));
+
+        // the proxy removes itself once it's done
+        controller.remove_connection(42);
+        assert!(controller.recently_closed.contains_key(&42));
+    }
+
+    #[test]
+    fn number_of_pending_connections_is_limited() {
+        let (mut controller, _) = Controller::new();
+        for conn_id in 0..MAX_PENDING_CONNECTIONS as ConnectionId {
+            controller.send_to_connection(conn_id, ordered_bytes(0), false);
+        }
+        let excess_id = MAX_PENDING_CONNECTIONS as ConnectionId;
+        controller.send_to_connection(excess_id, ordered_bytes(0), false);
+        assert_eq!(controller.pending_messages.len(), MAX_PENDING_CONNECTIONS);
+
+        let mut receiver = insert(&mut controller, excess_id, FlowControl::Pending);
+        assert!(matches!(
+            receiver.try_next().unwrap().unwrap(),
+            ConnectionMessage::BufferOverflow
+        ));
+
+        // the data of connections that already had some pending is still buffered
+        let mut receiver = insert(&mut controller, 0, FlowControl::Pending);
+        assert!(matches!(
+            receiver.try_next().unwrap().unwrap(),
+            ConnectionMessage::Data { .. }
+        ));
+    }
+
+    #[test]
+    fn stale_connections_are_eventually_forgotten() {
+        let (mut controller, _) = Controller::new();
+        controller.send_to_connection(1, ordered_bytes(0), false);
+        controller.reset_connection(2);
+        let _receiver = insert(&mut controller, 3, FlowControl::Enabled);
+        controller.remove_connection(3);
+
+        let now = Instant::now();
+        controller.remove_stale_connections(now);
+        assert!(controller.pending_messages.contains_key(&1));
+        assert!(controller.recently_closed.contains_key(&2));
+        assert!(controller.recently_closed.contains_key(&3));
+
+        // the pending data is dropped, so the connection is aborted if it's still established
+        let later = now + CONNECTION_RETENTION;
+        controller.remove_stale_connections(later);
+        assert!(controller.pending_messages.is_empty());
+        assert!(controller.recently_closed.is_empty());
+        assert!(controller.overflowed_pending.contains_key(&1));
+
+        controller.remove_stale_connections(later + CONNECTION_RETENTION);
+        assert!(controller.overflowed_pending.is_empty());
+    }
+
+    #[test]
+    fn pending_data_is_delivered_once_established() {
+        let (mut controller, _) = Controller::new();
+        controller.send_to_connection(42, ordered_bytes(0), true);
+
+        let mut receiver = insert(&mut controller, 42, FlowControl::Pending);
+        match receiver.try_next().unwrap().unwrap() {
+            ConnectionMessage::Data { socket_closed, .. } => assert!(socket_closed),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reset_before_connection_is_established_aborts_it() {
+        let (mut controller, _) = Controller::new();
+        controller.send_to_connection(42, ordered_bytes(0), false);
+        controller.reset_connection(42);
+        assert!(controller.pending_messages.is_empty());
+
+        let mut receiver = insert(&mut controller, 42, FlowControl::Pending);
+        assert!(matches!(
+            receiver.try_next().unwrap().unwrap(),
+            ConnectionMessage::Reset
+        ));
+        assert!(!controller.active_connections.contains_key(&42));
+    }
+
+    #[test]
+    fn reset_and_credit_are_passed_to_active_connection() {
+        let (mut controller, _) = Controller::new();
+        let mut receiver = insert(&mut controller, 42, FlowControl::Enabled);
+
+        controller.credit_connection(42, 8);
+        assert!(matches!(
+            receiver.try_next().unwrap().unwrap(),
+            ConnectionMessage::Credit(8)
+        ));
+
+        controller.reset_connection(42);
+        assert!(matches!(
+            receiver.try_next().unwrap().unwrap(),
+            ConnectionMessage::Reset
+        ));
+    }
+}
@@ -1,8 +1,8 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
-use super::MixProxySender;
 use super::SHUTDOWN_TIMEOUT;
+use super::{CreditReceiver, FlowControl, MixProxySender, SEND_WINDOW};
 use crate::available_reader::AvailableReader;
 use bytes::Bytes;
 use futures::FutureExt;
@@ -14,6 +14,44 @@ use std::{io, sync::Arc};
 use tokio::select;
 use tokio::{net::tcp::OwnedReadHalf, sync::Notify, time::sleep};
 
+/// Keeps track of how many messages we're still allowed to send to the remote.
+#[derive(Debug)]
+struct SendWindow {
+    flow_control: FlowControl,
+    sent: u64,
+    credited: u64,
+}
+
+impl SendWindow {
+    fn new(flow_control: FlowControl) -> Self {
+        SendWindow {
+            flow_control,
+            sent: 0,
+            credited: 0,
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        match self.flow_control {
+            FlowControl::Enabled => self.sent < SEND_WINDOW as u64 + self.credited,
+            FlowControl::Disabled | FlowControl::Pending => true,
+        }
+    }
+
+    fn record_sent(&mut self) {
+        self.sent += 1;
+    }
+
+    fn add_credit(&mut self, credit: u32) {
+        // granting any credit, even zero, means the remote supports the flow control.
+        // Everything we've sent before then is accounted for as well.
+        if self.flow_control == FlowControl::Pending {
+            self.flow_control = FlowControl::Enabled;
+        }
+        self.credited += credit as u64;
+    }
+}
+
 fn send_empty_close<F, S>(
     connection_id: ConnectionId,
     message_sender: &mut OrderedMessageSender,
@@ -74,6 +112,7 @@ where
     is_finished
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(super) async fn run_inbound<F, S>(
     mut reader: OwnedReadHalf,
     local_destination_address: String, // addresses are provided for better logging
@@ -81,26 +120,48 @@ pub(super) async fn run_inbound<F, S>(
     connection_id: ConnectionId,
     mix_sender: MixProxySender<S>,
     adapter_fn: F,
-    shutdown_notify: Arc<Notify>,
+    flow_control: FlowControl,
+    mut credit_receiver: CreditReceiver,
+    local_closed_notify: Arc<Notify>,
+    remote_closed_notify: Arc<Notify>,
 ) -> OwnedReadHalf
 where
     F: Fn(ConnectionId, Vec<u8>, bool) -> S + Send + 'static,
 {
     let mut available_reader = AvailableReader::new(&mut reader);
     let mut message_sender = OrderedMessageSender::new();
-    let shutdown_future = shutdown_notify.notified().then(|_| sleep(SHUTDOWN_TIMEOUT));
+    let shutdown_future = remote_closed_notify
+        .notified()
+        .then(|_| sleep(SHUTDOWN_TIMEOUT));
 
     tokio::pin!(shutdown_future);
 
+    let mut send_window = SendWindow::new(flow_control);
+
     loop {
         select! {
-            read_data = &mut available_reader.next() => {
+            // if we ran out of credit, we stop reading from the socket, so that the local side would
+            // slow down rather than us buffering everything it sends
+            read_data = &mut available_reader.next(), if send_window.is_open() => {
+                send_window.record_sent();
                 if deal_with_data(read_data, &local_destination_address, &remote_source_address, connection_id, &mut message_sender, &mix_sender, &adapter_fn) {
                     break
                 }
             }
+            credit = credit_receiver.next() => {
+                if let Some(credit) = credit {
+                    trace!("{} - received {} credit", connection_id, credit);
+                    send_window.add_credit(credit);
+                } else {
+                    debug!("closing inbound proxy as outbound is already gone");
+                    // the remote is either gone or has reset the connection, but in case it's
+                    // still there, let it know we're not going to send anything more
+                    send_empty_close(connection_id, &mut message_sender, &mix_sender, &adapter_fn);
+                    break;
+                }
+            }
             _ = &mut shutdown_future => {
-                debug!("closing inbound proxy after outbound was closed {:?} ago", SHUTDOWN_TIMEOUT);
+                debug!("closing inbound proxy after remote was closed {:?} ago", SHUTDOWN_TIMEOUT);
                 // inform remote just in case it was closed because of lack of heartbeat.
                 // worst case the remote will just have couple of false negatives
                 send_empty_close(connection_id, &mut message_sender, &mix_sender, &adapter_fn);
@@ -109,7 +170,63 @@ where
         }
     }
     trace!("{} - inbound closed", connection_id);
-    shutdown_notify.notify_one();
+    local_closed_notify.notify_one();
 
     reader
 }
+
+#[cfg(test)]
+mod send_window_tests {
+    use super::*;
+
+    fn exhaust(send_window: &mut SendWindow) {
+        for _ in 0..SEND_WINDOW {
+            assert!(send_window.is_open());
+            send_window.record_sent();
+        }
+    }
+
+    #[test]
+    fn window_is_closed_once_exhausted() {
+        let mut send_window = SendWindow::new(FlowControl::Enabled);
+        exhaust(&mut send_window);
+        assert!(!send_window.is_open());
+    }
+
+    #[test]
+    fn credit_reopens_the_window() {
+        let mut send_window = SendWindow::new(FlowControl::Enabled);
+        exhaust(&mut send_window);
+
+        send_window.add_credit(2);
+        assert!(send_window.is_open());
+        send_window.record_sent();
+        assert!(send_window.is_open());
+        send_window.record_sent();
+        assert!(!send_window.is_open());
+    }
+
+    #[test]
+    fn window_is_never_closed_without_flow_control() {
+        let mut send_window = SendWindow::new(FlowControl::Disabled);
+        exhaust(&mut send_window);
+        exhaust(&mut send_window);
+        assert!(send_window.is_open());
+    }
+
+    #[test]
+    fn pending_flow_control_is_enabled_by_any_credit() {
+        let mut send_window = SendWindow::new(FlowControl::Pending);
+        exhaust(&mut send_window);
+        send_window.record_sent();
+        assert!(send_window.is_open());
+
+        // the messages sent so far still count towards the window
+        send_window.add_credit(0);
+        assert!(!send_window.is_open());
+        send_window.add_credit(1);
+        assert!(!send_window.is_open());
+        send_window.add_credit(1);
+        assert!(send_window.is_open());
+    }
+}
@@ -3,7 +3,7 @@
 
 use crate::connection_controller::ConnectionReceiver;
 use futures::channel::mpsc;
-use socks5_requests::ConnectionId;
+use socks5_requests::{ConnectionId, StreamControl};
 use std::{sync::Arc, time::Duration};
 use tokio::{net::TcpStream, sync::Notify};
 
@@ -13,6 +13,34 @@ mod outbound;
 // TODO: make this configurable
 const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
 
+/// Number of messages either side of the proxy is allowed to send before having to wait for
+/// the remote to credit them back, i.e. to confirm it has written them onto its socket.
+pub const SEND_WINDOW: u32 = 32;
+
+/// The credit is returned in batches so that we wouldn't have to send a control message
+/// for every single data message we've received.
+const CREDIT_BATCH: u32 = SEND_WINDOW / 4;
+
+/// Channel used by the outbound half of the proxy to pass the credit granted by the remote
+/// onto the inbound half.
+type CreditSender = mpsc::UnboundedSender<u32>;
+type CreditReceiver = mpsc::UnboundedReceiver<u32>;
+
+/// Whether the credit-based flow control is used on the connection. Both sides have to support it,
+/// otherwise the data is sent without any limits, as it used to be.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlowControl {
+    /// Both sides are known to support the flow control.
+    Enabled,
+
+    /// The remote doesn't support the flow control.
+    Disabled,
+
+    /// It's not yet known whether the remote supports the flow control. It's enabled as soon as
+    /// the remote grants us any credit, which it's going to do straight away if it supports it.
+    Pending,
+}
+
 #[derive(Debug)]
 pub struct ProxyMessage {
     pub data: Vec<u8>,
@@ -69,15 +97,24 @@ where
     }
 
     // The `adapter_fn` is used to transform whatever was read into appropriate
-    // request/response as required by entity running particular side of the proxy.
-    pub async fn run<F>(mut self, adapter_fn: F) -> Self
+    // request/response as required by entity running particular side of the proxy,
+    // while the `control_adapter_fn` does the same for the flow control and reset messages.
+    pub async fn run<F, C>(
+        mut self,
+        flow_control: FlowControl,
+        adapter_fn: F,
+        control_adapter_fn: C,
+    ) -> Self
     where
         F: Fn(ConnectionId, Vec<u8>, bool) -> S + Send + 'static,
+        C: Fn(StreamControl) -> S + Send + 'static,
     {
         let (read_half, write_half) = self.socket.take().unwrap().into_split();
-        let shutdown_notify = Arc::new(Notify::new());
+        let local_closed_notify = Arc::new(Notify::new());
+        let remote_closed_notify = Arc::new(Notify::new());
+        let (credit_sender, credit_receiver) = mpsc::unbounded();
 
-        // should run until either inbound closes or is notified from outbound
+        // should run until either inbound closes, is notified from outbound or outbound is gone
         let inbound_future = inbound::run_inbound(
             read_half,
             self.local_destination_address.clone(),
@@ -85,16 +122,25 @@ where
             self.connection_id,
             self.mix_sender.clone(),
             adapter_fn,
-            Arc::clone(&shutdown_notify),
+            flow_control,
+            credit_receiver,
+            Arc::clone(&local_closed_notify),
+            Arc::clone(&remote_closed_notify),
         );
 
+        // should run until both sides are closed, is notified from inbound or the connection is reset
         let outbound_future = outbound::run_outbound(
             write_half,
             self.local_destination_address.clone(),
             self.remote_source_address.clone(),
             self.mix_receiver.take().unwrap(),
+            self.mix_sender.clone(),
+            control_adapter_fn,
             self.connection_id,
-            shutdown_notify,
+            flow_control,
+            credit_sender,
+            local_closed_notify,
+            remote_closed_notify,
         );
 
         // TODO: this shouldn't really have to spawn tasks inside "library" code, but
@@ -1,12 +1,11 @@
 // Copyright 2021 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
-use super::SHUTDOWN_TIMEOUT;
+use super::{CreditSender, FlowControl, MixProxySender, CREDIT_BATCH, SHUTDOWN_TIMEOUT};
 use crate::connection_controller::{ConnectionMessage, ConnectionReceiver};
-use futures::FutureExt;
 use futures::StreamExt;
 use log::*;
-use socks5_requests::ConnectionId;
+use socks5_requests::{ConnectionId, StreamControl};
 use std::{sync::Arc, time::Duration};
 use tokio::io::AsyncWriteExt;
 use tokio::select;
@@ -14,67 +13,206 @@ use tokio::{net::tcp::OwnedWriteHalf, sync::Notify, time::sleep, time::Instant};
 
 const MIX_TTL: Duration = Duration::from_secs(5 * 60);
 
-async fn deal_with_message(
-    connection_message: ConnectionMessage,
+/// What the outbound half should do after handling a message from the mix network.
+enum Outcome {
+    Continue,
+    RemoteClosed,
+    /// The remote has reset the connection, so we just stop.
+    Reset,
+    /// We have to abort the connection and tell the remote about it.
+    Abort,
+}
+
+async fn deal_with_data(
+    payload: Vec<u8>,
+    socket_closed: bool,
     writer: &mut OwnedWriteHalf,
     local_destination_address: &str,
     remote_source_address: &str,
     connection_id: ConnectionId,
-) -> bool {
+) -> Outcome {
     debug!(
         target: &*format!("({}) socks5 outbound", connection_id),
         "[{} bytes]\t{} → remote → mixnet → local → {} Remote closed: {}",
-        connection_message.payload.len(),
+        payload.len(),
         remote_source_address,
         local_destination_address,
-        connection_message.socket_closed
+        socket_closed
     );
 
-    if let Err(err) = writer.write_all(&connection_message.payload).await {
+    if let Err(err) = writer.write_all(&payload).await {
         // the other half is probably going to blow up too (if not, this task also needs to notify the other one!!)
         error!(target: &*format!("({}) socks5 outbound", connection_id), "failed to write response back to the socket - {}", err);
-        return true;
+        return Outcome::Abort;
     }
-    if connection_message.socket_closed {
+    if socket_closed {
         debug!(target: &*format!("({}) socks5 outbound", connection_id),
                "Remote socket got closed - closing the local socket too");
-        return true;
+        // let the local side know there's nothing more coming, but keep the connection alive
+        // so that it could still finish sending its own data
+        if let Err(err) = writer.shutdown().await {
+            debug!(target: &*format!("({}) socks5 outbound", connection_id), "failed to shutdown the socket - {}", err);
+        }
+        return Outcome::RemoteClosed;
+    }
+    Outcome::Continue
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn deal_with_message(
+    connection_message: ConnectionMessage,
+    writer: &mut OwnedWriteHalf,
+    local_destination_address: &str,
+    remote_source_address: &str,
+    connection_id: ConnectionId,
+    flow_control: &mut FlowControl,
+    credit_sender: &CreditSender,
+    pending_credit: &mut u32,
+    remote_closed: bool,
+) -> Outcome {
+    match connection_message {
+        ConnectionMessage::Data {
+            payload,
+            socket_closed,
+            message_count,
+        } => {
+            if remote_closed {
+                warn!(
+                    "received data on {} after the remote was closed",
+                    connection_id
+                );
+                return Outcome::Continue;
+            }
+            // the remote that doesn't support the flow control is never going to be credited
+            if *flow_control != FlowControl::Disabled {
+                *pending_credit = pending_credit.saturating_add(message_count);
+            }
+            deal_with_data(
+                payload,
+                socket_closed,
+                writer,
+                local_destination_address,
+                remote_source_address,
+                connection_id,
+            )
+            .await
+        }
+        ConnectionMessage::Credit(credit) => {
+            // the remote has just told us it supports the flow control
+            if *flow_control == FlowControl::Pending {
+                debug!("remote uses flow control on {}", connection_id);
+                *flow_control = FlowControl::Enabled;
+            }
+            // inbound might have already finished, in which case it doesn't need the credit
+            let _ = credit_sender.unbounded_send(credit);
+            Outcome::Continue
+        }
+        ConnectionMessage::Reset => {
+            debug!(
+                "remote has reset {}. Shutting down the proxy",
+                connection_id
+            );
+            Outcome::Reset
+        }
+        ConnectionMessage::BufferOverflow => {
+            warn!(
+                "remote has sent more data than allowed on {}. Resetting the connection",
+                connection_id
+            );
+            Outcome::Abort
+        }
     }
-    false
 }
 
-pub(super) async fn run_outbound(
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn run_outbound<C, S>(
     mut writer: OwnedWriteHalf,
     local_destination_address: String, // addresses are provided for better logging
     remote_source_address: String,
     mut mix_receiver: ConnectionReceiver,
+    mix_sender: MixProxySender<S>,
+    control_adapter_fn: C,
     connection_id: ConnectionId,
-    shutdown_notify: Arc<Notify>,
-) -> (OwnedWriteHalf, ConnectionReceiver) {
-    let shutdown_future = shutdown_notify.notified().then(|_| sleep(SHUTDOWN_TIMEOUT));
-    tokio::pin!(shutdown_future);
+    mut flow_control: FlowControl,
+    credit_sender: CreditSender,
+    local_closed_notify: Arc<Notify>,
+    remote_closed_notify: Arc<Notify>,
+) -> (OwnedWriteHalf, ConnectionReceiver)
+where
+    C: Fn(StreamControl) -> S + Send + 'static,
+{
+    let local_closed_future = local_closed_notify.notified();
+    tokio::pin!(local_closed_future);
+
+    // only started once the local side is closed
+    let shutdown_timeout = sleep(SHUTDOWN_TIMEOUT);
+    tokio::pin!(shutdown_timeout);
 
     let mut mix_timeout = Box::pin(sleep(MIX_TTL));
 
+    let mut local_closed = false;
+    let mut remote_closed = false;
+
+    // number of messages we've written, but haven't credited back to the remote yet
+    let mut pending_credit = 0;
+
+    // the remote might not know yet whether we support the flow control, so let it know
+    // by granting it some credit straight away
+    if flow_control == FlowControl::Enabled {
+        let credit = StreamControl::Credit(connection_id, 0);
+        mix_sender
+            .unbounded_send(control_adapter_fn(credit))
+            .unwrap();
+    }
+
     loop {
         select! {
             connection_message = &mut mix_receiver.next() => {
-                if let Some(connection_message) = connection_message {
-                    if deal_with_message(connection_message, &mut writer, &local_destination_address, &remote_source_address, connection_id).await {
-                        break;
-                    }
-                    mix_timeout.as_mut().reset(Instant::now() + MIX_TTL);
+                let outcome = if let Some(connection_message) = connection_message {
+                    deal_with_message(connection_message, &mut writer, &local_destination_address, &remote_source_address, connection_id, &mut flow_control, &credit_sender, &mut pending_credit, remote_closed).await
                 } else {
                     warn!("mix receiver is none so we already got removed somewhere. This isn't really a warning, but shouldn't happen to begin with, so please say if you see this message");
                     break;
+                };
+
+                match outcome {
+                    Outcome::Continue => {
+                        // the remote that doesn't support the flow control wouldn't understand the credit
+                        if flow_control == FlowControl::Enabled && pending_credit >= CREDIT_BATCH {
+                            let credit = StreamControl::Credit(connection_id, pending_credit);
+                            mix_sender.unbounded_send(control_adapter_fn(credit)).unwrap();
+                            pending_credit = 0;
+                        }
+                    }
+                    Outcome::RemoteClosed => {
+                        remote_closed = true;
+                        if local_closed {
+                            break;
+                        }
+                        remote_closed_notify.notify_one();
+                    }
+                    Outcome::Reset => break,
+                    Outcome::Abort => {
+                        let reset = StreamControl::Reset(connection_id);
+                        mix_sender.unbounded_send(control_adapter_fn(reset)).unwrap();
+                        break;
+                    }
                 }
+                mix_timeout.as_mut().reset(Instant::now() + MIX_TTL);
             }
             _ = &mut mix_timeout => {
                 warn!("didn't get anything from the client on {} mixnet in {:?}. Shutting down the proxy.", connection_id, MIX_TTL);
                 // If they were online it's kinda their fault they didn't send any heartbeat messages.
                 break;
             }
-            _ = &mut shutdown_future => {
+            _ = &mut local_closed_future, if !local_closed => {
+                if remote_closed {
+                    break;
+                }
+                local_closed = true;
+                shutdown_timeout.as_mut().reset(Instant::now() + SHUTDOWN_TIMEOUT);
+            }
+            _ = &mut shutdown_timeout, if local_closed => {
                 debug!("closing outbound proxy after inbound was closed {:?} ago", SHUTDOWN_TIMEOUT);
                 break;
             }
@@ -82,7 +220,7 @@ pub(super) async fn run_outbound(
     }
 
     trace!("{} - outbound closed", connection_id);
-    shutdown_notify.notify_one();
+    // dropping the credit sender makes the inbound finish if it's still running
 
     (writer, mix_receiver)
 }
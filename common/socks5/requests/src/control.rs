@@ -0,0 +1,161 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::ConnectionId;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StreamControlError {
+    ConnectionIdTooShort,
+    CreditTooShort,
+    NoData,
+    UnknownControlFlag,
+}
+
+impl fmt::Display for StreamControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamControlError::ConnectionIdTooShort => {
+                write!(f, "not enough bytes to recover the connection id")
+            }
+            StreamControlError::CreditTooShort => {
+                write!(f, "not enough bytes to recover the granted credit")
+            }
+            StreamControlError::NoData => write!(f, "no data provided"),
+            StreamControlError::UnknownControlFlag => write!(f, "control message of unknown type"),
+        }
+    }
+}
+
+impl std::error::Error for StreamControlError {}
+
+/// Flow control and teardown of a proxied connection. Unlike the data itself, those are not
+/// ordered and can be sent by either side of the connection.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StreamControl {
+    /// The sender has consumed the given number of data messages of this `ConnectionId`,
+    /// so the remote is allowed to send that many more.
+    Credit(ConnectionId, u32),
+
+    /// The connection has been aborted and any state associated with it should be dropped
+    /// without waiting for the remaining data.
+    Reset(ConnectionId),
+}
+
+impl StreamControl {
+    const CREDIT_FLAG: u8 = 0;
+    const RESET_FLAG: u8 = 1;
+
+    pub fn connection_id(&self) -> ConnectionId {
+        match self {
+            StreamControl::Credit(conn_id, _) => *conn_id,
+            StreamControl::Reset(conn_id) => *conn_id,
+        }
+    }
+
+    pub fn try_from_bytes(b: &[u8]) -> Result<StreamControl, StreamControlError> {
+        if b.is_empty() {
+            return Err(StreamControlError::NoData);
+        }
+
+        if b.len() < 9 {
+            return Err(StreamControlError::ConnectionIdTooShort);
+        }
+        let connection_id = u64::from_be_bytes([b[1], b[2], b[3], b[4], b[5], b[6], b[7], b[8]]);
+
+        match b[0] {
+            Self::CREDIT_FLAG => {
+                if b.len() < 13 {
+                    return Err(StreamControlError::CreditTooShort);
+                }
+                let credit = u32::from_be_bytes([b[9], b[10], b[11], b[12]]);
+                Ok(StreamControl::Credit(connection_id, credit))
+            }
+            Self::RESET_FLAG => Ok(StreamControl::Reset(connection_id)),
+            _ => Err(StreamControlError::UnknownControlFlag),
+        }
+    }
+
+    /// Serializes the control message as `CONTROL_FLAG || CONN_ID || CREDIT`, where the credit
+    /// is only present for the `Credit` messages.
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            StreamControl::Credit(conn_id, credit) => std::iter::once(Self::CREDIT_FLAG)
+                .chain(conn_id.to_be_bytes().iter().cloned())
+                .chain(credit.to_be_bytes().iter().cloned())
+                .collect(),
+            StreamControl::Reset(conn_id) => std::iter::once(Self::RESET_FLAG)
+                .chain(conn_id.to_be_bytes().iter().cloned())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod stream_control_tests {
+    use super::*;
+
+    #[test]
+    fn credit_serialization_works() {
+        let control = StreamControl::Credit(42, 16);
+        let bytes = control.into_bytes();
+        assert_eq!(
+            bytes,
+            vec![
+                StreamControl::CREDIT_FLAG,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                42,
+                0,
+                0,
+                0,
+                16
+            ]
+        );
+        assert_eq!(
+            StreamControl::try_from_bytes(&bytes).unwrap(),
+            StreamControl::Credit(42, 16)
+        );
+    }
+
+    #[test]
+    fn reset_serialization_works() {
+        let bytes = StreamControl::Reset(42).into_bytes();
+        assert_eq!(
+            StreamControl::try_from_bytes(&bytes).unwrap(),
+            StreamControl::Reset(42)
+        );
+    }
+
+    #[test]
+    fn returns_error_when_credit_is_too_short() {
+        let bytes = [StreamControl::CREDIT_FLAG, 0, 0, 0, 0, 0, 0, 0, 42, 0, 0];
+        assert_eq!(
+            StreamControl::try_from_bytes(&bytes).unwrap_err(),
+            StreamControlError::CreditTooShort
+        );
+    }
+
+    #[test]
+    fn returns_error_when_connection_id_is_too_short() {
+        let bytes = [StreamControl::RESET_FLAG, 0, 0, 0, 42];
+        assert_eq!(
+            StreamControl::try_from_bytes(&bytes).unwrap_err(),
+            StreamControlError::ConnectionIdTooShort
+        );
+    }
+
+    #[test]
+    fn returns_error_for_unknown_flag() {
+        let bytes = [255, 0, 0, 0, 0, 0, 0, 0, 42];
+        assert_eq!(
+            StreamControl::try_from_bytes(&bytes).unwrap_err(),
+            StreamControlError::UnknownControlFlag
+        );
+    }
+}
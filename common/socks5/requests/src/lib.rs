@@ -1,7 +1,9 @@
+pub mod control;
 pub mod msg;
 pub mod request;
 pub mod response;
 
+pub use control::*;
 pub use msg::*;
 pub use request::*;
 pub use response::*;
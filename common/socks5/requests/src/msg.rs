@@ -1,13 +1,15 @@
 // Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::control::{StreamControl, StreamControlError};
 use crate::request::{Request, RequestError};
-use crate::response::{Response, ResponseError};
+use crate::response::{ConnectionStatusResponse, DatagramResponse, Response, ResponseError};
 
 #[derive(Debug)]
 pub enum MessageError {
     Request(RequestError),
     Response(ResponseError),
+    StreamControl(StreamControlError),
     NoData,
     UnknownMessageType,
 }
@@ -17,6 +19,7 @@ impl std::fmt::Display for MessageError {
         match self {
             MessageError::Request(r) => write!(f, "{}", r),
             MessageError::Response(r) => write!(f, "{:?}", r),
+            MessageError::StreamControl(c) => write!(f, "{}", c),
             MessageError::NoData => write!(f, "no data provided"),
             MessageError::UnknownMessageType => write!(f, "unknown message type received"),
         }
@@ -26,19 +29,32 @@ impl std::fmt::Display for MessageError {
 pub enum Message {
     Request(Request),
     Response(Response),
+    Datagram(DatagramResponse),
+    ConnectionStatus(ConnectionStatusResponse),
+    StreamControl(StreamControl),
 }
 
 impl Message {
     const REQUEST_FLAG: u8 = 0;
     const RESPONSE_FLAG: u8 = 1;
+    const DATAGRAM_FLAG: u8 = 2;
+    const CONNECTION_STATUS_FLAG: u8 = 3;
+    const STREAM_CONTROL_FLAG: u8 = 4;
 
     pub fn conn_id(&self) -> u64 {
         match self {
             Message::Request(req) => match req {
                 Request::Connect(c) => c.conn_id,
                 Request::Send(conn_id, _, _) => *conn_id,
+                Request::UdpAssociate(req) => req.conn_id,
+                Request::UdpSend(req) => req.conn_id,
+                Request::UdpClose(req) => req.conn_id,
+                Request::Ping(req) => req.probe_id,
             },
             Message::Response(resp) => resp.connection_id,
+            Message::Datagram(datagram) => datagram.connection_id,
+            Message::ConnectionStatus(status) => status.connection_id,
+            Message::StreamControl(control) => control.connection_id(),
         }
     }
 
@@ -47,8 +63,12 @@ impl Message {
             Message::Request(req) => match req {
                 Request::Connect(_) => 0,
                 Request::Send(_, data, _) => data.len(),
+                Request::UdpAssociate(_) | Request::UdpClose(_) | Request::Ping(_) => 0,
+                Request::UdpSend(req) => req.data.len(),
             },
             Message::Response(resp) => resp.data.len(),
+            Message::Datagram(datagram) => datagram.data.len(),
+            Message::ConnectionStatus(_) | Message::StreamControl(_) => 0,
         }
     }
 
@@ -65,6 +85,18 @@ impl Message {
             Response::try_from_bytes(&b[1..])
                 .map(Message::Response)
                 .map_err(MessageError::Response)
+        } else if b[0] == Self::DATAGRAM_FLAG {
+            DatagramResponse::try_from_bytes(&b[1..])
+                .map(Message::Datagram)
+                .map_err(MessageError::Response)
+        } else if b[0] == Self::CONNECTION_STATUS_FLAG {
+            ConnectionStatusResponse::try_from_bytes(&b[1..])
+                .map(Message::ConnectionStatus)
+                .map_err(MessageError::Response)
+        } else if b[0] == Self::STREAM_CONTROL_FLAG {
+            StreamControl::try_from_bytes(&b[1..])
+                .map(Message::StreamControl)
+                .map_err(MessageError::StreamControl)
         } else {
             Err(MessageError::UnknownMessageType)
         }
@@ -78,6 +110,15 @@ impl Message {
             Self::Response(r) => std::iter::once(Self::RESPONSE_FLAG)
                 .chain(r.into_bytes().iter().cloned())
                 .collect(),
+            Self::Datagram(d) => std::iter::once(Self::DATAGRAM_FLAG)
+                .chain(d.into_bytes().iter().cloned())
+                .collect(),
+            Self::ConnectionStatus(s) => std::iter::once(Self::CONNECTION_STATUS_FLAG)
+                .chain(s.into_bytes().iter().cloned())
+                .collect(),
+            Self::StreamControl(c) => std::iter::once(Self::STREAM_CONTROL_FLAG)
+                .chain(c.into_bytes().iter().cloned())
+                .collect(),
         }
     }
 }
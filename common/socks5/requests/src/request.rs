@@ -10,6 +10,10 @@ pub type RemoteAddress = String;
 pub enum RequestFlag {
     Connect = 0,
     Send = 1,
+    UdpAssociate = 2,
+    UdpSend = 3,
+    UdpClose = 4,
+    Ping = 5,
 }
 
 #[derive(Debug)]
@@ -58,6 +62,10 @@ impl TryFrom<u8> for RequestFlag {
         match value {
             _ if value == (RequestFlag::Connect as u8) => Ok(Self::Connect),
             _ if value == (RequestFlag::Send as u8) => Ok(Self::Send),
+            _ if value == (RequestFlag::UdpAssociate as u8) => Ok(Self::UdpAssociate),
+            _ if value == (RequestFlag::UdpSend as u8) => Ok(Self::UdpSend),
+            _ if value == (RequestFlag::UdpClose as u8) => Ok(Self::UdpClose),
+            _ if value == (RequestFlag::Ping as u8) => Ok(Self::Ping),
             _ => Err(RequestError::UnknownRequestFlag),
         }
     }
@@ -70,6 +78,32 @@ pub struct ConnectRequest {
     pub return_address: Recipient,
 }
 
+#[derive(Debug)]
+pub struct UdpAssociateRequest {
+    pub conn_id: ConnectionId,
+    pub return_address: Recipient,
+}
+
+#[derive(Debug)]
+pub struct UdpSendRequest {
+    pub conn_id: ConnectionId,
+    pub remote_addr: RemoteAddress,
+    pub data: Vec<u8>,
+    pub return_address: Recipient,
+}
+
+#[derive(Debug)]
+pub struct UdpCloseRequest {
+    pub conn_id: ConnectionId,
+    pub return_address: Recipient,
+}
+
+#[derive(Debug)]
+pub struct PingRequest {
+    pub probe_id: ConnectionId,
+    pub return_address: Recipient,
+}
+
 /// A request from a SOCKS5 client that a Nym Socks5 service provider should
 /// take an action for an application using a (probably local) Nym Socks5 proxy.
 #[derive(Debug)]
@@ -81,6 +115,22 @@ pub enum Request {
 
     /// Re-use an existing TCP connection, sending more request data up it.
     Send(ConnectionId, Vec<u8>, bool),
+
+    /// Open a new UDP association, i.e. bind a UDP socket that's going to relay the datagrams
+    /// of this `ConnectionId`. All the datagrams received on it should come back to the specified `Recipient`
+    UdpAssociate(Box<UdpAssociateRequest>),
+
+    /// Send a single datagram to the specified `RemoteAddress` using an existing UDP association.
+    /// The `Recipient` has to match the one the association was opened by.
+    UdpSend(Box<UdpSendRequest>),
+
+    /// Close an existing UDP association. The `Recipient` has to match the one the association
+    /// was opened by.
+    UdpClose(Box<UdpCloseRequest>),
+
+    /// Check whether the service provider is alive. It should respond to the specified `Recipient`
+    /// with a successful connection status for the probe id.
+    Ping(Box<PingRequest>),
 }
 
 impl Request {
@@ -102,6 +152,84 @@ impl Request {
         Request::Send(conn_id, data, local_closed)
     }
 
+    /// Construct a new Request::UdpAssociate instance
+    pub fn new_udp_associate(conn_id: ConnectionId, return_address: Recipient) -> Request {
+        Request::UdpAssociate(Box::new(UdpAssociateRequest {
+            conn_id,
+            return_address,
+        }))
+    }
+
+    /// Construct a new Request::UdpSend instance
+    pub fn new_udp_send(
+        conn_id: ConnectionId,
+        remote_addr: RemoteAddress,
+        data: Vec<u8>,
+        return_address: Recipient,
+    ) -> Request {
+        Request::UdpSend(Box::new(UdpSendRequest {
+            conn_id,
+            remote_addr,
+            data,
+            return_address,
+        }))
+    }
+
+    /// Construct a new Request::UdpClose instance
+    pub fn new_udp_close(conn_id: ConnectionId, return_address: Recipient) -> Request {
+        Request::UdpClose(Box::new(UdpCloseRequest {
+            conn_id,
+            return_address,
+        }))
+    }
+
+    /// Construct a new Request::Ping instance
+    pub fn new_ping(probe_id: ConnectionId, return_address: Recipient) -> Request {
+        Request::Ping(Box::new(PingRequest {
+            probe_id,
+            return_address,
+        }))
+    }
+
+    // recovers the `address_length || address` prefix, returning the address alongside
+    // the remaining bytes
+    fn parse_remote_address(b: &[u8]) -> Result<(RemoteAddress, &[u8]), RequestError> {
+        // we need to be able to read at least 2 bytes that specify address length
+        if b.len() < 2 {
+            return Err(RequestError::AddressLengthTooShort);
+        }
+
+        let address_length = u16::from_be_bytes([b[0], b[1]]) as usize;
+        if b.len() < 2 + address_length {
+            return Err(RequestError::AddressTooShort);
+        }
+
+        let address_end = 2 + address_length;
+        let remote_address = String::from_utf8_lossy(&b[2..address_end]).to_string();
+        Ok((remote_address, &b[address_end..]))
+    }
+
+    fn parse_return_address(b: &[u8]) -> Result<Recipient, RequestError> {
+        if b.len() != Recipient::LEN {
+            return Err(RequestError::ReturnAddressTooShort);
+        }
+
+        Self::parse_return_address_prefix(b).map(|(return_address, _)| return_address)
+    }
+
+    // recovers the return address the bytes start with, returning it alongside the remaining bytes
+    fn parse_return_address_prefix(b: &[u8]) -> Result<(Recipient, &[u8]), RequestError> {
+        if b.len() < Recipient::LEN {
+            return Err(RequestError::ReturnAddressTooShort);
+        }
+
+        let mut return_bytes = [0u8; Recipient::LEN];
+        return_bytes.copy_from_slice(&b[..Recipient::LEN]);
+        let return_address = Recipient::try_from_bytes(return_bytes)
+            .map_err(RequestError::MalformedReturnAddress)?;
+        Ok((return_address, &b[Recipient::LEN..]))
+    }
+
     /// Deserialize the request type, connection id, destination address and port,
     /// and the request body from bytes.
     ///
@@ -115,6 +243,11 @@ impl Request {
     /// The request_flag tells us whether this is a new connection request (`new_connect`),
     /// an already-established connection we should send up (`new_send`), or
     /// a request to close an established connection (`new_close`).
+    ///
+    /// The UDP requests are laid out similarly: `new_udp_associate` carries only the return address
+    /// after the connection id, `new_udp_send` carries the address, the return address and
+    /// the datagram itself, while `new_udp_close` carries just the return address. Finally, `new_ping` carries the probe id
+    /// in place of the connection id, followed by the return address.
     pub fn try_from_bytes(b: &[u8]) -> Result<Request, RequestError> {
         // each request needs to at least contain flag and ConnectionId
         if b.is_empty() {
@@ -127,37 +260,8 @@ impl Request {
         let connection_id = u64::from_be_bytes([b[1], b[2], b[3], b[4], b[5], b[6], b[7], b[8]]);
         match RequestFlag::try_from(b[0])? {
             RequestFlag::Connect => {
-                let connect_request_bytes = &b[9..];
-
-                // we need to be able to read at least 2 bytes that specify address length
-                if connect_request_bytes.len() < 2 {
-                    return Err(RequestError::AddressLengthTooShort);
-                }
-
-                let address_length =
-                    u16::from_be_bytes([connect_request_bytes[0], connect_request_bytes[1]])
-                        as usize;
-
-                if connect_request_bytes.len() < 2 + address_length {
-                    return Err(RequestError::AddressTooShort);
-                }
-
-                let address_start = 2;
-                let address_end = address_start + address_length;
-                let address_bytes = &connect_request_bytes[address_start..address_end];
-                let remote_address = String::from_utf8_lossy(address_bytes).to_string();
-
-                // just a temporary reference to mid-slice for ease of use
-                let recipient_data_bytes = &connect_request_bytes[address_end..];
-
-                if recipient_data_bytes.len() != Recipient::LEN {
-                    return Err(RequestError::ReturnAddressTooShort);
-                }
-
-                let mut return_bytes = [0u8; Recipient::LEN];
-                return_bytes.copy_from_slice(&recipient_data_bytes[..Recipient::LEN]);
-                let return_address = Recipient::try_from_bytes(return_bytes)
-                    .map_err(RequestError::MalformedReturnAddress)?;
+                let (remote_address, recipient_data_bytes) = Self::parse_remote_address(&b[9..])?;
+                let return_address = Self::parse_return_address(recipient_data_bytes)?;
 
                 Ok(Request::new_connect(
                     connection_id,
@@ -171,6 +275,29 @@ impl Request {
 
                 Ok(Request::Send(connection_id, data, local_closed))
             }
+            RequestFlag::UdpAssociate => {
+                let return_address = Self::parse_return_address(&b[9..])?;
+                Ok(Request::new_udp_associate(connection_id, return_address))
+            }
+            RequestFlag::UdpSend => {
+                let (remote_address, recipient_data_bytes) = Self::parse_remote_address(&b[9..])?;
+                let (return_address, data) =
+                    Self::parse_return_address_prefix(recipient_data_bytes)?;
+                Ok(Request::new_udp_send(
+                    connection_id,
+                    remote_address,
+                    data.to_vec(),
+                    return_address,
+                ))
+            }
+            RequestFlag::UdpClose => {
+                let return_address = Self::parse_return_address(&b[9..])?;
+                Ok(Request::new_udp_close(connection_id, return_address))
+            }
+            RequestFlag::Ping => {
+                let return_address = Self::parse_return_address(&b[9..])?;
+                Ok(Request::new_ping(connection_id, return_address))
+            }
         }
     }
 
@@ -196,6 +323,34 @@ impl Request {
                 .chain(std::iter::once(local_closed as u8))
                 .chain(data.into_iter())
                 .collect(),
+            // udp associate is: UDP_ASSOCIATE_FLAG || CONN_ID || RETURN
+            Request::UdpAssociate(req) => std::iter::once(RequestFlag::UdpAssociate as u8)
+                .chain(req.conn_id.to_be_bytes().iter().cloned())
+                .chain(req.return_address.to_bytes().iter().cloned())
+                .collect(),
+            // udp send is: UDP_SEND_FLAG || CONN_ID || REMOTE_LEN || REMOTE || RETURN || DATA
+            Request::UdpSend(req) => {
+                let remote_address_bytes = req.remote_addr.into_bytes();
+                let remote_address_bytes_len = remote_address_bytes.len() as u16;
+
+                std::iter::once(RequestFlag::UdpSend as u8)
+                    .chain(req.conn_id.to_be_bytes().iter().cloned())
+                    .chain(remote_address_bytes_len.to_be_bytes().iter().cloned())
+                    .chain(remote_address_bytes.into_iter())
+                    .chain(req.return_address.to_bytes().iter().cloned())
+                    .chain(req.data.into_iter())
+                    .collect()
+            }
+            // udp close is: UDP_CLOSE_FLAG || CONN_ID || RETURN
+            Request::UdpClose(req) => std::iter::once(RequestFlag::UdpClose as u8)
+                .chain(req.conn_id.to_be_bytes().iter().cloned())
+                .chain(req.return_address.to_bytes().iter().cloned())
+                .collect(),
+            // ping is: PING_FLAG || PROBE_ID || RETURN
+            Request::Ping(req) => std::iter::once(RequestFlag::Ping as u8)
+                .chain(req.probe_id.to_be_bytes().iter().cloned())
+                .chain(req.return_address.to_bytes().iter().cloned())
+                .collect(),
         }
     }
 }
@@ -482,4 +637,121 @@ mod request_deserialization_tests {
             }
         }
     }
+
+    #[cfg(test)]
+    mod udp_associations {
+        use super::*;
+
+        #[test]
+        fn udp_associate_request_serialization_works() {
+            let recipient = Recipient::try_from_base58_string("CytBseW6yFXUMzz4SGAKdNLGR7q3sJLLYxyBGvutNEQV.4QXYyEVc5fUDjmmi8PrHN9tdUFV4PCvSJE1278cHyvoe@4sBbL1ngf1vtNqykydQKTFh26sQCw888GpUqvPvyNB4f").unwrap();
+            let request_bytes = Request::new_udp_associate(42, recipient).into_bytes();
+
+            match Request::try_from_bytes(&request_bytes).unwrap() {
+                Request::UdpAssociate(req) => {
+                    assert_eq!(42, req.conn_id);
+                    assert_eq!(
+                        req.return_address.to_bytes().to_vec(),
+                        recipient.to_bytes().to_vec()
+                    );
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        #[test]
+        fn udp_send_request_serialization_works() {
+            let recipient = Recipient::try_from_base58_string("CytBseW6yFXUMzz4SGAKdNLGR7q3sJLLYxyBGvutNEQV.4QXYyEVc5fUDjmmi8PrHN9tdUFV4PCvSJE1278cHyvoe@4sBbL1ngf1vtNqykydQKTFh26sQCw888GpUqvPvyNB4f").unwrap();
+            let request_bytes =
+                Request::new_udp_send(42, "1.1.1.1:53".to_string(), vec![1, 2, 3], recipient)
+                    .into_bytes();
+
+            match Request::try_from_bytes(&request_bytes).unwrap() {
+                Request::UdpSend(req) => {
+                    assert_eq!(42, req.conn_id);
+                    assert_eq!("1.1.1.1:53".to_string(), req.remote_addr);
+                    assert_eq!(vec![1, 2, 3], req.data);
+                    assert_eq!(
+                        req.return_address.to_bytes().to_vec(),
+                        recipient.to_bytes().to_vec()
+                    );
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        #[test]
+        fn udp_send_request_returns_error_when_return_address_is_missing() {
+            let request_bytes = [
+                RequestFlag::UdpSend as u8,
+                1,
+                2,
+                3,
+                4,
+                5,
+                6,
+                7,
+                8,
+                0,
+                1,
+                1,
+                1,
+                2,
+                3,
+            ]
+            .to_vec(); // 2 bytes address length, 1 byte of address, 3 bytes of data
+            match Request::try_from_bytes(&request_bytes).unwrap_err() {
+                RequestError::ReturnAddressTooShort => {}
+                _ => unreachable!(),
+            }
+        }
+
+        #[test]
+        fn udp_send_request_returns_error_when_address_too_short() {
+            let request_bytes =
+                [RequestFlag::UdpSend as u8, 1, 2, 3, 4, 5, 6, 7, 8, 0, 5, 1].to_vec(); // 2 bytes address length, 1 byte of address
+            match Request::try_from_bytes(&request_bytes).unwrap_err() {
+                RequestError::AddressTooShort => {}
+                _ => unreachable!(),
+            }
+        }
+
+        #[test]
+        fn udp_close_request_serialization_works() {
+            let recipient = Recipient::try_from_base58_string("CytBseW6yFXUMzz4SGAKdNLGR7q3sJLLYxyBGvutNEQV.4QXYyEVc5fUDjmmi8PrHN9tdUFV4PCvSJE1278cHyvoe@4sBbL1ngf1vtNqykydQKTFh26sQCw888GpUqvPvyNB4f").unwrap();
+            let request_bytes = Request::new_udp_close(42, recipient).into_bytes();
+            match Request::try_from_bytes(&request_bytes).unwrap() {
+                Request::UdpClose(req) => {
+                    assert_eq!(42, req.conn_id);
+                    assert_eq!(
+                        req.return_address.to_bytes().to_vec(),
+                        recipient.to_bytes().to_vec()
+                    );
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod probing_the_provider {
+        use super::*;
+
+        #[test]
+        fn ping_request_serialization_works() {
+            let recipient = Recipient::try_from_base58_string("CytBseW6yFXUMzz4SGAKdNLGR7q3sJLLYxyBGvutNEQV.4QXYyEVc5fUDjmmi8PrHN9tdUFV4PCvSJE1278cHyvoe@4sBbL1ngf1vtNqykydQKTFh26sQCw888GpUqvPvyNB4f").unwrap();
+            let request_bytes = Request::new_ping(42, recipient).into_bytes();
+
+            match Request::try_from_bytes(&request_bytes).unwrap() {
+                Request::Ping(req) => {
+                    assert_eq!(42, req.probe_id);
+                    assert_eq!(
+                        req.return_address.to_bytes().to_vec(),
+                        recipient.to_bytes().to_vec()
+                    );
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
 }
@@ -1,9 +1,15 @@
-use crate::ConnectionId;
+use crate::{ConnectionId, RemoteAddress};
+use std::fmt;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ResponseError {
+    AddressLengthTooShort,
+    AddressTooShort,
+    CapabilitiesTooShort,
     ConnectionIdTooShort,
     NoData,
+    StatusTooShort,
+    UnsupportedVersion(u8),
 }
 /// A remote network response retrieved by the Socks5 service provider. This
 /// can be serialized and sent back through the mixnet to the requesting
@@ -64,6 +70,206 @@ impl Response {
     }
 }
 
+/// A datagram received by the Socks5 service provider on a UDP association. This can be
+/// serialized and sent back through the mixnet to the requesting application.
+#[derive(Debug)]
+pub struct DatagramResponse {
+    pub data: Vec<u8>,
+    pub connection_id: ConnectionId,
+    pub source_address: RemoteAddress,
+}
+
+impl DatagramResponse {
+    /// Constructor for datagram responses
+    pub fn new(connection_id: ConnectionId, source_address: RemoteAddress, data: Vec<u8>) -> Self {
+        DatagramResponse {
+            data,
+            connection_id,
+            source_address,
+        }
+    }
+
+    pub fn try_from_bytes(b: &[u8]) -> Result<DatagramResponse, ResponseError> {
+        if b.is_empty() {
+            return Err(ResponseError::NoData);
+        }
+
+        if b.len() < 8 {
+            return Err(ResponseError::ConnectionIdTooShort);
+        }
+        let connection_id = u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]);
+
+        if b.len() < 10 {
+            return Err(ResponseError::AddressLengthTooShort);
+        }
+        let address_length = u16::from_be_bytes([b[8], b[9]]) as usize;
+        let address_end = 10 + address_length;
+        if b.len() < address_end {
+            return Err(ResponseError::AddressTooShort);
+        }
+        let source_address = String::from_utf8_lossy(&b[10..address_end]).to_string();
+
+        Ok(DatagramResponse::new(
+            connection_id,
+            source_address,
+            b[address_end..].to_vec(),
+        ))
+    }
+
+    /// Serializes the datagram as `CONN_ID || SOURCE_LEN || SOURCE || DATA` so that it can be
+    /// sent back through the mixnet to the requesting application.
+    pub fn into_bytes(self) -> Vec<u8> {
+        let source_address_bytes = self.source_address.into_bytes();
+        let source_address_bytes_len = source_address_bytes.len() as u16;
+
+        self.connection_id
+            .to_be_bytes()
+            .iter()
+            .cloned()
+            .chain(source_address_bytes_len.to_be_bytes().iter().cloned())
+            .chain(source_address_bytes.into_iter())
+            .chain(self.data.into_iter())
+            .collect()
+    }
+}
+
+/// Reasons for which the Socks5 service provider could have failed to establish a connection.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionError {
+    /// The remote host is not on the list of hosts the service provider is allowed to connect to.
+    HostNotAllowed = 1,
+    /// The address of the remote host could not be resolved.
+    DnsFailure = 2,
+    /// The remote host has refused the connection.
+    ConnectionRefused = 3,
+    /// The remote host has not responded in time.
+    Timeout = 4,
+    /// Any other failure.
+    Other = 255,
+}
+
+impl From<u8> for ConnectionError {
+    fn from(value: u8) -> Self {
+        match value {
+            _ if value == (ConnectionError::HostNotAllowed as u8) => {
+                ConnectionError::HostNotAllowed
+            }
+            _ if value == (ConnectionError::DnsFailure as u8) => ConnectionError::DnsFailure,
+            _ if value == (ConnectionError::ConnectionRefused as u8) => {
+                ConnectionError::ConnectionRefused
+            }
+            _ if value == (ConnectionError::Timeout as u8) => ConnectionError::Timeout,
+            // codes introduced by the future versions are treated as generic failures
+            _ => ConnectionError::Other,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::HostNotAllowed => write!(f, "host is not allowed"),
+            ConnectionError::DnsFailure => write!(f, "failed to resolve the host"),
+            ConnectionError::ConnectionRefused => write!(f, "connection refused"),
+            ConnectionError::Timeout => write!(f, "connection timed out"),
+            ConnectionError::Other => write!(f, "connection failed"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+/// Outcome of the `Connect` request, sent by the Socks5 service provider before any data
+/// of the connection, so that the requesting application could be told whether and why
+/// the connection has failed.
+#[derive(Debug)]
+pub struct ConnectionStatusResponse {
+    pub connection_id: ConnectionId,
+    pub result: Result<(), ConnectionError>,
+    /// Whether the service provider is going to use the credit-based flow control on this
+    /// connection. Providers not setting it expect the data to be sent without any limits.
+    pub flow_control: bool,
+}
+
+impl ConnectionStatusResponse {
+    /// Current version of the serialized status. Any future versions are only allowed to append
+    /// data after the status code.
+    pub const VERSION: u8 = 1;
+
+    const CONNECTED: u8 = 0;
+
+    const FLOW_CONTROL_CAPABILITY: u8 = 0b0000_0001;
+
+    pub fn new(connection_id: ConnectionId, result: Result<(), ConnectionError>) -> Self {
+        ConnectionStatusResponse {
+            connection_id,
+            result,
+            flow_control: false,
+        }
+    }
+
+    /// Announces that the service provider supports the credit-based flow control.
+    #[must_use]
+    pub fn with_flow_control(mut self) -> Self {
+        self.flow_control = true;
+        self
+    }
+
+    pub fn try_from_bytes(b: &[u8]) -> Result<ConnectionStatusResponse, ResponseError> {
+        if b.is_empty() {
+            return Err(ResponseError::NoData);
+        }
+
+        let version = b[0];
+        if version < Self::VERSION {
+            return Err(ResponseError::UnsupportedVersion(version));
+        }
+
+        if b.len() < 9 {
+            return Err(ResponseError::ConnectionIdTooShort);
+        }
+        let connection_id = u64::from_be_bytes([b[1], b[2], b[3], b[4], b[5], b[6], b[7], b[8]]);
+
+        let result = match b.get(9) {
+            None => return Err(ResponseError::StatusTooShort),
+            Some(&Self::CONNECTED) => Ok(()),
+            Some(&code) => Err(ConnectionError::from(code)),
+        };
+
+        let capabilities = match b.get(10) {
+            None => return Err(ResponseError::CapabilitiesTooShort),
+            Some(&capabilities) => capabilities,
+        };
+
+        Ok(ConnectionStatusResponse {
+            connection_id,
+            result,
+            flow_control: capabilities & Self::FLOW_CONTROL_CAPABILITY != 0,
+        })
+    }
+
+    /// Serializes the status as `VERSION || CONN_ID || STATUS_CODE || CAPABILITIES`, where
+    /// the status code is 0 if the connection was established or the `ConnectionError` otherwise.
+    pub fn into_bytes(self) -> Vec<u8> {
+        let status_code = match self.result {
+            Ok(()) => Self::CONNECTED,
+            Err(err) => err as u8,
+        };
+        let capabilities = if self.flow_control {
+            Self::FLOW_CONTROL_CAPABILITY
+        } else {
+            0
+        };
+
+        std::iter::once(Self::VERSION)
+            .chain(self.connection_id.to_be_bytes().iter().cloned())
+            .chain(std::iter::once(status_code))
+            .chain(std::iter::once(capabilities))
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod constructing_socks5_responses_from_bytes {
     use super::*;
@@ -115,3 +321,90 @@ mod constructing_socks5_responses_from_bytes {
         assert_eq!(expected.is_closed, actual.is_closed);
     }
 }
+
+#[cfg(test)]
+mod constructing_datagram_responses_from_bytes {
+    use super::*;
+
+    #[test]
+    fn fails_when_address_is_too_short() {
+        let response_bytes = vec![0, 0, 0, 0, 0, 0, 0, 42, 0, 10, 1, 2, 3];
+
+        assert_eq!(
+            ResponseError::AddressTooShort,
+            DatagramResponse::try_from_bytes(&response_bytes).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn works_when_there_is_data() {
+        let expected = DatagramResponse::new(42, "8.8.8.8:53".to_string(), vec![1, 2, 3]);
+        let actual = DatagramResponse::try_from_bytes(
+            &DatagramResponse::new(42, "8.8.8.8:53".to_string(), vec![1, 2, 3]).into_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(expected.connection_id, actual.connection_id);
+        assert_eq!(expected.source_address, actual.source_address);
+        assert_eq!(expected.data, actual.data);
+    }
+}
+
+#[cfg(test)]
+mod constructing_connection_status_responses_from_bytes {
+    use super::*;
+
+    #[test]
+    fn works_for_successful_and_failed_connections() {
+        let connected = ConnectionStatusResponse::new(42, Ok(())).into_bytes();
+        let recovered = ConnectionStatusResponse::try_from_bytes(&connected).unwrap();
+        assert_eq!(42, recovered.connection_id);
+        assert_eq!(Ok(()), recovered.result);
+
+        let refused =
+            ConnectionStatusResponse::new(42, Err(ConnectionError::ConnectionRefused)).into_bytes();
+        let recovered = ConnectionStatusResponse::try_from_bytes(&refused).unwrap();
+        assert_eq!(Err(ConnectionError::ConnectionRefused), recovered.result);
+    }
+
+    #[test]
+    fn newer_versions_and_unknown_codes_are_accepted() {
+        let response_bytes = vec![2, 0, 0, 0, 0, 0, 0, 0, 42, 100, 1, 2, 3];
+        let recovered = ConnectionStatusResponse::try_from_bytes(&response_bytes).unwrap();
+        assert_eq!(42, recovered.connection_id);
+        assert_eq!(Err(ConnectionError::Other), recovered.result);
+    }
+
+    #[test]
+    fn flow_control_capability_is_recovered() {
+        let status = ConnectionStatusResponse::new(42, Ok(()))
+            .with_flow_control()
+            .into_bytes();
+        let recovered = ConnectionStatusResponse::try_from_bytes(&status).unwrap();
+        assert!(recovered.flow_control);
+
+        let status = ConnectionStatusResponse::new(42, Ok(())).into_bytes();
+        let recovered = ConnectionStatusResponse::try_from_bytes(&status).unwrap();
+        assert!(!recovered.flow_control);
+    }
+
+    #[test]
+    fn fails_when_status_or_capabilities_are_missing() {
+        let response_bytes = vec![1, 0, 0, 0, 0, 0, 0, 0, 42];
+        assert_eq!(
+            ResponseError::StatusTooShort,
+            ConnectionStatusResponse::try_from_bytes(&response_bytes).unwrap_err()
+        );
+
+        let response_bytes = vec![1, 0, 0, 0, 0, 0, 0, 0, 42, 0];
+        assert_eq!(
+            ResponseError::CapabilitiesTooShort,
+            ConnectionStatusResponse::try_from_bytes(&response_bytes).unwrap_err()
+        );
+
+        assert_eq!(
+            ResponseError::UnsupportedVersion(0),
+            ConnectionStatusResponse::try_from_bytes(&[0, 1, 2]).unwrap_err()
+        );
+    }
+}
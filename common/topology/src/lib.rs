@@ -2,11 +2,12 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::filter::VersionFilterable;
+use crate::route_selection::RouteSelector;
 use log::warn;
 use mixnet_contract_common::{GatewayBond, MixNodeBond};
 use nymsphinx_addressing::nodes::NodeIdentity;
 use nymsphinx_types::Node as SphinxNode;
-use rand::Rng;
+use rand::{Rng, RngCore};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt::{self, Display, Formatter};
@@ -17,6 +18,8 @@ use std::str::FromStr;
 pub mod filter;
 pub mod gateway;
 pub mod mix;
+pub mod route_selection;
+pub mod serializable;
 
 #[derive(Debug)]
 pub enum NymTopologyError {
@@ -175,6 +178,57 @@ impl NymTopology {
             .collect())
     }
 
+    /// Returns a vec of size of `num_mix_hops` of mixnodes, such that each subsequent node is on
+    /// next layer, starting from layer 1, with each of them being chosen by the provided selector
+    pub fn mix_route_with_selector(
+        &self,
+        selector: &dyn RouteSelector,
+        rng: &mut dyn RngCore,
+        num_mix_hops: u8,
+    ) -> Result<Vec<SphinxNode>, NymTopologyError> {
+        if self.mixes.len() < num_mix_hops as usize {
+            return Err(NymTopologyError::InvalidNumberOfHopsError);
+        }
+        let mut route = Vec::with_capacity(num_mix_hops as usize);
+
+        // there is no "layer 0"
+        for layer in 1..=num_mix_hops {
+            let layer_mixes = self
+                .mixes
+                .get(&layer)
+                .ok_or(NymTopologyError::NoMixesOnLayerAvailable(layer))?;
+
+            // this returns a 'None' if the slice is empty or if all of the nodes got excluded
+            let chosen_mix = selector
+                .choose_mix(rng, layer_mixes)
+                .ok_or(NymTopologyError::NoMixesOnLayerAvailable(layer))?;
+            route.push(chosen_mix.into());
+        }
+
+        Ok(route)
+    }
+
+    /// Tries to create a route to the specified gateway, such that it goes through mixnode on layer 1,
+    /// mixnode on layer2, .... mixnode on layer n and finally the target gateway, with all of
+    /// the mixnodes being chosen by the provided selector
+    pub fn route_to_gateway_with_selector(
+        &self,
+        selector: &dyn RouteSelector,
+        rng: &mut dyn RngCore,
+        num_mix_hops: u8,
+        gateway_identity: &NodeIdentity,
+    ) -> Result<Vec<SphinxNode>, NymTopologyError> {
+        let gateway = self
+            .get_gateway(gateway_identity)
+            .ok_or(NymTopologyError::NonExistentGatewayError)?;
+
+        Ok(self
+            .mix_route_with_selector(selector, rng, num_mix_hops)?
+            .into_iter()
+            .chain(std::iter::once(gateway.into()))
+            .collect())
+    }
+
     /// Overwrites the existing nodes in the specified layer
     pub fn set_mixes_in_layer(&mut self, layer: u8, mixes: Vec<mix::Node>) {
         self.mixes.insert(layer, mixes);
@@ -0,0 +1,589 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::{mix, MixLayer, NymTopology};
+use log::warn;
+use nymsphinx_addressing::nodes::NymNodeRoutingAddress;
+use rand::seq::SliceRandom;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// Latency assumed for the nodes for which no measurements are available.
+const UNKNOWN_NODE_LATENCY: Duration = Duration::from_millis(500);
+
+/// Determines which mix nodes are used for the routes of the packets sent through the mix network.
+pub trait RouteSelector: Send + Sync {
+    /// Chooses one of the provided mix nodes, all of which are on the same layer.
+    /// Returns `None` if none of them can be used.
+    fn choose_mix<'a>(
+        &self,
+        rng: &mut dyn RngCore,
+        candidates: &'a [mix::Node],
+    ) -> Option<&'a mix::Node>;
+}
+
+/// Chooses every node with the same probability.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UniformRouteSelector;
+
+impl RouteSelector for UniformRouteSelector {
+    fn choose_mix<'a>(
+        &self,
+        rng: &mut dyn RngCore,
+        candidates: &'a [mix::Node],
+    ) -> Option<&'a mix::Node> {
+        candidates.choose(rng)
+    }
+}
+
+/// Property by which the probability of a node being chosen is weighted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteSelectionPolicy {
+    /// Every node is equally likely to be chosen.
+    Uniform,
+
+    /// Nodes are weighted by their total stake, i.e. the pledge and all delegations.
+    Stake,
+
+    /// Nodes are weighted by their average uptime as reported by the validator API.
+    Uptime,
+
+    /// Nodes are weighted by the inverse of their latency as measured by the verloc protocol.
+    Latency,
+}
+
+impl Default for RouteSelectionPolicy {
+    fn default() -> Self {
+        RouteSelectionPolicy::Uniform
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownRouteSelectionPolicy(String);
+
+impl Display for UnknownRouteSelectionPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is not a valid route selection policy - use one of 'uniform', 'stake', 'uptime' or 'latency'",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownRouteSelectionPolicy {}
+
+impl FromStr for RouteSelectionPolicy {
+    type Err = UnknownRouteSelectionPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "uniform" => Ok(RouteSelectionPolicy::Uniform),
+            "stake" => Ok(RouteSelectionPolicy::Stake),
+            "uptime" => Ok(RouteSelectionPolicy::Uptime),
+            "latency" => Ok(RouteSelectionPolicy::Latency),
+            _ => Err(UnknownRouteSelectionPolicy(s.to_string())),
+        }
+    }
+}
+
+/// Mix nodes that must never be part of any route.
+#[derive(Debug, Clone, Default)]
+pub struct NodeExclusions {
+    identities: HashSet<String>,
+    owners: HashSet<String>,
+    // all countries are stored normalized, see `normalize_country`
+    countries: HashSet<String>,
+}
+
+impl NodeExclusions {
+    pub fn new<I, O, C>(identities: I, owners: O, countries: C) -> Self
+    where
+        I: IntoIterator<Item = String>,
+        O: IntoIterator<Item = String>,
+        C: IntoIterator<Item = String>,
+    {
+        NodeExclusions {
+            identities: identities.into_iter().collect(),
+            owners: owners.into_iter().collect(),
+            countries: countries
+                .into_iter()
+                .map(|country| normalize_country(&country))
+                .filter(|country| !country.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.identities.is_empty() && self.owners.is_empty() && self.countries.is_empty()
+    }
+
+    /// Indicates whether any of the exclusions depends on the location of the nodes.
+    pub fn requires_locations(&self) -> bool {
+        !self.countries.is_empty()
+    }
+
+    fn excludes(&self, node: &mix::Node, metrics: Option<&NodeMetrics>) -> bool {
+        if self
+            .identities
+            .contains(&node.identity_key.to_base58_string())
+            || self.owners.contains(&node.owner)
+        {
+            return true;
+        }
+
+        if self.countries.is_empty() {
+            return false;
+        }
+
+        // if we don't know where the node is located, we can't risk using it
+        match metrics.and_then(|metrics| metrics.location.as_ref()) {
+            None => true,
+            // the location might be given as, for example, "Berlin, Germany", so each of its
+            // comma-separated parts is compared against the excluded countries
+            Some(location) => location
+                .split(',')
+                .any(|part| self.countries.contains(&normalize_country(part))),
+        }
+    }
+}
+
+// makes the comparison insensitive to the letter case and to the surrounding or repeated whitespace
+fn normalize_country(country: &str) -> String {
+    country
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Properties of a mix node that are not part of the network topology itself.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NodeMetrics {
+    /// Average uptime of the node, in percent.
+    pub uptime: Option<u8>,
+
+    /// Average latency of the node.
+    pub latency: Option<Duration>,
+
+    /// Location of the node, as announced by its operator.
+    pub location: Option<String>,
+}
+
+/// [`RouteSelector`] choosing the nodes according to the specified [`RouteSelectionPolicy`],
+/// while never choosing any of the excluded ones.
+#[derive(Debug, Default)]
+pub struct PolicyRouteSelector {
+    policy: RouteSelectionPolicy,
+    exclusions: NodeExclusions,
+
+    /// Metrics of all known mix nodes indexed by their identities.
+    metrics: RwLock<HashMap<String, NodeMetrics>>,
+}
+
+impl PolicyRouteSelector {
+    pub fn new(policy: RouteSelectionPolicy, exclusions: NodeExclusions) -> Self {
+        PolicyRouteSelector {
+            policy,
+            exclusions,
+            metrics: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn policy(&self) -> RouteSelectionPolicy {
+        self.policy
+    }
+
+    pub fn exclusions(&self) -> &NodeExclusions {
+        &self.exclusions
+    }
+
+    /// Replaces the metrics of all the nodes with the provided values.
+    pub fn update_metrics(&self, metrics: HashMap<String, NodeMetrics>) {
+        match self.metrics.write() {
+            Ok(mut guard) => *guard = metrics,
+            Err(poisoned) => *poisoned.into_inner() = metrics,
+        }
+    }
+
+    /// Returns the layers of the provided topology on which all of the nodes are excluded,
+    /// for example because their locations are not known yet, so that no route could be
+    /// chosen through them.
+    pub fn layers_without_eligible_nodes(&self, topology: &NymTopology) -> Vec<MixLayer> {
+        let metrics = match self.metrics.read() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let mut layers = topology
+            .mixes()
+            .iter()
+            .filter(|(_, nodes)| {
+                nodes.iter().all(|node| {
+                    let node_metrics = metrics.get(&node.identity_key.to_base58_string());
+                    self.exclusions.excludes(node, node_metrics)
+                })
+            })
+            .map(|(layer, _)| *layer)
+            .collect::<Vec<_>>();
+        layers.sort_unstable();
+        layers
+    }
+
+    fn weight(&self, node: &mix::Node, metrics: Option<&NodeMetrics>) -> f64 {
+        match self.policy {
+            RouteSelectionPolicy::Uniform => 1.0,
+            RouteSelectionPolicy::Stake => node.stake.saturating_add(node.delegation) as f64,
+            RouteSelectionPolicy::Uptime => metrics
+                .and_then(|metrics| metrics.uptime)
+                .unwrap_or_default() as f64,
+            RouteSelectionPolicy::Latency => {
+                let latency = metrics
+                    .and_then(|metrics| metrics.latency)
+                    .unwrap_or(UNKNOWN_NODE_LATENCY);
+                // make sure we never divide by zero
+                1.0 / latency.as_secs_f64().max(0.001)
+            }
+        }
+    }
+}
+
+impl RouteSelector for PolicyRouteSelector {
+    fn choose_mix<'a>(
+        &self,
+        rng: &mut dyn RngCore,
+        candidates: &'a [mix::Node],
+    ) -> Option<&'a mix::Node> {
+        let metrics = match self.metrics.read() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let weighted = candidates
+            .iter()
+            .map(|node| {
+                let node_metrics = metrics.get(&node.identity_key.to_base58_string());
+                (node, node_metrics)
+            })
+            .filter(|(node, node_metrics)| !self.exclusions.excludes(node, *node_metrics))
+            .map(|(node, node_metrics)| (node, self.weight(node, node_metrics)))
+            .collect::<Vec<_>>();
+
+        if weighted.is_empty() {
+            warn!("All of the mix nodes on one of the layers are excluded from routing");
+            return None;
+        }
+
+        // this can only fail if all the weights are zero, for example if we don't have any
+        // uptime data yet, in which case just choose uniformly among the allowed nodes
+        match weighted.choose_weighted(rng, |(_, weight)| *weight) {
+            Ok((node, _)) => Some(*node),
+            Err(_) => weighted.choose(rng).map(|(node, _)| *node),
+        }
+    }
+}
+
+/// Wraps another [`RouteSelector`] so that the nodes of a previously used route are avoided,
+/// for example when choosing a new route for a retransmitted packet. An avoided node is only
+/// chosen if there is no other usable node left on its layer.
+pub struct AvoidingRouteSelector<'r> {
+    inner: &'r dyn RouteSelector,
+    avoided: &'r [NymNodeRoutingAddress],
+}
+
+impl<'r> AvoidingRouteSelector<'r> {
+    pub fn new(inner: &'r dyn RouteSelector, avoided: &'r [NymNodeRoutingAddress]) -> Self {
+        AvoidingRouteSelector { inner, avoided }
+    }
+
+    fn is_avoided(&self, node: &mix::Node) -> bool {
+        self.avoided
+            .contains(&NymNodeRoutingAddress::from(node.mix_host))
+    }
+}
+
+impl<'r> RouteSelector for AvoidingRouteSelector<'r> {
+    fn choose_mix<'a>(
+        &self,
+        rng: &mut dyn RngCore,
+        candidates: &'a [mix::Node],
+    ) -> Option<&'a mix::Node> {
+        // every node belongs to a single layer, so only the node previously used on the layer
+        // of the candidates can ever get filtered out
+        let alternatives = candidates
+            .iter()
+            .filter(|node| !self.is_avoided(node))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if alternatives.is_empty() || alternatives.len() == candidates.len() {
+            return self.inner.choose_mix(rng, candidates);
+        }
+
+        match self.inner.choose_mix(rng, &alternatives) {
+            Some(chosen) => candidates
+                .iter()
+                .find(|node| node.identity_key == chosen.identity_key),
+            // the inner selector might not like any of the alternatives
+            None => self.inner.choose_mix(rng, candidates),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::asymmetric::{encryption, identity};
+    use mixnet_contract_common::Layer;
+
+    fn mix_node(owner: &str, stake: u128) -> mix::Node {
+        // derive distinct keys from the owner so that each node would have its own identity
+        let mut seed = [0u8; 32];
+        for (seed_byte, owner_byte) in seed.iter_mut().zip(owner.bytes()) {
+            *seed_byte = owner_byte;
+        }
+        let identity_key =
+            identity::PublicKey::from(&identity::PrivateKey::from_bytes(&seed).unwrap());
+        let sphinx_key =
+            encryption::PublicKey::from(&encryption::PrivateKey::from_bytes(&seed).unwrap());
+
+        mix::Node {
+            owner: owner.to_string(),
+            stake,
+            delegation: 0,
+            host: "3.3.3.3".parse().unwrap(),
+            mix_host: "3.3.3.3:1789".parse().unwrap(),
+            identity_key,
+            sphinx_key,
+            layer: Layer::One,
+            version: "0.x.0".to_string(),
+        }
+    }
+
+    fn mix_node_at(owner: &str, mix_host: &str) -> mix::Node {
+        mix::Node {
+            mix_host: mix_host.parse().unwrap(),
+            ..mix_node(owner, 100)
+        }
+    }
+
+    #[test]
+    fn previously_used_nodes_are_avoided_if_possible() {
+        let nodes = vec![
+            mix_node_at("Alice", "1.1.1.1:1789"),
+            mix_node_at("Bob", "2.2.2.2:1789"),
+        ];
+        let avoided = vec![NymNodeRoutingAddress::from(nodes[0].mix_host)];
+        let selector = AvoidingRouteSelector::new(&UniformRouteSelector, &avoided);
+
+        let mut rng = rand::rngs::OsRng;
+        for _ in 0..50 {
+            let chosen = selector.choose_mix(&mut rng, &nodes).unwrap();
+            assert_eq!(chosen.owner, "Bob");
+        }
+    }
+
+    #[test]
+    fn previously_used_node_is_reused_without_alternatives() {
+        let nodes = vec![mix_node_at("Alice", "1.1.1.1:1789")];
+        let avoided = vec![NymNodeRoutingAddress::from(nodes[0].mix_host)];
+        let selector = AvoidingRouteSelector::new(&UniformRouteSelector, &avoided);
+
+        let mut rng = rand::rngs::OsRng;
+        let chosen = selector.choose_mix(&mut rng, &nodes).unwrap();
+        assert_eq!(chosen.owner, "Alice");
+    }
+
+    #[test]
+    fn avoiding_nodes_respects_the_inner_selector() {
+        let nodes = vec![
+            mix_node_at("Alice", "1.1.1.1:1789"),
+            mix_node_at("Bob", "2.2.2.2:1789"),
+            mix_node_at("Carol", "3.3.3.3:1789"),
+        ];
+        let inner = PolicyRouteSelector::new(
+            RouteSelectionPolicy::Uniform,
+            NodeExclusions::new(vec![], vec!["Bob".to_string()], vec![]),
+        );
+        let avoided = vec![NymNodeRoutingAddress::from(nodes[0].mix_host)];
+        let selector = AvoidingRouteSelector::new(&inner, &avoided);
+
+        let mut rng = rand::rngs::OsRng;
+        for _ in 0..50 {
+            let chosen = selector.choose_mix(&mut rng, &nodes).unwrap();
+            assert_eq!(chosen.owner, "Carol");
+        }
+    }
+
+    #[test]
+    fn excluded_owners_are_never_chosen() {
+        let nodes = vec![mix_node("Alice", 100), mix_node("Bob", 100)];
+        let selector = PolicyRouteSelector::new(
+            RouteSelectionPolicy::Uniform,
+            NodeExclusions::new(vec![], vec!["Alice".to_string()], vec![]),
+        );
+
+        let mut rng = rand::rngs::OsRng;
+        for _ in 0..50 {
+            let chosen = selector.choose_mix(&mut rng, &nodes).unwrap();
+            assert_eq!(chosen.owner, "Bob");
+        }
+    }
+
+    #[test]
+    fn nodes_in_excluded_countries_or_with_unknown_location_are_never_chosen() {
+        let nodes = vec![
+            mix_node("Alice", 100),
+            mix_node("Bob", 100),
+            mix_node("Carol", 100),
+        ];
+        let selector = PolicyRouteSelector::new(
+            RouteSelectionPolicy::Uniform,
+            NodeExclusions::new(vec![], vec![], vec!["Atlantis".to_string()]),
+        );
+
+        let mut metrics = HashMap::new();
+        metrics.insert(
+            nodes[0].identity_key.to_base58_string(),
+            NodeMetrics {
+                location: Some("ATLANTIS".to_string()),
+                ..Default::default()
+            },
+        );
+        metrics.insert(
+            nodes[1].identity_key.to_base58_string(),
+            NodeMetrics {
+                location: Some("Lemuria".to_string()),
+                ..Default::default()
+            },
+        );
+        selector.update_metrics(metrics);
+
+        let mut rng = rand::rngs::OsRng;
+        for _ in 0..50 {
+            let chosen = selector.choose_mix(&mut rng, &nodes).unwrap();
+            assert_eq!(chosen.owner, "Bob");
+        }
+    }
+
+    #[test]
+    fn country_exclusions_match_exactly() {
+        let exclusions = NodeExclusions::new(
+            vec![],
+            vec![],
+            vec![" united   Kingdom ".to_string(), "India".to_string()],
+        );
+        let node = mix_node("Alice", 100);
+        let located_at = |location: &str| NodeMetrics {
+            location: Some(location.to_string()),
+            ..Default::default()
+        };
+
+        assert!(exclusions.excludes(&node, Some(&located_at("United Kingdom"))));
+        assert!(exclusions.excludes(&node, Some(&located_at("London,  UNITED kingdom"))));
+        assert!(exclusions.excludes(&node, Some(&located_at("india"))));
+        // neither substrings nor superstrings of the excluded countries are matched
+        assert!(!exclusions.excludes(&node, Some(&located_at("Indiana, US"))));
+        assert!(!exclusions.excludes(&node, Some(&located_at("British Indian Ocean Territory"))));
+        assert!(!exclusions.excludes(&node, Some(&located_at("Kingdom"))));
+        // nodes with unknown locations are still excluded
+        assert!(exclusions.excludes(&node, None));
+    }
+
+    #[test]
+    fn nodes_without_any_weight_are_never_chosen() {
+        let nodes = vec![mix_node("Alice", 0), mix_node("Bob", 100)];
+        let selector =
+            PolicyRouteSelector::new(RouteSelectionPolicy::Stake, NodeExclusions::default());
+
+        let mut rng = rand::rngs::OsRng;
+        for _ in 0..50 {
+            let chosen = selector.choose_mix(&mut rng, &nodes).unwrap();
+            assert_eq!(chosen.owner, "Bob");
+        }
+    }
+
+    #[test]
+    fn falls_back_to_uniform_choice_if_no_node_has_any_weight() {
+        let nodes = vec![mix_node("Alice", 100), mix_node("Bob", 100)];
+        let selector =
+            PolicyRouteSelector::new(RouteSelectionPolicy::Uptime, NodeExclusions::default());
+
+        let mut rng = rand::rngs::OsRng;
+        assert!(selector.choose_mix(&mut rng, &nodes).is_some());
+    }
+
+    #[test]
+    fn policies_are_parsed_case_insensitively() {
+        assert_eq!(
+            "Latency".parse::<RouteSelectionPolicy>().unwrap(),
+            RouteSelectionPolicy::Latency
+        );
+        assert_eq!(
+            "stake".parse::<RouteSelectionPolicy>().unwrap(),
+            RouteSelectionPolicy::Stake
+        );
+        assert!("fastest".parse::<RouteSelectionPolicy>().is_err());
+    }
+
+    #[test]
+    fn returns_none_if_all_nodes_are_excluded() {
+        let nodes = vec![mix_node("Alice", 100)];
+        let selector = PolicyRouteSelector::new(
+            RouteSelectionPolicy::Uniform,
+            NodeExclusions::new(vec![], vec!["Alice".to_string()], vec![]),
+        );
+
+        let mut rng = rand::rngs::OsRng;
+        assert!(selector.choose_mix(&mut rng, &nodes).is_none());
+    }
+
+    #[test]
+    fn layers_with_only_excluded_nodes_are_reported() {
+        let mut mixes = HashMap::new();
+        mixes.insert(1, vec![mix_node("Alice", 100), mix_node("Bob", 100)]);
+        mixes.insert(2, vec![mix_node("Carol", 100)]);
+        mixes.insert(3, vec![mix_node("Dave", 100)]);
+        let topology = NymTopology::new(mixes, vec![]);
+
+        let selector = PolicyRouteSelector::new(
+            RouteSelectionPolicy::Uniform,
+            NodeExclusions::new(
+                vec![],
+                vec!["Carol".to_string()],
+                vec!["Atlantis".to_string()],
+            ),
+        );
+        // none of the locations are known yet
+        assert_eq!(
+            selector.layers_without_eligible_nodes(&topology),
+            vec![1, 2, 3]
+        );
+
+        let located_at = |location: &str| NodeMetrics {
+            location: Some(location.to_string()),
+            ..Default::default()
+        };
+        let mut metrics = HashMap::new();
+        metrics.insert(
+            mix_node("Alice", 100).identity_key.to_base58_string(),
+            located_at("Atlantis"),
+        );
+        metrics.insert(
+            mix_node("Bob", 100).identity_key.to_base58_string(),
+            located_at("Lemuria"),
+        );
+        metrics.insert(
+            mix_node("Dave", 100).identity_key.to_base58_string(),
+            located_at("Lemuria"),
+        );
+        selector.update_metrics(metrics);
+        assert_eq!(selector.layers_without_eligible_nodes(&topology), vec![2]);
+    }
+}
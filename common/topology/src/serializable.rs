@@ -0,0 +1,287 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::gateway::GatewayConversionError;
+use crate::mix::MixnodeConversionError;
+use crate::{gateway, mix, MixLayer, NetworkAddress, NymTopology};
+use crypto::asymmetric::{encryption, identity};
+use mixnet_contract_common::Layer;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddr;
+
+#[derive(Debug)]
+pub enum SerializableTopologyError {
+    MalformedMixnode(MixnodeConversionError),
+    MalformedGateway(GatewayConversionError),
+    InvalidMixLayer(String),
+}
+
+impl From<MixnodeConversionError> for SerializableTopologyError {
+    fn from(err: MixnodeConversionError) -> Self {
+        SerializableTopologyError::MalformedMixnode(err)
+    }
+}
+
+impl From<GatewayConversionError> for SerializableTopologyError {
+    fn from(err: GatewayConversionError) -> Self {
+        SerializableTopologyError::MalformedGateway(err)
+    }
+}
+
+impl Display for SerializableTopologyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SerializableTopologyError::MalformedMixnode(err) => err.fmt(f),
+            SerializableTopologyError::MalformedGateway(err) => err.fmt(f),
+            SerializableTopologyError::InvalidMixLayer(identity) => {
+                write!(f, "mixnode {} is not on a valid mix layer", identity)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SerializableTopologyError {}
+
+/// Representation of a [`NymTopology`] that can be written to and read from a file, for example
+/// in order to run a client against a local network without any validator API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerializableNymTopology {
+    pub mixnodes: Vec<SerializableMixNode>,
+    pub gateways: Vec<SerializableGateway>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerializableMixNode {
+    pub owner: String,
+    #[serde(default)]
+    pub stake: u128,
+    #[serde(default)]
+    pub delegation: u128,
+    pub host: String,
+    pub mix_host: SocketAddr,
+    pub identity_key: String,
+    pub sphinx_key: String,
+    pub layer: Layer,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerializableGateway {
+    pub owner: String,
+    #[serde(default)]
+    pub stake: u128,
+    #[serde(default)]
+    pub location: String,
+    pub host: String,
+    pub mix_host: SocketAddr,
+    pub clients_port: u16,
+    pub identity_key: String,
+    pub sphinx_key: String,
+    pub version: String,
+}
+
+impl<'a> From<&'a mix::Node> for SerializableMixNode {
+    fn from(node: &'a mix::Node) -> Self {
+        SerializableMixNode {
+            owner: node.owner.clone(),
+            stake: node.stake,
+            delegation: node.delegation,
+            host: node.host.to_string(),
+            mix_host: node.mix_host,
+            identity_key: node.identity_key.to_base58_string(),
+            sphinx_key: node.sphinx_key.to_base58_string(),
+            layer: node.layer,
+            version: node.version.clone(),
+        }
+    }
+}
+
+impl TryFrom<SerializableMixNode> for mix::Node {
+    type Error = MixnodeConversionError;
+
+    fn try_from(node: SerializableMixNode) -> Result<Self, Self::Error> {
+        let host: NetworkAddress = node
+            .host
+            .parse()
+            .map_err(|err| MixnodeConversionError::InvalidAddress(node.host.clone(), err))?;
+
+        Ok(mix::Node {
+            owner: node.owner,
+            stake: node.stake,
+            delegation: node.delegation,
+            host,
+            mix_host: node.mix_host,
+            identity_key: identity::PublicKey::from_base58_string(&node.identity_key)?,
+            sphinx_key: encryption::PublicKey::from_base58_string(&node.sphinx_key)?,
+            layer: node.layer,
+            version: node.version,
+        })
+    }
+}
+
+impl<'a> From<&'a gateway::Node> for SerializableGateway {
+    fn from(node: &'a gateway::Node) -> Self {
+        SerializableGateway {
+            owner: node.owner.clone(),
+            stake: node.stake,
+            location: node.location.clone(),
+            host: node.host.to_string(),
+            mix_host: node.mix_host,
+            clients_port: node.clients_port,
+            identity_key: node.identity_key.to_base58_string(),
+            sphinx_key: node.sphinx_key.to_base58_string(),
+            version: node.version.clone(),
+        }
+    }
+}
+
+impl TryFrom<SerializableGateway> for gateway::Node {
+    type Error = GatewayConversionError;
+
+    fn try_from(node: SerializableGateway) -> Result<Self, Self::Error> {
+        let host: NetworkAddress = node
+            .host
+            .parse()
+            .map_err(|err| GatewayConversionError::InvalidAddress(node.host.clone(), err))?;
+
+        Ok(gateway::Node {
+            owner: node.owner,
+            stake: node.stake,
+            location: node.location,
+            host,
+            mix_host: node.mix_host,
+            clients_port: node.clients_port,
+            identity_key: identity::PublicKey::from_base58_string(&node.identity_key)?,
+            sphinx_key: encryption::PublicKey::from_base58_string(&node.sphinx_key)?,
+            version: node.version,
+        })
+    }
+}
+
+impl<'a> From<&'a NymTopology> for SerializableNymTopology {
+    fn from(topology: &'a NymTopology) -> Self {
+        let mut mixnodes = topology
+            .mixes_as_vec()
+            .iter()
+            .map(SerializableMixNode::from)
+            .collect::<Vec<_>>();
+        // make the output deterministic regardless of the ordering of the underlying map
+        mixnodes.sort_by_key(|node| node.layer);
+
+        SerializableNymTopology {
+            mixnodes,
+            gateways: topology
+                .gateways()
+                .iter()
+                .map(SerializableGateway::from)
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<SerializableNymTopology> for NymTopology {
+    type Error = SerializableTopologyError;
+
+    fn try_from(topology: SerializableNymTopology) -> Result<Self, Self::Error> {
+        let mut mixes: HashMap<MixLayer, Vec<mix::Node>> = HashMap::new();
+        for node in topology.mixnodes {
+            if node.layer == Layer::Gateway {
+                return Err(SerializableTopologyError::InvalidMixLayer(
+                    node.identity_key,
+                ));
+            }
+            let node = mix::Node::try_from(node)?;
+            mixes.entry(node.layer as MixLayer).or_default().push(node);
+        }
+
+        let gateways = topology
+            .gateways
+            .into_iter()
+            .map(gateway::Node::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(NymTopology::new(mixes, gateways))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dummy_topology() -> NymTopology {
+        let mix = mix::Node {
+            owner: "Alice".to_string(),
+            stake: 123,
+            delegation: 456,
+            host: "3.3.3.3".parse().unwrap(),
+            mix_host: "3.3.3.3:1789".parse().unwrap(),
+            identity_key: identity::PublicKey::from_base58_string(
+                "3ebjp1Fb9hdcS1AR6AZihgeJiMHkB5jjJUsvqNnfQwU7",
+            )
+            .unwrap(),
+            sphinx_key: encryption::PublicKey::from_base58_string(
+                "C7cown6dYCLZpLiMFC1PaBmhvLvmJmLDJGeRTbPD45bX",
+            )
+            .unwrap(),
+            layer: Layer::Two,
+            version: "0.x.0".to_string(),
+        };
+
+        let gateway = gateway::Node {
+            owner: "Bob".to_string(),
+            stake: 789,
+            location: "Neuchatel".to_string(),
+            host: "gateway.example.com".parse().unwrap(),
+            mix_host: "4.4.4.4:1789".parse().unwrap(),
+            clients_port: 9000,
+            identity_key: identity::PublicKey::from_base58_string(
+                "3ebjp1Fb9hdcS1AR6AZihgeJiMHkB5jjJUsvqNnfQwU7",
+            )
+            .unwrap(),
+            sphinx_key: encryption::PublicKey::from_base58_string(
+                "C7cown6dYCLZpLiMFC1PaBmhvLvmJmLDJGeRTbPD45bX",
+            )
+            .unwrap(),
+            version: "0.x.0".to_string(),
+        };
+
+        let mut mixes = HashMap::new();
+        mixes.insert(2, vec![mix]);
+        NymTopology::new(mixes, vec![gateway])
+    }
+
+    #[test]
+    fn topology_survives_conversion_roundtrip() {
+        let topology = dummy_topology();
+        let serializable = SerializableNymTopology::from(&topology);
+        let recovered = NymTopology::try_from(serializable).unwrap();
+
+        assert_eq!(recovered.mixes_in_layer(2).len(), 1);
+        assert_eq!(recovered.mixes_in_layer(2)[0].owner, "Alice");
+        assert_eq!(recovered.mixes_in_layer(2)[0].stake, 123);
+        assert_eq!(
+            recovered.mixes_in_layer(2)[0].identity_key,
+            topology.mixes_in_layer(2)[0].identity_key
+        );
+        assert_eq!(recovered.gateways().len(), 1);
+        assert_eq!(
+            recovered.gateways()[0].clients_address(),
+            "ws://gateway.example.com:9000"
+        );
+        assert_eq!(
+            recovered.gateways()[0].mix_host,
+            topology.gateways()[0].mix_host
+        );
+    }
+
+    #[test]
+    fn mixnodes_on_gateway_layer_are_rejected() {
+        let mut serializable = SerializableNymTopology::from(&dummy_topology());
+        serializable.mixnodes[0].layer = Layer::Gateway;
+
+        assert!(NymTopology::try_from(serializable).is_err());
+    }
+}
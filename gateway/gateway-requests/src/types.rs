@@ -116,6 +116,10 @@ pub enum ClientControlRequest {
         address: String,
         enc_address: String,
         iv: String,
+        /// If set, the messages stored while the client was offline are not pushed to it upon
+        /// authentication. Instead they have to be explicitly fetched with `FetchInbox`.
+        #[serde(default)]
+        pull_mode: bool,
     },
     #[serde(alias = "handshakePayload")]
     RegisterHandshakeInitRequest {
@@ -126,6 +130,27 @@ pub enum ClientControlRequest {
         iv: Vec<u8>,
     },
     ClaimFreeTestnetBandwidth,
+    /// Get the number of messages stored for the client.
+    InboxCount,
+    /// Push the next page of the stored messages to the client.
+    FetchInbox {
+        /// Fetch the messages stored after the one with this id.
+        #[serde(default)]
+        start_after: Option<i64>,
+        /// Maximum number of messages to push. The gateway might cap it further.
+        page_size: u32,
+        /// Whether the messages should be removed from the store once pushed.
+        /// Note that they're removed as soon as they're written to the socket, so any messages
+        /// lost on the way (e.g. due to the connection dropping) are gone for good. Clients
+        /// that can't afford that should remove them with `DeleteInbox` once they're received.
+        #[serde(default)]
+        remove: bool,
+    },
+    /// Remove the stored messages with the specified ids. The gateway rejects requests with
+    /// more ids than it returns in a single page.
+    DeleteInbox {
+        ids: Vec<i64>,
+    },
 }
 
 impl ClientControlRequest {
@@ -133,11 +158,13 @@ impl ClientControlRequest {
         address: DestinationAddressBytes,
         enc_address: EncryptedAddressBytes,
         iv: IV,
+        pull_mode: bool,
     ) -> Self {
         ClientControlRequest::Authenticate {
             address: address.as_base58_string(),
             enc_address: enc_address.to_base58_string(),
             iv: iv.to_base58_string(),
+            pull_mode,
         }
     }
 
@@ -233,6 +260,18 @@ pub enum ServerResponse {
     Send {
         remaining_bandwidth: i64,
     },
+    InboxCount {
+        count: u64,
+    },
+    /// The stored messages have been pushed to the client, in the order of their ids.
+    InboxPage {
+        ids: Vec<i64>,
+        /// Id to fetch the next page after, if there are any more messages stored.
+        next_start_after: Option<i64>,
+    },
+    InboxDeleted {
+        removed: u64,
+    },
     Error {
         message: String,
     },
@@ -392,4 +431,16 @@ mod tests {
             _ => unreachable!("this branch shouldn't have been reached!"),
         }
     }
+
+    #[test]
+    fn authenticate_request_defaults_to_push_mode() {
+        let legacy_request =
+            r#"{"type":"authenticate","address":"foo","enc_address":"bar","iv":"baz"}"#;
+        let deserialized = ClientControlRequest::try_from(legacy_request.to_string()).unwrap();
+
+        match deserialized {
+            ClientControlRequest::Authenticate { pull_mode, .. } => assert!(!pull_mode),
+            _ => unreachable!("this branch shouldn't have been reached!"),
+        }
+    }
 }
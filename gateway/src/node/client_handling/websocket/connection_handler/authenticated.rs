@@ -16,7 +16,7 @@ use std::convert::TryFrom;
 use std::process;
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::tungstenite::{protocol::Message, Error as WsError};
 
 use crate::node::client_handling::bandwidth::Bandwidth;
 use crate::node::client_handling::FREE_TESTNET_BANDWIDTH_VALUE;
@@ -48,6 +48,12 @@ pub(crate) enum RequestHandlingError {
     #[error("This gateway is not running in the disabled credentials mode")]
     NotInDisabledCredentialsMode,
 
+    #[error("Requested removal of {requested} stored messages at once while the maximum is {max}")]
+    TooManyMessageIds { requested: usize, max: i64 },
+
+    #[error("Failed to push the stored messages - {0}")]
+    ConnectionError(#[from] WsError),
+
     #[cfg(not(feature = "coconut"))]
     #[error("Ethereum web3 error")]
     Web3Error(#[from] web3::Error),
@@ -374,6 +380,85 @@ where
         Ok(ServerResponse::Bandwidth { available_total })
     }
 
+    /// Gets the number of messages stored for the connected client.
+    async fn handle_inbox_count(&self) -> Result<ServerResponse, RequestHandlingError> {
+        let count = self
+            .inner
+            .storage
+            .count_stored_messages(self.client.address)
+            .await?;
+        Ok(ServerResponse::InboxCount { count })
+    }
+
+    /// Pushes a single page of the messages stored for the connected client, optionally removing
+    /// them from the store afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `start_after`: optional id of the message after which the page starts.
+    /// * `page_size`: maximum number of messages to push.
+    /// * `remove`: whether the pushed messages should be removed from the store. They are removed
+    /// without waiting for the client to confirm it has received them, so they're lost if the
+    /// connection fails in the meantime.
+    async fn handle_fetch_inbox(
+        &mut self,
+        start_after: Option<i64>,
+        page_size: u32,
+        remove: bool,
+    ) -> Result<ServerResponse, RequestHandlingError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (messages, next_start_after) = self
+            .inner
+            .storage
+            .retrieve_messages_page(self.client.address, start_after, page_size as i64)
+            .await?;
+
+        let (messages, ids): (Vec<_>, Vec<_>) = messages
+            .into_iter()
+            .map(|msg| (msg.content, msg.id))
+            .unzip();
+
+        self.inner
+            .push_packets_to_client(self.client.shared_keys, messages)
+            .await?;
+        if remove {
+            self.inner
+                .storage
+                .remove_client_messages(self.client.address, ids.clone())
+                .await?;
+        }
+
+        Ok(ServerResponse::InboxPage {
+            ids,
+            next_start_after,
+        })
+    }
+
+    /// Removes the specified messages stored for the connected client.
+    ///
+    /// # Arguments
+    ///
+    /// * `ids`: ids of the messages to remove, at most as many as the message retrieval limit.
+    async fn handle_delete_inbox(
+        &self,
+        ids: Vec<i64>,
+    ) -> Result<ServerResponse, RequestHandlingError> {
+        let removed = self
+            .inner
+            .storage
+            .remove_client_messages(self.client.address, ids)
+            .await
+            .map_err(|err| match err {
+                StorageError::TooManyMessageIds { requested, max } => {
+                    RequestHandlingError::TooManyMessageIds { requested, max }
+                }
+                err => err.into(),
+            })?;
+        Ok(ServerResponse::InboxDeleted { removed })
+    }
+
     /// Tries to handle request to forward sphinx packet into the network. The request can only succeed
     /// if the client has enough available bandwidth.
     ///
@@ -425,12 +510,16 @@ where
 
     /// Attempts to handle a text data frame websocket message.
     ///
-    /// Currently the bandwidth credential request is the only one we can receive after authentication.
+    /// After authentication we can receive either the bandwidth requests or the requests
+    /// to retrieve the stored messages.
     ///
     /// # Arguments
     ///
     /// * `raw_request`: raw message to handle.
-    async fn handle_text(&mut self, raw_request: String) -> Message {
+    async fn handle_text(&mut self, raw_request: String) -> Message
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         match ClientControlRequest::try_from(raw_request) {
             Err(e) => RequestHandlingError::InvalidTextRequest(e).into_error_message(),
             Ok(request) => match request {
@@ -442,6 +531,20 @@ where
                     .handle_claim_testnet_bandwidth()
                     .await
                     .into_ws_message(),
+                ClientControlRequest::InboxCount => {
+                    self.handle_inbox_count().await.into_ws_message()
+                }
+                ClientControlRequest::FetchInbox {
+                    start_after,
+                    page_size,
+                    remove,
+                } => self
+                    .handle_fetch_inbox(start_after, page_size, remove)
+                    .await
+                    .into_ws_message(),
+                ClientControlRequest::DeleteInbox { ids } => {
+                    self.handle_delete_inbox(ids).await.into_ws_message()
+                }
                 _ => RequestHandlingError::IllegalRequest.into_error_message(),
             },
         }
@@ -452,7 +555,10 @@ where
     /// # Arguments
     ///
     /// * `raw_request`: raw received websocket message.
-    async fn handle_request(&mut self, raw_request: Message) -> Option<Message> {
+    async fn handle_request(&mut self, raw_request: Message) -> Option<Message>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         // apparently tungstenite auto-handles ping/pong/close messages so for now let's ignore
         // them and let's test that claim. If that's not the case, just copy code from
         // desktop nym-client websocket as I've manually handled everything there
@@ -354,18 +354,21 @@ where
     /// a fresh IV, attempts to authenticate the client by checking whether the ciphertext matches
     /// the expected value if encrypted with the shared key.
     ///
-    /// Finally, upon completion, all previously stored messages are pushed back to the client.
+    /// Finally, upon completion, unless the client has asked to fetch them on its own,
+    /// all previously stored messages are pushed back to the client.
     ///
     /// # Arguments
     ///
     /// * `client_address`: address of the client wishing to authenticate.
     /// * `encrypted_address`: ciphertext of the address of the client wishing to authenticate.
     /// * `iv`: fresh IV received with the request.
+    /// * `pull_mode`: whether the client is going to explicitly fetch its stored messages.
     async fn authenticate_client(
         &mut self,
         client_address: DestinationAddressBytes,
         encrypted_address: EncryptedAddressBytes,
         iv: IV,
+        pull_mode: bool,
     ) -> Result<Option<SharedKeys>, InitialAuthenticationError>
     where
         S: AsyncRead + AsyncWrite + Unpin,
@@ -380,8 +383,10 @@ where
             .await?;
 
         if let Some(shared_keys) = shared_keys {
-            self.push_stored_messages_to_client(client_address, shared_keys)
-                .await?;
+            if !pull_mode {
+                self.push_stored_messages_to_client(client_address, shared_keys)
+                    .await?;
+            }
             Ok(Some(shared_keys))
         } else {
             Ok(None)
@@ -395,11 +400,13 @@ where
     /// * `client_address`: address of the client wishing to authenticate.
     /// * `encrypted_address`: ciphertext of the address of the client wishing to authenticate.
     /// * `iv`: fresh IV received with the request.
+    /// * `pull_mode`: whether the client is going to explicitly fetch its stored messages.
     async fn handle_authenticate(
         &mut self,
         address: String,
         enc_address: String,
         iv: String,
+        pull_mode: bool,
     ) -> Result<InitialAuthResult, InitialAuthenticationError>
     where
         S: AsyncRead + AsyncWrite + Unpin,
@@ -414,7 +421,7 @@ where
         }
 
         let shared_keys = self
-            .authenticate_client(address, encrypted_address, iv)
+            .authenticate_client(address, encrypted_address, iv, pull_mode)
             .await?;
         let status = shared_keys.is_some();
         let bandwidth_remaining = self
@@ -524,7 +531,11 @@ where
                     address,
                     enc_address,
                     iv,
-                } => self.handle_authenticate(address, enc_address, iv).await,
+                    pull_mode,
+                } => {
+                    self.handle_authenticate(address, enc_address, iv, pull_mode)
+                        .await
+                }
                 ClientControlRequest::RegisterHandshakeInitRequest { data } => {
                     self.handle_register(data).await
                 }
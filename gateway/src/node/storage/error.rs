@@ -10,4 +10,7 @@ pub(crate) enum StorageError {
 
     #[error("Failed to perform database migration - {0}")]
     MigrationError(#[from] sqlx::migrate::MigrateError),
+
+    #[error("Attempted to remove {requested} messages at once while the maximum is {max}")]
+    TooManyMessageIds { requested: usize, max: i64 },
 }
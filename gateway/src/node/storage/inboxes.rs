@@ -63,9 +63,32 @@ impl InboxManager {
         client_address_bs58: &str,
         start_after: Option<i64>,
     ) -> Result<(Vec<StoredMessage>, Option<i64>), sqlx::Error> {
+        self.get_messages_page(client_address_bs58, start_after, self.retrieval_limit)
+            .await
+    }
+
+    /// Retrieves at most `page_size` messages stored for the particular client specified by the provided
+    /// address. The page size is capped by the retrieval limit.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_address_bs58`: base58-encoded address of the client
+    /// * `start_after`: optional starting id of the messages to grab
+    /// * `page_size`: maximum number of messages to grab
+    ///
+    /// returns the retrieved messages alongside optional id of the last message retrieved if
+    /// there are more messages to retrieve.
+    pub(crate) async fn get_messages_page(
+        &self,
+        client_address_bs58: &str,
+        start_after: Option<i64>,
+        page_size: i64,
+    ) -> Result<(Vec<StoredMessage>, Option<i64>), sqlx::Error> {
+        let page_size = page_size.clamp(1, self.retrieval_limit);
+
         // get 1 additional message to check whether there will be more to grab
         // next time
-        let limit = self.retrieval_limit + 1;
+        let limit = page_size + 1;
         let mut res = if let Some(start_after) = start_after {
             sqlx::query_as!(
                 StoredMessage,
@@ -97,9 +120,9 @@ impl InboxManager {
             .await?
         };
 
-        if res.len() > self.retrieval_limit as usize {
-            res.truncate(self.retrieval_limit as usize);
-            // assuming retrieval_limit > 0, unwrap will not fail
+        if res.len() > page_size as usize {
+            res.truncate(page_size as usize);
+            // page_size > 0, so unwrap will not fail
             let start_after = res.last().unwrap().id;
             Ok((res, Some(start_after)))
             //
@@ -119,4 +142,63 @@ impl InboxManager {
             .await?;
         Ok(())
     }
+
+    /// Counts messages stored for the particular client specified by the provided address.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_address_bs58`: base58-encoded address of the client
+    pub(crate) async fn count_messages(
+        &self,
+        client_address_bs58: &str,
+    ) -> Result<i32, sqlx::Error> {
+        let count = sqlx::query!(
+            "SELECT COUNT(*) as count FROM message_store WHERE client_address_bs58 = ?",
+            client_address_bs58,
+        )
+        .fetch_one(&self.connection_pool)
+        .await?
+        .count;
+        Ok(count)
+    }
+
+    /// Maximum number of messages that can be obtained from the database per operation.
+    pub(crate) fn retrieval_limit(&self) -> i64 {
+        self.retrieval_limit
+    }
+
+    /// Removes messages with the specified ids, as long as they were stored for the particular client.
+    /// All of them are removed with a single statement within a transaction.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_address_bs58`: base58-encoded address of the client
+    /// * `ids`: ids of the messages to remove
+    ///
+    /// returns the number of messages actually removed.
+    pub(crate) async fn remove_client_messages(
+        &self,
+        client_address_bs58: &str,
+        ids: &[i64],
+    ) -> Result<u64, sqlx::Error> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        // a list of values can't be bound to a single parameter, so each id gets its own
+        let placeholders = vec!["?"; ids.len()].join(", ");
+        let statement = format!(
+            "DELETE FROM message_store WHERE client_address_bs58 = ? AND id IN ({})",
+            placeholders
+        );
+        let query = ids.iter().fold(
+            sqlx::query(&statement).bind(client_address_bs58),
+            |query, id| query.bind(id),
+        );
+
+        let mut tx = self.connection_pool.begin().await?;
+        let result = query.execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
 }
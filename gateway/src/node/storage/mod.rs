@@ -11,6 +11,8 @@ use gateway_requests::registration::handshake::SharedKeys;
 use log::{debug, error};
 use nymsphinx::DestinationAddressBytes;
 use sqlx::ConnectOptions;
+#[cfg(test)]
+use std::ops::Bound;
 use std::path::Path;
 
 mod bandwidth;
@@ -91,6 +93,47 @@ pub(crate) trait Storage: Send + Sync {
     /// * `ids`: ids of the messages to remove
     async fn remove_messages(&self, ids: Vec<i64>) -> Result<(), StorageError>;
 
+    /// Retrieves a single page of messages stored for the particular client specified by the provided address.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_address`: address of the client
+    /// * `start_after`: optional starting id of the messages to grab
+    /// * `page_size`: maximum number of messages to grab, capped by the retrieval limit
+    ///
+    /// returns the retrieved messages alongside optional id of the last message retrieved if
+    /// there are more messages to retrieve.
+    async fn retrieve_messages_page(
+        &self,
+        client_address: DestinationAddressBytes,
+        start_after: Option<i64>,
+        page_size: i64,
+    ) -> Result<(Vec<StoredMessage>, Option<i64>), StorageError>;
+
+    /// Counts messages stored for the particular client.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_address`: address of the client
+    async fn count_stored_messages(
+        &self,
+        client_address: DestinationAddressBytes,
+    ) -> Result<u64, StorageError>;
+
+    /// Removes messages with the specified ids, as long as they were stored for the particular client.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_address`: address of the client
+    /// * `ids`: ids of the messages to remove, at most as many as the retrieval limit
+    ///
+    /// returns the number of messages actually removed.
+    async fn remove_client_messages(
+        &self,
+        client_address: DestinationAddressBytes,
+        ids: Vec<i64>,
+    ) -> Result<u64, StorageError>;
+
     /// Creates a new bandwidth entry for the particular client.
     ///
     /// # Arguments
@@ -261,6 +304,50 @@ impl Storage for PersistentStorage {
         Ok(())
     }
 
+    async fn retrieve_messages_page(
+        &self,
+        client_address: DestinationAddressBytes,
+        start_after: Option<i64>,
+        page_size: i64,
+    ) -> Result<(Vec<StoredMessage>, Option<i64>), StorageError> {
+        let messages = self
+            .inbox_manager
+            .get_messages_page(&client_address.as_base58_string(), start_after, page_size)
+            .await?;
+        Ok(messages)
+    }
+
+    async fn count_stored_messages(
+        &self,
+        client_address: DestinationAddressBytes,
+    ) -> Result<u64, StorageError> {
+        let count = self
+            .inbox_manager
+            .count_messages(&client_address.as_base58_string())
+            .await?;
+        Ok(count as u64)
+    }
+
+    async fn remove_client_messages(
+        &self,
+        client_address: DestinationAddressBytes,
+        ids: Vec<i64>,
+    ) -> Result<u64, StorageError> {
+        let max = self.inbox_manager.retrieval_limit();
+        if ids.len() as i64 > max {
+            return Err(StorageError::TooManyMessageIds {
+                requested: ids.len(),
+                max,
+            });
+        }
+
+        let removed = self
+            .inbox_manager
+            .remove_client_messages(&client_address.as_base58_string(), &ids)
+            .await?;
+        Ok(removed)
+    }
+
     async fn create_bandwidth_entry(
         &self,
         client_address: DestinationAddressBytes,
@@ -307,12 +394,26 @@ impl Storage for PersistentStorage {
 }
 
 /// In-memory implementation of `Storage`. The intention is primarily in testing environments.
+/// Only the client inboxes are actually kept in memory.
 #[cfg(test)]
-#[derive(Clone)]
-pub(crate) struct InMemStorage;
+#[derive(Clone, Default)]
+pub(crate) struct InMemStorage {
+    inboxes: std::sync::Arc<std::sync::Mutex<InMemInboxes>>,
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct InMemInboxes {
+    last_id: i64,
+    // stored messages alongside base58-encoded addresses of their clients, ordered by their ids
+    messages: std::collections::BTreeMap<i64, (String, Vec<u8>)>,
+}
 
 #[cfg(test)]
 impl InMemStorage {
+    /// Maximum number of stored client messages that can be retrieved at once.
+    const MESSAGE_RETRIEVAL_LIMIT: i64 = 100;
+
     #[allow(unused)]
     async fn init<P: AsRef<Path> + Send>() -> Result<Self, StorageError> {
         todo!()
@@ -346,22 +447,111 @@ impl Storage for InMemStorage {
 
     async fn store_message(
         &self,
-        _client_address: DestinationAddressBytes,
-        _message: Vec<u8>,
+        client_address: DestinationAddressBytes,
+        message: Vec<u8>,
     ) -> Result<(), StorageError> {
-        todo!()
+        let mut inboxes = self.inboxes.lock().unwrap();
+        inboxes.last_id += 1;
+        let id = inboxes.last_id;
+        inboxes
+            .messages
+            .insert(id, (client_address.as_base58_string(), message));
+        Ok(())
     }
 
     async fn retrieve_messages(
         &self,
-        _client_address: DestinationAddressBytes,
-        _start_after: Option<i64>,
+        client_address: DestinationAddressBytes,
+        start_after: Option<i64>,
     ) -> Result<(Vec<StoredMessage>, Option<i64>), StorageError> {
-        todo!()
+        self.retrieve_messages_page(client_address, start_after, Self::MESSAGE_RETRIEVAL_LIMIT)
+            .await
     }
 
-    async fn remove_messages(&self, _ids: Vec<i64>) -> Result<(), StorageError> {
-        todo!()
+    async fn remove_messages(&self, ids: Vec<i64>) -> Result<(), StorageError> {
+        let mut inboxes = self.inboxes.lock().unwrap();
+        for id in ids {
+            inboxes.messages.remove(&id);
+        }
+        Ok(())
+    }
+
+    async fn retrieve_messages_page(
+        &self,
+        client_address: DestinationAddressBytes,
+        start_after: Option<i64>,
+        page_size: i64,
+    ) -> Result<(Vec<StoredMessage>, Option<i64>), StorageError> {
+        let page_size = page_size.clamp(1, Self::MESSAGE_RETRIEVAL_LIMIT) as usize;
+        let client_address_bs58 = client_address.as_base58_string();
+        let start = match start_after {
+            Some(start_after) => Bound::Excluded(start_after),
+            None => Bound::Unbounded,
+        };
+
+        // get 1 additional message to check whether there will be more to grab next time
+        let inboxes = self.inboxes.lock().unwrap();
+        let mut res: Vec<_> = inboxes
+            .messages
+            .range((start, Bound::Unbounded))
+            .filter(|(_, (address, _))| *address == client_address_bs58)
+            .take(page_size + 1)
+            .map(|(id, (address, content))| StoredMessage {
+                id: *id,
+                client_address_bs58: address.clone(),
+                content: content.clone(),
+            })
+            .collect();
+
+        if res.len() > page_size {
+            res.truncate(page_size);
+            // page_size > 0, so unwrap will not fail
+            let start_after = res.last().unwrap().id;
+            Ok((res, Some(start_after)))
+        } else {
+            Ok((res, None))
+        }
+    }
+
+    async fn count_stored_messages(
+        &self,
+        client_address: DestinationAddressBytes,
+    ) -> Result<u64, StorageError> {
+        let client_address_bs58 = client_address.as_base58_string();
+        let count = self
+            .inboxes
+            .lock()
+            .unwrap()
+            .messages
+            .values()
+            .filter(|(address, _)| *address == client_address_bs58)
+            .count();
+        Ok(count as u64)
+    }
+
+    async fn remove_client_messages(
+        &self,
+        client_address: DestinationAddressBytes,
+        ids: Vec<i64>,
+    ) -> Result<u64, StorageError> {
+        let max = Self::MESSAGE_RETRIEVAL_LIMIT;
+        if ids.len() as i64 > max {
+            return Err(StorageError::TooManyMessageIds {
+                requested: ids.len(),
+                max,
+            });
+        }
+
+        let client_address_bs58 = client_address.as_base58_string();
+        let mut inboxes = self.inboxes.lock().unwrap();
+        let mut removed = 0;
+        for id in ids {
+            if inboxes.messages.get(&id).map(|(address, _)| address) == Some(&client_address_bs58) {
+                inboxes.messages.remove(&id);
+                removed += 1;
+            }
+        }
+        Ok(removed)
     }
 
     async fn create_bandwidth_entry(
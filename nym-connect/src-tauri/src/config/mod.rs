@@ -3,6 +3,8 @@ use std::path::PathBuf;
 use client_core::config::GatewayEndpoint;
 use log::info;
 
+use crate::error::BackendError;
+
 use client_core::config::Config as BaseConfig;
 use config::NymConfig;
 use nym_socks5::client::config::Config as Socks5Config;
@@ -55,12 +57,16 @@ impl Config {
         self.socks5.get_base_mut()
     }
 
-    pub async fn init(service_provider: Option<&String>, chosen_gateway_id: Option<&String>) {
+    pub async fn init(
+        service_provider: Option<&String>,
+        chosen_gateway_id: Option<&String>,
+    ) -> Result<(), BackendError> {
         let service_provider = service_provider.map_or(PROVIDER_ADDRESS, String::as_str);
         let chosen_gateway_id = chosen_gateway_id.map(String::as_str);
         info!("Initialising...");
-        init_socks5(service_provider, chosen_gateway_id).await;
+        init_socks5(service_provider, chosen_gateway_id).await?;
         info!("Configuration saved 🚀");
+        Ok(())
     }
 
     pub fn config_file_location(id: &str) -> PathBuf {
@@ -68,7 +74,10 @@ impl Config {
     }
 }
 
-pub async fn init_socks5(provider_address: &str, chosen_gateway_id: Option<&str>) {
+pub async fn init_socks5(
+    provider_address: &str,
+    chosen_gateway_id: Option<&str>,
+) -> Result<(), BackendError> {
     log::info!("Initialising client...");
 
     let id: &str = SOCKS5_CONFIG_ID;
@@ -103,7 +112,8 @@ pub async fn init_socks5(provider_address: &str, chosen_gateway_id: Option<&str>
         .get_base_mut()
         .with_eth_private_key(DEFAULT_ETH_PRIVATE_KEY);
 
-    let gateway = setup_gateway(id, register_gateway, chosen_gateway_id, config.get_socks5()).await;
+    let gateway =
+        setup_gateway(id, register_gateway, chosen_gateway_id, config.get_socks5()).await?;
     config.get_base_mut().with_gateway_endpoint(gateway);
 
     let config_save_location = config.get_socks5().get_config_file_save_location();
@@ -131,6 +141,7 @@ pub async fn init_socks5(provider_address: &str, chosen_gateway_id: Option<&str>
     info!("Client configuration completed.");
 
     client_core::init::show_address(config.get_base());
+    Ok(())
 }
 
 // TODO: deduplicate with same functions in other client
@@ -139,7 +150,7 @@ async fn setup_gateway(
     register: bool,
     user_chosen_gateway_id: Option<&str>,
     config: &Socks5Config,
-) -> GatewayEndpoint {
+) -> Result<GatewayEndpoint, BackendError> {
     if register {
         // Get the gateway details by querying the validator-api. Either pick one at random or use
         // the chosen one if it's among the available ones.
@@ -154,10 +165,10 @@ async fn setup_gateway(
         // Registering with gateway by setting up and writing shared keys to disk
         log::trace!("Registering gateway");
         client_core::init::register_with_gateway_and_store_keys(gateway.clone(), config.get_base())
-            .await;
+            .await?;
         println!("Saved all generated keys");
 
-        gateway.into()
+        Ok(gateway.into())
     } else if user_chosen_gateway_id.is_some() {
         // Just set the config, don't register or create any keys
         // This assumes that the user knows what they are doing, and that the existing keys are
@@ -169,20 +180,11 @@ async fn setup_gateway(
         )
         .await;
         log::debug!("Querying gateway gives: {}", gateway);
-        gateway.into()
+        Ok(gateway.into())
     } else {
         println!("Not registering gateway, will reuse existing config and keys");
-        match Socks5Config::load_from_file(Some(id)) {
-            Ok(existing_config) => existing_config.get_base().get_gateway_endpoint().clone(),
-            Err(err) => {
-                panic!(
-                    "Unable to configure gateway: {err}. \n
-                    Seems like the client was already initialized but it was not possible to read \
-                    the existing configuration file. \n
-                    CAUTION: Consider backing up your gateway keys and try force gateway registration, or \
-                    removing the existing configuration and starting over."
-                )
-            }
-        }
+        Socks5Config::load_from_file(Some(id))
+            .map(|existing_config| existing_config.get_base().get_gateway_endpoint().clone())
+            .map_err(BackendError::CouldNotLoadExistingGatewayConfiguration)
     }
 }
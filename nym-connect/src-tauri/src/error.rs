@@ -1,4 +1,6 @@
+use client_core::error::ClientCoreError;
 use serde::{Serialize, Serializer};
+use std::io;
 use thiserror::Error;
 
 #[allow(unused)]
@@ -14,6 +16,16 @@ pub enum BackendError {
     NoServiceProviderSet,
     #[error("No gateway provider set")]
     NoGatewaySet,
+    #[error("{0}")]
+    ClientCoreError(#[from] ClientCoreError),
+    #[error(
+        "Unable to configure gateway: {0}. \
+        Seems like the client was already initialized but it was not possible to read \
+        the existing configuration file. \
+        CAUTION: Consider backing up your gateway keys and try force gateway registration, or \
+        removing the existing configuration and starting over."
+    )]
+    CouldNotLoadExistingGatewayConfiguration(io::Error),
 }
 
 impl Serialize for BackendError {
@@ -11,7 +11,7 @@ pub async fn start_connecting(
 ) -> Result<ConnectResult, BackendError> {
     let mut guard = state.write().await;
 
-    guard.start_connecting(&window).await;
+    guard.start_connecting(&window).await?;
 
     Ok(ConnectResult {
         // WIP(JON): fixme
@@ -1,7 +1,7 @@
 use client_core::config::GatewayEndpoint;
 use futures::channel::mpsc;
 use futures::SinkExt;
-use log::info;
+use log::{error, info};
 
 use config::NymConfig;
 #[cfg(not(feature = "coconut"))]
@@ -9,6 +9,7 @@ use nym_socks5::client::NymClient as Socks5NymClient;
 use nym_socks5::client::{Socks5ControlMessage, Socks5ControlMessageSender};
 
 use crate::config::SOCKS5_CONFIG_ID;
+use crate::error::BackendError;
 use crate::models::{
     AppEventConnectionStatusChangedPayload, ConnectionStatusKind,
     APP_EVENT_CONNECTION_STATUS_CHANGED,
@@ -63,17 +64,24 @@ impl State {
         self.gateway = Some(gateway);
     }
 
-    pub async fn init_config(&self) {
-        crate::config::Config::init(self.service_provider.as_ref(), self.gateway.as_ref()).await;
+    pub async fn init_config(&self) -> Result<(), BackendError> {
+        crate::config::Config::init(self.service_provider.as_ref(), self.gateway.as_ref()).await
     }
 
-    pub async fn start_connecting(&mut self, window: &tauri::Window<tauri::Wry>) {
+    pub async fn start_connecting(
+        &mut self,
+        window: &tauri::Window<tauri::Wry>,
+    ) -> Result<(), BackendError> {
         info!("Connecting");
         self.set_state(ConnectionStatusKind::Connecting, window);
         self.status = ConnectionStatusKind::Connecting;
 
         // Setup configuration by writing to file
-        self.init_config().await;
+        if let Err(err) = self.init_config().await {
+            error!("Failed to initialise the client configuration: {err}");
+            self.set_state(ConnectionStatusKind::Disconnected, window);
+            return Err(err);
+        }
 
         // Kick of the main task and get the channel for controlling it
         let (sender, used_gateway) = start_nym_socks5_client();
@@ -82,6 +90,7 @@ impl State {
 
         self.status = ConnectionStatusKind::Connected;
         self.set_state(ConnectionStatusKind::Connected, window);
+        Ok(())
     }
 
     pub async fn start_disconnecting(&mut self, window: &tauri::Window<tauri::Wry>) {
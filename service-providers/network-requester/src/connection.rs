@@ -4,10 +4,16 @@
 use futures::channel::mpsc;
 use nymsphinx::addressing::clients::Recipient;
 use proxy_helpers::connection_controller::ConnectionReceiver;
-use proxy_helpers::proxy_runner::ProxyRunner;
-use socks5_requests::{ConnectionId, Message as Socks5Message, RemoteAddress, Response};
+use proxy_helpers::proxy_runner::{FlowControl, ProxyRunner};
+use socks5_requests::{
+    ConnectionError, ConnectionId, Message as Socks5Message, RemoteAddress, Response,
+};
 use std::io;
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpStream};
+
+/// For how long we're going to wait for the remote host to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// A TCP connection between the Socks5 service provider, which makes
 /// outbound requests on behalf of users and returns the responses through
@@ -25,8 +31,24 @@ impl Connection {
         id: ConnectionId,
         address: RemoteAddress,
         return_address: Recipient,
-    ) -> io::Result<Self> {
-        let conn = TcpStream::connect(&address).await?;
+    ) -> Result<Self, ConnectionError> {
+        // resolve the address separately so that we could tell DNS failures apart
+        let resolved: Vec<_> = lookup_host(&address)
+            .await
+            .map_err(|_| ConnectionError::DnsFailure)?
+            .collect();
+        if resolved.is_empty() {
+            return Err(ConnectionError::DnsFailure);
+        }
+
+        let conn = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&resolved[..]))
+            .await
+            .map_err(|_| ConnectionError::Timeout)?
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::ConnectionRefused => ConnectionError::ConnectionRefused,
+                io::ErrorKind::TimedOut => ConnectionError::Timeout,
+                _ => ConnectionError::Other,
+            })?;
 
         Ok(Connection {
             id,
@@ -53,12 +75,17 @@ impl Connection {
             mix_sender,
             connection_id,
         )
-        .run(move |conn_id, read_data, socket_closed| {
-            (
-                Socks5Message::Response(Response::new(conn_id, read_data, socket_closed)),
-                recipient,
-            )
-        })
+        .run(
+            // we only learn whether the client supports the flow control once it grants us credit
+            FlowControl::Pending,
+            move |conn_id, read_data, socket_closed| {
+                (
+                    Socks5Message::Response(Response::new(conn_id, read_data, socket_closed)),
+                    recipient,
+                )
+            },
+            move |control| (Socks5Message::StreamControl(control), recipient),
+        )
         .await
         .into_inner();
         self.conn = Some(stream);
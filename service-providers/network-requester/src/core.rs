@@ -4,6 +4,7 @@
 use crate::allowed_hosts::{HostsStore, OutboundRequestFilter};
 use crate::connection::Connection;
 use crate::statistics::ServiceStatisticsCollector;
+use crate::udp::{AssociationKey, DatagramSender, UdpAssociation, MAX_UDP_ASSOCIATIONS};
 use crate::websocket;
 use crate::websocket::TSWebsocketStream;
 use futures::channel::mpsc;
@@ -13,8 +14,13 @@ use log::*;
 use nymsphinx::addressing::clients::Recipient;
 use nymsphinx::receiver::ReconstructedMessage;
 use proxy_helpers::connection_controller::{Controller, ControllerCommand, ControllerSender};
-use socks5_requests::{ConnectionId, Message as Socks5Message, Request, Response};
+use proxy_helpers::proxy_runner::FlowControl;
+use socks5_requests::{
+    ConnectionError, ConnectionId, ConnectionStatusResponse, Message as Socks5Message,
+    RemoteAddress, Request, Response, StreamControl,
+};
 use statistics_common::collector::StatisticsSender;
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio_tungstenite::tungstenite::protocol::Message;
@@ -30,6 +36,7 @@ pub struct ServiceProvider {
     open_proxy: bool,
     enable_statistics: bool,
     stats_provider_addr: Option<Recipient>,
+    udp_associations: HashMap<AssociationKey, DatagramSender>,
 }
 
 impl ServiceProvider {
@@ -56,6 +63,7 @@ impl ServiceProvider {
             open_proxy,
             enable_statistics,
             stats_provider_addr,
+            udp_associations: HashMap::new(),
         }
     }
 
@@ -86,7 +94,9 @@ impl ServiceProvider {
             let response_message = ClientRequest::Send {
                 recipient: return_address,
                 message: msg.into_bytes(),
-                with_reply_surb: false,
+                num_reply_surbs: 0,
+                with_receipt: false,
+                headers: None,
             };
 
             let message = Message::Binary(response_message.serialize());
@@ -136,13 +146,21 @@ impl ServiceProvider {
         let mut conn = match Connection::new(conn_id, remote_addr.clone(), return_address).await {
             Ok(conn) => conn,
             Err(err) => {
-                error!(
-                    "error while connecting to {:?} ! - {:?}",
-                    remote_addr.clone(),
-                    err
-                );
+                error!("error while connecting to {:?} ! - {}", remote_addr, err);
+
+                // tell the remote why the connection has failed
+                mix_input_sender
+                    .unbounded_send((
+                        Socks5Message::ConnectionStatus(ConnectionStatusResponse::new(
+                            conn_id,
+                            Err(err),
+                        )),
+                        return_address,
+                    ))
+                    .unwrap();
 
                 // inform the remote that the connection is closed before it even was established
+                // (the clients not aware of the connection status rely on it)
                 mix_input_sender
                     .unbounded_send((
                         Socks5Message::Response(Response::new(conn_id, Vec::new(), true)),
@@ -154,10 +172,23 @@ impl ServiceProvider {
             }
         };
 
+        mix_input_sender
+            .unbounded_send((
+                Socks5Message::ConnectionStatus(
+                    ConnectionStatusResponse::new(conn_id, Ok(())).with_flow_control(),
+                ),
+                return_address,
+            ))
+            .unwrap();
+
         // Connect implies it's a fresh connection - register it with our controller
         let (mix_sender, mix_receiver) = mpsc::unbounded();
         controller_sender
-            .unbounded_send(ControllerCommand::Insert(conn_id, mix_sender))
+            .unbounded_send(ControllerCommand::Insert(
+                conn_id,
+                mix_sender,
+                FlowControl::Pending,
+            ))
             .unwrap();
 
         let old_count = ACTIVE_PROXIES.fetch_add(1, Ordering::SeqCst);
@@ -193,6 +224,15 @@ impl ServiceProvider {
     ) {
         if !self.open_proxy && !self.outbound_request_filter.check(&remote_addr) {
             log::info!("Domain {:?} failed filter check", remote_addr);
+            mix_input_sender
+                .unbounded_send((
+                    Socks5Message::ConnectionStatus(ConnectionStatusResponse::new(
+                        conn_id,
+                        Err(ConnectionError::HostNotAllowed),
+                    )),
+                    return_address,
+                ))
+                .unwrap();
             return;
         }
 
@@ -224,6 +264,102 @@ impl ServiceProvider {
             .unwrap()
     }
 
+    fn report_udp_association_failure(
+        mix_input_sender: &mpsc::UnboundedSender<(Socks5Message, Recipient)>,
+        conn_id: ConnectionId,
+        return_address: Recipient,
+    ) {
+        // the requester might be gone already, in which case there's nobody to tell
+        let _ = mix_input_sender.unbounded_send((
+            Socks5Message::ConnectionStatus(ConnectionStatusResponse::new(
+                conn_id,
+                Err(ConnectionError::Other),
+            )),
+            return_address,
+        ));
+    }
+
+    fn handle_udp_associate(
+        &mut self,
+        mix_input_sender: &mpsc::UnboundedSender<(Socks5Message, Recipient)>,
+        conn_id: ConnectionId,
+        return_address: Recipient,
+    ) {
+        // forget about the associations that have already finished
+        self.udp_associations
+            .retain(|_, datagram_sender| !datagram_sender.is_closed());
+
+        let key = (conn_id, return_address.to_bytes());
+        if self.udp_associations.contains_key(&key) {
+            warn!("UDP association {} is already open", conn_id);
+            return;
+        }
+
+        if self.udp_associations.len() >= MAX_UDP_ASSOCIATIONS {
+            warn!(
+                "Refusing UDP association {} as there are already {} associations open",
+                conn_id, MAX_UDP_ASSOCIATIONS
+            );
+            Self::report_udp_association_failure(mix_input_sender, conn_id, return_address);
+            return;
+        }
+
+        // register the association straight away so that any datagrams received
+        // before the socket is bound would just get queued up
+        let (datagram_sender, datagram_receiver) = mpsc::unbounded();
+        self.udp_associations.insert(key, datagram_sender);
+
+        let mix_input_sender_clone = mix_input_sender.clone();
+        tokio::spawn(async move {
+            match UdpAssociation::new(conn_id, return_address).await {
+                Ok(association) => {
+                    info!("Starting UDP association {}", conn_id);
+                    association
+                        .run(datagram_receiver, mix_input_sender_clone)
+                        .await
+                }
+                Err(err) => {
+                    error!(
+                        "Failed to bind UDP socket for association {} - {}",
+                        conn_id, err
+                    );
+                    Self::report_udp_association_failure(
+                        &mix_input_sender_clone,
+                        conn_id,
+                        return_address,
+                    )
+                }
+            }
+        });
+    }
+
+    fn handle_udp_send(
+        &mut self,
+        conn_id: ConnectionId,
+        remote_addr: RemoteAddress,
+        data: Vec<u8>,
+        return_address: Recipient,
+    ) {
+        if !self.open_proxy && !self.outbound_request_filter.check(&remote_addr) {
+            log::info!("Domain {:?} failed filter check", remote_addr);
+            return;
+        }
+
+        let key = (conn_id, return_address.to_bytes());
+        match self.udp_associations.get(&key) {
+            Some(datagram_sender) => {
+                if datagram_sender.unbounded_send((remote_addr, data)).is_err() {
+                    warn!("UDP association {} is already closed", conn_id);
+                    self.udp_associations.remove(&key);
+                }
+            }
+            None => warn!(
+                "Received a datagram for unknown UDP association {}",
+                conn_id
+            ),
+        }
+    }
+
     async fn handle_proxy_message(
         &mut self,
         raw_request: &[u8],
@@ -274,8 +410,46 @@ impl ServiceProvider {
                     }
                     self.handle_proxy_send(controller_sender, conn_id, data, closed)
                 }
+
+                Request::UdpAssociate(req) => {
+                    self.handle_udp_associate(mix_input_sender, req.conn_id, req.return_address)
+                }
+
+                Request::UdpSend(req) => {
+                    self.handle_udp_send(req.conn_id, req.remote_addr, req.data, req.return_address)
+                }
+
+                Request::UdpClose(req) => {
+                    // dropping the sender makes the association finish
+                    self.udp_associations
+                        .remove(&(req.conn_id, req.return_address.to_bytes()));
+                }
+
+                Request::Ping(req) => {
+                    trace!("Received a ping (probe id: {})", req.probe_id);
+                    mix_input_sender
+                        .unbounded_send((
+                            Socks5Message::ConnectionStatus(ConnectionStatusResponse::new(
+                                req.probe_id,
+                                Ok(()),
+                            )),
+                            req.return_address,
+                        ))
+                        .unwrap();
+                }
             },
-            Socks5Message::Response(_) => {}
+            Socks5Message::StreamControl(control) => {
+                let command = match control {
+                    StreamControl::Credit(conn_id, credit) => {
+                        ControllerCommand::Credit(conn_id, credit)
+                    }
+                    StreamControl::Reset(conn_id) => ControllerCommand::Reset(conn_id),
+                };
+                controller_sender.unbounded_send(command).unwrap();
+            }
+            Socks5Message::Response(_)
+            | Socks5Message::Datagram(_)
+            | Socks5Message::ConnectionStatus(_) => {}
         }
     }
 
@@ -10,6 +10,7 @@ mod allowed_hosts;
 mod connection;
 mod core;
 mod statistics;
+mod udp;
 mod websocket;
 
 const OPEN_PROXY_ARG: &str = "open-proxy";
@@ -0,0 +1,106 @@
+// Copyright 2022 - Nym Technologies SA <contact@nymtech.net>
+// SPDX-License-Identifier: Apache-2.0
+
+use futures::channel::mpsc;
+use futures::StreamExt;
+use log::*;
+use nymsphinx::addressing::clients::Recipient;
+use socks5_requests::{ConnectionId, DatagramResponse, Message as Socks5Message, RemoteAddress};
+use std::io;
+use std::time::Duration;
+use tokio::net::{lookup_host, UdpSocket};
+
+/// Maximum size of the payload of a single UDP datagram.
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// Maximum number of UDP associations that can be open at the same time, as each of them
+/// holds on to its own socket.
+pub(crate) const MAX_UDP_ASSOCIATIONS: usize = 64;
+
+/// UDP has no notion of closing, so the associations the client has forgotten about
+/// are closed after they haven't been used for this long.
+const ASSOCIATION_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// UDP associations are identified by their connection id alongside the address of the client
+/// that has opened them, so that no other client could use or close them.
+pub(crate) type AssociationKey = (ConnectionId, [u8; Recipient::LEN]);
+
+pub(crate) type DatagramSender = mpsc::UnboundedSender<(RemoteAddress, Vec<u8>)>;
+pub(crate) type DatagramReceiver = mpsc::UnboundedReceiver<(RemoteAddress, Vec<u8>)>;
+
+/// A UDP socket relaying the datagrams of a single SOCKS5 UDP association between
+/// the remote hosts and the requester, who receives everything through the mixnet.
+#[derive(Debug)]
+pub(crate) struct UdpAssociation {
+    id: ConnectionId,
+    socket: UdpSocket,
+    return_address: Recipient,
+}
+
+impl UdpAssociation {
+    pub(crate) async fn new(id: ConnectionId, return_address: Recipient) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+
+        Ok(UdpAssociation {
+            id,
+            socket,
+            return_address,
+        })
+    }
+
+    async fn send_datagram(&self, remote_addr: &str, data: &[u8]) {
+        // our socket is bound to an IPv4 address, so we can't reach IPv6-only hosts
+        let target = match lookup_host(remote_addr).await {
+            Ok(mut addresses) => addresses.find(|address| address.is_ipv4()),
+            Err(err) => {
+                warn!("Failed to resolve {} - {}", remote_addr, err);
+                return;
+            }
+        };
+
+        match target {
+            Some(target) => {
+                if let Err(err) = self.socket.send_to(data, target).await {
+                    warn!("Failed to send a datagram to {} - {}", remote_addr, err)
+                }
+            }
+            None => warn!("{} does not resolve to any IPv4 address", remote_addr),
+        }
+    }
+
+    /// Relays the datagrams until the association is closed by the requester
+    /// or it has been idle for too long.
+    pub(crate) async fn run(
+        self,
+        mut datagram_receiver: DatagramReceiver,
+        mix_sender: mpsc::UnboundedSender<(Socks5Message, Recipient)>,
+    ) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            tokio::select! {
+                received = self.socket.recv_from(&mut buf) => match received {
+                    Ok((len, source)) => {
+                        let datagram =
+                            DatagramResponse::new(self.id, source.to_string(), buf[..len].to_vec());
+                        let message = (Socks5Message::Datagram(datagram), self.return_address);
+                        if mix_sender.unbounded_send(message).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => debug!("Failed to receive a datagram - {}", err),
+                },
+                outgoing = datagram_receiver.next() => match outgoing {
+                    Some((remote_addr, data)) => self.send_datagram(&remote_addr, &data).await,
+                    None => {
+                        debug!("UDP association {} was closed", self.id);
+                        break;
+                    }
+                },
+                _ = tokio::time::sleep(ASSOCIATION_IDLE_TIMEOUT) => {
+                    info!("UDP association {} has been idle for too long - closing it", self.id);
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -53,7 +53,7 @@ impl Chunker {
 
         let (split_message, _reply_keys) = self
             .message_preparer
-            .prepare_and_split_message(message, false, topology)
+            .prepare_and_split_message(message, 0, topology)
             .expect("failed to split the message");
 
         let mut mix_packets = Vec::with_capacity(split_message.len());